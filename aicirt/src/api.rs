@@ -1,5 +1,5 @@
 use crate::{shm::ShmAllocator, HashMap};
-use aici_abi::{ProcessResultOffset, StorageCmd, TokenId};
+use aici_abi::{ContextTruncation, PostSampleResult, ProcessResultOffset, StorageCmd, TokenId};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -14,12 +14,19 @@ pub struct InferenceCapabilities {
     pub ff_tokens: bool,
     #[serde(default)]
     pub fork: bool,
+    /// Logit biases can be passed as lists of denied tokens (see aici_abi::svob::TokenSet).
+    #[serde(default)]
+    pub deny_list: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AiciMidProcessReq {
     pub ops: Vec<AiciMidOp>,
     pub freed: Vec<ModuleInstId>,
+    /// Instantiated requests (see AiciMidOp::req_id) that finished, or were rejected,
+    /// before their first mid_process.
+    #[serde(default)]
+    pub freed_req_ids: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,6 +39,24 @@ pub struct AiciMidProcessResp {
     pub num_masks: usize,
 }
 
+/// Sent after sampling for the sequences whose branch asked for post_sample().
+#[derive(Serialize, Deserialize)]
+pub struct AiciPostSampleReq {
+    pub ops: Vec<AiciPostSampleOp>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AiciPostSampleOp {
+    pub id: ModuleInstId,
+    /// The token sampled with the mask from the last mid_process().
+    pub token: Token,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AiciPostSampleResp {
+    pub seqs: HashMap<ModuleInstId, SequenceResult<PostSampleResult>>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 /// At most one of clone_id or req_id should be set.
 /// If either of them is set, then id should be fresh.
@@ -45,17 +70,35 @@ pub struct AiciMidOp {
     pub req_id: Option<String>,
     /// Sampling result for the previous iteration.
     /// For simple sampled token 't', backtrack==0 and tokens==[t].
-    /// For first request, backtrack==0 and tokens==[] (prompt is passed separetely, before).
+    /// For first request, backtrack==0 and tokens==[] (prompt is passed separetely, before),
+    /// except for requests restored from a snapshot, which pass the tokens generated so far.
     /// Can be more complex when splices are used.
     pub backtrack: u32,
     pub tokens: Vec<Token>,
+    /// The forced_byte_prefix of the branch the tokens were sampled for.
+    #[serde(default)]
+    pub forced_byte_prefix: Vec<u8>,
+    /// Length in bytes of the decoded sequence, after applying backtrack and tokens.
+    #[serde(default)]
+    pub byte_offset: u64,
+    /// Tokens dropped from the model's context since the previous call, if any.
+    #[serde(default)]
+    pub context_truncation: Option<ContextTruncation>,
+    /// Most likely tokens of the step that sampled the tokens, if the controller asked for
+    /// them (see aici_abi::InitPromptResult::top_logprobs).
+    #[serde(default)]
+    pub top_logprobs: Vec<(Token, f32)>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SequenceResult<T = ()> {
     pub result: Option<T>,
     pub error: String,
-    // StorageCmd::ReadVar are not recorded
+    /// The error was reported by the controller (see aici_abi::ErrorResult),
+    /// as opposed to a trap or a host failure.
+    #[serde(default)]
+    pub controller_error: bool,
+    // reads (StorageCmd::ReadVar, ReadBatch) are not recorded
     pub storage: Vec<StorageCmd>,
     pub logs: String,
     pub micros: u64,
@@ -66,6 +109,7 @@ impl<T> SequenceResult<T> {
         SequenceResult {
             logs: error.clone(),
             error,
+            controller_error: false,
             result: None,
             storage: vec![],
             micros: 0,
@@ -74,6 +118,7 @@ impl<T> SequenceResult<T> {
     pub fn clone_with<S>(&self, result: Option<S>) -> SequenceResult<S> {
         SequenceResult {
             error: self.error.clone(),
+            controller_error: self.controller_error,
             result,
             storage: self.storage.clone(),
             logs: self.logs.clone(),
@@ -86,6 +131,7 @@ impl<T> SequenceResult<T> {
     {
         SequenceResult {
             error: self.error,
+            controller_error: self.controller_error,
             result: self.result.map(f),
             storage: self.storage,
             logs: self.logs,
@@ -145,7 +191,7 @@ pub struct TokensResp {
     pub vocab_size: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AuthInfo {
     pub user: String,
     pub is_admin: bool,
@@ -271,6 +317,72 @@ impl BiasType {
             }
         }
     }
+
+    /// Like apply_to_shm_allocator(), but all tokens below `num_tokens` are allowed,
+    /// except for the ones in `deny`.
+    pub fn apply_deny_list_to_shm_allocator(
+        &self,
+        deny: &[TokenId],
+        num_tokens: usize,
+        shm: &ShmAllocator,
+        off: usize,
+    ) {
+        let vocab_size = self.bytes_to_elts(shm.elt_size());
+        assert!(num_tokens <= vocab_size);
+        match self {
+            BiasType::F32 => apply_deny_list(
+                deny,
+                num_tokens,
+                shm.slice_at_byte_offset::<f32>(off, vocab_size),
+                Self::LOGIT_BIAS_ALLOW,
+                Self::LOGIT_BIAS_DISALLOW,
+            ),
+            BiasType::F16 => apply_deny_list(
+                deny,
+                num_tokens,
+                shm.slice_at_byte_offset::<u16>(off, vocab_size),
+                Self::LOGIT_BIAS_ALLOW_F16,
+                Self::LOGIT_BIAS_DISALLOW_F16,
+            ),
+            BiasType::BF16 => apply_deny_list(
+                deny,
+                num_tokens,
+                shm.slice_at_byte_offset::<u16>(off, vocab_size),
+                Self::LOGIT_BIAS_ALLOW_BF16,
+                Self::LOGIT_BIAS_DISALLOW_BF16,
+            ),
+            BiasType::Bool => {
+                let trg = shm.slice_at_byte_offset::<u8>(off, self.size_in_bytes(vocab_size));
+                trg[0..num_tokens / 8].fill(0xff);
+                trg[num_tokens / 8..].fill(0);
+                for idx in (num_tokens & !7)..num_tokens {
+                    trg[idx / 8] |= 1 << (idx % 8);
+                }
+                for &tok in deny {
+                    let idx = tok as usize;
+                    if idx < num_tokens {
+                        trg[idx / 8] &= !(1 << (idx % 8));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn apply_deny_list<T: Copy>(
+    deny: &[TokenId],
+    num_tokens: usize,
+    dst: &mut [T],
+    allow: T,
+    disallow: T,
+) {
+    dst[0..num_tokens].fill(allow);
+    dst[num_tokens..].fill(disallow);
+    for &tok in deny {
+        if (tok as usize) < num_tokens {
+            dst[tok as usize] = disallow;
+        }
+    }
 }
 
 fn apply_to_slice<T: Copy>(src: &[u8], dst: &mut [T], allow: T, disallow: T) {
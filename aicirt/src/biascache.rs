@@ -0,0 +1,259 @@
+use crate::shm::Shm;
+use anyhow::{ensure, Result};
+use std::{
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// (namespace, key); the namespace separates the values of different modules.
+pub type BiasCacheKey = (u64, u64);
+
+/// Values stored by controllers with aici_abi::bias_cache_put(), shared by all sequences.
+/// It lives in shared memory mapped before the workers are forked, so that they look up
+/// values themselves, without a round-trip to another process. The table is
+/// set-associative: a key can only be in the WAYS slots of its set, and the least
+/// recently used one of these is replaced. Each set has its own lock, held while
+/// a value is copied; a set that stays locked (e.g., by a worker killed at that time)
+/// just misses.
+pub struct ShmBiasCache {
+    shm: Shm,
+    num_sets: usize,
+    max_value_size: usize,
+}
+
+const WAYS: usize = 4;
+const LOCK_TIMEOUT: Duration = Duration::from_millis(1);
+// hits, misses, puts, clock
+const HEADER_SIZE: usize = 64;
+// the lock of a set, padded
+const SET_HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Slot {
+    namespace: u64,
+    key: u64,
+    last_use: u64,
+    // 0 for an empty slot; values are never empty
+    len: u64,
+}
+
+const SLOT_HEADER_SIZE: usize = std::mem::size_of::<Slot>();
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BiasCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub puts: u64,
+}
+
+impl BiasCacheStats {
+    /// Percentage of lookups that found a value.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl ShmBiasCache {
+    /// A cache of about `size` bytes, for values of up to `max_value_size` bytes.
+    pub fn new(size: usize, max_value_size: usize) -> Result<Self> {
+        ensure!(max_value_size > 0);
+        let max_value_size = max_value_size.next_multiple_of(8);
+        let set_size = SET_HEADER_SIZE + WAYS * (SLOT_HEADER_SIZE + max_value_size);
+        let num_sets = size.saturating_sub(HEADER_SIZE) / set_size;
+        ensure!(
+            num_sets > 0,
+            "bias cache of {size} bytes has no room for values of {max_value_size} bytes"
+        );
+        // anonymous memory is zeroed: no values, and the counters at 0
+        let shm = Shm::anon(HEADER_SIZE + num_sets * set_size)?;
+        Ok(ShmBiasCache {
+            shm,
+            num_sets,
+            max_value_size,
+        })
+    }
+
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size
+    }
+
+    pub fn get(&self, key: BiasCacheKey) -> Option<Vec<u8>> {
+        let value = self.with_set(key, |slots| {
+            let idx = self.find(slots, key)?;
+            let slot = self.slot(slots, idx);
+            let len = slot.len as usize;
+            let mut value = vec![0u8; len];
+            unsafe {
+                ptr::copy_nonoverlapping(self.value_ptr(slots, idx), value.as_mut_ptr(), len);
+            }
+            self.set_slot(
+                slots,
+                idx,
+                Slot {
+                    last_use: self.tick(),
+                    ..slot
+                },
+            );
+            Some(value)
+        });
+        let value = value.flatten();
+        let (hits, misses) = match value {
+            Some(_) => (self.counter(0).fetch_add(1, Ordering::Relaxed) + 1, 0),
+            None => (0, self.counter(1).fetch_add(1, Ordering::Relaxed) + 1),
+        };
+        if (hits + misses) % 10_000 == 0 {
+            let stats = self.stats();
+            log::info!(
+                "bias cache: hit rate {:.1}% of {} lookups; {} puts",
+                stats.hit_rate(),
+                stats.hits + stats.misses,
+                stats.puts
+            );
+        }
+        value
+    }
+
+    /// Store the value, replacing the one with the same key; values over
+    /// max_value_size() (or empty) are not stored.
+    pub fn put(&self, key: BiasCacheKey, value: &[u8]) -> bool {
+        if value.is_empty() || value.len() > self.max_value_size {
+            return false;
+        }
+        self.counter(2).fetch_add(1, Ordering::Relaxed);
+        let stored = self.with_set(key, |slots| {
+            // the same key, an empty slot, or the least recently used one
+            let idx = self.find(slots, key).unwrap_or_else(|| {
+                (0..WAYS)
+                    .min_by_key(|idx| {
+                        let slot = self.slot(slots, *idx);
+                        (slot.len != 0, slot.last_use)
+                    })
+                    .unwrap()
+            });
+            unsafe {
+                ptr::copy_nonoverlapping(value.as_ptr(), self.value_ptr(slots, idx), value.len());
+            }
+            self.set_slot(
+                slots,
+                idx,
+                Slot {
+                    namespace: key.0,
+                    key: key.1,
+                    last_use: self.tick(),
+                    len: value.len() as u64,
+                },
+            );
+        });
+        stored.is_some()
+    }
+
+    pub fn stats(&self) -> BiasCacheStats {
+        BiasCacheStats {
+            hits: self.counter(0).load(Ordering::Relaxed),
+            misses: self.counter(1).load(Ordering::Relaxed),
+            puts: self.counter(2).load(Ordering::Relaxed),
+        }
+    }
+
+    fn counter(&self, idx: usize) -> &AtomicU64 {
+        unsafe { AtomicU64::from_ptr(self.shm.ptr_at(8 * idx) as *mut u64) }
+    }
+
+    fn tick(&self) -> u64 {
+        self.counter(3).fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn set_size(&self) -> usize {
+        SET_HEADER_SIZE + WAYS * (SLOT_HEADER_SIZE + self.max_value_size)
+    }
+
+    /// Run `f` with the offset of the set of `key` locked; None when it can't be locked.
+    fn with_set<T>(&self, key: BiasCacheKey, f: impl FnOnce(usize) -> T) -> Option<T> {
+        // a fixed mix, so that all processes agree on the set
+        let h = mix(mix(key.0) ^ key.1);
+        let off = HEADER_SIZE + (h % self.num_sets as u64) as usize * self.set_size();
+        let lock = unsafe { AtomicU32::from_ptr(self.shm.ptr_at(off) as *mut u32) };
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        while lock
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if Instant::now() > deadline {
+                log::warn!("bias cache: set busy");
+                return None;
+            }
+            std::hint::spin_loop();
+        }
+        let r = f(off + SET_HEADER_SIZE);
+        lock.store(0, Ordering::Release);
+        Some(r)
+    }
+
+    fn find(&self, slots: usize, key: BiasCacheKey) -> Option<usize> {
+        (0..WAYS).find(|idx| {
+            let slot = self.slot(slots, *idx);
+            slot.len != 0 && (slot.namespace, slot.key) == key
+        })
+    }
+
+    fn slot_ptr(&self, slots: usize, idx: usize) -> *mut u8 {
+        self.shm
+            .ptr_at(slots + idx * (SLOT_HEADER_SIZE + self.max_value_size))
+    }
+
+    fn value_ptr(&self, slots: usize, idx: usize) -> *mut u8 {
+        unsafe { self.slot_ptr(slots, idx).add(SLOT_HEADER_SIZE) }
+    }
+
+    fn slot(&self, slots: usize, idx: usize) -> Slot {
+        unsafe { ptr::read(self.slot_ptr(slots, idx) as *const Slot) }
+    }
+
+    fn set_slot(&self, slots: usize, idx: usize, slot: Slot) {
+        unsafe { ptr::write(self.slot_ptr(slots, idx) as *mut Slot, slot) }
+    }
+}
+
+// splitmix64
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_replaced_least_recently_used_first() {
+        // a single set
+        let cache = ShmBiasCache::new(2048, 256).unwrap();
+        assert_eq!(cache.num_sets, 1);
+        for key in 0..WAYS as u64 {
+            assert!(cache.put((1, key), &[key as u8; 10]));
+        }
+        assert_eq!(cache.get((1, 0)), Some(vec![0; 10]));
+        // replaces key 1, the oldest one not used since
+        assert!(cache.put((1, 100), &[7; 256]));
+        assert_eq!(cache.get((1, 1)), None);
+        assert_eq!(cache.get((1, 0)), Some(vec![0; 10]));
+        assert_eq!(cache.get((1, 100)), Some(vec![7; 256]));
+        // the same key in another namespace is another value
+        assert_eq!(cache.get((2, 0)), None);
+        // too large
+        assert!(!cache.put((1, 0), &[0; 257]));
+        assert_eq!(cache.get((1, 0)), Some(vec![0; 10]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.puts), (4, 2, 5));
+    }
+}
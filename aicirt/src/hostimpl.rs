@@ -1,11 +1,21 @@
 use crate::worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg};
 use aici_abi::{
     bytes::{clone_vec_as_bytes, limit_str, vec_from_bytes, TokRxInfo},
-    StorageCmd,
+    svob::TokenSet,
+    toktree::TokTrie,
+    ErrorResult, PostSampleArg, RuntimeInfo, StorageCmd, TokenId,
+};
+use aicirt::{
+    api::{BiasType, InferenceCapabilities},
+    biascache::ShmBiasCache,
+    shm::ShmAllocator,
+    user_error,
 };
-use aicirt::{api::{BiasType, InferenceCapabilities}, shm::ShmAllocator, user_error};
 use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
 use std::{
+    io::Write,
+    path::PathBuf,
     rc::Rc,
     sync::Arc,
     time::{Duration, Instant},
@@ -19,12 +29,28 @@ pub struct AiciLimits {
     pub timer_resolution_ns: u64,
     pub max_memory_bytes: usize,
     pub max_step_ms: u64,
+    pub max_post_sample_ms: u64,
     pub max_init_ms: u64,
     pub max_compile_ms: u64,
     pub max_timeout_steps: usize,
     pub logit_memory_bytes: usize,
     pub busy_wait_duration: Duration,
     pub max_forks: usize,
+    /// Logs of a single controller call are truncated beyond this.
+    pub max_log_bytes: usize,
+    /// Returned to controllers as get_config("log_level"); see aici_abi::LogLevel.
+    pub log_level: u32,
+    /// Total size of storage variables of a single request.
+    pub max_storage_bytes: usize,
+    /// Total size of the global storage variables, shared by all requests;
+    /// 0 makes them variables of the request.
+    pub max_global_storage_bytes: usize,
+    /// Controllers record their sessions into files in this directory, one per
+    /// sequence (see aici_abi::recording); None to not record.
+    pub record_dir: Option<PathBuf>,
+    /// Total size of token sets (and other values) controllers share through
+    /// aici_abi::bias_cache_put(); 0 disables the cache.
+    pub bias_cache_bytes: usize,
 
     pub module_upload: bool,
     pub gh_download: bool,
@@ -37,6 +63,7 @@ pub struct ModuleData {
     pub id: ModuleInstId,
     log: Vec<u8>,
     printed_log: usize,
+    log_truncated: bool,
     pub globals: GlobalInfo,
     pub group_channel: GroupHandle,
     pub process_result: Vec<u8>,
@@ -51,11 +78,19 @@ pub struct ModuleData {
     pub had_error: bool,
     pub storage_log: Vec<StorageCmd>,
     pub start_time: Instant,
+    /// End of the time budget for the current call (init or step).
+    pub deadline: Instant,
+    /// When the current call gets interrupted (see ModuleInstance::call_func());
+    /// None for no limit.
+    pub hard_deadline: Option<Instant>,
+    pub rand_seed: u64,
+    /// The sequence whose recording file was last written; see append_recording().
+    recorded_seq: Option<ModuleInstId>,
+    /// The cache, and the namespace of the module in it.
+    pub bias_cache: Option<(Rc<ShmBiasCache>, u64)>,
     blobs: Vec<Rc<Vec<u8>>>,
 }
 
-const MAXLOG: usize = 64 * 1024;
-
 pub struct BlobId(u32);
 
 impl BlobId {
@@ -64,6 +99,10 @@ impl BlobId {
     pub const TOKENS: BlobId = BlobId(3);
     pub const PROCESS_ARG: BlobId = BlobId(4);
     pub const STORAGE_RESULT: BlobId = BlobId(5);
+    pub const DECODE: BlobId = BlobId(6);
+    pub const BIAS_CACHE: BlobId = BlobId(7);
+    /// aici_abi::RuntimeInfo; read by the controllers with this id (no host call returns it).
+    pub const RUNTIME_INFO: BlobId = BlobId(8);
 
     pub const MAX_BLOB_ID: u32 = 20;
 
@@ -94,6 +133,7 @@ impl ModuleData {
             id,
             log: Vec::new(),
             printed_log: 0,
+            log_truncated: false,
             globals,
             group_channel,
             module: module.clone(),
@@ -108,12 +148,34 @@ impl ModuleData {
             had_error: false,
             storage_log: Vec::new(),
             start_time: Instant::now(),
+            deadline: Instant::now() + Duration::from_millis(limits.max_init_ms),
+            hard_deadline: Some(Instant::now() + Duration::from_millis(limits.max_init_ms)),
+            rand_seed: 0,
+            recorded_seq: None,
+            bias_cache: None,
             blobs: vec![Rc::new(Vec::new()); BlobId::MAX_BLOB_ID as usize],
         };
         r.set_blob(BlobId::MODULE_ARG, module_arg.as_bytes().to_vec());
+        let info = serde_json::to_vec(&r.globals.runtime_info).unwrap();
+        r.set_blob(BlobId::RUNTIME_INFO, info);
         r
     }
 
+    /// Derive the default random seed for the controller from its argument and the prompt,
+    /// so that re-running the same request is reproducible (also across hosts and builds,
+    /// so a hash with a fixed definition is used).
+    pub fn set_rand_seed(&mut self, prompt: &[TokenId]) {
+        let arg = &self.blobs[BlobId::MODULE_ARG.0 as usize];
+        let mut hasher = Sha256::new();
+        hasher.update((arg.len() as u64).to_le_bytes());
+        hasher.update(arg.as_slice());
+        for t in prompt {
+            hasher.update(t.to_le_bytes());
+        }
+        let digest = hasher.finalize();
+        self.rand_seed = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+    }
+
     fn clear_blob(&mut self, blob_id: BlobId) {
         self.set_blob(blob_id, vec![])
     }
@@ -127,10 +189,33 @@ impl ModuleData {
         self.set_blob(BlobId::PROCESS_ARG, bytes);
     }
 
+    /// The error passed by the controller in place of the result, if any.
+    pub fn error_result(&self) -> Option<ErrorResult> {
+        ErrorResult::from_result_bytes(&self.process_result)
+    }
+
+    /// Start the time budget of a call; past `hard_ms` (if any), the call is interrupted.
+    pub fn set_budget(&mut self, soft_ms: u64, hard_ms: Option<u64>) {
+        let now = Instant::now();
+        self.deadline = now + Duration::from_millis(soft_ms);
+        self.hard_deadline = hard_ms.map(|ms| now + Duration::from_millis(ms));
+    }
+
     pub fn set_mid_process_data(&mut self, data: RtMidProcessArg) {
         let bytes = serde_json::to_vec(&data.op).unwrap();
         self.set_process_arg(bytes);
         self.logit_offsets.clear();
+        // the host waits for late results for max_timeout_steps steps (applying
+        // no-op steps in the meantime) before giving up on the sequence
+        let max_step_ms = self.limits.max_step_ms;
+        let num_steps = 1 + self.limits.max_timeout_steps as u64;
+        self.set_budget(max_step_ms, Some(max_step_ms * num_steps));
+    }
+
+    pub fn set_post_sample_arg(&mut self, arg: &PostSampleArg) {
+        self.set_process_arg(serde_json::to_vec(arg).unwrap());
+        let max_ms = self.limits.max_post_sample_ms;
+        self.set_budget(max_ms, Some(max_ms));
     }
 
     pub fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
@@ -141,10 +226,38 @@ impl ModuleData {
         }
     }
 
+    /// Like tokenize(), but accepts bytes that are not valid UTF-8
+    /// (these are tokenized at the byte level).
+    pub fn tokenize_bytes(&self, s: &[u8]) -> Result<Vec<u32>> {
+        let mut err = None;
+        let tokens = self.globals.tok_trie.tokenize_bytes_with(s, |s| {
+            match self.globals.hf_tokenizer.encode(s, false) {
+                Ok(tokens) => Vec::from(tokens.get_ids()),
+                Err(e) => {
+                    err = Some(anyhow!(e));
+                    vec![]
+                }
+            }
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(tokens),
+        }
+    }
+
+    pub fn decode(&self, tokens: &[TokenId]) -> Result<Vec<u8>> {
+        let trie = &self.globals.tok_trie;
+        if let Some(t) = tokens.iter().find(|t| **t >= trie.vocab_size() as u32) {
+            return Err(anyhow!("invalid token {t}"));
+        }
+        Ok(trie.decode(tokens))
+    }
+
     pub fn fatal(&mut self, msg: &str) {
         log::warn!("{}: fatal error {}", self.id, msg);
         let msg = format!("FATAL ERROR: {}\n", msg);
-        self.write_log(msg.as_bytes());
+        // this goes past the size limit, so it's not lost
+        self.log.extend_from_slice(msg.as_bytes());
         self.had_error = true;
         // ideally, this should call into the module and cause panic
     }
@@ -156,22 +269,24 @@ impl ModuleData {
     }
 
     pub fn write_log(&mut self, bytes: &[u8]) {
-        self.log.extend_from_slice(bytes);
-        if self.log.len() > MAXLOG {
-            let drop = MAXLOG / 4;
-            if self.had_error {
-                // normally, we drop prefix, but if "had_error" is set
-                // we drop the suffix instead to avoid flushing out "FATAL ERROR" message
-                self.log.truncate(self.log.len() - drop);
-            } else {
-                self.printed_log = self.printed_log.saturating_sub(drop);
-                self.log.drain(0..drop);
-            }
+        if self.log_truncated {
+            return;
+        }
+        let max = self.limits.max_log_bytes;
+        if self.log.len() + bytes.len() > max {
+            let keep = max.saturating_sub(self.log.len());
+            self.log.extend_from_slice(&bytes[..keep]);
+            let marker = format!("\n[log truncated at {} bytes]\n", max);
+            self.log.extend_from_slice(marker.as_bytes());
+            self.log_truncated = true;
+        } else {
+            self.log.extend_from_slice(bytes);
         }
     }
 
     pub fn string_log(&mut self) -> String {
         self.printed_log = 0;
+        self.log_truncated = false;
         let logs = String::from_utf8_lossy(&self.log).to_string();
         self.log.clear();
         logs
@@ -195,13 +310,37 @@ impl ModuleData {
         }
     }
 
+    /// Append to the recording file of the sequence. The first write of a sequence
+    /// (also one just forked off, which the controller sends the whole log) starts the file.
+    pub fn append_recording(&mut self, data: &[u8]) {
+        let dir = match &self.limits.record_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let path = dir.join(format!("{}.bin", self.id));
+        let fresh = self.recorded_seq != Some(self.id);
+        self.recorded_seq = Some(self.id);
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!fresh)
+            .truncate(fresh)
+            .open(&path)
+            .and_then(|mut f| f.write_all(data));
+        if let Err(e) = res {
+            self.warn(&format!("can't write recording {}: {e}", path.display()));
+        }
+    }
+
     pub fn aici_host_storage_cmd(&mut self, m: Vec<u8>) -> BlobId {
         self.clear_blob(BlobId::STORAGE_RESULT);
         match serde_json::from_slice(&m) {
             Ok(cmd) => {
                 let save = match &cmd {
-                    StorageCmd::WriteVar { .. } => Some(cmd.clone()),
-                    StorageCmd::ReadVar { .. } => None,
+                    StorageCmd::WriteVar { .. } | StorageCmd::WriteBatch { .. } => {
+                        Some(cmd.clone())
+                    }
+                    StorageCmd::ReadVar { .. } | StorageCmd::ReadBatch { .. } => None,
                 };
                 let res = self.group_channel.send_cmd(GroupCmd::StorageCmd { cmd });
                 match res {
@@ -227,7 +366,10 @@ pub struct GlobalInfo {
     pub inference_caps: InferenceCapabilities,
     pub tokrx_info: TokRxInfo,
     pub trie_bytes: Arc<Vec<u8>>,
+    pub tok_trie: Arc<TokTrie>,
     pub hf_tokenizer: Arc<Tokenizer>,
+    /// Passed to all controllers; see BlobId::RUNTIME_INFO.
+    pub runtime_info: RuntimeInfo,
 }
 
 fn check_fatal(caller: &mut wasmtime::Caller<'_, ModuleData>) {
@@ -434,8 +576,7 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "aici_host_tokenize",
         |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
             let m = read_caller_mem(&caller, src, src_size);
-            let s = String::from_utf8_lossy(&m);
-            let tokens = caller.data_mut().tokenize(&s);
+            let tokens = caller.data_mut().tokenize_bytes(&m);
             match tokens {
                 Err(e) => {
                     caller.data_mut().warn(&format!("tokenize error: {e:?}"));
@@ -451,28 +592,75 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_decode",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
+            let m = read_caller_mem(&caller, src, 4 * src_size);
+            let tokens = vec_from_bytes::<TokenId>(&m);
+            match caller.data_mut().decode(&tokens) {
+                Err(e) => {
+                    caller.data_mut().warn(&format!("decode error: {e:?}"));
+                    caller.data_mut().clear_blob(BlobId::DECODE);
+                }
+                Ok(bytes) => {
+                    caller.data_mut().set_blob(BlobId::DECODE, bytes);
+                }
+            }
+            BlobId::DECODE.0
+        },
+    )?;
+
     linker.func_wrap(
         "env",
         "aici_host_return_logit_bias",
-        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32| {
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| -> Result<u32> {
             let data = caller.data();
 
+            // tokens past the tokenizer vocab are disallowed in deny lists,
+            // while masks have to cover all of the model's logits
             let numtok = data.globals.tokrx_info.vocab_size as usize;
+            let model_numtok = data.globals.tok_trie.model_vocab_size();
+            let deny = if src_size & TokenSet::DENY_LIST_FLAG != 0 {
+                let num_deny = src_size & !TokenSet::DENY_LIST_FLAG;
+                if num_deny as usize > numtok {
+                    return Err(user_error!(
+                        "deny list has {num_deny} tokens, but the model vocab size is {numtok}"
+                    ));
+                }
+                Some(vec_from_bytes::<TokenId>(&read_caller_mem(
+                    &caller,
+                    src,
+                    4 * num_deny,
+                )))
+            } else if (src_size as usize) < model_numtok {
+                return Err(user_error!(
+                    "logit bias covers {src_size} tokens, but the model vocab size is {model_numtok}"
+                ));
+            } else {
+                None
+            };
             let shm = data.logit_shm.clone();
             let id: u32 = data.id.try_into().unwrap();
-            let numbytes = 4 * ((numtok + 31) / 32);
-            let mem = caller.data().memory.unwrap();
-            let sptr = src as usize;
-            let slice = &mem.data(&caller)[sptr..sptr + numbytes];
 
             let bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
             let off = shm.alloc(id).unwrap();
 
-            bias_type.apply_to_shm_allocator(slice, &shm, off);
+            match deny {
+                // only the denied tokens are looked at
+                Some(deny) => bias_type.apply_deny_list_to_shm_allocator(&deny, numtok, &shm, off),
+                None => {
+                    let numbytes = 4 * ((model_numtok + 31) / 32);
+                    let mem = caller.data().memory.unwrap();
+                    let sptr = src as usize;
+                    let slice = &mem.data(&caller)[sptr..sptr + numbytes];
+                    bias_type.apply_to_shm_allocator(slice, &shm, off);
+                }
+            }
 
             let off32: u32 = off.try_into().unwrap();
             caller.data_mut().logit_offsets.push(off32);
-            off32
+            Ok(off32)
         },
     )?;
 
@@ -488,6 +676,9 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         |caller: wasmtime::Caller<'_, ModuleData>, name: u32, name_size: u32| {
             let m = read_caller_mem(&caller, name, name_size);
             let name = String::from_utf8_lossy(&m);
+            if name == "log_level" {
+                return caller.data().limits.log_level as i32;
+            }
             let caps = serde_json::to_value(caller.data().globals.inference_caps.clone()).unwrap();
             if caps[name.as_ref()].as_bool().unwrap_or(false) {
                 return 1;
@@ -496,6 +687,27 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_time_left_us",
+        |caller: wasmtime::Caller<'_, ModuleData>| {
+            let left = caller
+                .data()
+                .deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos() as u64;
+            // coarse, so that it can't be used as a precise timer
+            let res = std::cmp::max(caller.data().limits.timer_resolution_ns, 1_000_000);
+            left / res * res / 1000
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_rand_seed",
+        |caller: wasmtime::Caller<'_, ModuleData>| caller.data().rand_seed,
+    )?;
+
     linker.func_wrap(
         "env",
         "aici_host_eos_token",
@@ -511,6 +723,15 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_append_recording",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
+            let m = read_caller_mem(&caller, src, src_size);
+            caller.data_mut().append_recording(&m);
+        },
+    )?;
+
     linker.func_wrap(
         "env",
         "aici_host_storage_cmd",
@@ -522,6 +743,41 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_bias_cache_get",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, key: u64| {
+            let value = match &caller.data().bias_cache {
+                Some((cache, ns)) => cache.get((*ns, key)),
+                None => None,
+            };
+            caller
+                .data_mut()
+                .set_blob(BlobId::BIAS_CACHE, value.unwrap_or_default());
+            BlobId::BIAS_CACHE.0
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_bias_cache_put",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, key: u64, src: u32, src_size: u32| {
+            let (cache, ns) = match &caller.data().bias_cache {
+                Some((cache, ns)) => (cache.clone(), *ns),
+                None => return,
+            };
+            let max_size = cache.max_value_size();
+            if src_size as usize > max_size {
+                caller.data_mut().warn(&format!(
+                    "bias_cache_put: {src_size} bytes is over the limit of {max_size}"
+                ));
+                return;
+            }
+            let m = read_caller_mem(&caller, src, src_size);
+            cache.put((ns, key), &m);
+        },
+    )?;
+
     linker.func_wrap("env", "aici_host_stop", || {
         Err::<(), _>(user_error!("*** aici_host_stop()"))
     })?;
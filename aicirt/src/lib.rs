@@ -1,5 +1,6 @@
 pub mod api;
 mod bench;
+pub mod biascache;
 pub mod futexshm;
 pub mod msgchannel;
 pub mod semaphore;
@@ -12,7 +12,8 @@ use crate::{
     TimerSet,
 };
 use aici_abi::{
-    bytes::limit_str, toktree::TokTrie, Branch, MidProcessArg, ProcessResultOffset, SeqId,
+    bytes::limit_str, toktree::TokTrie, Branch, MidProcessArg, PostSampleArg, ProcessResultOffset,
+    SeqId,
 };
 use aicirt::{bintokens::find_tokenizer, futexshm::ServerChannel, shm::ShmAllocator, *};
 use anyhow::{anyhow, ensure, Result};
@@ -57,6 +58,11 @@ struct Cli {
     #[arg(long)]
     logits_size: Option<usize>,
 
+    /// Size of the model logits (defaults to the tokenizer vocab size);
+    /// logit biases returned by controllers have to cover that many tokens
+    #[arg(long)]
+    model_vocab_size: Option<usize>,
+
     /// Path to .wasm module to install
     #[arg(short, long)]
     module: Option<String>,
@@ -133,6 +139,10 @@ struct Cli {
     #[arg(long, default_value = "10")]
     wasm_max_timeout_steps: usize,
 
+    /// Maximum time WASM module can execute post_sample() in milliseconds
+    #[arg(long, default_value = "10")]
+    wasm_max_post_sample_time: u64,
+
     /// Maximum time WASM module can execute initialization code in milliseconds
     #[arg(long, default_value = "1000")]
     wasm_max_init_time: u64,
@@ -141,6 +151,33 @@ struct Cli {
     #[arg(long, default_value = "0")]
     wasm_timer_resolution_us: u64,
 
+    /// Maximum size of logs of a single WASM module call in kilobytes; the rest is dropped
+    #[arg(long, default_value = "64")]
+    wasm_max_log_size: usize,
+
+    /// Maximum total size of storage variables of a single request in kilobytes
+    #[arg(long, default_value = "1024")]
+    wasm_max_storage: usize,
+
+    /// Maximum total size of global storage variables (named "global/...", shared by
+    /// all requests) in kilobytes; 0 to make them variables of the request
+    #[arg(long, default_value = "16384")]
+    wasm_max_global_storage: usize,
+
+    /// Record WASM controller sessions into files <seq_id>.bin in this directory,
+    /// for replaying with aici_abi::replay
+    #[arg(long)]
+    wasm_record: Option<PathBuf>,
+
+    /// Size of the cache of token sets shared by WASM modules across sequences in megabytes;
+    /// 0 to disable
+    #[arg(long, default_value = "64")]
+    wasm_bias_cache: usize,
+
+    /// Log level of WASM modules (error, warn, info, debug)
+    #[arg(long, default_value = "info")]
+    wasm_log_level: String,
+
     /// Shm/semaphore name prefix
     #[arg(long, short, default_value = "/aici0-")]
     name: String,
@@ -174,6 +211,11 @@ struct Stepper {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     instances: HashMap<ModuleInstId, SeqWorkerHandle>,
     num_timeouts: HashMap<ModuleInstId, usize>,
+    // instances whose last result came in after the step deadline
+    late_results: HashSet<ModuleInstId>,
+    // Branch::fork_arg from the last result of an instance, for the sequences
+    // following its branches (only kept when not all empty)
+    fork_args: HashMap<ModuleInstId, Vec<Vec<u8>>>,
     limits: AiciLimits,
     globals: GlobalInfo,
     shm: Rc<ShmAllocator>,
@@ -635,6 +677,8 @@ impl Stepper {
             req_instances: reg.req_instances.clone(),
             instances: HashMap::default(),
             num_timeouts: HashMap::default(),
+            late_results: HashSet::default(),
+            fork_args: HashMap::default(),
             limits,
             globals: reg.wasm_ctx.globals.clone(),
             shm,
@@ -707,7 +751,7 @@ impl Stepper {
     }
 
     fn aici_mid_process(&mut self, req: AiciMidProcessReq) -> Result<AiciMidProcessResp> {
-        let block_elts = self.globals.tokrx_info.vocab_size as usize;
+        let block_elts = self.globals.tok_trie.model_vocab_size();
         let mut outputs = HashMap::default();
 
         // first, execute forks
@@ -761,11 +805,23 @@ impl Stepper {
                     .iter()
                     .map(|id| SeqId(*id as u32))
                     .collect::<Vec<_>>();
+                let fork_arg = self
+                    .fork_args
+                    .get(&par)
+                    .and_then(|args| args.get(op.clone_idx.unwrap_or(0)))
+                    .cloned()
+                    .unwrap_or_default();
                 let op = RtMidProcessArg {
                     op: MidProcessArg {
                         backtrack: op.backtrack,
                         tokens: op.tokens.clone(),
                         fork_group,
+                        fork_arg,
+                        prev_timed_out: self.late_results.contains(&instid),
+                        forced_byte_prefix: op.forced_byte_prefix.clone(),
+                        byte_offset: op.byte_offset,
+                        context_truncation: op.context_truncation.clone(),
+                        top_logprobs: op.top_logprobs.clone(),
                     },
                 };
                 if self.num_timeouts.get(&instid).is_some() {
@@ -776,7 +832,10 @@ impl Stepper {
                     used_ids.push(instid);
                 } else {
                     match h.start_process(op) {
-                        Ok(_) => used_ids.push(instid),
+                        Ok(_) => {
+                            self.late_results.remove(&instid);
+                            used_ids.push(instid)
+                        }
                         Err(e) => self.worker_error(instid, &mut outputs, e),
                     }
                 }
@@ -785,6 +844,10 @@ impl Stepper {
             }
         }
 
+        for par in parents.values() {
+            self.fork_args.remove(par);
+        }
+
         let deadline = Instant::now() + std::time::Duration::from_millis(self.limits.max_step_ms);
         let mut max_offset = 0;
         let mut max_idx = 0;
@@ -797,6 +860,9 @@ impl Stepper {
             let timeout = deadline.saturating_duration_since(Instant::now());
             match h.check_process(timeout) {
                 Ok(mut data) => {
+                    if prev_timeout > 0 {
+                        self.late_results.insert(id);
+                    }
                     if !self.globals.inference_caps.fork {
                         if let Some(r) = &data.result {
                             if r.branches.len() > 1 {
@@ -810,6 +876,10 @@ impl Stepper {
                         }
                     }
                     if let Some(r) = &mut data.result {
+                        if r.branches.iter().any(|b| !b.fork_arg.is_empty()) {
+                            let args = r.branches.iter().map(|b| b.fork_arg.clone()).collect();
+                            self.fork_args.insert(id, args);
+                        }
                         r.branches = r
                             .branches
                             .iter()
@@ -833,8 +903,12 @@ impl Stepper {
                             SequenceResult {
                                 result: Some(ProcessResultOffset {
                                     branches: vec![Branch::noop()],
+                                    suspend: None,
+                                    max_remaining_tokens: None,
+                                    captures: vec![],
                                 }),
                                 error: String::new(),
+                                controller_error: false,
                                 storage: vec![],
                                 logs: format!(
                                     "⏲ timeout [deadline: {}ms; step {}/{}]\n",
@@ -856,6 +930,15 @@ impl Stepper {
         for id in req.freed {
             log::debug!("free module {}", id);
             self.instances.remove(&id);
+            self.late_results.remove(&id);
+            self.fork_args.remove(&id);
+        }
+        if !req.freed_req_ids.is_empty() {
+            let mut req_instances = self.req_instances.lock().unwrap();
+            for req_id in req.freed_req_ids {
+                log::debug!("free unstarted module ({})", req_id);
+                req_instances.remove(&req_id);
+            }
         }
 
         self.shm.free(max_offset, |client_id| {
@@ -875,6 +958,46 @@ impl Stepper {
         })
     }
 
+    fn aici_post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp> {
+        let mut outputs = HashMap::default();
+        let mut used_ids = Vec::new();
+
+        for op in req.ops {
+            let id = op.id;
+            if self.num_timeouts.contains_key(&id) {
+                // the worker is still busy with mid_process() from a previous round
+                self.worker_error(
+                    id,
+                    &mut outputs,
+                    anyhow!("post_sample: sequence {id} timed out"),
+                );
+                continue;
+            }
+            match self.get_worker(id) {
+                Ok(h) => match h.start_post_sample(PostSampleArg { token: op.token }) {
+                    Ok(_) => used_ids.push(id),
+                    Err(e) => self.worker_error(id, &mut outputs, e),
+                },
+                Err(e) => self.worker_error(id, &mut outputs, e),
+            }
+        }
+
+        let deadline =
+            Instant::now() + std::time::Duration::from_millis(self.limits.max_post_sample_ms);
+        for id in used_ids {
+            let h = self.get_worker(id).unwrap();
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match h.check_post_sample(timeout) {
+                Ok(data) => {
+                    outputs.insert(id, data);
+                }
+                Err(e) => self.worker_error(id, &mut outputs, e),
+            }
+        }
+
+        Ok(AiciPostSampleResp { seqs: outputs })
+    }
+
     fn worker_error<T>(
         &mut self,
         instid: usize,
@@ -896,6 +1019,9 @@ impl Exec for Stepper {
             Some("mid_process") => Ok(serde_json::to_value(
                 &self.aici_mid_process(serde_json::from_value(json)?)?,
             )?),
+            Some("post_sample") => Ok(serde_json::to_value(
+                &self.aici_post_sample(serde_json::from_value(json)?)?,
+            )?),
             _ => return Err(anyhow!("bad op")),
         }
     }
@@ -1097,6 +1223,43 @@ fn bench_hashmap() {
     }
 }
 
+/// Compare passing the logit bias for a 128k vocabulary with 3 tokens denied as a mask,
+/// and as a deny list: the bytes the controller passes, and the time the host takes
+/// to fill in the bias.
+fn bench_logit_bias(bias_type: BiasType) {
+    const ROUNDS: u32 = 1000;
+    let vocab_size = 128 * 1024;
+    let shm = Shm::new("/aici-bench-bias", 4 * MEGABYTE, shm::Unlink::Post).unwrap();
+    let shm = ShmAllocator::new(shm, bias_type.size_in_bytes(vocab_size), bias_type.to_u32());
+    let off = shm.alloc(1).unwrap();
+
+    let deny = [1, 17, vocab_size as u32 - 1];
+    let mut mask = vec![0xffu8; vocab_size / 8];
+    for tok in deny {
+        mask[tok as usize / 8] &= !(1 << (tok % 8));
+    }
+
+    let t0 = Instant::now();
+    for _ in 0..ROUNDS {
+        bias_type.apply_to_shm_allocator(&mask, &shm, off);
+    }
+    let mask_time = t0.elapsed() / ROUNDS;
+    let t0 = Instant::now();
+    for _ in 0..ROUNDS {
+        bias_type.apply_deny_list_to_shm_allocator(&deny, vocab_size, &shm, off);
+    }
+    let deny_time = t0.elapsed() / ROUNDS;
+
+    println!(
+        "logit bias ({}): mask {} bytes, {:?}; deny list {} bytes, {:?}",
+        bias_type.to_string(),
+        mask.len(),
+        mask_time,
+        4 * deny.len(),
+        deny_time
+    );
+}
+
 fn save_tokenizer(cli: &Cli) {
     let filename = cli.save_tokenizer.as_deref().unwrap();
     let tokenizer = find_tokenizer(&cli.tokenizer).unwrap();
@@ -1107,7 +1270,8 @@ fn save_tokenizer(cli: &Cli) {
         tokenizer.tokrx_info(),
         tokens.len()
     );
-    let trie = TokTrie::from(&tokenizer.tokrx_info(), &tokens);
+    let trie =
+        TokTrie::from(&tokenizer.tokrx_info(), &tokens).with_special_tokens(&tokenizer.special);
     trie.check_against(&tokens);
 
     let bytes = trie.serialize();
@@ -1186,17 +1350,39 @@ fn main() -> () {
         }
     };
 
+    let log_level = match aici_abi::LogLevel::from_name(&cli.wasm_log_level) {
+        Some(x) => x,
+        None => {
+            eprintln!("invalid wasm_log_level: {}", cli.wasm_log_level);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(dir) = &cli.wasm_record {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("can't create {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
     let limits = AiciLimits {
         ipc_shm_bytes: cli.json_size * MEGABYTE,
         timer_resolution_ns: cli.wasm_timer_resolution_us * 1000,
         max_memory_bytes: cli.wasm_max_memory * MEGABYTE,
         max_init_ms: cli.wasm_max_init_time,
         max_step_ms: cli.wasm_max_step_time,
+        max_post_sample_ms: cli.wasm_max_post_sample_time,
         max_timeout_steps: cli.wasm_max_timeout_steps,
         max_compile_ms: 10_000,
         logit_memory_bytes: cli.bin_size * MEGABYTE,
         busy_wait_duration: Duration::from_millis(cli.busy_wait_time),
         max_forks: cli.wasm_max_forks,
+        max_log_bytes: cli.wasm_max_log_size * 1024,
+        log_level: log_level as u32,
+        max_storage_bytes: cli.wasm_max_storage * 1024,
+        max_global_storage_bytes: cli.wasm_max_global_storage * 1024,
+        record_dir: cli.wasm_record.clone(),
+        bias_cache_bytes: cli.wasm_bias_cache * MEGABYTE,
 
         module_upload: !cli.restricted,
         gh_download: !cli.restricted,
@@ -1204,6 +1390,7 @@ fn main() -> () {
 
     if cli.bench {
         bench_hashmap();
+        bench_logit_bias(bias_type);
         return ();
     }
 
@@ -1211,6 +1398,7 @@ fn main() -> () {
         fork: cli.cap_fork,
         backtrack: true,
         ff_tokens: true,
+        deny_list: true,
     };
 
     let mut tokenizer = find_tokenizer(&cli.tokenizer).unwrap();
@@ -1218,7 +1406,13 @@ fn main() -> () {
         tokenizer.add_missing_tokens(logits_size);
     }
     let token_bytes = tokenizer.token_bytes();
-    let wasm_ctx = WasmContext::new(inference_caps, limits.clone(), tokenizer).unwrap();
+    let wasm_ctx = WasmContext::new(
+        inference_caps,
+        limits.clone(),
+        tokenizer,
+        cli.model_vocab_size,
+    )
+    .unwrap();
 
     if cli.save_tokenizer.is_some() {
         save_tokenizer(&cli);
@@ -1236,7 +1430,7 @@ fn main() -> () {
     )
     .unwrap();
 
-    let vocab_size = wasm_ctx.globals.tokrx_info.vocab_size as usize;
+    let vocab_size = wasm_ctx.globals.tok_trie.model_vocab_size();
     let shm_alloc = Rc::new(ShmAllocator::new(
         bin_shm,
         // allow for a little leeway
@@ -1,22 +1,120 @@
 use crate::{
     api::ModuleInstId,
     hostimpl::{setup_linker, AiciLimits, GlobalInfo, ModuleData},
-    worker::{GroupHandle, RtMidProcessArg},
+    worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg},
     TimerSet, UserError,
 };
-use aici_abi::{toktree::TokTrie, InitPromptArg, ProcessResultOffset, TokenId};
+use aici_abi::{
+    toktree::TokTrie, Branch, Capability, ErrorResult, InitPromptArg, InitPromptResult,
+    PostSampleArg, PostSampleResult, ProcessResultOffset, RuntimeInfo, StorageCmd, StorageResp,
+    Suspend, TokenId, MAX_SUSPEND_STEPS,
+};
 use aicirt::{
     api::{InferenceCapabilities, SequenceResult},
     bail_user,
+    biascache::ShmBiasCache,
     bintokens::ByteTokenizer,
     shm::ShmAllocator,
     user_error,
 };
 use anyhow::{anyhow, ensure, Result};
 use serde::Deserialize;
-use std::{path::PathBuf, rc::Rc, sync::Arc, time::Instant};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use wasmtime;
 
+/// How often the epoch of the engine advances; calls are interrupted up to this late.
+const EPOCH_TICK: Duration = Duration::from_millis(2);
+
+/// Error reported by the controller (rather than a trap or a host failure).
+#[derive(Debug)]
+struct ControllerError(ErrorResult);
+
+impl Display for ControllerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.0.phase, self.0.message)
+    }
+}
+
+impl std::error::Error for ControllerError {}
+
+fn engine_config() -> wasmtime::Config {
+    let mut cfg = wasmtime::Config::default();
+    // these are defaults as of 13.0.0, but we specify them anyways for stability
+    cfg.debug_info(false)
+        .wasm_backtrace(true)
+        .native_unwind_info(true)
+        .consume_fuel(false)
+        .max_wasm_stack(512 * 1024)
+        .wasm_tail_call(false)
+        .wasm_threads(false)
+        .wasm_simd(true)
+        .wasm_relaxed_simd(false)
+        .wasm_bulk_memory(true)
+        .wasm_multi_value(true)
+        .wasm_memory64(false)
+        .strategy(wasmtime::Strategy::Auto)
+        .cranelift_nan_canonicalization(false)
+        .parallel_compilation(true);
+
+    // calls past their deadline are interrupted; see set_deadline()
+    cfg.epoch_interruption(true);
+
+    // we use fork()
+    cfg.macos_use_mach_ports(false);
+
+    // disable stuff we don't need
+    cfg.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Disable)
+        .wasm_reference_types(false);
+
+    // compilation in Speed mode seems to be ~10% slower but the generated code is 20-30% faster
+    cfg.cranelift_opt_level(wasmtime::OptLevel::Speed);
+    cfg
+}
+
+/// Start the thread advancing the epoch of the engine, unless it already runs in this
+/// process (threads don't survive fork(), and each sequence runs in a forked process).
+fn ensure_epoch_ticker(engine: &wasmtime::Engine) {
+    static TICKER_PID: AtomicU32 = AtomicU32::new(0);
+    let pid = std::process::id();
+    if TICKER_PID.swap(pid, Ordering::Relaxed) != pid {
+        let engine = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            engine.increment_epoch();
+        });
+    }
+}
+
+/// Have wasm code running in the store trap once the deadline passes (never for None).
+fn set_deadline<T>(store: &mut wasmtime::Store<T>, deadline: Option<Instant>) {
+    let ticks = match deadline {
+        Some(deadline) => {
+            ensure_epoch_ticker(store.engine());
+            let left = deadline.saturating_duration_since(Instant::now());
+            // the current tick may end any time
+            1 + (left.as_micros() / EPOCH_TICK.as_micros()) as u64
+        }
+        // far enough, without overflowing the epoch
+        None => u64::MAX / 2,
+    };
+    store.set_epoch_deadline(ticks);
+}
+
+fn is_interrupt(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::Interrupt)
+}
+
 #[derive(Clone)]
 pub struct WasmContext {
     pub engine: wasmtime::Engine,
@@ -35,41 +133,18 @@ impl WasmContext {
         inference_caps: InferenceCapabilities,
         limits: AiciLimits,
         tokenizer: ByteTokenizer,
+        model_vocab_size: Option<usize>,
     ) -> Result<Self> {
-        let mut cfg = wasmtime::Config::default();
-        // these are defaults as of 13.0.0, but we specify them anyways for stability
-        cfg.debug_info(false)
-            .wasm_backtrace(true)
-            .native_unwind_info(true)
-            .consume_fuel(false)
-            .max_wasm_stack(512 * 1024)
-            .wasm_tail_call(false)
-            .wasm_threads(false)
-            .wasm_simd(true)
-            .wasm_relaxed_simd(false)
-            .wasm_bulk_memory(true)
-            .wasm_multi_value(true)
-            .wasm_memory64(false)
-            .strategy(wasmtime::Strategy::Auto)
-            .cranelift_nan_canonicalization(false)
-            .parallel_compilation(true);
-
-        // we use fork()
-        cfg.macos_use_mach_ports(false);
-
-        // disable stuff we don't need
-        cfg.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Disable)
-            .wasm_reference_types(false);
-
-        // compilation in Speed mode seems to be ~10% slower but the generated code is 20-30% faster
-        cfg.cranelift_opt_level(wasmtime::OptLevel::Speed);
-
-        let engine = wasmtime::Engine::new(&cfg)?;
+        let engine = wasmtime::Engine::new(&engine_config())?;
         let linker = setup_linker(&engine)?;
 
         let tokens = tokenizer.token_bytes();
-        let trie = TokTrie::from(&tokenizer.tokrx_info(), &tokens);
+        let mut trie =
+            TokTrie::from(&tokenizer.tokrx_info(), &tokens).with_special_tokens(&tokenizer.special);
         trie.check_against(&tokens);
+        if let Some(n) = model_vocab_size {
+            trie.set_model_vocab_size(n)?;
+        }
         let bytes = trie.serialize();
         // validate
         let trie2 = TokTrie::from_bytes(&bytes);
@@ -80,11 +155,40 @@ impl WasmContext {
         // let tokens = tok.encode("I am something", false).unwrap();
         // println!("tokens: {:?}", tokens);
 
+        let mut caps = vec![
+            Capability::StorageBatch,
+            Capability::TopLogprobs,
+            Capability::PostSample,
+            Capability::Captures,
+        ];
+        for (cap, on) in [
+            (Capability::Fork, inference_caps.fork),
+            (Capability::Backtrack, inference_caps.backtrack),
+            (Capability::FfTokens, inference_caps.ff_tokens),
+            (Capability::DenyList, inference_caps.deny_list),
+            (Capability::BiasCache, limits.bias_cache_bytes > 0),
+            // see SharedVariables::fork()
+            (
+                Capability::GlobalStorage,
+                limits.max_global_storage_bytes > 0 && cfg!(target_os = "linux"),
+            ),
+        ] {
+            if on {
+                caps.push(cap);
+            }
+        }
+        let runtime_info = RuntimeInfo {
+            record: limits.record_dir.is_some(),
+            ..RuntimeInfo::new(&caps, tokenizer.tokrx_info().vocab_size, tokenizer.scheme)
+        };
+
         let globals = GlobalInfo {
             tokrx_info: tokenizer.tokrx_info(),
             trie_bytes: Arc::new(bytes),
+            tok_trie: Arc::new(trie2),
             hf_tokenizer: Arc::new(tokenizer.hf_tokenizer),
             inference_caps,
+            runtime_info,
         };
 
         Ok(Self {
@@ -102,8 +206,17 @@ pub struct ModuleInstance {
     memory: wasmtime::Memory,
     instance: wasmtime::Instance,
     handle: WasmAici,
-    #[allow(dead_code)]
     limits: AiciLimits,
+    suspended: Option<SuspendState>,
+}
+
+/// Wake-up condition of a suspended sequence, as tracked by the host.
+struct SuspendState {
+    /// Variable name and its version at the time of suspension (0 when missing).
+    var: Option<(String, u64)>,
+    /// At most MAX_SUSPEND_STEPS, so that a variable nobody writes doesn't
+    /// suspend the sequence forever.
+    steps_left: u32,
 }
 type WasmPtr = u32;
 type WasmAici = u32;
@@ -120,6 +233,8 @@ impl ModuleInstance {
         let f = self
             .instance
             .get_typed_func::<Params, Results>(&mut self.store, name)?;
+        let deadline = self.store.data().hard_deadline;
+        set_deadline(&mut self.store, deadline);
         let r = f.call(&mut self.store, params);
         let ctx = self.store.data_mut();
         ctx.flush_logs(name);
@@ -127,7 +242,14 @@ impl ModuleInstance {
             Ok(r) => Ok(r),
             Err(e) => {
                 ctx.had_error = true;
-                if let Some(e) = e.downcast_ref::<UserError>() {
+                if is_interrupt(&e) {
+                    // the controller may be in the middle of anything; it can't go on
+                    let log = ctx.string_log();
+                    Err(user_error!("{log}\n{name}: time limit exceeded"))
+                } else if let Some(err) = ctx.error_result() {
+                    // reported by the panic hook before the trap
+                    Err(ControllerError(err).into())
+                } else if let Some(e) = e.downcast_ref::<UserError>() {
                     Err(user_error!("{}\n{}", ctx.string_log(), e))
                 } else if let Some(bt) = e.downcast_ref::<wasmtime::WasmBacktrace>() {
                     Err(user_error!(
@@ -204,8 +326,30 @@ impl ModuleInstance {
             ),
         );
         store.limiter(|state| &mut state.store_limits);
-
-        let instance = ctx.linker.instantiate(&mut store, &module)?;
+        // instantiation may run the start function
+        let deadline = store.data().hard_deadline;
+        set_deadline(&mut store, deadline);
+
+        // Controllers built against a newer aici_abi may import host calls we don't have;
+        // they only trap when called, so that the controller can check runtime_info() first.
+        // (Controllers not importing some of our host calls need nothing special.)
+        let unknown = module
+            .imports()
+            .filter(|imp| {
+                ctx.linker
+                    .get(&mut store, imp.module(), imp.name())
+                    .is_none()
+            })
+            .map(|imp| format!("{}::{}", imp.module(), imp.name()))
+            .collect::<Vec<_>>();
+        let instance = if unknown.is_empty() {
+            ctx.linker.instantiate(&mut store, &module)?
+        } else {
+            log::warn!("module imports unknown functions: {}", unknown.join(", "));
+            let mut linker = (*ctx.linker).clone();
+            linker.define_unknown_imports_as_traps(&module)?;
+            linker.instantiate(&mut store, &module)?
+        };
         let memory = instance
             .get_memory(&mut store, "memory")
             .ok_or_else(|| anyhow!("memory missing"))?;
@@ -218,6 +362,7 @@ impl ModuleInstance {
             memory,
             instance,
             limits: ctx.limits,
+            suspended: None,
         })
     }
 
@@ -225,12 +370,21 @@ impl ModuleInstance {
         self.store.data_mut().id = id;
     }
 
+    /// Let the module use the cache; its values are only visible to instances
+    /// of the same module.
+    pub fn set_bias_cache(&mut self, cache: Rc<ShmBiasCache>, module_id: &str) {
+        let mut hasher = DefaultHasher::new();
+        module_id.hash(&mut hasher);
+        self.store.data_mut().bias_cache = Some((cache, hasher.finish()));
+    }
+
     fn run_init(&mut self) -> Result<()> {
         self.call_func::<(), ()>("aici_init", ())?;
         Ok(())
     }
 
     pub fn run_main(&mut self) -> Result<()> {
+        self.store.data_mut().set_budget(u32::MAX as u64, None);
         self.run_init()?;
         let t0 = Instant::now();
         if self
@@ -255,12 +409,76 @@ impl ModuleInstance {
         let bytes = &self.store.data().process_result;
         if bytes.len() == 0 {
             Err(anyhow!("aici_host_return_process_result not called"))
+        } else if let Some(err) = self.store.data().error_result() {
+            Err(ControllerError(err).into())
         } else {
             serde_json::from_slice::<T>(bytes).map_err(|e| e.into())
         }
     }
 
+    fn var_version(&self, name: &str) -> Result<u64> {
+        let cmd = StorageCmd::ReadVar {
+            name: name.to_string(),
+        };
+        match self
+            .group_channel()
+            .send_cmd(GroupCmd::StorageCmd { cmd })?
+        {
+            GroupResp::StorageResp { resp } => match resp {
+                StorageResp::ReadVar { version, .. } => Ok(version),
+                StorageResp::VariableMissing {} => Ok(0),
+                _ => Err(anyhow!("unexpected response to read var")),
+            },
+        }
+    }
+
+    fn suspend(&mut self, cond: &Suspend) -> Result<()> {
+        let var = match &cond.wake_on_var {
+            Some(name) => Some((name.clone(), self.var_version(name)?)),
+            None => None,
+        };
+        let steps_left = cond
+            .wake_after_steps
+            .map_or(MAX_SUSPEND_STEPS, |n| n.min(MAX_SUSPEND_STEPS));
+        self.suspended = Some(SuspendState { var, steps_left });
+        Ok(())
+    }
+
+    /// Check (and advance) the wake-up condition of a suspended sequence.
+    /// Note that the variable may change between the controller deciding to suspend
+    /// and the host recording its version; controllers should re-check the variable
+    /// after waking up, or also set wake_after_steps.
+    fn should_wake(&mut self) -> Result<bool> {
+        let var = match &mut self.suspended {
+            None => return Ok(true),
+            Some(st) => {
+                if st.steps_left == 0 {
+                    return Ok(true);
+                }
+                st.steps_left -= 1;
+                st.var.clone()
+            }
+        };
+        match var {
+            Some((name, version)) => Ok(self.var_version(&name)? != version),
+            None => Ok(false),
+        }
+    }
+
     fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<Option<ProcessResultOffset>> {
+        if self.suspended.is_some() {
+            // any tokens (e.g., fast-forwarded by the host) need to go to the controller
+            if op.op.backtrack == 0 && op.op.tokens.is_empty() && !self.should_wake()? {
+                return Ok(Some(ProcessResultOffset {
+                    branches: vec![Branch::noop()],
+                    suspend: None,
+                    max_remaining_tokens: None,
+                    captures: vec![],
+                }));
+            }
+            self.suspended = None;
+        }
+
         self.store.data_mut().set_mid_process_data(op);
         self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
         let res: ProcessResultOffset = self.proc_result()?;
@@ -279,7 +497,13 @@ impl ModuleInstance {
                     })
                 })
                 .collect(),
+            suspend: res.suspend,
+            max_remaining_tokens: res.max_remaining_tokens,
+            captures: res.captures,
         };
+        if let Some(cond) = &res.suspend {
+            self.suspend(cond)?;
+        }
         Ok(Some(res))
     }
 
@@ -296,6 +520,7 @@ impl ModuleInstance {
         match res {
             Ok(r) => SequenceResult {
                 error: String::new(),
+                controller_error: false,
                 logs,
                 storage,
                 micros,
@@ -308,6 +533,7 @@ impl ModuleInstance {
                 log::warn!("exec: {error}");
                 SequenceResult {
                     error,
+                    controller_error: e.downcast_ref::<ControllerError>().is_some(),
                     logs,
                     storage,
                     micros,
@@ -324,11 +550,35 @@ impl ModuleInstance {
         self.seq_result("mid", t0, res)
     }
 
+    fn do_post_sample(&mut self, arg: PostSampleArg) -> Result<PostSampleResult> {
+        self.store.data_mut().set_post_sample_arg(&arg);
+        self.call_func::<WasmAici, ()>("aici_post_sample", self.handle)?;
+        let res: PostSampleResult = self.proc_result()?;
+        if let PostSampleResult::Replace { token } = res {
+            let vocab_size = self.store.data().globals.tokrx_info.vocab_size;
+            if token >= vocab_size {
+                bail_user!("post_sample: invalid token {token} (vocab size {vocab_size})");
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn post_sample(&mut self, arg: PostSampleArg) -> SequenceResult<PostSampleResult> {
+        let t0 = Instant::now();
+        let res = self.do_post_sample(arg).map(Some);
+        self.seq_result("post_sample", t0, res)
+    }
+
     pub fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
         self.store.data_mut().tokenize(s)
     }
 
-    fn setup_inner(&mut self, prompt: Vec<TokenId>) -> Result<()> {
+    fn setup_inner(&mut self, prompt: Vec<TokenId>) -> Result<InitPromptResult> {
+        let max_init_ms = self.limits.max_init_ms;
+        self.store
+            .data_mut()
+            .set_budget(max_init_ms, Some(max_init_ms));
+        self.store.data_mut().set_rand_seed(&prompt);
         self.run_init()?;
 
         self.handle = self.call_func::<(), WasmAici>("aici_create", ())?;
@@ -338,14 +588,72 @@ impl ModuleInstance {
             .set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
         self.call_func::<WasmAici, ()>("aici_init_prompt", self.handle)?;
 
-        Ok(())
+        let res: InitPromptResult = self.proc_result()?;
+        let vocab_size = self.store.data().globals.tokrx_info.vocab_size;
+        if let Some(t) = res.ff_tokens.iter().find(|t| **t >= vocab_size) {
+            bail_user!("init_prompt: invalid token {t} (vocab size {vocab_size})");
+        }
+
+        Ok(res)
     }
 
-    pub fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult {
+    pub fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult<InitPromptResult> {
         let t0 = Instant::now();
-        match self.setup_inner(prompt) {
-            Err(err) => self.seq_result("setup", t0, Err(err)),
-            Ok(()) => self.seq_result("setup", t0, Ok(Some(()))),
-        }
+        let res = self.setup_inner(prompt).map(Some);
+        self.seq_result("setup", t0, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module
+    //   (func (export "spin") (loop (br 0)))
+    //   (func (export "nop")))
+    const SPIN_WASM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // types: () -> ()
+        0x03, 0x03, 0x02, 0x00, 0x00, // functions
+        0x07, 0x0e, 0x02, // exports
+        0x04, b's', b'p', b'i', b'n', 0x00, 0x00, // "spin"
+        0x03, b'n', b'o', b'p', 0x00, 0x01, // "nop"
+        0x0a, 0x0c, 0x02, // code
+        0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // loop (br 0) end
+        0x02, 0x00, 0x0b, // end
+    ];
+
+    #[test]
+    fn spinning_controllers_are_interrupted() {
+        let engine = wasmtime::Engine::new(&engine_config()).unwrap();
+        let module = wasmtime::Module::new(&engine, SPIN_WASM).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        set_deadline(&mut store, None);
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let spin = instance
+            .get_typed_func::<(), ()>(&mut store, "spin")
+            .unwrap();
+        let nop = instance
+            .get_typed_func::<(), ()>(&mut store, "nop")
+            .unwrap();
+
+        let budget = Duration::from_millis(50);
+        let t0 = Instant::now();
+        set_deadline(&mut store, Some(t0 + budget));
+        let err = spin.call(&mut store, ()).unwrap_err();
+        assert!(is_interrupt(&err), "{err:?}");
+        let elapsed = t0.elapsed();
+        assert!(elapsed >= budget, "{elapsed:?}");
+        assert!(elapsed < budget * 20, "{elapsed:?}");
+
+        // calls within the budget go through
+        set_deadline(&mut store, Some(Instant::now() + budget));
+        nop.call(&mut store, ()).unwrap();
+
+        // a deadline in the past interrupts right away
+        let t0 = Instant::now();
+        set_deadline(&mut store, Some(t0));
+        assert!(is_interrupt(&spin.call(&mut store, ()).unwrap_err()));
+        assert!(t0.elapsed() < budget * 20);
     }
 }
@@ -31,6 +31,14 @@ impl Semaphore {
         Ok(Self { sem })
     }
 
+    /// Remove the name; processes that have the semaphore open (or inherit it) can still use it.
+    pub fn unlink(name: &str) {
+        let c_name = CString::new(name).unwrap();
+        unsafe {
+            libc::sem_unlink(c_name.as_ptr());
+        };
+    }
+
     pub fn wait(&self) -> Result<()> {
         let ret = unsafe { libc::sem_wait(self.sem) };
         if ret < 0 {
@@ -2,18 +2,22 @@ use crate::{
     api::ModuleInstId,
     hostimpl::AiciLimits,
     moduleinstance::{ModuleInstance, WasmContext},
+    semaphore::Semaphore,
     setup_bg_worker_pool,
     shm::Shm,
     InstantiateReq, UserError,
 };
-use aici_abi::{MidProcessArg, ProcessResultOffset, StorageCmd, StorageResp, TokenId};
+use aici_abi::{
+    toktree::TokTrie, variables::Variables, InitPromptResult, MidProcessArg, PostSampleArg,
+    PostSampleResult, ProcessResultOffset, StorageCmd, StorageResp, TokenId,
+};
 use aicirt::{
     api::SequenceResult,
+    biascache::ShmBiasCache,
     futexshm::{TypedClient, TypedClientHandle, TypedServer},
     set_max_priority,
     shm::{ShmAllocator, Unlink},
     user_error,
-    variables::Variables,
 };
 use anyhow::{anyhow, Result};
 use libc::pid_t;
@@ -111,6 +115,7 @@ type ForkerHandle = ProcessHandle<ForkerCmd, ForkerResp>;
 type SeqHandle = ProcessHandle<SeqCmd, SeqResp>;
 type WireSeqHandle = WireProcessHandle<SeqCmd, SeqResp>;
 pub type GroupHandle = ProcessHandle<GroupCmd, GroupResp>;
+type GlobalVarsHandle = ProcessHandle<StorageCmd, StorageResp>;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ForkerCmd {
@@ -144,6 +149,9 @@ enum SeqCmd {
     MidProcess {
         data: RtMidProcessArg,
     },
+    PostSample {
+        arg: PostSampleArg,
+    },
     RunMain {},
     Compile {
         wasm: Vec<u8>,
@@ -157,6 +165,7 @@ impl SeqCmd {
             SeqCmd::Fork { .. } => "fork",
             SeqCmd::SetId { .. } => "set_id",
             SeqCmd::MidProcess { .. } => "process",
+            SeqCmd::PostSample { .. } => "post_sample",
             SeqCmd::RunMain {} => "run_main",
             SeqCmd::Compile { .. } => "compile",
         }
@@ -170,6 +179,7 @@ enum SeqResp {
     InitPrompt { json: String },
     PostPreProcess { post_json: String, pre_json: String },
     MidProcess { json: String },
+    PostSample { json: String },
     Compile { binary: Vec<u8> },
     Error { msg: String, is_user_error: bool },
 }
@@ -301,6 +311,15 @@ impl SeqHandle {
     }
 }
 
+/// Text prompts are tokenized after the BOS token of the tokenizer, if it has one.
+fn prompt_with_bos(trie: &TokTrie, prompt: &str) -> String {
+    match trie.bos_token() {
+        None => prompt.to_string(),
+        Some((_, bos)) if prompt.is_empty() => bos.to_string(),
+        Some((_, bos)) => format!("{bos} {prompt}"),
+    }
+}
+
 fn ok() -> Result<SeqResp> {
     Ok(SeqResp::Ok {})
 }
@@ -343,7 +362,6 @@ impl SeqCtx {
                 prompt_toks,
             } => {
                 let module = self.wasm_ctx.deserialize_module(module_path).unwrap();
-                let _ = module_id;
                 let ch = std::mem::take(&mut self.query);
                 let mut inst = ModuleInstance::new(
                     424242,
@@ -353,16 +371,17 @@ impl SeqCtx {
                     ch.unwrap(),
                     self.shm.clone(),
                 )?;
+                if let Some(cache) = &self.bias_cache {
+                    inst.set_bias_cache(cache.clone(), &module_id);
+                }
                 let prompt_toks = if let Some(t) = prompt_toks {
                     t
                 } else {
                     // TODO llama hack (doesn't apply in rllm)
-                    let ps = &prompt_str.as_ref().unwrap();
-                    let p = if ps.len() == 0 {
-                        "<s>".to_string()
-                    } else {
-                        "<s> ".to_string() + ps
-                    };
+                    let p = prompt_with_bos(
+                        &self.wasm_ctx.globals.tok_trie,
+                        prompt_str.as_ref().unwrap(),
+                    );
                     inst.tokenize(&p)?
                 };
                 self.modinst = Some(inst);
@@ -382,6 +401,12 @@ impl SeqCtx {
                     json: serde_json::to_string(&res)?,
                 })
             }
+            SeqCmd::PostSample { arg } => {
+                let res = self.mutinst().post_sample(arg);
+                Ok(SeqResp::PostSample {
+                    json: serde_json::to_string(&res)?,
+                })
+            }
             SeqCmd::RunMain {} => {
                 self.mutinst().run_main()?;
                 ok()
@@ -428,6 +453,7 @@ impl SeqCtx {
 
 struct GroupCtx {
     variables: Variables,
+    global_vars: Option<Rc<SharedVariables>>,
     server: TypedServer<GroupCmd, GroupResp>,
     limits: AiciLimits,
 }
@@ -441,6 +467,22 @@ struct SeqCtx {
     inst_id: ModuleInstId,
     modinst: Option<ModuleInstance>,
     shm: Rc<ShmAllocator>,
+    bias_cache: Option<Rc<ShmBiasCache>>,
+    global_vars: Option<Rc<SharedVariables>>,
+}
+
+/// Client of the process keeping the global variables (see aici_abi::GLOBAL_PREFIX),
+/// shared by the group processes of all requests (they inherit it from the forker).
+pub struct SharedVariables {
+    handle: GlobalVarsHandle,
+    // there is room for one request in the channel, so the group processes take turns
+    lock: Semaphore,
+}
+
+struct GlobalVarsCtx {
+    variables: Variables,
+    server: TypedServer<StorageCmd, StorageResp>,
+    limits: AiciLimits,
 }
 
 pub struct SeqWorkerHandle {
@@ -499,11 +541,30 @@ impl SeqWorkerHandle {
             Err(e) => Err(e.into()),
         }
     }
+
+    pub fn start_post_sample(&self, arg: PostSampleArg) -> Result<()> {
+        self.handle.just_send(SeqCmd::PostSample { arg })?;
+        Ok(())
+    }
+
+    pub fn check_post_sample(&self, timeout: Duration) -> Result<SequenceResult<PostSampleResult>> {
+        match self
+            .handle
+            .seq_recv_with_timeout("r-post_sample", Timeout::Strict(timeout))
+        {
+            Ok(SeqResp::PostSample { json }) => Ok(serde_json::from_str(&json)?),
+            Ok(r) => Err(anyhow!("unexpected response (post_sample) {r:?}")),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 impl GroupCtx {
     fn dispatch_storage_cmd(&mut self, cmd: StorageCmd) -> StorageResp {
-        self.variables.process_cmd(cmd)
+        match &self.global_vars {
+            Some(global_vars) if cmd.is_global() => global_vars.storage_cmd(cmd),
+            _ => self.variables.process_cmd(cmd),
+        }
     }
 
     fn dispatch_cmd(&mut self, cmd: GroupCmd) -> GroupResp {
@@ -523,6 +584,79 @@ impl GroupCtx {
     }
 }
 
+impl SharedVariables {
+    /// Fork the process of the global variables, unless they are disabled;
+    /// without it, the group processes treat them as the variables of the request.
+    fn fork(limits: &AiciLimits) -> Option<Self> {
+        if limits.max_global_storage_bytes == 0 || !cfg!(target_os = "linux") {
+            return None;
+        }
+        let lock = new_lock("/aicig-");
+        match fork_child(limits).unwrap() {
+            ForkResult::Parent { handle } => Some(SharedVariables {
+                handle: handle.to_client(),
+                lock,
+            }),
+            ForkResult::Child { server } => {
+                set_max_priority();
+                let mut ctx = GlobalVarsCtx {
+                    variables: Variables::new(limits.max_global_storage_bytes),
+                    server,
+                    limits: limits.clone(),
+                };
+                ctx.dispatch_loop()
+            }
+        }
+    }
+
+    fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp {
+        self.lock.wait().unwrap();
+        let r = self.handle.send_cmd(cmd);
+        self.lock.post().unwrap();
+        r.unwrap()
+    }
+}
+
+impl GlobalVarsCtx {
+    fn dispatch_loop(&mut self) -> ! {
+        loop {
+            let cmd = self.server.recv_req(self.limits.busy_wait_duration);
+            let resp = self.variables.process_cmd(cmd);
+            self.server.send_resp(resp);
+        }
+    }
+}
+
+// a semaphore shared by the processes forked after this (for SharedVariables etc.)
+fn new_lock(prefix: &str) -> Semaphore {
+    let name = format!(
+        "{prefix}{}",
+        uuid::Uuid::new_v4().to_string().replace('-', "")
+    );
+    // 31 is max length for semaphore name on macos
+    let name = name[0..31].to_string();
+    let lock = Semaphore::new(&name, 1, true).unwrap();
+    Semaphore::unlink(&name);
+    lock
+}
+
+/// The cache is mapped here, before the seq workers are forked, so that they all share it.
+fn new_bias_cache(wasm_ctx: &WasmContext) -> Option<ShmBiasCache> {
+    let size = wasm_ctx.limits.bias_cache_bytes;
+    if size == 0 {
+        return None;
+    }
+    // a token set, and a bit of a header
+    let max_value_size = wasm_ctx.globals.tokrx_info.vocab_size as usize / 8 + 1024;
+    match ShmBiasCache::new(size, max_value_size) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            log::warn!("bias cache disabled: {e}");
+            None
+        }
+    }
+}
+
 pub struct WorkerForker {
     limits: AiciLimits,
     fork_worker: ForkerHandle,
@@ -533,6 +667,10 @@ fn forker_dispatcher(
     wasm_ctx: WasmContext,
     shm: Rc<ShmAllocator>,
 ) -> ! {
+    // one for all requests, so that sequences running the same grammar share token sets
+    let bias_cache = new_bias_cache(&wasm_ctx).map(Rc::new);
+    let global_vars = SharedVariables::fork(&wasm_ctx.limits).map(Rc::new);
+
     loop {
         // wait for any children that might have exited to prevent zombies
         loop {
@@ -570,6 +708,8 @@ fn forker_dispatcher(
                     query: None,
                     inst_id: 424242,
                     modinst: None,
+                    bias_cache,
+                    global_vars,
                 };
 
                 if for_compile {
@@ -589,7 +729,8 @@ fn forker_dispatcher(
                     ForkResult::Child { server } => {
                         set_max_priority();
                         let mut grp_ctx = GroupCtx {
-                            variables: Variables::default(),
+                            variables: Variables::new(w_ctx.wasm_ctx.limits.max_storage_bytes),
+                            global_vars: w_ctx.global_vars.clone(),
                             server,
                             limits: w_ctx.wasm_ctx.limits,
                         };
@@ -662,7 +803,7 @@ impl WorkerForker {
         &self,
         req: InstantiateReq,
         module_path: PathBuf,
-    ) -> Result<(SeqWorkerHandle, SequenceResult<()>)> {
+    ) -> Result<(SeqWorkerHandle, SequenceResult<InitPromptResult>)> {
         let module_arg = match req.module_arg.as_str() {
             Some(a) => a.to_string(),
             None => serde_json::to_string(&req.module_arg)?,
@@ -708,7 +849,7 @@ impl WorkerForker {
             Timeout::from_millis(self.limits.max_init_ms),
         )? {
             SeqResp::InitPrompt { json } => {
-                let r: SequenceResult<()> = serde_json::from_str(&json)?;
+                let r: SequenceResult<InitPromptResult> = serde_json::from_str(&json)?;
                 Ok((res, r))
             }
             r => Err(anyhow!("unexpected response (init prompt) {r:?}")),
@@ -736,3 +877,36 @@ impl WorkerForker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aici_abi::bytes::TokRxInfo;
+
+    fn trie(special: &[(&str, TokenId)]) -> TokTrie {
+        let mut tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        tokens.extend([vec![], vec![]]);
+        let info = TokRxInfo {
+            vocab_size: tokens.len() as u32,
+            tok_eos: 256,
+        };
+        let special: Vec<(String, TokenId)> =
+            special.iter().map(|(n, t)| (n.to_string(), *t)).collect();
+        TokTrie::from(&info, &tokens).with_special_tokens(special.iter().map(|(n, t)| (n, t)))
+    }
+
+    #[test]
+    fn prompts_start_with_the_tokenizer_bos() {
+        let llama = trie(&[("<s>", 257)]);
+        assert_eq!(prompt_with_bos(&llama, ""), "<s>");
+        assert_eq!(prompt_with_bos(&llama, "Hello"), "<s> Hello");
+
+        let llama3 = trie(&[("<|begin_of_text|>", 257)]);
+        assert_eq!(prompt_with_bos(&llama3, "Hello"), "<|begin_of_text|> Hello");
+
+        // no BOS, nothing to add
+        let gpt2 = trie(&[("<|endoftext|>", 256)]);
+        assert_eq!(prompt_with_bos(&gpt2, "Hello"), "Hello");
+        assert_eq!(prompt_with_bos(&gpt2, ""), "");
+    }
+}
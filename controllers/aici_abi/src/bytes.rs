@@ -1,4 +1,8 @@
-use std::{mem::size_of, slice::from_raw_parts};
+use std::{
+    mem::size_of,
+    ops::{BitAnd, BitOr, BitOrAssign, Not, RangeInclusive},
+    slice::from_raw_parts,
+};
 
 use anyhow::{anyhow, Result};
 
@@ -85,3 +89,212 @@ pub fn from_hex_string(s: &str) -> Result<Vec<u8>> {
     }
     Ok(result)
 }
+
+/// Set of bytes, e.g., the ones allowed by a recognizer in some state.
+/// The constructors are const, so sets can be precomputed:
+/// `const DIGITS: ByteSet = ByteSet::from_range(b'0'..=b'9');`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    pub const fn new() -> Self {
+        ByteSet([0; 4])
+    }
+
+    pub const fn full() -> Self {
+        ByteSet([u64::MAX; 4])
+    }
+
+    pub const fn from_byte(b: u8) -> Self {
+        Self::new().with(b)
+    }
+
+    pub const fn from_range(range: RangeInclusive<u8>) -> Self {
+        let mut r = Self::new();
+        let mut b = *range.start() as usize;
+        while b <= *range.end() as usize {
+            r = r.with(b as u8);
+            b += 1;
+        }
+        r
+    }
+
+    /// The bytes of `s` (not its characters).
+    #[allow(clippy::should_implement_trait)]
+    pub const fn from_str(s: &str) -> Self {
+        Self::from_bytes(s.as_bytes())
+    }
+
+    pub const fn from_bytes(bytes: &[u8]) -> Self {
+        let mut r = Self::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            r = r.with(bytes[i]);
+            i += 1;
+        }
+        r
+    }
+
+    /// The set with `b` added.
+    pub const fn with(self, b: u8) -> Self {
+        let mut r = self;
+        r.0[b as usize / 64] |= 1 << (b % 64);
+        r
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        let (a, b) = (self.0, other.0);
+        ByteSet([a[0] | b[0], a[1] | b[1], a[2] | b[2], a[3] | b[3]])
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        let (a, b) = (self.0, other.0);
+        ByteSet([a[0] & b[0], a[1] & b[1], a[2] & b[2], a[3] & b[3]])
+    }
+
+    /// All the bytes not in the set.
+    pub const fn negate(self) -> Self {
+        let a = self.0;
+        ByteSet([!a[0], !a[1], !a[2], !a[3]])
+    }
+
+    #[inline(always)]
+    pub const fn contains(&self, b: u8) -> bool {
+        self.0[b as usize / 64] & (1 << (b % 64)) != 0
+    }
+
+    pub fn insert(&mut self, b: u8) {
+        *self = self.with(b);
+    }
+
+    pub fn remove(&mut self, b: u8) {
+        self.0[b as usize / 64] &= !(1 << (b % 64));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|w| *w == 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(|b| self.contains(*b))
+    }
+
+    /// The smallest byte in the set.
+    pub fn first_byte(&self) -> Option<u8> {
+        let i = self.0.iter().position(|w| *w != 0)?;
+        Some((i * 64) as u8 + self.0[i].trailing_zeros() as u8)
+    }
+
+    /// The byte, if it's the only one in the set.
+    pub fn single_byte(&self) -> Option<u8> {
+        if self.len() == 1 {
+            self.first_byte()
+        } else {
+            None
+        }
+    }
+
+    /// The set of bytes for which `f` returns true.
+    pub fn from_fn(mut f: impl FnMut(u8) -> bool) -> Self {
+        let mut r = Self::new();
+        for b in 0..=255u8 {
+            if f(b) {
+                r.insert(b);
+            }
+        }
+        r
+    }
+
+    /// Write the set in the format of Recognizer::allowed_byte_mask().
+    pub fn fill_mask(&self, mask_out: &mut [bool; 256]) {
+        for b in 0..=255u8 {
+            mask_out[b as usize] = self.contains(b);
+        }
+    }
+}
+
+impl BitOr for ByteSet {
+    type Output = ByteSet;
+    fn bitor(self, other: ByteSet) -> ByteSet {
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for ByteSet {
+    fn bitor_assign(&mut self, other: ByteSet) {
+        *self = self.union(other);
+    }
+}
+
+impl FromIterator<ByteSet> for ByteSet {
+    /// The union of the sets.
+    fn from_iter<I: IntoIterator<Item = ByteSet>>(iter: I) -> Self {
+        iter.into_iter().fold(Self::new(), Self::union)
+    }
+}
+
+impl BitAnd for ByteSet {
+    type Output = ByteSet;
+    fn bitand(self, other: ByteSet) -> ByteSet {
+        self.intersection(other)
+    }
+}
+
+impl Not for ByteSet {
+    type Output = ByteSet;
+    fn not(self) -> ByteSet {
+        self.negate()
+    }
+}
+
+impl std::fmt::Debug for ByteSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes: String = self.iter().map(|b| b.escape_ascii().to_string()).collect();
+        write!(f, "ByteSet(\"{bytes}\")")
+    }
+}
+
+/// Ranges of bytes, like `a-z;_;x80-xff`.
+impl std::fmt::Display for ByteSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut start = None;
+        let mut first = true;
+        for i in 0u32..=256 {
+            if i <= 0xff && self.contains(i as u8) {
+                if start.is_none() {
+                    start = Some(i);
+                }
+            } else {
+                if let Some(start) = start {
+                    if !first {
+                        write!(f, ";")?;
+                    }
+                    first = false;
+                    write!(f, "{}", byte_to_string(start as u8))?;
+                    if i - start > 1 {
+                        write!(f, "-{}", byte_to_string((i - 1) as u8))?;
+                    }
+                }
+                start = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The byte as an identifier character, a quoted character, or `xNN`.
+pub fn byte_to_string(b: u8) -> String {
+    if b >= 0x7f {
+        format!("x{:02x}", b)
+    } else {
+        let b = b as char;
+        match b {
+            '_' | 'a'..='z' | 'A'..='Z' | '0'..='9' => format!("{}", b),
+            _ => format!("{:?}", b),
+        }
+    }
+}
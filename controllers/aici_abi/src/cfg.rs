@@ -514,7 +514,7 @@ pub fn cfg_test() -> Result<()> {
 
         let mut line = 1;
         let mut vob = SimpleVob::new();
-        vob.resize(trie.vocab_size() + 1);
+        vob.resize(trie.vocab_size() + 1, false);
 
         for tok in &toks[0..1000] {
             let tok = *tok;
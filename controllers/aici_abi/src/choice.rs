@@ -0,0 +1,111 @@
+//! Picking one of a fixed set of strings (e.g., classification labels) with as few
+//! sampled tokens as possible: any token that is a prefix of an option can be sampled,
+//! and the option is known as soon as only one of them is consistent with the tokens.
+
+use crate::{svob::SimpleVob, toktree::TokTrie, TokenId};
+
+/// Which options are still consistent with the tokens sampled so far;
+/// created with choice_bias().
+#[derive(Debug, Clone)]
+pub struct ChoiceTable {
+    options: Vec<Vec<u8>>,
+    // bytes of the tokens passed to commit_token()
+    prefix: Vec<u8>,
+    // indexes of options that start with `prefix`
+    candidates: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ChoiceStep {
+    /// The tokens so far commit to the option at this index;
+    /// see ChoiceTable::remaining() for the rest of it.
+    Chosen(usize),
+    /// Several options share the tokens so far; sample one of these tokens next.
+    Continue(SimpleVob),
+    /// The token doesn't continue any of the options.
+    Invalid,
+}
+
+/// Tokens allowed for the first step of choosing one of `options`, and the table
+/// for resolving the option from the sampled tokens.
+/// Options are matched byte-wise, so they should include the leading space
+/// when they follow a word (" positive" rather than "positive").
+pub fn choice_bias(trie: &TokTrie, options: &[&str]) -> (SimpleVob, ChoiceTable) {
+    let table = ChoiceTable {
+        options: options.iter().map(|o| o.as_bytes().to_vec()).collect(),
+        prefix: Vec::new(),
+        candidates: (0..options.len()).collect(),
+    };
+    (table.allowed_tokens(trie), table)
+}
+
+impl ChoiceTable {
+    pub fn options(&self) -> &[Vec<u8>] {
+        &self.options
+    }
+
+    /// Indexes of the options consistent with the tokens so far.
+    pub fn candidates(&self) -> &[usize] {
+        &self.candidates
+    }
+
+    /// Bytes of option `idx` after the tokens so far (to be forced, or dropped when
+    /// the option alone is enough).
+    pub fn remaining(&self, idx: usize) -> &[u8] {
+        let opt = &self.options[idx];
+        &opt[std::cmp::min(self.prefix.len(), opt.len())..]
+    }
+
+    /// Tokens continuing any of the candidates; EOS is allowed when one of them
+    /// is already complete, but others continue past it.
+    pub fn allowed_tokens(&self, trie: &TokTrie) -> SimpleVob {
+        let mut toks = trie.alloc_token_set();
+        for &idx in &self.candidates {
+            let rest = self.remaining(idx);
+            if rest.is_empty() {
+                toks.allow_token(trie.eos_token());
+                continue;
+            }
+            // every token along the path of the rest of the option in the trie
+            let mut n = trie.root();
+            for &b in rest {
+                n = match trie.child_at_byte(n, b) {
+                    Some(n) => n,
+                    None => break,
+                };
+                if let Some(tok) = n.token_id() {
+                    toks.allow_token(tok);
+                }
+            }
+        }
+        trie.apply_duplicates(&mut toks);
+        toks
+    }
+
+    /// Record the sampled token, and resolve the option it commits to
+    /// (or the tokens to sample next, when that's not yet clear).
+    pub fn commit_token(&mut self, trie: &TokTrie, tok: TokenId) -> ChoiceStep {
+        if tok == trie.eos_token() {
+            let complete = self
+                .candidates
+                .iter()
+                .find(|&&i| self.remaining(i).is_empty());
+            return match complete {
+                Some(&idx) => {
+                    self.candidates = vec![idx];
+                    ChoiceStep::Chosen(idx)
+                }
+                None => ChoiceStep::Invalid,
+            };
+        }
+        self.prefix.extend_from_slice(trie.token(tok));
+        let options = &self.options;
+        let prefix = &self.prefix;
+        self.candidates.retain(|&i| options[i].starts_with(prefix));
+        match self.candidates.len() {
+            0 => ChoiceStep::Invalid,
+            1 => ChoiceStep::Chosen(self.candidates[0]),
+            _ => ChoiceStep::Continue(self.allowed_tokens(trie)),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{QuotaExceeded, StorageError};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Failure of a controller call; see TryAiciCtrl.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// The argument from the host couldn't be parsed, or the result couldn't be serialized.
+    Json(String),
+    /// A call to the host failed.
+    Host(String),
+    /// Reported by the controller, e.g., when the grammar rejects the output.
+    Controller(String),
+    /// The controller panicked.
+    Panic(String),
+    /// The host lacks something the controller needs; see require_capabilities().
+    Unsupported(String),
+}
+
+impl Error {
+    pub fn controller(msg: impl Into<String>) -> Self {
+        Error::Controller(msg.into())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Json(msg) => write!(f, "JSON error: {msg}"),
+            Error::Host(msg) => write!(f, "host call failed: {msg}"),
+            Error::Controller(msg) => write!(f, "{msg}"),
+            Error::Panic(msg) => write!(f, "panic: {msg}"),
+            Error::Unsupported(msg) => write!(f, "unsupported by the host: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e.to_string())
+    }
+}
+
+impl From<QuotaExceeded> for Error {
+    fn from(e: QuotaExceeded) -> Self {
+        Error::Host(e.to_string())
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Self {
+        Error::Host(e.to_string())
+    }
+}
+
+/// Passed to the host (in place of the regular result) when a controller call fails.
+/// The host then stops the sequence, with the message as the error.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResult {
+    pub message: String,
+    /// The failing call: "init_prompt" or "mid_process".
+    pub phase: String,
+}
+
+// ErrorResult as passed to the host, with "type": "error"; without the tag, other
+// results could pass for an ErrorResult, as unknown fields are ignored
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TaggedResult {
+    Error(ErrorResult),
+}
+
+impl ErrorResult {
+    /// The bytes to pass to the host in place of the result.
+    pub fn to_result_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&TaggedResult::Error(self.clone()))
+            .expect("failed to serialize ErrorResult")
+    }
+
+    /// The error in the result bytes from the controller, unless it's a regular result.
+    pub fn from_result_bytes(bytes: &[u8]) -> Option<Self> {
+        match serde_json::from_slice(bytes) {
+            Ok(TaggedResult::Error(err)) => Some(err),
+            Err(_) => None,
+        }
+    }
+}
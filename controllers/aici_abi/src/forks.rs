@@ -0,0 +1,153 @@
+use aici_abi::{tokenize, toktree::TokTrie, AiciCtrl, MidProcessArg, MidProcessResult, TokenId};
+
+// Answer the prompt twice, in parallel: once formally and once casually.
+const STYLES: [&str; 2] = ["\nAnswer formally:\n", "\nAnswer casually:\n"];
+
+#[derive(Clone)]
+pub struct Runner {
+    toktrie: TokTrie,
+    tokens: Vec<TokenId>,
+    forked: bool,
+    // index into STYLES, once known
+    branch: Option<usize>,
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Runner {
+            toktrie: TokTrie::from_host(),
+            tokens: Vec::new(),
+            forked: false,
+            branch: None,
+        }
+    }
+}
+
+impl AiciCtrl for Runner {
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        arg.save_tokens(&mut self.tokens);
+        if !self.forked {
+            // one branch per style; each gets its instructions appended,
+            // and is told the style index
+            self.forked = true;
+            let mut res =
+                MidProcessResult::fork_splices(STYLES.iter().map(|s| tokenize(s)).collect());
+            for (idx, b) in res.branches.iter_mut().enumerate() {
+                b.fork_arg = vec![idx as u8];
+            }
+            return res;
+        }
+        if self.branch.is_none() {
+            self.branch = arg.fork_arg.first().map(|idx| *idx as usize);
+        }
+
+        if self.tokens.len() > 50 || arg.has_eos() {
+            return MidProcessResult::stop();
+        }
+        let mut set = self.toktrie.alloc_token_set();
+        set.set_all(true);
+        MidProcessResult::sample(set)
+    }
+}
+
+fn main() {
+    // test code here?
+}
+
+aici_abi::aici_expose_all!(Runner, Runner::new());
+
+#[cfg(test)]
+mod tests {
+    use super::{Runner, STYLES};
+    use aici_abi::{
+        testing::{run_forking_script, MockHost, MockTokenizerEnv, Phase},
+        tokenize, AiciCtrl, MidProcessArg, MidProcessResult, SeqId, TokenId, TokenizerEnv,
+    };
+    use std::cell::RefCell;
+
+    fn arg(
+        env: &MockTokenizerEnv,
+        tokens: Vec<TokenId>,
+        fork_group: Vec<SeqId>,
+        fork_arg: Vec<u8>,
+    ) -> MidProcessArg {
+        MidProcessArg {
+            backtrack: 0,
+            byte_offset: env.decode_tokens(&tokens).len() as u64,
+            tokens,
+            fork_group,
+            fork_arg,
+            prev_timed_out: false,
+            forced_byte_prefix: vec![],
+            context_truncation: None,
+            top_logprobs: vec![],
+        }
+    }
+
+    #[test]
+    fn branches_start_with_their_tokens() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let script = vec![
+            Phase::Prompt("Hello".to_string()),
+            Phase::Generate {
+                prefer: String::new(),
+                max_tokens: 3,
+            },
+        ];
+        let transcripts = run_forking_script(Runner::new(), script);
+        assert_eq!(transcripts.len(), STYLES.len());
+        for (tr, style) in transcripts.iter().zip(STYLES) {
+            let ff_tokens = tokenize(style);
+            assert!(tr.output().starts_with(&ff_tokens), "{style:?}");
+            // the branch samples after its instructions
+            assert_eq!(tr.output().len(), ff_tokens.len() + 3);
+            assert!(!tr.stopped);
+        }
+    }
+
+    #[test]
+    fn branches_know_their_style() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        // stop right after the fork, and check where each copy of the controller ended up
+        let script = vec![
+            Phase::Prompt("Hello".to_string()),
+            Phase::Generate {
+                prefer: String::new(),
+                max_tokens: 1,
+            },
+        ];
+        let transcripts = run_forking_script(Tracked(Runner::new()), script);
+        let branches = BRANCHES.with(|b| b.take());
+        assert_eq!(transcripts.len(), STYLES.len());
+        assert_eq!(branches, [Some(0), Some(1)]);
+    }
+
+    // records the branch of each copy of Runner after its first step on it
+    #[derive(Clone)]
+    struct Tracked(Runner);
+
+    thread_local! {
+        static BRANCHES: RefCell<Vec<Option<usize>>> = RefCell::default();
+    }
+
+    impl AiciCtrl for Tracked {
+        fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+            let res = self.0.mid_process(arg);
+            if self.0.forked && res.branches.len() == 1 {
+                BRANCHES.with(|b| b.borrow_mut().push(self.0.branch));
+            }
+            res
+        }
+    }
+
+    #[test]
+    fn fork_index_outside_the_group() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        MockHost::set_self_seq_id(7);
+        let arg = arg(&env, vec![], vec![SeqId(0), SeqId(1)], vec![]);
+        assert!(arg.fork_index().is_err());
+    }
+}
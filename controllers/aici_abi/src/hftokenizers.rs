@@ -0,0 +1,174 @@
+//! Bytes of the tokens of HuggingFace tokenizers (not for wasm controllers,
+//! which get the TokTrie from the host).
+
+use crate::{bytes::TokRxInfo, toktree::TokTrie, TokenId};
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use tokenizers::Tokenizer;
+
+/// Special tokens that end generation in common models, most likely first.
+pub const EOS_TOKENS: &[&str] = &[
+    "</s>",
+    "<|endoftext|>",
+    "<|end_of_text|>",
+    "<|eot_id|>",
+    "<|im_end|>",
+    "<eos>",
+];
+
+/// The tokens of a tokenizer, as bytes.
+pub struct HfTokenBytes {
+    /// Bytes of every token; empty for special tokens.
+    pub token_bytes: Vec<Vec<u8>>,
+    /// The special (added) tokens, by name.
+    pub special: BTreeMap<String, TokenId>,
+    /// The first of EOS_TOKENS the tokenizer has (or 0).
+    pub eos_token: TokenId,
+    /// "byte_level" or "byte_fallback"; see RuntimeInfo::tokenizer.
+    pub scheme: &'static str,
+}
+
+impl HfTokenBytes {
+    pub fn tokrx_info(&self) -> TokRxInfo {
+        TokRxInfo {
+            vocab_size: self.token_bytes.len() as u32,
+            tok_eos: self.eos_token,
+        }
+    }
+
+    pub fn to_toktrie(&self) -> TokTrie {
+        TokTrie::from(&self.tokrx_info(), &self.token_bytes).with_special_tokens(&self.special)
+    }
+}
+
+impl TokTrie {
+    /// Build the trie of a tokenizer; special tokens decode to empty byte strings
+    /// (and are marked as special), and byte-fallback tokens (like <0x0A>)
+    /// to the byte they represent.
+    pub fn from_tokenizer(tok: &Tokenizer) -> Result<TokTrie> {
+        Ok(token_bytes(tok)?.to_toktrie())
+    }
+}
+
+// useful when debugging this: https://www.cogsci.ed.ac.uk/~richard/utf-8.cgi
+
+fn is_self_mapped(c: char) -> bool {
+    matches!(c, '!'..='~' | '\u{00A1}'..='\u{00AC}' | '\u{00AE}'..='\u{00FF}')
+}
+
+/// The characters byte-level tokenizers (like GPT-2's) use for the bytes.
+fn build_char_map() -> HashMap<char, u8> {
+    let mut res = HashMap::new();
+    let mut k = 0x100u32;
+    for byte in 0..=255u8 {
+        let c = byte as char;
+        if is_self_mapped(c) {
+            res.insert(c, byte);
+        } else {
+            res.insert(char::from_u32(k).unwrap(), byte);
+            k += 1;
+        }
+    }
+    res
+}
+
+/// Bytes of the tokens of the tokenizer, which has to be byte-level or use byte fallback.
+pub fn token_bytes(hft: &Tokenizer) -> Result<HfTokenBytes> {
+    let mut is_byte_level = false;
+    let mut is_byte_fallback = false;
+    let mut space_ch = ' ';
+
+    if let Some(d) = hft.get_decoder() {
+        // DecoderWrapper::Sequence() doesn't let one access the decoders
+        // so we resort to json munching
+        let v = serde_json::to_value(d)?;
+        if v["type"].as_str() == Some("ByteLevel") {
+            is_byte_level = true;
+        } else if v["type"].as_str() == Some("Sequence") {
+            if let Some(decoders) = v["decoders"].as_array() {
+                for decoder in decoders {
+                    if decoder["type"].as_str() == Some("ByteFallback") {
+                        is_byte_fallback = true;
+                    } else if decoder["type"].as_str() == Some("Replace")
+                        && decoder["content"].as_str() == Some(" ")
+                    {
+                        if let Some(s) = decoder["pattern"]["String"].as_str() {
+                            let s: Vec<char> = s.chars().collect();
+                            if s.len() == 1 {
+                                space_ch = s[0];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if !is_byte_fallback && !is_byte_level {
+        bail!("can't determine decoder type: {:?}", hft.get_decoder());
+    }
+
+    let vocab_size = hft.get_vocab_size(true) as u32;
+    let added = hft.get_added_tokens_decoder();
+
+    let mut res = HfTokenBytes {
+        token_bytes: (0..vocab_size).map(|_| Vec::new()).collect(),
+        special: BTreeMap::new(),
+        eos_token: 0,
+        scheme: if is_byte_fallback {
+            "byte_fallback"
+        } else {
+            "byte_level"
+        },
+    };
+
+    let mut eos_rank = EOS_TOKENS.len();
+    for (id, info) in added.iter() {
+        if info.special {
+            if let Some(rank) = EOS_TOKENS.iter().position(|t| *t == info.content) {
+                if rank < eos_rank {
+                    eos_rank = rank;
+                    res.eos_token = *id;
+                }
+            }
+            res.special.insert(info.content.clone(), *id);
+        } else {
+            res.token_bytes[*id as usize] = info.content.clone().into_bytes();
+        }
+    }
+
+    let char_map = build_char_map();
+
+    for tok_id in 0..vocab_size {
+        if added.contains_key(&tok_id) {
+            continue;
+        }
+        // tokens missing from the vocabulary, or with characters outside of the byte
+        // mapping, are left empty
+        let Some(tok_name) = hft.id_to_token(tok_id) else {
+            continue;
+        };
+        if is_byte_fallback {
+            if tok_name.len() == 6 && tok_name.starts_with("<0x") && tok_name.ends_with('>') {
+                let byte = u8::from_str_radix(&tok_name[3..5], 16)
+                    .map_err(|_| anyhow!("invalid byte token {tok_name}"))?;
+                res.token_bytes[tok_id as usize] = vec![byte];
+            } else if tok_name.starts_with("<0x") {
+                bail!("invalid byte token {tok_name}");
+            } else {
+                let tok_name = tok_name.replace(space_ch, " ");
+                res.token_bytes[tok_id as usize] = tok_name.into_bytes();
+            }
+        } else {
+            let bytes: Option<Vec<u8>> = tok_name
+                .chars()
+                .map(|c| char_map.get(&c).copied())
+                .collect();
+            if let Some(bytes) = bytes {
+                res.token_bytes[tok_id as usize] = bytes;
+            }
+        }
+    }
+
+    Ok(res)
+}
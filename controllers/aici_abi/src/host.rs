@@ -1,15 +1,28 @@
 use crate::{
-    bytes::{vec_from_bytes, TokenId},
-    svob::SimpleVob,
-    toktree::TokTrie,
-    SeqId,
+    bytes::TokenId,
+    recording::{self, RecordKind},
+    svob::TokenSet,
+    toktree::{TokTrie, TokenizationResult},
+    Error, SeqId,
 };
 use serde::{Deserialize, Serialize};
 
+#[cfg(target_arch = "wasm32")]
+use crate::bytes::vec_from_bytes;
+#[cfg(target_arch = "wasm32")]
+use std::cell::OnceCell;
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct BlobId(u32);
 
+impl BlobId {
+    // Not returned by any host call, so that controllers can read it on any host;
+    // hosts that predate RuntimeInfo leave it empty.
+    #[allow(dead_code)]
+    const RUNTIME_INFO: BlobId = BlobId(8);
+}
+
 #[allow(dead_code)]
 extern "C" {
     // Read binary blob.
@@ -29,15 +42,37 @@ extern "C" {
     // Tokenize given UTF8 string. The result is only valid until next call to this function.
     fn aici_host_tokenize(src: *const u8, src_size: u32) -> BlobId;
 
-    // Set logit bias based on bit-mask in src.
-    fn aici_host_return_logit_bias(src: *const u32) -> u32;
+    // Decode tokens into bytes (the result is not necessarily valid UTF-8).
+    fn aici_host_decode(src: *const u32, src_size: u32) -> BlobId;
+
+    // Set logit bias based on bit-mask in src; src_size is the mask length in bits.
+    // Traps if the mask is shorter than the model vocabulary.
+    // With TokenSet::DENY_LIST_FLAG in src_size, src is a list of denied tokens instead,
+    // and src_size (without the flag) their number; hosts with the "deny_list" config only.
+    fn aici_host_return_logit_bias(src: *const u32, src_size: u32) -> u32;
 
     fn aici_host_self_seq_id() -> u32;
 
+    // Default seed for random number generators; same for the same controller argument and prompt.
+    fn aici_host_rand_seed() -> u64;
+
+    // Microseconds left until the deadline of the current call (with millisecond resolution).
+    fn aici_host_time_left_us() -> u64;
+
     fn aici_host_return_process_result(res: *const u8, res_size: u32);
 
     fn aici_host_storage_cmd(cmd: *const u8, cmd_size: u32) -> BlobId;
 
+    // Append to the recording of the sequence; only on hosts with RuntimeInfo::record.
+    fn aici_host_append_recording(src: *const u8, src_size: u32);
+
+    // Look up a value stored with aici_host_bias_cache_put(), possibly by another sequence;
+    // the blob is empty when there is none.
+    fn aici_host_bias_cache_get(key: u64) -> BlobId;
+
+    // Store a value in the cache shared by all sequences running the module.
+    fn aici_host_bias_cache_put(key: u64, src: *const u8, src_size: u32);
+
     // This can be also obtained from the TokTrie.
     fn aici_host_eos_token() -> TokenId;
 
@@ -50,6 +85,7 @@ extern "C" {
 }
 
 // TODO: add <T>
+#[cfg(target_arch = "wasm32")]
 fn read_blob(blob: BlobId, prefetch_size: usize) -> Vec<u8> {
     let mut buffer = vec![0u8; prefetch_size];
     let prefetch_size = prefetch_size as u32;
@@ -67,6 +103,7 @@ fn init_panic() {
     std::panic::set_hook(Box::new(|info| {
         // skip 'run with `RUST_BACKTRACE=1`' message (not relevant for remote running)
         println!("{}", info);
+        crate::report_panic(info);
     }))
 }
 
@@ -74,7 +111,8 @@ fn init_panic() {
 #[no_mangle]
 pub extern "C" fn aici_init() {
     init_panic();
-    set_host(Box::new(WasmHost {}));
+    set_host(Box::new(WasmHost::default()));
+    recording::start_recording_if_requested();
 }
 
 pub trait TokenizerEnv: Send {
@@ -85,6 +123,9 @@ pub trait TokenizerEnv: Send {
     fn tokenize(&self, s: &str) -> Vec<TokenId> {
         self.tokenize_bytes(s.as_bytes())
     }
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8> {
+        self.tok_trie().decode(toks)
+    }
     fn eos_token(&self) -> TokenId {
         self.tok_trie().eos_token()
     }
@@ -114,6 +155,10 @@ impl TokenizerEnv for WasmTokenizerEnv {
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
         tokenize_bytes(s)
     }
+
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8> {
+        decode_tokens(toks)
+    }
 }
 
 /**
@@ -123,12 +168,31 @@ impl TokenizerEnv for WasmTokenizerEnv {
 pub trait HostInterface {
     fn arg_bytes(&self) -> Vec<u8>;
     fn trie_bytes(&self) -> Vec<u8>;
-    fn return_logit_bias(&self, vob: &SimpleVob) -> u32;
+    fn return_logit_bias(&self, set: &TokenSet) -> u32;
     fn process_arg_bytes(&self) -> Vec<u8>;
+    /// RuntimeInfo as JSON; the default (empty) is a host without the header.
+    fn runtime_info_bytes(&self) -> Vec<u8> {
+        vec![]
+    }
     fn return_process_result(&self, res: &[u8]);
     fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp;
+    /// See bias_cache_get(); the default is a host without the cache.
+    fn bias_cache_get(&self, _key: u64) -> Option<Vec<u8>> {
+        None
+    }
+    /// See bias_cache_put().
+    fn bias_cache_put(&self, _key: u64, _value: &[u8]) {}
+    /// See RuntimeInfo::record; the default drops the log.
+    fn append_recording(&self, _log: &[u8]) {}
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId>;
+    /// See tokenize_bytes_greedy(); the default loads the trie each time.
+    fn tokenize_bytes_greedy(&self, s: &[u8]) -> TokenizationResult {
+        TokTrie::from_bytes(&self.trie_bytes()).tokenize_bytes_greedy(s)
+    }
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8>;
     fn self_seq_id(&self) -> SeqId;
+    fn rand_seed(&self) -> u64;
+    fn time_left_us(&self) -> u64;
     fn eos_token(&self) -> TokenId;
     fn get_config(&self, name: &str) -> i32;
     fn stop(&self) -> !;
@@ -136,7 +200,14 @@ pub trait HostInterface {
 
 static mut HOST: Option<Box<dyn HostInterface>> = None;
 
-struct WasmHost {}
+#[cfg(target_arch = "wasm32")]
+#[derive(Default)]
+struct WasmHost {
+    // for tokenize_bytes_greedy(); loaded on first use
+    trie: OnceCell<TokTrie>,
+}
+
+#[cfg(target_arch = "wasm32")]
 impl HostInterface for WasmHost {
     fn arg_bytes(&self) -> Vec<u8> {
         read_blob(unsafe { aici_host_module_arg() }, 1024)
@@ -146,10 +217,18 @@ impl HostInterface for WasmHost {
         read_blob(unsafe { aici_host_token_trie() }, 0)
     }
 
-    fn return_logit_bias(&self, vob: &SimpleVob) -> u32 {
-        assert!(vob.len() > 0);
-        unsafe {
-            aici_host_return_logit_bias(vob.as_ptr())
+    fn return_logit_bias(&self, set: &TokenSet) -> u32 {
+        match set {
+            TokenSet::Allow(vob) => {
+                assert!(vob.len() > 0);
+                unsafe { aici_host_return_logit_bias(vob.as_ptr(), vob.len() as u32) }
+            }
+            TokenSet::Deny(deny) => unsafe {
+                aici_host_return_logit_bias(
+                    deny.as_ptr(),
+                    deny.len() as u32 | TokenSet::DENY_LIST_FLAG,
+                )
+            },
         }
     }
 
@@ -157,6 +236,10 @@ impl HostInterface for WasmHost {
         read_blob(unsafe { aici_host_process_arg() }, 1024)
     }
 
+    fn runtime_info_bytes(&self) -> Vec<u8> {
+        read_blob(BlobId::RUNTIME_INFO, 256)
+    }
+
     fn return_process_result(&self, res: &[u8]) {
         unsafe {
             aici_host_return_process_result(res.as_ptr(), res.len() as u32);
@@ -170,6 +253,23 @@ impl HostInterface for WasmHost {
         serde_json::from_slice(&resp_bytes).unwrap()
     }
 
+    fn bias_cache_get(&self, key: u64) -> Option<Vec<u8>> {
+        let value = read_blob(unsafe { aici_host_bias_cache_get(key) }, 8 * 1024);
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn bias_cache_put(&self, key: u64, value: &[u8]) {
+        unsafe { aici_host_bias_cache_put(key, value.as_ptr(), value.len() as u32) }
+    }
+
+    fn append_recording(&self, log: &[u8]) {
+        unsafe { aici_host_append_recording(log.as_ptr(), log.len() as u32) }
+    }
+
     fn stop(&self) -> ! {
         unsafe { aici_host_stop() };
         panic!("didn't stop")
@@ -187,10 +287,29 @@ impl HostInterface for WasmHost {
         res
     }
 
+    fn tokenize_bytes_greedy(&self, s: &[u8]) -> TokenizationResult {
+        self.trie
+            .get_or_init(|| TokTrie::from_bytes(&self.trie_bytes()))
+            .tokenize_bytes_greedy(s)
+    }
+
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8> {
+        let id = unsafe { aici_host_decode(toks.as_ptr(), toks.len() as u32) };
+        read_blob(id, 4 * toks.len() + 16)
+    }
+
     fn self_seq_id(&self) -> SeqId {
         unsafe { SeqId(aici_host_self_seq_id()) }
     }
 
+    fn rand_seed(&self) -> u64 {
+        unsafe { aici_host_rand_seed() }
+    }
+
+    fn time_left_us(&self) -> u64 {
+        unsafe { aici_host_time_left_us() }
+    }
+
     fn eos_token(&self) -> TokenId {
         unsafe { aici_host_eos_token() }
     }
@@ -214,7 +333,9 @@ pub fn set_host(host: Box<dyn HostInterface>) {
 }
 
 pub fn arg_bytes() -> Vec<u8> {
-    get_host().arg_bytes()
+    let r = get_host().arg_bytes();
+    recording::record(RecordKind::ModuleArg, || r.clone());
+    r
 
     // #[cfg(not(target_arch = "wasm32"))]
     // return std::fs::read("arg.json").unwrap();
@@ -225,27 +346,267 @@ pub fn arg_string() -> String {
 }
 
 pub fn trie_bytes() -> Vec<u8> {
-    get_host().trie_bytes()
+    let r = get_host().trie_bytes();
+    recording::record(RecordKind::TokTrie, || r.clone());
+    r
     // #[cfg(not(target_arch = "wasm32"))]
     // return std::fs::read("tokenizer.bin").unwrap();
 }
 
-pub fn return_logit_bias(vob: &SimpleVob) -> u32 {
-    get_host().return_logit_bias(vob)
+pub fn return_logit_bias(set: &TokenSet) -> u32 {
+    let r = get_host().return_logit_bias(set);
+    recording::record_logit_bias(set, r);
+    r
 }
 
 pub fn process_arg_bytes() -> Vec<u8> {
-    get_host().process_arg_bytes()
+    let r = get_host().process_arg_bytes();
+    recording::record(RecordKind::ProcessArg, || r.clone());
+    r
 }
 
 pub fn return_process_result(res: &[u8]) {
-    unsafe {
-        aici_host_return_process_result(res.as_ptr(), res.len() as u32);
-    }
+    recording::record(RecordKind::ProcessResult, || res.to_vec());
+    get_host().return_process_result(res)
 }
 
 pub fn get_config(name: &str) -> i32 {
-    get_host().get_config(name)
+    let r = get_host().get_config(name);
+    recording::record_config(name, r);
+    r
+}
+
+/// Verbosity of controller logs, set by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl LogLevel {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// The host's log level; read once, on first use.
+pub fn log_level() -> LogLevel {
+    static LOG_LEVEL: std::sync::OnceLock<LogLevel> = std::sync::OnceLock::new();
+    *LOG_LEVEL.get_or_init(|| match get_config("log_level") {
+        1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        4 => LogLevel::Debug,
+        // hosts that don't know about log levels return 0
+        _ => LogLevel::Info,
+    })
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+/// Write to the logs of the current call, if the level is enabled.
+/// The host returns them to the client with the sequence output (up to a size limit).
+/// Use wlog_info!() etc. to skip formatting when the level is disabled.
+pub fn log(level: LogLevel, msg: &str) {
+    if log_enabled(level) {
+        println!("{}", msg);
+    }
+}
+
+/// Version of the controller ABI: the host calls, and the JSON passed through them.
+/// The major version changes when existing calls or fields change their meaning,
+/// the minor version when calls, fields or capabilities are added.
+pub const ABI_MAJOR: u32 = 1;
+pub const ABI_MINOR: u32 = 4;
+
+/// Optional features of the host; see RuntimeInfo::host_calls.
+/// New capabilities are added at the end, with a new ABI_MINOR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Capability {
+    /// MidProcessResult with several branches.
+    Fork = 0,
+    /// Splices with backtrack > 0.
+    Backtrack = 1,
+    /// Splices with tokens, and InitPromptResult::ff_tokens.
+    FfTokens = 2,
+    /// Logit biases passed as lists of denied tokens (see svob::TokenSet).
+    DenyList = 3,
+    /// The cache of bias_cache_get() and bias_cache_put().
+    BiasCache = 4,
+    /// StorageCmd::ReadBatch and StorageCmd::WriteBatch (VariableStorage::transaction()).
+    StorageBatch = 5,
+    /// InitPromptResult::top_logprobs.
+    TopLogprobs = 6,
+    /// Branch::post_sample.
+    PostSample = 7,
+    /// MidProcessResult::captures are returned to the client.
+    Captures = 8,
+    /// Variables named with GLOBAL_PREFIX are shared by all requests.
+    GlobalStorage = 9,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Fork,
+        Capability::Backtrack,
+        Capability::FfTokens,
+        Capability::DenyList,
+        Capability::BiasCache,
+        Capability::StorageBatch,
+        Capability::TopLogprobs,
+        Capability::PostSample,
+        Capability::Captures,
+        Capability::GlobalStorage,
+    ];
+
+    pub fn bit(self) -> u64 {
+        1 << (self as u32)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Fork => "fork",
+            Capability::Backtrack => "backtrack",
+            Capability::FfTokens => "ff_tokens",
+            Capability::DenyList => "deny_list",
+            Capability::BiasCache => "bias_cache",
+            Capability::StorageBatch => "storage_batch",
+            Capability::TopLogprobs => "top_logprobs",
+            Capability::PostSample => "post_sample",
+            Capability::Captures => "captures",
+            Capability::GlobalStorage => "global_storage",
+        }
+    }
+}
+
+/// What the host supports, passed before any controller call; see runtime_info().
+///
+/// Compatibility between hosts and controllers built against other versions of this crate:
+/// the JSON passed either way (this, the arguments, and the results) never denies unknown
+/// fields, so fields added in a newer minor version are ignored by older readers, and new
+/// fields come with defaults for when older writers leave them out. Hence, a controller
+/// can't tell if an older host ignored a field it set; it should only rely on fields
+/// (and host calls) of a minor version when has_minor() says the host knows it,
+/// or check the capability with require_capabilities().
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeInfo {
+    pub abi_major: u32,
+    pub abi_minor: u32,
+    /// Capability::bit() of each capability the host supports; bits this version
+    /// doesn't know are from newer hosts, and are ignored.
+    pub host_calls: u64,
+    pub vocab_size: u32,
+    /// How token bytes are stored in the tokenizer: "byte_level" (GPT-2 style),
+    /// "byte_fallback" (like Llama, with <0x0A> tokens), or empty when unknown.
+    #[serde(default)]
+    pub tokenizer: String,
+    /// The host wants the session recorded (see recording), and takes the log through
+    /// aici_host_append_recording(); since ABI 1.4.
+    #[serde(default)]
+    pub record: bool,
+}
+
+impl RuntimeInfo {
+    /// Header of a host at the current ABI version.
+    pub fn new(caps: &[Capability], vocab_size: u32, tokenizer: &str) -> Self {
+        RuntimeInfo {
+            abi_major: ABI_MAJOR,
+            abi_minor: ABI_MINOR,
+            host_calls: caps.iter().fold(0, |acc, c| acc | c.bit()),
+            vocab_size,
+            tokenizer: tokenizer.to_string(),
+            record: false,
+        }
+    }
+
+    pub fn supports(&self, cap: Capability) -> bool {
+        self.host_calls & cap.bit() != 0
+    }
+
+    /// The host knows the calls and fields of the given minor version of ABI_MAJOR.
+    pub fn has_minor(&self, minor: u32) -> bool {
+        self.abi_major == ABI_MAJOR && self.abi_minor >= minor
+    }
+}
+
+/// The header of the host. Hosts from before ABI 1.1 don't pass one; for them,
+/// the capabilities they announce in get_config() are reported, with ABI 1.0,
+/// and without the tokenizer.
+pub fn runtime_info() -> RuntimeInfo {
+    let bytes = get_host().runtime_info_bytes();
+    recording::record(RecordKind::RuntimeInfo, || bytes.clone());
+    if !bytes.is_empty() {
+        match serde_json::from_slice(&bytes) {
+            Ok(info) => return info,
+            Err(e) => crate::wlog!(LogLevel::Warn, "warning: invalid runtime info: {e}"),
+        }
+    }
+    let caps = [
+        Capability::Fork,
+        Capability::Backtrack,
+        Capability::FfTokens,
+        Capability::DenyList,
+    ];
+    let caps = caps
+        .into_iter()
+        .filter(|c| get_config(c.name()) != 0)
+        .collect::<Vec<_>>();
+    let vocab_size = TokTrie::from_bytes(&get_host().trie_bytes()).vocab_size() as u32;
+    RuntimeInfo {
+        abi_minor: 0,
+        ..RuntimeInfo::new(&caps, vocab_size, "")
+    }
+}
+
+/// Fail unless the host has the ABI_MAJOR of the controller, and all of `caps`.
+/// Call it from TryAiciCtrl::try_init_prompt() (with `?`), so that the request fails
+/// right away with a message naming what's missing, and not later, mid-generation,
+/// with a trap, or with the host silently ignoring what the controller asked for.
+pub fn require_capabilities(caps: &[Capability]) -> Result<RuntimeInfo, Error> {
+    let info = runtime_info();
+    let versions = format!(
+        "host ABI {}.{}, controller ABI {}.{}",
+        info.abi_major, info.abi_minor, ABI_MAJOR, ABI_MINOR
+    );
+    if info.abi_major != ABI_MAJOR {
+        return Err(Error::Unsupported(format!("ABI version ({versions})")));
+    }
+    let missing = caps
+        .iter()
+        .filter(|c| !info.supports(**c))
+        .map(|c| c.name())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(Error::Unsupported(format!(
+            "{} ({versions})",
+            missing.join(", ")
+        )));
+    }
+    Ok(info)
+}
+
+/// Default seed for random number generators (see rng::Rng::default()).
+pub fn rand_seed() -> u64 {
+    let r = get_host().rand_seed();
+    recording::record(RecordKind::RandSeed, || r.to_le_bytes().to_vec());
+    r
+}
+
+/// Time left (in microseconds) before the host gives up on the current call;
+/// long computations can check this and return a cheaper result.
+pub fn time_left_us() -> u64 {
+    get_host().time_left_us()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -285,6 +646,63 @@ pub mod hex_string {
     }
 }
 
+/// Like hex_string, for the (name, value) pairs of StorageCmd::WriteBatch.
+pub mod hex_string_writes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::bytes::{from_hex_string, to_hex_string};
+
+    pub fn serialize<S: Serializer>(v: &[(String, Vec<u8>)], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(v.iter().map(|(name, value)| (name, to_hex_string(value))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(String, Vec<u8>)>, D::Error> {
+        let pairs = Vec::<(String, String)>::deserialize(d)?;
+        pairs
+            .into_iter()
+            .map(|(name, hexstr)| Ok((name, from_hex_string(&hexstr)?)))
+            .collect::<anyhow::Result<_>>()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Like hex_string, for the (version, value) pairs of StorageResp::ReadBatch.
+pub mod hex_string_versions {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::bytes::{from_hex_string, to_hex_string};
+
+    type Entries = Vec<Option<(u64, Vec<u8>)>>;
+
+    pub fn serialize<S: Serializer>(v: &[Option<(u64, Vec<u8>)>], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(v.iter().map(|e| {
+            e.as_ref()
+                .map(|(version, value)| (version, to_hex_string(value)))
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Entries, D::Error> {
+        let entries = Vec::<Option<(u64, String)>>::deserialize(d)?;
+        entries
+            .into_iter()
+            .map(|e| match e {
+                Some((version, hexstr)) => Ok(Some((version, from_hex_string(&hexstr)?))),
+                None => Ok(None),
+            })
+            .collect::<anyhow::Result<_>>()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Variables are scoped to the request: all sequences forked from it share them,
+/// but other requests don't see them.
+/// The total size of (names and values of) variables of a request is limited by the host.
+/// Variables named with GLOBAL_PREFIX are instead shared by all requests, with a quota
+/// of their own (see Capability::GlobalStorage; other hosts scope them to the request).
+/// A batch goes to the variables of its first name, so its names have to be either
+/// all global or all scoped (VariableStorage makes sure of that).
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageCmd {
     /// Read variable. Returns StorageResp::ReadVar or StorageResp::VariableMissing.
@@ -294,8 +712,11 @@ pub enum StorageCmd {
     /// If `when_version_is == None`, always writes the variable and returns StorageResp::WriteVar.
     /// Otherwise, if the variable has the specified version, it writes the variable
     /// and returns StorageResp::WriteVar.
+    /// Version 0 refers to unset variable (the first write sets version to 1).
     /// Otherwise (version conflict), returns either StorageResp::ReadVar or StorageResp::VariableMissing
     /// just like ReadVar would.
+    /// If the write would exceed the storage quota, nothing is written and
+    /// StorageResp::QuotaExceeded is returned.
     WriteVar {
         name: String,
         #[serde(with = "hex_string")]
@@ -303,6 +724,23 @@ pub enum StorageCmd {
         op: StorageOp,
         when_version_is: Option<u64>,
     },
+
+    /// Read several variables at once, as of the same point in time.
+    /// Returns StorageResp::ReadBatch, with the values in the order of `names`.
+    ReadBatch { names: Vec<String> },
+
+    /// Set several variables at once (like WriteVar with StorageOp::Set);
+    /// other requests see either all of the writes, or none.
+    /// If `require_seq` is given, and anything was written since the storage
+    /// was at that sequence number (see StorageResp::ReadBatch), nothing is written
+    /// and StorageResp::Conflict is returned.
+    /// Otherwise, returns StorageResp::WriteBatch, or StorageResp::QuotaExceeded
+    /// (with nothing written) like WriteVar.
+    WriteBatch {
+        #[serde(with = "hex_string_writes")]
+        writes: Vec<(String, Vec<u8>)>,
+        require_seq: Option<u64>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -317,13 +755,109 @@ pub enum StorageResp {
     VariableMissing {},
     /// The variable has been written, and the new version is returned.
     WriteVar { version: u64 },
+    /// The variable was not written, since the request (or all requests together,
+    /// for global variables) would then use more than `quota` bytes of storage.
+    QuotaExceeded { quota: usize },
+    /// The variables of ReadBatch, each with its version, or None if unset.
+    /// `seq` is the sequence number of the storage, which increases with every write
+    /// (a WriteBatch being one write).
+    ReadBatch {
+        seq: u64,
+        #[serde(with = "hex_string_versions")]
+        values: Vec<Option<(u64, Vec<u8>)>>,
+    },
+    /// The variables of WriteBatch have been written; `seq` is the new sequence number.
+    WriteBatch { seq: u64 },
+    /// WriteBatch was not applied, as the storage changed since `require_seq`;
+    /// `seq` is the current sequence number.
+    Conflict { seq: u64 },
+}
+
+/// Prefix of the names of variables shared by all requests; see StorageCmd.
+pub const GLOBAL_PREFIX: &str = "global/";
+
+fn is_global_name(name: &str) -> bool {
+    name.starts_with(GLOBAL_PREFIX)
+}
+
+impl StorageCmd {
+    /// The command goes to the global variables (and not the ones of the request);
+    /// for batches, this depends on the first name.
+    pub fn is_global(&self) -> bool {
+        let name = match self {
+            StorageCmd::ReadVar { name } | StorageCmd::WriteVar { name, .. } => Some(name),
+            StorageCmd::ReadBatch { names } => names.first(),
+            StorageCmd::WriteBatch { writes, .. } => writes.first().map(|(name, _)| name),
+        };
+        name.is_some_and(|name| is_global_name(name))
+    }
+}
+
+/// Returned when a write would exceed the storage quota (in bytes) of the request,
+/// or of the global variables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub quota: usize,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "storage quota of {} bytes exceeded", self.quota)
+    }
+}
+
+/// Why VariableStorage::read_many() or transaction() failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    QuotaExceeded(QuotaExceeded),
+    /// The host answered with a response that doesn't go with the command
+    /// (e.g., a host without batches); the name of the command is given.
+    UnexpectedResponse(&'static str),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::QuotaExceeded(e) => e.fmt(f),
+            StorageError::UnexpectedResponse(cmd) => write!(f, "unexpected response to {cmd}"),
+        }
+    }
+}
+
+impl From<QuotaExceeded> for StorageError {
+    fn from(e: QuotaExceeded) -> Self {
+        StorageError::QuotaExceeded(e)
+    }
+}
+
+/// Why VariableStorage::cas() didn't write the variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasError {
+    /// The variable is at another version; the current version and value are returned
+    /// (`0` and empty if the variable is unset), to retry with.
+    Conflict { version: u64, value: Vec<u8> },
+    /// Retrying won't help here.
+    QuotaExceeded(QuotaExceeded),
+    /// Neither will it here; see StorageError::UnexpectedResponse.
+    UnexpectedResponse(&'static str),
 }
 
 pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
-    let cmd_bytes = serde_json::to_vec(&cmd).unwrap();
-    let res_id = unsafe { aici_host_storage_cmd(cmd_bytes.as_ptr(), cmd_bytes.len() as u32) };
-    let resp_bytes = read_blob(res_id, 1024);
-    serde_json::from_slice(&resp_bytes).unwrap()
+    if !recording::is_recording() {
+        return get_host().storage_cmd(cmd);
+    }
+    let resp = get_host().storage_cmd(cmd.clone());
+    recording::record_storage(&cmd, &resp);
+    resp
+}
+
+pub(crate) fn append_recording(log: &[u8]) {
+    get_host().append_recording(log)
+}
+
+// the header, without recording it
+pub(crate) fn raw_runtime_info_bytes() -> Vec<u8> {
+    get_host().runtime_info_bytes()
 }
 
 // Public APIs
@@ -343,52 +877,256 @@ impl VariableStorage {
         self.get_with_version(name).map(|x| x.1)
     }
 
-    /// Write specified value to variable.
-    pub fn set(&self, name: &str, value: Vec<u8>) {
-        let _ver = self.write_var(name, value, StorageOp::Set);
+    /// Write specified value to variable; returns the new version.
+    /// If the storage quota would be exceeded, nothing is written.
+    pub fn set(&self, name: &str, value: Vec<u8>) -> Result<u64, QuotaExceeded> {
+        self.write_var(name, value, StorageOp::Set)
     }
 
-    /// Append specified value to variable.
-    pub fn append(&self, name: &str, value: Vec<u8>) {
-        let _ver = self.write_var(name, value, StorageOp::Append);
+    /// Append specified value to variable; returns the new version.
+    /// If the storage quota would be exceeded, nothing is written.
+    pub fn append(&self, name: &str, value: Vec<u8>) -> Result<u64, QuotaExceeded> {
+        self.write_var(name, value, StorageOp::Append)
     }
 
-    fn write_var(&self, name: &str, value: Vec<u8>, op: StorageOp) -> u64 {
+    /// Write value to variable, but only if it is currently at `expected_version`
+    /// (0 means the variable is unset).
+    /// Returns the new version, or CasError::Conflict with the current version and value.
+    pub fn cas(&self, name: &str, expected_version: u64, value: Vec<u8>) -> Result<u64, CasError> {
+        match storage_cmd(StorageCmd::WriteVar {
+            name: name.to_string(),
+            value,
+            op: StorageOp::Set,
+            when_version_is: Some(expected_version),
+        }) {
+            StorageResp::WriteVar { version } => Ok(version),
+            StorageResp::ReadVar { version, value } => Err(CasError::Conflict { version, value }),
+            StorageResp::VariableMissing {} => Err(CasError::Conflict {
+                version: 0,
+                value: vec![],
+            }),
+            StorageResp::QuotaExceeded { quota } => {
+                Err(CasError::QuotaExceeded(QuotaExceeded { quota }))
+            }
+            _ => Err(CasError::UnexpectedResponse("write var")),
+        }
+    }
+
+    fn write_var(&self, name: &str, value: Vec<u8>, op: StorageOp) -> Result<u64, QuotaExceeded> {
         match storage_cmd(StorageCmd::WriteVar {
             name: name.to_string(),
             value,
             op,
             when_version_is: None,
         }) {
-            StorageResp::WriteVar { version } => version,
+            StorageResp::WriteVar { version } => Ok(version),
+            StorageResp::QuotaExceeded { quota } => Err(QuotaExceeded { quota }),
             _ => panic!("unexpected response to write var"),
         }
     }
 
-    fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
-        match storage_cmd(StorageCmd::ReadVar {
-            name: name.to_string(),
+    /// Read variable together with its version. Returns None if the variable is unset.
+    pub fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
+        storage_get_versioned(name)
+    }
+
+    /// Read several variables as of the same point in time; None for unset ones.
+    /// Unlike calling get() for each of them, this never mixes the values from before
+    /// and after another sequence wrote some of them.
+    /// Panics if some of the names are global, and some are not (see GLOBAL_PREFIX).
+    pub fn read_many(&self, names: &[&str]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        Ok(storage_read_batch(names)?.1)
+    }
+
+    /// Run `f` with the variables as of one point in time, and then apply
+    /// the writes it made with Transaction::set(), all at once.
+    /// If anything was written in the meantime (e.g., by another fork), the writes
+    /// are dropped and `f` runs again, so it may be called several times,
+    /// and shouldn't have other side effects.
+    /// Returns the result of the last call of `f`; if a read of `f` fails, its
+    /// writes are dropped, and the error is returned instead.
+    /// The variables of a transaction have to be either all global, or all scoped
+    /// to the request (see GLOBAL_PREFIX); Transaction::get() and set() panic otherwise.
+    pub fn transaction<T>(
+        &self,
+        mut f: impl FnMut(&mut Transaction) -> T,
+    ) -> Result<T, StorageError> {
+        loop {
+            let mut txn = Transaction::default();
+            let r = f(&mut txn);
+            if txn.commit()? {
+                return Ok(r);
+            }
+        }
+    }
+}
+
+/// The reads and pending writes of VariableStorage::transaction().
+#[derive(Default)]
+pub struct Transaction {
+    // storage sequence number of the first read
+    seq: Option<u64>,
+    // reads were done at different sequence numbers
+    torn: bool,
+    writes: Vec<(String, Vec<u8>)>,
+    // the variables are global ones, once any is accessed
+    global: Option<bool>,
+    // of the first failed read
+    error: Option<StorageError>,
+}
+
+impl Transaction {
+    // the global and scoped variables have separate sequence numbers,
+    // so they can't be read (or written) atomically together
+    fn check_namespace(&mut self, name: &str) {
+        let global = is_global_name(name);
+        if *self.global.get_or_insert(global) != global {
+            panic!("transaction mixes global and request variables ('{name}')");
+        }
+    }
+
+    /// Read variable; sees the writes of the transaction itself.
+    /// If the read fails, returns None (the transaction then fails with the error).
+    pub fn get(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.check_namespace(name);
+        if let Some((_, value)) = self.writes.iter().rev().find(|(n, _)| n == name) {
+            return Some(value.clone());
+        }
+        let (seq, mut values) = match storage_read_batch(&[name]) {
+            Ok(r) => r,
+            Err(e) => {
+                self.error.get_or_insert(e);
+                return None;
+            }
+        };
+        match self.seq {
+            None => self.seq = Some(seq),
+            Some(s) => self.torn |= s != seq,
+        }
+        values.pop().unwrap()
+    }
+
+    /// Write value to variable when the transaction is committed.
+    pub fn set(&mut self, name: &str, value: Vec<u8>) {
+        self.check_namespace(name);
+        self.writes.push((name.to_string(), value));
+    }
+
+    // false if the transaction has to be retried
+    fn commit(self) -> Result<bool, StorageError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if self.writes.is_empty() {
+            return Ok(!self.torn);
+        }
+        match storage_cmd(StorageCmd::WriteBatch {
+            writes: self.writes,
+            require_seq: self.seq,
         }) {
-            StorageResp::ReadVar { version, value } => Some((version, value)),
-            StorageResp::VariableMissing {} => None,
-            StorageResp::WriteVar { .. } => panic!("unexpected response to read var"),
+            StorageResp::WriteBatch { .. } => Ok(true),
+            StorageResp::Conflict { .. } => Ok(false),
+            StorageResp::QuotaExceeded { quota } => Err(QuotaExceeded { quota }.into()),
+            _ => Err(StorageError::UnexpectedResponse("write batch")),
         }
     }
 }
 
+type Values = Vec<Option<Vec<u8>>>;
+
+// the sequence number, and the values (without versions)
+fn storage_read_batch(names: &[&str]) -> Result<(u64, Values), StorageError> {
+    let num_global = names.iter().filter(|n| is_global_name(n)).count();
+    if num_global != 0 && num_global != names.len() {
+        panic!("batch mixes global and request variables: {names:?}");
+    }
+    match storage_cmd(StorageCmd::ReadBatch {
+        names: names.iter().map(|n| n.to_string()).collect(),
+    }) {
+        StorageResp::ReadBatch { seq, values } => {
+            Ok((seq, values.into_iter().map(|v| v.map(|x| x.1)).collect()))
+        }
+        _ => Err(StorageError::UnexpectedResponse("read batch")),
+    }
+}
+
+/// Read variable together with its version. Returns None if the variable is unset.
+/// The version increases with every write, so a controller can cheaply poll
+/// a variable in each mid_process() and only react when it changes.
+pub fn storage_get_versioned(name: &str) -> Option<(u64, Vec<u8>)> {
+    match storage_cmd(StorageCmd::ReadVar {
+        name: name.to_string(),
+    }) {
+        StorageResp::ReadVar { version, value } => Some((version, value)),
+        StorageResp::VariableMissing {} => None,
+        StorageResp::WriteVar { .. }
+        | StorageResp::QuotaExceeded { .. }
+        | StorageResp::ReadBatch { .. }
+        | StorageResp::WriteBatch { .. }
+        | StorageResp::Conflict { .. } => panic!("unexpected response to read var"),
+    }
+}
+
+/// Look up a value stored with bias_cache_put(), by this or another sequence
+/// running the same module (also in other requests); e.g., a token set computed
+/// for a parser state. The host evicts the least recently used values, so this may
+/// return None at any time; hosts without the cache always do.
+pub fn bias_cache_get(key: u64) -> Option<Vec<u8>> {
+    get_host().bias_cache_get(key)
+}
+
+/// Store a value for bias_cache_get(), replacing the one with the same key.
+/// The key has to include everything the value depends on (e.g., a hash of the grammar),
+/// as all sequences of the module see the same values.
+pub fn bias_cache_put(key: u64, vob_bytes: &[u8]) {
+    get_host().bias_cache_put(key, vob_bytes)
+}
+
 /// Tokenize given byte string.
+/// The string doesn't have to be valid UTF-8; invalid bytes are tokenized at the byte level.
 pub fn tokenize_bytes(s: &[u8]) -> Vec<TokenId> {
-    get_host().tokenize_bytes(s)
+    let r = get_host().tokenize_bytes(s);
+    recording::record_tokenize(s, &r);
+    r
+}
+
+/// Tokenize given bytes with the longest matching tokens of the vocabulary, falling back
+/// to single-byte tokens (like <0x00>), without the tokenizer; e.g., to force raw bytes.
+/// Bytes the vocabulary can't represent are returned as the remainder of the result
+/// (see TokTrie::tokenize_bytes_greedy()).
+pub fn tokenize_bytes_greedy(s: &[u8]) -> TokenizationResult {
+    get_host().tokenize_bytes_greedy(s)
+}
+
+/// Tokenize the bytes of `tokens` again, with the tokenizer of the model.
+/// The result differs from `tokens` when the tokenizer would split their text differently
+/// (e.g., tokens sampled one at a time, or TokTrie::check_canonical() ones); when in doubt,
+/// this is what the model would see for the same text in a prompt.
+pub fn retokenize(tokens: &[TokenId]) -> Vec<TokenId> {
+    tokenize_bytes(&decode_tokens(tokens))
 }
 
 /// Tokenize given UTF8 string.
 pub fn tokenize(s: &str) -> Vec<TokenId> {
-    get_host().tokenize_bytes(s.as_bytes())
+    tokenize_bytes(s.as_bytes())
+}
+
+/// Decode given tokens into bytes.
+/// The result may end in the middle of a multi-byte UTF-8 character.
+pub fn decode_tokens(toks: &[TokenId]) -> Vec<u8> {
+    get_host().decode_tokens(toks)
 }
 
 /// Return the ID of the current process.
 pub fn self_seq_id() -> SeqId {
-    get_host().self_seq_id()
+    let r = raw_self_seq_id();
+    recording::record(RecordKind::SelfSeqId, || r.to_le_bytes().to_vec());
+    SeqId(r)
+}
+
+// bypasses the recording
+pub(crate) fn raw_self_seq_id() -> u32 {
+    get_host().self_seq_id().0
 }
 
 /// Return the ID of the EOS token.
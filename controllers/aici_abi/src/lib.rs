@@ -1,9 +1,13 @@
 use serde::{Deserialize, Serialize};
-use svob::SimpleVob;
+use std::collections::BTreeMap;
+use svob::{SimpleVob, TokenSet};
 
 pub mod bytes;
+pub mod choice;
+mod error;
 mod host;
 pub mod recognizer;
+pub mod recording;
 pub mod rng;
 pub mod svob;
 pub mod toktree;
@@ -18,11 +22,28 @@ pub mod rx;
 
 pub mod substring;
 
+#[cfg(feature = "tokenizers")]
+pub mod hftokenizers;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod native;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replay;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod variables;
+
 pub type TokenId = bytes::TokenId;
 
+pub use error::{Error, ErrorResult};
+
 pub use host::{
-    aici_stop, arg_bytes, arg_string, get_config, self_seq_id, tokenize, tokenize_bytes,
-    StorageCmd, StorageOp, StorageResp, TokenizerEnv, VariableStorage, WasmTokenizerEnv,
+    aici_stop, arg_bytes, arg_string, bias_cache_get, bias_cache_put, get_config, log, log_enabled,
+    log_level, rand_seed, require_capabilities, retokenize, runtime_info, self_seq_id,
+    storage_get_versioned, time_left_us, tokenize, tokenize_bytes, tokenize_bytes_greedy,
+    Capability, CasError, LogLevel, QuotaExceeded, RuntimeInfo, StorageCmd, StorageError,
+    StorageOp, StorageResp, TokenizerEnv, Transaction, VariableStorage, WasmTokenizerEnv,
+    ABI_MAJOR, ABI_MINOR, GLOBAL_PREFIX,
 };
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -34,7 +55,45 @@ pub struct InitPromptArg {
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
-pub struct InitPromptResult {}
+pub struct InitPromptResult {
+    /// Tokens to append to the prompt, before the first mid_process() call.
+    /// Forking is not possible at this stage; fork from mid_process() if needed.
+    #[serde(default)]
+    pub ff_tokens: Vec<TokenId>,
+    /// Number of most likely tokens to report in MidProcessArg::top_logprobs;
+    /// 0 (the default) to skip the computation. The host caps it at MAX_TOP_LOGPROBS.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub top_logprobs: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Maximum of InitPromptResult::top_logprobs.
+pub const MAX_TOP_LOGPROBS: u32 = 32;
+
+impl InitPromptResult {
+    pub fn ff_tokens(ff_tokens: Vec<TokenId>) -> Self {
+        InitPromptResult {
+            ff_tokens,
+            ..Default::default()
+        }
+    }
+
+    /// Ask the host for the `k` most likely tokens at every sampling step;
+    /// see MidProcessArg::top_logprobs.
+    pub fn with_top_logprobs(self, k: u32) -> Self {
+        InitPromptResult {
+            top_logprobs: k,
+            ..self
+        }
+    }
+}
 
 #[repr(transparent)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -46,13 +105,78 @@ pub struct MidProcessArg {
     /// For simple sampled token 't', backtrack==0 and tokens==[t].
     /// For first request, backtrack==0 and tokens==[] (prompt is passed separetely, before).
     /// Can be more complex when splices are used.
+    /// Controllers mirroring the tokens (or recognizer state) have to drop
+    /// the last `backtrack` tokens before appending `tokens`; see save_tokens().
     pub backtrack: u32,
     pub tokens: Vec<TokenId>,
-    ///
+    /// Sequences resulting from the last fork, ordered by branch index
+    /// (the parent sequence continues as branch 0).
+    /// When there was no fork, this is just the current sequence.
     pub fork_group: Vec<SeqId>,
+    /// The Branch::fork_arg of the branch this sequence follows (from the previous
+    /// mid_process() call).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fork_arg: Vec<u8>,
+    /// Set when the previous mid_process() call ran past the step deadline.
+    /// A no-op step was inserted in the meantime, and the late result was applied
+    /// in the step after; controllers may want to use a cheaper strategy.
+    #[serde(default)]
+    pub prev_timed_out: bool,
+    /// The forced_byte_prefix of the branch the previous token was sampled for (if any).
+    /// Normally, the first of `tokens` starts with these bytes, or covers only the start
+    /// of them (the rest is then up to the controller); if the vocabulary has
+    /// no suitable token, the host instead appends the prefix tokenized on its own
+    /// (and `tokens` only cover the prefix).
+    #[serde(default)]
+    pub forced_byte_prefix: Vec<u8>,
+    /// Length in bytes of the decoded sequence (prompt included), after dropping
+    /// `backtrack` tokens and appending `tokens`. It only goes down on backtrack,
+    /// so controllers can track byte positions without decoding all the tokens.
+    #[serde(default)]
+    pub byte_offset: u64,
+    /// Set when the host dropped tokens from the model's context since the previous call,
+    /// to keep generating past the maximum sequence length. The dropped tokens are still
+    /// part of the sequence (for `backtrack` and `byte_offset`), but the model no longer
+    /// sees them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_truncation: Option<ContextTruncation>,
+    /// When asked for with InitPromptResult::top_logprobs, the most likely tokens of the
+    /// step that sampled the last of `tokens`, with their log-probabilities, most likely
+    /// first. They come from the model's distribution before the controller's mask
+    /// (and before temperature and the like). Empty when the tokens were not sampled
+    /// (e.g., forced by a splice).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_logprobs: Vec<(TokenId, f32)>,
+}
+
+/// Tokens dropped from the middle of the model's context; see MidProcessArg::context_truncation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContextTruncation {
+    /// Index of the first dropped token in the sequence (prompt included).
+    /// Tokens dropped before (if any) end right there.
+    pub start: usize,
+    pub num_tokens: usize,
 }
 
 impl MidProcessArg {
+    /// Index of the branch (as returned from previous mid_process()) that the current
+    /// sequence follows. Any per-branch state can be stored in the controller before
+    /// returning the branches, and then picked up using this index
+    /// (alternatively, see Branch::fork_arg).
+    pub fn fork_index(&self) -> anyhow::Result<usize> {
+        let self_id = host::self_seq_id();
+        self.fork_group
+            .iter()
+            .position(|id| *id == self_id)
+            .ok_or_else(|| anyhow::anyhow!("sequence {} not in fork_group", self_id.0))
+    }
+
+    /// Byte offset in the decoded sequence where `tokens` start.
+    pub fn tokens_byte_offset(&self) -> u64 {
+        let len = host::decode_tokens(&self.tokens).len() as u64;
+        self.byte_offset.saturating_sub(len)
+    }
+
     pub fn has_eos(&self) -> bool {
         let eos = host::eos_token();
         self.tokens.iter().any(|t| *t == eos)
@@ -111,6 +235,30 @@ pub struct Branch<S> {
     /// Describes what to do after sampling.
     /// If no sampling, there should be exactly one splice, with empty `when_sampled`.
     pub splices: Vec<Splice>,
+    /// The sampled token has to start with these bytes (typically, bytes forced by the grammar
+    /// that don't end on a token boundary), or be a strict prefix of them. If the sampled
+    /// token is neither, the host re-samples from the masked distribution limited to such tokens.
+    /// If there are no such tokens, the prefix is tokenized and appended instead.
+    /// The prefix is passed back in the next MidProcessArg.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forced_byte_prefix: Vec<u8>,
+    /// Pass the sampled token to post_sample() before it's committed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub post_sample: bool,
+    /// Which tokens the following tokens attend to; either empty (all of them), or one
+    /// value per token of the sequence (prompt included) as of this mid_process() call.
+    /// The values are multiplicative weights, but only 0.0 (the token is hidden)
+    /// and 1.0 are supported; anything else fails the sequence.
+    /// A token always attends to itself, and tokens appended later are never hidden.
+    /// The mask applies from the next step on (the logits for the token sampled now
+    /// are already computed), until the next mid_process() result; forks (other branches)
+    /// have their own masks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attention_mask: Vec<f32>,
+    /// Opaque data passed to the next mid_process() of the sequence following
+    /// this branch (as MidProcessArg::fork_arg); e.g., which constraint it should use.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fork_arg: Vec<u8>,
 }
 
 impl<S: Clone> Clone for Branch<S> {
@@ -118,6 +266,10 @@ impl<S: Clone> Clone for Branch<S> {
         Branch {
             sample_mask: self.sample_mask.clone(),
             splices: self.splices.clone(),
+            forced_byte_prefix: self.forced_byte_prefix.clone(),
+            post_sample: self.post_sample,
+            attention_mask: self.attention_mask.clone(),
+            fork_arg: self.fork_arg.clone(),
         }
     }
 }
@@ -130,9 +282,18 @@ impl<S> Branch<S> {
         Branch {
             sample_mask: self.sample_mask.as_ref().map(f),
             splices: self.splices.clone(),
+            forced_byte_prefix: self.forced_byte_prefix.clone(),
+            post_sample: self.post_sample,
+            attention_mask: self.attention_mask.clone(),
+            fork_arg: self.fork_arg.clone(),
         }
     }
 
+    /// See Branch::fork_arg.
+    pub fn with_fork_arg(self, fork_arg: Vec<u8>) -> Self {
+        Branch { fork_arg, ..self }
+    }
+
     pub fn splice(backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
         Branch {
             sample_mask: None,
@@ -141,6 +302,10 @@ impl<S> Branch<S> {
                 backtrack,
                 ff_tokens,
             }],
+            forced_byte_prefix: vec![],
+            post_sample: false,
+            attention_mask: vec![],
+            fork_arg: vec![],
         }
     }
 
@@ -149,90 +314,459 @@ impl<S> Branch<S> {
     }
 }
 
-#[derive(Debug)]
+/// The host resumes a suspended sequence after at most this many steps, whatever
+/// the condition; the controller can then suspend it again.
+pub const MAX_SUSPEND_STEPS: u32 = 10_000;
+
+/// Condition for resuming a suspended sequence.
+/// The sequence resumes when any of the given conditions holds,
+/// or after MAX_SUSPEND_STEPS steps.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Suspend {
+    /// Resume when this storage variable is written (its version changes)
+    /// after the sequence was suspended.
+    pub wake_on_var: Option<String>,
+    /// Resume after this many steps.
+    pub wake_after_steps: Option<u32>,
+}
+
+impl Suspend {
+    /// Resume when the storage variable is written.
+    pub fn until_written(var: &str) -> Self {
+        Suspend {
+            wake_on_var: Some(var.to_string()),
+            wake_after_steps: None,
+        }
+    }
+
+    /// Resume after given number of steps.
+    pub fn for_steps(steps: u32) -> Self {
+        Suspend {
+            wake_on_var: None,
+            wake_after_steps: Some(steps),
+        }
+    }
+
+    /// Also resume after given number of steps.
+    pub fn or_after_steps(mut self, steps: u32) -> Self {
+        self.wake_after_steps = Some(steps);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct MidProcessResult {
     /// Fork the request into multiple branches.
     /// Typically, exactly one branch is returned.
     /// If multiple branches are returned, they are executed in parallel.
     /// If no branches are returned, the request is terminated.
     pub branches: Vec<Branch<SimpleVob>>,
+    /// If set, the host will not call mid_process() again until the condition holds;
+    /// in the meantime no tokens are generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspend: Option<Suspend>,
+    /// Upper bound on the number of tokens that may still be generated (e.g., the rest
+    /// of the grammar can't take more). This is only a hint for the host's scheduling;
+    /// it can only tighten - a larger bound than given before is ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_remaining_tokens: Option<u32>,
+    /// Named parts of the output that completed (or grew) since the last call;
+    /// the host returns them to the client with the text (see Capability::Captures).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub captures: Vec<Capture>,
 }
 
 impl MidProcessResult {
     pub fn stop() -> Self {
-        MidProcessResult { branches: vec![] }
+        MidProcessResult {
+            branches: vec![],
+            suspend: None,
+            max_remaining_tokens: None,
+            captures: vec![],
+        }
     }
 
     pub fn sample(set: SimpleVob) -> Self {
+        Self::sample_with_prefix(set, vec![])
+    }
+
+    /// Sample from `set`, but only accept tokens fitting `forced_byte_prefix`;
+    /// see Branch::forced_byte_prefix.
+    pub fn sample_with_prefix(set: SimpleVob, forced_byte_prefix: Vec<u8>) -> Self {
         MidProcessResult {
             branches: vec![Branch {
                 sample_mask: Some(set),
                 splices: vec![],
+                forced_byte_prefix,
+                post_sample: false,
+                attention_mask: vec![],
+                fork_arg: vec![],
             }],
+            suspend: None,
+            max_remaining_tokens: None,
+            captures: vec![],
         }
     }
 
     pub fn splice(backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
         MidProcessResult {
             branches: vec![Branch::splice(backtrack, ff_tokens)],
+            suspend: None,
+            max_remaining_tokens: None,
+            captures: vec![],
         }
     }
 
     pub fn noop() -> Self {
         Self::splice(0, vec![])
     }
+
+    /// Fork into one branch per entry of `ff_tokens`, each appending its tokens
+    /// (nothing is sampled in this step). In the next mid_process(), `fork_group`
+    /// lists the sequences in the same order, so fork_index() tells which tokens
+    /// the current sequence got.
+    pub fn fork_splices(ff_tokens: Vec<Vec<TokenId>>) -> Self {
+        MidProcessResult {
+            branches: ff_tokens
+                .into_iter()
+                .map(|toks| Branch::splice(0, toks))
+                .collect(),
+            suspend: None,
+            max_remaining_tokens: None,
+            captures: vec![],
+        }
+    }
+
+    /// Have the host call post_sample() with the token sampled in this step.
+    pub fn with_post_sample(mut self) -> Self {
+        for b in self.branches.iter_mut() {
+            b.post_sample = b.sample_mask.is_some();
+        }
+        self
+    }
+
+    /// Set the max_remaining_tokens hint.
+    pub fn with_max_remaining_tokens(mut self, max_remaining_tokens: u32) -> Self {
+        self.max_remaining_tokens = Some(max_remaining_tokens);
+        self
+    }
+
+    /// Report these captures along with the result.
+    pub fn with_captures(mut self, captures: Vec<Capture>) -> Self {
+        self.captures = captures;
+        self
+    }
+
+    /// Don't generate anything until the condition holds.
+    pub fn suspend(cond: Suspend) -> Self {
+        MidProcessResult {
+            suspend: Some(cond),
+            ..Self::noop()
+        }
+    }
+}
+
+/// A named part of the output, like `gen(name="answer")` in a grammar.
+/// Repeated and nested captures each have their own occurrence, in the order they
+/// completed (inner ones first). A capture with the name and occurrence of one
+/// reported before replaces it; e.g., when a regex match got longer.
+/// A `retracted` one withdraws it instead, e.g., when its bytes were backtracked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub name: String,
+    /// Index among the captures with the same name.
+    pub occurrence: u32,
+    /// Byte range in the output of the controller; empty when retracted.
+    pub start: u32,
+    pub end: u32,
+    /// The bytes of the range, as (lossy) UTF-8.
+    pub value: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub retracted: bool,
+}
+
+impl Capture {
+    /// Withdraw the capture with the name and occurrence reported before.
+    pub fn retraction(name: &str, occurrence: u32) -> Self {
+        Capture {
+            name: name.to_string(),
+            occurrence,
+            start: 0,
+            end: 0,
+            value: String::new(),
+            retracted: true,
+        }
+    }
+}
+
+/// The latest values after `captures` (e.g., everything reported for a sequence,
+/// in order), as a list per name, ordered by occurrence. A retraction drops the
+/// occurrence and the ones after it, as they completed later.
+pub fn captures_by_name(captures: &[Capture]) -> BTreeMap<String, Vec<String>> {
+    let mut r: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for c in captures {
+        let values = r.entry(c.name.clone()).or_default();
+        let idx = c.occurrence as usize;
+        if c.retracted {
+            values.truncate(idx);
+            continue;
+        }
+        if values.len() <= idx {
+            values.resize(idx + 1, String::new());
+        }
+        values[idx] = c.value.clone();
+    }
+    r.retain(|_, values| !values.is_empty());
+    r
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostSampleArg {
+    /// The token sampled from the mask of the branch.
+    pub token: TokenId,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PostSampleResult {
+    /// Commit the token.
+    Accept,
+    /// Don't commit the token. With `resample`, the host removes it from the mask and
+    /// samples again (calling post_sample() with the new token), up to a host-configured
+    /// number of times, after which the sequence fails. Otherwise, nothing is committed,
+    /// and mid_process() is called again with no new tokens.
+    Reject { resample: bool },
+    /// Commit this token instead (it doesn't have to be allowed by the mask).
+    Replace { token: TokenId },
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ProcessResultOffset {
     /// Branches use byte offsets into the bias tensor.
     pub branches: Vec<Branch<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspend: Option<Suspend>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_remaining_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub captures: Vec<Capture>,
+}
+
+/// Hosts that only speak JSON (e.g., out-of-process, or replaying recorded sessions)
+/// set the "inline_bias" config; then mid_process() results are returned as
+/// serialized MidProcessResult, with the masks inline. Otherwise, the masks are
+/// passed via the (faster) logit bias buffer, and ProcessResultOffset is returned.
+fn inline_bias() -> bool {
+    static INLINE_BIAS: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *INLINE_BIAS.get_or_init(|| host::get_config("inline_bias") != 0)
+}
+
+/// Hosts that set the "deny_list" config accept a list of denied tokens instead of
+/// the mask; masks allowing almost all tokens are then passed that way.
+fn deny_lists() -> bool {
+    static DENY_LISTS: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *DENY_LISTS.get_or_init(|| host::get_config("deny_list") != 0)
+}
+
+fn return_mask(vob: SimpleVob) -> u32 {
+    let set = if deny_lists() {
+        TokenSet::from_vob(vob)
+    } else {
+        TokenSet::Allow(vob)
+    };
+    host::return_logit_bias(&set)
 }
 
 pub trait AiciCtrl {
-    /// Called with the initial prompt. ~1000ms time limit.
+    /// Called with the initial prompt. ~1000ms time limit, after which the
+    /// controller is interrupted, and the sequence stopped.
     /// By default ignore prompt.
     /// This is typically just the start token if any (REST API forces empty prompt).
     fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
         InitPromptResult::default()
     }
 
-    /// This is the main entry point for the module. ~20ms time limit.
+    /// This is the main entry point for the module. ~20ms time limit; see
+    /// MidProcessArg::prev_timed_out. Controllers running late for several steps
+    /// are interrupted, and the sequence stopped.
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult;
 
+    /// Called with the sampled token when the branch asked for it (Branch::post_sample),
+    /// before the token is committed. ~10ms time limit, after which the controller
+    /// is interrupted, and the sequence stopped.
+    fn post_sample(&mut self, _arg: PostSampleArg) -> PostSampleResult {
+        PostSampleResult::Accept
+    }
+}
+
+/// Like AiciCtrl, but the calls can fail; the error is passed to the host (as ErrorResult),
+/// which stops the sequence with the error message.
+/// Every AiciCtrl is also a TryAiciCtrl.
+pub trait TryAiciCtrl {
+    fn try_init_prompt(&mut self, _arg: InitPromptArg) -> Result<InitPromptResult, Error> {
+        Ok(InitPromptResult::default())
+    }
+
+    fn try_mid_process(&mut self, arg: MidProcessArg) -> Result<MidProcessResult, Error>;
+
+    fn try_post_sample(&mut self, _arg: PostSampleArg) -> Result<PostSampleResult, Error> {
+        Ok(PostSampleResult::Accept)
+    }
+
     // Internals
     fn aici_init_prompt(&mut self) {
-        let arg: InitPromptArg = serde_json::from_slice(&host::process_arg_bytes()).unwrap();
-        let res = self.init_prompt(arg);
-        let res_bytes = serde_json::to_vec(&res).unwrap();
-        host::return_process_result(&res_bytes);
+        run_call("init_prompt", || {
+            let arg: InitPromptArg = serde_json::from_slice(&host::process_arg_bytes())?;
+            let res = self.try_init_prompt(arg)?;
+            Ok(serde_json::to_vec(&res)?)
+        })
     }
 
     fn aici_mid_process(&mut self) {
-        let arg: MidProcessArg = serde_json::from_slice(&host::process_arg_bytes())
-            .expect("aici_mid_process: failed to deserialize MidProcessArg");
-        let res = self.mid_process(arg);
-        let mut used_logits = false;
-        let res = ProcessResultOffset {
-            branches: res
-                .branches
-                .into_iter()
-                .map(|b| {
-                    b.map_mask(|vob| {
-                        if used_logits {
-                            panic!("aici_mid_process: multiple branches with sampling not yet supported");
-                        }
-                        used_logits = true;
-                        host::return_logit_bias(&vob) as usize
+        run_call("mid_process", || {
+            let arg: MidProcessArg = serde_json::from_slice(&host::process_arg_bytes())?;
+            let res = self.try_mid_process(arg)?;
+            if inline_bias() {
+                // the host wants the whole MidProcessResult, masks included, as JSON
+                return Ok(serde_json::to_vec(&res)?);
+            }
+            let sampling = res.branches.iter().filter(|b| b.sample_mask.is_some());
+            if sampling.count() > 1 {
+                return Err(Error::controller(
+                    "multiple branches with sampling not yet supported",
+                ));
+            }
+            let res = ProcessResultOffset {
+                branches: res
+                    .branches
+                    .into_iter()
+                    .map(|b| Branch {
+                        sample_mask: b.sample_mask.map(|vob| return_mask(vob) as usize),
+                        splices: b.splices,
+                        forced_byte_prefix: b.forced_byte_prefix,
+                        post_sample: b.post_sample,
+                        attention_mask: b.attention_mask,
+                        fork_arg: b.fork_arg,
                     })
-                })
-                .collect(),
+                    .collect(),
+                suspend: res.suspend,
+                max_remaining_tokens: res.max_remaining_tokens,
+                captures: res.captures,
+            };
+            Ok(serde_json::to_vec(&res)?)
+        })
+    }
+
+    fn aici_post_sample(&mut self) {
+        run_call("post_sample", || {
+            let arg: PostSampleArg = serde_json::from_slice(&host::process_arg_bytes())?;
+            let res = self.try_post_sample(arg)?;
+            Ok(serde_json::to_vec(&res)?)
+        })
+    }
+}
+
+impl<T: AiciCtrl> TryAiciCtrl for T {
+    fn try_init_prompt(&mut self, arg: InitPromptArg) -> Result<InitPromptResult, Error> {
+        Ok(self.init_prompt(arg))
+    }
+
+    fn try_mid_process(&mut self, arg: MidProcessArg) -> Result<MidProcessResult, Error> {
+        Ok(self.mid_process(arg))
+    }
+
+    fn try_post_sample(&mut self, arg: PostSampleArg) -> Result<PostSampleResult, Error> {
+        Ok(self.post_sample(arg))
+    }
+}
+
+thread_local! {
+    // the controller call in progress, if any
+    static CURRENT_CALL: std::cell::Cell<Option<&'static str>> = std::cell::Cell::new(None);
+}
+
+/// Run a controller call, passing either its result or an ErrorResult to the host.
+fn run_call(phase: &'static str, f: impl FnOnce() -> Result<Vec<u8>, Error>) {
+    CURRENT_CALL.with(|c| c.set(Some(phase)));
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(Error::Panic(panic_message(payload.as_ref()))));
+    // the panic hook takes the call when it reports the panic itself
+    let reported = CURRENT_CALL.with(|c| c.take()).is_none();
+    match res {
+        Ok(bytes) => host::return_process_result(&bytes),
+        Err(_) if reported => {}
+        Err(e) => return_error(phase, &e),
+    }
+    recording::flush_recording();
+}
+
+pub(crate) fn current_call() -> Option<&'static str> {
+    CURRENT_CALL.with(|c| c.get())
+}
+
+fn return_error(phase: &str, e: &Error) {
+    let err = ErrorResult {
+        message: e.to_string(),
+        phase: phase.to_string(),
+    };
+    // if even this fails, we trap
+    host::return_process_result(&err.to_result_bytes());
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Panics abort in WASM, so run_call() never sees them; instead, the panic hook
+/// reports them to the host, before the trap. Where panics unwind, run_call() then
+/// doesn't report them again.
+#[allow(dead_code, deprecated)]
+pub(crate) fn report_panic(info: &std::panic::PanicInfo) {
+    if let Some(phase) = CURRENT_CALL.with(|c| c.take()) {
+        let msg = match info.location() {
+            Some(loc) => format!("{} at {}", panic_message(info.payload()), loc),
+            None => panic_message(info.payload()),
         };
-        let res_bytes = serde_json::to_vec(&res).expect("aici_mid_process: failed to serialize");
-        host::return_process_result(&res_bytes);
+        return_error(phase, &Error::Panic(msg));
     }
 }
 
+#[macro_export]
+macro_rules! wlog {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log_enabled($level) {
+            $crate::log($level, &format!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! wlog_info {
+    ($($arg:tt)*) => {
+        $crate::wlog!($crate::LogLevel::Info, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! wlog_debug {
+    ($($arg:tt)*) => {
+        $crate::wlog!($crate::LogLevel::Debug, $($arg)*)
+    };
+}
+
+/// Same as wlog_info!().
+#[macro_export]
+macro_rules! wprintln {
+    ($($arg:tt)*) => {
+        $crate::wlog_info!($($arg)*)
+    };
+}
+
 /// Expose method as extern "C", usage:
 ///     expose!(Foo::set_count(n: i32) -> i32);
 /// Generates "C" function:
@@ -242,17 +776,17 @@ macro_rules! expose {
     ($struct_name:ident :: $method_name:ident ( $($arg:ident : $typ:ty),* ) -> $ret:ty) => {
         #[no_mangle]
         pub extern "C" fn $method_name(self_: *mut $struct_name, $($arg : $typ),*) -> $ret {
-            unsafe {
-                (&mut *self_).$method_name($($arg),*)
-            }
+            let self_ = unsafe { self_.as_mut() }
+                .expect(concat!(stringify!($method_name), ": null pointer"));
+            self_.$method_name($($arg),*)
         }
     };
     ($struct_name:ident :: $field:ident :: $method_name:ident ( $($arg:ident : $typ:ty),* ) -> $ret:ty) => {
         #[no_mangle]
         pub extern "C" fn $method_name(self_: *mut $struct_name, $($arg : $typ),*) -> $ret {
-            unsafe {
-                (&mut *self_).$field.$method_name($($arg),*)
-            }
+            let self_ = unsafe { self_.as_mut() }
+                .expect(concat!(stringify!($method_name), ": null pointer"));
+            self_.$field.$method_name($($arg),*)
         }
     };
 }
@@ -260,8 +794,24 @@ macro_rules! expose {
 #[macro_export]
 macro_rules! aici_expose_all {
     ($struct_name:ident, $new:expr) => {
-        $crate::expose!($struct_name::aici_mid_process() -> ());
-        $crate::expose!($struct_name::aici_init_prompt() -> ());
+        #[no_mangle]
+        pub extern "C" fn aici_mid_process(self_: *mut $struct_name) {
+            // the host passes the pointer returned from aici_create()
+            let ctrl = unsafe { self_.as_mut() }.expect("aici_mid_process: null pointer");
+            $crate::TryAiciCtrl::aici_mid_process(ctrl)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn aici_post_sample(self_: *mut $struct_name) {
+            let ctrl = unsafe { self_.as_mut() }.expect("aici_post_sample: null pointer");
+            $crate::TryAiciCtrl::aici_post_sample(ctrl)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn aici_init_prompt(self_: *mut $struct_name) {
+            let ctrl = unsafe { self_.as_mut() }.expect("aici_init_prompt: null pointer");
+            $crate::TryAiciCtrl::aici_init_prompt(ctrl)
+        }
 
         #[no_mangle]
         pub extern "C" fn aici_create() -> *mut $struct_name {
@@ -273,7 +823,7 @@ macro_rules! aici_expose_all {
         pub extern "C" fn aici_panic() {
             panic!("aici_panic()")
         }
-    }
+    };
 }
 
 #[macro_export]
@@ -0,0 +1,54 @@
+use aici_abi::{
+    recognizer::{
+        AiciRecognizer, ByteClass, Literal, Repeat, RepeatState, Seq, SeqState, StackRecognizer,
+    },
+    AiciCtrl, MidProcessArg, MidProcessResult,
+};
+
+// Constrain the output to: "Name: " [A-Z][a-z]+ ", Age: " [0-9]{1,3}
+type Letters = Repeat<ByteClass, bool>;
+type AgePart = Seq<Literal, Letters, RepeatState>;
+type NamePart = Seq<Letters, AgePart, SeqState<usize>>;
+type Word = Seq<ByteClass, NamePart, SeqState<RepeatState>>;
+type NameAge = Seq<Literal, Word, SeqState<bool>>;
+type NameAgeState = SeqState<usize>;
+
+fn name_age() -> NameAge {
+    Seq::new(
+        Literal::new(b"Name: "),
+        Seq::new(
+            ByteClass::range(b'A', b'Z'),
+            Seq::new(
+                Repeat::new(ByteClass::range(b'a', b'z'), 1, usize::MAX),
+                Seq::new(
+                    Literal::new(b", Age: "),
+                    Repeat::new(ByteClass::range(b'0', b'9'), 1, 3),
+                ),
+            ),
+        ),
+    )
+}
+
+pub struct Runner {
+    rec: AiciRecognizer<StackRecognizer<NameAgeState, NameAge>>,
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Runner {
+            rec: AiciRecognizer::from_recognizer(StackRecognizer::from(name_age())),
+        }
+    }
+}
+
+impl AiciCtrl for Runner {
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        self.rec.mid_process(arg)
+    }
+}
+
+fn main() {
+    // test code here?
+}
+
+aici_abi::aici_expose_all!(Runner, Runner::new());
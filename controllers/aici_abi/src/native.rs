@@ -0,0 +1,212 @@
+//! Running controllers natively: in the host process, calling AiciCtrl directly, without
+//! wasm, JSON, or the host functions in between. This is meant for trusted, built-in
+//! constraints; see NativeCtrl.
+
+use crate::{
+    svob::SimpleVob, toktree::TokTrie, AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg,
+    MidProcessResult, ProcessResultOffset, TokenId,
+};
+use std::sync::Arc;
+
+#[cfg(feature = "rx")]
+use crate::{
+    recognizer::BiasCache,
+    rx::{RecRx, RxStackRecognizer},
+};
+
+/// A controller running in the host process. The host functions (tokenize(),
+/// TokTrie::from_host(), storage, etc.) are not available; native controllers get
+/// the host's TokTrie when they are created instead.
+pub type NativeCtrl = Box<dyn AiciCtrl + Send>;
+
+/// Logit bias of allowed tokens (the same as in the masks aicirt passes to the host).
+pub const BIAS_ALLOW: f32 = 0.0;
+/// Logit bias of disallowed tokens.
+pub const BIAS_DISALLOW: f32 = f32::NEG_INFINITY;
+
+/// Masks of mid_process() results, as rows of logit biases of `num_elts` each.
+#[derive(Debug, Clone)]
+pub struct BiasBuffer {
+    data: Vec<f32>,
+    num_elts: usize,
+}
+
+impl BiasBuffer {
+    pub fn new(num_elts: usize) -> Self {
+        assert!(num_elts > 0);
+        BiasBuffer {
+            data: Vec::new(),
+            num_elts,
+        }
+    }
+
+    pub fn num_elts(&self) -> usize {
+        self.num_elts
+    }
+
+    pub fn num_masks(&self) -> usize {
+        self.data.len() / self.num_elts
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Append the mask as a row of biases; returns its index.
+    /// Tokens past the end of the mask are disallowed, and ones past `num_elts` dropped.
+    pub fn push(&mut self, mask: &SimpleVob) -> usize {
+        let idx = self.num_masks();
+        let start = self.data.len();
+        self.data.resize(start + self.num_elts, BIAS_DISALLOW);
+        let row = &mut self.data[start..];
+        let len = std::cmp::min(mask.len(), self.num_elts);
+        for (tok, bias) in row[..len].iter_mut().enumerate() {
+            if mask.is_allowed(tok as TokenId) {
+                *bias = BIAS_ALLOW;
+            }
+        }
+        idx
+    }
+
+    pub fn mask(&self, idx: usize) -> &[f32] {
+        &self.data[idx * self.num_elts..(idx + 1) * self.num_elts]
+    }
+
+    /// All the rows, one after another.
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+}
+
+/// Call mid_process(), and move the masks of the branches to `biases`; the result
+/// refers to them by index, like the results aicirt returns.
+pub fn mid_process_into(
+    ctrl: &mut dyn AiciCtrl,
+    arg: MidProcessArg,
+    biases: &mut BiasBuffer,
+) -> ProcessResultOffset {
+    let res = ctrl.mid_process(arg);
+    ProcessResultOffset {
+        branches: res
+            .branches
+            .iter()
+            .map(|b| b.map_mask(|mask| biases.push(mask)))
+            .collect(),
+        suspend: res.suspend,
+        max_remaining_tokens: res.max_remaining_tokens,
+        captures: res.captures,
+    }
+}
+
+fn has_eos(trie: &TokTrie, arg: &MidProcessArg) -> bool {
+    arg.tokens.contains(&trie.eos_token())
+}
+
+/// Constrains the output to a regular expression (anchored at both ends, without
+/// Unicode classes); EOS is allowed once the output matches.
+/// The sequence stops after EOS, or when no token can continue the output.
+#[cfg(feature = "rx")]
+pub struct RegexCtrl {
+    trie: Arc<TokTrie>,
+    rec: RxStackRecognizer,
+    tokens: Vec<TokenId>,
+    bias_cache: BiasCache,
+}
+
+#[cfg(feature = "rx")]
+impl RegexCtrl {
+    pub fn new(trie: Arc<TokTrie>, rx: &str) -> anyhow::Result<Self> {
+        Ok(RegexCtrl {
+            trie,
+            rec: RecRx::try_from_rx(rx)?.to_stack_recognizer(),
+            tokens: Vec::new(),
+            bias_cache: BiasCache::default(),
+        })
+    }
+}
+
+#[cfg(feature = "rx")]
+impl AiciCtrl for RegexCtrl {
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        if has_eos(&self.trie, &arg) {
+            return MidProcessResult::stop();
+        }
+        arg.save_tokens(&mut self.tokens);
+        if arg.backtrack > 0 {
+            // the recognizer is collapsed after each token, so it can't pop them
+            self.rec.reset();
+            self.trie.append_tokens(&mut self.rec, &self.tokens);
+        } else {
+            self.trie.append_tokens(&mut self.rec, &arg.tokens);
+        }
+        let mut set = self.trie.alloc_token_set();
+        self.trie
+            .compute_bias_cached(&mut self.rec, &mut self.bias_cache, &mut set);
+        if set.num_set() == 0 {
+            return MidProcessResult::stop();
+        }
+        MidProcessResult::sample(set)
+    }
+}
+
+/// Allows all tokens, and stops the sequence once the output contains one of the stop
+/// sequences (the stop sequence is kept in the output).
+pub struct StopSequenceCtrl {
+    trie: Arc<TokTrie>,
+    stops: Vec<Vec<u8>>,
+    // the output so far, and the length in bytes of the prompt before it
+    output: Vec<u8>,
+    prompt_bytes: u64,
+}
+
+impl StopSequenceCtrl {
+    pub fn new(trie: Arc<TokTrie>, stops: &[&str]) -> Self {
+        StopSequenceCtrl {
+            trie,
+            stops: stops
+                .iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.as_bytes().to_vec())
+                .collect(),
+            output: Vec::new(),
+            prompt_bytes: 0,
+        }
+    }
+
+    // whether a stop sequence ends in the output at or after `start`
+    fn stop_after(&self, start: usize) -> bool {
+        self.stops.iter().any(|stop| {
+            let from = start.saturating_sub(stop.len() - 1);
+            self.output[from..]
+                .windows(stop.len())
+                .any(|w| w == stop.as_slice())
+        })
+    }
+}
+
+impl AiciCtrl for StopSequenceCtrl {
+    fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
+        self.prompt_bytes = self.trie.decode(&arg.prompt).len() as u64;
+        InitPromptResult::default()
+    }
+
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        if has_eos(&self.trie, &arg) {
+            return MidProcessResult::stop();
+        }
+        // byte_offset already accounts for the backtrack
+        let added = self.trie.decode(&arg.tokens);
+        let kept = arg
+            .byte_offset
+            .saturating_sub(self.prompt_bytes + added.len() as u64);
+        let start = std::cmp::min(kept as usize, self.output.len());
+        self.output.truncate(start);
+        self.output.extend_from_slice(&added);
+        if self.stop_after(start) {
+            return MidProcessResult::stop();
+        }
+        let mut set = self.trie.alloc_token_set();
+        set.set_all(true);
+        MidProcessResult::sample(set)
+    }
+}
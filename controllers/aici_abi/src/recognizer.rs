@@ -1,21 +1,36 @@
 use crate::{
+    bytes::ByteSet,
+    svob::SimpleVob,
     toktree::{Recognizer, SpecialToken, TokTrie},
-    AiciCtrl, MidProcessArg, MidProcessResult,
+    AiciCtrl, MidProcessArg, MidProcessResult, TokenId,
 };
-use std::fmt::Debug;
+use std::{cell::RefCell, collections::HashMap, fmt::Debug, hash::Hash};
 
 pub struct AiciRecognizer<R: Recognizer> {
     pub trie: TokTrie,
     pub rec: R,
+    /// The recognizer before any tokens; on backtrack, the tokens are replayed from here.
+    initial: R,
+    /// Tokens appended to `rec` so far.
+    tokens: Vec<TokenId>,
+    bias_cache: BiasCache,
 }
 
-impl<R: Recognizer> AiciRecognizer<R> {
+impl<R: Recognizer + Clone> AiciRecognizer<R> {
     pub fn from_recognizer(rec: R) -> Self {
         AiciRecognizer {
             trie: TokTrie::from_host(),
+            initial: rec.clone(),
             rec,
+            tokens: Vec::new(),
+            bias_cache: BiasCache::default(),
         }
     }
+
+    /// The tokens passed to the recognizer, backtracking included.
+    pub fn tokens(&self) -> &[TokenId] {
+        &self.tokens
+    }
 }
 
 impl<R: Recognizer + Clone> AiciCtrl for AiciRecognizer<R> {
@@ -23,13 +38,185 @@ impl<R: Recognizer + Clone> AiciCtrl for AiciRecognizer<R> {
         if arg.has_eos() {
             return MidProcessResult::stop();
         }
-        self.trie.append_tokens(&mut self.rec, &arg.tokens);
+        arg.save_tokens(&mut self.tokens);
+        if arg.backtrack > 0 {
+            // the recognizer is collapsed after each token, so it can't pop them
+            self.rec = self.initial.clone();
+            self.trie.append_tokens(&mut self.rec, &self.tokens);
+        } else {
+            self.trie.append_tokens(&mut self.rec, &arg.tokens);
+        }
         let mut set = self.trie.alloc_token_set();
-        self.trie.compute_bias(&mut self.rec, &mut set);
+        self.trie
+            .compute_bias_cached(&mut self.rec, &mut self.bias_cache, &mut set);
         MidProcessResult::sample(set)
     }
 }
 
+/// Token sets computed by TokTrie::compute_bias_cached(), by Recognizer::state_key().
+/// Only the most recently used few are kept; with share(), the host's cache
+/// (see crate::bias_cache_get()) backs them.
+#[derive(Clone)]
+pub struct BiasCache {
+    // most recently used last; (key, check, set)
+    entries: Vec<(u64, Vec<u64>, SimpleVob)>,
+    capacity: usize,
+    hits: usize,
+    misses: usize,
+    // see share()
+    namespace: Option<u64>,
+    shared_hits: usize,
+}
+
+impl Default for BiasCache {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl BiasCache {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        BiasCache {
+            entries: Vec::new(),
+            capacity,
+            hits: 0,
+            misses: 0,
+            namespace: None,
+            shared_hits: 0,
+        }
+    }
+
+    /// Also look for sets in the host's cache, shared with other sequences running
+    /// the controller, and put the inserted ones there. The `namespace` (e.g., a hash
+    /// of the grammar) has to tell apart all recognizers whose state keys could mean
+    /// different things; None stops the sharing.
+    pub fn share(&mut self, namespace: Option<u64>) {
+        self.namespace = namespace;
+    }
+
+    /// Copy the set for `key` to `set`, if there is one.
+    pub fn get(&mut self, key: u64, set: &mut SimpleVob) -> bool {
+        self.get_checked(key, &[], set)
+    }
+
+    /// Like get(), but only takes a set inserted with the same `check`; e.g., the state
+    /// `key` is a hash of, so that another state with a colliding key gets its own set.
+    pub fn get_checked(&mut self, key: u64, check: &[u64], set: &mut SimpleVob) -> bool {
+        match self
+            .entries
+            .iter()
+            .position(|(k, c, _)| *k == key && c == check)
+        {
+            Some(idx) => {
+                let entry = self.entries.remove(idx);
+                set.clone_from(&entry.2);
+                self.entries.push(entry);
+                self.hits += 1;
+                true
+            }
+            None => match self.get_shared(key, check, set.len()) {
+                Some(shared) => {
+                    set.clone_from(&shared);
+                    self.insert_local(key, check.to_vec(), shared);
+                    self.shared_hits += 1;
+                    true
+                }
+                None => {
+                    self.misses += 1;
+                    false
+                }
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: u64, set: &SimpleVob) {
+        self.insert_checked(key, &[], set)
+    }
+
+    /// Insert a set for get_checked().
+    pub fn insert_checked(&mut self, key: u64, check: &[u64], set: &SimpleVob) {
+        if let Some(ns) = self.namespace {
+            // the namespace, key and check are stored too, and compared by get_shared(),
+            // so that a collision of the combined key can't bring in a set of another
+            // recognizer (or state)
+            let mut value = Vec::with_capacity(24 + 8 * check.len() + set.len() / 8);
+            value.extend_from_slice(&ns.to_le_bytes());
+            value.extend_from_slice(&key.to_le_bytes());
+            value.extend_from_slice(&(check.len() as u64).to_le_bytes());
+            for w in check {
+                value.extend_from_slice(&w.to_le_bytes());
+            }
+            value.extend_from_slice(&set.to_bytes());
+            crate::bias_cache_put(Self::shared_key(ns, key), &value);
+        }
+        self.insert_local(key, check.to_vec(), set.clone());
+    }
+
+    fn insert_local(&mut self, key: u64, check: Vec<u64>, set: SimpleVob) {
+        self.entries.retain(|(k, _, _)| *k != key);
+        if self.entries.len() >= self.capacity {
+            self.entries.remove(0);
+        }
+        self.entries.push((key, check, set));
+    }
+
+    // all sequences have to agree on the key, so it can't depend on the Rust version
+    // (as DefaultHasher does); this is splitmix64, applied to both words
+    fn shared_key(namespace: u64, key: u64) -> u64 {
+        fn mix(x: u64) -> u64 {
+            let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        }
+        mix(mix(namespace) ^ key)
+    }
+
+    fn get_shared(&self, key: u64, check: &[u64], len: usize) -> Option<SimpleVob> {
+        let ns = self.namespace?;
+        let value = crate::bias_cache_get(Self::shared_key(ns, key))?;
+        let check_end = 24 + 8 * check.len();
+        if value.len() < check_end {
+            return None;
+        }
+        if value[0..8] != ns.to_le_bytes()
+            || value[8..16] != key.to_le_bytes()
+            || value[16..24] != (check.len() as u64).to_le_bytes()
+        {
+            return None;
+        }
+        let same_check = value[24..check_end]
+            .chunks_exact(8)
+            .zip(check)
+            .all(|(b, w)| *b == w.to_le_bytes());
+        if !same_check {
+            return None;
+        }
+        SimpleVob::from_bytes(&value[check_end..]).filter(|set| set.len() == len)
+    }
+
+    /// Drop all the sets, e.g., when the recognizer is replaced; keeps the counters.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Number of get() calls that found a set (not counting shared_hits()).
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Number of get() calls that found a set only in the host's cache; see share().
+    pub fn shared_hits(&self) -> usize {
+        self.shared_hits
+    }
+
+    /// Number of get() calls that didn't find a set.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
 pub trait FunctionalRecognizer<S: Copy> {
     /// Initial state
     fn initial(&self) -> S;
@@ -37,8 +224,31 @@ pub trait FunctionalRecognizer<S: Copy> {
     fn append(&self, state: S, byte: u8) -> S;
     /// Check if given byte is allowed in given state.
     fn byte_allowed(&self, state: S, byte: u8) -> bool;
+    /// All the bytes allowed in given state; must agree with byte_allowed().
+    /// Defaults to asking byte_allowed() about every byte; override with a precomputed
+    /// (or otherwise cheap) set, together with has_fast_allowed_set().
+    fn allowed_set(&self, state: S) -> ByteSet {
+        ByteSet::from_fn(|b| self.byte_allowed(state, b))
+    }
+    /// If true, allowed_set() is cheap, and the trie walk in TokTrie::compute_bias()
+    /// uses it for nodes with many children, instead of trying each child.
+    fn has_fast_allowed_set(&self) -> bool {
+        false
+    }
     /// Check if given special token is allowed in given state.
+    /// This is asked about every special token of the tokenizer (BOS, chat markers, etc.),
+    /// not only EOS; return false for the ones the recognizer doesn't deal with.
     fn special_allowed(&self, state: S, tok: SpecialToken) -> bool;
+    /// Check if the bytes so far form a complete match.
+    /// Defaults to whether EOS is allowed in given state.
+    fn is_accepting(&self, state: S) -> bool {
+        self.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+    /// See Recognizer::state_key(); states with the same key must allow the same
+    /// sequences of bytes and special tokens.
+    fn state_key(&self, _state: S) -> Option<u64> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -46,6 +256,9 @@ pub struct StackRecognizer<S: Copy, R: FunctionalRecognizer<S>> {
     rec: R,
     stack: Vec<S>,
     stack_ptr: usize,
+    // see allow_prefix_eos()
+    prefix_eos: bool,
+    accepting_in_token: bool,
 }
 
 impl<S: Copy, R: FunctionalRecognizer<S>> StackRecognizer<S, R> {
@@ -55,12 +268,39 @@ impl<S: Copy, R: FunctionalRecognizer<S>> StackRecognizer<S, R> {
             rec,
             stack,
             stack_ptr: 0,
+            prefix_eos: false,
+            accepting_in_token: false,
         }
     }
 
+    /// Also allow EOS after a token whose bytes formed a complete match at some point,
+    /// even if the state after all of them is not accepting; e.g., after "42 " for `[0-9]+`.
+    /// The output then ends with the bytes past the match.
+    pub fn allow_prefix_eos(mut self) -> Self {
+        self.prefix_eos = true;
+        self
+    }
+
+    /// Whether any state reached while appending the bytes of the last token
+    /// (before collapse()) was accepting, the state after the last byte included.
+    pub fn was_accepting_in_token(&self) -> bool {
+        self.accepting_in_token
+    }
+
     pub fn reset(&mut self) {
+        self.reset_to(self.rec.initial());
+    }
+
+    /// The state after the bytes appended so far.
+    pub fn state(&self) -> S {
+        self.stack[self.stack_ptr]
+    }
+
+    /// Start over from the given state, e.g., one derived from MidProcessArg::byte_offset.
+    pub fn reset_to(&mut self, state: S) {
         self.stack_ptr = 0;
-        self.stack[0] = self.rec.initial();
+        self.stack[0] = state;
+        self.accepting_in_token = false;
     }
 }
 
@@ -91,12 +331,33 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
         self.rec.byte_allowed(self.stack[self.stack_ptr], byte)
     }
 
+    fn allowed_byte_mask(&mut self, mask_out: &mut [bool; 256]) {
+        self.rec
+            .allowed_set(self.stack[self.stack_ptr])
+            .fill_mask(mask_out)
+    }
+
+    fn has_fast_byte_mask(&self) -> bool {
+        self.rec.has_fast_allowed_set()
+    }
+
+    fn state_key(&self) -> Option<u64> {
+        if self.eos_after_match() {
+            // EOS depends on the token, not only on the state
+            return None;
+        }
+        self.rec.state_key(self.stack[self.stack_ptr])
+    }
+
     fn trie_finished(&mut self) {
         // println!("{:?}", &self.stack[0..=self.stack_ptr]);
         assert!(self.stack_ptr == 0);
     }
 
     fn collapse(&mut self) {
+        self.accepting_in_token = self.stack[1..=self.stack_ptr]
+            .iter()
+            .any(|s| self.rec.is_accepting(*s));
         self.stack[0] = self.stack[self.stack_ptr];
         self.stack_ptr = 0;
     }
@@ -105,6 +366,10 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
         self.rec.special_allowed(self.stack[self.stack_ptr], tok)
     }
 
+    fn eos_after_match(&self) -> bool {
+        self.prefix_eos && self.accepting_in_token && self.stack_ptr == 0
+    }
+
     #[inline(always)]
     fn try_push_byte(&mut self, byte: u8) -> bool {
         if self.rec.byte_allowed(self.stack[self.stack_ptr], byte) {
@@ -132,7 +397,520 @@ impl FunctionalRecognizer<()> for AnythingGoes {
         true
     }
 
-    fn special_allowed(&self, _state: (), _tok: SpecialToken) -> bool {
+    fn allowed_set(&self, _state: ()) -> ByteSet {
+        ByteSet::full()
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
         true
     }
+
+    fn state_key(&self, _state: ()) -> Option<u64> {
+        Some(0)
+    }
+
+    // any text, but not chat markers and the like
+    fn special_allowed(&self, _state: (), tok: SpecialToken) -> bool {
+        tok == SpecialToken::EndOfSentence
+    }
+}
+
+/// EOS is allowed when the composite recognizer is accepting; other special tokens never are.
+fn composite_special_allowed(accepting: bool, tok: SpecialToken) -> bool {
+    match tok {
+        SpecialToken::EndOfSentence => accepting,
+        _ => false,
+    }
+}
+
+/// Matches exactly the given bytes. The state is the number of bytes matched so far.
+#[derive(Clone)]
+pub struct Literal {
+    bytes: Vec<u8>,
+}
+
+impl Literal {
+    pub fn new(bytes: &[u8]) -> Self {
+        Literal {
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+impl FunctionalRecognizer<usize> for Literal {
+    fn initial(&self) -> usize {
+        0
+    }
+
+    fn append(&self, state: usize, _byte: u8) -> usize {
+        state + 1
+    }
+
+    fn byte_allowed(&self, state: usize, byte: u8) -> bool {
+        state < self.bytes.len() && self.bytes[state] == byte
+    }
+
+    fn allowed_set(&self, state: usize) -> ByteSet {
+        match self.bytes.get(state) {
+            Some(&b) => ByteSet::from_byte(b),
+            None => ByteSet::new(),
+        }
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
+        true
+    }
+
+    fn special_allowed(&self, state: usize, tok: SpecialToken) -> bool {
+        composite_special_allowed(self.is_accepting(state), tok)
+    }
+
+    fn is_accepting(&self, state: usize) -> bool {
+        state == self.bytes.len()
+    }
+}
+
+/// Matches a single byte from the given set. The state is whether the byte was seen.
+#[derive(Clone)]
+pub struct ByteClass {
+    allowed: ByteSet,
+}
+
+impl ByteClass {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self::from_set(ByteSet::from_bytes(bytes))
+    }
+
+    /// Bytes from `from` to `to` inclusive, like [a-z].
+    pub fn range(from: u8, to: u8) -> Self {
+        Self::from_set(ByteSet::from_range(from..=to))
+    }
+
+    pub fn from_set(allowed: ByteSet) -> Self {
+        ByteClass { allowed }
+    }
+}
+
+impl FunctionalRecognizer<bool> for ByteClass {
+    fn initial(&self) -> bool {
+        false
+    }
+
+    fn append(&self, _state: bool, _byte: u8) -> bool {
+        true
+    }
+
+    fn byte_allowed(&self, state: bool, byte: u8) -> bool {
+        !state && self.allowed.contains(byte)
+    }
+
+    fn allowed_set(&self, state: bool) -> ByteSet {
+        if state {
+            ByteSet::new()
+        } else {
+            self.allowed
+        }
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
+        true
+    }
+
+    fn special_allowed(&self, state: bool, tok: SpecialToken) -> bool {
+        composite_special_allowed(state, tok)
+    }
+
+    fn is_accepting(&self, state: bool) -> bool {
+        state
+    }
+}
+
+fn step<S: Copy>(rec: &impl FunctionalRecognizer<S>, state: Option<S>, byte: u8) -> Option<S> {
+    match state {
+        Some(s) if rec.byte_allowed(s, byte) => Some(rec.append(s, byte)),
+        _ => None,
+    }
+}
+
+fn accepts<S: Copy>(rec: &impl FunctionalRecognizer<S>, state: Option<S>) -> bool {
+    state.is_some_and(|s| rec.is_accepting(s))
+}
+
+fn allows<S: Copy>(rec: &impl FunctionalRecognizer<S>, state: Option<S>, byte: u8) -> bool {
+    state.is_some_and(|s| rec.byte_allowed(s, byte))
+}
+
+fn allowed<S: Copy>(rec: &impl FunctionalRecognizer<S>, state: Option<S>) -> ByteSet {
+    state.map_or(ByteSet::new(), |s| rec.allowed_set(s))
+}
+
+/// Id of a set of states of a part of Seq or Repeat; see StateSets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StateSetId(u32);
+
+impl StateSetId {
+    const EMPTY: StateSetId = StateSetId(0);
+}
+
+#[derive(Clone)]
+struct StateSet<S> {
+    states: Vec<S>,
+    allowed: ByteSet,
+    accepting: bool,
+}
+
+/// Seq and Repeat track all the ways of splitting the bytes among their parts, each with
+/// a state of the part; the sets of these states are interned here, so that the states
+/// of the combinators stay Copy. The transitions between the sets are cached as they are
+/// computed, so appending a byte is mostly a lookup.
+#[derive(Clone)]
+struct StateSets<S> {
+    sets: Vec<StateSet<S>>,
+    ids: HashMap<Vec<S>, StateSetId>,
+    // (set, flag, byte) -> set; the flag is whether a new part is started
+    transitions: HashMap<(StateSetId, bool, u8), StateSetId>,
+}
+
+impl<S: Copy + Eq + Hash> StateSets<S> {
+    fn new() -> Self {
+        let mut sets = StateSets {
+            sets: Vec::new(),
+            ids: HashMap::new(),
+            transitions: HashMap::new(),
+        };
+        let empty = sets.intern(Vec::new(), |_| ByteSet::new(), |_| false);
+        assert!(empty == StateSetId::EMPTY);
+        sets
+    }
+
+    fn get(&self, id: StateSetId) -> &StateSet<S> {
+        &self.sets[id.0 as usize]
+    }
+
+    fn intern(
+        &mut self,
+        states: Vec<S>,
+        allowed: impl Fn(S) -> ByteSet,
+        accepting: impl Fn(S) -> bool,
+    ) -> StateSetId {
+        let mut uniq = Vec::with_capacity(states.len());
+        for s in states {
+            if !uniq.contains(&s) {
+                uniq.push(s);
+            }
+        }
+        if let Some(id) = self.ids.get(&uniq) {
+            return *id;
+        }
+        let id = StateSetId(self.sets.len() as u32);
+        self.sets.push(StateSet {
+            allowed: uniq.iter().fold(ByteSet::new(), |r, s| r | allowed(*s)),
+            accepting: uniq.iter().any(|s| accepting(*s)),
+            states: uniq.clone(),
+        });
+        self.ids.insert(uniq, id);
+        id
+    }
+
+    fn transition(
+        &mut self,
+        id: StateSetId,
+        start: bool,
+        byte: u8,
+        next: impl FnOnce(&[S]) -> Vec<S>,
+        allowed: impl Fn(S) -> ByteSet,
+        accepting: impl Fn(S) -> bool,
+    ) -> StateSetId {
+        if let Some(r) = self.transitions.get(&(id, start, byte)) {
+            return *r;
+        }
+        let states = next(&self.get(id).states);
+        let r = self.intern(states, allowed, accepting);
+        self.transitions.insert((id, start, byte), r);
+        r
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SeqState<SA> {
+    a: Option<SA>,
+    /// The copies of B, one started at every byte after which A was accepting.
+    b: StateSetId,
+}
+
+/// Matches A followed by B.
+/// B is started whenever A is accepting, and all the started copies are tracked,
+/// so e.g. `Seq::new(Repeat::new(a, 0, usize::MAX), Literal::new(b"aab"))` matches "aaab".
+/// SB is the state type of B.
+#[derive(Clone)]
+pub struct Seq<A, B, SB> {
+    a: A,
+    b: B,
+    b_initial: SB,
+    b_initial_allowed: ByteSet,
+    b_initial_accepting: bool,
+    b_sets: RefCell<StateSets<SB>>,
+}
+
+impl<A, SB: Copy + Eq + Hash, B: FunctionalRecognizer<SB>> Seq<A, B, SB> {
+    pub fn new(a: A, b: B) -> Self {
+        let b_initial = b.initial();
+        Seq {
+            a,
+            b_initial_allowed: b.allowed_set(b_initial),
+            b_initial_accepting: b.is_accepting(b_initial),
+            b,
+            b_initial,
+            b_sets: RefCell::new(StateSets::new()),
+        }
+    }
+}
+
+impl<SA: Copy, SB: Copy + Eq + Hash, A: FunctionalRecognizer<SA>, B: FunctionalRecognizer<SB>>
+    FunctionalRecognizer<SeqState<SA>> for Seq<A, B, SB>
+{
+    fn initial(&self) -> SeqState<SA> {
+        SeqState {
+            a: Some(self.a.initial()),
+            b: StateSetId::EMPTY,
+        }
+    }
+
+    fn append(&self, state: SeqState<SA>, byte: u8) -> SeqState<SA> {
+        let start = accepts(&self.a, state.a);
+        let b = self.b_sets.borrow_mut().transition(
+            state.b,
+            start,
+            byte,
+            |states| {
+                states
+                    .iter()
+                    .copied()
+                    .chain(start.then_some(self.b_initial))
+                    .filter(|s| self.b.byte_allowed(*s, byte))
+                    .map(|s| self.b.append(s, byte))
+                    .collect()
+            },
+            |s| self.b.allowed_set(s),
+            |s| self.b.is_accepting(s),
+        );
+        SeqState {
+            a: step(&self.a, state.a, byte),
+            b,
+        }
+    }
+
+    fn byte_allowed(&self, state: SeqState<SA>, byte: u8) -> bool {
+        allows(&self.a, state.a, byte)
+            || self.b_sets.borrow().get(state.b).allowed.contains(byte)
+            || (accepts(&self.a, state.a) && self.b_initial_allowed.contains(byte))
+    }
+
+    fn allowed_set(&self, state: SeqState<SA>) -> ByteSet {
+        let mut r = allowed(&self.a, state.a) | self.b_sets.borrow().get(state.b).allowed;
+        if accepts(&self.a, state.a) {
+            r |= self.b_initial_allowed;
+        }
+        r
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
+        self.a.has_fast_allowed_set()
+    }
+
+    fn special_allowed(&self, state: SeqState<SA>, tok: SpecialToken) -> bool {
+        composite_special_allowed(self.is_accepting(state), tok)
+    }
+
+    fn is_accepting(&self, state: SeqState<SA>) -> bool {
+        self.b_sets.borrow().get(state.b).accepting
+            || (accepts(&self.a, state.a) && self.b_initial_accepting)
+    }
+}
+
+/// Matches either A or B; both are tracked in parallel.
+#[derive(Clone)]
+pub struct Alt<A, B>(pub A, pub B);
+
+impl<SA: Copy, SB: Copy, A: FunctionalRecognizer<SA>, B: FunctionalRecognizer<SB>>
+    FunctionalRecognizer<(Option<SA>, Option<SB>)> for Alt<A, B>
+{
+    fn initial(&self) -> (Option<SA>, Option<SB>) {
+        (Some(self.0.initial()), Some(self.1.initial()))
+    }
+
+    fn append(&self, state: (Option<SA>, Option<SB>), byte: u8) -> (Option<SA>, Option<SB>) {
+        (step(&self.0, state.0, byte), step(&self.1, state.1, byte))
+    }
+
+    fn byte_allowed(&self, state: (Option<SA>, Option<SB>), byte: u8) -> bool {
+        allows(&self.0, state.0, byte) || allows(&self.1, state.1, byte)
+    }
+
+    fn allowed_set(&self, state: (Option<SA>, Option<SB>)) -> ByteSet {
+        allowed(&self.0, state.0) | allowed(&self.1, state.1)
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
+        self.0.has_fast_allowed_set() && self.1.has_fast_allowed_set()
+    }
+
+    fn special_allowed(&self, state: (Option<SA>, Option<SB>), tok: SpecialToken) -> bool {
+        composite_special_allowed(self.is_accepting(state), tok)
+    }
+
+    fn is_accepting(&self, state: (Option<SA>, Option<SB>)) -> bool {
+        accepts(&self.0, state.0) || accepts(&self.1, state.1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct RepeatThread<S> {
+    /// State of the current repetition.
+    inner: S,
+    /// Whether any byte was appended to the current repetition.
+    started: bool,
+    /// Number of finished repetitions before the current one
+    /// (at most `min` when there is no `max`).
+    done: usize,
+}
+
+/// The ways of splitting the bytes so far into repetitions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RepeatState(StateSetId);
+
+/// Matches between `min` and `max` (inclusive) repetitions of A.
+/// Whenever the current repetition is accepting, both continuing it and starting
+/// a new one are tracked. S is the state type of A.
+#[derive(Clone)]
+pub struct Repeat<A, S> {
+    rec: A,
+    min: usize,
+    max: usize,
+    inner_initial: S,
+    initial: RepeatState,
+    sets: RefCell<StateSets<RepeatThread<S>>>,
+}
+
+impl<S: Copy + Eq + Hash, A: FunctionalRecognizer<S>> Repeat<A, S> {
+    /// Use `usize::MAX` for `max` for unbounded repetition.
+    pub fn new(rec: A, min: usize, max: usize) -> Self {
+        assert!(min <= max);
+        let inner_initial = rec.initial();
+        let mut r = Repeat {
+            rec,
+            min,
+            max,
+            inner_initial,
+            initial: RepeatState(StateSetId::EMPTY),
+            sets: RefCell::new(StateSets::new()),
+        };
+        let first = RepeatThread {
+            inner: inner_initial,
+            started: false,
+            done: 0,
+        };
+        let id = r.sets.borrow_mut().intern(
+            vec![first],
+            |t| r.thread_allowed(t),
+            |t| r.thread_accepting(t),
+        );
+        r.initial = RepeatState(id);
+        r
+    }
+
+    fn can_start(&self, t: RepeatThread<S>) -> bool {
+        t.started && self.rec.is_accepting(t.inner) && t.done + 1 < self.max
+    }
+
+    fn thread_allowed(&self, t: RepeatThread<S>) -> ByteSet {
+        if t.started {
+            let mut r = self.rec.allowed_set(t.inner);
+            if self.can_start(t) {
+                r |= self.rec.allowed_set(self.inner_initial);
+            }
+            r
+        } else if t.done < self.max {
+            self.rec.allowed_set(t.inner)
+        } else {
+            ByteSet::new()
+        }
+    }
+
+    fn thread_accepting(&self, t: RepeatThread<S>) -> bool {
+        if t.started {
+            self.rec.is_accepting(t.inner) && t.done + 1 >= self.min
+        } else {
+            t.done >= self.min
+        }
+    }
+
+    fn thread_append(&self, t: RepeatThread<S>, byte: u8, out: &mut Vec<RepeatThread<S>>) {
+        if (t.started || t.done < self.max) && self.rec.byte_allowed(t.inner, byte) {
+            out.push(RepeatThread {
+                inner: self.rec.append(t.inner, byte),
+                started: true,
+                done: t.done,
+            });
+        }
+        if self.can_start(t) && self.rec.byte_allowed(self.inner_initial, byte) {
+            let done = t.done + 1;
+            out.push(RepeatThread {
+                inner: self.rec.append(self.inner_initial, byte),
+                started: true,
+                // with no upper bound, all the counts past `min` are the same
+                done: if self.max == usize::MAX {
+                    done.min(self.min)
+                } else {
+                    done
+                },
+            });
+        }
+    }
+}
+
+impl<S: Copy + Eq + Hash, A: FunctionalRecognizer<S>> FunctionalRecognizer<RepeatState>
+    for Repeat<A, S>
+{
+    fn initial(&self) -> RepeatState {
+        self.initial
+    }
+
+    fn append(&self, state: RepeatState, byte: u8) -> RepeatState {
+        let id = self.sets.borrow_mut().transition(
+            state.0,
+            false,
+            byte,
+            |threads| {
+                let mut r = Vec::new();
+                for t in threads {
+                    self.thread_append(*t, byte, &mut r);
+                }
+                r
+            },
+            |t| self.thread_allowed(t),
+            |t| self.thread_accepting(t),
+        );
+        RepeatState(id)
+    }
+
+    fn byte_allowed(&self, state: RepeatState, byte: u8) -> bool {
+        self.sets.borrow().get(state.0).allowed.contains(byte)
+    }
+
+    fn allowed_set(&self, state: RepeatState) -> ByteSet {
+        self.sets.borrow().get(state.0).allowed
+    }
+
+    fn has_fast_allowed_set(&self) -> bool {
+        true
+    }
+
+    fn special_allowed(&self, state: RepeatState, tok: SpecialToken) -> bool {
+        composite_special_allowed(self.is_accepting(state), tok)
+    }
+
+    fn is_accepting(&self, state: RepeatState) -> bool {
+        self.sets.borrow().get(state.0).accepting
+    }
 }
@@ -0,0 +1,281 @@
+//! Recording of what passes between the host and the controller, so that a session
+//! can be replayed natively, without the model (see replay::replay_session()).
+//!
+//! The log is a sequence of records, each with a 10-byte header: kind (u8), phase (u8),
+//! step (u32) and data length (u32), all little-endian, followed by the data.
+//! Steps count the controller calls (init_prompt(), mid_process()); records from before
+//! the first call (e.g., reading the tokenizer when the controller is created) are in step 0.
+
+use crate::{
+    bytes::{clone_vec_as_bytes, TokenId},
+    host::{self, RuntimeInfo, StorageCmd, StorageResp},
+    svob::TokenSet,
+};
+use anyhow::{bail, Result};
+use std::cell::RefCell;
+
+const HEADER_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// arg_bytes()
+    ModuleArg = 1,
+    /// trie_bytes()
+    TokTrie = 2,
+    /// get_config(): the value (i32), then the name.
+    Config = 3,
+    /// process_arg_bytes(); starts a new step.
+    ProcessArg = 4,
+    /// return_process_result()
+    ProcessResult = 5,
+    /// return_logit_bias() with a mask: the value returned by the host (u32),
+    /// then the mask (u32 words).
+    LogitBias = 6,
+    /// storage_cmd(): the command, as JSON.
+    StorageCmd = 7,
+    /// The response to the preceding StorageCmd, as JSON.
+    StorageResp = 8,
+    /// tokenize_bytes(): the input.
+    Tokenize = 9,
+    /// The result of the preceding Tokenize (u32 each).
+    Tokens = 10,
+    /// rand_seed() (u64)
+    RandSeed = 11,
+    /// self_seq_id() (u32)
+    SelfSeqId = 12,
+    /// return_logit_bias() with a deny list: the value returned by the host (u32),
+    /// then the denied tokens (u32 each).
+    LogitDenyList = 13,
+    /// runtime_info(): the header as passed by the host (empty for hosts without it).
+    RuntimeInfo = 14,
+}
+
+impl RecordKind {
+    fn from_u8(v: u8) -> Option<Self> {
+        use RecordKind::*;
+        [
+            ModuleArg,
+            TokTrie,
+            Config,
+            ProcessArg,
+            ProcessResult,
+            LogitBias,
+            StorageCmd,
+            StorageResp,
+            Tokenize,
+            Tokens,
+            RandSeed,
+            SelfSeqId,
+            LogitDenyList,
+            RuntimeInfo,
+        ]
+        .into_iter()
+        .find(|k| *k as u8 == v)
+    }
+}
+
+/// The controller call a record comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordPhase {
+    /// Outside of a call; typically creating the controller.
+    Setup = 0,
+    InitPrompt = 1,
+    MidProcess = 2,
+    PostSample = 3,
+}
+
+impl RecordPhase {
+    fn from_call(call: Option<&str>) -> Self {
+        match call {
+            Some("init_prompt") => RecordPhase::InitPrompt,
+            Some("mid_process") => RecordPhase::MidProcess,
+            Some("post_sample") => RecordPhase::PostSample,
+            _ => RecordPhase::Setup,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(RecordPhase::Setup),
+            1 => Some(RecordPhase::InitPrompt),
+            2 => Some(RecordPhase::MidProcess),
+            3 => Some(RecordPhase::PostSample),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub kind: RecordKind,
+    pub phase: RecordPhase,
+    pub step: u32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(self.kind as u8);
+        out.push(self.phase as u8);
+        out.extend_from_slice(&self.step.to_le_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+    }
+
+    /// Split a log (as returned from take_recording()) into records.
+    pub fn parse_log(mut log: &[u8]) -> Result<Vec<Record>> {
+        let mut records = vec![];
+        while !log.is_empty() {
+            if log.len() < HEADER_LEN {
+                bail!("truncated record header at the end of the log");
+            }
+            let kind = match RecordKind::from_u8(log[0]) {
+                Some(k) => k,
+                None => bail!("unknown record kind {}", log[0]),
+            };
+            let phase = match RecordPhase::from_u8(log[1]) {
+                Some(p) => p,
+                None => bail!("unknown record phase {}", log[1]),
+            };
+            let step = u32::from_le_bytes(log[2..6].try_into().unwrap());
+            let len = u32::from_le_bytes(log[6..10].try_into().unwrap()) as usize;
+            if log.len() < HEADER_LEN + len {
+                bail!("truncated {kind:?} record at step {step}");
+            }
+            records.push(Record {
+                kind,
+                phase,
+                step,
+                data: log[HEADER_LEN..HEADER_LEN + len].to_vec(),
+            });
+            log = &log[HEADER_LEN + len..];
+        }
+        Ok(records)
+    }
+}
+
+struct Recorder {
+    log: Vec<u8>,
+    step: u32,
+    // pass the log to the host after every call
+    to_host: bool,
+    // the sequence the host has the log up to `flushed` of
+    flushed_seq: Option<u32>,
+    flushed: usize,
+}
+
+thread_local! {
+    // there is only one thread in WASM; natively, tests run in parallel threads
+    static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+}
+
+/// Start recording the calls between the host and the controller (on this thread).
+/// With `to_host`, the new records are passed to the host after every call (forked
+/// sequences record separately, so the host keeps a log per sequence).
+pub fn start_recording(to_host: bool) {
+    RECORDER.with(|r| {
+        *r.borrow_mut() = Some(Recorder {
+            log: vec![],
+            step: 0,
+            to_host,
+            flushed_seq: None,
+            flushed: 0,
+        })
+    });
+}
+
+/// Start recording for the host when its header asks for it (see RuntimeInfo::record);
+/// called when the controller is loaded.
+pub fn start_recording_if_requested() {
+    let info: Option<RuntimeInfo> = serde_json::from_slice(&host::raw_runtime_info_bytes()).ok();
+    if info.is_some_and(|info| info.record) {
+        start_recording(true);
+    }
+}
+
+pub fn is_recording() -> bool {
+    RECORDER.with(|r| r.borrow().is_some())
+}
+
+/// Stop recording, and return the log.
+pub fn take_recording() -> Vec<u8> {
+    RECORDER.with(|r| r.borrow_mut().take().map(|r| r.log).unwrap_or_default())
+}
+
+pub(crate) fn record(kind: RecordKind, data: impl FnOnce() -> Vec<u8>) {
+    RECORDER.with(|r| {
+        if let Some(rec) = r.borrow_mut().as_mut() {
+            if kind == RecordKind::ProcessArg {
+                rec.step += 1;
+            }
+            Record {
+                kind,
+                phase: RecordPhase::from_call(crate::current_call()),
+                step: rec.step,
+                data: data(),
+            }
+            .write_to(&mut rec.log);
+        }
+    })
+}
+
+pub(crate) fn record_config(name: &str, value: i32) {
+    record(RecordKind::Config, || {
+        let mut data = value.to_le_bytes().to_vec();
+        data.extend_from_slice(name.as_bytes());
+        data
+    })
+}
+
+/// The record kind and data (after the value returned by the host) for a logit bias.
+pub(crate) fn token_set_record(set: &TokenSet) -> (RecordKind, Vec<u8>) {
+    match set {
+        TokenSet::Allow(vob) => (RecordKind::LogitBias, clone_vec_as_bytes(vob.as_words())),
+        TokenSet::Deny(deny) => (RecordKind::LogitDenyList, clone_vec_as_bytes(deny)),
+    }
+}
+
+pub(crate) fn record_logit_bias(set: &TokenSet, id: u32) {
+    let kind = match set {
+        TokenSet::Allow(_) => RecordKind::LogitBias,
+        TokenSet::Deny(_) => RecordKind::LogitDenyList,
+    };
+    record(kind, || {
+        let mut data = id.to_le_bytes().to_vec();
+        data.extend(token_set_record(set).1);
+        data
+    })
+}
+
+pub(crate) fn record_storage(cmd: &StorageCmd, resp: &StorageResp) {
+    record(RecordKind::StorageCmd, || serde_json::to_vec(cmd).unwrap());
+    record(RecordKind::StorageResp, || {
+        serde_json::to_vec(resp).unwrap()
+    });
+}
+
+pub(crate) fn record_tokenize(s: &[u8], tokens: &[TokenId]) {
+    record(RecordKind::Tokenize, || s.to_vec());
+    record(RecordKind::Tokens, || clone_vec_as_bytes(tokens));
+}
+
+/// Pass the new records to the host (after a fork, the new sequence gets the whole log).
+/// Called after every call.
+pub(crate) fn flush_recording() {
+    RECORDER.with(|r| {
+        let mut r = r.borrow_mut();
+        let rec = match r.as_mut().filter(|rec| rec.to_host) {
+            Some(rec) => rec,
+            None => return,
+        };
+        let seq_id = host::raw_self_seq_id();
+        if rec.flushed_seq != Some(seq_id) {
+            rec.flushed_seq = Some(seq_id);
+            rec.flushed = 0;
+        }
+        host::append_recording(&rec.log[rec.flushed..]);
+        rec.flushed = rec.log.len();
+    })
+}
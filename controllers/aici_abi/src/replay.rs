@@ -0,0 +1,176 @@
+//! Replaying sessions recorded with recording::start_recording(), natively and without
+//! the model: the controller gets the recorded inputs, and its outputs (storage commands,
+//! masks and results) are checked against the recorded ones.
+
+use crate::{
+    bytes::{limit_bytes, vec_from_bytes},
+    recording::{token_set_record, Record, RecordKind, RecordPhase},
+    testing::{MockHost, MockTokenizerEnv},
+    toktree::TokTrie,
+    StorageResp, TryAiciCtrl,
+};
+use anyhow::{bail, Result};
+
+/// Replay the recording `log` with a controller created by `new_ctrl`; fails at the first
+/// step where the controller does something else than in the recording.
+/// Installs the MockHost (with the recorded tokenizer) on the current thread.
+/// Returns the number of controller calls replayed.
+pub fn replay_session<C: TryAiciCtrl>(log: &[u8], new_ctrl: impl FnOnce() -> C) -> Result<usize> {
+    let records = Record::parse_log(log)?;
+    let trie = match records.iter().find(|r| r.kind == RecordKind::TokTrie) {
+        Some(r) => TokTrie::from_bytes(&r.data),
+        None => bail!("the recording has no tokenizer"),
+    };
+    MockHost::install(&MockTokenizerEnv::from_trie(trie));
+
+    let num_steps = records.last().map_or(0, |r| r.step);
+    let mut ctrl = None;
+    let mut new_ctrl = Some(new_ctrl);
+    for step in 0..=num_steps {
+        let step_records: Vec<&Record> = records.iter().filter(|r| r.step == step).collect();
+        let phase = step_records
+            .iter()
+            .find(|r| r.kind == RecordKind::ProcessArg)
+            .map_or(RecordPhase::Setup, |r| r.phase);
+        let expected = provide_inputs(&step_records)?;
+
+        match phase {
+            RecordPhase::Setup => {
+                if let Some(new_ctrl) = new_ctrl.take() {
+                    ctrl = Some(new_ctrl());
+                }
+            }
+            RecordPhase::InitPrompt | RecordPhase::MidProcess | RecordPhase::PostSample => {
+                let ctrl = match ctrl.as_mut() {
+                    Some(c) => c,
+                    None => bail!("step {step}: the recording doesn't start with the setup"),
+                };
+                match phase {
+                    RecordPhase::InitPrompt => ctrl.aici_init_prompt(),
+                    RecordPhase::MidProcess => ctrl.aici_mid_process(),
+                    _ => ctrl.aici_post_sample(),
+                }
+            }
+        }
+
+        let replayed = Outputs {
+            storage_cmds: MockHost::take_storage_cmds()
+                .iter()
+                .map(|c| serde_json::to_vec(c).unwrap())
+                .collect(),
+            logit_biases: MockHost::take_token_sets()
+                .iter()
+                .map(|set| {
+                    let (kind, data) = token_set_record(set);
+                    logit_bias_bytes(kind, &data)
+                })
+                .collect(),
+            process_results: MockHost::take_process_results(),
+        };
+        expected.check(&replayed, step, phase)?;
+    }
+    Ok(num_steps as usize)
+}
+
+// masks and deny lists compare as different, even if they allow the same tokens
+fn logit_bias_bytes(kind: RecordKind, data: &[u8]) -> Vec<u8> {
+    let mut r = vec![kind as u8];
+    r.extend_from_slice(data);
+    r
+}
+
+// what the controller passed to the host in a step
+#[derive(Default)]
+struct Outputs {
+    storage_cmds: Vec<Vec<u8>>,
+    logit_biases: Vec<Vec<u8>>,
+    process_results: Vec<Vec<u8>>,
+}
+
+impl Outputs {
+    fn check(&self, replayed: &Outputs, step: u32, phase: RecordPhase) -> Result<()> {
+        let lists = [
+            (
+                "storage command",
+                &self.storage_cmds,
+                &replayed.storage_cmds,
+            ),
+            ("logit bias", &self.logit_biases, &replayed.logit_biases),
+            ("result", &self.process_results, &replayed.process_results),
+        ];
+        for (what, recorded, replayed) in lists {
+            if recorded.len() != replayed.len() {
+                bail!(
+                    "step {step} ({phase:?}): {} {what}(s) recorded, {} replayed",
+                    recorded.len(),
+                    replayed.len()
+                );
+            }
+            for (idx, (a, b)) in recorded.iter().zip(replayed.iter()).enumerate() {
+                if a != b {
+                    bail!(
+                        "step {step} ({phase:?}): {what} #{idx} differs\nrecorded: {}\nreplayed: {}",
+                        limit_bytes(a, 300),
+                        limit_bytes(b, 300)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Set up the MockHost to answer like the recorded host; returns the recorded outputs.
+fn provide_inputs(records: &[&Record]) -> Result<Outputs> {
+    let mut outputs = Outputs::default();
+    let mut tokenize_input = None;
+    for r in records {
+        match r.kind {
+            RecordKind::ModuleArg => MockHost::set_arg_bytes(&r.data),
+            RecordKind::TokTrie => {}
+            RecordKind::Config => {
+                let value = i32::from_le_bytes(prefix(r, 4)?.try_into()?);
+                MockHost::set_config(&String::from_utf8_lossy(&r.data[4..]), value);
+            }
+            RecordKind::ProcessArg => MockHost::set_process_arg_bytes(&r.data),
+            RecordKind::ProcessResult => outputs.process_results.push(r.data.clone()),
+            RecordKind::LogitBias | RecordKind::LogitDenyList => {
+                MockHost::push_logit_bias_id(u32::from_le_bytes(prefix(r, 4)?.try_into()?));
+                outputs
+                    .logit_biases
+                    .push(logit_bias_bytes(r.kind, &r.data[4..]));
+            }
+            RecordKind::StorageCmd => outputs.storage_cmds.push(r.data.clone()),
+            RecordKind::StorageResp => {
+                let resp: StorageResp = serde_json::from_slice(&r.data)?;
+                MockHost::push_storage_resp(resp);
+            }
+            RecordKind::Tokenize => tokenize_input = Some(&r.data),
+            RecordKind::Tokens => match tokenize_input.take() {
+                Some(input) => MockHost::set_tokenization(input, &vec_from_bytes(&r.data)),
+                None => bail!("step {}: tokens without the tokenized input", r.step),
+            },
+            RecordKind::RandSeed => {
+                MockHost::set_rand_seed(u64::from_le_bytes(prefix(r, 8)?.try_into()?))
+            }
+            RecordKind::SelfSeqId => {
+                MockHost::set_self_seq_id(u32::from_le_bytes(prefix(r, 4)?.try_into()?))
+            }
+            RecordKind::RuntimeInfo => MockHost::set_runtime_info_bytes(&r.data),
+        }
+    }
+    Ok(outputs)
+}
+
+// the first `len` bytes of the record's data, which has at least that many
+fn prefix(r: &Record, len: usize) -> Result<&[u8]> {
+    match r.data.get(0..len) {
+        Some(data) => Ok(data),
+        None => bail!(
+            "step {}: {:?} record of {} bytes, expected at least {len}",
+            r.step,
+            r.kind,
+            r.data.len()
+        ),
+    }
+}
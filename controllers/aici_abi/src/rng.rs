@@ -1,34 +1,59 @@
+use std::ops::Range;
+
+/// Deterministic xor-shift random number generator.
+/// The same seed always yields the same sequence, on any host.
 pub struct Rng {
-    state: usize,
+    seed: u64,
+    state: u64,
+}
+
+// splitmix64 finalizer; spreads poor seeds (like small integers) over all bits
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Seeded from the host; the seed is derived from the controller argument and the prompt,
+/// so re-running the same request yields the same decisions.
+impl Default for Rng {
+    fn default() -> Self {
+        Self::seeded(crate::host::rand_seed())
+    }
 }
 
 impl Rng {
     pub fn new(seed: usize) -> Self {
+        Self::seeded(seed as u64)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        let state = mix(seed);
         Self {
-            state: if seed == 0 { 13 } else { seed },
+            seed,
+            state: if state == 0 { 13 } else { state },
         }
     }
 
-    pub fn gen(&mut self) -> usize {
+    /// Generator for the fork with given index (see MidProcessArg::fork_index()).
+    /// Forks get distinct, but deterministic, seeds.
+    pub fn fork(&self, fork_index: usize) -> Self {
+        Self::seeded(mix(self.seed ^ mix(fork_index as u64)))
+    }
+
+    pub fn gen_u64(&mut self) -> u64 {
         // xor-shift algorithm
-        #[cfg(target_pointer_width = "32")]
-        {
-            let mut x = self.state;
-            x ^= x << 13;
-            x ^= x >> 17;
-            x ^= x << 5;
-            self.state = x;
-            x
-        }
-        #[cfg(target_pointer_width = "64")]
-        {
-            let mut x = self.state;
-            x ^= x << 13;
-            x ^= x >> 7;
-            x ^= x << 17;
-            self.state = x;
-            x
-        }
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    pub fn gen(&mut self) -> usize {
+        self.gen_u64() as usize
     }
 
     pub fn gen_up_to(&mut self, mx: usize) -> usize {
@@ -43,4 +68,21 @@ impl Rng {
             }
         }
     }
+
+    /// Uniformly distributed number in given (non-empty) range.
+    pub fn gen_range(&mut self, range: Range<usize>) -> usize {
+        assert!(range.start < range.end, "empty range");
+        range.start + self.gen_up_to(range.end - range.start - 1)
+    }
+
+    /// Returns true with probability `p`.
+    pub fn gen_bool(&mut self, p: f64) -> bool {
+        let v = (self.gen_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        v < p
+    }
+
+    /// Uniformly chosen element of given (non-empty) slice.
+    pub fn choose<'a, T>(&mut self, elts: &'a [T]) -> &'a T {
+        &elts[self.gen_range(0..elts.len())]
+    }
 }
@@ -2,6 +2,7 @@ use crate::{
     recognizer::{FunctionalRecognizer, StackRecognizer},
     toktree::SpecialToken,
 };
+use anyhow::{bail, Result};
 use regex_automata::{
     dfa::{dense, Automaton},
     util::{primitives::StateID, syntax},
@@ -18,6 +19,14 @@ pub type RxStackRecognizer = StackRecognizer<StateID, RecRx>;
 
 impl RecRx {
     pub fn from_rx(rx: &str) -> Self {
+        let r = Self::try_from_rx(rx).unwrap();
+        println!("dfa: {} bytes", r.dfa.memory_usage());
+        r
+    }
+
+    /// Like from_rx(), but fails instead of panicking on invalid (or unsupported)
+    /// regular expressions.
+    pub fn try_from_rx(rx: &str) -> Result<Self> {
         let rx = if rx.ends_with("$") {
             rx.to_string()
         } else {
@@ -26,10 +35,14 @@ impl RecRx {
         let dfa = dense::Builder::new()
             .configure(dense::Config::new().start_kind(regex_automata::dfa::StartKind::Anchored))
             .syntax(syntax::Config::new().unicode(false).utf8(false))
-            .build(&rx)
-            .unwrap();
-        println!("dfa: {} bytes", dfa.memory_usage());
-        Self { dfa }
+            .build(&rx)?;
+        if dfa
+            .universal_start_state(regex_automata::Anchored::Yes)
+            .is_none()
+        {
+            bail!("regex {rx:?} has no universal start state");
+        }
+        Ok(Self { dfa })
     }
 
     pub fn to_stack_recognizer(self) -> RxStackRecognizer {
@@ -62,4 +75,8 @@ impl FunctionalRecognizer<RecRxState> for RecRx {
             _ => false,
         }
     }
+
+    fn state_key(&self, state: RecRxState) -> Option<u64> {
+        Some(state.as_usize() as u64)
+    }
 }
@@ -1,4 +1,6 @@
 use crate::TokenId;
+use base64::{self, Engine as _};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use std::{fmt::Debug, ops::Index};
 
 #[derive(Clone)]
@@ -29,7 +31,7 @@ impl SimpleVob {
 
     pub fn alloc(size: usize) -> Self {
         let mut r = Self::new();
-        r.resize(size);
+        r.resize(size, false);
         r
     }
 
@@ -55,6 +57,27 @@ impl SimpleVob {
         self.data.as_ptr()
     }
 
+    pub(crate) fn as_words(&self) -> &[u32] {
+        &self.data
+    }
+
+    /// The words of the set, little-endian; see from_bytes().
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    /// The set serialized with to_bytes(), or None if the length is not a multiple of 4.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() % 4 != 0 {
+            return None;
+        }
+        let data = bytes
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        Some(SimpleVob { data })
+    }
+
     #[inline(always)]
     pub fn allow_token(&mut self, tok: TokenId) {
         let idx = tok as usize;
@@ -79,10 +102,17 @@ impl SimpleVob {
         }
     }
 
-    pub fn resize(&mut self, size: usize) {
-        let new_size = size / BITS + 1;
-        assert!(new_size >= self.data.len());
-        self.data.resize(new_size, 0);
+    /// Grow the set so it can hold token `new_len` (and everything below);
+    /// tokens past the previous length are allowed iff `value` is true.
+    pub fn resize(&mut self, new_len: usize, value: bool) {
+        let new_size = new_len / BITS + 1;
+        assert!(
+            new_size >= self.data.len(),
+            "SimpleVob can't shrink from {} to {}",
+            self.len(),
+            new_len
+        );
+        self.data.resize(new_size, if value { !0 } else { 0 });
     }
 
     #[inline(always)]
@@ -98,6 +128,27 @@ impl SimpleVob {
         self.data.iter_mut().for_each(|x| *x = val);
     }
 
+    /// The tokens not in the set, or None if there are more than `max_len` of them.
+    /// The list includes the padding past the vocabulary (less than 32 tokens),
+    /// unless the padding was allowed (e.g., with set_all(true)).
+    pub fn to_deny_list(&self, max_len: usize) -> Option<Vec<TokenId>> {
+        if self.len() - self.num_set() > max_len {
+            return None;
+        }
+        let mut r = Vec::new();
+        for (idx, v) in self.data.iter().enumerate() {
+            if *v == !0 {
+                continue;
+            }
+            for bit_idx in 0..BITS {
+                if v & (1 << bit_idx) == 0 {
+                    r.push((idx * BITS + bit_idx) as TokenId);
+                }
+            }
+        }
+        Some(r)
+    }
+
     pub fn apply_to(&self, logits: &mut [f32]) {
         for (idx, v) in self.data.iter().enumerate() {
             if *v == 0 {
@@ -124,3 +175,78 @@ impl Index<usize> for SimpleVob {
         }
     }
 }
+
+/// Tokens for the host to sample from: either the mask of allowed tokens, or the list
+/// of denied ones (all others are allowed). The host applies a deny list in O(k)
+/// instead of O(vocab), and it takes 4 bytes per token instead of 1 bit per token
+/// of the vocabulary to pass.
+#[derive(Clone, Debug)]
+pub enum TokenSet {
+    Allow(SimpleVob),
+    Deny(Vec<TokenId>),
+}
+
+impl TokenSet {
+    /// Set in the size passed to the host with a deny list (which is only done
+    /// when the host has the "deny_list" config).
+    pub const DENY_LIST_FLAG: u32 = 1 << 31;
+
+    /// The cheaper of the two representations of `vob`: the deny list when it takes
+    /// less space than the mask.
+    pub fn from_vob(vob: SimpleVob) -> Self {
+        match vob.to_deny_list(vob.len() / 32) {
+            Some(deny) => TokenSet::Deny(deny),
+            None => TokenSet::Allow(vob),
+        }
+    }
+
+    pub fn is_allowed(&self, tok: TokenId) -> bool {
+        match self {
+            TokenSet::Allow(vob) => vob.is_allowed(tok),
+            TokenSet::Deny(deny) => !deny.contains(&tok),
+        }
+    }
+}
+
+/// Serialized as `{"len": <number of bits>, "data": <base64 of little-endian u32 words>}`,
+/// which takes 4/3 bits per token (about 5.3kB for a 32k vocabulary).
+/// Other writers may pass the vocabulary size as `len`; the bits of the last word
+/// past it are then cleared.
+#[derive(Serialize, Deserialize)]
+struct SimpleVobRepr {
+    len: usize,
+    data: String,
+}
+
+impl Serialize for SimpleVob {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        SimpleVobRepr {
+            len: self.len(),
+            data: base64::engine::general_purpose::STANDARD.encode(self.to_bytes()),
+        }
+        .serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for SimpleVob {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let repr = SimpleVobRepr::deserialize(d)?;
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&repr.data)
+            .map_err(D::Error::custom)?;
+        let num_words = repr.len.div_ceil(BITS);
+        match SimpleVob::from_bytes(&bytes) {
+            Some(mut vob) if vob.data.len() == num_words => {
+                for tok in repr.len..vob.len() {
+                    vob.disallow_token(tok as TokenId);
+                }
+                Ok(vob)
+            }
+            _ => Err(D::Error::custom(format!(
+                "SimpleVob: {} bytes of data for {} bits",
+                bytes.len(),
+                repr.len
+            ))),
+        }
+    }
+}
@@ -0,0 +1,855 @@
+//! Running controllers natively, without the wasm host, for unit tests.
+//!
+//! MockTokenizerEnv provides a small vocabulary, MockHost implements the host
+//! interface on top of it, and run_controller_script() plays the part of the
+//! LLM and the host's generation loop.
+
+use crate::{
+    bytes::TokRxInfo,
+    host::{
+        set_host, Capability, HostInterface, RuntimeInfo, StorageCmd, StorageResp, TokenizerEnv,
+    },
+    recording,
+    svob::{SimpleVob, TokenSet},
+    toktree::{TokTrie, TokenizationResult},
+    variables::Variables,
+    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, PostSampleArg,
+    PostSampleResult, ProcessResultOffset, SeqId, TokenId, TryAiciCtrl, MAX_TOP_LOGPROBS,
+};
+use anyhow::Result;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+/// How many tokens post_sample() can reject in a step (like the host's default).
+pub const POST_SAMPLE_RETRIES: usize = 8;
+
+// used by MockTokenizerEnv::default()
+const BUILTIN_WORDS: &[&str] = &[
+    "the", " the", "The", " a", " an", " and", " of", " to", " in", " is", " it", "Here", "'s",
+    " tweet", ":\n", "\n\n", "Hello", " world", "ing", "ed", "er", "  ", "    ", "{\"", "\":",
+    "\",", "\"}", "\"", "true", "false", "null", "name", "age", "value", "10", "20", "42", "100",
+    "],", "},",
+];
+
+/// Tokenizer with all single bytes, some words, and EOS (which decodes to nothing).
+/// Tokenization is greedy (longest match first).
+pub struct MockTokenizerEnv {
+    trie: TokTrie,
+}
+
+impl MockTokenizerEnv {
+    /// Tokens 0-255 are the single bytes, then come `words`, then EOS.
+    pub fn new(words: &[&str]) -> Self {
+        let mut tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+        for w in words {
+            if w.len() > 1 && !tokens.iter().any(|t| t == w.as_bytes()) {
+                tokens.push(w.as_bytes().to_vec());
+            }
+        }
+        let eos = tokens.len() as TokenId;
+        tokens.push(vec![]);
+        let info = TokRxInfo {
+            vocab_size: tokens.len() as u32,
+            tok_eos: eos,
+        };
+        MockTokenizerEnv {
+            trie: TokTrie::from(&info, &tokens),
+        }
+    }
+
+    /// Use the given tokenizer (e.g., one from a recorded session).
+    pub fn from_trie(trie: TokTrie) -> Self {
+        MockTokenizerEnv { trie }
+    }
+
+    /// Load the words from a JSON array of strings, e.g., a test fixture.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let words: Vec<String> = serde_json::from_str(json)?;
+        let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+        Ok(Self::new(&words))
+    }
+}
+
+impl Default for MockTokenizerEnv {
+    fn default() -> Self {
+        Self::new(BUILTIN_WORDS)
+    }
+}
+
+impl Clone for MockTokenizerEnv {
+    fn clone(&self) -> Self {
+        MockTokenizerEnv {
+            trie: TokTrie::from_bytes(&self.trie.serialize()),
+        }
+    }
+}
+
+impl TokenizerEnv for MockTokenizerEnv {
+    fn stop(&self) -> ! {
+        panic!("stop called")
+    }
+
+    fn tok_trie(&self) -> &TokTrie {
+        &self.trie
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        self.trie.greedy_tokenize(s)
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    env: Option<Rc<MockTokenizerEnv>>,
+    arg: Vec<u8>,
+    process_arg: Vec<u8>,
+    process_results: Vec<Vec<u8>>,
+    logit_biases: Vec<TokenSet>,
+    config: HashMap<String, i32>,
+    // None for the default header
+    runtime_info: Option<Vec<u8>>,
+    seq_id: u32,
+    rand_seed: Option<u64>,
+    // these take precedence over the tokenizer
+    tokenizations: HashMap<Vec<u8>, Vec<TokenId>>,
+    // when non-empty, used instead of the variables
+    storage_resps: VecDeque<StorageResp>,
+    storage_cmds: Vec<StorageCmd>,
+    // when non-empty, returned from return_logit_bias()
+    logit_bias_ids: VecDeque<u32>,
+    // see set_request()
+    request: u32,
+    // the variables of each request, and the global ones; created on first use
+    vars: HashMap<u32, Variables>,
+    global_vars: Option<Variables>,
+    // None for no quota
+    storage_quota: Option<usize>,
+    // see bias_cache_get(); shared by the controllers run on the thread
+    bias_cache: HashMap<u64, Vec<u8>>,
+    // see append_recording()
+    recording: Vec<u8>,
+}
+
+thread_local! {
+    // tests run in parallel threads, each with its own state
+    static MOCK_STATE: RefCell<MockState> = RefCell::new(MockState::default());
+}
+
+fn with_state<T>(f: impl FnOnce(&mut MockState) -> T) -> T {
+    MOCK_STATE.with(|s| f(&mut s.borrow_mut()))
+}
+
+/// Host for native tests; its state (argument, variables, captured results)
+/// is per thread, so tests can run in parallel.
+pub struct MockHost {}
+
+impl MockHost {
+    /// Install the mock host (for the whole process, on first call), and reset
+    /// the state of the current thread to use the given tokenizer.
+    pub fn install(env: &MockTokenizerEnv) {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| set_host(Box::new(MockHost {})));
+        with_state(|s| {
+            *s = MockState {
+                env: Some(Rc::new(env.clone())),
+                ..MockState::default()
+            }
+        });
+    }
+
+    /// Set what arg_bytes() returns, i.e., the controller argument.
+    pub fn set_arg_bytes(arg: &[u8]) {
+        with_state(|s| s.arg = arg.to_vec());
+    }
+
+    /// Set what process_arg_bytes() returns; only needed when calling
+    /// the TryAiciCtrl::aici_*() entry points directly.
+    pub fn set_process_arg_bytes(arg: &[u8]) {
+        with_state(|s| s.process_arg = arg.to_vec());
+    }
+
+    /// Set what get_config() returns for `name` (0 when not set).
+    pub fn set_config(name: &str, value: i32) {
+        with_state(|s| {
+            s.config.insert(name.to_string(), value);
+        });
+    }
+
+    /// Set the header read by runtime_info(); empty bytes make a host from before
+    /// the header. By default, the header is of the current ABI, with all capabilities.
+    pub fn set_runtime_info_bytes(info: &[u8]) {
+        with_state(|s| s.runtime_info = Some(info.to_vec()));
+    }
+
+    /// Like set_runtime_info_bytes(), with the header serialized.
+    pub fn set_runtime_info(info: &RuntimeInfo) {
+        Self::set_runtime_info_bytes(&serde_json::to_vec(info).unwrap());
+    }
+
+    /// Set what self_seq_id() returns (0 by default); with fork_group, this selects
+    /// the branch the controller thinks it's on.
+    pub fn set_self_seq_id(id: u32) {
+        with_state(|s| s.seq_id = id);
+    }
+
+    /// Set what rand_seed() returns (42 by default).
+    pub fn set_rand_seed(seed: u64) {
+        with_state(|s| s.rand_seed = Some(seed));
+    }
+
+    /// Make tokenize_bytes() return `tokens` for `bytes`, instead of tokenizing them.
+    pub fn set_tokenization(bytes: &[u8], tokens: &[TokenId]) {
+        with_state(|s| {
+            s.tokenizations.insert(bytes.to_vec(), tokens.to_vec());
+        });
+    }
+
+    /// Queue a response for storage_cmd(); queued responses are used in order,
+    /// instead of the variables kept by the mock host.
+    pub fn push_storage_resp(resp: StorageResp) {
+        with_state(|s| s.storage_resps.push_back(resp));
+    }
+
+    /// Limit the size of the variables of each request, and of the global ones,
+    /// like the host does; call before the first storage command.
+    pub fn set_storage_quota(bytes: usize) {
+        with_state(|s| s.storage_quota = Some(bytes));
+    }
+
+    /// Switch to the variables of another request (0 by default), to see what
+    /// requests running at the same time share: only the global variables.
+    pub fn set_request(id: u32) {
+        with_state(|s| s.request = id);
+    }
+
+    /// Report the panics of controller calls from the panic hook, like in WASM
+    /// (where they abort, and the calls can't catch them); for the whole process.
+    pub fn install_panic_hook() {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            prev(info);
+            crate::report_panic(info);
+        }));
+    }
+
+    /// The log passed to append_recording() since the last call (see RuntimeInfo::record).
+    pub fn take_host_recording() -> Vec<u8> {
+        with_state(|s| std::mem::take(&mut s.recording))
+    }
+
+    /// Commands passed to storage_cmd() since the last call.
+    pub fn take_storage_cmds() -> Vec<StorageCmd> {
+        with_state(|s| std::mem::take(&mut s.storage_cmds))
+    }
+
+    /// Queue a value for return_logit_bias() to return (instead of the index of the mask).
+    pub fn push_logit_bias_id(id: u32) {
+        with_state(|s| s.logit_bias_ids.push_back(id));
+    }
+
+    /// Results passed to return_process_result() since the last call.
+    pub fn take_process_results() -> Vec<Vec<u8>> {
+        with_state(|s| std::mem::take(&mut s.process_results))
+    }
+
+    /// Token sets passed to return_logit_bias() since the last call;
+    /// return_logit_bias() returns the index into this list.
+    pub fn take_token_sets() -> Vec<TokenSet> {
+        with_state(|s| std::mem::take(&mut s.logit_biases))
+    }
+
+    /// Like take_token_sets(), with deny lists turned into masks.
+    pub fn take_logit_biases() -> Vec<SimpleVob> {
+        let env = Self::env();
+        Self::take_token_sets()
+            .into_iter()
+            .map(|set| match set {
+                TokenSet::Allow(vob) => vob,
+                TokenSet::Deny(deny) => {
+                    let mut vob = env.trie.alloc_token_set();
+                    vob.set_all(true);
+                    for tok in deny {
+                        if (tok as usize) < vob.len() {
+                            vob.disallow_token(tok);
+                        }
+                    }
+                    vob
+                }
+            })
+            .collect()
+    }
+
+    fn run_storage_cmd(s: &mut MockState, cmd: StorageCmd) -> StorageResp {
+        let quota = s.storage_quota.unwrap_or(usize::MAX);
+        let vars = if cmd.is_global() {
+            s.global_vars.get_or_insert_with(|| Variables::new(quota))
+        } else {
+            s.vars
+                .entry(s.request)
+                .or_insert_with(|| Variables::new(quota))
+        };
+        vars.process_cmd(cmd)
+    }
+
+    fn env() -> Rc<MockTokenizerEnv> {
+        with_state(|s| {
+            s.env
+                .clone()
+                .expect("MockHost::install() not called on this thread")
+        })
+    }
+}
+
+impl HostInterface for MockHost {
+    fn arg_bytes(&self) -> Vec<u8> {
+        with_state(|s| s.arg.clone())
+    }
+
+    fn trie_bytes(&self) -> Vec<u8> {
+        Self::env().trie.serialize()
+    }
+
+    fn return_logit_bias(&self, set: &TokenSet) -> u32 {
+        with_state(|s| {
+            s.logit_biases.push(set.clone());
+            s.logit_bias_ids
+                .pop_front()
+                .unwrap_or((s.logit_biases.len() - 1) as u32)
+        })
+    }
+
+    fn process_arg_bytes(&self) -> Vec<u8> {
+        with_state(|s| s.process_arg.clone())
+    }
+
+    fn runtime_info_bytes(&self) -> Vec<u8> {
+        if let Some(info) = with_state(|s| s.runtime_info.clone()) {
+            return info;
+        }
+        let vocab_size = Self::env().tok_trie().vocab_size() as u32;
+        serde_json::to_vec(&RuntimeInfo::new(Capability::ALL, vocab_size, "")).unwrap()
+    }
+
+    fn return_process_result(&self, res: &[u8]) {
+        with_state(|s| s.process_results.push(res.to_vec()));
+    }
+
+    fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp {
+        with_state(|s| {
+            s.storage_cmds.push(cmd.clone());
+            match s.storage_resps.pop_front() {
+                Some(resp) => resp,
+                None => Self::run_storage_cmd(s, cmd),
+            }
+        })
+    }
+
+    fn bias_cache_get(&self, key: u64) -> Option<Vec<u8>> {
+        with_state(|s| s.bias_cache.get(&key).cloned())
+    }
+
+    fn bias_cache_put(&self, key: u64, value: &[u8]) {
+        with_state(|s| {
+            s.bias_cache.insert(key, value.to_vec());
+        });
+    }
+
+    fn append_recording(&self, log: &[u8]) {
+        with_state(|s| s.recording.extend_from_slice(log));
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        match with_state(|st| st.tokenizations.get(s).cloned()) {
+            Some(tokens) => tokens,
+            None => Self::env().tokenize_bytes(s),
+        }
+    }
+
+    fn tokenize_bytes_greedy(&self, s: &[u8]) -> TokenizationResult {
+        Self::env().tok_trie().tokenize_bytes_greedy(s)
+    }
+
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8> {
+        Self::env().decode_tokens(toks)
+    }
+
+    fn self_seq_id(&self) -> SeqId {
+        with_state(|s| SeqId(s.seq_id))
+    }
+
+    fn rand_seed(&self) -> u64 {
+        with_state(|s| s.rand_seed.unwrap_or(42))
+    }
+
+    fn time_left_us(&self) -> u64 {
+        // no deadlines in tests
+        u64::MAX
+    }
+
+    fn eos_token(&self) -> TokenId {
+        Self::env().eos_token()
+    }
+
+    fn get_config(&self, name: &str) -> i32 {
+        with_state(|s| s.config.get(name).copied().unwrap_or(0))
+    }
+
+    fn stop(&self) -> ! {
+        panic!("stop called")
+    }
+}
+
+/// A step of the script for run_controller_script().
+#[derive(Debug, Clone)]
+pub enum Phase {
+    /// Tokenize the text and pass it to init_prompt(); forced tokens are appended.
+    Prompt(String),
+    /// Call mid_process() until the controller stops, EOS is sampled, or `max_tokens`
+    /// tokens were sampled. The "model" picks, among the allowed tokens, the longest one
+    /// continuing `prefer`; when none does, EOS if allowed and `prefer` is used up,
+    /// and otherwise the allowed token with the lowest id (and `prefer` is dropped).
+    /// Tokens rejected by post_sample() are removed from the mask for the step.
+    /// When the controller asks for InitPromptResult::top_logprobs, the distribution
+    /// before the mask has the tokens continuing `prefer` (EOS when it's used up), the
+    /// longer the more likely; nothing is reported once `prefer` is dropped.
+    Generate { prefer: String, max_tokens: usize },
+}
+
+/// What happened in run_controller_script().
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    /// All tokens, including the prompt.
+    pub tokens: Vec<TokenId>,
+    /// Length of the prompt, including tokens forced by init_prompt().
+    pub prompt_len: usize,
+    /// Tokens picked by the "model", in order (including ones later backtracked).
+    pub sampled: Vec<TokenId>,
+    /// Tokens rejected by post_sample(), in order.
+    pub rejected: Vec<TokenId>,
+    /// Number of mid_process() calls.
+    pub num_steps: usize,
+    /// The controller stopped the sequence (returned no branches).
+    pub stopped: bool,
+    /// EOS was sampled.
+    pub eos: bool,
+}
+
+impl Transcript {
+    /// Tokens after the prompt.
+    pub fn output(&self) -> &[TokenId] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    pub fn output_text(&self, env: &dyn TokenizerEnv) -> String {
+        String::from_utf8_lossy(&env.decode_tokens(self.output())).to_string()
+    }
+}
+
+/// Drive the controller like the host does, with a deterministic "model"; see Phase.
+/// Splices are applied to the tokens, and the next mid_process() gets the changes.
+/// Forks (see run_forking_script()) and suspending are not supported.
+/// Uses the tokenizer passed to MockHost::install(). While recording (see
+/// record_controller_script()), the calls go through the TryAiciCtrl::aici_*() entry
+/// points, so that the arguments and results are recorded like in the host.
+pub fn run_controller_script<C: AiciCtrl>(ctrl: &mut C, script: Vec<Phase>) -> Transcript {
+    let mut seq = ScriptSeq::default();
+    if run_script(ctrl, &mut seq, &script).is_some() {
+        panic!("forks not supported in tests");
+    }
+    seq.tr
+}
+
+/// Wraps a controller, replacing its result of the `step`-th mid_process() call
+/// (counting from 0) with a splice; the controller then gets the splice in the next
+/// call, as if it had returned it itself. For testing how controllers follow splices.
+pub struct SpliceAt<C> {
+    pub ctrl: C,
+    step: usize,
+    backtrack: u32,
+    ff_tokens: Vec<TokenId>,
+    num_calls: usize,
+}
+
+impl<C: AiciCtrl> SpliceAt<C> {
+    pub fn new(ctrl: C, step: usize, backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
+        SpliceAt {
+            ctrl,
+            step,
+            backtrack,
+            ff_tokens,
+            num_calls: 0,
+        }
+    }
+}
+
+impl<C: AiciCtrl> AiciCtrl for SpliceAt<C> {
+    fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
+        self.ctrl.init_prompt(arg)
+    }
+
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        let res = self.ctrl.mid_process(arg);
+        self.num_calls += 1;
+        if self.num_calls - 1 == self.step {
+            MidProcessResult::splice(self.backtrack, self.ff_tokens.clone())
+        } else {
+            res
+        }
+    }
+
+    fn post_sample(&mut self, arg: PostSampleArg) -> PostSampleResult {
+        self.ctrl.post_sample(arg)
+    }
+}
+
+/// Like run_controller_script(), but when the controller forks, each branch continues
+/// the rest of the script with its own copy of the controller, as in the host.
+/// The first branch keeps the sequence id, the others get new ones; the first
+/// mid_process() after the fork gets the ids of all branches in fork_group, with
+/// MockHost::self_seq_id() set to the branch's one. Branches run one after another.
+/// Returns the transcripts ordered by sequence id (the original sequence first).
+pub fn run_forking_script<C: AiciCtrl + Clone>(ctrl: C, script: Vec<Phase>) -> Vec<Transcript> {
+    let mut next_id = 1;
+    let mut done = vec![];
+    let mut todo = vec![(ctrl, ScriptSeq::default())];
+    while let Some((mut ctrl, mut seq)) = todo.pop() {
+        let res = match run_script(&mut ctrl, &mut seq, &script) {
+            Some(res) => res,
+            None => {
+                done.push(seq);
+                continue;
+            }
+        };
+        let ids: Vec<u32> = std::iter::once(seq.id)
+            .chain(next_id..)
+            .take(res.branches.len())
+            .collect();
+        next_id += ids.len() as u32 - 1;
+        // run the branches in order
+        for (branch, id) in res.branches.into_iter().zip(&ids).rev() {
+            let mut ctrl = ctrl.clone();
+            let mut seq = seq.clone();
+            seq.id = *id;
+            seq.fork_group = ids.clone();
+            let res = MidProcessResult {
+                branches: vec![branch],
+                ..MidProcessResult::stop()
+            };
+            seq.apply(&mut ctrl, &script, res);
+            todo.push((ctrl, seq));
+        }
+    }
+    done.sort_by_key(|seq| seq.id);
+    done.into_iter().map(|seq| seq.tr).collect()
+}
+
+// a sequence in run_controller_script() and run_forking_script()
+#[derive(Clone, Default)]
+struct ScriptSeq {
+    tr: Transcript,
+    id: u32,
+    // passed with the next mid_process() when non-empty (after a fork)
+    fork_group: Vec<u32>,
+    // number of tokens the controller has seen
+    seen_len: usize,
+    backtrack: u32,
+    forced_byte_prefix: Vec<u8>,
+    fork_arg: Vec<u8>,
+    top_k: usize,
+    top_logprobs: Vec<(TokenId, f32)>,
+    // index of the current phase in the script
+    phase: usize,
+    // for the current Generate phase: where its output starts, and the limit on tr.sampled
+    generate: Option<(usize, usize)>,
+}
+
+// runs the rest of the script; when the controller forks, returns the result
+fn run_script<C: AiciCtrl>(
+    ctrl: &mut C,
+    seq: &mut ScriptSeq,
+    script: &[Phase],
+) -> Option<MidProcessResult> {
+    let env = MockHost::env();
+    let trie = env.tok_trie();
+    while let Some(phase) = script.get(seq.phase) {
+        match phase {
+            Phase::Prompt(text) => {
+                let tr = &mut seq.tr;
+                tr.tokens.extend(env.tokenize(text));
+                let res = call_init_prompt(
+                    ctrl,
+                    InitPromptArg {
+                        prompt: tr.tokens.clone(),
+                    },
+                );
+                seq.top_k = std::cmp::min(res.top_logprobs, MAX_TOP_LOGPROBS) as usize;
+                tr.tokens.extend(res.ff_tokens);
+                tr.prompt_len = tr.tokens.len();
+                seq.seen_len = tr.tokens.len();
+            }
+            Phase::Generate { max_tokens, .. } => {
+                let tr = &seq.tr;
+                let (_, max_sampled) = *seq
+                    .generate
+                    .get_or_insert((tr.tokens.len(), tr.sampled.len() + max_tokens));
+                while !seq.tr.stopped && !seq.tr.eos && seq.tr.sampled.len() < max_sampled {
+                    let fork_group = if seq.fork_group.is_empty() {
+                        vec![SeqId(seq.id)]
+                    } else {
+                        std::mem::take(&mut seq.fork_group)
+                            .into_iter()
+                            .map(SeqId)
+                            .collect()
+                    };
+                    let arg = MidProcessArg {
+                        backtrack: seq.backtrack,
+                        tokens: seq.tr.tokens[seq.seen_len..].to_vec(),
+                        fork_group,
+                        fork_arg: std::mem::take(&mut seq.fork_arg),
+                        prev_timed_out: false,
+                        forced_byte_prefix: std::mem::take(&mut seq.forced_byte_prefix),
+                        byte_offset: trie.decode(&seq.tr.tokens).len() as u64,
+                        context_truncation: None,
+                        top_logprobs: std::mem::take(&mut seq.top_logprobs),
+                    };
+                    seq.seen_len = seq.tr.tokens.len();
+                    MockHost::set_self_seq_id(seq.id);
+                    let res = call_mid_process(ctrl, arg);
+                    seq.tr.num_steps += 1;
+                    if res.branches.len() > 1 {
+                        return Some(res);
+                    }
+                    seq.apply(ctrl, script, res);
+                }
+                seq.generate = None;
+            }
+        }
+        seq.phase += 1;
+    }
+    None
+}
+
+impl ScriptSeq {
+    // applies the (single-branch) result of mid_process() in a Generate phase
+    fn apply<C: AiciCtrl>(&mut self, ctrl: &mut C, script: &[Phase], res: MidProcessResult) {
+        let env = MockHost::env();
+        let trie = env.tok_trie();
+        let prefer = match &script[self.phase] {
+            Phase::Generate { prefer, .. } => prefer,
+            Phase::Prompt(_) => unreachable!(),
+        };
+        let (start, _) = self.generate.unwrap();
+        // tokens may have been spliced in or backtracked, so compare
+        // the whole output of the phase with the preferred text
+        let generated = trie.decode(&self.tr.tokens[std::cmp::min(start, self.seen_len)..]);
+        let rest = prefer.as_bytes().strip_prefix(generated.as_slice());
+        let num_sampled = self.tr.sampled.len();
+        if let Some(b) = res.branches.first() {
+            self.fork_arg.clone_from(&b.fork_arg);
+        }
+        let kept = apply_result(
+            ctrl,
+            &mut self.tr,
+            trie,
+            res,
+            rest,
+            &mut self.forced_byte_prefix,
+        );
+        if self.top_k > 0 && self.tr.sampled.len() > num_sampled {
+            self.top_logprobs = model_top_logprobs(trie, rest, self.top_k);
+        }
+        self.backtrack = (self.seen_len - kept) as u32;
+        self.seen_len = kept;
+    }
+}
+
+/// Run the script (see run_controller_script()) on a controller created with
+/// `new_ctrl`, recording the session; returns the transcript and the recording,
+/// which replay::replay_session() checks the controller against.
+pub fn record_controller_script<C: AiciCtrl>(
+    new_ctrl: impl FnOnce() -> C,
+    script: Vec<Phase>,
+) -> (Transcript, Vec<u8>) {
+    recording::start_recording(false);
+    let mut ctrl = new_ctrl();
+    let tr = run_controller_script(&mut ctrl, script);
+    (tr, recording::take_recording())
+}
+
+fn call_init_prompt<C: AiciCtrl>(ctrl: &mut C, arg: InitPromptArg) -> InitPromptResult {
+    if !recording::is_recording() {
+        return ctrl.init_prompt(arg);
+    }
+    MockHost::set_process_arg_bytes(&serde_json::to_vec(&arg).unwrap());
+    TryAiciCtrl::aici_init_prompt(ctrl);
+    parse_process_result(&MockHost::take_process_results())
+}
+
+fn call_mid_process<C: AiciCtrl>(ctrl: &mut C, arg: MidProcessArg) -> MidProcessResult {
+    if !recording::is_recording() {
+        return ctrl.mid_process(arg);
+    }
+    MockHost::set_process_arg_bytes(&serde_json::to_vec(&arg).unwrap());
+    TryAiciCtrl::aici_mid_process(ctrl);
+    let results = MockHost::take_process_results();
+    if crate::inline_bias() {
+        return parse_process_result(&results);
+    }
+    // masks were passed separately; the offsets are indices into them
+    let biases = MockHost::take_logit_biases();
+    let res: ProcessResultOffset = parse_process_result(&results);
+    MidProcessResult {
+        branches: res
+            .branches
+            .iter()
+            .map(|b| b.map_mask(|idx| biases[*idx].clone()))
+            .collect(),
+        suspend: res.suspend,
+        max_remaining_tokens: res.max_remaining_tokens,
+        captures: res.captures,
+    }
+}
+
+fn call_post_sample<C: AiciCtrl>(ctrl: &mut C, arg: PostSampleArg) -> PostSampleResult {
+    if !recording::is_recording() {
+        return ctrl.post_sample(arg);
+    }
+    MockHost::set_process_arg_bytes(&serde_json::to_vec(&arg).unwrap());
+    TryAiciCtrl::aici_post_sample(ctrl);
+    parse_process_result(&MockHost::take_process_results())
+}
+
+fn parse_process_result<T: serde::de::DeserializeOwned>(results: &[Vec<u8>]) -> T {
+    assert_eq!(results.len(), 1, "expected exactly one process result");
+    serde_json::from_slice(&results[0]).unwrap_or_else(|e| {
+        panic!(
+            "controller call failed: {} ({e})",
+            String::from_utf8_lossy(&results[0])
+        )
+    })
+}
+
+// returns the number of tokens left from before the step
+fn apply_result<C: AiciCtrl>(
+    ctrl: &mut C,
+    tr: &mut Transcript,
+    trie: &TokTrie,
+    res: MidProcessResult,
+    prefer: Option<&[u8]>,
+    forced_byte_prefix: &mut Vec<u8>,
+) -> usize {
+    assert!(res.suspend.is_none(), "suspend not supported in tests");
+    let branch = match res.branches.len() {
+        0 => {
+            tr.stopped = true;
+            return tr.tokens.len();
+        }
+        1 => &res.branches[0],
+        _ => panic!("forks not supported in tests"),
+    };
+    let mut kept = tr.tokens.len();
+    let mut sampled = None;
+    if let Some(mask) = &branch.sample_mask {
+        let prefix = &branch.forced_byte_prefix;
+        let mut mask = mask.clone();
+        let mut picked = pick_token(trie, &mask, prefer, prefix);
+        let mut num_rejected = 0;
+        let mut dropped = false;
+        while let (Some(tok), true) = (picked, branch.post_sample) {
+            match call_post_sample(ctrl, PostSampleArg { token: tok }) {
+                PostSampleResult::Accept => break,
+                PostSampleResult::Replace { token } => {
+                    picked = Some(token);
+                    break;
+                }
+                PostSampleResult::Reject { resample } => {
+                    tr.rejected.push(tok);
+                    if !resample {
+                        dropped = true;
+                        break;
+                    }
+                    num_rejected += 1;
+                    assert!(
+                        num_rejected <= POST_SAMPLE_RETRIES,
+                        "post_sample() rejected too many tokens"
+                    );
+                    mask.disallow_token(tok);
+                    picked = pick_token(trie, &mask, prefer, prefix);
+                }
+            }
+        }
+        match picked {
+            // nothing is committed
+            _ if dropped => {}
+            Some(tok) => {
+                tr.sampled.push(tok);
+                tr.tokens.push(tok);
+                tr.eos = tok == trie.eos_token();
+                sampled = Some(tok);
+            }
+            // the host tokenizes the prefix on its own
+            None if !prefix.is_empty() => tr.tokens.extend(trie.greedy_tokenize(prefix)),
+            None => panic!("no tokens allowed"),
+        }
+        forced_byte_prefix.clone_from(prefix);
+    }
+    let splice = branch.splices.iter().find(|s| {
+        s.when_sampled.is_empty() || sampled.is_some_and(|t| s.when_sampled.contains(&t))
+    });
+    if let Some(splice) = splice {
+        let len = tr.tokens.len() - splice.backtrack as usize;
+        tr.tokens.truncate(len);
+        kept = std::cmp::min(kept, len);
+        tr.tokens.extend_from_slice(&splice.ff_tokens);
+    }
+    kept
+}
+
+// the k most likely tokens before the mask; see Phase::Generate
+fn model_top_logprobs(trie: &TokTrie, prefer: Option<&[u8]>, k: usize) -> Vec<(TokenId, f32)> {
+    let prefer = match prefer {
+        Some([]) => return vec![(trie.eos_token(), 0.0)],
+        Some(p) => p,
+        None => return vec![],
+    };
+    // logits are token lengths
+    let mut top = (0..trie.vocab_size() as TokenId)
+        .filter(|t| !trie.token(*t).is_empty() && prefer.starts_with(trie.token(*t)))
+        .map(|t| (t, trie.token(t).len() as f32))
+        .collect::<Vec<_>>();
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let max = top.first().map_or(0.0, |(_, l)| *l);
+    let lse = max + top.iter().map(|(_, l)| (l - max).exp()).sum::<f32>().ln();
+    top.truncate(k);
+    top.into_iter().map(|(t, l)| (t, l - lse)).collect()
+}
+
+fn pick_token(
+    trie: &TokTrie,
+    mask: &SimpleVob,
+    prefer: Option<&[u8]>,
+    prefix: &[u8],
+) -> Option<TokenId> {
+    let eos = trie.eos_token();
+    let eos_allowed = mask.is_allowed(eos) && prefix.is_empty();
+    let mut allowed = (0..trie.vocab_size() as TokenId)
+        .filter(|t| *t != eos && mask.is_allowed(*t) && trie.token_fits_prefix(*t, prefix));
+    if let Some(prefer) = prefer {
+        let best = allowed
+            .clone()
+            .filter(|t| !trie.token(*t).is_empty() && prefer.starts_with(trie.token(*t)))
+            .max_by_key(|t| trie.token(*t).len());
+        if best.is_some() {
+            return best;
+        }
+        if prefer.is_empty() && eos_allowed {
+            return Some(eos);
+        }
+    }
+    allowed
+        .next()
+        .or(if eos_allowed { Some(eos) } else { None })
+}
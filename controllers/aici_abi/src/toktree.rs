@@ -1,7 +1,8 @@
 // use 8:24 encoding - num_ch:tok_id (ch_byte:ch_off)* - 8 bytes per tree node
 // special case num_ch=0xff -> num_ch=0x100
 
-use rustc_hash::FxHashMap;
+use anyhow::{bail, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
     bytes::{
@@ -9,6 +10,7 @@ use crate::{
         TokRxInfo, TokenId,
     },
     host::trie_bytes,
+    recognizer::BiasCache,
     svob::SimpleVob,
 };
 
@@ -19,6 +21,36 @@ pub enum SpecialToken {
     Separator,
     BeginningOfSentence,
     EndOfSentence,
+    /// Any other special token of the tokenizer (like `<|im_start|>`), by token id.
+    Other(TokenId),
+}
+
+impl SpecialToken {
+    /// Guess the kind of special token from its name in the tokenizer.
+    pub fn from_name(name: &str, tok: TokenId) -> Self {
+        match name {
+            "<s>" | "<|begin_of_text|>" | "<|startoftext|>" | "[CLS]" => {
+                SpecialToken::BeginningOfSentence
+            }
+            "</s>" | "<|endoftext|>" | "<|end_of_text|>" => SpecialToken::EndOfSentence,
+            "<pad>" | "<|pad|>" | "[PAD]" => SpecialToken::Padding,
+            "<unk>" | "[UNK]" => SpecialToken::Unknown,
+            "[SEP]" => SpecialToken::Separator,
+            _ => SpecialToken::Other(tok),
+        }
+    }
+}
+
+/// Result of TokTrie::probe_extensions().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtensionProbe {
+    /// No token starting with the given bytes is allowed by the recognizer.
+    NoExtension,
+    /// Some token starting with the given bytes is allowed.
+    HasExtension,
+    /// The budget (the number of bytes pushed to the recognizer) ran out before
+    /// the question was settled.
+    Unknown(usize),
 }
 
 pub trait Recognizer {
@@ -41,6 +73,20 @@ pub trait Recognizer {
             false
         }
     }
+    /// for b in 0..=255 { mask_out[b] = byte_allowed(b) }
+    /// Implementations may over-approximate (the trie walk still calls try_push_byte()
+    /// on the bytes in the mask), but must not leave out any allowed byte.
+    fn allowed_byte_mask(&mut self, mask_out: &mut [bool; 256]) {
+        for b in 0..=255u8 {
+            mask_out[b as usize] = self.byte_allowed(b);
+        }
+    }
+    /// If true, allowed_byte_mask() is cheap (e.g., a union of terminal byte sets),
+    /// and the trie walk in compute_bias() gets it once for each node with many children
+    /// (see MASK_MIN_CHILDREN), instead of trying each child.
+    fn has_fast_byte_mask(&self) -> bool {
+        false
+    }
     /// check if stack.top() transitions via tok to a viable state
     fn special_allowed(&mut self, tok: SpecialToken) -> bool;
     /// Called when iteration over the trie is finished
@@ -49,10 +95,37 @@ pub trait Recognizer {
     fn trie_finished(&mut self);
     /// Called when iteration over the trie is started
     fn trie_started(&mut self) {}
+    /// If true, EOS is allowed even when special_allowed() doesn't allow it, because
+    /// the bytes formed a complete match partway through the last token; see
+    /// StackRecognizer::allow_prefix_eos(). Defaults to false.
+    fn eos_after_match(&self) -> bool {
+        false
+    }
+    /// Fingerprint of stack.top(): recognizers with the same key must allow the same
+    /// tokens, so that compute_bias_cached() can reuse the set. None (the default)
+    /// means the set is always recomputed.
+    fn state_key(&self) -> Option<u64> {
+        None
+    }
     /// This combines `push_byte` and `byte_allowed` into one function for performance.
     fn try_push_byte(&mut self, byte: u8) -> bool;
 }
 
+/// Result of TokTrie::tokenize_bytes_greedy().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenizationResult {
+    pub tokens: Vec<TokenId>,
+    /// The bytes that were not tokenized; they start with one that no token starts with.
+    /// Empty when the tokens cover all the bytes.
+    pub remainder: Vec<u8>,
+}
+
+impl TokenizationResult {
+    pub fn is_complete(&self) -> bool {
+        self.remainder.is_empty()
+    }
+}
+
 #[derive(Clone)]
 pub struct TokTrie {
     info: TokRxInfo,
@@ -61,21 +134,31 @@ pub struct TokTrie {
     nodes: Vec<TrieNode>,
     max_token_len: usize,
     token_duplicates: FxHashMap<TokenId, Vec<TokenId>>,
+    model_vocab_size: u32,
+    /// Special tokens of the tokenizer (other than EOS), by name; sorted by token id.
+    special_tokens: Vec<(TokenId, String)>,
 }
 
 #[repr(C)]
 pub struct TokTrieHeader {
     magic: u32,
+    version: u32,
     hd_size: u32,
     trie_bytes: u32,
     token_offset_bytes: u32,
     token_data_bytes: u32,
+    special_token_bytes: u32,
+    /// Size of the logits (and logit biases) of the host model; can be larger than
+    /// info.vocab_size when the model has extra (e.g., padding) tokens.
+    model_vocab_size: u32,
     info: TokRxInfo,
     align: [u32; 0],
 }
 
 impl TokTrieHeader {
     const MAGIC: u32 = 0x558b6fd3;
+    /// Bump when the binary layout of the trie changes.
+    const VERSION: u32 = 3;
 }
 
 #[derive(Clone)]
@@ -88,6 +171,10 @@ pub struct TrieNode {
 
 const NO_TOKEN: u32 = 0xffffff;
 
+/// Nodes with fewer children are walked without Recognizer::allowed_byte_mask(),
+/// as trying the children is cheaper than computing the mask.
+const MASK_MIN_CHILDREN: usize = 8;
+
 impl TrieNode {
     fn new(byte: u8, token_id: u32, num_parents: u8) -> TrieNode {
         TrieNode {
@@ -123,6 +210,9 @@ impl TrieNode {
 }
 
 impl TokTrie {
+    /// Load the trie passed by the host.
+    /// Controllers should check model_vocab_size() against vocab_size() here,
+    /// rather than have return_logit_bias() trap mid-generation.
     pub fn from_host() -> Self {
         let buffer = trie_bytes();
         Self::from_bytes(&buffer)
@@ -151,6 +241,8 @@ impl TokTrie {
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            model_vocab_size: info.vocab_size,
+            special_tokens: Vec::new(),
         };
         r.finalize_ctor();
         r
@@ -159,11 +251,12 @@ impl TokTrie {
     fn finalize_ctor(&mut self) {
         for tok_id in 0..self.info.vocab_size {
             let bytes = self.token(tok_id);
-            let tok_ids = self.greedy_tokenize(bytes);
+            // with banned tokens, some bytes may have no token
+            let r = self.tokenize_bytes_greedy(bytes);
             self.max_token_len = std::cmp::max(self.max_token_len, bytes.len());
-            if tok_ids.len() == 1 && tok_ids[0] != tok_id {
+            if r.is_complete() && r.tokens.len() == 1 && r.tokens[0] != tok_id {
                 self.token_duplicates
-                    .entry(tok_ids[0])
+                    .entry(r.tokens[0])
                     .or_insert_with(Vec::new)
                     .push(tok_id);
             }
@@ -187,10 +280,116 @@ impl TokTrie {
         &self.info
     }
 
+    /// Mark the given tokens (typically the added tokens of the tokenizer) as special.
+    /// compute_bias() then asks Recognizer::special_allowed() about them,
+    /// regardless of their bytes (which are often empty).
+    pub fn with_special_tokens<'a>(
+        mut self,
+        special: impl IntoIterator<Item = (&'a String, &'a TokenId)>,
+    ) -> Self {
+        self.special_tokens = special
+            .into_iter()
+            .filter(|(_, tok)| **tok != self.info.tok_eos && **tok < self.info.vocab_size)
+            .map(|(name, tok)| (*tok, name.clone()))
+            .collect();
+        self.special_tokens.sort();
+        self.special_tokens.dedup_by_key(|(tok, _)| *tok);
+        self
+    }
+
+    /// A trie without the tokens in `banned` (a set from alloc_token_set()): compute_bias()
+    /// never allows them, and their branches are skipped in the walk, rather than
+    /// masked out after it. Lookups by bytes (token_id(), greedy_tokenize() etc.)
+    /// don't find them either. Banning EOS or another special token is an error.
+    /// Bans of tokens with the same bytes as an allowed token are not kept by serialize().
+    pub fn with_banned_tokens(&self, banned: &SimpleVob) -> Result<TokTrie> {
+        let is_banned = |tok: TokenId| (tok as usize) < banned.len() && banned.is_allowed(tok);
+        for tok in
+            std::iter::once(self.info.tok_eos).chain(self.special_tokens.iter().map(|(t, _)| *t))
+        {
+            if is_banned(tok) {
+                bail!("special token {} can't be banned", self.token_dbg(tok));
+            }
+        }
+        let mut trie = TrieHash::new(0xff);
+        for tok in 0..self.info.vocab_size {
+            let word = self.token(tok);
+            if !word.is_empty() && !is_banned(tok) {
+                trie.insert(word, tok);
+            }
+        }
+        let mut nodes = Vec::new();
+        trie.serialize(&mut nodes, 0);
+        let mut r = TokTrie {
+            nodes,
+            max_token_len: 0,
+            token_duplicates: FxHashMap::default(),
+            ..self.clone()
+        };
+        r.finalize_ctor();
+        for dups in r.token_duplicates.values_mut() {
+            dups.retain(|t| !is_banned(*t));
+        }
+        r.token_duplicates.retain(|_, dups| !dups.is_empty());
+        Ok(r)
+    }
+
+    /// with_banned_tokens() for the tokens whose bytes don't pass `keep`,
+    /// e.g., `|b| b.is_ascii()`; special tokens are always kept.
+    pub fn with_token_filter(&self, keep: impl Fn(&[u8]) -> bool) -> Result<TokTrie> {
+        let mut banned = self.alloc_token_set();
+        for tok in 0..self.info.vocab_size {
+            if !self.is_special_token(tok) && !keep(self.token(tok)) {
+                banned.allow_token(tok);
+            }
+        }
+        self.with_banned_tokens(&banned)
+    }
+
+    /// Special tokens other than EOS, with their names.
+    pub fn special_tokens(&self) -> &[(TokenId, String)] {
+        &self.special_tokens
+    }
+
+    pub fn special_token_by_name(&self, name: &str) -> Option<TokenId> {
+        self.special_tokens
+            .iter()
+            .find(|(_, n)| n == name)
+            .map(|(tok, _)| *tok)
+    }
+
+    /// What is passed to Recognizer::special_allowed() for given token;
+    /// None if the token is not special.
+    pub fn special_kind(&self, tok: TokenId) -> Option<SpecialToken> {
+        if tok == self.info.tok_eos {
+            return Some(SpecialToken::EndOfSentence);
+        }
+        let idx = self
+            .special_tokens
+            .binary_search_by_key(&tok, |(t, _)| *t)
+            .ok()?;
+        match SpecialToken::from_name(&self.special_tokens[idx].1, tok) {
+            // there is only one EOS token, the one in info
+            SpecialToken::EndOfSentence => Some(SpecialToken::Other(tok)),
+            kind => Some(kind),
+        }
+    }
+
+    pub fn is_special_token(&self, tok: TokenId) -> bool {
+        self.special_kind(tok).is_some()
+    }
+
+    /// Panics if the tokenizer has no such token.
     pub fn special_token(&self, tok: SpecialToken) -> TokenId {
         match tok {
             SpecialToken::EndOfSentence => self.info.tok_eos,
-            _ => panic!("non-EOS special_token() called"), // TODO?
+            SpecialToken::Other(id) => id,
+            _ => self
+                .special_tokens
+                .iter()
+                .map(|(t, _)| *t)
+                .find(|t| self.special_kind(*t) == Some(tok))
+                .unwrap_or_else(|| panic!("no {:?} token in the tokenizer", tok)),
         }
     }
 
@@ -198,13 +397,41 @@ impl TokTrie {
         self.info.tok_eos
     }
 
+    /// The beginning-of-sentence token and its name, if the tokenizer has one.
+    pub fn bos_token(&self) -> Option<(TokenId, &str)> {
+        self.special_tokens
+            .iter()
+            .find(|(t, _)| self.special_kind(*t) == Some(SpecialToken::BeginningOfSentence))
+            .map(|(t, n)| (*t, n.as_str()))
+    }
+
     pub fn vocab_size(&self) -> usize {
         self.info.vocab_size as usize
     }
 
+    /// Number of tokens the host model expects in logit biases; token sets passed to
+    /// return_logit_bias() need to cover at least that many.
+    /// When larger than vocab_size(), use `SimpleVob::resize()` to decide about the extra tokens.
+    pub fn model_vocab_size(&self) -> usize {
+        self.model_vocab_size as usize
+    }
+
+    /// Set by the host when loading the model, to the size of its logits.
+    pub fn set_model_vocab_size(&mut self, model_vocab_size: usize) -> Result<()> {
+        if model_vocab_size < self.vocab_size() {
+            bail!(
+                "model vocab size {} smaller than tokenizer vocab size {}",
+                model_vocab_size,
+                self.vocab_size()
+            );
+        }
+        self.model_vocab_size = model_vocab_size as u32;
+        Ok(())
+    }
+
     pub fn alloc_token_set(&self) -> SimpleVob {
         let mut r = SimpleVob::new();
-        r.resize(self.vocab_size() + 1);
+        r.resize(self.vocab_size() + 1, false);
         r
     }
 
@@ -241,6 +468,7 @@ impl TokTrie {
         vec![0.0; self.vocab_size() + 1]
     }
 
+    /// The tokens on a single line, for logs; see token_dbg().
     pub fn tokens_dbg(&self, toks: &[u32]) -> String {
         let minimal = false;
         let sep = "‧";
@@ -267,9 +495,12 @@ impl TokTrie {
         format!("\"{}\"", joined.trim_start_matches(sep))
     }
 
+    /// Control characters (e.g., newlines) are escaped, also in special token names.
     pub fn token_dbg(&self, idx: u32) -> String {
         if idx == self.info.tok_eos {
             "EOS".to_string()
+        } else if let Ok(i) = self.special_tokens.binary_search_by_key(&idx, |(t, _)| *t) {
+            self.special_tokens[i].1.escape_debug().to_string()
         } else if idx as usize >= self.vocab_size() {
             format!("OOB[{}]", idx)
         } else {
@@ -290,6 +521,10 @@ impl TokTrie {
         String::from_utf8_lossy(self.token(idx)).to_string()
     }
 
+    pub fn token_len(&self, idx: u32) -> usize {
+        (self.token_offsets[idx as usize] & 0xff) as usize
+    }
+
     pub fn token(&self, idx: u32) -> &[u8] {
         let off = self.token_offsets[idx as usize];
         let len = off & 0xff;
@@ -304,39 +539,167 @@ impl TokTrie {
             .collect()
     }
 
+    /// Invalid UTF-8 (e.g., from byte-fallback tokens) is replaced with U+FFFD.
     pub fn decode_str(&self, tokens: &[TokenId]) -> String {
         String::from_utf8_lossy(&self.decode(tokens)).to_string()
     }
 
-    pub fn greedy_tokenize(&self, bytes: &[u8]) -> Vec<TokenId> {
+    /// Same as decode_str(), but also returns the byte offset in the string
+    /// where each token starts.
+    /// When a character is split between tokens, the tokens after the first one
+    /// get the offset of the character (so the offsets never decrease).
+    pub fn decode_str_with_offsets(&self, tokens: &[TokenId]) -> (String, Vec<usize>) {
+        let bytes = self.decode(tokens);
+        // for each input byte, the offset of the character it ended up in
+        let mut char_offsets = Vec::with_capacity(bytes.len() + 1);
+        let mut res = String::with_capacity(bytes.len());
+        let mut rest = &bytes[..];
+        while rest.len() > 0 {
+            // same replacement rules as String::from_utf8_lossy()
+            let (valid, num_invalid) = match std::str::from_utf8(rest) {
+                Ok(s) => (s, 0),
+                Err(e) => {
+                    let valid = std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap();
+                    let num_invalid = e.error_len().unwrap_or(rest.len() - e.valid_up_to());
+                    (valid, num_invalid)
+                }
+            };
+            for (idx, ch) in valid.char_indices() {
+                for _ in 0..ch.len_utf8() {
+                    char_offsets.push(res.len() + idx);
+                }
+            }
+            res.push_str(valid);
+            if num_invalid > 0 {
+                for _ in 0..num_invalid {
+                    char_offsets.push(res.len());
+                }
+                res.push(char::REPLACEMENT_CHARACTER);
+            }
+            rest = &rest[valid.len() + num_invalid..];
+        }
+        char_offsets.push(res.len());
+
+        let mut pos = 0;
+        let offsets = tokens
+            .iter()
+            .map(|t| {
+                let off = char_offsets[pos];
+                pos += self.token_len(*t);
+                off
+            })
+            .collect();
+        (res, offsets)
+    }
+
+    /// Tokenize a byte string that may not be valid UTF-8.
+    /// Valid UTF-8 runs are passed to `tokenize_str` (typically the HF tokenizer),
+    /// while invalid bytes (e.g., a partial multi-byte character at the end)
+    /// are tokenized greedily at the byte level.
+    pub fn tokenize_bytes_with(
+        &self,
+        bytes: &[u8],
+        mut tokenize_str: impl FnMut(&str) -> Vec<TokenId>,
+    ) -> Vec<TokenId> {
         let mut r = Vec::new();
-        if bytes.len() == 0 {
-            return r;
+        let mut rest = bytes;
+        while rest.len() > 0 {
+            let (valid, invalid_len) = match std::str::from_utf8(rest) {
+                Ok(s) => (s, 0),
+                Err(e) => {
+                    let valid = std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap();
+                    let invalid_len = e.error_len().unwrap_or(rest.len() - e.valid_up_to());
+                    (valid, invalid_len)
+                }
+            };
+            if valid.len() > 0 {
+                r.extend_from_slice(&tokenize_str(valid));
+            }
+            let end = valid.len() + invalid_len;
+            if invalid_len > 0 {
+                r.extend_from_slice(&self.greedy_tokenize(&rest[valid.len()..end]));
+            }
+            rest = &rest[end..];
         }
+        r
+    }
 
-        let mut n = self.root();
-        let mut last_tok = None;
-        let mut last_idx = 0;
-        let mut idx = 0;
-        while idx < bytes.len() {
-            match self.child_at_byte(n, bytes[idx]) {
-                Some(c) => {
-                    if let Some(tok) = c.token_id() {
-                        last_tok = Some(tok);
-                        last_idx = idx;
+    /// Panics if some byte can't be tokenized; see tokenize_bytes_greedy().
+    pub fn greedy_tokenize(&self, bytes: &[u8]) -> Vec<TokenId> {
+        let r = self.tokenize_bytes_greedy(bytes);
+        assert!(
+            r.is_complete(),
+            "no token for byte 0x{:02x}",
+            r.remainder[0]
+        );
+        r.tokens
+    }
+
+    /// Tokenize with the longest token at each position, using only the trie (so this
+    /// doesn't depend on the tokenizer, and may differ from what it would return).
+    /// Bytes without a longer token are covered by their byte_fallback_token().
+    /// Stops at the first byte that no token starts with; the bytes from there on
+    /// are returned as the remainder.
+    pub fn tokenize_bytes_greedy(&self, bytes: &[u8]) -> TokenizationResult {
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        while start < bytes.len() {
+            let mut n = self.root();
+            let mut longest = None;
+            for (idx, &byte) in bytes[start..].iter().enumerate() {
+                match self.child_at_byte(n, byte) {
+                    Some(c) => {
+                        if let Some(tok) = c.token_id() {
+                            longest = Some((tok, idx + 1));
+                        }
+                        n = c;
                     }
-                    n = c;
+                    None => break,
+                }
+            }
+            match longest {
+                Some((tok, len)) => {
+                    tokens.push(tok);
+                    start += len;
                 }
                 None => {
-                    r.push(last_tok.unwrap());
-                    idx = last_idx;
-                    n = self.root();
+                    return TokenizationResult {
+                        tokens,
+                        remainder: bytes[start..].to_vec(),
+                    }
                 }
             }
-            idx = idx + 1;
         }
-        r.push(last_tok.unwrap());
-        r
+        TokenizationResult {
+            tokens,
+            remainder: Vec::new(),
+        }
+    }
+
+    /// Index of the first token that is not the one tokenize_bytes_greedy() picks for
+    /// the bytes of `tokens`, or None if they are the greedy tokenization of their bytes.
+    /// Tokens with the same bytes as the greedy ones are the same here; tokens
+    /// without bytes (like EOS) are skipped.
+    pub fn check_canonical(&self, tokens: &[TokenId]) -> Option<usize> {
+        let greedy = self.tokenize_bytes_greedy(&self.decode(tokens)).tokens;
+        let mut greedy = greedy.iter();
+        for (idx, tok) in tokens.iter().enumerate() {
+            let len = self.token_len(*tok);
+            if len == 0 {
+                continue;
+            }
+            // both cover the same bytes from the start, so equal lengths mean equal bytes
+            if greedy.next().map(|t| self.token_len(*t)) != Some(len) {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    /// The token that decodes to just the given byte: a byte-fallback token
+    /// (like <0x0A>), or a single-byte token of a byte-level tokenizer.
+    pub fn byte_fallback_token(&self, byte: u8) -> Option<TokenId> {
+        self.token_id(&[byte])
     }
 
     pub fn has_extensions(&self, bytes: &[u8]) -> bool {
@@ -346,6 +709,43 @@ impl TokTrie {
         }
     }
 
+    /// Whether `tok` starts with `prefix`, or is a non-empty strict prefix of it;
+    /// either way, the token agrees with the forced bytes.
+    pub fn token_fits_prefix(&self, tok: TokenId, prefix: &[u8]) -> bool {
+        let bytes = self.token(tok);
+        bytes.starts_with(prefix) || (!bytes.is_empty() && prefix.starts_with(bytes))
+    }
+
+    /// Allow all tokens that fit `prefix` (see token_fits_prefix()).
+    /// Returns false if there are no such tokens.
+    pub fn tokens_fitting_prefix(&self, prefix: &[u8], toks: &mut SimpleVob) -> bool {
+        let mut found = false;
+        let mut n = self.root();
+        for &byte in prefix {
+            // tokens on the way are strict prefixes
+            if let Some(tok) = n.token_id() {
+                toks.allow_token(tok);
+                found = true;
+            }
+            n = match self.child_at_byte(n, byte) {
+                Some(n) => n,
+                None => {
+                    self.apply_duplicates(toks);
+                    return found;
+                }
+            };
+        }
+        let off = self.node_offset(n);
+        for node in &self.nodes[off..off + n.subtree_size()] {
+            if let Some(tok) = node.token_id() {
+                toks.allow_token(tok);
+                found = true;
+            }
+        }
+        self.apply_duplicates(toks);
+        found
+    }
+
     pub fn token_id(&self, bytes: &[u8]) -> Option<TokenId> {
         let (tok, len) = self.prefix_token_id(bytes);
         // println!("tok_id {:?} {:?} {:?} ", bytes, tok, len);
@@ -372,17 +772,117 @@ impl TokTrie {
         return last;
     }
 
+    /// Return the longest token that is a prefix of `bytes` (if any).
+    pub fn longest_token_prefix(&self, bytes: &[u8]) -> Option<TokenId> {
+        if bytes.len() == 0 {
+            return None;
+        }
+        let (tok, len) = self.prefix_token_id(bytes);
+        if len == 0 {
+            None
+        } else {
+            Some(tok)
+        }
+    }
+
+    /// Compute how many tokens at the end of `tokens` could be tokenized differently,
+    /// because their bytes (jointly) form a prefix of a longer token allowed by `r`.
+    /// Returns the number of such tokens and the number of bytes they occupy.
+    pub fn chop_tokens(&self, r: &mut impl Recognizer, tokens: &[TokenId]) -> (usize, usize) {
+        let probes = self.probe_chop(r, tokens, usize::MAX);
+        probes
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, (probe, _))| *probe == ExtensionProbe::HasExtension)
+            .map_or((0, 0), |(idx, (_, num_bytes))| (idx + 1, *num_bytes))
+    }
+
+    /// For every suffix of `tokens` (shortest first) that is at most max_token_len() bytes long,
+    /// check if its bytes are a prefix of a longer token allowed by `r`.
+    /// Returns the probe result and the length of the suffix in bytes.
+    /// See probe_extensions() for `max_probes`.
+    pub fn probe_chop(
+        &self,
+        r: &mut impl Recognizer,
+        tokens: &[TokenId],
+        max_probes: usize,
+    ) -> Vec<(ExtensionProbe, usize)> {
+        let max_len = self.max_token_len();
+        let mut num_toks = 0;
+        let mut num_bytes = 0;
+        for t in tokens.iter().rev() {
+            let len = self.token_len(*t);
+            if num_bytes + len > max_len {
+                break;
+            }
+            num_toks += 1;
+            num_bytes += len;
+        }
+
+        let suff = self.decode(&tokens[tokens.len() - num_toks..]);
+        let mut suff_lens = vec![];
+        let mut suff_len = 0;
+        for t in tokens.iter().rev().take(num_toks) {
+            suff_len += self.token_len(*t);
+            suff_lens.push(suff_len);
+        }
+        let starts = suff_lens
+            .iter()
+            .map(|len| &suff[suff.len() - len..])
+            .collect::<Vec<_>>();
+        let probes = self.probe_extensions(r, &starts, max_probes);
+        probes.into_iter().zip(suff_lens).collect()
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self::deserialize(bytes).unwrap()
+    }
+
+    /// Inverse of serialize(); checks the header before trusting the section sizes.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
         let pref = std::mem::size_of::<TokTrieHeader>();
+        if bytes.len() < pref {
+            bail!("TokTrie: data too short ({} bytes)", bytes.len());
+        }
         let hd = *box_from_bytes::<TokTrieHeader>(&bytes[0..pref]);
-        assert!(hd.magic == TokTrieHeader::MAGIC);
-        assert!(hd.hd_size as usize == pref);
+        if hd.magic != TokTrieHeader::MAGIC {
+            bail!("TokTrie: invalid magic {:#x}", hd.magic);
+        }
+        if hd.version != TokTrieHeader::VERSION {
+            bail!(
+                "TokTrie: unsupported version {} (expecting {})",
+                hd.version,
+                TokTrieHeader::VERSION
+            );
+        }
+        if hd.hd_size as usize != pref {
+            bail!("TokTrie: invalid header size {}", hd.hd_size);
+        }
+        if hd.model_vocab_size < hd.info.vocab_size {
+            bail!(
+                "TokTrie: model vocab size {} smaller than tokenizer vocab size {}",
+                hd.model_vocab_size,
+                hd.info.vocab_size
+            );
+        }
 
         let trie_end = pref + hd.trie_bytes as usize;
-        let nodes = vec_from_bytes(&bytes[pref..trie_end]);
         let offsets_end = trie_end + hd.token_offset_bytes as usize;
+        let data_end = offsets_end + hd.token_data_bytes as usize;
+        if data_end + hd.special_token_bytes as usize != bytes.len() {
+            bail!("TokTrie: section sizes don't match data length");
+        }
+        let nodes = vec_from_bytes(&bytes[pref..trie_end]);
         let token_offsets = vec_from_bytes(&bytes[trie_end..offsets_end]);
-        let token_data = vec_from_bytes(&bytes[offsets_end..]);
+        let token_data = vec_from_bytes(&bytes[offsets_end..data_end]);
+        let special_tokens = deserialize_special_tokens(&bytes[data_end..])?;
+        if special_tokens
+            .iter()
+            .any(|(tok, _)| *tok >= hd.info.vocab_size)
+        {
+            bail!("TokTrie: special token out of range");
+        }
 
         let mut r = TokTrie {
             info: hd.info,
@@ -391,9 +891,11 @@ impl TokTrie {
             nodes,
             max_token_len: 0,
             token_duplicates: FxHashMap::default(),
+            model_vocab_size: hd.model_vocab_size,
+            special_tokens,
         };
         r.finalize_ctor();
-        r
+        Ok(r)
     }
 
     pub fn max_token_len(&self) -> usize {
@@ -428,13 +930,17 @@ impl TokTrie {
         let mut trie_data = clone_vec_as_bytes(&self.nodes);
         let mut token_offsets = clone_vec_as_bytes(&self.token_offsets);
         let mut token_data = clone_vec_as_bytes(&self.token_data);
+        let mut special_data = serialize_special_tokens(&self.special_tokens);
 
         let hd = TokTrieHeader {
             magic: TokTrieHeader::MAGIC,
+            version: TokTrieHeader::VERSION,
             hd_size: std::mem::size_of::<TokTrieHeader>() as u32,
             trie_bytes: trie_data.len() as u32,
             token_offset_bytes: token_offsets.len() as u32,
-            token_data_bytes: trie_data.len() as u32,
+            token_data_bytes: token_data.len() as u32,
+            special_token_bytes: special_data.len() as u32,
+            model_vocab_size: self.model_vocab_size,
             info: self.info.clone(),
             align: [],
         };
@@ -443,6 +949,7 @@ impl TokTrie {
         bytes.append(&mut trie_data);
         bytes.append(&mut token_offsets);
         bytes.append(&mut token_data);
+        bytes.append(&mut special_data);
         bytes
     }
 
@@ -502,13 +1009,31 @@ impl TokTrie {
         self.compute_bias_ext(r, logits, &[]);
     }
 
+    /// Like compute_bias(), but the set is taken from `cache` when it has one for
+    /// the Recognizer::state_key() of `r`.
+    pub fn compute_bias_cached(
+        &self,
+        r: &mut impl Recognizer,
+        cache: &mut BiasCache,
+        logits: &mut SimpleVob,
+    ) {
+        let key = match r.state_key() {
+            Some(key) => key,
+            None => return self.compute_bias(r, logits),
+        };
+        if !cache.get(key, logits) {
+            self.compute_bias(r, logits);
+            cache.insert(key, logits);
+        }
+    }
+
     pub fn compute_bias_ext(&self, r: &mut impl Recognizer, logits: &mut SimpleVob, start: &[u8]) {
         logits.set_all(false);
-        for tok in vec![SpecialToken::EndOfSentence] {
-            if r.special_allowed(tok) {
-                logits.allow_token(self.special_token(tok))
-            }
-        }
+        // special tokens are up to the recognizer, not the bytes we happen to have for them
+        let special = std::iter::once(self.info.tok_eos)
+            .chain(self.special_tokens.iter().map(|(t, _)| *t))
+            .map(|t| (t, special_allowed(r, self.special_kind(t).unwrap())))
+            .collect::<Vec<_>>();
         // all prefixes of 'start' are also allowed
         if start.len() > 0 {
             for len in 1..=start.len() {
@@ -520,6 +1045,9 @@ impl TokTrie {
         }
         self.add_bias(r, logits, start);
         self.apply_duplicates(logits);
+        for (tok, allowed) in special {
+            logits.set(tok, allowed);
+        }
     }
 
     pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
@@ -538,16 +1066,21 @@ impl TokTrie {
         }
     }
 
+    /// Special tokens don't push any bytes.
     pub fn append_token(&self, r: &mut impl Recognizer, t: TokenId) {
         // println!("append_token: {}", self.token_dbg(t));
-        let bytes = self.token(t);
-        for &byte in bytes {
-            r.push_byte(byte)
+        if !self.is_special_token(t) {
+            for &byte in self.token(t) {
+                r.push_byte(byte)
+            }
         }
         r.collapse()
     }
 
     pub fn token_allowed(&self, r: &mut impl Recognizer, t: TokenId) -> bool {
+        if let Some(kind) = self.special_kind(t) {
+            return special_allowed(r, kind);
+        }
         let bytes = self.token(t);
         let mut num = 0;
         let mut ok = true;
@@ -563,6 +1096,82 @@ impl TokTrie {
         ok
     }
 
+    /// Like has_valid_extensions(), for all of `starts` in a single walk of the trie and `r`.
+    /// Byte sequences rejected by `r` are remembered, so that they are not probed again
+    /// when they follow another start.
+    /// At most `max_probes` bytes are pushed to `r` in total; the starts not settled
+    /// by then get ExtensionProbe::Unknown.
+    #[inline(never)]
+    pub fn probe_extensions(
+        &self,
+        r: &mut impl Recognizer,
+        starts: &[&[u8]],
+        max_probes: usize,
+    ) -> Vec<ExtensionProbe> {
+        // bytes following the start, as pushed to r
+        let mut path = Vec::new();
+        let mut rejected = FxHashSet::<Vec<u8>>::default();
+        let mut num_probes = 0;
+        let mut res = Vec::with_capacity(starts.len());
+        r.trie_started();
+        for start in starts {
+            let n = match self.child_at_bytes(self.root(), start) {
+                Some(n) => n,
+                None => {
+                    res.push(ExtensionProbe::NoExtension);
+                    continue;
+                }
+            };
+            let off = self.node_offset(n);
+            let mut p = off + 1;
+            let endp = off + n.subtree_size();
+            let mut probe = ExtensionProbe::NoExtension;
+            let mut next_pop = 0;
+            while p < endp {
+                r.pop_bytes(next_pop);
+                path.truncate(path.len() - next_pop);
+                let n = &self.nodes[p];
+                path.push(n.byte());
+                let ok = if rejected.contains(&path) {
+                    false
+                } else if num_probes >= max_probes {
+                    path.pop();
+                    probe = ExtensionProbe::Unknown(max_probes);
+                    break;
+                } else {
+                    num_probes += 1;
+                    let ok = r.try_push_byte(n.byte());
+                    if !ok {
+                        rejected.insert(path.clone());
+                    }
+                    ok
+                };
+                if ok {
+                    if n.token_id().is_some() {
+                        probe = ExtensionProbe::HasExtension;
+                        break;
+                    }
+                    next_pop = if n.subtree_size() == 1 {
+                        n.num_parents()
+                    } else {
+                        0
+                    };
+                    p += 1;
+                } else {
+                    path.pop();
+                    p += n.subtree_size();
+                    next_pop = n.num_parents() - 1;
+                }
+            }
+            // back to the state before the start
+            r.pop_bytes(path.len());
+            path.clear();
+            res.push(probe);
+        }
+        r.trie_finished();
+        res
+    }
+
     /// Check if add_bias() would have returned any tokens.
     #[inline(never)]
     pub fn has_valid_extensions(&self, r: &mut impl Recognizer, start: &[u8]) -> bool {
@@ -607,6 +1216,9 @@ impl TokTrie {
 
     #[inline(never)]
     pub fn add_bias(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, start: &[u8]) {
+        if r.has_fast_byte_mask() {
+            return self.add_bias_masked(r, toks, start);
+        }
         r.trie_started();
         let n = self.child_at_bytes(self.root(), start).unwrap();
         let defl_tok = self.vocab_size() as u32;
@@ -639,6 +1251,58 @@ impl TokTrie {
         // revert the fake token
         toks.disallow_token(defl_tok);
     }
+
+    /// Same as add_bias(), but children of a node with many children are first checked
+    /// against Recognizer::allowed_byte_mask() of the node.
+    fn add_bias_masked(&self, r: &mut impl Recognizer, toks: &mut SimpleVob, start: &[u8]) {
+        r.trie_started();
+        let n = self.child_at_bytes(self.root(), start).unwrap();
+        let defl_tok = self.vocab_size() as u32;
+        let off = self.node_offset(n);
+        let mut p = off + 1;
+        let endp = off + n.subtree_size();
+        // masks[d] is for the node d bytes below n, if masked[d]
+        let mut masks = vec![[false; 256]; self.max_token_len + 1];
+        let mut masked = vec![false; self.max_token_len + 1];
+        let mut depth = 0;
+        masked[0] = self.has_many_children(n);
+        if masked[0] {
+            r.allowed_byte_mask(&mut masks[0]);
+        }
+        let mut next_pop = 0;
+        while p < endp {
+            r.pop_bytes(next_pop);
+            depth -= next_pop;
+            let n = &self.nodes[p];
+            let b = n.byte();
+            if (!masked[depth] || masks[depth][b as usize]) && r.try_push_byte(b) {
+                depth += 1;
+                toks.allow_token(n.token_id().unwrap_or(defl_tok));
+                next_pop = if n.subtree_size() == 1 {
+                    n.num_parents()
+                } else {
+                    masked[depth] = self.has_many_children(n);
+                    if masked[depth] {
+                        r.allowed_byte_mask(&mut masks[depth]);
+                    }
+                    0
+                };
+                p += 1;
+            } else {
+                p += n.subtree_size();
+                next_pop = n.num_parents() - 1;
+            }
+        }
+        if start.len() == 0 {
+            r.pop_bytes(next_pop);
+        }
+        r.trie_finished();
+        toks.disallow_token(defl_tok);
+    }
+
+    fn has_many_children(&self, n: &TrieNode) -> bool {
+        self.node_children(n).nth(MASK_MIN_CHILDREN - 1).is_some()
+    }
 }
 
 pub struct NodeChildren<'a> {
@@ -668,6 +1332,40 @@ pub struct TokenizerBin {
     tree_bytes: u32,
 }
 
+// what compute_bias() allows for the special token
+fn special_allowed(r: &mut impl Recognizer, kind: SpecialToken) -> bool {
+    r.special_allowed(kind) || (kind == SpecialToken::EndOfSentence && r.eos_after_match())
+}
+
+// each entry is: token id (u32, little endian), name length (u32, little endian), name
+fn serialize_special_tokens(special: &[(TokenId, String)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (tok, name) in special {
+        bytes.extend_from_slice(&tok.to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes
+}
+
+fn deserialize_special_tokens(mut bytes: &[u8]) -> Result<Vec<(TokenId, String)>> {
+    let mut special = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 8 {
+            bail!("TokTrie: truncated special token data");
+        }
+        let tok = TokenId::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        if bytes.len() - 8 < len {
+            bail!("TokTrie: truncated special token data");
+        }
+        let end = 8 + len;
+        special.push((tok, String::from_utf8_lossy(&bytes[8..end]).to_string()));
+        bytes = &bytes[end..];
+    }
+    Ok(special)
+}
+
 struct TrieHash {
     token_id: u32,
     byte: u8,
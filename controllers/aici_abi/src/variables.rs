@@ -0,0 +1,134 @@
+use crate::{StorageCmd, StorageOp, StorageResp};
+use std::collections::HashMap;
+
+/// The storage variables of a request (or the global ones), as kept by the host;
+/// see StorageCmd for the semantics of the commands.
+pub struct Variables {
+    pub variables: HashMap<String, (u64, Vec<u8>)>,
+    /// Maximum total size of names and values.
+    quota: usize,
+    num_bytes: usize,
+    /// Number of writes so far; see StorageResp::ReadBatch.
+    seq: u64,
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl Variables {
+    pub fn new(quota: usize) -> Self {
+        Variables {
+            variables: HashMap::default(),
+            quota,
+            num_bytes: 0,
+            seq: 0,
+        }
+    }
+
+    fn size_after(&self, name: &str, value: Option<&[u8]>) -> usize {
+        let prev_size = self
+            .variables
+            .get(name)
+            .map_or(0, |(_, v)| name.len() + v.len());
+        self.num_bytes - prev_size + value.map_or(0, |v| name.len() + v.len())
+    }
+
+    // doesn't check the quota
+    fn put(&mut self, name: String, entry: Option<(u64, Vec<u8>)>) {
+        self.num_bytes = self.size_after(&name, entry.as_ref().map(|e| e.1.as_slice()));
+        match entry {
+            Some(entry) => self.variables.insert(name, entry),
+            None => self.variables.remove(&name),
+        };
+    }
+
+    fn insert(&mut self, name: String, version: u64, value: Vec<u8>) -> StorageResp {
+        if self.size_after(&name, Some(&value)) > self.quota {
+            return StorageResp::QuotaExceeded { quota: self.quota };
+        }
+        self.put(name, Some((version, value)));
+        self.seq += 1;
+        StorageResp::WriteVar { version }
+    }
+
+    fn write_batch(
+        &mut self,
+        writes: Vec<(String, Vec<u8>)>,
+        require_seq: Option<u64>,
+    ) -> StorageResp {
+        if require_seq.is_some_and(|s| s != self.seq) {
+            return StorageResp::Conflict { seq: self.seq };
+        }
+        // apply the writes, and undo them (in reverse) if the quota ends up exceeded
+        let mut undo = Vec::new();
+        for (name, value) in writes {
+            let prev = self.variables.get(&name).cloned();
+            let version = prev.as_ref().map_or(0, |(v, _)| *v) + 1;
+            self.put(name.clone(), Some((version, value)));
+            undo.push((name, prev));
+        }
+        if self.num_bytes > self.quota {
+            for (name, prev) in undo.into_iter().rev() {
+                self.put(name, prev);
+            }
+            return StorageResp::QuotaExceeded { quota: self.quota };
+        }
+        self.seq += 1;
+        StorageResp::WriteBatch { seq: self.seq }
+    }
+
+    pub fn process_cmd(&mut self, cmd: StorageCmd) -> StorageResp {
+        match cmd {
+            StorageCmd::ReadVar { name } => match self.variables.get(&name).cloned() {
+                None => StorageResp::VariableMissing {},
+                Some((version, value)) => StorageResp::ReadVar { value, version },
+            },
+            StorageCmd::ReadBatch { names } => StorageResp::ReadBatch {
+                seq: self.seq,
+                values: names
+                    .iter()
+                    .map(|name| self.variables.get(name).cloned())
+                    .collect(),
+            },
+            StorageCmd::WriteBatch {
+                writes,
+                require_seq,
+            } => self.write_batch(writes, require_seq),
+            StorageCmd::WriteVar {
+                name,
+                value,
+                when_version_is,
+                op,
+            } => {
+                let curr = self.variables.get(&name).cloned();
+                match curr {
+                    Some((prev_version, prev_val)) => match when_version_is {
+                        Some(v) if v != prev_version => StorageResp::ReadVar {
+                            version: prev_version,
+                            value: prev_val,
+                        },
+                        _ => {
+                            let value = match op {
+                                StorageOp::Append => {
+                                    let mut v = prev_val.clone();
+                                    v.extend(value);
+                                    v
+                                }
+                                StorageOp::Set => value,
+                            };
+                            self.insert(name, prev_version + 1, value)
+                        }
+                    },
+
+                    None => match when_version_is {
+                        None | Some(0) => self.insert(name, 1, value),
+                        Some(_) => StorageResp::VariableMissing {},
+                    },
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use aici_abi::{
+    recognizer::{AnythingGoes, ByteClass, Literal, StackRecognizer},
+    rx::RecRx,
+    svob::SimpleVob,
+    testing::MockTokenizerEnv,
+    toktree::{Recognizer, SpecialToken, TokTrie},
+    TokenId, TokenizerEnv,
+};
+
+const WORDS: &[&str] = &[
+    " the",
+    " value",
+    "\u{2014}",
+    " \u{2014}",
+    "\u{2026}",
+    "\u{2019}s",
+    "caf\u{e9}",
+    "\u{e9}t\u{e9}",
+    "na\u{ef}ve",
+    "\u{2192}",
+    "->",
+    "Hello",
+    " world",
+];
+
+/// Counts the bytes the trie walk tries.
+struct Counting<R: Recognizer> {
+    inner: R,
+    num_probes: usize,
+}
+
+impl<R: Recognizer> Recognizer for Counting<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.inner.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.inner.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.inner.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.inner.trie_finished()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.num_probes += 1;
+        self.inner.try_push_byte(byte)
+    }
+}
+
+fn anything_counting() -> Counting<StackRecognizer<(), AnythingGoes>> {
+    Counting {
+        inner: StackRecognizer::from(AnythingGoes {}),
+        num_probes: 0,
+    }
+}
+
+fn with_e2(trie: &TokTrie) -> SimpleVob {
+    let mut banned = trie.alloc_token_set();
+    for tok in 0..trie.vocab_size() as TokenId {
+        if trie.token(tok).contains(&0xE2) {
+            banned.allow_token(tok);
+        }
+    }
+    banned
+}
+
+fn allowed(trie: &TokTrie, r: &mut impl Recognizer) -> SimpleVob {
+    let mut set = trie.alloc_token_set();
+    trie.compute_bias(r, &mut set);
+    set
+}
+
+#[test]
+fn banned_tokens_are_never_allowed() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let banned = with_e2(trie);
+    // the byte itself, and the words with it
+    assert_eq!(banned.num_set(), 6);
+    let pruned = trie.with_banned_tokens(&banned).unwrap();
+    assert_eq!(pruned.vocab_size(), trie.vocab_size());
+
+    let check = |set: SimpleVob, full: SimpleVob| {
+        for tok in 0..trie.vocab_size() as TokenId {
+            let expected = full.is_allowed(tok) && !banned.is_allowed(tok);
+            assert_eq!(set.is_allowed(tok), expected, "{}", trie.token_dbg(tok));
+        }
+    };
+    // "->" and "\u{2192}" only differ in the banned byte
+    let rx = r"( the| value| \xE2\x80\x94|\xE2\x80\xA6|caf\xC3\xA9|->|\xE2\x86\x92)+";
+    macro_rules! check_all {
+        ($($rec:expr),*) => {
+            $(check(
+                allowed(&pruned, &mut StackRecognizer::from($rec)),
+                allowed(trie, &mut StackRecognizer::from($rec)),
+            );)*
+        };
+    }
+    check_all!(
+        AnythingGoes {},
+        Literal::new(" \u{2014}".as_bytes()),
+        ByteClass::new(&[b'-', 0xE2, b' ']),
+        RecRx::from_rx(rx)
+    );
+
+    // lookups by bytes don't find them either
+    assert_eq!(pruned.token_id(" \u{2014}".as_bytes()), None);
+    assert_eq!(pruned.token_id(b" the"), trie.token_id(b" the"));
+    assert!(!pruned
+        .tokenize_bytes_greedy("\u{2014}".as_bytes())
+        .is_complete());
+}
+
+#[test]
+fn pruned_trie_walks_fewer_nodes() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let pruned = trie.with_token_filter(|b| b.is_ascii()).unwrap();
+    // all bytes from 0x80 up, and the words with them
+    let num_banned = (0..trie.vocab_size() as TokenId)
+        .filter(|t| !trie.token(*t).is_ascii())
+        .count();
+    assert_eq!(num_banned, 128 + 8);
+
+    let mut full_walk = anything_counting();
+    let mut pruned_walk = anything_counting();
+    let full = allowed(trie, &mut full_walk);
+    let set = allowed(&pruned, &mut pruned_walk);
+    assert_eq!(full.num_set() - set.num_set(), num_banned);
+    // every banned token is a node not visited (some are inner nodes of others)
+    assert!(
+        pruned_walk.num_probes + num_banned <= full_walk.num_probes,
+        "{} vs {}",
+        pruned_walk.num_probes,
+        full_walk.num_probes
+    );
+}
+
+#[test]
+fn special_tokens_cant_be_banned() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let mut banned = with_e2(trie);
+    banned.allow_token(trie.eos_token());
+    let err = trie.with_banned_tokens(&banned).err().unwrap();
+    assert!(err.to_string().contains("can't be banned"), "{err}");
+
+    // the filter leaves EOS alone, even though it has no bytes to keep
+    let pruned = trie.with_token_filter(|b| !b.is_empty()).unwrap();
+    let set = allowed(&pruned, &mut StackRecognizer::from(AnythingGoes {}));
+    assert!(set.is_allowed(trie.eos_token()));
+}
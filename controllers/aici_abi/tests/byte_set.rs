@@ -0,0 +1,159 @@
+use aici_abi::{
+    bytes::ByteSet,
+    recognizer::{
+        Alt, ByteClass, FunctionalRecognizer, Literal, Repeat, RepeatState, Seq, SeqState,
+        StackRecognizer,
+    },
+    rng::Rng,
+    testing::MockTokenizerEnv,
+    toktree::{Recognizer, SpecialToken},
+    TokenizerEnv,
+};
+
+fn random_set(rng: &mut Rng) -> (ByteSet, [bool; 256]) {
+    let mut set = ByteSet::new();
+    let mut naive = [false; 256];
+    for b in 0..=255u8 {
+        if rng.gen_bool(0.3) {
+            set.insert(b);
+            naive[b as usize] = true;
+        }
+    }
+    (set, naive)
+}
+
+fn same_as(set: ByteSet, naive: impl Fn(u8) -> bool) -> bool {
+    (0..=255u8).all(|b| set.contains(b) == naive(b))
+}
+
+#[test]
+fn set_operations_match_per_byte_reference() {
+    let mut rng = Rng::seeded(7);
+    for _ in 0..50 {
+        let (a, na) = random_set(&mut rng);
+        let (b, nb) = random_set(&mut rng);
+        assert!(same_as(a, |x| na[x as usize]));
+        assert!(same_as(a | b, |x| na[x as usize] || nb[x as usize]));
+        assert!(same_as(a & b, |x| na[x as usize] && nb[x as usize]));
+        assert!(same_as(!a, |x| !na[x as usize]));
+        assert_eq!(a.len(), na.iter().filter(|x| **x).count());
+        assert_eq!(a.iter().count(), a.len());
+
+        let mut c = a;
+        let x = rng.gen_up_to(255) as u8;
+        c.remove(x);
+        assert!(same_as(c, |y| y != x && na[y as usize]));
+        c |= b;
+        assert!(same_as(c, |y| (y != x && na[y as usize]) || nb[y as usize]));
+        assert_eq!([a, b].into_iter().collect::<ByteSet>(), a | b);
+        assert_eq!(a.first_byte(), a.iter().next());
+    }
+
+    const LOWER: ByteSet = ByteSet::from_range(b'a'..=b'z');
+    assert!(same_as(LOWER, |x| x.is_ascii_lowercase()));
+    assert!(same_as(ByteSet::from_str("abc"), |x| b"abc".contains(&x)));
+    assert!(same_as(ByteSet::from_range(0..=255), |_| true));
+    assert_eq!(ByteSet::full(), !ByteSet::new());
+    assert!(ByteSet::new().is_empty() && ByteSet::full().len() == 256);
+    assert_eq!(
+        format!("{:?}", ByteSet::from_str("a\n")),
+        "ByteSet(\"\\na\")"
+    );
+    assert_eq!(ByteSet::from_byte(0xf0).single_byte(), Some(0xf0));
+    assert_eq!(ByteSet::from_str("ab").single_byte(), None);
+    assert_eq!(ByteSet::new().first_byte(), None);
+    let ranges =
+        ByteSet::from_range(b'a'..=b'z') | ByteSet::from_str("_ ") | !ByteSet::from_range(0..=0x7f);
+    assert_eq!(ranges.to_string(), "' ';_;a-z;x80-xff");
+}
+
+/// Hides the allowed_set() of the wrapped recognizer, so that the default one is used.
+struct Probed<R>(R);
+
+impl<S: Copy, R: FunctionalRecognizer<S>> FunctionalRecognizer<S> for Probed<R> {
+    fn initial(&self) -> S {
+        self.0.initial()
+    }
+
+    fn append(&self, state: S, byte: u8) -> S {
+        self.0.append(state, byte)
+    }
+
+    fn byte_allowed(&self, state: S, byte: u8) -> bool {
+        self.0.byte_allowed(state, byte)
+    }
+
+    fn special_allowed(&self, state: S, tok: SpecialToken) -> bool {
+        self.0.special_allowed(state, tok)
+    }
+
+    fn is_accepting(&self, state: S) -> bool {
+        self.0.is_accepting(state)
+    }
+}
+
+// "x=" followed by a capitalized name or a number of up to 3 digits
+type Name = Seq<ByteClass, Repeat<ByteClass, bool>, RepeatState>;
+type NameOrAge = Seq<Literal, Alt<Name, Repeat<ByteClass, bool>>, NameOrAgeTail>;
+type NameOrAgeTail = (Option<SeqState<bool>>, Option<RepeatState>);
+type NameOrAgeState = SeqState<usize>;
+
+fn name_or_age() -> NameOrAge {
+    Seq::new(
+        Literal::new(b"x="),
+        Alt(
+            Seq::new(
+                ByteClass::range(b'A', b'Z'),
+                Repeat::new(ByteClass::range(b'a', b'z'), 1, usize::MAX),
+            ),
+            Repeat::new(ByteClass::from_set(ByteSet::from_str("0123456789")), 1, 3),
+        ),
+    )
+}
+
+#[test]
+fn overridden_allowed_set_matches_default() {
+    let rec = name_or_age();
+    assert!(rec.has_fast_allowed_set());
+    let probed = Probed(name_or_age());
+    assert!(!probed.has_fast_allowed_set());
+
+    for text in ["x=Bob", "x=42", "x=123"] {
+        // the states are only valid in the recognizer that made them
+        let mut state = rec.initial();
+        let mut probed_state = probed.initial();
+        for &b in text.as_bytes() {
+            assert_eq!(rec.allowed_set(state), probed.allowed_set(probed_state));
+            assert!(rec.byte_allowed(state, b));
+            state = rec.append(state, b);
+            probed_state = probed.append(probed_state, b);
+        }
+        assert_eq!(rec.allowed_set(state), probed.allowed_set(probed_state));
+    }
+}
+
+#[test]
+fn fast_sets_give_same_token_bias() {
+    let env = MockTokenizerEnv::default();
+    let trie = env.tok_trie();
+    let mut fast: StackRecognizer<NameOrAgeState, _> = StackRecognizer::from(name_or_age());
+    let mut slow = StackRecognizer::from(Probed(name_or_age()));
+    for prefix in ["", "x=", "x=B", "x=4"] {
+        fast.reset();
+        slow.reset();
+        for &b in prefix.as_bytes() {
+            fast.push_byte(b);
+            slow.push_byte(b);
+        }
+        fast.collapse();
+        slow.collapse();
+        let mut fast_set = trie.alloc_token_set();
+        trie.compute_bias(&mut fast, &mut fast_set);
+        let mut slow_set = trie.alloc_token_set();
+        trie.compute_bias(&mut slow, &mut slow_set);
+        let differ = (0..trie.vocab_size() as u32)
+            .find(|t| fast_set.is_allowed(*t) != slow_set.is_allowed(*t));
+        assert_eq!(differ, None, "prefix {prefix:?}");
+        assert!(fast_set.num_set() > 0);
+    }
+}
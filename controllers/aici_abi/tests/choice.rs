@@ -0,0 +1,137 @@
+use aici_abi::{
+    choice::{choice_bias, ChoiceStep},
+    svob::SimpleVob,
+    testing::MockTokenizerEnv,
+    toktree::TokTrie,
+    TokenId, TokenizerEnv,
+};
+
+const WORDS: &[&str] = &[" ne", " neg", "gative", "ative", " neutral", " pos", "itive"];
+
+fn tok(trie: &TokTrie, s: &str) -> TokenId {
+    trie.token_id(s.as_bytes()).unwrap()
+}
+
+/// Toy model: prefers tokens continuing `target` after `generated`, the longer the better.
+fn toy_logits(trie: &TokTrie, target: &str, generated: &[u8]) -> Vec<f32> {
+    (0..trie.vocab_size() as TokenId)
+        .map(|t| {
+            let mut text = generated.to_vec();
+            text.extend_from_slice(trie.token(t));
+            if text.len() > generated.len() && target.as_bytes().starts_with(&text) {
+                2.0 * trie.token_len(t) as f32
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+fn log_softmax(logits: &[f32], t: TokenId) -> f32 {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let lse = max + logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+    logits[t as usize] - lse
+}
+
+fn greedy(logits: &[f32], allowed: &SimpleVob) -> TokenId {
+    (0..logits.len() as TokenId)
+        .filter(|t| allowed.is_allowed(*t))
+        .max_by(|a, b| logits[*a as usize].total_cmp(&logits[*b as usize]))
+        .unwrap()
+}
+
+#[test]
+fn shared_prefixes_are_resolved_over_steps() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let (allowed, mut table) = choice_bias(trie, &[" neg", " negative", " neutral"]);
+    for s in [" ", " ne", " neg", " neutral"] {
+        assert!(allowed.is_allowed(tok(trie, s)), "{s:?}");
+    }
+    assert!(!allowed.is_allowed(tok(trie, " pos")));
+    assert!(!allowed.is_allowed(trie.eos_token()));
+
+    match table.commit_token(trie, tok(trie, " neg")) {
+        ChoiceStep::Continue(allowed) => {
+            // " neg" is complete, " negative" continues
+            assert!(allowed.is_allowed(trie.eos_token()));
+            assert!(allowed.is_allowed(tok(trie, "ative")));
+            assert!(!allowed.is_allowed(tok(trie, "gative")));
+        }
+        r => panic!("expected Continue, got {r:?}"),
+    }
+    assert_eq!(table.candidates(), &[0, 1]);
+    assert_eq!(table.remaining(1), b"ative");
+
+    let mut other = table.clone();
+    assert!(matches!(
+        table.commit_token(trie, trie.eos_token()),
+        ChoiceStep::Chosen(0)
+    ));
+    assert!(matches!(
+        other.commit_token(trie, tok(trie, "a")),
+        ChoiceStep::Chosen(1)
+    ));
+    assert_eq!(other.remaining(1), b"tive");
+}
+
+#[test]
+fn tokens_outside_the_options_are_invalid() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let (_, mut table) = choice_bias(trie, &[" negative", " neutral"]);
+    assert!(matches!(
+        table.commit_token(trie, tok(trie, " ne")),
+        ChoiceStep::Continue(_)
+    ));
+    assert!(matches!(
+        table.commit_token(trie, tok(trie, "x")),
+        ChoiceStep::Invalid
+    ));
+    assert!(table.candidates().is_empty());
+}
+
+#[test]
+fn greedy_choice_matches_best_scored_option() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    let options = [" positive", " negative", " neutral"];
+    for target in options {
+        // greedy generation constrained to the options
+        let (mut allowed, mut table) = choice_bias(trie, &options);
+        let mut generated = Vec::new();
+        let chosen = loop {
+            let t = greedy(&toy_logits(trie, target, &generated), &allowed);
+            generated.extend_from_slice(trie.token(t));
+            match table.commit_token(trie, t) {
+                ChoiceStep::Chosen(idx) => break idx,
+                ChoiceStep::Continue(next) => allowed = next,
+                ChoiceStep::Invalid => panic!("invalid token {}", trie.token_dbg(t)),
+            }
+        };
+
+        // total log-probability of each option's tokens, teacher-forced
+        let logprobs: Vec<f32> = options
+            .iter()
+            .map(|opt| {
+                let mut generated = Vec::new();
+                let mut lp = 0.0;
+                for t in env.tokenize_bytes(opt.as_bytes()) {
+                    lp += log_softmax(&toy_logits(trie, target, &generated), t);
+                    generated.extend_from_slice(trie.token(t));
+                }
+                lp
+            })
+            .collect();
+        let max = logprobs.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let sum: f32 = logprobs.iter().map(|lp| (lp - max).exp()).sum();
+        let scores: Vec<f32> = logprobs.iter().map(|lp| (lp - max).exp() / sum).collect();
+
+        assert!((scores.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        let best = (0..options.len())
+            .max_by(|a, b| scores[*a].total_cmp(&scores[*b]))
+            .unwrap();
+        assert_eq!(options[chosen], target);
+        assert_eq!(best, chosen, "{target:?}: {scores:?}");
+    }
+}
@@ -0,0 +1,184 @@
+use aici_abi::{
+    bytes::TokRxInfo,
+    recognizer::{AnythingGoes, ByteClass, Literal, Repeat, StackRecognizer},
+    toktree::{Recognizer, SpecialToken, TokTrie},
+    TokenId,
+};
+use std::{collections::HashSet, time::Instant};
+
+const VOCAB_SIZE: usize = 32_000;
+const NUM_FORCED: usize = 20;
+
+// deterministic pseudo-random numbers (64-bit LCG)
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % n
+    }
+
+    fn text(&mut self, len: usize) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz      ";
+        (0..len)
+            .map(|_| ALPHABET[self.next(ALPHABET.len())])
+            .collect()
+    }
+}
+
+/// All single bytes, and words of 2-8 letters (some with a leading space) up to VOCAB_SIZE.
+fn big_trie() -> TokTrie {
+    let mut rng = Lcg(1);
+    let mut tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    let mut seen = HashSet::new();
+    while tokens.len() < VOCAB_SIZE - 1 {
+        let len = 2 + rng.next(7);
+        let mut word = rng.text(len);
+        if word.iter().all(|b| *b == b' ') {
+            continue;
+        }
+        if rng.next(2) == 0 {
+            word[0] = b' ';
+        }
+        if seen.insert(word.clone()) {
+            tokens.push(word);
+        }
+    }
+    tokens.push(vec![]);
+    let info = TokRxInfo {
+        vocab_size: tokens.len() as u32,
+        tok_eos: tokens.len() as TokenId - 1,
+    };
+    TokTrie::from(&info, &tokens)
+}
+
+/// Like the grammar parser, drops the bytes pushed by an early exit from
+/// has_valid_extensions() in trie_finished(); StackRecognizer insists they were popped.
+struct Unwinding<R> {
+    rec: R,
+    depth: usize,
+}
+
+impl<R> Unwinding<R> {
+    fn new(rec: R) -> Self {
+        Unwinding { rec, depth: 0 }
+    }
+}
+
+impl<R: Recognizer> Recognizer for Unwinding<R> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.depth -= num;
+        self.rec.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.depth = 0;
+        self.rec.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.rec.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.pop_bytes(self.depth);
+        self.rec.trie_finished()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let ok = self.rec.try_push_byte(byte);
+        self.depth += ok as usize;
+        ok
+    }
+}
+
+/// The loop TokenParser used before chop_tokens().
+fn chop_tokens_ref(trie: &TokTrie, r: &mut impl Recognizer, tokens: &[TokenId]) -> (usize, usize) {
+    let mut suff = Vec::new();
+    let mut chop_tokens = 0;
+    let mut chop_bytes = 0;
+    for (idx, t) in tokens.iter().rev().enumerate() {
+        suff.splice(0..0, trie.token(*t).iter().cloned());
+        if suff.len() > trie.max_token_len() {
+            break;
+        }
+        if trie.has_valid_extensions(r, &suff) {
+            chop_tokens = idx + 1;
+            chop_bytes = suff.len();
+        }
+    }
+    (chop_tokens, chop_bytes)
+}
+
+// forced tokens, as the grammar would produce them
+fn forced_tokens(trie: &TokTrie, rng: &mut Lcg) -> Vec<TokenId> {
+    let text = rng.text(200);
+    let r = trie.tokenize_bytes_greedy(&text);
+    r.tokens[r.tokens.len() - NUM_FORCED..].to_vec()
+}
+
+fn check_same_chops(trie: &TokTrie, r: &mut impl Recognizer, rng: &mut Lcg) -> usize {
+    let mut num_chopped = 0;
+    for _ in 0..50 {
+        let tokens = forced_tokens(trie, rng);
+        let expected = chop_tokens_ref(trie, r, &tokens);
+        assert_eq!(
+            trie.chop_tokens(r, &tokens),
+            expected,
+            "{}",
+            trie.tokens_dbg(&tokens)
+        );
+        num_chopped += expected.0;
+    }
+    num_chopped
+}
+
+#[test]
+fn chop_tokens_matches_reference() {
+    let trie = big_trie();
+    let mut rng = Lcg(2);
+
+    let mut anything = Unwinding::new(StackRecognizer::from(AnythingGoes {}));
+    assert!(check_same_chops(&trie, &mut anything, &mut rng) > 0);
+
+    let letters = Repeat::new(ByteClass::range(b'a', b'z'), 0, 100);
+    let mut letters = Unwinding::new(StackRecognizer::from(letters));
+    assert!(check_same_chops(&trie, &mut letters, &mut rng) > 0);
+
+    // nothing extends the forced text
+    let mut done = Unwinding::new(StackRecognizer::from(Literal::new(b"")));
+    assert_eq!(check_same_chops(&trie, &mut done, &mut rng), 0);
+}
+
+#[test]
+#[ignore = "micro-benchmark; run with --nocapture"]
+fn bench_chop_tokens() {
+    let trie = big_trie();
+    let mut rng = Lcg(3);
+    let inputs: Vec<_> = (0..1000).map(|_| forced_tokens(&trie, &mut rng)).collect();
+    let mut r = Unwinding::new(StackRecognizer::from(AnythingGoes {}));
+
+    let t0 = Instant::now();
+    let reference: usize = inputs
+        .iter()
+        .map(|tokens| chop_tokens_ref(&trie, &mut r, tokens).0)
+        .sum();
+    let reference_time = t0.elapsed();
+
+    let t0 = Instant::now();
+    let chopped: usize = inputs
+        .iter()
+        .map(|tokens| trie.chop_tokens(&mut r, tokens).0)
+        .sum();
+    let chop_time = t0.elapsed();
+
+    assert_eq!(chopped, reference);
+    println!(
+        "{} x {NUM_FORCED} forced tokens ({VOCAB_SIZE} vocab): \
+         old loop {reference_time:?}, chop_tokens() {chop_time:?}",
+        inputs.len()
+    );
+}
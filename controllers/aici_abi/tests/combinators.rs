@@ -0,0 +1,124 @@
+use aici_abi::{
+    recognizer::{Alt, ByteClass, FunctionalRecognizer, Literal, Repeat, Seq, StackRecognizer},
+    rng::Rng,
+    toktree::{Recognizer, SpecialToken},
+};
+
+fn matches<S: Copy>(rec: &impl FunctionalRecognizer<S>, text: &str) -> bool {
+    let mut state = rec.initial();
+    for &b in text.as_bytes() {
+        if !rec.byte_allowed(state, b) {
+            return false;
+        }
+        state = rec.append(state, b);
+    }
+    rec.is_accepting(state)
+}
+
+fn a() -> ByteClass {
+    ByteClass::new(b"a")
+}
+
+fn many_a() -> Repeat<ByteClass, bool> {
+    Repeat::new(a(), 0, usize::MAX)
+}
+
+#[test]
+fn seq_tries_every_split() {
+    // a greedy match would have the first 'a's go to the Repeat, or start "aab" too late
+    let rec = Seq::new(many_a(), Literal::new(b"aab"));
+    for text in ["aab", "aaab", "aaaaaab"] {
+        assert!(matches(&rec, text), "{text:?}");
+    }
+    for text in ["", "ab", "aa", "aaba", "baab"] {
+        assert!(!matches(&rec, text), "{text:?}");
+    }
+
+    let digits = || Repeat::new(ByteClass::range(b'0', b'9'), 1, 3);
+    let rec = Seq::new(digits(), digits());
+    for text in ["12", "123", "1234", "123456"] {
+        assert!(matches(&rec, text), "{text:?}");
+    }
+    for text in ["1", "1234567"] {
+        assert!(!matches(&rec, text), "{text:?}");
+    }
+}
+
+#[test]
+fn repeat_tries_every_split() {
+    // "aa" is either one or two repetitions of a{1,2}
+    let rec = Repeat::new(Repeat::new(a(), 1, 2), 2, 2);
+    for text in ["aa", "aaa", "aaaa"] {
+        assert!(matches(&rec, text), "{text:?}");
+    }
+    for text in ["", "a", "aaaaa"] {
+        assert!(!matches(&rec, text), "{text:?}");
+    }
+
+    // (a|ab)+b: the last "ab" may be a repetition or the final "b"
+    let rec = Seq::new(
+        Repeat::new(Alt(Literal::new(b"a"), Literal::new(b"ab")), 1, usize::MAX),
+        Literal::new(b"b"),
+    );
+    for text in ["ab", "abb", "aab", "ababb"] {
+        assert!(matches(&rec, text), "{text:?}");
+    }
+    for text in ["b", "a", "abba"] {
+        assert!(!matches(&rec, text), "{text:?}");
+    }
+}
+
+#[test]
+fn eos_only_when_accepting() {
+    let mut rec = StackRecognizer::from(Seq::new(many_a(), Literal::new(b"aab")));
+    for (i, &b) in b"aaab".iter().enumerate() {
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence), "{i}");
+        assert!(rec.try_push_byte(b));
+        rec.collapse();
+    }
+    assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+    assert!(!rec.special_allowed(SpecialToken::BeginningOfSentence));
+    assert!(!rec.try_push_byte(b'a'));
+}
+
+#[cfg(feature = "rx")]
+#[test]
+fn ambiguous_patterns_match_regex() {
+    use regex_automata::meta::Regex;
+
+    fn check<S: Copy>(rec: &impl FunctionalRecognizer<S>, rx: &str, rng: &mut Rng) {
+        let rx = Regex::new(&format!("^(?:{rx})$")).unwrap();
+        for _ in 0..500 {
+            let len = rng.gen_up_to(8);
+            let text: String = (0..len)
+                .map(|_| if rng.gen_bool(0.7) { 'a' } else { 'b' })
+                .collect();
+            assert_eq!(matches(rec, &text), rx.is_match(&text), "{rx:?} {text:?}");
+        }
+    }
+
+    let mut rng = Rng::seeded(5);
+    check(&Seq::new(many_a(), Literal::new(b"aab")), "a*aab", &mut rng);
+    check(
+        &Seq::new(Repeat::new(Literal::new(b"ab"), 0, 2), many_a()),
+        "(ab){0,2}a*",
+        &mut rng,
+    );
+    check(
+        &Repeat::new(Seq::new(many_a(), Literal::new(b"ab")), 1, 3),
+        "(a*ab){1,3}",
+        &mut rng,
+    );
+    check(
+        &Seq::new(
+            Alt(Literal::new(b"a"), Repeat::new(a(), 2, 3)),
+            Repeat::new(
+                Alt(ByteClass::new(b"b"), Literal::new(b"ab")),
+                1,
+                usize::MAX,
+            ),
+        ),
+        "(a|a{2,3})(b|ab)+",
+        &mut rng,
+    );
+}
@@ -0,0 +1,89 @@
+use aici_abi::{rng::Rng, testing::MockTokenizerEnv, TokenId, TokenizerEnv};
+
+const WORDS: &[&str] = &[" hello", "é", "日本", "\r\n", "a\tb"];
+
+fn env() -> MockTokenizerEnv {
+    MockTokenizerEnv::new(WORDS)
+}
+
+fn random_tokens(rng: &mut Rng, from: &[TokenId]) -> Vec<TokenId> {
+    let len = rng.gen_up_to(12);
+    (0..len).map(|_| *rng.choose(from)).collect()
+}
+
+#[test]
+fn offsets_follow_the_tokens() {
+    let env = env();
+    let trie = env.tok_trie();
+    let mut rng = Rng::seeded(1);
+
+    // ASCII bytes and the words: always valid UTF-8
+    let valid: Vec<TokenId> = (0..128)
+        .chain(WORDS.iter().map(|w| trie.token_id(w.as_bytes()).unwrap()))
+        .collect();
+    for _ in 0..500 {
+        let tokens = random_tokens(&mut rng, &valid);
+        let (s, offsets) = trie.decode_str_with_offsets(&tokens);
+        assert_eq!(s.as_bytes(), trie.decode(&tokens));
+        assert_eq!(offsets.len(), tokens.len());
+        let mut pos = 0;
+        for (off, t) in offsets.iter().zip(&tokens) {
+            assert_eq!(*off, pos, "{}", trie.tokens_dbg(&tokens));
+            pos += trie.token_len(*t);
+        }
+        assert_eq!(pos, s.len());
+    }
+
+    // with bytes of split (or invalid) characters
+    let all: Vec<TokenId> = (0..trie.vocab_size() as TokenId)
+        .filter(|t| *t != trie.eos_token())
+        .collect();
+    for _ in 0..500 {
+        let tokens = random_tokens(&mut rng, &all);
+        let (s, offsets) = trie.decode_str_with_offsets(&tokens);
+        assert_eq!(s, trie.decode_str(&tokens));
+        assert_eq!(offsets.len(), tokens.len());
+        for w in offsets.windows(2) {
+            assert!(w[0] <= w[1], "{}", trie.tokens_dbg(&tokens));
+        }
+        assert!(offsets.iter().all(|off| s.is_char_boundary(*off)));
+    }
+}
+
+#[test]
+fn split_characters_get_the_offset_of_the_character() {
+    let env = env();
+    let trie = env.tok_trie();
+    let e_acute = "é".as_bytes();
+    let tokens = [
+        b'x' as TokenId,
+        e_acute[0] as TokenId,
+        e_acute[1] as TokenId,
+    ];
+    let (s, offsets) = trie.decode_str_with_offsets(&tokens);
+    assert_eq!(s, "xé");
+    assert_eq!(offsets, [0, 1, 1]);
+
+    // a lone continuation byte is replaced
+    let (s, offsets) = trie.decode_str_with_offsets(&[0x80, b'y' as TokenId]);
+    assert_eq!(s, "\u{fffd}y");
+    assert_eq!(offsets, [0, 3]);
+}
+
+#[test]
+fn tokens_dbg_is_single_line() {
+    let env = env();
+    let hello = env.tok_trie().token_id(b" hello").unwrap();
+    let special = [("<|line\nbreak|>".to_string(), hello)];
+    let trie = env.tok_trie().clone();
+    let trie = trie.with_special_tokens(special.iter().map(|(n, t)| (n, t)));
+    let tokens: Vec<TokenId> = [&b"\n"[..], b"\r\n", b"a\tb", b"\x1b", b"\x00"]
+        .iter()
+        .map(|b| trie.token_id(b).unwrap())
+        .chain([hello, 0xff, trie.eos_token()])
+        .collect();
+    let dbg = trie.tokens_dbg(&tokens);
+    assert!(!dbg.chars().any(|c| c.is_control()), "{dbg}");
+    assert!(dbg.contains("\\n"), "{dbg}");
+    assert!(dbg.contains("line\\nbreak"), "{dbg}");
+}
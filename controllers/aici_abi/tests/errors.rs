@@ -0,0 +1,89 @@
+use aici_abi::{
+    testing::{MockHost, MockTokenizerEnv},
+    Error, ErrorResult, MidProcessArg, MidProcessResult, TryAiciCtrl,
+};
+
+/// Fails every mid_process(), or panics.
+struct Failing {
+    panic: bool,
+}
+
+impl TryAiciCtrl for Failing {
+    fn try_mid_process(&mut self, _arg: MidProcessArg) -> Result<MidProcessResult, Error> {
+        if self.panic {
+            panic!("bad state");
+        }
+        Err(Error::controller(
+            "grammar rejected byte 0x7d at position 52",
+        ))
+    }
+}
+
+const MID_ARG: &[u8] = br#"{"backtrack": 0, "tokens": [1], "fork_group": []}"#;
+
+/// Run an entry point like the host; the results it passed back.
+fn call(arg: &[u8], f: impl FnOnce()) -> Vec<Vec<u8>> {
+    MockHost::set_process_arg_bytes(arg);
+    f();
+    MockHost::take_process_results()
+}
+
+fn only_error(results: Vec<Vec<u8>>) -> ErrorResult {
+    assert_eq!(results.len(), 1);
+    ErrorResult::from_result_bytes(&results[0]).expect("not an error")
+}
+
+#[test]
+fn malformed_arguments_are_reported() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let mut ctrl = Failing { panic: false };
+    let malformed: [&[u8]; 4] = [b"", b"{", b"[1, 2]", br#"{"prompt": "Hello"}"#];
+    for arg in malformed {
+        let err = only_error(call(arg, || ctrl.aici_init_prompt()));
+        assert_eq!(err.phase, "init_prompt");
+        assert!(err.message.starts_with("JSON error: "), "{}", err.message);
+
+        let err = only_error(call(arg, || ctrl.aici_mid_process()));
+        assert_eq!(err.phase, "mid_process");
+        assert!(err.message.starts_with("JSON error: "), "{}", err.message);
+    }
+}
+
+#[test]
+fn results_are_not_taken_for_errors() {
+    let err = ErrorResult {
+        message: "m".to_string(),
+        phase: "mid_process".to_string(),
+    };
+    let back = ErrorResult::from_result_bytes(&err.to_result_bytes()).unwrap();
+    assert_eq!((back.message, back.phase), (err.message, err.phase));
+    // the fields of ErrorResult alone don't make an error
+    assert!(ErrorResult::from_result_bytes(br#"{"message": "m", "phase": "p"}"#).is_none());
+    let res = serde_json::to_vec(&MidProcessResult::stop()).unwrap();
+    assert!(ErrorResult::from_result_bytes(&res).is_none());
+}
+
+#[test]
+fn each_failure_is_reported_once() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let mut ctrl = Failing { panic: false };
+    let err = only_error(call(MID_ARG, || ctrl.aici_mid_process()));
+    assert_eq!(err.message, "grammar rejected byte 0x7d at position 52");
+
+    // caught by the call
+    ctrl.panic = true;
+    let err = only_error(call(MID_ARG, || ctrl.aici_mid_process()));
+    assert_eq!(err.message, "panic: bad state");
+
+    // reported by the hook (with the location), and not again by the call
+    MockHost::install_panic_hook();
+    let err = only_error(call(MID_ARG, || ctrl.aici_mid_process()));
+    assert!(
+        err.message.starts_with("panic: bad state at "),
+        "{}",
+        err.message
+    );
+    assert!(err.message.contains("tests/errors.rs:"), "{}", err.message);
+}
@@ -0,0 +1,92 @@
+use aici_abi::{
+    testing::{run_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, MidProcessArg, MidProcessResult, TokenId, TokenizerEnv,
+};
+
+const WORDS: &[&str] = &["ab", "abc", "abcd", "abx", "xy"];
+
+fn tok(trie: &TokTrie, s: &str) -> TokenId {
+    trie.token_id(s.as_bytes()).unwrap()
+}
+
+#[test]
+fn strict_prefixes_fit() {
+    let env = MockTokenizerEnv::new(WORDS);
+    let trie = env.tok_trie();
+    for s in ["a", "ab", "abc", "abcd"] {
+        assert!(trie.token_fits_prefix(tok(trie, s), b"abc"), "{s:?}");
+    }
+    for s in ["abx", "b", "xy"] {
+        assert!(!trie.token_fits_prefix(tok(trie, s), b"abc"), "{s:?}");
+    }
+    assert!(!trie.token_fits_prefix(trie.eos_token(), b"abc"));
+
+    let mut set = trie.alloc_token_set();
+    assert!(trie.tokens_fitting_prefix(b"abc", &mut set));
+    let allowed: Vec<&[u8]> = (0..trie.vocab_size() as TokenId)
+        .filter(|t| set.is_allowed(*t))
+        .map(|t| trie.token(t))
+        .collect();
+    assert_eq!(allowed, [&b"a"[..], b"ab", b"abc", b"abcd"]);
+
+    // a single byte is always a strict prefix, unless the first byte doesn't match
+    let mut set = trie.alloc_token_set();
+    assert!(trie.tokens_fitting_prefix(b"az", &mut set));
+    assert_eq!(set.num_set(), 1);
+}
+
+/// Forces "abc", allowing only the given tokens.
+struct ForcedAbc {
+    allowed: Vec<TokenId>,
+    prefixes: Vec<Vec<u8>>,
+}
+
+impl AiciCtrl for ForcedAbc {
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        self.prefixes.push(arg.forced_byte_prefix);
+        if self.prefixes.len() > 1 {
+            return MidProcessResult::stop();
+        }
+        let trie = TokTrie::from_host();
+        let mut set = trie.alloc_token_set();
+        for t in &self.allowed {
+            set.allow_token(*t);
+        }
+        MidProcessResult::sample_with_prefix(set, b"abc".to_vec())
+    }
+}
+
+fn run(allowed: &[&str]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let env = MockTokenizerEnv::new(WORDS);
+    MockHost::install(&env);
+    let trie = env.tok_trie();
+    let mut ctrl = ForcedAbc {
+        allowed: allowed.iter().map(|s| tok(trie, s)).collect(),
+        prefixes: vec![],
+    };
+    let script = vec![
+        Phase::Prompt("x".to_string()),
+        Phase::Generate {
+            prefer: "abx".to_string(),
+            max_tokens: 5,
+        },
+    ];
+    let tr = run_controller_script(&mut ctrl, script);
+    (trie.decode(tr.output()), ctrl.prefixes)
+}
+
+#[test]
+fn strict_prefix_token_is_sampled() {
+    // "abx" is preferred but doesn't fit; "ab" covers the start of the forced bytes
+    let (output, prefixes) = run(&["abx", "ab", "xy"]);
+    assert_eq!(output, b"ab");
+    assert_eq!(prefixes, [vec![], b"abc".to_vec()]);
+}
+
+#[test]
+fn prefix_is_appended_when_no_token_fits() {
+    let (output, prefixes) = run(&["abx", "xy"]);
+    assert_eq!(output, b"abc");
+    assert_eq!(prefixes, [vec![], b"abc".to_vec()]);
+}
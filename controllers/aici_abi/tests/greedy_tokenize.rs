@@ -0,0 +1,120 @@
+use aici_abi::{
+    bytes::TokRxInfo,
+    retokenize,
+    testing::{MockHost, MockTokenizerEnv},
+    tokenize_bytes_greedy,
+    toktree::{TokTrie, TokenizationResult},
+    TokenId, TokenizerEnv,
+};
+
+/// All single bytes except 0x00, and a few words.
+fn trie_without_nul() -> TokTrie {
+    let mut tokens: Vec<Vec<u8>> = (1..=255u8).map(|b| vec![b]).collect();
+    tokens.push(b"the".to_vec());
+    tokens.push(b"\x00x".to_vec());
+    tokens.push(vec![]);
+    let info = TokRxInfo {
+        vocab_size: tokens.len() as u32,
+        tok_eos: tokens.len() as TokenId - 1,
+    };
+    TokTrie::from(&info, &tokens)
+}
+
+#[test]
+fn remainder_starts_at_unknown_byte() {
+    let trie = trie_without_nul();
+    assert_eq!(trie.byte_fallback_token(0x00), None);
+    let a = trie.byte_fallback_token(b'a').unwrap();
+    assert_eq!(trie.token(a), b"a");
+
+    let r = trie.tokenize_bytes_greedy(b"the\x01a");
+    assert!(r.is_complete());
+    assert_eq!(trie.decode(&r.tokens), b"the\x01a");
+    assert_eq!(r.tokens.len(), 3);
+
+    // 0x00 only appears within a longer token
+    let r = trie.tokenize_bytes_greedy(b"the\x00x\x00the");
+    assert_eq!(trie.decode(&r.tokens), b"the\x00x");
+    assert_eq!(r.remainder, b"\x00the");
+    assert!(!r.is_complete());
+
+    assert_eq!(
+        trie.tokenize_bytes_greedy(b""),
+        TokenizationResult::default()
+    );
+}
+
+#[test]
+fn host_tokenizes_greedily() {
+    let env = MockTokenizerEnv::from_trie(trie_without_nul());
+    MockHost::install(&env);
+    let r = tokenize_bytes_greedy(b"\x00");
+    assert!(r.tokens.is_empty());
+    assert_eq!(r.remainder, b"\x00");
+    let r = tokenize_bytes_greedy(b"base64+/=");
+    assert!(r.is_complete());
+    assert_eq!(r.tokens.len(), 9);
+}
+
+#[test]
+fn check_canonical_finds_other_splits() {
+    let env = MockTokenizerEnv::new(&["in", "ing"]);
+    let trie = env.tok_trie();
+    let tok = |s: &str| trie.token_id(s.as_bytes()).unwrap();
+    let (s, i, n, g, ing) = (tok("s"), tok("i"), tok("n"), tok("g"), tok("ing"));
+    let eos = trie.eos_token();
+
+    assert_eq!(trie.check_canonical(&[]), None);
+    assert_eq!(trie.check_canonical(&[s, ing, eos]), None);
+    assert_eq!(trie.check_canonical(&[tok("in"), g]), Some(0));
+    assert_eq!(trie.check_canonical(&[s, tok("in"), g]), Some(1));
+    assert_eq!(trie.check_canonical(&[s, ing, i, n]), Some(2));
+
+    // the host may split it differently still
+    MockHost::install(&env);
+    MockHost::set_tokenization(b"sing", &[s, tok("in"), g]);
+    assert_eq!(retokenize(&[s, ing]), vec![s, tok("in"), g]);
+    assert_eq!(retokenize(&[i, n, g, eos]), vec![ing]);
+}
+
+#[test]
+fn partial_characters_are_tokenized_by_bytes() {
+    let env = MockTokenizerEnv::new(&["ab", "😀"]);
+    let trie = env.tok_trie();
+    let emoji = "😀".as_bytes();
+    let byte_tokens = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| trie.byte_fallback_token(*b).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let tokenize = |bytes: &[u8]| {
+        let mut strs = vec![];
+        let tokens = trie.tokenize_bytes_with(bytes, |s| {
+            strs.push(s.to_string());
+            env.tokenize(s)
+        });
+        assert_eq!(trie.decode(&tokens), bytes);
+        (tokens, strs)
+    };
+    let ab = trie.token_id(b"ab").unwrap();
+
+    // forced bytes ending in the middle of the emoji
+    let (tokens, strs) = tokenize(&[b"ab", &emoji[..2]].concat());
+    assert_eq!(strs, ["ab"]);
+    assert_eq!(tokens, [vec![ab], byte_tokens(&emoji[..2])].concat());
+
+    // the split character is followed by more text
+    let (tokens, strs) = tokenize(&[&emoji[..2], b"ab"].concat());
+    assert_eq!(strs, ["ab"]);
+    assert_eq!(tokens, [byte_tokens(&emoji[..2]), vec![ab]].concat());
+
+    // only the bytes of the split character
+    let (tokens, strs) = tokenize(&emoji[2..]);
+    assert!(strs.is_empty());
+    assert_eq!(tokens, byte_tokens(&emoji[2..]));
+
+    // once the rest of it is there, the whole character is one token
+    let (tokens, _) = tokenize(emoji);
+    assert_eq!(tokens, [trie.token_id(emoji).unwrap()]);
+}
@@ -0,0 +1,145 @@
+#![cfg(feature = "tokenizers")]
+
+use aici_abi::{hftokenizers::token_bytes, toktree::TokTrie};
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+
+fn added_token(id: usize, content: &str) -> Value {
+    json!({
+        "id": id,
+        "content": content,
+        "single_word": false,
+        "lstrip": false,
+        "rstrip": false,
+        "normalized": false,
+        "special": true,
+    })
+}
+
+fn tokenizer(model: Value, added: &[(usize, &str)], decoder: Value) -> Tokenizer {
+    let json = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added.iter().map(|(id, t)| added_token(*id, t)).collect::<Vec<_>>(),
+        "normalizer": null,
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": decoder,
+        "model": model,
+    });
+    Tokenizer::from_bytes(json.to_string()).unwrap()
+}
+
+fn bpe(vocab: &[&str], byte_fallback: bool) -> Value {
+    let vocab: serde_json::Map<_, _> = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, t)| (t.to_string(), json!(id)))
+        .collect();
+    json!({
+        "type": "BPE",
+        "dropout": null,
+        "unk_token": null,
+        "continuing_subword_prefix": null,
+        "end_of_word_suffix": null,
+        "fuse_unk": byte_fallback,
+        "byte_fallback": byte_fallback,
+        "vocab": vocab,
+        "merges": [],
+    })
+}
+
+// like Llama's
+fn byte_fallback_tokenizer() -> Tokenizer {
+    let vocab = [
+        "<unk>",
+        "<s>",
+        "</s>",
+        "<0x0A>",
+        "<0xE2>",
+        "\u{2581}",
+        "\u{2581}hello",
+        "lo",
+    ];
+    let decoder = json!({
+        "type": "Sequence",
+        "decoders": [
+            {"type": "Replace", "pattern": {"String": "\u{2581}"}, "content": " "},
+            {"type": "ByteFallback"},
+            {"type": "Fuse"},
+            {"type": "Strip", "content": " ", "start": 1, "stop": 0},
+        ],
+    });
+    let added = [(0, "<unk>"), (1, "<s>"), (2, "</s>")];
+    tokenizer(bpe(&vocab, true), &added, decoder)
+}
+
+// like GPT-2's; "Ġ" is the space, "Ċ" the newline, "âĢĶ" the bytes of an em dash
+fn byte_level_tokenizer() -> Tokenizer {
+    let vocab = ["a", "Ġb", "Ċ", "\u{e2}\u{122}\u{136}", "<|endoftext|>"];
+    let decoder = json!({
+        "type": "ByteLevel",
+        "add_prefix_space": true,
+        "trim_offsets": true,
+        "use_regex": true,
+    });
+    tokenizer(bpe(&vocab, false), &[(4, "<|endoftext|>")], decoder)
+}
+
+fn assert_round_trip(trie: &TokTrie) {
+    let bytes = trie.serialize();
+    let trie2 = TokTrie::deserialize(&bytes).unwrap();
+    assert_eq!(trie2.info(), trie.info());
+    assert_eq!(trie2.special_tokens(), trie.special_tokens());
+    for tok in 0..trie.vocab_size() as u32 {
+        assert_eq!(trie2.token(tok), trie.token(tok));
+    }
+    assert_eq!(trie2.serialize(), bytes);
+
+    // truncated, or with another version
+    assert!(TokTrie::deserialize(&bytes[..bytes.len() - 1]).is_err());
+    let mut other_version = bytes.clone();
+    other_version[4] ^= 0xff;
+    assert!(TokTrie::deserialize(&other_version).is_err());
+}
+
+#[test]
+fn byte_fallback_tokens() {
+    let tok = byte_fallback_tokenizer();
+    let bytes = token_bytes(&tok).unwrap();
+    assert_eq!(bytes.scheme, "byte_fallback");
+    assert_eq!(bytes.eos_token, 2);
+
+    let trie = TokTrie::from_tokenizer(&tok).unwrap();
+    assert_eq!(trie.eos_token(), 2);
+    assert_eq!(trie.token(3), b"\n");
+    assert_eq!(trie.token(4), &[0xe2]);
+    assert_eq!(trie.token(6), b" hello");
+    assert_eq!(trie.token(7), b"lo");
+    // special tokens have no bytes
+    assert_eq!(trie.token(1), b"");
+    assert_eq!(trie.special_token_by_name("<s>"), Some(1));
+    assert_eq!(trie.special_token_by_name("<unk>"), Some(0));
+    assert_eq!(trie.bos_token(), Some((1, "<s>")));
+    assert!(trie.is_special_token(1));
+    assert!(!trie.is_special_token(6));
+    assert_round_trip(&trie);
+}
+
+#[test]
+fn byte_level_tokens() {
+    let tok = byte_level_tokenizer();
+    let bytes = token_bytes(&tok).unwrap();
+    assert_eq!(bytes.scheme, "byte_level");
+    assert_eq!(bytes.eos_token, 4);
+
+    let trie = TokTrie::from_tokenizer(&tok).unwrap();
+    assert_eq!(trie.token(0), b"a");
+    assert_eq!(trie.token(1), b" b");
+    assert_eq!(trie.token(2), b"\n");
+    assert_eq!(trie.token(3), "\u{2014}".as_bytes());
+    assert_eq!(trie.token(4), b"");
+    assert_eq!(trie.bos_token(), None);
+    assert_round_trip(&trie);
+}
@@ -0,0 +1,99 @@
+use aici_abi::{
+    recording::{Record, RecordKind},
+    svob::SimpleVob,
+    testing::{record_controller_script, run_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, MidProcessArg, MidProcessResult,
+};
+use serde_json::json;
+
+/// Only allows tokens starting with a letter from `letters`, or EOS.
+struct Letters {
+    trie: TokTrie,
+    letters: &'static [u8],
+}
+
+impl AiciCtrl for Letters {
+    fn mid_process(&mut self, _arg: MidProcessArg) -> MidProcessResult {
+        let mut set = self.trie.alloc_token_set();
+        for tok in 0..self.trie.vocab_size() as u32 {
+            if let Some(b) = self.trie.token(tok).first() {
+                set.set(tok, self.letters.contains(b));
+            }
+        }
+        set.allow_token(self.trie.eos_token());
+        MidProcessResult::sample(set)
+    }
+}
+
+fn allowed(set: &SimpleVob) -> Vec<u32> {
+    (0..set.len() as u32)
+        .filter(|t| set.is_allowed(*t))
+        .collect()
+}
+
+#[test]
+fn vobs_round_trip() {
+    let mut set = SimpleVob::alloc(100);
+    for tok in [0, 31, 32, 77, 100] {
+        set.allow_token(tok);
+    }
+    let json = serde_json::to_string(&set).unwrap();
+    let set2: SimpleVob = serde_json::from_str(&json).unwrap();
+    assert_eq!(set2.len(), set.len());
+    assert_eq!(allowed(&set2), allowed(&set));
+
+    let res = MidProcessResult::sample(set.clone());
+    let res2: MidProcessResult =
+        serde_json::from_slice(&serde_json::to_vec(&res).unwrap()).unwrap();
+    let mask = res2.branches[0].sample_mask.as_ref().unwrap();
+    assert_eq!(allowed(mask), allowed(&set));
+
+    // another writer may pass the vocabulary size, with garbage in the padding
+    let mut words = SimpleVob::alloc(63);
+    words.set_all(true);
+    let value = json!({ "len": 40, "data": serde_json::to_value(&words).unwrap()["data"] });
+    let set3: SimpleVob = serde_json::from_value(value).unwrap();
+    assert_eq!(allowed(&set3), (0..40).collect::<Vec<_>>());
+
+    // the data has to match the length
+    for len in [0, 32, 65, 1000] {
+        let value = json!({ "len": len, "data": serde_json::to_value(&words).unwrap()["data"] });
+        assert!(serde_json::from_value::<SimpleVob>(value).is_err(), "{len}");
+    }
+    let value = json!({ "len": 32, "data": "not base64!" });
+    assert!(serde_json::from_value::<SimpleVob>(value).is_err());
+}
+
+#[test]
+fn inline_masks_match_the_bias_buffer() {
+    let env = MockTokenizerEnv::default();
+    let new_ctrl = || Letters {
+        trie: TokTrie::from_host(),
+        letters: b" lo",
+    };
+    let script = || {
+        vec![
+            Phase::Prompt("Hello".to_string()),
+            Phase::Generate {
+                prefer: " lol, no".to_string(),
+                max_tokens: 10,
+            },
+        ]
+    };
+
+    // called directly, without going through the host
+    MockHost::install(&env);
+    let mut ctrl = new_ctrl();
+    let expected = run_controller_script(&mut ctrl, script());
+    assert!(expected.output_text(&env).starts_with(" lol"));
+
+    // through the entry point of a JSON-only host, which gets the masks in the results
+    MockHost::install(&env);
+    MockHost::set_config("inline_bias", 1);
+    let (tr, log) = record_controller_script(new_ctrl, script());
+    let records = Record::parse_log(&log).unwrap();
+    assert!(records.iter().all(|r| r.kind != RecordKind::LogitBias));
+    assert_eq!(tr.sampled, expected.sampled);
+    assert_eq!(tr.output_text(&env), expected.output_text(&env));
+}
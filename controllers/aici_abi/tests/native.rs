@@ -0,0 +1,103 @@
+use aici_abi::{
+    native::{mid_process_into, BiasBuffer, RegexCtrl, StopSequenceCtrl, BIAS_ALLOW},
+    testing::{run_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, InitPromptArg, MidProcessArg, SeqId, TokenId, TokenizerEnv,
+};
+use std::sync::Arc;
+
+fn generate(ctrl: &mut impl AiciCtrl, env: &MockTokenizerEnv, prefer: &str) -> (String, bool) {
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: prefer.to_string(),
+            max_tokens: 20,
+        },
+    ];
+    let tr = run_controller_script(ctrl, script);
+    (tr.output_text(env), tr.eos || tr.stopped)
+}
+
+fn shared_trie(env: &MockTokenizerEnv) -> Arc<TokTrie> {
+    Arc::new(env.tok_trie().clone())
+}
+
+#[test]
+fn regex_ctrl_constrains_output() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let trie = shared_trie(&env);
+
+    let mut ctrl = RegexCtrl::new(trie.clone(), "value [0-9]+").unwrap();
+    assert_eq!(
+        generate(&mut ctrl, &env, "value 42"),
+        ("value 42".to_string(), true)
+    );
+
+    // text outside of the regex is not followed
+    let mut ctrl = RegexCtrl::new(trie.clone(), "(true|false)").unwrap();
+    let (text, done) = generate(&mut ctrl, &env, "maybe");
+    assert!(text == "true" || text == "false", "{text:?}");
+    assert!(done);
+
+    assert!(RegexCtrl::new(trie, "(unclosed").is_err());
+}
+
+#[test]
+fn stop_sequence_ctrl_stops_after_match() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let trie = shared_trie(&env);
+
+    let mut ctrl = StopSequenceCtrl::new(trie.clone(), &["\n\n", "END"]);
+    let (text, stopped) = generate(&mut ctrl, &env, "the value\n\nmore text");
+    assert_eq!(text, "the value\n\n");
+    assert!(stopped);
+
+    // the stop sequence can span tokens
+    let mut ctrl = StopSequenceCtrl::new(trie, &["ue E"]);
+    let (text, _) = generate(&mut ctrl, &env, "the value END and more");
+    assert_eq!(text, "the value E");
+}
+
+#[test]
+fn bias_rows_follow_masks() {
+    let env = MockTokenizerEnv::default();
+    let trie = shared_trie(&env);
+    let mut ctrl = RegexCtrl::new(trie.clone(), "[0-9]+").unwrap();
+    ctrl.init_prompt(InitPromptArg {
+        prompt: env.tokenize("Hello"),
+    });
+
+    // a few padding entries past the vocabulary
+    let num_elts = trie.vocab_size() + 40;
+    let mut biases = BiasBuffer::new(num_elts);
+    for (tokens, byte_offset) in [(vec![], 5), (env.tokenize("42"), 7)] {
+        let arg = MidProcessArg {
+            backtrack: 0,
+            tokens,
+            fork_group: vec![SeqId(0)],
+            fork_arg: vec![],
+            prev_timed_out: false,
+            forced_byte_prefix: vec![],
+            byte_offset,
+            context_truncation: None,
+            top_logprobs: vec![],
+        };
+        let res = mid_process_into(&mut ctrl, arg, &mut biases);
+        assert_eq!(res.branches.len(), 1);
+        let idx = res.branches[0].sample_mask.unwrap();
+        let row = biases.mask(idx);
+        assert_eq!(row.len(), num_elts);
+        for (tok, bias) in row.iter().enumerate() {
+            let tok = tok as TokenId;
+            let digits = (tok as usize) < trie.vocab_size()
+                && (tok == trie.eos_token() && idx > 0
+                    || trie.token_len(tok) > 0
+                        && trie.token(tok).iter().all(|b| b.is_ascii_digit()));
+            assert_eq!(*bias == BIAS_ALLOW, digits, "token {tok}");
+        }
+    }
+    assert_eq!(biases.num_masks(), 2);
+    assert_eq!(biases.data().len(), 2 * num_elts);
+}
@@ -0,0 +1,57 @@
+use aici_abi::{
+    replay::replay_session,
+    testing::{record_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, MidProcessArg, MidProcessResult, PostSampleArg, PostSampleResult, TokenizerEnv,
+};
+
+/// Allows all tokens, but rejects the sampled ones containing a quote.
+struct NoQuotes {
+    trie: TokTrie,
+}
+
+impl AiciCtrl for NoQuotes {
+    fn mid_process(&mut self, _arg: MidProcessArg) -> MidProcessResult {
+        let mut set = self.trie.alloc_token_set();
+        set.set_all(true);
+        MidProcessResult::sample(set).with_post_sample()
+    }
+
+    fn post_sample(&mut self, arg: PostSampleArg) -> PostSampleResult {
+        if self.trie.token(arg.token).contains(&b'"') {
+            PostSampleResult::Reject { resample: true }
+        } else {
+            PostSampleResult::Accept
+        }
+    }
+}
+
+fn no_quotes() -> NoQuotes {
+    NoQuotes {
+        trie: TokTrie::from_host(),
+    }
+}
+
+#[test]
+fn rejected_tokens_are_resampled() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: "{\"name\": \"the\"}".to_string(),
+            max_tokens: 10,
+        },
+    ];
+    let (tr, log) = record_controller_script(no_quotes, script);
+
+    let trie = env.tok_trie();
+    assert!(!tr.rejected.is_empty());
+    assert!(tr.rejected.iter().all(|t| trie.token(*t).contains(&b'"')));
+    assert_eq!(tr.output().len(), 10);
+    assert!(!tr.output_text(&env).contains('"'));
+    // the preferred text is followed up to the first quote
+    assert!(tr.output_text(&env).starts_with('{'));
+
+    replay_session(&log, no_quotes).unwrap();
+}
@@ -0,0 +1,51 @@
+use aici_abi::{
+    rx::{RecRx, RxStackRecognizer},
+    testing::MockTokenizerEnv,
+    toktree::{Recognizer, SpecialToken, TokTrie},
+    TokenizerEnv,
+};
+
+fn digits(trie: &TokTrie, tokens: &[u32], prefix_eos: bool) -> RxStackRecognizer {
+    let mut rec = RecRx::from_rx("[0-9]+").to_stack_recognizer();
+    if prefix_eos {
+        rec = rec.allow_prefix_eos();
+    }
+    trie.append_tokens(&mut rec, tokens);
+    rec
+}
+
+fn eos_allowed(trie: &TokTrie, rec: &mut RxStackRecognizer) -> bool {
+    let mut set = trie.alloc_token_set();
+    trie.compute_bias(rec, &mut set);
+    assert_eq!(
+        set.is_allowed(trie.eos_token()),
+        trie.token_allowed(rec, trie.eos_token())
+    );
+    set.is_allowed(trie.eos_token())
+}
+
+#[test]
+fn eos_after_match_within_token() {
+    let env = MockTokenizerEnv::new(&["42 ", "42"]);
+    let trie = env.tok_trie();
+    let tokens = env.tokenize("42 ");
+    assert_eq!(tokens.len(), 1);
+
+    // "42" matched, but the space that follows it in the token doesn't
+    let mut rec = digits(trie, &tokens, true);
+    assert!(rec.was_accepting_in_token());
+    assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+    assert!(eos_allowed(trie, &mut rec));
+
+    // only when asked for
+    let mut rec = digits(trie, &tokens, false);
+    assert!(rec.was_accepting_in_token());
+    assert!(!eos_allowed(trie, &mut rec));
+
+    // a matching token allows EOS either way; the next token resets the flag
+    let mut rec = digits(trie, &env.tokenize("42"), false);
+    assert!(eos_allowed(trie, &mut rec));
+    let mut rec = digits(trie, &env.tokenize(" 42 "), true);
+    assert!(!rec.was_accepting_in_token());
+    assert!(!eos_allowed(trie, &mut rec));
+}
@@ -0,0 +1,47 @@
+use aici_abi::{
+    rng::Rng,
+    testing::{MockHost, MockTokenizerEnv},
+};
+
+fn first(rng: &mut Rng, n: usize) -> Vec<u64> {
+    (0..n).map(|_| rng.gen_u64()).collect()
+}
+
+#[test]
+fn sequences_are_fixed() {
+    // the same on every host and build, so recorded decisions stay reproducible
+    assert_eq!(
+        first(&mut Rng::seeded(0), 3),
+        [
+            7377219508542733812,
+            3375351177031125519,
+            1405982755453415387
+        ]
+    );
+    assert_eq!(first(&mut Rng::new(0), 3), first(&mut Rng::seeded(0), 3));
+    assert_eq!(
+        first(&mut Rng::new(12345), 3),
+        first(&mut Rng::seeded(12345), 3)
+    );
+
+    let parent = Rng::seeded(7);
+    assert_eq!(first(&mut parent.fork(1), 3), first(&mut parent.fork(1), 3));
+    assert_ne!(first(&mut parent.fork(1), 3), first(&mut parent.fork(2), 3));
+    assert_ne!(first(&mut parent.fork(0), 3), first(&mut Rng::seeded(7), 3));
+}
+
+#[test]
+fn default_is_seeded_from_host() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    MockHost::set_rand_seed(99);
+    assert_eq!(
+        first(&mut Rng::default(), 5),
+        first(&mut Rng::seeded(99), 5)
+    );
+    MockHost::set_rand_seed(100);
+    assert_ne!(
+        first(&mut Rng::default(), 5),
+        first(&mut Rng::seeded(99), 5)
+    );
+}
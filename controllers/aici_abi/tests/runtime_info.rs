@@ -0,0 +1,227 @@
+use aici_abi::{
+    recording::{self, Record, RecordKind, RecordPhase},
+    replay::replay_session,
+    require_capabilities, runtime_info,
+    testing::{record_controller_script, run_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, Capability, Error, ErrorResult, InitPromptArg, InitPromptResult, MidProcessArg,
+    MidProcessResult, RuntimeInfo, TokenizerEnv, TryAiciCtrl, ABI_MAJOR, ABI_MINOR,
+};
+
+/// Asks for top logprobs when the host has them, and otherwise does without.
+struct TopIfAvailable {
+    trie: TokTrie,
+    num_top: usize,
+}
+
+impl AiciCtrl for TopIfAvailable {
+    fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
+        if runtime_info().supports(Capability::TopLogprobs) {
+            InitPromptResult::default().with_top_logprobs(3)
+        } else {
+            InitPromptResult::default()
+        }
+    }
+
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        self.num_top += arg.top_logprobs.len();
+        let mut set = self.trie.alloc_token_set();
+        set.set_all(true);
+        MidProcessResult::sample(set)
+    }
+}
+
+/// Fails in init_prompt() unless the host has `caps`.
+struct Requiring {
+    caps: Vec<Capability>,
+}
+
+impl TryAiciCtrl for Requiring {
+    fn try_init_prompt(&mut self, _arg: InitPromptArg) -> Result<InitPromptResult, Error> {
+        require_capabilities(&self.caps)?;
+        Ok(InitPromptResult::default())
+    }
+
+    fn try_mid_process(&mut self, _arg: MidProcessArg) -> Result<MidProcessResult, Error> {
+        Ok(MidProcessResult::stop())
+    }
+}
+
+fn script() -> Vec<Phase> {
+    vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: " the value".to_string(),
+            max_tokens: 5,
+        },
+    ]
+}
+
+fn run_top_if_available() -> (usize, String) {
+    let new_ctrl = || TopIfAvailable {
+        trie: TokTrie::from_host(),
+        num_top: 0,
+    };
+    let (_, log) = record_controller_script(new_ctrl, script());
+    replay_session(&log, new_ctrl).unwrap();
+
+    let mut ctrl = new_ctrl();
+    let tr = run_controller_script(&mut ctrl, script());
+    (ctrl.num_top, tr.output_text(&MockTokenizerEnv::default()))
+}
+
+/// Run init_prompt() through the entry point, like the host; the error, if any.
+fn init_error(ctrl: &mut impl TryAiciCtrl, arg: &[u8]) -> Option<ErrorResult> {
+    MockHost::set_process_arg_bytes(arg);
+    ctrl.aici_init_prompt();
+    let res = MockHost::take_process_results();
+    assert_eq!(res.len(), 1);
+    ErrorResult::from_result_bytes(&res[0])
+}
+
+fn prompt_arg() -> Vec<u8> {
+    serde_json::to_vec(&InitPromptArg { prompt: vec![1] }).unwrap()
+}
+
+#[test]
+fn old_host_new_controller() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    // a host without the header, announcing deny lists in get_config()
+    MockHost::set_runtime_info_bytes(&[]);
+    MockHost::set_config("deny_list", 1);
+
+    let info = runtime_info();
+    assert_eq!((info.abi_major, info.abi_minor), (ABI_MAJOR, 0));
+    assert!(info.supports(Capability::DenyList));
+    assert!(!info.supports(Capability::Fork));
+    assert!(!info.supports(Capability::TopLogprobs));
+    assert_eq!(info.vocab_size, env.tok_trie().vocab_size() as u32);
+    assert_eq!(info.tokenizer, "");
+    assert!(!info.has_minor(ABI_MINOR));
+
+    // the controller does without top logprobs
+    let (num_top, output) = run_top_if_available();
+    assert_eq!(num_top, 0);
+    assert_eq!(output, " the value");
+
+    // or reports what's missing, before generating anything
+    let mut ctrl = Requiring {
+        caps: vec![
+            Capability::DenyList,
+            Capability::BiasCache,
+            Capability::PostSample,
+        ],
+    };
+    let err = init_error(&mut ctrl, &prompt_arg()).unwrap();
+    assert_eq!(err.phase, "init_prompt");
+    assert!(
+        err.message
+            .contains("bias_cache, post_sample (host ABI 1.0"),
+        "{}",
+        err.message
+    );
+    ctrl.caps = vec![Capability::DenyList];
+    assert!(init_error(&mut ctrl, &prompt_arg()).is_none());
+}
+
+#[test]
+fn new_host_old_controller() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    // from a newer host: a capability, and a field this version doesn't know
+    let mut header = serde_json::to_value(RuntimeInfo::new(
+        Capability::ALL,
+        env.tok_trie().vocab_size() as u32,
+        "byte_level",
+    ))
+    .unwrap();
+    header["abi_minor"] = (ABI_MINOR + 1).into();
+    header["host_calls"] = (header["host_calls"].as_u64().unwrap() | 1 << 40).into();
+    header["future_field"] = serde_json::json!({ "x": 1 });
+    MockHost::set_runtime_info_bytes(&serde_json::to_vec(&header).unwrap());
+
+    let info = require_capabilities(Capability::ALL).unwrap();
+    assert!(info.has_minor(ABI_MINOR));
+    assert_eq!(info.tokenizer, "byte_level");
+
+    let (num_top, output) = run_top_if_available();
+    assert!(num_top > 0);
+    assert_eq!(output, " the value");
+
+    // unknown fields in the arguments are ignored too
+    let mut ctrl = Requiring { caps: vec![] };
+    let mut arg = serde_json::to_value(InitPromptArg { prompt: vec![1] }).unwrap();
+    arg["future_field"] = 42.into();
+    assert!(init_error(&mut ctrl, &serde_json::to_vec(&arg).unwrap()).is_none());
+}
+
+#[test]
+fn other_major_version_is_reported() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let info = RuntimeInfo {
+        abi_major: ABI_MAJOR + 1,
+        ..RuntimeInfo::new(Capability::ALL, 300, "")
+    };
+    MockHost::set_runtime_info(&info);
+    assert!(!info.has_minor(0));
+    let err = require_capabilities(&[]).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("unsupported by the host: ABI version"),
+        "{err}"
+    );
+
+    // a broken header is like none at all
+    MockHost::set_runtime_info_bytes(b"{");
+    assert_eq!(runtime_info().abi_minor, 0);
+}
+
+#[test]
+fn header_asks_for_the_recording() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    recording::start_recording_if_requested();
+    assert!(!recording::is_recording());
+
+    let info = RuntimeInfo {
+        record: true,
+        ..RuntimeInfo::new(Capability::ALL, env.tok_trie().vocab_size() as u32, "")
+    };
+    MockHost::set_runtime_info(&info);
+    recording::start_recording_if_requested();
+    let new_ctrl = || TopIfAvailable {
+        trie: TokTrie::from_host(),
+        num_top: 0,
+    };
+    let mut ctrl = new_ctrl();
+    run_controller_script(&mut ctrl, script());
+    let log = recording::take_recording();
+    // the host got the log as it grew, and not through the variables
+    assert_eq!(MockHost::take_host_recording(), log);
+    assert!(MockHost::take_storage_cmds().is_empty());
+    assert!(replay_session(&log, new_ctrl).unwrap() > 1);
+}
+
+#[test]
+fn short_records_are_errors() {
+    let env = MockTokenizerEnv::default();
+    let mut log = vec![];
+    let mut record = |kind, data| {
+        Record {
+            kind,
+            phase: RecordPhase::Setup,
+            step: 0,
+            data,
+        }
+        .write_to(&mut log)
+    };
+    record(RecordKind::TokTrie, env.tok_trie().serialize());
+    record(RecordKind::RandSeed, vec![1, 2, 3]);
+    let err = replay_session(&log, || Requiring { caps: vec![] }).unwrap_err();
+    assert!(
+        err.to_string().contains("RandSeed record of 3 bytes"),
+        "{err}"
+    );
+}
@@ -0,0 +1,59 @@
+use aici_abi::{
+    recognizer::{AiciRecognizer, FunctionalRecognizer, StackRecognizer},
+    testing::{run_controller_script, MockHost, MockTokenizerEnv, Phase, SpliceAt},
+    toktree::SpecialToken,
+    TokenizerEnv,
+};
+
+/// Allows any bytes; the state is a hash of the bytes so far.
+#[derive(Clone)]
+struct BytesHash;
+
+impl FunctionalRecognizer<u64> for BytesHash {
+    fn initial(&self) -> u64 {
+        0
+    }
+
+    fn append(&self, state: u64, byte: u8) -> u64 {
+        state.wrapping_mul(31).wrapping_add(byte as u64 + 1)
+    }
+
+    fn byte_allowed(&self, _state: u64, _byte: u8) -> bool {
+        true
+    }
+
+    fn special_allowed(&self, _state: u64, _tok: SpecialToken) -> bool {
+        false
+    }
+}
+
+fn bytes_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |h, b| BytesHash.append(h, *b))
+}
+
+#[test]
+fn recognizer_follows_splices() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let ff_tokens = env.tokenize(" the end");
+    // after sampling 3 tokens, the last 2 are replaced
+    let rec = AiciRecognizer::from_recognizer(StackRecognizer::from(BytesHash));
+    let mut ctrl = SpliceAt::new(rec, 3, 2, ff_tokens.clone());
+    let script = vec![
+        Phase::Prompt("Hi".to_string()),
+        Phase::Generate {
+            prefer: "Hello world, this is a tweet".to_string(),
+            max_tokens: 8,
+        },
+    ];
+    let tr = run_controller_script(&mut ctrl, script);
+    let output = tr.output();
+    assert_eq!(tr.sampled.len(), 8);
+    assert_eq!(output.len(), 8 - 2 + ff_tokens.len());
+    assert_eq!(output[1..1 + ff_tokens.len()], ff_tokens);
+
+    // the last sampled token is not passed to mid_process() anymore
+    let seen = &output[..output.len() - 1];
+    assert_eq!(ctrl.ctrl.tokens(), seen);
+    assert_eq!(ctrl.ctrl.rec.state(), bytes_hash(&env.decode_tokens(seen)));
+}
@@ -0,0 +1,291 @@
+use aici_abi::{
+    testing::{MockHost, MockTokenizerEnv},
+    CasError, QuotaExceeded, StorageCmd, StorageOp, StorageResp, Transaction, VariableStorage,
+    GLOBAL_PREFIX,
+};
+use serde_json::json;
+
+fn get_count(txn: &mut Transaction) -> usize {
+    txn.get("count")
+        .map_or(0, |v| String::from_utf8(v).unwrap().parse().unwrap())
+}
+
+// A fork done with its work: stores its payload, and counts itself.
+fn worker(vars: &VariableStorage, name: &str) {
+    vars.transaction(|txn| {
+        let count = get_count(txn);
+        txn.set(&format!("payload_{name}"), name.as_bytes().to_vec());
+        txn.set("count", (count + 1).to_string().into_bytes());
+    })
+    .unwrap();
+}
+
+fn num_payloads(mut get: impl FnMut(&str) -> Option<Vec<u8>>) -> usize {
+    ["a", "b"]
+        .iter()
+        .filter(|w| get(&format!("payload_{w}")).is_some())
+        .count()
+}
+
+#[test]
+fn reducer_never_sees_torn_snapshot() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let vars = VariableStorage::new();
+    worker(&vars, "a");
+
+    // worker "b" finishes while the reducer is reading; with get() the reducer sees
+    // the count from before, and the payloads from after
+    let count = vars.get("count").unwrap();
+    worker(&vars, "b");
+    assert_eq!(count, b"1");
+    assert_eq!(num_payloads(|name| vars.get(name)), 2);
+
+    // the transaction is retried instead
+    MockHost::install(&env);
+    worker(&vars, "a");
+    let mut attempts = 0;
+    let (count, payloads) = vars
+        .transaction(|txn| {
+            attempts += 1;
+            let count = get_count(txn);
+            if attempts == 1 {
+                worker(&vars, "b");
+            }
+            (count, num_payloads(|name| txn.get(name)))
+        })
+        .unwrap();
+    assert_eq!(attempts, 2);
+    assert_eq!((count, payloads), (2, 2));
+}
+
+#[test]
+fn racing_workers_both_count() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let vars = VariableStorage::new();
+    let mut attempts = 0;
+    vars.transaction(|txn| {
+        attempts += 1;
+        let count = get_count(txn);
+        if attempts == 1 {
+            worker(&vars, "b");
+        }
+        txn.set("payload_a", b"a".to_vec());
+        txn.set("count", (count + 1).to_string().into_bytes());
+    })
+    .unwrap();
+    assert_eq!(attempts, 2);
+
+    let values = vars
+        .read_many(&["count", "payload_a", "payload_b", "payload_c"])
+        .unwrap();
+    let expected = [
+        Some(b"2".to_vec()),
+        Some(b"a".to_vec()),
+        Some(b"b".to_vec()),
+        None,
+    ];
+    assert_eq!(values, expected);
+
+    // the failed attempt required the sequence number of its read
+    let require_seqs: Vec<_> = MockHost::take_storage_cmds()
+        .into_iter()
+        .filter_map(|cmd| match cmd {
+            StorageCmd::WriteBatch { require_seq, .. } => require_seq,
+            _ => None,
+        })
+        .collect();
+    // "b" (seq 0), the conflict (seq 0), the retry (seq 1)
+    assert_eq!(require_seqs, vec![0, 0, 1]);
+}
+
+#[test]
+fn transaction_reads_its_own_writes() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let vars = VariableStorage::new();
+    vars.set("x", b"1".to_vec()).unwrap();
+    let r = vars
+        .transaction(|txn| {
+            txn.set("x", b"2".to_vec());
+            txn.get("x")
+        })
+        .unwrap();
+    assert_eq!(r, Some(b"2".to_vec()));
+    assert_eq!(vars.get_with_version("x"), Some((2, b"2".to_vec())));
+}
+
+fn parse_count(value: &[u8]) -> usize {
+    match value {
+        [] => 0,
+        _ => std::str::from_utf8(value).unwrap().parse().unwrap(),
+    }
+}
+
+#[test]
+fn interleaved_cas_loops_lose_no_updates() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    // two sequences sharing the storage
+    let seqs = [VariableStorage::new(), VariableStorage::new()];
+    let mut conflicts = 0;
+    for _ in 0..10 {
+        // both read the counter before either writes it
+        let reads = seqs
+            .each_ref()
+            .map(|vars| vars.get_with_version("n").unwrap_or((0, vec![])));
+        for (vars, (mut version, mut value)) in seqs.iter().zip(reads) {
+            loop {
+                let next = (parse_count(&value) + 1).to_string().into_bytes();
+                match vars.cas("n", version, next) {
+                    Ok(_) => break,
+                    Err(CasError::Conflict {
+                        version: v,
+                        value: val,
+                    }) => {
+                        conflicts += 1;
+                        (version, value) = (v, val);
+                    }
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+        }
+    }
+    assert_eq!(seqs[0].get_with_version("n"), Some((20, b"20".to_vec())));
+    // the second sequence retried once per round
+    assert_eq!(conflicts, 10);
+}
+
+#[test]
+fn writes_over_quota_are_rejected() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    // names count too
+    MockHost::set_storage_quota(10);
+    let vars = VariableStorage::new();
+    let quota = QuotaExceeded { quota: 10 };
+
+    assert_eq!(vars.set("x", b"12345".to_vec()), Ok(1));
+    assert_eq!(vars.append("x", b"12345".to_vec()), Err(quota));
+    assert_eq!(vars.set("y", b"12345".to_vec()), Err(quota));
+    assert_eq!(vars.get_with_version("x"), Some((1, b"12345".to_vec())));
+    assert_eq!(vars.get("y"), None);
+
+    assert_eq!(
+        vars.cas("x", 1, b"1234567890".to_vec()),
+        Err(CasError::QuotaExceeded(quota))
+    );
+    // a conflict is reported before the quota
+    assert_eq!(
+        vars.cas("x", 7, b"123456789".to_vec()),
+        Err(CasError::Conflict {
+            version: 1,
+            value: b"12345".to_vec()
+        })
+    );
+    assert_eq!(vars.cas("x", 1, b"1".to_vec()), Ok(2));
+
+    // a transaction is all or nothing
+    let r = vars.transaction(|txn| {
+        txn.set("x", b"".to_vec());
+        txn.set("y", b"123456789".to_vec());
+    });
+    assert_eq!(r, Err(quota.into()));
+    assert_eq!(
+        vars.read_many(&["x", "y"]).unwrap(),
+        [Some(b"1".to_vec()), None]
+    );
+
+    // shrinking frees the space
+    assert_eq!(vars.set("x", b"".to_vec()), Ok(3));
+    assert_eq!(vars.append("y", b"1234".to_vec()), Ok(1));
+}
+
+#[test]
+fn global_variables_are_shared_by_requests() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    MockHost::set_storage_quota(20);
+    let vars = VariableStorage::new();
+    let counter = format!("{GLOBAL_PREFIX}counter");
+
+    vars.set("x", b"request 0".to_vec()).unwrap();
+    vars.set(&counter, b"1".to_vec()).unwrap();
+
+    MockHost::set_request(1);
+    assert_eq!(vars.get("x"), None);
+    assert_eq!(vars.get_with_version(&counter), Some((1, b"1".to_vec())));
+    // the request fills its quota; the global variables have their own
+    vars.set("x", b"request 1 is longer".to_vec()).unwrap();
+    assert_eq!(vars.cas(&counter, 1, b"2".to_vec()), Ok(2));
+
+    MockHost::set_request(0);
+    assert_eq!(vars.get("x"), Some(b"request 0".to_vec()));
+    let r = vars.transaction(|txn| {
+        let n = parse_count(&txn.get(&counter).unwrap());
+        txn.set(&counter, (n + 1).to_string().into_bytes());
+        n
+    });
+    assert_eq!(r, Ok(2));
+
+    MockHost::set_request(1);
+    assert_eq!(vars.read_many(&[&counter]).unwrap(), [Some(b"3".to_vec())]);
+    // each request has its own sequence numbers, and the global variables too
+    let seqs: Vec<_> = MockHost::take_storage_cmds()
+        .into_iter()
+        .filter_map(|cmd| match cmd {
+            StorageCmd::WriteBatch { require_seq, .. } => require_seq,
+            _ => None,
+        })
+        .collect();
+    assert_eq!(seqs, vec![2]);
+}
+
+#[test]
+#[should_panic(expected = "mixes global and request variables")]
+fn transaction_cannot_mix_global_and_request_variables() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let vars = VariableStorage::new();
+    let _ = vars.transaction(|txn| {
+        let x = txn.get("x");
+        txn.set(&format!("{GLOBAL_PREFIX}x"), x.unwrap_or_default());
+    });
+}
+
+#[test]
+fn batches_encode_values_like_single_variables() {
+    let value = b"\x00\xffx".to_vec();
+    let write = StorageCmd::WriteVar {
+        name: "x".to_string(),
+        value: value.clone(),
+        op: StorageOp::Set,
+        when_version_is: None,
+    };
+    let batch = StorageCmd::WriteBatch {
+        writes: vec![("x".to_string(), value.clone())],
+        require_seq: Some(3),
+    };
+    let write = serde_json::to_value(&write).unwrap();
+    let batch = serde_json::to_value(&batch).unwrap();
+    assert_eq!(write["WriteVar"]["value"], "00ff78");
+    assert_eq!(batch["WriteBatch"]["writes"], json!([["x", "00ff78"]]));
+    match serde_json::from_value(batch).unwrap() {
+        StorageCmd::WriteBatch { writes, .. } => {
+            assert_eq!(writes, [("x".to_string(), value.clone())])
+        }
+        cmd => panic!("{cmd:?}"),
+    }
+
+    let read = StorageResp::ReadBatch {
+        seq: 1,
+        values: vec![Some((2, value.clone())), None],
+    };
+    let read = serde_json::to_value(&read).unwrap();
+    assert_eq!(read["ReadBatch"]["values"], json!([[2, "00ff78"], null]));
+    match serde_json::from_value(read).unwrap() {
+        StorageResp::ReadBatch { values, .. } => assert_eq!(values, [Some((2, value)), None]),
+        resp => panic!("{resp:?}"),
+    }
+}
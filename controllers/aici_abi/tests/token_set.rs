@@ -0,0 +1,178 @@
+use aici_abi::{
+    recognizer::BiasCache,
+    recording::{Record, RecordKind},
+    replay::replay_session,
+    svob::{SimpleVob, TokenSet},
+    testing::{record_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, MidProcessArg, MidProcessResult, TokenId, TokenizerEnv,
+};
+
+/// Doesn't allow EOS before `min_tokens` tokens are generated.
+struct MinTokens {
+    trie: TokTrie,
+    min_tokens: usize,
+    num_tokens: usize,
+}
+
+impl AiciCtrl for MinTokens {
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        self.num_tokens += arg.tokens.len();
+        let mut set = self.trie.alloc_token_set();
+        set.set_all(true);
+        if self.num_tokens < self.min_tokens {
+            set.disallow_token(self.trie.eos_token());
+        }
+        MidProcessResult::sample(set)
+    }
+}
+
+fn min_tokens(min_tokens: usize) -> MinTokens {
+    MinTokens {
+        trie: TokTrie::from_host(),
+        min_tokens,
+        num_tokens: 0,
+    }
+}
+
+fn almost_full(trie: &TokTrie, deny: &[TokenId]) -> SimpleVob {
+    let mut set = trie.alloc_token_set();
+    set.set_all(true);
+    for t in deny {
+        set.disallow_token(*t);
+    }
+    set
+}
+
+#[test]
+fn almost_full_sets_become_deny_lists() {
+    let env = MockTokenizerEnv::default();
+    let trie = env.tok_trie();
+    let set = almost_full(trie, &[3, 100, trie.eos_token()]);
+    assert_eq!(set.to_deny_list(3), Some(vec![3, 100, trie.eos_token()]));
+    assert_eq!(set.to_deny_list(2), None);
+    match TokenSet::from_vob(set) {
+        TokenSet::Deny(deny) => assert_eq!(deny, vec![3, 100, trie.eos_token()]),
+        r => panic!("expected a deny list, got {r:?}"),
+    }
+
+    // without set_all(), the padding past the vocabulary is denied too
+    let mut set = trie.alloc_token_set();
+    let padding = set.len() - trie.vocab_size();
+    assert_eq!(set.to_deny_list(set.len()).unwrap().len(), set.len());
+    set.allow_token(5);
+    let set = TokenSet::from_vob(set);
+    assert!(matches!(set, TokenSet::Allow(_)));
+    assert!(set.is_allowed(5) && !set.is_allowed(6));
+    assert!(padding > 0);
+}
+
+#[test]
+fn deny_lists_are_passed_when_the_host_accepts_them() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    MockHost::set_config("deny_list", 1);
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: " world".to_string(),
+            max_tokens: 10,
+        },
+    ];
+    let (tr, log) = record_controller_script(|| min_tokens(1), script);
+
+    // EOS is sampled as soon as it's allowed, after the preferred text runs out
+    let trie = env.tok_trie();
+    let eos = trie.eos_token();
+    assert!(tr.eos);
+    assert_eq!(tr.sampled, vec![trie.token_id(b" world").unwrap(), eos]);
+
+    let records = Record::parse_log(&log).unwrap();
+    let deny_lists: Vec<&Record> = records
+        .iter()
+        .filter(|r| r.kind == RecordKind::LogitDenyList)
+        .collect();
+    assert_eq!(deny_lists.len(), 2);
+    // the index returned by the host, then the tokens; nothing is denied once EOS is allowed
+    assert_eq!(deny_lists[0].data[4..], eos.to_le_bytes());
+    assert!(deny_lists[1].data[4..].is_empty());
+    assert!(records.iter().all(|r| r.kind != RecordKind::LogitBias));
+
+    replay_session(&log, || min_tokens(1)).unwrap();
+}
+
+#[test]
+fn model_vocab_size_is_checked_and_kept() {
+    let env = MockTokenizerEnv::default();
+    let mut trie = TokTrie::from_bytes(&env.tok_trie().serialize());
+    let vocab_size = trie.vocab_size();
+    assert_eq!(trie.model_vocab_size(), vocab_size);
+
+    // the model can't have fewer logits than the tokenizer has tokens
+    assert!(trie.set_model_vocab_size(vocab_size - 1).is_err());
+    assert_eq!(trie.model_vocab_size(), vocab_size);
+
+    // padded, like models with the vocabulary rounded up to a multiple of 64
+    let model_vocab_size = (vocab_size + 64) & !63;
+    trie.set_model_vocab_size(model_vocab_size).unwrap();
+    let bytes = trie.serialize();
+    let trie2 = TokTrie::from_bytes(&bytes);
+    assert_eq!(trie2.vocab_size(), vocab_size);
+    assert_eq!(trie2.model_vocab_size(), model_vocab_size);
+
+    // a header with fewer logits than tokens is rejected
+    let mut bad = bytes.clone();
+    bad[28..32].copy_from_slice(&(vocab_size as u32 - 1).to_le_bytes());
+    assert!(TokTrie::deserialize(&bad).is_err());
+}
+
+#[test]
+fn token_sets_can_be_padded_to_the_model_vocab() {
+    let env = MockTokenizerEnv::default();
+    let mut trie = TokTrie::from_bytes(&env.tok_trie().serialize());
+    let vocab_size = trie.vocab_size();
+    let model_vocab_size = vocab_size + 100;
+    trie.set_model_vocab_size(model_vocab_size).unwrap();
+    let last = model_vocab_size as TokenId - 1;
+
+    let mut set = trie.alloc_token_set();
+    set.set_all(true);
+    // the host would reject this one
+    assert!(set.len() < model_vocab_size);
+
+    let mut padded = set.clone();
+    padded.resize(model_vocab_size, false);
+    assert!(padded.len() >= model_vocab_size);
+    assert!(padded.is_allowed(trie.eos_token()));
+    assert!(!padded.is_allowed(last));
+
+    set.resize(model_vocab_size, true);
+    assert!(set.is_allowed(last));
+}
+
+#[test]
+fn bias_cache_checks_the_state() {
+    let env = MockTokenizerEnv::new(&[]);
+    MockHost::install(&env);
+    let trie = env.tok_trie();
+    let mut set = trie.alloc_token_set();
+    set.allow_token(b'x' as TokenId);
+    let mut cache = BiasCache::default();
+    cache.share(Some(17));
+    cache.insert_checked(42, &[1, 2], &set);
+
+    let mut found = trie.alloc_token_set();
+    assert!(cache.get_checked(42, &[1, 2], &mut found));
+    assert!(found.is_allowed(b'x' as TokenId));
+    // another state with the same key
+    assert!(!cache.get_checked(42, &[1, 3], &mut found));
+    assert!(!cache.get_checked(42, &[1], &mut found));
+
+    // same on the host
+    let mut other = BiasCache::default();
+    other.share(Some(17));
+    assert!(!other.get_checked(42, &[1, 3], &mut found));
+    assert!(other.get_checked(42, &[1, 2], &mut found));
+    assert_eq!(other.shared_hits(), 1);
+    assert_eq!(other.misses(), 1);
+}
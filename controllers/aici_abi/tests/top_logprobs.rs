@@ -0,0 +1,99 @@
+use aici_abi::{
+    replay::replay_session,
+    testing::{record_controller_script, run_controller_script, MockHost, MockTokenizerEnv, Phase},
+    toktree::TokTrie,
+    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId,
+    TokenizerEnv,
+};
+
+/// Asks for the top `k` tokens, and keeps them with the token they came with;
+/// tokens containing `banned` are not allowed.
+struct TopK {
+    trie: TokTrie,
+    k: u32,
+    banned: u8,
+    steps: Vec<(TokenId, Vec<(TokenId, f32)>)>,
+}
+
+impl AiciCtrl for TopK {
+    fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
+        InitPromptResult::default().with_top_logprobs(self.k)
+    }
+
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        if let Some(tok) = arg.tokens.first() {
+            self.steps.push((*tok, arg.top_logprobs.clone()));
+        }
+        let mut set = self.trie.alloc_token_set();
+        set.set_all(true);
+        for tok in 0..self.trie.vocab_size() as TokenId {
+            if self.trie.token(tok).contains(&self.banned) {
+                set.disallow_token(tok);
+            }
+        }
+        MidProcessResult::sample(set)
+    }
+}
+
+fn script(prefer: &str) -> Vec<Phase> {
+    vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: prefer.to_string(),
+            max_tokens: 5,
+        },
+    ]
+}
+
+fn run(k: u32, banned: u8, prefer: &str) -> Vec<(TokenId, Vec<(TokenId, f32)>)> {
+    let new_ctrl = || TopK {
+        trie: TokTrie::from_host(),
+        k,
+        banned,
+        steps: vec![],
+    };
+    // the arguments go through JSON, like with the host
+    let (_, log) = record_controller_script(new_ctrl, script(prefer));
+    replay_session(&log, new_ctrl).unwrap();
+
+    let mut ctrl = new_ctrl();
+    run_controller_script(&mut ctrl, script(prefer));
+    ctrl.steps
+}
+
+#[test]
+fn sampled_token_is_in_top_logprobs() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let trie = env.tok_trie();
+
+    let steps = run(3, b'#', " the value");
+    // " the", " ", "value"
+    assert_eq!(steps.len(), 3);
+    for (tok, top) in steps {
+        assert!(!top.is_empty() && top.len() <= 3);
+        // greedy sampling without a mask in the way picks the most likely token
+        assert_eq!(top[0].0, tok, "{}", trie.token_dbg(tok));
+        assert!(top.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(top.iter().all(|(_, lp)| *lp <= 0.0));
+        assert!(top.iter().map(|(_, lp)| lp.exp()).sum::<f32>() <= 1.0 + 1e-5);
+    }
+}
+
+#[test]
+fn top_logprobs_are_before_the_mask() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let trie = env.tok_trie();
+
+    // " the" is the most likely, but masked out
+    let steps = run(3, b'h', " the");
+    let (tok, top) = &steps[0];
+    assert_eq!(top[0].0, trie.token_id(b" the").unwrap());
+    assert_eq!(trie.token(*tok), b" ");
+    assert!(top.iter().any(|(t, _)| t == tok));
+
+    // nothing is computed unless asked for
+    let steps = run(0, b'#', " the value");
+    assert!(steps.iter().all(|(_, top)| top.is_empty()));
+}
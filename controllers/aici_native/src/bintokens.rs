@@ -1,6 +1,5 @@
-use aici_abi::{bytes::TokRxInfo, toktree::TokTrie, TokenId, TokenizerEnv};
+use aici_abi::{bytes::TokRxInfo, hftokenizers, toktree::TokTrie, TokenId, TokenizerEnv};
 use anyhow::{anyhow, bail, Result};
-use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use tokenizers::{normalizers::Sequence, FromPretrainedParameters, NormalizerWrapper, Tokenizer};
@@ -11,10 +10,14 @@ pub struct ByteTokenizer {
     pub hf_tokenizer: Tokenizer,
     pub eos_token: u32,
     pub vocab_size: u32,
+    /// "byte_level" or "byte_fallback"; see aici_abi::RuntimeInfo::tokenizer.
+    pub scheme: &'static str,
     token_bytes: Vec<Vec<u8>>,
     pub special: BTreeMap<String, u32>,
 }
 
+pub use aici_abi::hftokenizers::EOS_TOKENS;
+
 pub struct TokenizerInfo {
     pub name: &'static str,
     pub description: &'static str,
@@ -87,30 +90,6 @@ pub fn tokenizers() -> Vec<TokenizerInfo> {
     ]
 }
 
-// useful when debugging this: https://www.cogsci.ed.ac.uk/~richard/utf-8.cgi
-
-fn is_self_mapped(c: char) -> bool {
-    match c {
-        '!'..='~' | '\u{00A1}'..='\u{00AC}' | '\u{00AE}'..='\u{00FF}' => true,
-        _ => false,
-    }
-}
-
-fn build_char_map() -> FxHashMap<char, u8> {
-    let mut res = FxHashMap::default();
-    let mut k = 0x100u32;
-    for byte in 0..=255u8 {
-        let c = byte as char;
-        if is_self_mapped(c) {
-            res.insert(c, byte);
-        } else {
-            res.insert(char::from_u32(k).unwrap(), byte);
-            k += 1;
-        }
-    }
-    res
-}
-
 pub fn list_tokenizers() -> String {
     format!(
         "Available tokenizers for -t or --tokenizer:\n{}\n{}\n{}",
@@ -166,7 +145,7 @@ pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
 
     log::info!("loading tokenizer: {}", name);
 
-    let loaded = if name.starts_with(".") {
+    let loaded = if name.starts_with(".") || name.starts_with("/") {
         Tokenizer::from_file(name)
     } else {
         let mut name2 = name.to_string();
@@ -194,10 +173,6 @@ pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
 
 impl ByteTokenizer {
     pub fn from_tokenizer(mut hft: Tokenizer) -> Result<ByteTokenizer> {
-        let mut is_byte_level = false;
-        let mut is_byte_fallback = false;
-        let mut space_ch = ' ';
-
         // remove the "Prepend space"
         if let Some(n) = hft.get_normalizer() {
             let n = match n {
@@ -215,107 +190,16 @@ impl ByteTokenizer {
             hft.with_normalizer(n);
         }
 
-        if let Some(d) = hft.get_decoder() {
-            // DecoderWrapper::Sequence() doesn't let one access the decoders
-            // so we resort to json munching
-            let v = serde_json::to_value(d).unwrap();
-            if v["type"].as_str() == Some("ByteLevel") {
-                is_byte_level = true;
-            } else if v["type"].as_str() == Some("Sequence") {
-                if let Some(decoders) = v["decoders"].as_array() {
-                    for decoder in decoders {
-                        if decoder["type"].as_str() == Some("ByteFallback") {
-                            is_byte_fallback = true;
-                        } else if decoder["type"].as_str() == Some("Replace")
-                            && decoder["content"].as_str() == Some(" ")
-                        {
-                            if let Some(s) = decoder["pattern"]["String"].as_str() {
-                                let s: Vec<char> = s.chars().collect();
-                                if s.len() == 1 {
-                                    space_ch = s[0];
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        if !is_byte_fallback && !is_byte_level {
-            bail!("can't determine decoder type: {:?}", hft.get_decoder());
-        }
-
-        let vocab_size = hft.get_vocab_size(true) as u32;
-        let added = hft.get_added_tokens_decoder();
-
-        let mut res = ByteTokenizer {
+        let bytes = hftokenizers::token_bytes(&hft)?;
+        Ok(ByteTokenizer {
             hf_model: "foobar".to_string(),
-            eos_token: 0,
-            vocab_size,
-            special: BTreeMap::new(),
-            token_bytes: (0..vocab_size).map(|_| Vec::new()).collect(),
+            eos_token: bytes.eos_token,
+            vocab_size: bytes.token_bytes.len() as u32,
+            scheme: bytes.scheme,
+            special: bytes.special,
+            token_bytes: bytes.token_bytes,
             hf_tokenizer: hft,
-        };
-
-        for (id, info) in added.iter() {
-            if info.special {
-                match info.content.as_str() {
-                    "</s>" | "<|endoftext|>" => res.eos_token = *id,
-                    _ => {}
-                }
-                res.special.insert(info.content.clone(), *id);
-            } else {
-                res.token_bytes[*id as usize] = info.content.clone().into_bytes();
-            }
-        }
-
-        let char_map = build_char_map();
-
-        for tok_id in 0..vocab_size {
-            if added.contains_key(&tok_id) {
-                continue;
-            }
-            if let Some(tok_name) = res.hf_tokenizer.id_to_token(tok_id) {
-                if is_byte_fallback {
-                    if tok_name.len() == 6 && tok_name.starts_with("<0x") && tok_name.ends_with(">")
-                    {
-                        // parse hex number from tok_name
-                        let hex_str = &tok_name[3..5];
-                        let byte = u8::from_str_radix(hex_str, 16).unwrap();
-                        res.token_bytes[tok_id as usize] = vec![byte];
-                    } else {
-                        assert!(!tok_name.starts_with("<0x"));
-                        let tok_name = tok_name.replace(space_ch, " ");
-                        res.token_bytes[tok_id as usize] = tok_name.as_bytes().to_vec();
-                    }
-                } else if is_byte_level {
-                    let bytes: Result<Vec<u8>> = tok_name
-                        .chars()
-                        .map(|c| {
-                            char_map
-                                .get(&c)
-                                .map(|c| *c)
-                                .ok_or_else(|| anyhow!("missing char: {}", c))
-                        })
-                        .collect();
-                    let bytes = match bytes {
-                        Ok(b) => b,
-                        Err(e) => {
-                            println!("error: {} for {:?}", e, tok_name);
-                            continue;
-                        }
-                    };
-
-                    res.token_bytes[tok_id as usize] = bytes;
-                } else {
-                    panic!();
-                }
-            } else {
-                log::warn!("missing token: {}", tok_id);
-            }
-        }
-
-        Ok(res)
+        })
     }
 
     pub fn tokrx_info(&self) -> TokRxInfo {
@@ -324,10 +208,39 @@ impl ByteTokenizer {
             tok_eos: self.eos_token,
         }
     }
+
+    /// Use the first of `ids` as the EOS token, and mark all of them as special
+    /// (so they decode to empty byte strings).
+    pub fn set_eos_tokens(&mut self, ids: &[u32]) -> Result<()> {
+        if ids.is_empty() {
+            bail!("no EOS tokens");
+        }
+        for &id in ids {
+            if id >= self.vocab_size {
+                bail!(
+                    "EOS token {id} is out of the vocabulary of {}",
+                    self.vocab_size
+                );
+            }
+            let name = self
+                .hf_tokenizer
+                .id_to_token(id)
+                .unwrap_or_else(|| format!("<EOS_{id}>"));
+            self.token_bytes[id as usize].clear();
+            self.special.insert(name, id);
+        }
+        self.eos_token = ids[0];
+        Ok(())
+    }
+
     pub fn token_bytes(&self) -> Vec<Vec<u8>> {
         self.token_bytes.clone()
     }
 
+    pub fn to_toktrie(&self) -> TokTrie {
+        TokTrie::from(&self.tokrx_info(), &self.token_bytes).with_special_tokens(&self.special)
+    }
+
     pub fn add_missing_tokens(&mut self, vocab_size: usize) {
         assert!(self.vocab_size == self.token_bytes.len() as u32);
         assert!(vocab_size >= self.token_bytes.len());
@@ -353,7 +266,7 @@ impl ByteTokenizerEnv {
         Ok(Self::new(tokenizer))
     }
     pub fn new(tokenizer: ByteTokenizer) -> ByteTokenizerEnv {
-        let tok_trie = TokTrie::from(&tokenizer.tokrx_info(), &tokenizer.token_bytes());
+        let tok_trie = tokenizer.to_toktrie();
         ByteTokenizerEnv {
             tokenizer,
             tok_trie,
@@ -371,13 +284,11 @@ impl TokenizerEnv for ByteTokenizerEnv {
     }
 
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
-        let tokens = self
-            .tokenizer
-            .hf_tokenizer
-            .encode(String::from_utf8_lossy(s), false);
-        match tokens {
-            Err(e) => panic!("tokenize error: {e}"),
-            Ok(tokens) => Vec::from(tokens.get_ids()),
-        }
+        self.tok_trie.tokenize_bytes_with(s, |s| {
+            match self.tokenizer.hf_tokenizer.encode(s, false) {
+                Err(e) => panic!("tokenize error: {e}"),
+                Ok(tokens) => Vec::from(tokens.get_ids()),
+            }
+        })
     }
 }
@@ -1,6 +1,5 @@
 pub mod bintokens;
 mod log;
-pub mod variables;
 
 pub use log::*;
 
@@ -9,6 +9,8 @@ pub enum LogMode {
     Normal,
     Test,
     Daemon,
+    /// Like Normal, but to stderr; for command-line tools printing their output to stdout.
+    Stderr,
 }
 
 struct LimitedWrite {
@@ -89,6 +91,9 @@ pub fn init_log(mode: LogMode) -> Result<()> {
         LogMode::Daemon => Logger::try_with_env_or_str("info")?
             .format(daemon_format)
             .log_to_stdout(),
+        LogMode::Stderr => Logger::try_with_env_or_str("info")?
+            .format(truncated_format)
+            .log_to_stderr(),
     };
 
     logger.start()?;
@@ -746,24 +746,20 @@ impl StepState {
         self.check_eos(false)
     }
 
-    #[allow(dead_code)]
     fn attention_mask(&self, ctx: &RunnerCtx) -> Vec<f32> {
         if self.mask_tags.len() == 0 {
             vec![]
         } else {
-            let mut mask = vec![1.0f32; ctx.tokens.len()];
-            for (idx, tok) in ctx.tokens.iter().enumerate() {
-                if self.mask_tags.contains(&tok.tag) {
-                    println!(
-                        "masking t[{}] = {:?} tag={:?}",
-                        idx,
-                        ctx.trie.token_str(tok.id),
-                        tok.tag
-                    );
-                    mask[idx] = 0.0;
-                }
-            }
-            mask
+            ctx.tokens
+                .iter()
+                .map(|tok| {
+                    if self.mask_tags.contains(&tok.tag) {
+                        0.0
+                    } else {
+                        1.0
+                    }
+                })
+                .collect()
         }
     }
 
@@ -864,7 +860,9 @@ impl StepState {
                 Stmt::Set { var, expr } => {
                     let val = runner.expand_with_curr(&expr, self);
                     println!("  set {:?} := {:?}", var, String::from_utf8_lossy(&val));
-                    runner.vars.set(&var.0, val);
+                    if let Err(e) = runner.vars.set(&var.0, val) {
+                        panic!("can't set {:?}: {e}", var.0);
+                    }
                 }
             }
         }
@@ -1121,11 +1119,17 @@ impl Runner {
 
         self.finish_states();
 
-        if let Some(ff_tokens) = ff_tokens {
+        let attention_mask = self
+            .states
+            .get(self.state_idx)
+            .map_or(vec![], |state| state.attention_mask(&self.ctx));
+        let mut res = if let Some(ff_tokens) = ff_tokens {
             MidProcessResult::splice(0, ff_tokens)
         } else {
             MidProcessResult::sample(allowed_tokens)
-        }
+        };
+        res.branches[0].attention_mask = attention_mask;
+        res
     }
 
     fn maybe_wait(&mut self) -> bool {
@@ -1248,6 +1252,9 @@ impl AiciCtrl for Runner {
             assert!(branches.len() > 1);
             return MidProcessResult {
                 branches: branches.iter().map(|_| Branch::noop()).collect(),
+                suspend: None,
+                max_remaining_tokens: None,
+                captures: vec![],
             };
         }
 
@@ -0,0 +1,116 @@
+use crate::{
+    earley::{
+        earley_grm_for_tools, earley_grm_from_guidance, earley_grm_from_json_schema, Grammar,
+        JsonCompileOptions, ToolSpec,
+    },
+    GrammarRegistry, TokenParser,
+};
+use aici_abi::{storage_get_versioned, LogLevel};
+use anyhow::{anyhow, Result};
+use base64::{self, Engine as _};
+use serde::{Deserialize, Serialize};
+
+/// Grammar as passed in the controller argument, or written to a grammar variable.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GrammarSpec {
+    /// Base64-encoded guidance protobuf.
+    #[serde(default)]
+    pub guidance_b64: String,
+    /// Base64-encoded bundle of named guidance protobufs referencing each other
+    /// (see GrammarRegistry); used instead of `guidance_b64` when set.
+    #[serde(default)]
+    pub grammars_b64: String,
+    /// The grammar of the bundle to generate; defaults to the first one.
+    #[serde(default)]
+    pub root_grammar: Option<String>,
+    /// When set, constrain output to JSON values conforming to this schema
+    /// instead of a guidance grammar.
+    #[serde(default)]
+    pub json_schema: Option<serde_json::Value>,
+    /// When set, constrain output to a call to one of these tools;
+    /// takes precedence over the other fields.
+    #[serde(default)]
+    pub tools: Option<Vec<ToolSpec>>,
+    /// Also used for the arguments of `tools`.
+    #[serde(default)]
+    pub json_options: JsonCompileOptions,
+}
+
+impl GrammarSpec {
+    pub fn to_grammar(&self) -> Result<Grammar> {
+        if let Some(tools) = &self.tools {
+            return earley_grm_for_tools(tools, &self.json_options)
+                .map_err(|e| anyhow!("invalid tools: {e}"));
+        }
+        match &self.json_schema {
+            Some(schema) => earley_grm_from_json_schema(schema, &self.json_options)
+                .map_err(|e| anyhow!("invalid JSON schema: {e}")),
+            None if !self.grammars_b64.is_empty() => {
+                let bundle = base64::engine::general_purpose::STANDARD
+                    .decode(&self.grammars_b64)
+                    .map_err(|e| anyhow!("invalid base64: {e}"))?;
+                GrammarRegistry::from_bundle(&bundle)?.grammar(self.root_grammar.as_deref())
+            }
+            None => {
+                let guidance = base64::engine::general_purpose::STANDARD
+                    .decode(&self.guidance_b64)
+                    .map_err(|e| anyhow!("invalid base64: {e}"))?;
+                earley_grm_from_guidance(&guidance)
+                    .map_err(|e| anyhow!("invalid guidance protobuf: {e}"))
+            }
+        }
+    }
+}
+
+/// Lets another process (typically the application server) change the constraint
+/// while the sequence is generating, by writing a JSON-encoded GrammarSpec
+/// to a storage variable.
+///
+/// Call update() at the start of each mid_process(). When the variable has been
+/// written since the last check, the new grammar is compiled and swapped in;
+/// the output generated so far is kept and has to parse under the new grammar,
+/// otherwise the update is ignored (with a warning).
+pub struct DynGrammar {
+    var_name: String,
+    version: u64,
+}
+
+impl DynGrammar {
+    /// Only writes to the variable after this call are considered.
+    pub fn new(var_name: &str) -> Self {
+        let version = storage_get_versioned(var_name).map_or(0, |(v, _)| v);
+        DynGrammar {
+            var_name: var_name.to_string(),
+            version,
+        }
+    }
+
+    /// Returns true if the grammar was swapped.
+    pub fn update(&mut self, tok_parser: &mut TokenParser) -> bool {
+        let (version, value) = match storage_get_versioned(&self.var_name) {
+            Some((version, value)) if version != self.version => (version, value),
+            _ => return false,
+        };
+        // don't retry a broken update on every step
+        self.version = version;
+        let res = serde_json::from_slice::<GrammarSpec>(&value)
+            .map_err(|e| anyhow!("invalid grammar spec: {e}"))
+            .and_then(|spec| spec.to_grammar())
+            .and_then(|grm| tok_parser.swap_grammar(grm));
+        match res {
+            Ok(()) => {
+                aici_abi::wlog_info!("grammar from '{}' v{} applied", self.var_name, version);
+                true
+            }
+            Err(e) => {
+                aici_abi::wlog!(
+                    LogLevel::Warn,
+                    "grammar from '{}' v{} rejected: {e}",
+                    self.var_name,
+                    version
+                );
+                false
+            }
+        }
+    }
+}
@@ -1,8 +1,116 @@
-use aici_abi::toktree;
+use aici_abi::toktree::{self, Recognizer, SpecialToken};
+use std::time::{Duration, Instant};
 
-use super::Parser;
+use super::{ByteSet, Grammar, Parser};
 use crate::earley::{from_guidance::earley_grm_from_guidance, parser::ParseResult};
 
+/// Hides Parser::allowed_byte_mask() from the trie walk.
+struct NoByteMask<'a>(&'a mut Parser);
+
+impl<'a> Recognizer for NoByteMask<'a> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.pop_bytes(num)
+    }
+    fn collapse(&mut self) {
+        self.0.collapse()
+    }
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.special_allowed(tok)
+    }
+    fn trie_started(&mut self) {
+        self.0.trie_started()
+    }
+    fn trie_finished(&mut self) {
+        self.0.trie_finished()
+    }
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.try_push_byte(byte)
+    }
+}
+
+/// Walk the same tokens through the grammar compiled with and without lexemes; the token
+/// sets have to be the same. Prints the chart size and the time in compute_bias().
+fn compare_lexemes(trie: &toktree::TokTrie, name: &str, grm: &Grammar, input: &[u8]) {
+    let mut dfa = Parser::new(grm.compile().unwrap());
+    let mut earley = Parser::new(grm.compile_without_lexemes().unwrap());
+    let mut dfa_set = trie.alloc_token_set();
+    let mut earley_set = trie.alloc_token_set();
+    let mut dfa_time = Duration::ZERO;
+    let mut earley_time = Duration::ZERO;
+    let toks = trie.greedy_tokenize(input);
+    for tok in &toks {
+        let t0 = Instant::now();
+        trie.compute_bias(&mut dfa, &mut dfa_set);
+        dfa_time += t0.elapsed();
+        let t0 = Instant::now();
+        trie.compute_bias(&mut earley, &mut earley_set);
+        earley_time += t0.elapsed();
+        assert!((0..trie.vocab_size() as u32)
+            .all(|t| dfa_set.is_allowed(t) == earley_set.is_allowed(t)));
+        trie.append_token(&mut dfa, *tok);
+        trie.append_token(&mut earley, *tok);
+    }
+    assert!(dfa.is_accepting() && earley.is_accepting());
+    println!(
+        "{name}: chart {} items, bias {:.1}us/token (Earley: {} items, {:.1}us/token)",
+        dfa.chart_size(),
+        dfa_time.as_micros() as f64 / toks.len() as f64,
+        earley.chart_size(),
+        earley_time.as_micros() as f64 / toks.len() as f64,
+    );
+}
+
+fn lexeme_test(trie: &toktree::TokTrie) {
+    // a quoted 1000-byte literal, made into a lexeme by compile(); Earley already has
+    // a single item per byte here, so this mostly checks the DFA doesn't add overhead
+    let text = "The quick brown fox jumps over the lazy dog. "
+        .repeat(23)
+        .into_bytes();
+    let text = &text[0..1000];
+    let mut grm = Grammar::new();
+    let start = grm.start();
+    let quote = grm.terminal(&ByteSet::from_byte(b'"'));
+    let mut rhs = vec![quote];
+    for b in text {
+        rhs.push(grm.terminal(&ByteSet::from_byte(*b)));
+    }
+    rhs.push(quote);
+    grm.add_rule(start, rhs);
+    let input = [&b"\""[..], text, &b"\""[..]].concat();
+    compare_lexemes(trie, "1000-byte literal", &grm, &input);
+
+    // comma-separated identifiers: [a-zA-Z0-9_]{1,64}
+    let mut grm = Grammar::new();
+    let start = grm.start();
+    let ch = grm.terminal(
+        &(ByteSet::from_range(b'a'..=b'z')
+            | ByteSet::from_range(b'A'..=b'Z')
+            | ByteSet::from_range(b'0'..=b'9')
+            | ByteSet::from_byte(b'_')),
+    );
+    let comma = grm.terminal(&ByteSet::from_byte(b','));
+    let mut tail = grm.fresh_symbol("tail");
+    grm.add_rule(tail, vec![]);
+    for _ in 1..64 {
+        let sym = grm.fresh_symbol("tail");
+        grm.add_rule(sym, vec![]);
+        grm.add_rule(sym, vec![ch, tail]);
+        tail = sym;
+    }
+    let ident = grm.fresh_symbol("ident");
+    grm.add_rule(ident, vec![ch, tail]);
+    grm.make_lexeme(ident);
+    let idents = grm.fresh_symbol("idents");
+    grm.add_rule(idents, vec![ident]);
+    grm.add_rule(idents, vec![idents, comma, ident]);
+    grm.add_rule(start, vec![idents]);
+    let input = (0..40)
+        .map(|i| format!("identifier_{i}_of_the_list"))
+        .collect::<Vec<_>>()
+        .join(",");
+    compare_lexemes(trie, "identifier list", &grm, input.as_bytes());
+}
+
 pub fn earley_test(trie: toktree::TokTrie) {
     let g_bytes = include_bytes!("../../../aici_abi/grammars/json0.guidance");
     let cfg = earley_grm_from_guidance(g_bytes).unwrap();
@@ -15,7 +123,7 @@ pub fn earley_test(trie: toktree::TokTrie) {
     let toks = trie.greedy_tokenize(input);
     println!("tokens: {:?}", toks.len());
 
-    let grm = cfg.compile();
+    let grm = cfg.compile().unwrap();
 
     let mut parser = Parser::new(grm.clone());
     let mut last_res = ParseResult::Reject;
@@ -30,10 +138,13 @@ pub fn earley_test(trie: toktree::TokTrie) {
         println!("final non-accept");
     }
 
+    lexeme_test(&trie);
+
     const COLLECT_TIMES: bool = false;
     const NUM_REP: usize = if COLLECT_TIMES { 5 } else { 500 };
     let mut durations = vec![];
     let mut durations_us = vec![];
+    let mut durations_slow_us = vec![];
     println!("start!");
 
     let num_tok = 4;
@@ -54,6 +165,9 @@ pub fn earley_test(trie: toktree::TokTrie) {
             trie.compute_bias(&mut parser, &mut vob);
             if idx == num_tok - 1 {
                 durations_us.push(tt.elapsed().as_micros() as u64);
+                let tt = std::time::Instant::now();
+                trie.compute_bias(&mut NoByteMask(&mut parser), &mut vob);
+                durations_slow_us.push(tt.elapsed().as_micros() as u64);
             }
             // parser.print_stats();
             if !vob.is_allowed(tok) {
@@ -90,4 +204,9 @@ pub fn earley_test(trie: toktree::TokTrie) {
     // println!("min_time_us: {:?}", min_us);
     // for ~5ms 0.1ms is the precision we expect
     println!("min_time_ms: {:.1}", min_us as f64 / 1000.0);
+    let min_slow_us = *durations_slow_us.iter().min().unwrap();
+    println!(
+        "min_time_ms without byte mask: {:.1}",
+        min_slow_us as f64 / 1000.0
+    );
 }
@@ -16,6 +16,7 @@ pub struct NodeProps {
     pub commit_point: bool,
     pub capture_name: String,
     pub max_tokens: i32,
+    pub lexeme: bool,
 }
 
 impl NodeProps {
@@ -28,6 +29,7 @@ impl NodeProps {
                 commit_point: n.commit_point,
                 capture_name: n.capture_name.to_string(),
                 max_tokens: n.max_tokens,
+                lexeme: n.lexeme,
             },
             OneOffunction_type::select(n) => NodeProps {
                 nullable: n.nullable,
@@ -36,6 +38,7 @@ impl NodeProps {
                 commit_point: n.commit_point,
                 capture_name: n.capture_name.to_string(),
                 max_tokens: n.max_tokens,
+                lexeme: n.lexeme,
             },
             OneOffunction_type::byte(n) => NodeProps {
                 nullable: n.nullable,
@@ -44,6 +47,7 @@ impl NodeProps {
                 commit_point: n.commit_point,
                 capture_name: n.capture_name.to_string(),
                 max_tokens: i32::MAX,
+                lexeme: false,
             },
             OneOffunction_type::byte_range(n) => NodeProps {
                 nullable: false, // n.nullable,
@@ -52,6 +56,7 @@ impl NodeProps {
                 commit_point: n.commit_point,
                 capture_name: n.capture_name.to_string(),
                 max_tokens: i32::MAX,
+                lexeme: false,
             },
             OneOffunction_type::model_variable(n) => NodeProps {
                 nullable: n.nullable,
@@ -60,6 +65,7 @@ impl NodeProps {
                 commit_point: n.commit_point,
                 capture_name: n.capture_name.to_string(),
                 max_tokens: i32::MAX,
+                lexeme: false,
             },
             OneOffunction_type::None => {
                 panic!("None function type in guidance::Grammar")
@@ -82,6 +88,7 @@ impl NodeProps {
                 self.max_tokens.try_into().unwrap()
             },
             model_variable: None,
+            lexeme: self.lexeme,
             capture_name: if self.capture_name.is_empty() {
                 None
             } else {
@@ -91,9 +98,49 @@ impl NodeProps {
     }
 }
 
+/// Name of the grammar referenced by the node (see Grammar::reference()), if it's a reference;
+/// that's a join or select node without values, named `@` followed by the grammar name.
+fn reference_name<'a>(function_type: &'a OneOffunction_type) -> Option<&'a str> {
+    let (values, name) = match function_type {
+        OneOffunction_type::join(n) => (&n.values, &n.name),
+        OneOffunction_type::select(n) => (&n.values, &n.name),
+        _ => return None,
+    };
+    if values.is_empty() {
+        name.strip_prefix('@')
+    } else {
+        None
+    }
+}
+
+/// The string matched by the node, if it only matches one: a byte, or a join of those
+/// (strings in guidance are joins of bytes), without captures or other special props.
+fn literal_bytes(nodes: &[guidance::GrammarFunction], idx: usize, depth: usize) -> Option<Vec<u8>> {
+    // joins can't be cyclic in a valid grammar; don't loop on invalid ones
+    if depth > nodes.len() {
+        return None;
+    }
+    let function_type = &nodes[idx].function_type;
+    let props = NodeProps::from_grammar_function(function_type).to_symbol_props();
+    if props.is_special() || reference_name(function_type).is_some() {
+        return None;
+    }
+    match function_type {
+        OneOffunction_type::byte(n) if n.byte.len() == 1 => Some(vec![n.byte[0]]),
+        OneOffunction_type::join(n) => {
+            let mut bytes = vec![];
+            for v in &n.values {
+                bytes.extend(literal_bytes(nodes, *v as usize, depth + 1)?);
+            }
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
 pub fn earley_grm_from_guidance(bytes: &[u8]) -> Result<Grammar> {
     let mut reader = quick_protobuf::BytesReader::from_bytes(bytes);
-    let gg = guidance::Grammar::from_reader(&mut reader, bytes).unwrap();
+    let gg = guidance::Grammar::from_reader(&mut reader, bytes)?;
     let mut grm = Grammar::new();
 
     let symbols = gg
@@ -103,11 +150,11 @@ pub fn earley_grm_from_guidance(bytes: &[u8]) -> Result<Grammar> {
             let term = match &n.function_type {
                 OneOffunction_type::byte(n) => {
                     assert!(n.byte.len() == 1);
-                    Some(grm.terminal(&ByteSet::from_range(n.byte[0], n.byte[0])))
+                    Some(grm.terminal(&ByteSet::from_byte(n.byte[0])))
                 }
                 OneOffunction_type::byte_range(n) => {
                     assert!(n.byte_range.len() == 2);
-                    Some(grm.terminal(&ByteSet::from_range(n.byte_range[0], n.byte_range[1])))
+                    Some(grm.terminal(&ByteSet::from_range(n.byte_range[0]..=n.byte_range[1])))
                 }
                 OneOffunction_type::model_variable(n) => Some(grm.model_variable(&n.name)),
                 _ => None,
@@ -127,7 +174,12 @@ pub fn earley_grm_from_guidance(bytes: &[u8]) -> Result<Grammar> {
                 }
             } else {
                 assert!(name.len() > 0, "empty name");
-                grm.fresh_symbol(name)
+                let sym = grm.fresh_symbol(name);
+                if let Some(ref_name) = reference_name(&n.function_type) {
+                    let target = grm.reference(ref_name);
+                    grm.add_rule(sym, vec![target]);
+                }
+                sym
             };
             grm.apply_props(sym, sym_props);
             sym
@@ -139,12 +191,29 @@ pub fn earley_grm_from_guidance(bytes: &[u8]) -> Result<Grammar> {
 
     for (n, sym) in gg.nodes.iter().zip(symbols.iter()) {
         let lhs = *sym;
+        if reference_name(&n.function_type).is_some() {
+            // already points to the referenced grammar
+            continue;
+        }
         match &n.function_type {
             OneOffunction_type::join(n) => {
                 let rhs = n.values.iter().map(|idx| symbols[*idx as usize]).collect();
                 grm.add_rule(lhs, rhs);
             }
             OneOffunction_type::select(n) => {
+                let literals = n
+                    .values
+                    .iter()
+                    .map(|v| literal_bytes(&gg.nodes, *v as usize, 0))
+                    .collect::<Option<Vec<_>>>();
+                if let Some(mut literals) = literals {
+                    if n.nullable {
+                        literals.push(vec![]);
+                    }
+                    let options = literals.iter().map(|l| l.as_slice()).collect::<Vec<_>>();
+                    grm.add_string_choice(lhs, &options);
+                    continue;
+                }
                 if n.nullable {
                     grm.add_rule(lhs, vec![]);
                 }
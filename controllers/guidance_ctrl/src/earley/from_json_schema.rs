@@ -0,0 +1,647 @@
+use anyhow::{bail, Result};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{ByteSet, Grammar};
+use crate::earley::grammar::SymIdx;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JsonCompileOptions {
+    /// Pretty-print the output (newlines and two-space indentation);
+    /// otherwise output is compact with no whitespace at all.
+    #[serde(default)]
+    pub pretty: bool,
+    /// Accept any run of whitespace (of at most this many bytes) wherever JSON
+    /// allows it, and never force whitespace, leaving the formatting to the model.
+    /// Overrides `pretty`.
+    #[serde(default)]
+    pub flexible_whitespace: Option<usize>,
+}
+
+// keywords that don't affect the output
+const IGNORED_KEYWORDS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "anyOf",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+];
+
+/// Build a grammar for JSON values conforming to given schema.
+/// Object properties are generated in the order they are listed in the schema.
+/// Strings are valid UTF-8; their length limits count characters, with an escape
+/// sequence counting as one.
+pub fn earley_grm_from_json_schema(
+    schema: &Value,
+    options: &JsonCompileOptions,
+) -> Result<Grammar> {
+    let mut compiler = Compiler::new(options);
+    let root = compiler.gen_value(schema, "#", 0)?;
+    let start = compiler.grm.start();
+    compiler.grm.add_rule(start, vec![root]);
+    Ok(compiler.grm)
+}
+
+/// A function the model can call, as in the OpenAI tools API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolSpec {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema of the arguments; no arguments (`{}`) when missing.
+    #[serde(default)]
+    pub parameters: Option<Value>,
+}
+
+/// Build a grammar for a call to one of `tools`:
+/// `{"name": "<tool name>", "arguments": <JSON conforming to the tool's parameters>}`.
+/// The options only apply to the arguments; the enclosing object is always
+/// formatted as above.
+pub fn earley_grm_for_tools(tools: &[ToolSpec], options: &JsonCompileOptions) -> Result<Grammar> {
+    if tools.is_empty() {
+        bail!("no tools defined");
+    }
+    let mut compiler = Compiler::new(options);
+    let mut calls = vec![];
+    for (idx, tool) in tools.iter().enumerate() {
+        if tool.name.is_empty() {
+            bail!("tool #{idx} has no name");
+        }
+        if tools[..idx].iter().any(|t| t.name == tool.name) {
+            bail!("tool '{}' defined more than once", tool.name);
+        }
+        let path = format!("{}/parameters", tool.name);
+        let args = match &tool.parameters {
+            Some(schema) => compiler.gen_value(schema, &path, 0)?,
+            None => compiler.literal(b"{}"),
+        };
+        let name = format!("{}, \"arguments\": ", serde_json::to_string(&tool.name)?);
+        let name = compiler.literal(name.as_bytes());
+        calls.push(compiler.sequence("call", vec![name, args]));
+    }
+    let open = compiler.literal(b"{\"name\": ");
+    let calls = compiler.select("tool", calls);
+    let close = compiler.literal(b"}");
+    let start = compiler.grm.start();
+    compiler.grm.add_rule(start, vec![open, calls, close]);
+    Ok(compiler.grm)
+}
+
+struct Compiler {
+    grm: Grammar,
+    options: JsonCompileOptions,
+    literals: FxHashMap<Vec<u8>, SymIdx>,
+    // set with flexible_whitespace
+    ws: Option<SymIdx>,
+}
+
+fn opt_usize(obj: &Map<String, Value>, key: &str, path: &str) -> Result<Option<usize>> {
+    match obj.get(key) {
+        None => Ok(None),
+        Some(v) => match v.as_u64() {
+            Some(n) => Ok(Some(n as usize)),
+            None => bail!("{path}: '{key}' must be a non-negative integer"),
+        },
+    }
+}
+
+fn opt_i64(obj: &Map<String, Value>, key: &str, path: &str) -> Result<Option<i64>> {
+    match obj.get(key) {
+        None => Ok(None),
+        Some(v) => match v.as_i64() {
+            Some(n) => Ok(Some(n)),
+            None => bail!("{path}: '{key}' must be an integer"),
+        },
+    }
+}
+
+// bounded repetitions are unrolled, so limit their size
+const MAX_BOUNDED_REPEAT: usize = 1000;
+
+fn check_bounds(min: usize, max: Option<usize>, kmin: &str, kmax: &str, path: &str) -> Result<()> {
+    if let Some(max) = max {
+        if max < min {
+            bail!("{path}: '{kmin}' is greater than '{kmax}'");
+        }
+        if max - min > MAX_BOUNDED_REPEAT {
+            bail!("{path}: '{kmax}' - '{kmin}' is too large (over {MAX_BOUNDED_REPEAT})");
+        }
+    } else if min > MAX_BOUNDED_REPEAT {
+        bail!("{path}: '{kmin}' is too large (over {MAX_BOUNDED_REPEAT})");
+    }
+    Ok(())
+}
+
+impl Compiler {
+    fn new(options: &JsonCompileOptions) -> Self {
+        let mut grm = Grammar::new();
+        let ws = options
+            .flexible_whitespace
+            .map(|max_len| grm.whitespace(max_len));
+        Compiler {
+            grm,
+            options: options.clone(),
+            literals: FxHashMap::default(),
+            ws,
+        }
+    }
+
+    fn literal(&mut self, bytes: &[u8]) -> SymIdx {
+        if bytes.len() == 1 {
+            return self.grm.terminal(&ByteSet::from_byte(bytes[0]));
+        }
+        if let Some(sym) = self.literals.get(bytes) {
+            return *sym;
+        }
+        let rhs = bytes
+            .iter()
+            .map(|b| self.grm.terminal(&ByteSet::from_byte(*b)))
+            .collect();
+        let sym = self.grm.fresh_symbol("lit");
+        self.grm.add_rule(sym, rhs);
+        self.literals.insert(bytes.to_vec(), sym);
+        sym
+    }
+
+    fn sequence(&mut self, name: &str, rhs: Vec<SymIdx>) -> SymIdx {
+        let sym = self.grm.fresh_symbol(name);
+        self.grm.add_rule(sym, rhs);
+        sym
+    }
+
+    fn select(&mut self, name: &str, options: Vec<SymIdx>) -> SymIdx {
+        let sym = self.grm.fresh_symbol(name);
+        for opt in options {
+            self.grm.add_rule(sym, vec![opt]);
+        }
+        sym
+    }
+
+    fn optional(&mut self, sym: SymIdx) -> SymIdx {
+        let r = self.grm.fresh_symbol("opt");
+        self.grm.add_rule(r, vec![]);
+        self.grm.add_rule(r, vec![sym]);
+        r
+    }
+
+    /// Between `min` and `max` copies of `sym`; `max == None` means unbounded.
+    fn repeat(&mut self, sym: SymIdx, min: usize, max: Option<usize>) -> SymIdx {
+        let mut rhs = vec![sym; min];
+        match max {
+            None => {
+                let star = self.grm.fresh_symbol("star");
+                self.grm.add_rule(star, vec![]);
+                self.grm.add_rule(star, vec![star, sym]);
+                rhs.push(star);
+            }
+            Some(max) => {
+                if max > min {
+                    // sym? nested as (sym (sym (...)?)?)?
+                    let mut tail = self.optional(sym);
+                    for _ in min + 1..max {
+                        let inner = self.sequence("rep", vec![sym, tail]);
+                        tail = self.optional(inner);
+                    }
+                    rhs.push(tail);
+                }
+            }
+        }
+        self.sequence("rep", rhs)
+    }
+
+    // optional whitespace before a comma or colon
+    fn space(&self) -> Vec<SymIdx> {
+        self.ws.into_iter().collect()
+    }
+
+    fn newline(&mut self, depth: usize) -> Vec<SymIdx> {
+        if self.ws.is_some() {
+            self.space()
+        } else if self.options.pretty {
+            let mut s = "\n".to_string();
+            s.push_str(&"  ".repeat(depth));
+            vec![self.literal(s.as_bytes())]
+        } else {
+            vec![]
+        }
+    }
+
+    fn check_keywords(&self, obj: &Map<String, Value>, path: &str) -> Result<()> {
+        let unsupported = obj
+            .keys()
+            .filter(|k| {
+                !SUPPORTED_KEYWORDS.contains(&k.as_str()) && !IGNORED_KEYWORDS.contains(&k.as_str())
+            })
+            .map(|k| format!("'{}'", k))
+            .collect::<Vec<_>>();
+        if unsupported.len() > 0 {
+            bail!(
+                "{path}: unsupported JSON schema keyword(s): {}",
+                unsupported.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn gen_value(&mut self, schema: &Value, path: &str, depth: usize) -> Result<SymIdx> {
+        let obj = match schema {
+            Value::Bool(true) => bail!("{path}: unconstrained schema (true) is not supported"),
+            Value::Object(obj) => obj,
+            _ => bail!("{path}: schema must be an object"),
+        };
+        self.check_keywords(obj, path)?;
+
+        if let Some(v) = obj.get("const") {
+            return Ok(self.literal(serde_json::to_string(v)?.as_bytes()));
+        }
+
+        if let Some(v) = obj.get("enum") {
+            let values = match v.as_array() {
+                Some(a) if a.len() > 0 => a,
+                _ => bail!("{path}: 'enum' must be a non-empty array"),
+            };
+            let options = values
+                .iter()
+                .map(|v| Ok(self.literal(serde_json::to_string(v)?.as_bytes())))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(self.select("enum", options));
+        }
+
+        if let Some(v) = obj.get("anyOf") {
+            let schemas = match v.as_array() {
+                Some(a) if a.len() > 0 => a,
+                _ => bail!("{path}: 'anyOf' must be a non-empty array"),
+            };
+            let options = schemas
+                .iter()
+                .enumerate()
+                .map(|(idx, s)| self.gen_value(s, &format!("{path}/anyOf/{idx}"), depth))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(self.select("anyOf", options));
+        }
+
+        match obj.get("type") {
+            Some(Value::String(tp)) => self.gen_typed(tp, obj, path, depth),
+            Some(Value::Array(types)) => {
+                if types.is_empty() {
+                    bail!("{path}: 'type' must not be an empty array");
+                }
+                let options = types
+                    .iter()
+                    .map(|tp| match tp.as_str() {
+                        Some(tp) => self.gen_typed(tp, obj, path, depth),
+                        None => bail!("{path}: 'type' must be a string or an array of strings"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.select("types", options))
+            }
+            Some(_) => bail!("{path}: 'type' must be a string or an array of strings"),
+            None => bail!("{path}: schema must specify 'type', 'enum', 'const' or 'anyOf'"),
+        }
+    }
+
+    fn gen_typed(
+        &mut self,
+        tp: &str,
+        obj: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+    ) -> Result<SymIdx> {
+        match tp {
+            "null" => Ok(self.literal(b"null")),
+            "boolean" => {
+                let t = self.literal(b"true");
+                let f = self.literal(b"false");
+                Ok(self.select("boolean", vec![t, f]))
+            }
+            "integer" => self.gen_integer(obj, path),
+            "number" => {
+                if obj.contains_key("minimum") || obj.contains_key("maximum") {
+                    bail!("{path}: 'minimum'/'maximum' are only supported for integers");
+                }
+                Ok(self.gen_number())
+            }
+            "string" => self.gen_string(obj, path),
+            "array" => self.gen_array(obj, path, depth),
+            "object" => self.gen_object(obj, path, depth),
+            _ => bail!("{path}: unknown type '{tp}'"),
+        }
+    }
+
+    /// -?(0|[1-9][0-9]*)
+    fn gen_any_integer(&mut self) -> SymIdx {
+        let minus = self.literal(b"-");
+        let minus = self.optional(minus);
+        let unsigned = self.gen_unbounded_from(0);
+        self.sequence("integer", vec![minus, unsigned])
+    }
+
+    fn gen_number(&mut self) -> SymIdx {
+        let int = self.gen_any_integer();
+        let digit = self.grm.terminal(&ByteSet::from_range(b'0'..=b'9'));
+        let digits1 = self.repeat(digit, 1, None);
+        let dot = self.literal(b".");
+        let frac = self.sequence("frac", vec![dot, digits1]);
+        let frac = self.optional(frac);
+        let e = self.grm.terminal(&ByteSet::from_str("eE"));
+        let sign = self.grm.terminal(&ByteSet::from_str("+-"));
+        let sign = self.optional(sign);
+        let exp = self.sequence("exp", vec![e, sign, digits1]);
+        let exp = self.optional(exp);
+        self.sequence("number", vec![int, frac, exp])
+    }
+
+    fn gen_integer(&mut self, obj: &Map<String, Value>, path: &str) -> Result<SymIdx> {
+        let min = opt_i64(obj, "minimum", path)?;
+        let max = opt_i64(obj, "maximum", path)?;
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                bail!("{path}: 'minimum' is greater than 'maximum'");
+            }
+        }
+        let minus = self.literal(b"-");
+        let mut options = vec![];
+        // non-negative part
+        if max.map_or(true, |m| m >= 0) {
+            let lo = std::cmp::max(min.unwrap_or(0), 0) as u64;
+            options.push(match max {
+                Some(hi) => self.gen_uint_range(lo, hi as u64),
+                None => self.gen_unbounded_from(lo),
+            });
+        }
+        // negative part, as the absolute value
+        if min.map_or(true, |m| m < 0) {
+            let lo = match max {
+                Some(m) if m < 0 => m.unsigned_abs(),
+                _ => 1,
+            };
+            let abs = match min {
+                Some(m) => self.gen_uint_range(lo, m.unsigned_abs()),
+                None => self.gen_unbounded_from(lo),
+            };
+            options.push(self.sequence("neg", vec![minus, abs]));
+        }
+        Ok(self.select("integer", options))
+    }
+
+    fn num_digits(n: u64) -> usize {
+        n.to_string().len()
+    }
+
+    /// Integers >= lo, without leading zeros.
+    fn gen_unbounded_from(&mut self, lo: u64) -> SymIdx {
+        let k = Self::num_digits(lo);
+        let top = 10u64.saturating_pow(k as u32).saturating_sub(1);
+        let same_len = self.gen_uint_range(lo, top);
+        // anything with more than k digits
+        let first = self.grm.terminal(&ByteSet::from_range(b'1'..=b'9'));
+        let digit = self.grm.terminal(&ByteSet::from_range(b'0'..=b'9'));
+        let rest = self.repeat(digit, k, None);
+        let longer = self.sequence("uint", vec![first, rest]);
+        self.select("uint", vec![same_len, longer])
+    }
+
+    /// Integers in [lo, hi], without leading zeros.
+    fn gen_uint_range(&mut self, lo: u64, hi: u64) -> SymIdx {
+        let mut options = vec![];
+        for d in Self::num_digits(lo)..=Self::num_digits(hi) {
+            let a = if d == 1 { 0 } else { 10u64.pow(d as u32 - 1) };
+            let b = 10u64.saturating_pow(d as u32).saturating_sub(1);
+            let a = std::cmp::max(a, lo);
+            let b = std::cmp::min(b, hi);
+            if a <= b {
+                let sym = self.gen_digit_range(a.to_string().as_bytes(), b.to_string().as_bytes());
+                options.push(sym);
+            }
+        }
+        self.select("range", options)
+    }
+
+    /// Digit strings of the same length between `a` and `b` inclusive.
+    fn gen_digit_range(&mut self, a: &[u8], b: &[u8]) -> SymIdx {
+        assert!(a.len() == b.len() && a <= b);
+        let n = a.len();
+        if n == 1 {
+            return self.grm.terminal(&ByteSet::from_range(a[0]..=b[0]));
+        }
+        let digit = self.grm.terminal(&ByteSet::from_range(b'0'..=b'9'));
+        if a[1..].iter().all(|c| *c == b'0') && b[1..].iter().all(|c| *c == b'9') {
+            let first = self.grm.terminal(&ByteSet::from_range(a[0]..=b[0]));
+            let mut rhs = vec![first];
+            rhs.extend(std::iter::repeat(digit).take(n - 1));
+            return self.sequence("range", rhs);
+        }
+        if a[0] == b[0] {
+            let first = self.literal(&a[0..1]);
+            let rest = self.gen_digit_range(&a[1..], &b[1..]);
+            return self.sequence("range", vec![first, rest]);
+        }
+        let nines = vec![b'9'; n - 1];
+        let zeros = vec![b'0'; n - 1];
+        let mut options = vec![];
+        let first = self.literal(&a[0..1]);
+        let rest = self.gen_digit_range(&a[1..], &nines);
+        options.push(self.sequence("range", vec![first, rest]));
+        if a[0] + 1 < b[0] {
+            let first = self.grm.terminal(&ByteSet::from_range(a[0] + 1..=b[0] - 1));
+            let mut rhs = vec![first];
+            rhs.extend(std::iter::repeat(digit).take(n - 1));
+            options.push(self.sequence("range", rhs));
+        }
+        let first = self.literal(&b[0..1]);
+        let rest = self.gen_digit_range(&zeros, &b[1..]);
+        options.push(self.sequence("range", vec![first, rest]));
+        self.select("range", options)
+    }
+
+    fn gen_string(&mut self, obj: &Map<String, Value>, path: &str) -> Result<SymIdx> {
+        let min = opt_usize(obj, "minLength", path)?.unwrap_or(0);
+        let max = opt_usize(obj, "maxLength", path)?;
+        check_bounds(min, max, "minLength", "maxLength", path)?;
+
+        let plain = self.gen_plain_char();
+        let backslash = self.literal(b"\\");
+        let simple_esc = self.grm.terminal(&ByteSet::from_bytes(b"\"\\/bfnrt"));
+        let hex = self.grm.terminal(
+            &(ByteSet::from_range(b'0'..=b'9')
+                | ByteSet::from_range(b'a'..=b'f')
+                | ByteSet::from_range(b'A'..=b'F')),
+        );
+        let u = self.literal(b"u");
+        let unicode_esc = self.sequence("uesc", vec![u, hex, hex, hex, hex]);
+        let esc = self.select("esc", vec![simple_esc, unicode_esc]);
+        let escaped = self.sequence("escaped", vec![backslash, esc]);
+        let ch = self.select("char", vec![plain, escaped]);
+
+        let chars = self.repeat(ch, min, max);
+        let quote = self.literal(b"\"");
+        Ok(self.sequence("string", vec![quote, chars, quote]))
+    }
+
+    /// Any character except for '"', '\\' and control characters, UTF-8 encoded.
+    fn gen_plain_char(&mut self) -> SymIdx {
+        let ascii = self.grm.terminal(
+            &(ByteSet::from_range(0x20..=0x21)
+                | ByteSet::from_range(0x23..=0x5B)
+                | ByteSet::from_range(0x5D..=0x7F)),
+        );
+        let mut options = vec![ascii];
+        // ranges of the lead byte and of the byte after it, which rule out overlong
+        // encodings, surrogates (ED A0-BF) and code points past U+10FFFF
+        let multi_byte: [(u8, u8, u8, u8, usize); 8] = [
+            (0xC2, 0xDF, 0x80, 0xBF, 2),
+            (0xE0, 0xE0, 0xA0, 0xBF, 3),
+            (0xE1, 0xEC, 0x80, 0xBF, 3),
+            (0xED, 0xED, 0x80, 0x9F, 3),
+            (0xEE, 0xEF, 0x80, 0xBF, 3),
+            (0xF0, 0xF0, 0x90, 0xBF, 4),
+            (0xF1, 0xF3, 0x80, 0xBF, 4),
+            (0xF4, 0xF4, 0x80, 0x8F, 4),
+        ];
+        let cont = self.grm.terminal(&ByteSet::from_range(0x80..=0xBF));
+        for (lead_lo, lead_hi, next_lo, next_hi, len) in multi_byte {
+            let mut rhs = vec![
+                self.grm.terminal(&ByteSet::from_range(lead_lo..=lead_hi)),
+                self.grm.terminal(&ByteSet::from_range(next_lo..=next_hi)),
+            ];
+            rhs.extend(std::iter::repeat(cont).take(len - 2));
+            options.push(self.sequence("utf8", rhs));
+        }
+        self.select("plain", options)
+    }
+
+    fn gen_array(&mut self, obj: &Map<String, Value>, path: &str, depth: usize) -> Result<SymIdx> {
+        let items = match obj.get("items") {
+            Some(items) => items,
+            None => bail!("{path}: array schema requires 'items'"),
+        };
+        let min = opt_usize(obj, "minItems", path)?.unwrap_or(0);
+        let max = opt_usize(obj, "maxItems", path)?;
+        check_bounds(min, max, "minItems", "maxItems", path)?;
+
+        let open = self.literal(b"[");
+        let close = self.literal(b"]");
+        if max == Some(0) {
+            return Ok(self.sequence("array", vec![open, close]));
+        }
+
+        let item = self.gen_value(items, &format!("{path}/items"), depth + 1)?;
+        let mut first = self.newline(depth + 1);
+        first.push(item);
+        let first = self.sequence("item", first);
+        let mut next = self.space();
+        next.push(self.literal(b","));
+        next.extend(self.newline(depth + 1));
+        next.push(item);
+        let next = self.sequence("item", next);
+        let rest = self.repeat(
+            next,
+            min.saturating_sub(1),
+            max.map(|m| m.saturating_sub(1)),
+        );
+        let mut body = vec![first, rest];
+        body.extend(self.newline(depth));
+        let mut body = self.sequence("items", body);
+        if min == 0 {
+            body = self.optional(body);
+        }
+        Ok(self.sequence("array", vec![open, body, close]))
+    }
+
+    fn gen_object(&mut self, obj: &Map<String, Value>, path: &str, depth: usize) -> Result<SymIdx> {
+        match obj.get("additionalProperties") {
+            None | Some(Value::Bool(false)) => {}
+            Some(_) => bail!("{path}: only 'additionalProperties: false' is supported"),
+        }
+        let empty = Map::new();
+        let props = match obj.get("properties") {
+            None => &empty,
+            Some(Value::Object(props)) => props,
+            Some(_) => bail!("{path}: 'properties' must be an object"),
+        };
+        let required = match obj.get("required") {
+            None => vec![],
+            Some(Value::Array(r)) => r
+                .iter()
+                .map(|v| match v.as_str() {
+                    Some(s) if props.contains_key(s) => Ok(s.to_string()),
+                    _ => bail!("{path}: 'required' must list names from 'properties'"),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => bail!("{path}: 'required' must be an array"),
+        };
+
+        let open = self.literal(b"{");
+        let close = self.literal(b"}");
+        if props.is_empty() {
+            return Ok(self.sequence("object", vec![open, close]));
+        }
+
+        let colon = if self.ws.is_some() {
+            let mut rhs = self.space();
+            rhs.push(self.literal(b":"));
+            rhs.extend(self.space());
+            self.sequence("colon", rhs)
+        } else {
+            self.literal(if self.options.pretty {
+                &b": "[..]
+            } else {
+                &b":"[..]
+            })
+        };
+        let mut entries = vec![];
+        for (name, schema) in props.iter() {
+            let key = self.literal(serde_json::to_string(name)?.as_bytes());
+            let value = self.gen_value(schema, &format!("{path}/properties/{name}"), depth + 1)?;
+            entries.push((required.contains(name), key, value));
+        }
+
+        // rest[i][c] - entries from i onwards, with c saying if a comma is needed before the first one
+        let end = self.sequence("props", vec![]);
+        let mut rest = [end, end];
+        for (is_required, key, value) in entries.into_iter().rev() {
+            let mut next = [end, end];
+            for (c, slot) in next.iter_mut().enumerate() {
+                let mut rhs = vec![];
+                if c == 1 {
+                    rhs.extend(self.space());
+                    rhs.push(self.literal(b","));
+                }
+                rhs.extend(self.newline(depth + 1));
+                rhs.extend([key, colon, value, rest[1]]);
+                let present = self.sequence("prop", rhs);
+                *slot = if is_required {
+                    present
+                } else {
+                    self.select("prop", vec![present, rest[c]])
+                };
+            }
+            rest = next;
+        }
+
+        let mut rhs = vec![open, rest[0]];
+        rhs.extend(self.newline(depth));
+        rhs.push(close);
+        Ok(self.sequence("object", rhs))
+    }
+}
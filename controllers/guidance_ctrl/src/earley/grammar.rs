@@ -1,9 +1,19 @@
-use std::fmt::Debug;
-
-use aici_abi::{svob::SimpleVob, toktree::SpecialToken};
-
-use super::ByteSet;
-use rustc_hash::FxHashMap;
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+use aici_abi::{
+    svob::SimpleVob,
+    toktree::{SpecialToken, TokTrie},
+};
+
+use super::{
+    lexeme::{LexState, LexemeDfa},
+    ByteSet,
+};
+use anyhow::{bail, Result};
+use rustc_hash::{FxHashMap, FxHasher};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SymIdx(u32);
@@ -58,6 +68,8 @@ pub struct SymbolProps {
     pub capture_name: Option<String>,
     pub hidden: bool,
     pub model_variable: Option<ModelVariable>,
+    /// Matched with a DFA, rather than with Earley items (see Grammar::make_lexeme()).
+    pub lexeme: bool,
 }
 
 impl Default for SymbolProps {
@@ -68,6 +80,7 @@ impl Default for SymbolProps {
             max_tokens: usize::MAX,
             model_variable: None,
             capture_name: None,
+            lexeme: false,
         }
     }
 }
@@ -79,9 +92,14 @@ impl SymbolProps {
             || self.hidden
             || self.max_tokens < usize::MAX
             || self.capture_name.is_some()
+            || self.lexeme
     }
 }
 
+/// Runs of at least this many bytes in a rule are matched as lexemes (see compile()).
+const LITERAL_LEXEME_MIN_LEN: usize = 32;
+
+#[derive(Clone)]
 struct Symbol {
     idx: SymIdx,
     name: String,
@@ -90,6 +108,7 @@ struct Symbol {
     props: SymbolProps,
 }
 
+#[derive(Clone)]
 struct Rule {
     lhs: SymIdx,
     rhs: Vec<SymIdx>,
@@ -117,11 +136,14 @@ impl SymName {
     }
 }
 
+#[derive(Clone)]
 pub struct Grammar {
     symbols: Vec<Symbol>,
     symbol_by_name: FxHashMap<String, SymIdx>,
     byte_terminals: FxHashMap<ByteSet, SymIdx>,
     model_variables: FxHashMap<String, SymIdx>,
+    // see reference()
+    references: FxHashMap<String, SymIdx>,
 }
 
 impl Grammar {
@@ -131,6 +153,7 @@ impl Grammar {
             symbol_by_name: FxHashMap::default(),
             byte_terminals: FxHashMap::default(),
             model_variables: FxHashMap::default(),
+            references: FxHashMap::default(),
         };
         let _ = r.symbol("_start");
         r
@@ -167,6 +190,166 @@ impl Grammar {
         }
     }
 
+    /// Symbol standing for the grammar inserted with insert_subgrammar() under `name`;
+    /// it can be used before the insertion, but compile() fails if it never happens.
+    pub fn reference(&mut self, name: &str) -> SymIdx {
+        match self.references.get(name) {
+            Some(sym) => *sym,
+            None => {
+                let sym = self.fresh_symbol(format!("@{}", name).as_str());
+                self.references.insert(name.to_string(), sym);
+                sym
+            }
+        }
+    }
+
+    /// Names of references (see reference()) with no grammar inserted yet, sorted.
+    pub fn unresolved_references(&self) -> Vec<String> {
+        let mut r = self
+            .references
+            .iter()
+            .filter(|(_, sym)| self.sym_data(**sym).rules.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        r.sort();
+        r
+    }
+
+    /// Copy the rules of `sub` into this grammar, with its symbols renamed to `name::...`,
+    /// and bind reference(name) to its start symbol. References in `sub` become references
+    /// in this grammar, so sub-grammars can refer to each other (and to themselves).
+    pub fn insert_subgrammar(&mut self, name: &str, sub: &Grammar) -> Result<()> {
+        let target = self.reference(name);
+        if !self.sym_data(target).rules.is_empty() {
+            bail!("grammar '{}' is inserted twice", name);
+        }
+        // the start symbol keeps its props (e.g., a capture), as does the reference
+        let start = self.fresh_symbol(&format!("{}::{}", name, sub.sym_name(sub.start())));
+        self.sym_data_mut(start).props = sub.sym_data(sub.start()).props.clone();
+        self.add_rule(target, vec![start]);
+        let mut sym_map = FxHashMap::default();
+        sym_map.insert(sub.start(), start);
+        for (ref_name, sym) in &sub.references {
+            sym_map.insert(*sym, self.reference(ref_name));
+        }
+        for (var_name, sym) in &sub.model_variables {
+            let r = self.model_variable(var_name);
+            // keep special tokens resolved in `sub`
+            self.sym_data_mut(r).props.model_variable =
+                sub.sym_data(*sym).props.model_variable.clone();
+            sym_map.insert(*sym, r);
+        }
+        for sym in &sub.symbols {
+            if sym_map.contains_key(&sym.idx) {
+                continue;
+            }
+            let r = match &sym.bytes {
+                Some(bytes) => self.terminal(bytes),
+                None => {
+                    let r = self.fresh_symbol(&format!("{}::{}", name, sym.name));
+                    self.sym_data_mut(r).props = sym.props.clone();
+                    r
+                }
+            };
+            sym_map.insert(sym.idx, r);
+        }
+        for sym in &sub.symbols {
+            for rule in &sym.rules {
+                let rhs = rule.rhs.iter().map(|s| sym_map[s]).collect();
+                self.add_rule(sym_map[&sym.idx], rhs);
+            }
+        }
+        Ok(())
+    }
+
+    /// A cycle of references (see reference()) that consumes no bytes: `a` derives `b`,
+    /// which derives `a`, with everything around them empty. Returns the names along it,
+    /// with the first one repeated at the end. Other recursion between references
+    /// (e.g., `a` matching "(" b ")", and `b` a list of `a`) is fine.
+    pub fn no_progress_cycle(&self) -> Option<Vec<String>> {
+        let mut nullable = vec![false; self.symbols.len()];
+        loop {
+            let mut changed = false;
+            for sym in &self.symbols {
+                let idx = sym.idx.0 as usize;
+                if !nullable[idx]
+                    && sym
+                        .rules
+                        .iter()
+                        .any(|r| r.rhs.iter().all(|s| nullable[s.0 as usize]))
+                {
+                    nullable[idx] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // the symbols `sym` derives with everything around them empty
+        let unit_children = |sym: SymIdx| {
+            let mut r = vec![];
+            for rule in &self.sym_data(sym).rules {
+                for (idx, s) in rule.rhs.iter().enumerate() {
+                    let others_nullable = rule
+                        .rhs
+                        .iter()
+                        .enumerate()
+                        .all(|(j, o)| j == idx || nullable[o.0 as usize]);
+                    if others_nullable {
+                        r.push(*s);
+                    }
+                }
+            }
+            r
+        };
+
+        let ref_names: FxHashMap<SymIdx, &String> =
+            self.references.iter().map(|(n, s)| (*s, n)).collect();
+        let mut refs = self.references.iter().collect::<Vec<_>>();
+        refs.sort_by_key(|(name, _)| *name);
+        for (name, start) in refs {
+            let mut prev = FxHashMap::default();
+            let mut todo = vec![*start];
+            while let Some(sym) = todo.pop() {
+                for next in unit_children(sym) {
+                    if next == *start {
+                        let mut path = vec![sym];
+                        while *path.last().unwrap() != *start {
+                            path.push(prev[path.last().unwrap()]);
+                        }
+                        let mut cycle = path
+                            .iter()
+                            .rev()
+                            .filter_map(|s| ref_names.get(s).map(|n| n.to_string()))
+                            .collect::<Vec<_>>();
+                        cycle.push(name.clone());
+                        return Some(cycle);
+                    }
+                    if !prev.contains_key(&next) {
+                        prev.insert(next, sym);
+                        todo.push(next);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Special tokens (chat markers and the like) are never allowed by the grammar,
+    /// except where it has a model variable named after the token (e.g., `<|im_start|>`).
+    /// The token doesn't advance the grammar, as it has no bytes.
+    pub fn resolve_special_tokens(&mut self, trie: &TokTrie) {
+        for sym in self.symbols.iter_mut() {
+            if let Some(ModelVariable::Other(name)) = &sym.props.model_variable {
+                if let Some(tok) = trie.special_token_by_name(name) {
+                    let kind = trie.special_kind(tok).unwrap();
+                    sym.props.model_variable = Some(ModelVariable::SpecialToken(kind));
+                }
+            }
+        }
+    }
+
     pub fn terminal(&mut self, bytes: &ByteSet) -> SymIdx {
         match self.byte_terminals.get(bytes) {
             Some(sym) => *sym,
@@ -183,10 +366,96 @@ impl Grammar {
         }
     }
 
+    /// Optional run of whitespace (spaces, tabs, newlines, carriage returns),
+    /// at most `max_len` bytes long. Since it matches several bytes, it's never
+    /// forced (see Parser::force_bytes()), and the model picks the formatting.
+    pub fn whitespace(&mut self, max_len: usize) -> SymIdx {
+        let space = self.terminal(&ByteSet::from_bytes(b" \t\n\r"));
+        let mut tail = self.fresh_symbol("ws");
+        self.add_rule(tail, vec![]);
+        for _ in 0..max_len {
+            let sym = self.fresh_symbol("ws");
+            self.add_rule(sym, vec![]);
+            self.add_rule(sym, vec![space, tail]);
+            tail = sym;
+        }
+        tail
+    }
+
+    /// Rules for `lhs` to match one of the byte strings in `options` (the empty string
+    /// included), compiled as a trie: options share the symbols of their common prefix,
+    /// so the parser follows a single item until the options differ, forces only the
+    /// bytes they all have in common, and then allows exactly the bytes that tell them
+    /// apart (and whatever follows `lhs`, when an option ends there).
+    pub fn add_string_choice(&mut self, lhs: SymIdx, options: &[&[u8]]) {
+        let mut options = options.to_vec();
+        options.sort();
+        options.dedup();
+        // (symbol, options, bytes of the options already matched before the symbol)
+        let mut todo = vec![(lhs, &options[..], 0)];
+        while let Some((sym, options, depth)) = todo.pop() {
+            let mut start = 0;
+            while start < options.len() {
+                let first = &options[start][depth..];
+                if first.is_empty() {
+                    // sorted, so this is the first one of the group
+                    self.add_rule(sym, vec![]);
+                    start += 1;
+                    continue;
+                }
+                let group = &options[start..];
+                let end = start
+                    + group
+                        .iter()
+                        .take_while(|o| o.get(depth) == Some(&first[0]))
+                        .count();
+                let common = options[start + 1..end]
+                    .iter()
+                    .map(|o| common_prefix_len(first, &o[depth..]))
+                    .min()
+                    .unwrap_or(first.len());
+                let mut rhs = first[..common]
+                    .iter()
+                    .map(|b| self.terminal(&ByteSet::from_byte(*b)))
+                    .collect::<Vec<_>>();
+                if end - start > 1 {
+                    let name = format!("{}_trie", self.sym_name(lhs));
+                    let child = self.fresh_symbol(&name);
+                    rhs.push(child);
+                    todo.push((child, &options[start..end], depth + common));
+                }
+                self.add_rule(sym, rhs);
+                start = end;
+            }
+        }
+    }
+
     pub fn sym_name(&self, sym: SymIdx) -> &str {
         &self.symbols[sym.0 as usize].name
     }
 
+    pub(super) fn sym_props(&self, sym: SymIdx) -> &SymbolProps {
+        &self.sym_data(sym).props
+    }
+
+    pub(super) fn terminal_bytes(&self, sym: SymIdx) -> Option<&ByteSet> {
+        self.sym_data(sym).bytes.as_ref()
+    }
+
+    pub(super) fn rules_rhs(&self, sym: SymIdx) -> impl Iterator<Item = &[SymIdx]> {
+        self.sym_data(sym).rules.iter().map(|r| r.rhs.as_slice())
+    }
+
+    /// Match the strings of `sym` with a DFA: the parser then doesn't track the rules
+    /// inside `sym`, only the DFA state. The sub-grammar of `sym` has to be regular
+    /// (only direct left or right recursion), and can't have model variables or
+    /// special symbols (captures, commit points, ...) inside; compile() fails otherwise.
+    /// When the grammar allows `sym` to both continue and end, the parser follows both.
+    pub fn make_lexeme(&mut self, sym: SymIdx) {
+        assert!(!self.sym_data(sym).is_terminal());
+        self.sym_data_mut(sym).props.lexeme = true;
+    }
+
     fn rule_to_string(&self, rule: &Rule, dot: Option<usize>) -> String {
         let ldata = self.sym_data(rule.lhs());
         let dot_data = rule
@@ -219,6 +488,16 @@ impl Grammar {
         r
     }
 
+    // references that are still unresolved in `other` (resolved ones may be inlined)
+    fn copy_references(&mut self, other: &Grammar) {
+        for name in other.unresolved_references() {
+            let sym = other.references[&name];
+            if let Some(r) = self.symbol_by_name.get(other.sym_name(sym)) {
+                self.references.insert(name, *r);
+            }
+        }
+    }
+
     fn rule_shape(&self, r: &Rule) -> Vec<Option<SymIdx>> {
         let mut shape = Vec::new();
         let mut had_term = false;
@@ -255,10 +534,9 @@ impl Grammar {
                     .enumerate()
                     .map(|(i, s)| {
                         if shape[i].is_none() {
-                            let terminals = rules
-                                .iter()
-                                .map(|r| self.sym_data(r.rhs[i]).bytes.clone().unwrap());
-                            outp.terminal(&ByteSet::from_sum(terminals))
+                            let terminals =
+                                rules.iter().map(|r| self.sym_data(r.rhs[i]).bytes.unwrap());
+                            outp.terminal(&terminals.collect())
                         } else {
                             outp.copy_from(self, *s)
                         }
@@ -267,6 +545,7 @@ impl Grammar {
                 outp.add_rule(lhs, rhs);
             }
         }
+        outp.copy_references(self);
         outp
     }
 
@@ -336,17 +615,113 @@ impl Grammar {
                 outp.add_rule(lhs, rhs);
             }
         }
+        outp.copy_references(self);
         outp
     }
 
+    /// Hash of the symbols and rules; grammars with the same hash compile to the same
+    /// CGrammar, so e.g. token sets computed for one apply to the other. FxHasher
+    /// is used, as the hash is shared between processes, which may not agree on
+    /// DefaultHasher.
+    pub fn content_hash(&self) -> u64 {
+        let mut h = FxHasher::default();
+        for sym in &self.symbols {
+            sym.name.hash(&mut h);
+            sym.bytes.hash(&mut h);
+            sym.props.hash(&mut h);
+            sym.rules.len().hash(&mut h);
+            for rule in &sym.rules {
+                rule.rhs.hash(&mut h);
+            }
+        }
+        h.finish()
+    }
+
     pub fn optimize(&self) -> Self {
         self.expand_shortcuts()
             .collapse_terminals()
             .expand_shortcuts()
     }
 
-    pub fn compile(&self) -> CGrammar {
-        CGrammar::from_grammar(self)
+    /// Fails if some references (see reference()) were never resolved, or some lexemes
+    /// (see make_lexeme()) are not regular. Long literals (runs of single bytes in a rule)
+    /// are made into lexemes.
+    pub fn compile(&self) -> Result<CGrammar> {
+        self.check_references()?;
+        CGrammar::from_grammar(&self.with_lexemes(), true)
+    }
+
+    /// Like compile(), but lexemes are parsed like the rest of the grammar.
+    pub fn compile_without_lexemes(&self) -> Result<CGrammar> {
+        self.check_references()?;
+        CGrammar::from_grammar(self, false)
+    }
+
+    fn check_references(&self) -> Result<()> {
+        let missing = self.unresolved_references();
+        if !missing.is_empty() {
+            bail!(
+                "missing grammar(s) for reference(s): {}",
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn with_lexemes(&self) -> Self {
+        let mut outp = self.clone();
+        for idx in 0..outp.symbols.len() {
+            for rule_idx in 0..outp.symbols[idx].rules.len() {
+                outp.extract_literals(SymIdx(idx as u32), rule_idx);
+            }
+        }
+        // the DFA doesn't handle props; they stay on a wrapper symbol
+        for idx in 0..outp.symbols.len() {
+            let sym = SymIdx(idx as u32);
+            let mut props = outp.sym_data(sym).props.clone();
+            if !props.lexeme {
+                continue;
+            }
+            props.lexeme = false;
+            if sym == outp.start() || props.is_special() {
+                let name = format!("{}_lexeme", outp.sym_name(sym));
+                let inner = outp.fresh_symbol(&name);
+                let rules = std::mem::take(&mut outp.sym_data_mut(sym).rules);
+                for rule in rules {
+                    outp.add_rule(inner, rule.rhs);
+                }
+                outp.make_lexeme(inner);
+                outp.sym_data_mut(sym).props = props;
+                outp.add_rule(sym, vec![inner]);
+            }
+        }
+        outp
+    }
+
+    fn extract_literals(&mut self, sym: SymIdx, rule_idx: usize) {
+        let rhs = self.sym_data(sym).rules[rule_idx].rhs.clone();
+        let mut outp = Vec::new();
+        let mut i = 0;
+        while i < rhs.len() {
+            let len = rhs[i..]
+                .iter()
+                .take_while(|s| {
+                    let bytes = self.sym_data(**s).bytes.as_ref();
+                    bytes.is_some_and(|b| b.single_byte().is_some())
+                })
+                .count();
+            if len < LITERAL_LEXEME_MIN_LEN {
+                outp.extend_from_slice(&rhs[i..i + len.max(1)]);
+                i += len.max(1);
+                continue;
+            }
+            let lit = self.fresh_symbol("literal");
+            self.add_rule(lit, rhs[i..i + len].to_vec());
+            self.make_lexeme(lit);
+            outp.push(lit);
+            i += len;
+        }
+        self.sym_data_mut(sym).rules[rule_idx].rhs = outp;
     }
 
     pub fn apply_props(&mut self, sym: SymIdx, mut props: SymbolProps) {
@@ -391,6 +766,10 @@ impl Grammar {
     }
 }
 
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
 impl Debug for Grammar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Grammar:")?;
@@ -478,6 +857,8 @@ pub struct CSymbol {
     pub props: SymbolProps,
     pub rules: Vec<RuleIdx>,
     pub sym_flags: SymFlags,
+    /// Start state in CGrammar::lexemes(), for lexemes.
+    pub lexeme: Option<LexState>,
 }
 
 #[derive(Clone, Copy)]
@@ -529,6 +910,7 @@ pub struct CGrammar {
     rule_idx_to_sym_idx: Vec<CSymIdx>,
     rule_idx_to_sym_flags: Vec<SymFlags>,
     terminals_by_byte: Vec<SimpleVob>,
+    lexemes: LexemeDfa,
 }
 
 const RULE_SHIFT: usize = 2;
@@ -592,7 +974,11 @@ impl CGrammar {
         &self.sym_data(sym).rules
     }
 
-    fn from_grammar(grammar: &Grammar) -> Self {
+    pub fn lexemes(&self) -> &LexemeDfa {
+        &self.lexemes
+    }
+
+    fn from_grammar(grammar: &Grammar, use_lexemes: bool) -> Result<Self> {
         let mut outp = CGrammar {
             start_symbol: CSymIdx::NULL, // replaced
             terminals: vec![ByteSet::new()],
@@ -605,11 +991,13 @@ impl CGrammar {
                 rules: vec![],
                 props: SymbolProps::default(),
                 sym_flags: SymFlags(0),
+                lexeme: None,
             }],
             rules: vec![CSymIdx::NULL], // make sure RuleIdx::NULL is invalid
             rule_idx_to_sym_idx: vec![],
             terminals_by_byte: vec![],
             rule_idx_to_sym_flags: vec![],
+            lexemes: LexemeDfa::default(),
         };
         let mut sym_map = FxHashMap::default();
         let (single, multi) = grammar
@@ -635,28 +1023,45 @@ impl CGrammar {
                 rules: vec![],
                 props: sym.props.clone(),
                 sym_flags: SymFlags(0),
+                lexeme: None,
             });
             sym_map.insert(sym.idx, CSymIdx(idx));
         }
+        let mut lexemes = vec![];
         for sym in &grammar.symbols {
             if sym.is_terminal() {
                 continue;
             }
             let idx = outp.symbols.len() as u16;
+            let mut props = sym.props.clone();
+            props.lexeme &= use_lexemes;
+            if props.lexeme {
+                lexemes.push((sym.idx, CSymIdx(idx)));
+            }
             outp.symbols.push(CSymbol {
                 idx: CSymIdx(idx),
                 name: sym.name.clone(),
                 is_terminal: false,
                 is_nullable: sym.rules.iter().any(|r| r.rhs.is_empty()),
                 rules: vec![],
-                props: sym.props.clone(),
+                props,
                 sym_flags: SymFlags(0),
+                lexeme: None,
             });
             sym_map.insert(sym.idx, CSymIdx(idx));
         }
         outp.start_symbol = sym_map[&grammar.start()];
+
+        let (dfa, starts) = LexemeDfa::build(grammar, &lexemes)?;
+        for ((_, csym), start) in lexemes.iter().zip(starts) {
+            let sym = outp.sym_data_mut(*csym);
+            sym.lexeme = Some(start);
+            sym.is_nullable = dfa.is_accepting(start);
+        }
+        outp.lexemes = dfa;
+
         for sym in &grammar.symbols {
-            if sym.is_terminal() {
+            if sym.is_terminal() || (sym.props.lexeme && use_lexemes) {
                 continue;
             }
             let idx = sym_map[&sym.idx];
@@ -722,7 +1127,7 @@ impl CGrammar {
             }
             outp.terminals_by_byte.push(v);
         }
-        outp
+        Ok(outp)
     }
 
     pub fn sym_name(&self, sym: CSymIdx) -> &str {
@@ -799,7 +1204,7 @@ fn rule_to_string(
         }
     }
     format!(
-        "{:15} ⇦ {}{}{}{}",
+        "{:15} ⇦ {}{}{}{}{}",
         lhs,
         outp.join(" "),
         if props.commit_point {
@@ -816,6 +1221,7 @@ fn rule_to_string(
         } else {
             ""
         },
+        if props.lexeme { " LEXEME" } else { "" },
         if props.max_tokens < 1000 {
             format!(" max_tokens={}", props.max_tokens)
         } else {
@@ -0,0 +1,340 @@
+//! Lexemes are symbols with a regular sub-grammar (see Grammar::make_lexeme()), compiled
+//! to a DFA. The parser runs the DFA over the bytes of a lexeme, instead of creating
+//! Earley items for each of them.
+
+use anyhow::{bail, Result};
+use rustc_hash::FxHashMap;
+
+use super::{
+    grammar::{CSymIdx, Grammar, SymIdx},
+    ByteSet,
+};
+
+/// State of the DFA of all the lexemes of a grammar.
+pub type LexState = u32;
+
+/// No bytes can follow; lexemes in this state are dropped.
+pub const DEAD: LexState = u32::MAX;
+
+const MAX_NFA_STATES: usize = 100_000;
+const MAX_DFA_STATES: usize = 20_000;
+
+#[derive(Clone)]
+struct StateInfo {
+    lexeme: CSymIdx,
+    accepting: bool,
+    allowed: ByteSet,
+}
+
+/// Transition table of the lexemes; each lexeme has its own start state.
+/// Bytes are mapped to classes of bytes that no lexeme tells apart.
+#[derive(Clone)]
+pub struct LexemeDfa {
+    byte_class: Vec<u8>,
+    num_classes: usize,
+    // transitions[state * num_classes + class]
+    transitions: Vec<LexState>,
+    states: Vec<StateInfo>,
+}
+
+impl Default for LexemeDfa {
+    fn default() -> Self {
+        LexemeDfa {
+            byte_class: vec![0; 256],
+            num_classes: 1,
+            transitions: vec![],
+            states: vec![],
+        }
+    }
+}
+
+impl LexemeDfa {
+    #[inline(always)]
+    pub fn next(&self, state: LexState, byte: u8) -> LexState {
+        let class = self.byte_class[byte as usize] as usize;
+        self.transitions[state as usize * self.num_classes + class]
+    }
+
+    /// Whether the bytes so far form a complete lexeme.
+    pub fn is_accepting(&self, state: LexState) -> bool {
+        self.states[state as usize].accepting
+    }
+
+    pub fn lexeme(&self, state: LexState) -> CSymIdx {
+        self.states[state as usize].lexeme
+    }
+
+    /// Bytes with a transition to a live state.
+    pub fn allowed(&self, state: LexState) -> &ByteSet {
+        &self.states[state as usize].allowed
+    }
+
+    /// Whether any bytes can follow.
+    pub fn can_advance(&self, state: LexState) -> bool {
+        !self.allowed(state).is_empty()
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Compile the lexemes (given with their compiled symbols); returns the start state
+    /// of each. Fails if the sub-grammar of some lexeme is not regular (only direct left or
+    /// right recursion is allowed), or uses captures, commit points, max_tokens, or model
+    /// variables.
+    pub(super) fn build(
+        grammar: &Grammar,
+        lexemes: &[(SymIdx, CSymIdx)],
+    ) -> Result<(Self, Vec<LexState>)> {
+        let mut nfas = Vec::new();
+        for (sym, _) in lexemes {
+            let mut b = NfaBuilder {
+                grammar,
+                lexeme: *sym,
+                nfa: Nfa::default(),
+                stack: Vec::new(),
+            };
+            let entry = b.nfa.add_state()?;
+            let exit = b.nfa.add_state()?;
+            b.add_symbol(*sym, entry, exit)?;
+            nfas.push((b.nfa, entry, exit));
+        }
+
+        let (byte_class, class_bytes) = byte_classes(nfas.iter().map(|n| &n.0));
+        let mut dfa = LexemeDfa {
+            byte_class,
+            num_classes: class_bytes.len(),
+            transitions: Vec::new(),
+            states: Vec::new(),
+        };
+        let mut starts = Vec::new();
+        for ((nfa, entry, exit), (_, csym)) in nfas.iter().zip(lexemes) {
+            starts.push(dfa.add_lexeme(nfa, *entry, *exit, *csym, &class_bytes)?);
+        }
+        dfa.prune_dead_states(&class_bytes);
+        Ok((dfa, starts))
+    }
+
+    // subset construction
+    fn add_lexeme(
+        &mut self,
+        nfa: &Nfa,
+        entry: usize,
+        exit: usize,
+        lexeme: CSymIdx,
+        class_bytes: &[ByteSet],
+    ) -> Result<LexState> {
+        let first = self.states.len();
+        let mut state_ids: FxHashMap<Vec<usize>, LexState> = FxHashMap::default();
+        let mut sets = vec![nfa.closure(vec![entry])];
+        state_ids.insert(sets[0].clone(), first as LexState);
+        let mut idx = 0;
+        while idx < sets.len() {
+            if first + sets.len() > MAX_DFA_STATES {
+                bail!("lexeme DFAs have too many states");
+            }
+            let set = sets[idx].clone();
+            self.states.push(StateInfo {
+                lexeme,
+                accepting: set.contains(&exit),
+                allowed: ByteSet::new(),
+            });
+            for bytes in class_bytes {
+                let byte = bytes.first_byte().unwrap();
+                let targets = set
+                    .iter()
+                    .flat_map(|s| nfa.edges[*s].iter())
+                    .filter(|(b, _)| b.contains(byte))
+                    .map(|(_, t)| *t)
+                    .collect::<Vec<_>>();
+                let next = if targets.is_empty() {
+                    DEAD
+                } else {
+                    let targets = nfa.closure(targets);
+                    match state_ids.get(&targets) {
+                        Some(id) => *id,
+                        None => {
+                            let id = (first + sets.len()) as LexState;
+                            state_ids.insert(targets.clone(), id);
+                            sets.push(targets);
+                            id
+                        }
+                    }
+                };
+                self.transitions.push(next);
+            }
+            idx += 1;
+        }
+        Ok(first as LexState)
+    }
+
+    // states that can't reach an accepting one are replaced with DEAD,
+    // so that the parser drops lexemes as soon as they can't complete
+    fn prune_dead_states(&mut self, class_bytes: &[ByteSet]) {
+        let n = self.num_classes;
+        let mut live = self.states.iter().map(|s| s.accepting).collect::<Vec<_>>();
+        loop {
+            let mut changed = false;
+            for state in 0..self.states.len() {
+                if !live[state]
+                    && self.transitions[state * n..(state + 1) * n]
+                        .iter()
+                        .any(|t| *t != DEAD && live[*t as usize])
+                {
+                    live[state] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        for state in 0..self.states.len() {
+            let mut allowed = ByteSet::new();
+            for (class, bytes) in class_bytes.iter().enumerate() {
+                let t = &mut self.transitions[state * n + class];
+                if *t != DEAD && !live[*t as usize] {
+                    *t = DEAD;
+                }
+                if *t != DEAD {
+                    allowed |= *bytes;
+                }
+            }
+            self.states[state].allowed = allowed;
+        }
+    }
+}
+
+/// Partition of the bytes into classes, such that each byte set of the NFAs is a union
+/// of classes; returns the class of each byte, and the bytes of each class.
+fn byte_classes<'a>(nfas: impl Iterator<Item = &'a Nfa>) -> (Vec<u8>, Vec<ByteSet>) {
+    let mut sets: Vec<&ByteSet> = Vec::new();
+    for nfa in nfas {
+        for (bytes, _) in nfa.edges.iter().flatten() {
+            if !sets.contains(&bytes) {
+                sets.push(bytes);
+            }
+        }
+    }
+    let mut class_of_signature: FxHashMap<Vec<bool>, u8> = FxHashMap::default();
+    let mut class_bytes: Vec<ByteSet> = Vec::new();
+    let mut byte_class = Vec::with_capacity(256);
+    for b in 0..=255u8 {
+        let signature = sets.iter().map(|s| s.contains(b)).collect::<Vec<_>>();
+        let class = *class_of_signature.entry(signature).or_insert_with(|| {
+            class_bytes.push(ByteSet::new());
+            (class_bytes.len() - 1) as u8
+        });
+        class_bytes[class as usize].insert(b);
+        byte_class.push(class);
+    }
+    (byte_class, class_bytes)
+}
+
+#[derive(Default)]
+struct Nfa {
+    eps: Vec<Vec<usize>>,
+    edges: Vec<Vec<(ByteSet, usize)>>,
+}
+
+impl Nfa {
+    fn add_state(&mut self) -> Result<usize> {
+        if self.eps.len() >= MAX_NFA_STATES {
+            bail!("lexeme is too large");
+        }
+        self.eps.push(Vec::new());
+        self.edges.push(Vec::new());
+        Ok(self.eps.len() - 1)
+    }
+
+    /// States reachable with epsilon moves, sorted.
+    fn closure(&self, mut todo: Vec<usize>) -> Vec<usize> {
+        let mut r = Vec::new();
+        while let Some(s) = todo.pop() {
+            if !r.contains(&s) {
+                r.push(s);
+                todo.extend_from_slice(&self.eps[s]);
+            }
+        }
+        r.sort();
+        r
+    }
+}
+
+struct NfaBuilder<'a> {
+    grammar: &'a Grammar,
+    lexeme: SymIdx,
+    nfa: Nfa,
+    // symbols being expanded, with their (private) entry and exit states
+    stack: Vec<(SymIdx, usize, usize)>,
+}
+
+impl NfaBuilder<'_> {
+    /// Add paths for the strings of `sym` from state `from` to state `to`.
+    fn add_symbol(&mut self, sym: SymIdx, from: usize, to: usize) -> Result<()> {
+        if let Some(bytes) = self.grammar.terminal_bytes(sym) {
+            self.nfa.edges[from].push((bytes.clone(), to));
+            return Ok(());
+        }
+        let props = self.grammar.sym_props(sym);
+        let mut props = props.clone();
+        props.lexeme = false;
+        if props.model_variable.is_some() || (sym != self.lexeme && props.is_special()) {
+            bail!(
+                "lexeme {}: {} can't be a part of a lexeme",
+                self.grammar.sym_name(self.lexeme),
+                self.grammar.sym_name(sym)
+            );
+        }
+
+        // sym directly within itself; the first or last element of the rule only
+        if let Some(&(top, entry, exit)) = self.stack.last() {
+            if top == sym {
+                if from == entry && to == exit {
+                    // sym ::= sym
+                } else if to == exit {
+                    self.nfa.eps[from].push(entry);
+                } else if from == entry {
+                    self.nfa.eps[exit].push(to);
+                } else {
+                    self.not_regular(sym)?;
+                }
+                return Ok(());
+            }
+        }
+        if self.stack.iter().any(|(s, _, _)| *s == sym) {
+            self.not_regular(sym)?;
+        }
+
+        let entry = self.nfa.add_state()?;
+        let exit = self.nfa.add_state()?;
+        self.nfa.eps[from].push(entry);
+        self.nfa.eps[exit].push(to);
+        self.stack.push((sym, entry, exit));
+        for rhs in self.grammar.rules_rhs(sym) {
+            let mut prev = entry;
+            for (idx, elt) in rhs.iter().enumerate() {
+                let next = if idx + 1 == rhs.len() {
+                    exit
+                } else {
+                    self.nfa.add_state()?
+                };
+                self.add_symbol(*elt, prev, next)?;
+                prev = next;
+            }
+            if rhs.is_empty() {
+                self.nfa.eps[entry].push(exit);
+            }
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn not_regular(&self, sym: SymIdx) -> Result<()> {
+        bail!(
+            "lexeme {} is not regular: {} is recursive",
+            self.grammar.sym_name(self.lexeme),
+            self.grammar.sym_name(sym)
+        )
+    }
+}
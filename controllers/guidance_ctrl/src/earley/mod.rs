@@ -1,13 +1,21 @@
-mod byteset;
 mod from_guidance;
+mod from_json_schema;
 mod grammar;
+mod lexeme;
 mod parser;
+mod validate;
 
-pub use byteset::ByteSet;
+pub use aici_abi::bytes::ByteSet;
 pub use from_guidance::earley_grm_from_guidance;
+pub use from_json_schema::{
+    earley_grm_for_tools, earley_grm_from_json_schema, JsonCompileOptions, ToolSpec,
+};
 #[allow(unused_imports)]
-pub use grammar::{Grammar, ModelVariable};
-pub use parser::{Parser, ParseResult};
+pub use grammar::{CGrammar, Grammar, ModelVariable, SymIdx, SymbolProps};
+pub use parser::{
+    CaptureSpan, ParseRejection, ParseResult, Parser, ParserCheckpoint, RejectInfo, TerminalDesc,
+};
+pub use validate::{validate_grammar, DeadEnd, ValidationConfig, ValidationReport};
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod bench;
@@ -1,11 +1,23 @@
-use std::{fmt::Debug, hash::Hash, ops::Range, vec};
+use std::{
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::{Arc, Weak},
+    vec,
+};
 
 use aici_abi::{
+    bytes::{byte_to_string, ByteSet},
     toktree::{Recognizer, SpecialToken, TokTrie},
     TokenId,
 };
+use anyhow::{bail, Result};
+use rustc_hash::{FxHashSet, FxHasher};
 
-use super::grammar::{CGrammar, CSymIdx, ModelVariable, RuleIdx, SimpleHash};
+use super::{
+    grammar::{CGrammar, CSymIdx, ModelVariable, RuleIdx, SimpleHash},
+    lexeme::{LexState, DEAD},
+};
 
 const DEBUG: bool = false;
 const INFO: bool = true;
@@ -34,6 +46,13 @@ struct Item {
     data: u64,
 }
 
+/// Position in a lexeme (see Grammar::make_lexeme()) started at row `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct LexItem {
+    state: LexState,
+    start: u32,
+}
+
 #[derive(Debug, Default)]
 pub struct Stats {
     pub rows: usize,
@@ -50,15 +69,120 @@ pub enum ParseResult {
     Continue,
 }
 
+/// A terminal the grammar allows at some position; see Parser::expected_terminals_at_current().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminalDesc {
+    /// Name of the terminal symbol, like "T:0-9".
+    pub name: String,
+    pub bytes: ByteSet,
+}
+
+impl Display for TerminalDesc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bytes)
+    }
+}
+
+/// Where and why a byte was rejected; see Parser::last_reject_info().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectInfo {
+    pub byte: u8,
+    /// Position of the byte in the bytes scanned so far (Parser::get_bytes()).
+    pub byte_offset: usize,
+    /// Names of the symbols the parser was inside of, outermost (start symbol) first.
+    /// When the grammar is ambiguous, this is one of the possible nestings.
+    pub rule_stack: Vec<String>,
+    /// Terminals that would have been allowed instead.
+    pub expected: Vec<TerminalDesc>,
+}
+
+impl Display for RejectInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "byte {} at {} rejected in {}; expected one of: {}",
+            byte_to_string(self.byte),
+            self.byte_offset,
+            self.rule_stack.join(" > "),
+            self.expected
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(" | ")
+        )
+    }
+}
+
+/// Bytes matched by a symbol with a capture_name (SymbolProps); see Parser::captures().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureSpan {
+    pub name: String,
+    /// Index among the captures with the same name, in the order they completed.
+    pub occurrence: usize,
+    /// Byte range in the bytes scanned so far (Parser::get_bytes()).
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Why Parser::apply_tokens() failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseRejection {
+    /// The grammar doesn't allow the byte.
+    Rejected(RejectInfo),
+    /// The byte completed a hidden item, which rewinds the parser.
+    HiddenItem { byte_offset: usize },
+    /// The byte differs from the one the parser already has at this position
+    /// (e.g., forced by the grammar).
+    ByteMismatch {
+        byte_offset: usize,
+        expected: u8,
+        got: u8,
+    },
+}
+
+impl Display for ParseRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseRejection::Rejected(info) => write!(f, "parse reject: {}", info),
+            ParseRejection::HiddenItem { byte_offset } => {
+                write!(f, "hidden item at {}", byte_offset)
+            }
+            ParseRejection::ByteMismatch {
+                byte_offset,
+                expected,
+                got,
+            } => write!(
+                f,
+                "static reject: byte {} at {}, expected {}",
+                byte_to_string(*got),
+                byte_offset,
+                byte_to_string(*expected)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseRejection {}
+
+#[derive(Clone)]
 struct Row {
     first_item: usize,
     last_item: usize,
+    first_lex: usize,
+    last_lex: usize,
+    is_accepting: bool,
+    // see Parser::state_key()
+    key: u64,
 }
 
 impl Row {
     fn item_indices(&self) -> Range<usize> {
         self.first_item..self.last_item
     }
+
+    fn lex_indices(&self) -> Range<usize> {
+        self.first_lex..self.last_lex
+    }
 }
 
 impl Item {
@@ -152,9 +276,15 @@ struct Scratch {
     row_start: usize,
     row_end: usize,
     items: Vec<Item>,
+    // lexemes of the current row are at lex_start..
+    lex_start: usize,
+    lex_items: Vec<LexItem>,
+    // the lexemes of the current row, for add_lex()
+    row_lex: FxHashSet<LexItem>,
     predicated_syms: SimpleSet<CSymIdx>,
 }
 
+#[derive(Clone)]
 struct RowInfo {
     byte: u8,
     token_idx: usize,
@@ -165,7 +295,7 @@ struct RowInfo {
 pub struct Parser {
     grammar: CGrammar,
     scratch: Scratch,
-    captures: Vec<(String, Vec<u8>)>,
+    captures: Vec<CaptureSpan>,
     rows: Vec<Row>,
     row_infos: Vec<RowInfo>,
     stats: Stats,
@@ -173,18 +303,67 @@ pub struct Parser {
     last_collapse: usize,
     speculative: bool,
     token_idx: usize,
+    last_reject: Option<RejectInfo>,
+    // live checkpoints, with their number of rows and captures
+    checkpoints: Vec<(Weak<()>, usize, usize)>,
+    // how to undo the changes made below the live checkpoints, oldest first
+    undo: Vec<Undo>,
+}
+
+/// Saved state of a Parser; see Parser::checkpoint().
+pub struct ParserCheckpoint {
+    num_rows: usize,
+    num_captures: usize,
+    undo_len: usize,
+    is_accepting: bool,
+    last_collapse: usize,
+    token_idx: usize,
+    // the parser keeps the undo journal while this is around
+    #[allow(dead_code)]
+    alive: Arc<()>,
+}
+
+/// A change to the rows (or captures) a checkpoint has.
+enum Undo {
+    Rows {
+        start: usize,
+        rows: Vec<Row>,
+        row_infos: Vec<RowInfo>,
+    },
+    TokenIdx {
+        row_idx: usize,
+        token_idx: usize,
+    },
+    Captures(Vec<CaptureSpan>),
+    CaptureEnd {
+        idx: usize,
+        end: usize,
+    },
 }
 
 impl Scratch {
-    fn new_row(&mut self, pos: usize) {
+    fn new_row(&mut self, pos: usize, lex_pos: usize) {
         self.row_start = pos;
         self.row_end = pos;
+        self.lex_items.truncate(lex_pos);
+        self.lex_start = lex_pos;
+        self.row_lex.clear();
     }
 
     fn row_len(&self) -> usize {
         self.row_end - self.row_start
     }
 
+    fn lex_len(&self) -> usize {
+        self.lex_items.len() - self.lex_start
+    }
+
+    fn add_lex(&mut self, item: LexItem) {
+        if self.row_lex.insert(item) {
+            self.lex_items.push(item);
+        }
+    }
+
     #[inline(always)]
     fn ensure_items(&mut self, n: usize) {
         if self.items.len() < n {
@@ -228,6 +407,9 @@ impl Parser {
             last_collapse: 0,
             speculative: false,
             token_idx: 0,
+            last_reject: None,
+            checkpoints: vec![],
+            undo: vec![],
         };
         for rule in r.grammar.rules_of(start).to_vec() {
             r.scratch.add_unique(Item::new(rule, 0), &r.grammar, "init");
@@ -241,6 +423,26 @@ impl Parser {
         self.is_accepting
     }
 
+    pub fn grammar(&self) -> &CGrammar {
+        &self.grammar
+    }
+
+    /// Check if the grammar allows any more bytes in the current state.
+    pub fn can_advance(&self) -> bool {
+        // lexemes that can't advance are dropped in push_row()
+        !self.curr_row().lex_indices().is_empty()
+            || self.curr_row().item_indices().any(|i| {
+                let sym = self.grammar.sym_idx_at(self.scratch.items[i].rule_idx());
+                sym != CSymIdx::NULL && self.grammar.is_terminal(sym)
+            })
+    }
+
+    fn curr_lex_items(&self) -> impl Iterator<Item = LexItem> + '_ {
+        self.curr_row()
+            .lex_indices()
+            .map(|i| self.scratch.lex_items[i])
+    }
+
     fn item_to_string(&self, item: &Item) -> String {
         item_to_string(&self.grammar, item)
     }
@@ -251,22 +453,61 @@ impl Parser {
         for i in row.item_indices() {
             println!("{}", self.item_to_string(&self.scratch.items[i]));
         }
+        for i in row.lex_indices() {
+            let lex = self.scratch.lex_items[i];
+            let sym = self.grammar.lexemes().lexeme(lex.state);
+            println!(
+                "{} LEXEME state={} @{}",
+                self.grammar.sym_name(sym),
+                lex.state,
+                lex.start
+            );
+        }
     }
 
     pub fn num_rows(&self) -> usize {
         self.rows.len()
     }
 
+    /// Number of items (including positions in lexemes) in the rows so far.
+    pub fn chart_size(&self) -> usize {
+        self.rows
+            .iter()
+            .map(|r| r.item_indices().len() + r.lex_indices().len())
+            .sum()
+    }
+
     fn pop_row_infos(&mut self, n: usize) {
         assert!(!self.speculative);
         assert!(self.row_infos.len() == self.rows.len());
-        unsafe { self.row_infos.set_len(self.row_infos.len() - n) }
+        let start = self.rows.len() - n;
+        let (num_rows, num_captures) = self.protected();
+        if start < num_rows {
+            let end = num_rows.min(self.rows.len());
+            self.undo.push(Undo::Rows {
+                start,
+                rows: self.rows[start..end].to_vec(),
+                row_infos: self.row_infos[start..end].to_vec(),
+            });
+        }
+        unsafe { self.row_infos.set_len(start) }
         self.pop_rows(n);
+        // the ones still matching are captured again when the rows are re-done
+        let num_bytes = self.row_infos.len() - 1;
+        let num_captures = num_captures.min(self.captures.len());
+        if self.captures[..num_captures]
+            .iter()
+            .any(|c| c.end > num_bytes)
+        {
+            self.undo.push(Undo::Captures(self.captures.clone()));
+        }
+        self.captures.retain(|c| c.end <= num_bytes);
     }
 
     fn pop_rows(&mut self, n: usize) {
         unsafe { self.rows.set_len(self.rows.len() - n) }
         // self.rows.drain(self.rows.len() - n..);
+        self.is_accepting = self.curr_row().is_accepting;
     }
 
     #[allow(dead_code)]
@@ -280,37 +521,383 @@ impl Parser {
         assert!(self.num_rows() == self.row_infos.len());
     }
 
+    /// Number of rows and captures the live checkpoints have; changes to these
+    /// go to the undo journal, which is dropped once there are no checkpoints.
+    fn protected(&mut self) -> (usize, usize) {
+        self.checkpoints
+            .retain(|(alive, _, _)| alive.strong_count() > 0);
+        if self.checkpoints.is_empty() {
+            self.undo.clear();
+        }
+        self.checkpoints
+            .iter()
+            .fold((0, 0), |(r, c), &(_, nr, nc)| (r.max(nr), c.max(nc)))
+    }
+
+    /// Save the current state, so that it can be restored after scanning
+    /// some more bytes (e.g., a speculative draft).
+    /// Nothing is copied: items of existing rows are never overwritten, and while
+    /// the checkpoint is around, rows and captures it has that get dropped or changed
+    /// are kept in an undo journal. Checkpoints have to be restored in stack order
+    /// (most recent first).
+    pub fn checkpoint(&mut self) -> ParserCheckpoint {
+        self.non_trie();
+        self.protected();
+        let alive = Arc::new(());
+        self.checkpoints
+            .push((Arc::downgrade(&alive), self.rows.len(), self.captures.len()));
+        ParserCheckpoint {
+            num_rows: self.rows.len(),
+            num_captures: self.captures.len(),
+            undo_len: self.undo.len(),
+            is_accepting: self.is_accepting,
+            last_collapse: self.last_collapse,
+            token_idx: self.token_idx,
+            alive,
+        }
+    }
+
+    /// Go back to the state at the time of checkpoint().
+    /// The same checkpoint can be restored any number of times.
+    pub fn restore(&mut self, checkpoint: &ParserCheckpoint) {
+        self.non_trie();
+        assert!(
+            checkpoint.undo_len <= self.undo.len(),
+            "checkpoints restored out of order"
+        );
+        while self.undo.len() > checkpoint.undo_len {
+            match self.undo.pop().unwrap() {
+                Undo::Rows {
+                    start,
+                    rows,
+                    row_infos,
+                } => {
+                    self.rows.truncate(start);
+                    self.rows.extend(rows);
+                    self.row_infos.truncate(start);
+                    self.row_infos.extend(row_infos);
+                }
+                Undo::TokenIdx { row_idx, token_idx } => {
+                    self.row_infos[row_idx].token_idx = token_idx;
+                }
+                Undo::Captures(captures) => self.captures = captures,
+                Undo::CaptureEnd { idx, end } => self.captures[idx].end = end,
+            }
+        }
+        self.rows.truncate(checkpoint.num_rows);
+        self.row_infos.truncate(checkpoint.num_rows);
+        self.captures.truncate(checkpoint.num_captures);
+        self.is_accepting = checkpoint.is_accepting;
+        self.last_collapse = checkpoint.last_collapse;
+        self.token_idx = checkpoint.token_idx;
+    }
+
     pub fn get_bytes(&self) -> Vec<u8> {
         self.non_trie();
         self.row_infos.iter().skip(1).map(|ri| ri.byte).collect()
     }
 
-    pub fn apply_tokens(&mut self, trie: &TokTrie, tokens: &[TokenId]) -> &'static str {
+    /// Drop the bytes after the first `num_bytes` (e.g., backtracked ones), together
+    /// with the captures that end in them.
+    pub fn truncate_bytes(&mut self, num_bytes: usize) {
+        self.non_trie();
+        let num_extra = (self.row_infos.len() - 1).saturating_sub(num_bytes);
+        if num_extra > 0 {
+            self.pop_row_infos(num_extra);
+        }
+    }
+
+    /// Walk the parser through the bytes of the tokens; bytes the parser already has
+    /// (scanned or forced earlier) need to match.
+    pub fn apply_tokens(
+        &mut self,
+        trie: &TokTrie,
+        tokens: &[TokenId],
+    ) -> Result<(), ParseRejection> {
         self.non_trie();
+        let (num_protected, _) = self.protected();
         let mut byte_idx = 1; // row_infos[0] has just the 0 byte
         let mut tok_idx = 0;
         for t in tokens {
             for b in trie.token(*t).iter() {
                 if byte_idx >= self.row_infos.len() {
                     if self.scan(*b) == ParseResult::Reject {
-                        return "parse reject";
+                        return Err(ParseRejection::Rejected(self.last_reject.clone().unwrap()));
                     }
                     if byte_idx >= self.row_infos.len() {
-                        return "hidden item";
+                        return Err(ParseRejection::HiddenItem {
+                            byte_offset: byte_idx - 1,
+                        });
                     }
                 }
-                let info = &mut self.row_infos[byte_idx];
+                let info = &self.row_infos[byte_idx];
                 if info.byte != *b {
-                    println!("byte mismatch: {} != {} at {}", info.byte, b, byte_idx);
-                    return "static reject";
+                    return Err(ParseRejection::ByteMismatch {
+                        byte_offset: byte_idx - 1,
+                        expected: info.byte,
+                        got: *b,
+                    });
+                }
+                if info.token_idx != tok_idx {
+                    if byte_idx < num_protected {
+                        self.undo.push(Undo::TokenIdx {
+                            row_idx: byte_idx,
+                            token_idx: info.token_idx,
+                        });
+                    }
+                    self.row_infos[byte_idx].token_idx = tok_idx;
                 }
-                info.token_idx = tok_idx;
                 byte_idx += 1;
             }
             tok_idx += 1;
         }
         self.token_idx = tok_idx;
-        return "";
+        Ok(())
+    }
+
+    /// The terminals allowed after the bytes scanned so far, without duplicates.
+    /// Like allowed_byte_mask(), this over-approximates: some of them may still be rejected.
+    pub fn expected_terminals_at_current(&self) -> Vec<TerminalDesc> {
+        let mut syms = vec![];
+        for i in self.curr_row().item_indices() {
+            let sym = self.grammar.sym_idx_at(self.scratch.items[i].rule_idx());
+            if self.grammar.is_terminal(sym) && !syms.contains(&sym) {
+                syms.push(sym);
+            }
+        }
+        let mut r: Vec<TerminalDesc> = syms
+            .iter()
+            .map(|sym| TerminalDesc {
+                name: self.grammar.sym_name(*sym).to_string(),
+                bytes: *self.grammar.terminal_byteset(*sym),
+            })
+            .collect();
+        let dfa = self.grammar.lexemes();
+        for lex in self.curr_lex_items() {
+            let desc = TerminalDesc {
+                name: self.grammar.sym_name(dfa.lexeme(lex.state)).to_string(),
+                bytes: dfa.allowed(lex.state).clone(),
+            };
+            if !r.contains(&desc) {
+                r.push(desc);
+            }
+        }
+        r
+    }
+
+    /// Details of the last byte rejected by scan() outside of the trie walk, if any.
+    pub fn last_reject_info(&self) -> Option<&RejectInfo> {
+        self.last_reject.as_ref()
+    }
+
+    /// Symbols the current row is nested in, outermost first, following one
+    /// chain of items back to where they were predicted.
+    fn rule_stack(&self) -> Vec<String> {
+        let first = self
+            .curr_row()
+            .item_indices()
+            .map(|i| self.scratch.items[i])
+            .find(|item| {
+                let sym = self.grammar.sym_idx_at(item.rule_idx());
+                sym != CSymIdx::NULL && self.grammar.is_terminal(sym)
+            });
+        let (mut lhs, mut start) = match first {
+            Some(item) => (self.grammar.sym_idx_of(item.rule_idx()), item.start_pos()),
+            None => match self.curr_lex_items().next() {
+                Some(lex) => (self.grammar.lexemes().lexeme(lex.state), lex.start as usize),
+                None => return vec![],
+            },
+        };
+        let mut stack = vec![];
+        let mut visited = vec![];
+        loop {
+            stack.push(self.grammar.sym_name(lhs).to_string());
+            visited.push((start, lhs));
+            // with left recursion, lhs may be predicted by an item of itself
+            let parent = self.rows[start]
+                .item_indices()
+                .map(|i| self.scratch.items[i])
+                .find(|it| {
+                    self.grammar.sym_idx_at(it.rule_idx()) == lhs
+                        && !visited
+                            .contains(&(it.start_pos(), self.grammar.sym_idx_of(it.rule_idx())))
+                });
+            match parent {
+                Some(p) => {
+                    lhs = self.grammar.sym_idx_of(p.rule_idx());
+                    start = p.start_pos();
+                }
+                None => break,
+            }
+        }
+        stack.reverse();
+        stack
+    }
+
+    fn record_reject(&mut self, byte: u8) {
+        self.last_reject = Some(RejectInfo {
+            byte,
+            byte_offset: self.num_rows() - 1,
+            rule_stack: self.rule_stack(),
+            expected: self.expected_terminals_at_current(),
+        });
+    }
+
+    /// Terminate any symbol with max_tokens (typically a gen() node) that has already
+    /// consumed its budget, as if its stop condition matched, so that the grammar
+    /// moves on to the next symbol.
+    /// `is_ff[i]` says whether the i-th token was forced (fast-forwarded); these are
+    /// not counted towards max_tokens.
+    /// Returns true if anything was terminated; fails if the grammar can't continue
+    /// after the symbol.
+    pub fn limit_max_tokens(&mut self, is_ff: &[bool]) -> Result<bool> {
+        self.non_trie();
+        let mut any = false;
+        while let Some((sym, start)) = self.find_exhausted_symbol(is_ff) {
+            let name = self.grammar.sym_data(sym).name.clone();
+            info!("max_tokens reached: {} @{}", name, start);
+            if self.force_complete(sym, start) == ParseResult::Reject {
+                bail!("max_tokens reached in {name} @{start}, which can't end there");
+            }
+            any = true;
+        }
+        Ok(any)
+    }
+
+    /// Number of sampled tokens since the given row.
+    fn sampled_tokens_since(&self, row_idx: usize, is_ff: &[bool]) -> usize {
+        // the token containing the first byte after the row is the first one attributable
+        let first_tok = self.row_infos[row_idx + 1].token_idx;
+        (first_tok..self.token_idx)
+            .filter(|idx| !is_ff.get(*idx).unwrap_or(&false))
+            .count()
+    }
+
+    /// The outermost symbol with max_tokens the current row is (strictly) inside of,
+    /// that has used up its budget, with the row where it started.
+    /// Only the rows the items of the current row were predicted from are visited.
+    fn find_exhausted_symbol(&self, is_ff: &[bool]) -> Option<(CSymIdx, usize)> {
+        let curr = self.num_rows() - 1;
+        let mut todo = self.curr_open_symbols();
+        let mut visited = FxHashSet::default();
+        let mut res: Option<(CSymIdx, usize)> = None;
+        while let Some((row_idx, lhs)) = todo.pop() {
+            if !visited.insert((row_idx, lhs)) {
+                continue;
+            }
+            let max_tokens = self.grammar.sym_data(lhs).props.max_tokens;
+            if row_idx < curr
+                && max_tokens != usize::MAX
+                && res.map_or(true, |(_, start)| row_idx < start)
+                && self.sampled_tokens_since(row_idx, is_ff) >= max_tokens
+            {
+                res = Some((lhs, row_idx));
+            }
+            self.push_parents(row_idx, lhs, 0, &mut todo);
+        }
+        res
+    }
+
+    /// The symbols (with their start rows) of the items of the current row that can
+    /// still scan bytes, and of its lexemes.
+    fn curr_open_symbols(&self) -> Vec<(usize, CSymIdx)> {
+        let mut res = vec![];
+        for i in self.curr_row().item_indices() {
+            let item = self.scratch.items[i];
+            let rule = item.rule_idx();
+            if self.grammar.sym_idx_at(rule) != CSymIdx::NULL {
+                res.push((item.start_pos(), self.grammar.sym_idx_of(rule)));
+            }
+        }
+        for lex in self.curr_lex_items() {
+            let lexeme = self.grammar.lexemes().lexeme(lex.state);
+            res.push((lex.start as usize, lexeme));
+        }
+        res
+    }
+
+    /// Add the symbols that predicted `lhs` at `row_idx` (and started at `min_start` or later)
+    /// to `todo`.
+    fn push_parents(
+        &self,
+        row_idx: usize,
+        lhs: CSymIdx,
+        min_start: usize,
+        todo: &mut Vec<(usize, CSymIdx)>,
+    ) {
+        for i in self.rows[row_idx].item_indices() {
+            let item = self.scratch.items[i];
+            let rule = item.rule_idx();
+            if self.grammar.sym_idx_at(rule) == lhs && item.start_pos() >= min_start {
+                todo.push((item.start_pos(), self.grammar.sym_idx_of(rule)));
+            }
+        }
+    }
+
+    /// Check if `lhs` started at `row_idx` is (possibly) inside of `sym` started at `start`,
+    /// by following the items back to where they were predicted.
+    fn is_inside(&self, sym: CSymIdx, start: usize, row_idx: usize, lhs: CSymIdx) -> bool {
+        let mut todo = vec![(row_idx, lhs)];
+        let mut visited = FxHashSet::default();
+        while let Some((row_idx, lhs)) = todo.pop() {
+            if row_idx < start {
+                continue;
+            }
+            if row_idx == start && lhs == sym {
+                return true;
+            }
+            if visited.insert((row_idx, lhs)) {
+                self.push_parents(row_idx, lhs, start, &mut todo);
+            }
+        }
+        false
+    }
+
+    /// Replace the current row with one where `sym` started at `start` is complete.
+    /// The items and lexemes of the row that are not inside of `sym` (other parses
+    /// of the bytes so far) stay.
+    fn force_complete(&mut self, sym: CSymIdx, start: usize) -> ParseResult {
+        let row_idx = self.num_rows() - 1;
+        assert!(start < row_idx);
+
+        let mut items: Vec<Item> = self
+            .curr_row()
+            .item_indices()
+            .map(|i| self.scratch.items[i])
+            .filter(|item| {
+                let lhs = self.grammar.sym_idx_of(item.rule_idx());
+                !self.is_inside(sym, start, item.start_pos(), lhs)
+            })
+            .collect();
+        let lexes: Vec<LexItem> = self
+            .curr_lex_items()
+            .filter(|lex| {
+                let lexeme = self.grammar.lexemes().lexeme(lex.state);
+                !self.is_inside(sym, start, lex.start as usize, lexeme)
+            })
+            .collect();
+
+        // any rule of sym will do; move the dot to the end
+        let mut item = Item::new(self.grammar.rules_of(sym)[0], start);
+        while self.grammar.sym_idx_at(item.rule_idx()) != CSymIdx::NULL {
+            item = item.advance_dot();
+        }
+        items.push(item);
+
+        let last_byte = self.row_infos[row_idx].byte;
+        // start a fresh row after the current one, to keep checkpointed items intact
+        let agenda_ptr = self.curr_row().last_item;
+        let lex_ptr = self.curr_row().last_lex;
+        self.pop_row_infos(1);
+
+        self.scratch.new_row(agenda_ptr, lex_ptr);
+        for lex in lexes {
+            self.scratch.add_lex(lex);
+        }
+        for item in items {
+            self.scratch.add_unique(item, &self.grammar, "max_tokens");
+        }
+        self.push_row(agenda_ptr, last_byte)
     }
 
     pub fn force_bytes(&mut self) -> Vec<u8> {
@@ -327,6 +914,38 @@ impl Parser {
         bytes
     }
 
+    /// What state_key() is a hash of: the items of the current row, with their starts
+    /// given as the keys of the rows they started in. States with the same words
+    /// allow the same bytes; use these to tell apart states whose keys collide.
+    pub fn state_words(&self) -> Vec<u64> {
+        let mut words = vec![];
+        self.row_words(self.num_rows() - 1, self.curr_row(), |w| words.push(w));
+        words
+    }
+
+    // `row` is at `row_idx`; its own row is u64::MAX, as its key is not known yet
+    fn row_words(&self, row_idx: usize, row: &Row, mut f: impl FnMut(u64)) {
+        let start_key = |start: usize| {
+            if start == row_idx {
+                u64::MAX
+            } else {
+                self.rows[start].key
+            }
+        };
+        f(row.item_indices().len() as u64);
+        for i in row.item_indices() {
+            let item = self.scratch.items[i];
+            f(item.rule_idx().as_index() as u64);
+            f(start_key(item.start_pos()));
+        }
+        f(row.lex_indices().len() as u64);
+        for i in row.lex_indices() {
+            let lex = self.scratch.lex_items[i];
+            f(lex.state as u64);
+            f(start_key(lex.start as usize));
+        }
+    }
+
     fn curr_row(&self) -> &Row {
         &self.rows[self.rows.len() - 1]
     }
@@ -352,14 +971,16 @@ impl Parser {
             return None;
         }
 
-        let mut byte_sym = None;
+        let mut byte = None;
         for i in self.curr_row().item_indices() {
             let item = self.scratch.items[i];
             let sym = self.grammar.sym_idx_at(item.rule_idx());
             if self.grammar.is_terminal(sym) {
                 if self.grammar.is_single_byte_terminal(sym) {
-                    if byte_sym == None || byte_sym == Some(sym) {
-                        byte_sym = Some(sym);
+                    let b = self.grammar.terminal_byteset(sym).single_byte();
+                    assert!(b.is_some());
+                    if byte.is_none() || byte == b {
+                        byte = b;
                     } else {
                         return None;
                     }
@@ -368,14 +989,15 @@ impl Parser {
                 }
             }
         }
-
-        if let Some(s) = byte_sym {
-            let r = self.grammar.terminal_byteset(s).single_byte();
-            assert!(r.is_some());
-            r
-        } else {
-            None
+        for lex in self.curr_lex_items() {
+            let b = self.grammar.lexemes().allowed(lex.state).single_byte();
+            if b.is_some() && (byte.is_none() || byte == b) {
+                byte = b;
+            } else {
+                return None;
+            }
         }
+        byte
     }
 
     pub fn hide_item(&mut self, sym: CSymIdx, row_idx: usize) -> ParseResult {
@@ -383,7 +1005,9 @@ impl Parser {
 
         let row_range = self.rows[row_idx].item_indices();
         let last_byte = self.row_infos[row_idx].byte;
-        let agenda_ptr = row_range.start;
+        // start a fresh row after the current one, to keep checkpointed items intact
+        let agenda_ptr = self.curr_row().last_item;
+        let lex_ptr = self.curr_row().last_lex;
         self.pop_row_infos(self.num_rows() - row_idx);
         assert!(self.num_rows() == row_idx);
 
@@ -401,7 +1025,7 @@ impl Parser {
         }
 
         // we remove everything from the current row before adding the entries
-        self.scratch.new_row(agenda_ptr);
+        self.scratch.new_row(agenda_ptr, lex_ptr);
         for item in items_to_add {
             self.scratch.add_unique(item, &self.grammar, "hide");
         }
@@ -419,7 +1043,8 @@ impl Parser {
 
         let allowed = self.grammar.terminals_by_byte(b);
 
-        self.scratch.new_row(last);
+        let lex_range = self.rows[row_idx].lex_indices();
+        self.scratch.new_row(last, lex_range.end);
 
         while i < last {
             let item = self.scratch.items[i];
@@ -430,13 +1055,107 @@ impl Parser {
             }
             i += 1;
         }
-        self.push_row(self.scratch.row_start, b)
+        let dfa = self.grammar.lexemes();
+        for idx in lex_range {
+            let lex = self.scratch.lex_items[idx];
+            let state = dfa.next(lex.state, b);
+            if state != DEAD {
+                self.scratch.add_lex(LexItem {
+                    state,
+                    start: lex.start,
+                });
+            }
+        }
+        let res = self.push_row(self.scratch.row_start, b);
+        if res == ParseResult::Reject && !self.speculative {
+            self.record_reject(b);
+        }
+        res
     }
 
-    pub fn captures(&self) -> &[(String, Vec<u8>)] {
+    /// Scan bytes until one is rejected; the bytes before the rejected one stay scanned.
+    pub fn scan_bytes(&mut self, bytes: &[u8]) -> ParseResult {
+        let mut res = if self.is_accepting {
+            ParseResult::Accept
+        } else {
+            ParseResult::Continue
+        };
+        for b in bytes {
+            res = self.scan(*b);
+            if res == ParseResult::Reject {
+                break;
+            }
+        }
+        res
+    }
+
+    /// Captures completed so far, in the order they first completed (so nested ones
+    /// come before the outer ones). A capture that completes again from the same
+    /// position with more bytes (e.g., a regex that can go on) is updated in place.
+    pub fn captures(&self) -> &[CaptureSpan] {
         &self.captures
     }
 
+    pub fn capture_bytes(&self, capture: &CaptureSpan) -> Vec<u8> {
+        self.non_trie();
+        self.row_infos[capture.start + 1..capture.end + 1]
+            .iter()
+            .map(|ri| ri.byte)
+            .collect()
+    }
+
+    fn add_capture(&mut self, name: String, start: usize, end: usize) {
+        if let Some(idx) = self
+            .captures
+            .iter()
+            .rposition(|c| c.name == name && c.start == start)
+        {
+            let (_, num_protected) = self.protected();
+            if idx < num_protected {
+                self.undo.push(Undo::CaptureEnd {
+                    idx,
+                    end: self.captures[idx].end,
+                });
+            }
+            self.captures[idx].end = end;
+            return;
+        }
+        let occurrence = self.captures.iter().filter(|c| c.name == name).count();
+        self.captures.push(CaptureSpan {
+            name,
+            occurrence,
+            start,
+            end,
+        });
+    }
+
+    /// Complete the lexemes that end with the byte just scanned. The grammar may also
+    /// allow them to go on, so they are kept, unless no more bytes can follow.
+    fn complete_lexemes(&mut self) {
+        let dfa = self.grammar.lexemes();
+        let mut num_kept = self.scratch.lex_start;
+        for idx in self.scratch.lex_start..self.scratch.lex_items.len() {
+            let lex = self.scratch.lex_items[idx];
+            if dfa.is_accepting(lex.state) {
+                let lexeme = dfa.lexeme(lex.state);
+                for i in self.rows[lex.start as usize].item_indices() {
+                    let item = self.scratch.items[i];
+                    if self.grammar.sym_idx_at(item.rule_idx()) == lexeme {
+                        self.scratch
+                            .add_unique(item.advance_dot(), &self.grammar, "lexeme");
+                    }
+                }
+            }
+            if dfa.can_advance(lex.state) {
+                self.scratch.lex_items[num_kept] = lex;
+                num_kept += 1;
+            } else {
+                self.scratch.row_lex.remove(&lex);
+            }
+        }
+        self.scratch.lex_items.truncate(num_kept);
+    }
+
     #[inline(always)]
     fn push_row(&mut self, mut agenda_ptr: usize, byte: u8) -> ParseResult {
         let curr_idx = self.rows.len();
@@ -447,6 +1166,9 @@ impl Parser {
         self.stats.rows += 1;
         self.is_accepting = false;
 
+        // lexemes in the row were all scanned, so they started in earlier rows
+        self.complete_lexemes();
+
         while agenda_ptr < self.scratch.row_end {
             let mut item = self.scratch.items[agenda_ptr];
             agenda_ptr += 1;
@@ -467,14 +1189,11 @@ impl Parser {
                         .sym_data(lhs)
                         .props
                         .capture_name
-                        .as_ref()
+                        .clone()
                         .unwrap();
-                    let mut bytes = self.row_infos[item.start_pos() + 1..curr_idx]
-                        .iter()
-                        .map(|ri| ri.byte)
-                        .collect::<Vec<_>>();
-                    bytes.push(byte);
-                    self.captures.push((var_name.clone(), bytes));
+                    // row N is after N bytes; an empty match (nullable symbol)
+                    // starts at the current row
+                    self.add_capture(var_name, item.start_pos(), curr_idx);
                 }
 
                 if flags.commit_point() {
@@ -511,22 +1230,20 @@ impl Parser {
                     }
                 }
             } else {
+                // max_tokens is enforced separately, see limit_max_tokens()
                 let sym_data = self.grammar.sym_data(after_dot);
-                // if no max_tokens, no point looking in row_infos
-                // if start_pos() is recent enough, not to make it into row_infos, also no point checking
-                // otherwise, if we exceeded max_tokens, don't add anything
-                if sym_data.props.max_tokens != usize::MAX
-                    && item.start_pos() < self.row_infos.len()
-                    && self.token_idx - self.row_infos[item.start_pos()].token_idx
-                        >= sym_data.props.max_tokens
-                {
-                    continue;
-                }
                 if sym_data.is_nullable {
                     self.scratch
                         .add_unique(item.advance_dot(), &self.grammar, "null");
                 }
-                if self.scratch.predicated_syms.should_insert(after_dot) {
+                if let Some(state) = sym_data.lexeme {
+                    if self.grammar.lexemes().can_advance(state) {
+                        self.scratch.add_lex(LexItem {
+                            state,
+                            start: curr_idx as u32,
+                        });
+                    }
+                } else if self.scratch.predicated_syms.should_insert(after_dot) {
                     for rule in &sym_data.rules {
                         let new_item = Item::new(*rule, curr_idx);
                         self.scratch.add_unique(new_item, &self.grammar, "predict");
@@ -535,18 +1252,28 @@ impl Parser {
             }
         }
 
-        let row_len = self.scratch.row_len();
+        let row_len = self.scratch.row_len() + self.scratch.lex_len();
         self.stats.all_items += row_len;
 
         if row_len == 0 {
             assert!(!self.is_accepting);
+            // the current row stays, and so does its accepting state
+            self.is_accepting = self.rows.last().map_or(false, |r| r.is_accepting);
             return ParseResult::Reject;
         }
 
-        self.rows.push(Row {
+        let mut row = Row {
             first_item: self.scratch.row_start,
             last_item: self.scratch.row_end,
-        });
+            first_lex: self.scratch.lex_start,
+            last_lex: self.scratch.lex_items.len(),
+            is_accepting: self.is_accepting,
+            key: 0,
+        };
+        let mut h = FxHasher::default();
+        self.row_words(curr_idx, &row, |w| h.write_u64(w));
+        row.key = h.finish();
+        self.rows.push(row);
 
         if !self.speculative {
             self.row_infos.drain((self.rows.len() - 1)..);
@@ -612,6 +1339,36 @@ impl Recognizer for Parser {
         self.speculative = false;
     }
 
+    fn has_fast_byte_mask(&self) -> bool {
+        true
+    }
+
+    // A hash of the current row, computed in push_row(): items in it refer to earlier
+    // rows, and completing them brings those back, so the starts are hashed as the
+    // keys of these rows. The scanned bytes (and row numbers) are not included;
+    // inputs with the same current row allow the same bytes from then on.
+    fn state_key(&self) -> Option<u64> {
+        Some(self.curr_row().key)
+    }
+
+    fn allowed_byte_mask(&mut self, mask_out: &mut [bool; 256]) {
+        // bytes of terminals after the dot; the items may still die out
+        // in push_row(), so this over-approximates
+        let mut set = ByteSet::new();
+        for i in self.curr_row().item_indices() {
+            let sym = self.grammar.sym_idx_at(self.scratch.items[i].rule_idx());
+            if self.grammar.is_terminal(sym) {
+                set |= *self.grammar.terminal_byteset(sym);
+            }
+        }
+        for lex in self.curr_lex_items() {
+            set |= *self.grammar.lexemes().allowed(lex.state);
+        }
+        for b in 0..=255u8 {
+            mask_out[b as usize] = set.contains(b);
+        }
+    }
+
     fn try_push_byte(&mut self, byte: u8) -> bool {
         let res = self.scan(byte);
         if res == ParseResult::Reject {
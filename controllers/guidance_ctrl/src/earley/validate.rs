@@ -0,0 +1,169 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use aici_abi::toktree::{Recognizer, TokTrie};
+use anyhow::{bail, Result};
+use rustc_hash::FxHashSet;
+use serde::{Deserialize, Serialize};
+
+use super::{grammar::CGrammar, ParseResult, Parser, TerminalDesc};
+
+/// Limits on the exploration in validate_grammar().
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationConfig {
+    /// Don't look at states after more bytes than this.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Stop after visiting this many states.
+    #[serde(default = "default_max_states")]
+    pub max_states: usize,
+}
+
+fn default_max_depth() -> usize {
+    64
+}
+
+fn default_max_states() -> usize {
+    2000
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_depth: default_max_depth(),
+            max_states: default_max_states(),
+        }
+    }
+}
+
+/// A state where the grammar allows neither a token nor EOS.
+#[derive(Debug, Clone)]
+pub struct DeadEnd {
+    /// Bytes leading to the state (the shortest such, up to the exploration limits).
+    pub prefix: Vec<u8>,
+    /// What the grammar wants next, which no token starts with.
+    pub expected: Vec<TerminalDesc>,
+}
+
+impl Display for DeadEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let expected = self
+            .expected
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "no token allowed after {:?}; expected {}",
+            String::from_utf8_lossy(&self.prefix),
+            expected.join(" or ")
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// Distinct parser states visited.
+    pub num_states: usize,
+    /// The limits of ValidationConfig were hit, so some states were not looked at.
+    pub truncated: bool,
+    pub dead_ends: Vec<DeadEnd>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.dead_ends.is_empty()
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} dead end(s) in {} state(s){}",
+            self.dead_ends.len(),
+            self.num_states,
+            if self.truncated { " (truncated)" } else { "" }
+        )?;
+        for d in &self.dead_ends {
+            write!(f, "\n  {d}")?;
+        }
+        Ok(())
+    }
+}
+
+// whether the model can be at this point, i.e., some token ends here
+fn token_can_end(trie: &TokTrie, prefix: &[u8]) -> bool {
+    let max_len = std::cmp::min(prefix.len(), trie.max_token_len());
+    prefix.is_empty() || (1..=max_len).any(|n| trie.token_id(&prefix[prefix.len() - n..]).is_some())
+}
+
+/// Look for states of the grammar where the model can end up (after some token),
+/// but then no token of `trie` (nor EOS) is allowed, typically because the grammar
+/// requires bytes none of the tokens start with; at runtime, these show up as empty
+/// token sets, and stop the sequence.
+/// States are explored byte by byte, shortest prefixes first, and checked with
+/// TokTrie::compute_bias_ext(), as when generating; states after a dead end are skipped.
+/// Fails if the parser rejects bytes it accepted before, which would be a parser bug.
+pub fn validate_grammar(
+    grm: &CGrammar,
+    trie: &TokTrie,
+    config: ValidationConfig,
+) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    let mut parser = Parser::new(grm.clone());
+    let start = parser.checkpoint();
+    let mut seen = FxHashSet::default();
+    seen.insert(parser.state_words());
+    let mut queue = VecDeque::from([vec![]]);
+    let mut set = trie.alloc_token_set();
+    let mut mask = [false; 256];
+
+    while let Some(prefix) = queue.pop_front() {
+        parser.restore(&start);
+        if parser.scan_bytes(&prefix) == ParseResult::Reject {
+            bail!(
+                "parser rejected {:?} on replay",
+                String::from_utf8_lossy(&prefix)
+            );
+        }
+        report.num_states += 1;
+
+        if token_can_end(trie, &prefix) {
+            trie.compute_bias_ext(&mut parser, &mut set, &[]);
+            if set.num_set() == 0 {
+                report.dead_ends.push(DeadEnd {
+                    expected: parser.expected_terminals_at_current(),
+                    prefix,
+                });
+                continue;
+            }
+        }
+
+        if prefix.len() >= config.max_depth {
+            report.truncated = true;
+            continue;
+        }
+        let here = parser.checkpoint();
+        parser.allowed_byte_mask(&mut mask);
+        for b in 0..=255u8 {
+            if !mask[b as usize] {
+                continue;
+            }
+            // states with the same current row (see Parser::state_words()) allow
+            // the same bytes from then on, so only the first one is explored; the
+            // words are compared rather than state_key(), which may collide
+            if parser.scan(b) != ParseResult::Reject && seen.insert(parser.state_words()) {
+                if seen.len() > config.max_states {
+                    report.truncated = true;
+                } else {
+                    let mut next = prefix.clone();
+                    next.push(b);
+                    queue.push_back(next);
+                }
+            }
+            parser.restore(&here);
+        }
+    }
+
+    Ok(report)
+}
@@ -1,77 +1,234 @@
-use aici_abi::{arg_bytes, bytes::to_hex_string, AiciCtrl, MidProcessArg, MidProcessResult};
-use base64::{self, Engine as _};
+use aici_abi::{
+    arg_bytes, bytes::to_hex_string, AiciCtrl, Error, InitPromptArg, InitPromptResult,
+    MidProcessArg, MidProcessResult, PostSampleArg, PostSampleResult, TryAiciCtrl,
+};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
-use aici_guidance_ctrl::TokenParser;
-
-const INFO: bool = true;
+use aici_guidance_ctrl::{
+    earley::{validate_grammar, ParseRejection, ValidationConfig, ValidationReport},
+    DynGrammar, GrammarSpec, TokenParser,
+};
 
 macro_rules! infoln {
     ($($arg:tt)*) => {
-        if INFO {
-            println!($($arg)*);
-        }
+        aici_abi::wlog_info!($($arg)*)
     };
 }
 
 pub struct Runner {
     tok_parser: TokenParser,
-    reported_captures: usize,
+    dyn_grammar: Option<DynGrammar>,
+    validate_only: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct RunnerArg {
-    guidance_b64: String,
+    #[serde(flatten)]
+    grammar: GrammarSpec,
+    /// When set, a JSON-encoded GrammarSpec written to this storage variable
+    /// replaces the grammar mid-generation.
+    #[serde(default)]
+    grammar_var: Option<String>,
+    /// Only check the grammar against the tokenizer (see validate_grammar()),
+    /// report the result, and stop without generating.
+    #[serde(default)]
+    validate_only: bool,
+    #[serde(default)]
+    validation: ValidationConfig,
 }
 
 impl Runner {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self> {
         infoln!("building runner...");
-        let arg: RunnerArg = serde_json::from_slice(&arg_bytes()).expect("invalid JSON arg");
-        let guidance = base64::engine::general_purpose::STANDARD
-            .decode(arg.guidance_b64)
-            .expect("invalid base64");
-        Runner {
-            tok_parser: TokenParser::from_guidance_protobuf(
-                Box::new(aici_abi::WasmTokenizerEnv::default()),
-                &guidance,
-            )
-            .expect("invalid guidance protobuf"),
-            reported_captures: 0,
+        let arg: serde_json::Value =
+            serde_json::from_slice(&arg_bytes()).map_err(|e| anyhow!("invalid JSON arg: {e}"))?;
+        // a bare list is the tools to call
+        let arg = match arg {
+            serde_json::Value::Array(_) => RunnerArg {
+                grammar: GrammarSpec {
+                    tools: Some(
+                        serde_json::from_value(arg).map_err(|e| anyhow!("invalid tools: {e}"))?,
+                    ),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            _ => serde_json::from_value::<RunnerArg>(arg)
+                .map_err(|e| anyhow!("invalid JSON arg: {e}"))?,
+        };
+        let token_env = Box::new(aici_abi::WasmTokenizerEnv::default());
+        let grm = arg.grammar.to_grammar()?;
+        let tok_parser = TokenParser::from_grammar(token_env, grm)?;
+        if arg.validate_only {
+            let report = validate_grammar(
+                tok_parser.parser.grammar(),
+                tok_parser.token_env.tok_trie(),
+                arg.validation,
+            )?;
+            infoln!("{report}");
+            Self::report_validation(&report);
         }
+        Ok(Runner {
+            tok_parser,
+            dyn_grammar: arg.grammar_var.as_deref().map(DynGrammar::new),
+            validate_only: arg.validate_only,
+        })
+    }
+
+    fn report_validation(report: &ValidationReport) {
+        let v = Validation {
+            object: "validation",
+            ok: report.is_ok(),
+            num_states: report.num_states,
+            truncated: report.truncated,
+            dead_ends: report
+                .dead_ends
+                .iter()
+                .map(|d| ValidationDeadEnd {
+                    prefix: String::from_utf8_lossy(&d.prefix).to_string(),
+                    hex: to_hex_string(&d.prefix),
+                    expected: d.expected.iter().map(|t| t.to_string()).collect(),
+                })
+                .collect(),
+        };
+        println!("JSON-OUT: {}", serde_json::to_string(&v).unwrap());
     }
 
-    fn report_captures(&mut self) {
-        let captures = &self.tok_parser.parser.captures()[self.reported_captures..];
-        for (name, val) in captures {
-            self.reported_captures += 1;
+    fn report_captures(&self, captures: &[aici_abi::Capture]) {
+        if captures.is_empty() {
+            return;
+        }
+        let bytes = self.tok_parser.parser.get_bytes();
+        for c in captures {
             let cap = Capture {
                 object: "capture",
-                name: name.clone(),
-                str: String::from_utf8_lossy(val).to_string(),
-                hex: to_hex_string(val),
+                name: c.name.clone(),
+                occurrence: c.occurrence,
+                start: c.start,
+                end: c.end,
+                str: c.value.clone(),
+                hex: to_hex_string(&bytes[c.start as usize..c.end as usize]),
+                retracted: c.retracted,
             };
             println!("JSON-OUT: {}", serde_json::to_string(&cap).unwrap());
         }
     }
+
+    fn report_rejection(&self) {
+        let rej = match self.tok_parser.last_rejection() {
+            Some(r) => r,
+            None => return,
+        };
+        let (rule_stack, expected) = match rej {
+            ParseRejection::Rejected(info) => (
+                info.rule_stack.clone(),
+                info.expected.iter().map(|t| t.to_string()).collect(),
+            ),
+            _ => (vec![], vec![]),
+        };
+        let r = Rejection {
+            object: "rejection",
+            message: rej.to_string(),
+            rule_stack,
+            expected,
+        };
+        println!("JSON-OUT: {}", serde_json::to_string(&r).unwrap());
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct Capture {
     object: &'static str, // "capture"
     name: String,
+    occurrence: u32,
+    start: u32,
+    end: u32,
     str: String,
     hex: String,
+    // withdraws the one with the name and occurrence; see aici_abi::Capture
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    retracted: bool,
+}
+
+/// Why the grammar rejected the model's output; for grammar authors.
+#[derive(Serialize, Deserialize)]
+struct Rejection {
+    object: &'static str, // "rejection"
+    message: String,
+    rule_stack: Vec<String>,
+    expected: Vec<String>,
+}
+
+/// Result of validate_only.
+#[derive(Serialize, Deserialize)]
+struct Validation {
+    object: &'static str, // "validation"
+    ok: bool,
+    num_states: usize,
+    truncated: bool,
+    dead_ends: Vec<ValidationDeadEnd>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ValidationDeadEnd {
+    prefix: String,
+    hex: String,
+    expected: Vec<String>,
 }
 
 impl AiciCtrl for Runner {
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        if self.validate_only {
+            return MidProcessResult::stop();
+        }
+        if let Some(dyn_grammar) = &mut self.dyn_grammar {
+            dyn_grammar.update(&mut self.tok_parser);
+        }
         let r = self.tok_parser.mid_process(arg);
-        self.report_captures();
+        self.report_captures(&r.captures);
+        self.report_rejection();
         r
     }
 }
 
+/// The runner, or why it couldn't be built (e.g., an invalid JSON schema);
+/// the error fails the request in init_prompt, instead of trapping.
+enum Controller {
+    Ready(Runner),
+    Invalid(String),
+}
+
+impl Controller {
+    fn new() -> Self {
+        match Runner::new() {
+            Ok(runner) => Controller::Ready(runner),
+            Err(e) => Controller::Invalid(e.to_string()),
+        }
+    }
+
+    fn runner(&mut self) -> Result<&mut Runner, Error> {
+        match self {
+            Controller::Ready(runner) => Ok(runner),
+            Controller::Invalid(msg) => Err(Error::controller(msg.clone())),
+        }
+    }
+}
+
+impl TryAiciCtrl for Controller {
+    fn try_init_prompt(&mut self, arg: InitPromptArg) -> Result<InitPromptResult, Error> {
+        Ok(self.runner()?.init_prompt(arg))
+    }
+
+    fn try_mid_process(&mut self, arg: MidProcessArg) -> Result<MidProcessResult, Error> {
+        Ok(self.runner()?.mid_process(arg))
+    }
+
+    fn try_post_sample(&mut self, arg: PostSampleArg) -> Result<PostSampleResult, Error> {
+        Ok(self.runner()?.post_sample(arg))
+    }
+}
+
 fn main() {}
 
-aici_abi::aici_expose_all!(Runner, Runner::new());
+aici_abi::aici_expose_all!(Controller, Controller::new());
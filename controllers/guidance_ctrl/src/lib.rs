@@ -1,4 +1,8 @@
+mod dyngrammar;
 pub mod earley;
+mod registry;
 mod serialization;
 mod tokenparser;
-pub use tokenparser::TokenParser;
+pub use dyngrammar::{DynGrammar, GrammarSpec};
+pub use registry::GrammarRegistry;
+pub use tokenparser::{SpliceOrAccept, TokenParser};
@@ -0,0 +1,131 @@
+use crate::earley::{earley_grm_from_guidance, Grammar};
+use anyhow::{anyhow, bail, Result};
+
+/// Named grammars that reference each other (see Grammar::reference()), composed
+/// into one grammar with grammar().
+///
+/// A bundle of guidance protobufs is a sequence of entries, each being the name and the
+/// protobuf, both prefixed with their length as a little-endian u32.
+/// In the protobufs, a join or select node without values named `@foo` stands for
+/// the grammar `foo` of the bundle.
+pub struct GrammarRegistry {
+    grammars: Vec<(String, Grammar)>,
+}
+
+impl GrammarRegistry {
+    pub fn new() -> Self {
+        GrammarRegistry {
+            grammars: Vec::new(),
+        }
+    }
+
+    /// Fails on malformed bundles, and on cyclic references between the grammars
+    /// that make no progress (see check_cycles()).
+    pub fn from_bundle(bytes: &[u8]) -> Result<Self> {
+        let mut r = Self::new();
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let name = read_chunk(&mut bytes)?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| anyhow!("grammar name is not UTF-8"))?;
+            let grm = earley_grm_from_guidance(read_chunk(&mut bytes)?)
+                .map_err(|e| anyhow!("invalid guidance protobuf for '{name}': {e}"))?;
+            r.insert(&name, grm)?;
+        }
+        r.check_cycles()?;
+        Ok(r)
+    }
+
+    /// Encode (name, guidance protobuf) pairs as read by from_bundle().
+    pub fn encode_bundle(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut r = Vec::new();
+        for (name, protobuf) in entries {
+            for chunk in [name.as_bytes(), protobuf] {
+                r.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+                r.extend_from_slice(chunk);
+            }
+        }
+        r
+    }
+
+    pub fn insert(&mut self, name: &str, grm: Grammar) -> Result<()> {
+        if self.get(name).is_some() {
+            bail!("duplicate grammar '{name}'");
+        }
+        self.grammars.push((name.to_string(), grm));
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Grammar> {
+        self.grammars
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, grm)| grm)
+    }
+
+    /// Names of the grammars, in the order of insertion.
+    pub fn names(&self) -> Vec<&str> {
+        self.grammars.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    /// Grammars can reference each other recursively, as long as every cycle of references
+    /// consumes some bytes; a grammar that derives itself with nothing around it is rejected
+    /// (see Grammar::no_progress_cycle()).
+    pub fn check_cycles(&self) -> Result<()> {
+        let mut all = Grammar::new();
+        for (name, grm) in &self.grammars {
+            all.insert_subgrammar(name, grm)?;
+        }
+        if let Some(cycle) = all.no_progress_cycle() {
+            bail!(
+                "cyclic grammar references without progress: {}",
+                cycle.join(" -> ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Grammar `root` (by default, the first one) with the grammars it references
+    /// (directly or not) inserted. References to grammars not in the registry are left
+    /// unresolved, and reported by Grammar::compile().
+    pub fn grammar(&self, root: Option<&str>) -> Result<Grammar> {
+        let root = match root {
+            Some(name) => name,
+            None => match self.grammars.first() {
+                Some((name, _)) => name.as_str(),
+                None => bail!("no grammars in the registry"),
+            },
+        };
+        if self.get(root).is_none() {
+            bail!("root grammar '{root}' not found");
+        }
+        let mut grm = Grammar::new();
+        let start = grm.start();
+        let root_sym = grm.reference(root);
+        grm.add_rule(start, vec![root_sym]);
+        loop {
+            let pending = grm
+                .unresolved_references()
+                .into_iter()
+                .find(|name| self.get(name).is_some());
+            match pending {
+                Some(name) => grm.insert_subgrammar(&name, self.get(&name).unwrap())?,
+                None => break,
+            }
+        }
+        Ok(grm)
+    }
+}
+
+fn read_chunk<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    if bytes.len() < 4 {
+        bail!("truncated grammar bundle");
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    if bytes.len() - 4 < len {
+        bail!("truncated grammar bundle");
+    }
+    let r = &bytes[4..4 + len];
+    *bytes = &bytes[4 + len..];
+    Ok(r)
+}
@@ -279,6 +279,7 @@ pub struct Join<'a> {
     pub commit_point: bool,
     pub capture_name: Cow<'a, str>,
     pub max_tokens: i32,
+    pub lexeme: bool,
 }
 
 impl<'a> MessageRead<'a> for Join<'a> {
@@ -293,6 +294,7 @@ impl<'a> MessageRead<'a> for Join<'a> {
                 Ok(40) => msg.commit_point = r.read_bool(bytes)?,
                 Ok(50) => msg.capture_name = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(56) => msg.max_tokens = r.read_int32(bytes)?,
+                Ok(64) => msg.lexeme = r.read_bool(bytes)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -311,6 +313,7 @@ impl<'a> MessageWrite for Join<'a> {
         + if self.commit_point == false { 0 } else { 1 + sizeof_varint(*(&self.commit_point) as u64) }
         + if self.capture_name == "" { 0 } else { 1 + sizeof_len((&self.capture_name).len()) }
         + if self.max_tokens == 0i32 { 0 } else { 1 + sizeof_varint(*(&self.max_tokens) as u64) }
+        + if self.lexeme == false { 0 } else { 1 + sizeof_varint(*(&self.lexeme) as u64) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -321,6 +324,7 @@ impl<'a> MessageWrite for Join<'a> {
         if self.commit_point != false { w.write_with_tag(40, |w| w.write_bool(*&self.commit_point))?; }
         if self.capture_name != "" { w.write_with_tag(50, |w| w.write_string(&**&self.capture_name))?; }
         if self.max_tokens != 0i32 { w.write_with_tag(56, |w| w.write_int32(*&self.max_tokens))?; }
+        if self.lexeme != false { w.write_with_tag(64, |w| w.write_bool(*&self.lexeme))?; }
         Ok(())
     }
 }
@@ -336,6 +340,7 @@ pub struct Select<'a> {
     pub capture_name: Cow<'a, str>,
     pub max_tokens: i32,
     pub recursive: bool,
+    pub lexeme: bool,
 }
 
 impl<'a> MessageRead<'a> for Select<'a> {
@@ -351,6 +356,7 @@ impl<'a> MessageRead<'a> for Select<'a> {
                 Ok(50) => msg.capture_name = r.read_string(bytes).map(Cow::Borrowed)?,
                 Ok(56) => msg.max_tokens = r.read_int32(bytes)?,
                 Ok(64) => msg.recursive = r.read_bool(bytes)?,
+                Ok(72) => msg.lexeme = r.read_bool(bytes)?,
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -370,6 +376,7 @@ impl<'a> MessageWrite for Select<'a> {
         + if self.capture_name == "" { 0 } else { 1 + sizeof_len((&self.capture_name).len()) }
         + if self.max_tokens == 0i32 { 0 } else { 1 + sizeof_varint(*(&self.max_tokens) as u64) }
         + if self.recursive == false { 0 } else { 1 + sizeof_varint(*(&self.recursive) as u64) }
+        + if self.lexeme == false { 0 } else { 1 + sizeof_varint(*(&self.lexeme) as u64) }
     }
 
     fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -381,6 +388,7 @@ impl<'a> MessageWrite for Select<'a> {
         if self.capture_name != "" { w.write_with_tag(50, |w| w.write_string(&**&self.capture_name))?; }
         if self.max_tokens != 0i32 { w.write_with_tag(56, |w| w.write_int32(*&self.max_tokens))?; }
         if self.recursive != false { w.write_with_tag(64, |w| w.write_bool(*&self.recursive))?; }
+        if self.lexeme != false { w.write_with_tag(72, |w| w.write_bool(*&self.lexeme))?; }
         Ok(())
     }
 }
@@ -1,22 +1,61 @@
-use crate::earley::{earley_grm_from_guidance, ParseResult, Parser};
-use aici_abi::{toktree::TokTrie, MidProcessArg, MidProcessResult, TokenId, TokenizerEnv};
-use anyhow::Result;
+use crate::earley::{
+    earley_grm_from_guidance, earley_grm_from_json_schema, CaptureSpan, Grammar,
+    JsonCompileOptions, ParseRejection, ParseResult, Parser,
+};
+use aici_abi::{
+    recognizer::BiasCache,
+    time_left_us,
+    toktree::{ExtensionProbe, Recognizer, SpecialToken, TokTrie},
+    Capture, LogLevel, MidProcessArg, MidProcessResult, TokenId, TokenizerEnv,
+};
+use anyhow::{bail, Result};
+use rustc_hash::FxHasher;
+use std::hash::{Hash, Hasher};
 
-const INFO: bool = true;
+// stop computing the bias when there is less time than this left
+const MIN_TIME_LEFT_US: u64 = 2000;
+
+// limit on parser steps when checking which forced tokens could be tokenized differently
+const MAX_CHOP_PROBES: usize = 50_000;
 
 macro_rules! infoln {
     ($($arg:tt)*) => {
-        if INFO {
-            println!($($arg)*);
-        }
+        aici_abi::wlog_info!($($arg)*)
     };
 }
 
+// for the per-step details
+macro_rules! debugln {
+    ($($arg:tt)*) => {
+        aici_abi::wlog_debug!($($arg)*)
+    };
+}
+
+/// Result of TokenParser::mid_process_speculative().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpliceOrAccept {
+    /// The grammar allows the whole draft.
+    Accept,
+    /// Only the first `num_accepted` tokens of the draft are allowed; the rest has to be dropped.
+    Splice { num_accepted: usize },
+}
+
 pub struct TokenParser {
     pub token_env: Box<dyn TokenizerEnv>,
     pub parser: Parser,
     // tokens currently in KV cache
     llm_tokens: Vec<TokenId>,
+    // number of llm_tokens kept as they are split; see resplit()
+    llm_settled: usize,
+    // for each of llm_tokens, whether it was forced (rather than sampled)
+    llm_token_is_ff: Vec<bool>,
+    last_was_splice: bool,
+    // why the tokens from the LLM didn't parse in the last mid_process(), if they didn't
+    last_rejection: Option<ParseRejection>,
+    // by the parser state and the byte suffix; see bias_key()
+    bias_cache: BiasCache,
+    // parser captures as of the last mid_process()
+    reported_captures: Vec<CaptureSpan>,
 }
 
 impl TokenParser {
@@ -26,31 +65,303 @@ impl TokenParser {
 
     pub fn from_guidance_protobuf(token_env: Box<dyn TokenizerEnv>, buf: &[u8]) -> Result<Self> {
         let grm = earley_grm_from_guidance(buf)?;
+        Self::from_grammar(token_env, grm)
+    }
+
+    pub fn from_json_schema(
+        token_env: Box<dyn TokenizerEnv>,
+        schema: &serde_json::Value,
+        options: &JsonCompileOptions,
+    ) -> Result<Self> {
+        let grm = earley_grm_from_json_schema(schema, options)?;
+        Self::from_grammar(token_env, grm)
+    }
+
+    pub fn from_grammar(token_env: Box<dyn TokenizerEnv>, mut grm: Grammar) -> Result<Self> {
+        grm.resolve_special_tokens(token_env.tok_trie());
         infoln!("original: {:?}", grm);
         let grm = grm.optimize();
         infoln!("optimized: {:?}", grm);
-        let cgrm = grm.compile();
+        let cgrm = grm.compile()?;
         let parser = Parser::new(cgrm);
+        // sequences running the same grammar get the same sets for the same parser states
+        let mut bias_cache = BiasCache::default();
+        bias_cache.share(Some(grm.content_hash()));
         Ok(TokenParser {
             token_env,
             parser,
             llm_tokens: Vec::new(),
+            llm_settled: 0,
+            llm_token_is_ff: Vec::new(),
+            last_was_splice: false,
+            last_rejection: None,
+            bias_cache,
+            reported_captures: Vec::new(),
         })
     }
 
+    /// Replace the grammar, keeping the tokens generated so far; these are replayed
+    /// through the new grammar, and if they don't parse, the current grammar is kept
+    /// and an error returned.
+    pub fn swap_grammar(&mut self, mut grm: Grammar) -> Result<()> {
+        grm.resolve_special_tokens(self.token_env.tok_trie());
+        infoln!("swapping grammar: {:?}", grm);
+        let grm = grm.optimize();
+        let mut parser = Parser::new(grm.compile()?);
+        if let Err(e) = parser.apply_tokens(self.token_env.tok_trie(), &self.llm_tokens) {
+            bail!("output so far doesn't match the new grammar: {}", e);
+        }
+        self.parser = parser;
+        self.bias_cache.clear();
+        self.bias_cache.share(Some(grm.content_hash()));
+        Ok(())
+    }
+
+    /// Why the tokens from the model were rejected by the grammar in the last
+    /// mid_process(), if they were; typically the grammar then forces a stop.
+    pub fn last_rejection(&self) -> Option<&ParseRejection> {
+        self.last_rejection.as_ref()
+    }
+
+    /// Token sets reused across mid_process() calls (also ones computed by other sequences
+    /// with the same grammar), with their hit and miss counts.
+    pub fn bias_cache(&self) -> &BiasCache {
+        &self.bias_cache
+    }
+
+    // the parser state comes back e.g. after a splice, or a token rejected in post_sample,
+    // and the current row repeats in loops like free text
+    fn bias_key(&self, byte_suffix: &[u8]) -> u64 {
+        let mut h = FxHasher::default();
+        self.parser.state_key().hash(&mut h);
+        byte_suffix.hash(&mut h);
+        h.finish()
+    }
+
     pub fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        let r = self.mid_process_inner(arg);
+        self.last_was_splice = r.branches.iter().any(|b| b.sample_mask.is_none());
+        r.with_captures(self.new_captures())
+    }
+
+    /// Changes to the captures since the last mid_process(): retractions of the ones
+    /// gone (e.g., backtracked, or not in a swapped grammar), and the ones that completed
+    /// or got more bytes. Their ranges are in the output, which may not have all forced
+    /// bytes yet.
+    fn new_captures(&mut self) -> Vec<Capture> {
+        let captures = self.parser.captures();
+        let same_id =
+            |a: &CaptureSpan, b: &CaptureSpan| a.name == b.name && a.occurrence == b.occurrence;
+        let mut r: Vec<Capture> = self
+            .reported_captures
+            .iter()
+            .filter(|old| !captures.iter().any(|c| same_id(c, old)))
+            .map(|old| Capture::retraction(&old.name, old.occurrence as u32))
+            .collect();
+        r.extend(
+            captures
+                .iter()
+                .filter(|c| !self.reported_captures.contains(c))
+                .map(|c| Capture {
+                    name: c.name.clone(),
+                    occurrence: c.occurrence as u32,
+                    start: c.start as u32,
+                    end: c.end as u32,
+                    value: String::from_utf8_lossy(&self.parser.capture_bytes(c)).to_string(),
+                    retracted: false,
+                }),
+        );
+        self.reported_captures = captures.to_vec();
+        r
+    }
+
+    /// Check which prefix of `draft` (tokens proposed to follow the ones in KV cache)
+    /// the grammar allows, without changing the state of the parser.
+    pub fn mid_process_speculative(&mut self, draft: &[TokenId]) -> SpliceOrAccept {
+        let checkpoint = self.parser.checkpoint();
+        let num_accepted = self.check_draft(draft);
+        self.parser.restore(&checkpoint);
+        if num_accepted == draft.len() {
+            SpliceOrAccept::Accept
+        } else {
+            SpliceOrAccept::Splice { num_accepted }
+        }
+    }
+
+    fn check_draft(&mut self, draft: &[TokenId]) -> usize {
+        let trie = self.token_env.tok_trie();
+        // the parser may be ahead of the LLM with forced bytes; these have to match
+        let grm_bytes = self.parser.get_bytes();
+        let mut pos: usize = self.llm_tokens.iter().map(|t| trie.token(*t).len()).sum();
+        if pos > grm_bytes.len() {
+            return 0;
+        }
+        for (idx, tok) in draft.iter().enumerate() {
+            if *tok == trie.eos_token() {
+                let at_end = pos == self.parser.num_rows() - 1;
+                return if at_end && self.parser.is_accepting() {
+                    idx + 1
+                } else {
+                    idx
+                };
+            }
+            if let Some(kind) = trie.special_kind(*tok) {
+                // no bytes to scan; only allowed where the grammar says so
+                if !self.parser.special_allowed(kind) {
+                    return idx;
+                }
+                continue;
+            }
+            for b in trie.token(*tok) {
+                if pos < grm_bytes.len() {
+                    if grm_bytes[pos] != *b {
+                        return idx;
+                    }
+                } else if self.parser.scan(*b) == ParseResult::Reject
+                    // hidden items rewind the parser; don't try to follow that here
+                    || self.parser.num_rows() != pos + 2
+                {
+                    return idx;
+                }
+                pos += 1;
+            }
+        }
+        draft.len()
+    }
+
+    // The longest prefixes of llm_tokens and `grm_tokens` that decode to the same bytes,
+    // at most `max_bytes` of them; returns their lengths in tokens.
+    fn common_token_prefix(&self, grm_tokens: &[TokenId], max_bytes: usize) -> (usize, usize) {
+        let trie = self.toktrie();
+        let mut grm_ends = vec![0];
+        for t in grm_tokens {
+            grm_ends.push(grm_ends.last().unwrap() + trie.token_len(*t));
+        }
+        let mut best = (0, 0);
+        let mut pos = 0;
+        for idx in 0..=self.llm_tokens.len() {
+            if idx > 0 {
+                pos += trie.token_len(self.llm_tokens[idx - 1]);
+            }
+            if pos > max_bytes {
+                break;
+            }
+            if let Ok(grm_idx) = grm_ends.binary_search(&pos) {
+                best = (idx, grm_idx);
+            }
+        }
+        best
+    }
+
+    // The LLM has the same bytes as grm_tokens (up to `grm_prefix_len`), maybe split into
+    // other tokens. Tokens the trie splits the same way are kept (e.g., "ing" when the
+    // tokenizer has "in" + "g", which it would otherwise replace in every step), others
+    // (e.g., " " + " " for "  ") are replaced with grm_tokens. Only the tokens that came
+    // after the ones settled in earlier steps are looked at; the rest of the tokens
+    // before `grm_prefix_len` are settled now.
+    fn resplit(
+        &mut self,
+        grm_tokens: &[TokenId],
+        grm_prefix_len: usize,
+    ) -> Option<MidProcessResult> {
+        let trie = self.token_env.tok_trie();
+        let start = self.llm_settled;
+        let mut pos: usize = self.llm_tokens[..start]
+            .iter()
+            .map(|t| trie.token_len(*t))
+            .sum();
+        let mut grm_idx = 0;
+        let mut grm_pos = 0;
+        while grm_pos < pos && grm_idx < grm_tokens.len() {
+            grm_pos += trie.token_len(grm_tokens[grm_idx]);
+            grm_idx += 1;
+        }
+        if grm_pos == pos {
+            let pairs = (start..self.llm_tokens.len()).zip(grm_idx..grm_tokens.len());
+            for (idx, grm_idx) in pairs {
+                if self.llm_tokens[idx] == grm_tokens[grm_idx] {
+                    continue;
+                }
+                if trie.check_canonical(&self.llm_tokens[idx..]).is_some() {
+                    let backtrack: u32 = (self.llm_tokens.len() - idx).try_into().unwrap();
+                    let ff_tokens = grm_tokens[grm_idx..].to_vec();
+                    debugln!(
+                        "resplit: backtrack: {}, ff_tokens: {}",
+                        backtrack,
+                        trie.tokens_dbg(&ff_tokens),
+                    );
+                    return Some(MidProcessResult::splice(backtrack, ff_tokens));
+                }
+                break;
+            }
+        }
+
+        let mut settled = start;
+        while let Some(t) = self.llm_tokens.get(settled) {
+            pos += trie.token_len(*t);
+            if pos > grm_prefix_len {
+                break;
+            }
+            settled += 1;
+        }
+        self.llm_settled = settled;
+        None
+    }
+
+    fn mid_process_inner(&mut self, arg: MidProcessArg) -> MidProcessResult {
         let start_time = std::time::Instant::now();
 
-        infoln!("\n");
+        debugln!("\n");
 
-        infoln!("post tokens: {}", self.toktrie().tokens_dbg(&arg.tokens));
+        debugln!(
+            "post tokens: {}; byte_offset: {}",
+            self.toktrie().tokens_dbg(&arg.tokens),
+            arg.byte_offset
+        );
+        if arg.prev_timed_out {
+            debugln!("previous step timed out");
+        }
         arg.save_tokens(&mut self.llm_tokens);
+        self.llm_settled = self
+            .llm_settled
+            .min(self.llm_tokens.len() - arg.tokens.len());
+        // goes over all the tokens; only when debugging
+        if aici_abi::log_enabled(LogLevel::Debug) {
+            if let Some(idx) = self.toktrie().check_canonical(&self.llm_tokens) {
+                debugln!(
+                    "tokens not split greedily from: {}",
+                    self.toktrie().tokens_dbg(&self.llm_tokens[idx..])
+                );
+            }
+        }
+        if arg.backtrack > 0 {
+            // forced bytes that are still there get forced again below
+            let num_bytes = self.toktrie().decode(&self.llm_tokens).len();
+            self.parser.truncate_bytes(num_bytes);
+        }
+        // the tokens passed in are the ones we spliced last time, or the sampled one
+        let keep = self.llm_token_is_ff.len() - arg.backtrack as usize;
+        self.llm_token_is_ff.truncate(keep);
+        self.llm_token_is_ff
+            .extend(arg.tokens.iter().map(|_| self.last_was_splice));
 
-        let res = self
+        self.last_rejection = self
             .parser
-            .apply_tokens(self.token_env.tok_trie(), &self.llm_tokens);
-        if res != "" {
-            infoln!("rejected: {}", res);
+            .apply_tokens(self.token_env.tok_trie(), &self.llm_tokens)
+            .err();
+        if let Some(rej) = &self.last_rejection {
+            debugln!("rejected: {}", rej);
+            if self.parser.is_accepting() {
+                // the grammar was already complete; ignore whatever the model added
+                infoln!("grammar complete; stopping");
+                return MidProcessResult::stop();
+            }
+        }
+
+        // stop any gen() that ran out of max_tokens
+        if let Err(e) = self.parser.limit_max_tokens(&self.llm_token_is_ff) {
+            infoln!("{}", e);
+            return MidProcessResult::stop();
         }
 
         // force after scanning tokens from LLM (this may walk the parser some more)
@@ -63,48 +374,59 @@ impl TokenParser {
         // tokens/bytes forced by the grammar
         let full_grm_bytes = self.parser.get_bytes();
         let mut grm_tokens = self.token_env.tokenize_bytes(&full_grm_bytes);
-        infoln!("forced: {}", self.toktrie().tokens_dbg(&grm_tokens));
-        let mut suff = Vec::new();
-        let mut chop_tokens = 0;
-        let mut chop_bytes = 0;
-        for (idx, t) in grm_tokens.iter().rev().enumerate() {
-            suff.splice(0..0, self.toktrie().token(*t).iter().cloned());
-            if suff.len() > self.toktrie().max_token_len() {
-                break;
-            }
-            if self
-                .token_env
+        debugln!("forced: {}", self.toktrie().tokens_dbg(&grm_tokens));
+        let probes =
+            self.token_env
                 .tok_trie()
-                .has_valid_extensions(&mut self.parser, &suff)
-            {
-                chop_tokens = idx + 1;
-                chop_bytes = suff.len();
-            }
+                .probe_chop(&mut self.parser, &grm_tokens, MAX_CHOP_PROBES);
+        if probes
+            .iter()
+            .any(|(p, _)| matches!(p, ExtensionProbe::Unknown(_)))
+        {
+            debugln!("chop: out of budget; chopping conservatively");
         }
+        // if we don't know, assume the tokens could be tokenized differently
+        let (chop_tokens, chop_bytes) = probes
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, (p, _))| *p != ExtensionProbe::NoExtension)
+            .map_or((0, 0), |(idx, (_, num_bytes))| (idx + 1, *num_bytes));
 
         // here we remove a suffix from grm_tokens that could be possibly tokenized differently
         grm_tokens.truncate(grm_tokens.len() - chop_tokens);
+        let grm_prefix_len = full_grm_bytes.len() - chop_bytes;
 
-        for idx in 0..grm_tokens.len() {
-            // if the LLM state disagrees with forced tokens, we need to splice
-            if self.llm_tokens.get(idx) != grm_tokens.get(idx) {
-                let backtrack: u32 = (self.llm_tokens.len() - idx).try_into().unwrap();
-                let ff_tokens = grm_tokens[idx..].to_vec();
-                infoln!(
-                    "backtrack: {}, ff_tokens: {}",
-                    backtrack,
-                    self.toktrie().tokens_dbg(&ff_tokens),
-                );
-                infoln!("fixed_tokens: {}", self.toktrie().tokens_dbg(&grm_tokens));
-                return MidProcessResult::splice(backtrack, ff_tokens);
-            }
+        // The model may split the forced text into tokens differently than the tokenizer
+        // (e.g., "in" + "g" rather than "ing"); that's no reason to backtrack, so only
+        // splice when the LLM state lacks some forced bytes, or disagrees with them.
+        let llm_bytes = self.toktrie().decode(&self.llm_tokens);
+        let num_common = llm_bytes
+            .iter()
+            .zip(&full_grm_bytes[..grm_prefix_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if num_common < grm_prefix_len {
+            let (keep, grm_idx) = self.common_token_prefix(&grm_tokens, num_common);
+            let backtrack: u32 = (self.llm_tokens.len() - keep).try_into().unwrap();
+            let ff_tokens = grm_tokens[grm_idx..].to_vec();
+            debugln!(
+                "backtrack: {}, ff_tokens: {}",
+                backtrack,
+                self.toktrie().tokens_dbg(&ff_tokens),
+            );
+            debugln!("fixed_tokens: {}", self.toktrie().tokens_dbg(&grm_tokens));
+            return MidProcessResult::splice(backtrack, ff_tokens);
+        }
+        if let Some(r) = self.resplit(&grm_tokens, grm_prefix_len) {
+            return r;
         }
 
-        // here, grm_tokens are at most as long as llm_tokens (otherwise we would have spliced)
+        // here, the LLM has all the bytes of grm_tokens (otherwise we would have spliced)
         // llm_suffix are additional bytes generated by the model
-        let llm_suffix = self.toktrie().decode(&self.llm_tokens[grm_tokens.len()..]);
+        let llm_suffix = llm_bytes[grm_prefix_len..].to_vec();
         // grm_suffix are additional bytes generated by the grammar
-        let grm_suffix = full_grm_bytes[full_grm_bytes.len() - chop_bytes..].to_vec();
+        let grm_suffix = full_grm_bytes[grm_prefix_len..].to_vec();
 
         let byte_suffix = if grm_suffix.len() < llm_suffix.len() {
             // this branch should be unreachable, since we already walked the parser in apply_tokens() above
@@ -137,11 +459,37 @@ impl TokenParser {
 
         // self.parser.print_row(self.parser.num_rows() - 1);
 
+        if byte_suffix.is_empty() && self.parser.is_accepting() && !self.parser.can_advance() {
+            infoln!("grammar complete; stopping");
+            return MidProcessResult::stop();
+        }
+
         let mut set = self.toktrie().alloc_token_set();
-        self.token_env
-            .tok_trie()
-            .compute_bias_ext(&mut self.parser, &mut set, &byte_suffix);
-        infoln!(
+        let key = self.bias_key(&byte_suffix);
+        // a hash of the current row and the suffix; the set is taken when both are the same
+        let mut check = self.parser.state_words();
+        check.extend(byte_suffix.iter().map(|b| *b as u64));
+        if !self.bias_cache.get_checked(key, &check, &mut set) {
+            let mut limited = TimeLimited::new(&mut self.parser);
+            self.token_env
+                .tok_trie()
+                .compute_bias_ext(&mut limited, &mut set, &byte_suffix);
+            if limited.out_of_time {
+                // the set only has the tokens visited so far; they are all valid
+                infoln!("out of time computing bias");
+            } else {
+                self.bias_cache.insert_checked(key, &check, &set);
+            }
+        }
+        if !byte_suffix.is_empty() {
+            // the parser is accepting (or not) after the suffix, which the model doesn't have yet
+            set.disallow_token(self.toktrie().eos_token());
+        }
+        if set.num_set() == 0 {
+            aici_abi::wlog!(LogLevel::Warn, "no tokens allowed by the grammar; stopping");
+            return MidProcessResult::stop();
+        }
+        debugln!(
             "bias: (pref: {:?}) {:?} {}",
             String::from_utf8_lossy(&byte_suffix),
             start_time.elapsed(),
@@ -151,3 +499,63 @@ impl TokenParser {
         return MidProcessResult::sample(set);
     }
 }
+
+/// Stops the trie walk (by rejecting all further bytes) when the step deadline approaches.
+struct TimeLimited<'a> {
+    parser: &'a mut Parser,
+    num_bytes: usize,
+    out_of_time: bool,
+}
+
+impl<'a> TimeLimited<'a> {
+    fn new(parser: &'a mut Parser) -> Self {
+        TimeLimited {
+            parser,
+            num_bytes: 0,
+            out_of_time: false,
+        }
+    }
+}
+
+impl<'a> Recognizer for TimeLimited<'a> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.parser.pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.parser.collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.parser.special_allowed(tok)
+    }
+
+    fn trie_started(&mut self) {
+        self.parser.trie_started()
+    }
+
+    fn trie_finished(&mut self) {
+        self.parser.trie_finished()
+    }
+
+    fn has_fast_byte_mask(&self) -> bool {
+        self.parser.has_fast_byte_mask()
+    }
+
+    fn allowed_byte_mask(&mut self, mask_out: &mut [bool; 256]) {
+        self.parser.allowed_byte_mask(mask_out)
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.out_of_time {
+            return false;
+        }
+        // checking the time is a host call; don't do it for every byte
+        self.num_bytes += 1;
+        if self.num_bytes % 4096 == 0 && time_left_us() < MIN_TIME_LEFT_US {
+            self.out_of_time = true;
+            return false;
+        }
+        self.parser.try_push_byte(byte)
+    }
+}
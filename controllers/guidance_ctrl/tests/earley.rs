@@ -0,0 +1,1224 @@
+use aici_abi::{
+    bytes::TokRxInfo,
+    rng::Rng,
+    testing::MockTokenizerEnv,
+    toktree::{ExtensionProbe, Recognizer, SpecialToken, TokTrie},
+    TokenId, TokenizerEnv,
+};
+use aici_guidance_ctrl::{
+    earley::{
+        earley_grm_from_guidance, earley_grm_from_json_schema, validate_grammar, ByteSet, Grammar,
+        JsonCompileOptions, ParseRejection, ParseResult, Parser, SymIdx, SymbolProps,
+        ValidationConfig,
+    },
+    GrammarRegistry,
+};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// expr ::= expr "+" term | term
+// term ::= term "*" factor | factor
+// factor ::= "(" expr ")" | num
+// num ::= digit num | digit
+fn arith_parser() -> Parser {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let expr = g.symbol("expr");
+    let term = g.symbol("term");
+    let factor = g.symbol("factor");
+    let num = g.symbol("num");
+    let plus = g.terminal(&ByteSet::from_byte(b'+'));
+    let times = g.terminal(&ByteSet::from_byte(b'*'));
+    let lparen = g.terminal(&ByteSet::from_byte(b'('));
+    let rparen = g.terminal(&ByteSet::from_byte(b')'));
+    let digit = g.terminal(&ByteSet::from_range(b'0'..=b'9'));
+    g.add_rule(start, vec![expr]);
+    g.add_rule(expr, vec![expr, plus, term]);
+    g.add_rule(expr, vec![term]);
+    g.add_rule(term, vec![term, times, factor]);
+    g.add_rule(term, vec![factor]);
+    g.add_rule(factor, vec![lparen, expr, rparen]);
+    g.add_rule(factor, vec![num]);
+    g.add_rule(num, vec![digit, num]);
+    g.add_rule(num, vec![digit]);
+    Parser::new(g.optimize().compile().unwrap())
+}
+
+fn expected(parser: &Parser) -> Vec<String> {
+    let mut r = parser
+        .expected_terminals_at_current()
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+    r.sort();
+    r
+}
+
+fn scan(parser: &mut Parser, s: &str) {
+    assert!(
+        parser.scan_bytes(s.as_bytes()) != ParseResult::Reject,
+        "{s:?}"
+    );
+}
+
+#[test]
+fn expected_terminals_at_positions() {
+    let mut parser = arith_parser();
+    assert_eq!(expected(&parser), vec!["'('", "0-9"]);
+
+    scan(&mut parser, "12");
+    assert_eq!(expected(&parser), vec!["'*'", "'+'", "0-9"]);
+
+    scan(&mut parser, "*(");
+    assert_eq!(expected(&parser), vec!["'('", "0-9"]);
+
+    scan(&mut parser, "3");
+    assert_eq!(expected(&parser), vec!["')'", "'*'", "'+'", "0-9"]);
+}
+
+#[test]
+fn reject_info_has_position_and_rules() {
+    let mut parser = arith_parser();
+    scan(&mut parser, "(1+");
+    assert!(parser.last_reject_info().is_none());
+    assert_eq!(parser.scan(b')'), ParseResult::Reject);
+
+    let info = parser.last_reject_info().unwrap();
+    assert_eq!(info.byte, b')');
+    assert_eq!(info.byte_offset, 3);
+    assert_eq!(info.rule_stack.first().unwrap(), "_start");
+    assert!(info.rule_stack.len() > 1, "{:?}", info.rule_stack);
+    let mut exp = info
+        .expected
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+    exp.sort();
+    assert_eq!(exp, vec!["'('", "0-9"]);
+}
+
+#[test]
+fn state_key_follows_chart() {
+    let key = |input: &str| {
+        let mut parser = arith_parser();
+        scan(&mut parser, input);
+        parser.state_key().unwrap()
+    };
+    // digits are not told apart in the chart
+    assert_eq!(key("1+2"), key("1+3"));
+    assert_ne!(key("1+2"), key("1+("));
+    assert_ne!(key("1+2"), key("1*2"));
+    assert_ne!(key("(1"), key("1"));
+
+    let mut parser = arith_parser();
+    scan(&mut parser, "(1");
+    let checkpoint = parser.checkpoint();
+    scan(&mut parser, "+2)");
+    assert_ne!(parser.state_key(), Some(key("(1")));
+    parser.restore(&checkpoint);
+    assert_eq!(parser.state_key(), Some(key("(1")));
+}
+
+#[test]
+fn apply_tokens_reports_rejection() {
+    let env = MockTokenizerEnv::new(&[]);
+    let trie = env.tok_trie();
+    let mut parser = arith_parser();
+    let tokens = env.tokenize("1+*");
+    match parser.apply_tokens(trie, &tokens) {
+        Err(ParseRejection::Rejected(info)) => {
+            assert_eq!(info.byte, b'*');
+            assert_eq!(info.byte_offset, 2);
+        }
+        r => panic!("unexpected {r:?}"),
+    }
+
+    let mut parser = arith_parser();
+    assert_eq!(parser.apply_tokens(trie, &env.tokenize("(1+2)*3")), Ok(()));
+    assert!(parser.is_accepting());
+}
+
+// start ::= "x" gen | "xabc"
+// gen ::= "" | a-c gen, limited to 2 tokens
+fn max_tokens_parser() -> Parser {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let gen = g.symbol("gen");
+    let letter = g.terminal(&ByteSet::from_range(b'a'..=b'c'));
+    let [x, a, b, c] = [b'x', b'a', b'b', b'c'].map(|ch| byte(&mut g, ch));
+    g.add_rule(start, vec![x, gen]);
+    g.add_rule(start, vec![x, a, b, c]);
+    g.add_rule(gen, vec![]);
+    g.add_rule(gen, vec![letter, gen]);
+    g.apply_props(
+        gen,
+        SymbolProps {
+            max_tokens: 2,
+            ..Default::default()
+        },
+    );
+    Parser::new(g.optimize().compile().unwrap())
+}
+
+#[test]
+fn max_tokens_keeps_other_parses() {
+    let env = MockTokenizerEnv::new(&[]);
+    let trie = env.tok_trie();
+    let tokens = env.tokenize("xab");
+
+    let mut parser = max_tokens_parser();
+    assert_eq!(parser.apply_tokens(trie, &tokens), Ok(()));
+    assert!(parser.limit_max_tokens(&[false; 3]).unwrap());
+    assert!(parser.is_accepting());
+    // gen ended, but "xabc" is still possible
+    assert_eq!(expected(&parser), vec!["c"]);
+    scan(&mut parser, "c");
+    assert!(parser.is_accepting());
+
+    // forced tokens don't count
+    let mut parser = max_tokens_parser();
+    assert_eq!(parser.apply_tokens(trie, &tokens), Ok(()));
+    assert!(!parser.limit_max_tokens(&[false, true, false]).unwrap());
+    assert_eq!(expected(&parser), vec!["a-c", "c"]);
+
+    // neither dropping the rows of a checkpoint nor changing their tokens sticks
+    let env2 = MockTokenizerEnv::new(&["xa"]);
+    let mut parser = max_tokens_parser();
+    assert_eq!(parser.apply_tokens(trie, &tokens), Ok(()));
+    let checkpoint = parser.checkpoint();
+    for _ in 0..2 {
+        assert!(parser.limit_max_tokens(&[false; 3]).unwrap());
+        scan(&mut parser, "c");
+        parser.restore(&checkpoint);
+        let tokens2 = env2.tokenize("xab");
+        assert_eq!(tokens2.len(), 2);
+        assert_eq!(parser.apply_tokens(env2.tok_trie(), &tokens2), Ok(()));
+        parser.restore(&checkpoint);
+    }
+    assert!(!parser.limit_max_tokens(&[false, true, false]).unwrap());
+    assert_eq!(expected(&parser), vec!["a-c", "c"]);
+}
+
+fn json_parser(flexible_whitespace: Option<usize>) -> Parser {
+    let schema = json!({
+        "type": "object",
+        "properties": { "age": { "type": "integer" } },
+        "required": ["age"]
+    });
+    let options = JsonCompileOptions {
+        flexible_whitespace,
+        ..Default::default()
+    };
+    let grm = earley_grm_from_json_schema(&schema, &options).unwrap();
+    Parser::new(grm.optimize().compile().unwrap())
+}
+
+#[test]
+fn flexible_whitespace_is_not_forced() {
+    let mut parser = json_parser(None);
+    assert_eq!(parser.force_bytes(), br#"{"age":"#);
+
+    // forcing stops wherever whitespace is allowed
+    let mut parser = json_parser(Some(4));
+    assert_eq!(parser.force_bytes(), b"{");
+    scan(&mut parser, "\n  \"");
+    assert_eq!(parser.force_bytes(), br#"age""#);
+    scan(&mut parser, " : 42\n}");
+    assert!(parser.is_accepting());
+
+    // runs of whitespace are limited
+    let mut parser = json_parser(Some(4));
+    scan(&mut parser, "{    ");
+    assert_eq!(parser.scan(b' '), ParseResult::Reject);
+}
+
+fn schema_parser(schema: serde_json::Value) -> Parser {
+    let grm = earley_grm_from_json_schema(&schema, &JsonCompileOptions::default()).unwrap();
+    Parser::new(grm.optimize().compile().unwrap())
+}
+
+/// TokTrie::chop_tokens() as it used to be, probing with has_valid_extensions() for every suffix.
+fn chop_tokens_ref(trie: &TokTrie, r: &mut impl Recognizer, tokens: &[TokenId]) -> (usize, usize) {
+    let max_len = trie.max_token_len();
+    let mut num_toks = 0;
+    let mut num_bytes = 0;
+    for t in tokens.iter().rev() {
+        let len = trie.token_len(*t);
+        if num_bytes + len > max_len {
+            break;
+        }
+        num_toks += 1;
+        num_bytes += len;
+    }
+
+    let suff = trie.decode(&tokens[tokens.len() - num_toks..]);
+    let mut chop = (0, 0);
+    let mut suff_len = 0;
+    for (idx, t) in tokens.iter().rev().take(num_toks).enumerate() {
+        suff_len += trie.token_len(*t);
+        if trie.has_valid_extensions(r, &suff[suff.len() - suff_len..]) {
+            chop = (idx + 1, suff_len);
+        }
+    }
+    chop
+}
+
+#[test]
+fn chop_tokens_matches_probing_every_suffix() {
+    let env = MockTokenizerEnv::new(&["{\"", "\"property_", "property", "_0", "00", "\":", ",\""]);
+    let trie = env.tok_trie();
+
+    // force a ~500 byte object through a schema grammar, checking every token boundary
+    let names = (0..30)
+        .map(|i| format!("property_{i:03}"))
+        .collect::<Vec<_>>();
+    let props = names
+        .iter()
+        .map(|n| (n.clone(), json!({ "type": "integer" })))
+        .collect::<serde_json::Map<_, _>>();
+    let schema = json!({
+        "type": "object",
+        "properties": props,
+        "required": names,
+        "additionalProperties": false,
+    });
+    let input = format!(
+        "{{{}}}",
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| format!("\"{n}\":{i}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+
+    let mut prefix_len = 0;
+    for tok in trie.greedy_tokenize(input.as_bytes()) {
+        let mut parser = schema_parser(schema.clone());
+        assert!(parser.scan_bytes(&input.as_bytes()[0..prefix_len]) != ParseResult::Reject);
+        let _ = parser.force_bytes();
+        let grm_tokens = trie.greedy_tokenize(&parser.get_bytes());
+
+        let expected = chop_tokens_ref(trie, &mut parser, &grm_tokens);
+        let chop = trie.chop_tokens(&mut parser, &grm_tokens);
+        assert_eq!(chop, expected, "chop at {prefix_len}");
+
+        // whatever is settled within a small budget, has to be right
+        let full = trie.probe_chop(&mut parser, &grm_tokens, usize::MAX);
+        let limited = trie.probe_chop(&mut parser, &grm_tokens, 10);
+        for (l, f) in limited.iter().zip(full.iter()) {
+            assert!(matches!(l.0, ExtensionProbe::Unknown(_)) || l == f);
+        }
+
+        prefix_len += trie.token_len(tok);
+    }
+    assert_eq!(prefix_len, input.len());
+}
+
+// whether the bytes are a complete JSON value the schema allows
+fn schema_accepts(schema: &serde_json::Value, bytes: &[u8]) -> bool {
+    let mut parser = schema_parser(schema.clone());
+    parser.scan_bytes(bytes) != ParseResult::Reject && parser.is_accepting()
+}
+
+#[test]
+fn json_strings_are_utf8() {
+    let schema = json!({ "type": "string" });
+    for s in [
+        "\"plain\"",
+        "\"caf\u{e9}\"",
+        "\"\u{2014}\u{1F600}\"",
+        "\"\\u00e9\"",
+    ] {
+        assert!(schema_accepts(&schema, s.as_bytes()), "{s:?}");
+    }
+    let invalid: &[&[u8]] = &[
+        b"\"\xff\"",
+        b"\"\x80\"",             // continuation byte without a lead byte
+        b"\"\xc3\"",             // truncated
+        b"\"\xc0\xaf\"",         // overlong '/'
+        b"\"\xed\xa0\x80\"",     // surrogate
+        b"\"\xf4\x90\x80\x80\"", // past U+10FFFF
+    ];
+    for s in invalid {
+        assert!(!schema_accepts(&schema, s), "{s:?}");
+    }
+
+    // lengths count characters
+    let schema = json!({ "type": "string", "minLength": 2, "maxLength": 2 });
+    assert!(schema_accepts(&schema, "\"\u{e9}\u{2014}\"".as_bytes()));
+    assert!(schema_accepts(&schema, b"\"\\n\\t\""));
+    assert!(!schema_accepts(&schema, "\"\u{e9}\"".as_bytes()));
+    assert!(!schema_accepts(&schema, "\"abc\"".as_bytes()));
+}
+
+#[test]
+fn json_properties_follow_schema_order() {
+    let mut parser = schema_parser(json!({
+        "type": "object",
+        "properties": {
+            "zip": { "type": "integer" },
+            "city": { "type": "string" }
+        },
+        "required": ["zip", "city"]
+    }));
+    assert_eq!(parser.force_bytes(), br#"{"zip":"#);
+    scan(&mut parser, "12");
+    scan(&mut parser, ",");
+    assert_eq!(parser.force_bytes(), br#""city":""#);
+}
+
+#[test]
+fn invalid_json_schemas_are_errors() {
+    let options = JsonCompileOptions::default();
+    let invalid = [
+        (json!(true), "unconstrained"),
+        (json!(42), "must be an object"),
+        (json!({ "type": "string", "pattern": "a+" }), "'pattern'"),
+        (json!({ "type": [] }), "empty array"),
+        (json!({ "type": "thing" }), "unknown type"),
+        (json!({ "enum": [] }), "'enum'"),
+        (
+            json!({ "type": "integer", "minimum": 5, "maximum": 1 }),
+            "greater than",
+        ),
+        (json!({ "type": "integer", "minimum": 1.5 }), "'minimum'"),
+        (json!({ "type": "string", "maxLength": -1 }), "'maxLength'"),
+        (json!({ "type": "array" }), "'items'"),
+        (
+            json!({ "type": "object", "properties": {}, "required": ["x"] }),
+            "'required'",
+        ),
+        (
+            json!({ "type": "object", "properties": { "x": { "type": "date" } } }),
+            "#/properties/x: unknown type",
+        ),
+    ];
+    for (schema, msg) in invalid {
+        let err = earley_grm_from_json_schema(&schema, &options).unwrap_err();
+        assert!(err.to_string().contains(msg), "{schema}: {err}");
+    }
+}
+
+fn byte(g: &mut Grammar, b: u8) -> SymIdx {
+    g.terminal(&ByteSet::from_byte(b))
+}
+
+// lhs ::= '"' chars '"'
+// chars ::= "" | char chars
+// char ::= a-z | '\\' ( '"' | '\\' )
+fn add_quoted_string(g: &mut Grammar, lhs: SymIdx) {
+    let quote = byte(g, b'"');
+    let backslash = byte(g, b'\\');
+    let letter = g.terminal(&ByteSet::from_range(b'a'..=b'z'));
+    let chars = g.symbol("chars");
+    let char = g.symbol("char");
+    g.add_rule(lhs, vec![quote, chars, quote]);
+    g.add_rule(chars, vec![]);
+    g.add_rule(chars, vec![char, chars]);
+    g.add_rule(char, vec![letter]);
+    g.add_rule(char, vec![backslash, quote]);
+    g.add_rule(char, vec![backslash, backslash]);
+}
+
+// start ::= pairs
+// pairs ::= pair | pair ',' pairs
+// pair ::= string '=' string
+fn key_value_list(string: impl FnOnce(&mut Grammar) -> SymIdx) -> Grammar {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let string = string(&mut g);
+    let pairs = g.symbol("pairs");
+    let pair = g.symbol("pair");
+    let comma = byte(&mut g, b',');
+    let eq = byte(&mut g, b'=');
+    g.add_rule(start, vec![pairs]);
+    g.add_rule(pairs, vec![pair]);
+    g.add_rule(pairs, vec![pair, comma, pairs]);
+    g.add_rule(pair, vec![string, eq, string]);
+    g
+}
+
+fn inlined_key_value_list() -> Grammar {
+    key_value_list(|g| {
+        let string = g.symbol("string");
+        add_quoted_string(g, string);
+        string
+    })
+}
+
+fn quoted_string() -> Grammar {
+    let mut g = Grammar::new();
+    let start = g.start();
+    add_quoted_string(&mut g, start);
+    g
+}
+
+// what the parser forces and expects before each byte, and what the byte does
+fn trace(mut parser: Parser, input: &str) -> Vec<(Vec<u8>, Vec<String>, ParseResult, bool)> {
+    let mut r = vec![];
+    for b in input.bytes() {
+        let checkpoint = parser.checkpoint();
+        let forced = parser.force_bytes();
+        parser.restore(&checkpoint);
+        let res = parser.scan(b);
+        r.push((forced, expected(&parser), res, parser.is_accepting()));
+        if res == ParseResult::Reject {
+            break;
+        }
+    }
+    r
+}
+
+#[test]
+fn subgrammars_behave_like_inlined_rules() {
+    let mut composed = key_value_list(|g| g.reference("string"));
+    assert_eq!(composed.unresolved_references(), vec!["string"]);
+    let err = composed.compile().err().unwrap();
+    assert!(err.to_string().contains("string"), "{err}");
+
+    composed
+        .insert_subgrammar("string", &quoted_string())
+        .unwrap();
+    assert!(composed.unresolved_references().is_empty());
+    assert!(composed
+        .insert_subgrammar("string", &quoted_string())
+        .is_err());
+
+    for input in [
+        r#""ab"="c","d"="e\"f""#,
+        r#""a"="""#,
+        r#""a"=b"#,
+        r#""a\x"="b""#,
+        r#""a"="b",,"#,
+    ] {
+        let inlined = Parser::new(inlined_key_value_list().optimize().compile().unwrap());
+        let parser = Parser::new(composed.optimize().compile().unwrap());
+        assert_eq!(trace(parser, input), trace(inlined, input), "{input}");
+    }
+}
+
+#[test]
+fn subgrammar_start_keeps_its_props() {
+    let mut string = quoted_string();
+    let start = string.start();
+    string.apply_props(
+        start,
+        SymbolProps {
+            capture_name: Some("s".to_string()),
+            ..Default::default()
+        },
+    );
+    let mut composed = key_value_list(|g| g.reference("string"));
+    composed.insert_subgrammar("string", &string).unwrap();
+    let mut parser = Parser::new(composed.optimize().compile().unwrap());
+    scan(&mut parser, r#""ab"="c""#);
+    let captures = parser.captures().to_vec();
+    assert_eq!(captures.len(), 2);
+    assert_eq!(parser.capture_bytes(&captures[1]), br#""c""#);
+}
+
+#[test]
+fn registry_resolves_references_transitively() {
+    // "string" is only referenced by "pair", which is referenced by the root
+    let mut pair = Grammar::new();
+    let start = pair.start();
+    let string = pair.reference("string");
+    let eq = byte(&mut pair, b'=');
+    pair.add_rule(start, vec![string, eq, string]);
+
+    let mut root = Grammar::new();
+    let start = root.start();
+    let pair_ref = root.reference("pair");
+    let pairs = root.symbol("pairs");
+    let comma = byte(&mut root, b',');
+    root.add_rule(start, vec![pairs]);
+    root.add_rule(pairs, vec![pair_ref]);
+    root.add_rule(pairs, vec![pair_ref, comma, pairs]);
+
+    let mut registry = GrammarRegistry::new();
+    registry.insert("root", root).unwrap();
+    registry.insert("pair", pair).unwrap();
+    let grm = registry.grammar(None).unwrap();
+    let err = grm.compile().err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "missing grammar(s) for reference(s): string"
+    );
+
+    registry.insert("string", quoted_string()).unwrap();
+    registry.check_cycles().unwrap();
+    let input = r#""ab"="c","d"="e\"f""#;
+    let grm = registry.grammar(Some("root")).unwrap();
+    let parser = Parser::new(grm.optimize().compile().unwrap());
+    let inlined = Parser::new(inlined_key_value_list().optimize().compile().unwrap());
+    assert_eq!(trace(parser, input), trace(inlined, input));
+}
+
+// a ::= x b | "", b ::= c, c ::= a | "y"; with `progress`, the x is there
+fn cyclic_registry(progress: bool) -> GrammarRegistry {
+    let mut registry = GrammarRegistry::new();
+    for (name, other) in [("a", "b"), ("b", "c"), ("c", "a")] {
+        let mut g = Grammar::new();
+        let start = g.start();
+        let r = g.reference(other);
+        match name {
+            "a" if progress => {
+                let x = byte(&mut g, b'x');
+                g.add_rule(start, vec![x, r]);
+                g.add_rule(start, vec![]);
+            }
+            "c" => {
+                let y = byte(&mut g, b'y');
+                g.add_rule(start, vec![r]);
+                g.add_rule(start, vec![y]);
+            }
+            _ => g.add_rule(start, vec![r]),
+        }
+        registry.insert(name, g).unwrap();
+    }
+    registry
+}
+
+#[test]
+fn registry_allows_recursion_with_progress() {
+    let registry = cyclic_registry(true);
+    registry.check_cycles().unwrap();
+    let grm = registry.grammar(Some("a")).unwrap();
+    let mut parser = Parser::new(grm.optimize().compile().unwrap());
+    scan(&mut parser, "xxxy");
+    assert!(parser.is_accepting());
+    let mut parser = Parser::new(grm.optimize().compile().unwrap());
+    assert_eq!(parser.scan(b'y'), ParseResult::Reject);
+}
+
+#[test]
+fn registry_rejects_cyclic_references() {
+    let err = cyclic_registry(false).check_cycles().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "cyclic grammar references without progress: a -> b -> c -> a"
+    );
+
+    // malformed bundles
+    let bundle = GrammarRegistry::encode_bundle(&[("a", &b"abc"[..])]);
+    assert!(GrammarRegistry::from_bundle(&bundle[..bundle.len() - 1]).is_err());
+    assert!(GrammarRegistry::from_bundle(&bundle[0..3]).is_err());
+}
+
+// like trace(), with the union of the expected bytes, as lexemes are described differently
+fn byte_trace(mut parser: Parser, input: &str) -> Vec<(Vec<u8>, String, ParseResult, bool)> {
+    let mut r = vec![];
+    for b in input.bytes() {
+        let checkpoint = parser.checkpoint();
+        let forced = parser.force_bytes();
+        parser.restore(&checkpoint);
+        let res = parser.scan(b);
+        let mut bytes = ByteSet::new();
+        for t in parser.expected_terminals_at_current() {
+            bytes |= t.bytes;
+        }
+        r.push((forced, bytes.to_string(), res, parser.is_accepting()));
+        if res == ParseResult::Reject {
+            break;
+        }
+    }
+    r
+}
+
+fn assert_same_as_earley(g: &Grammar, inputs: &[String]) {
+    let g = g.optimize();
+    for input in inputs {
+        let parser = Parser::new(g.compile().unwrap());
+        let earley = Parser::new(g.compile_without_lexemes().unwrap());
+        assert_eq!(
+            byte_trace(parser, input),
+            byte_trace(earley, input),
+            "{input:?}"
+        );
+    }
+}
+
+fn all_strings(alphabet: &str, max_len: usize) -> Vec<String> {
+    let mut r = vec![String::new()];
+    let mut last = r.clone();
+    for _ in 0..max_len {
+        last = last
+            .iter()
+            .flat_map(|s| alphabet.chars().map(move |c| format!("{s}{c}")))
+            .collect();
+        r.extend_from_slice(&last);
+    }
+    r
+}
+
+#[test]
+fn lexemes_parse_like_earley() {
+    // start ::= word "a" word, where word ::= [ab]+ is a lexeme; after "aa",
+    // the word may go on or end
+    let mut g = Grammar::new();
+    let start = g.start();
+    let letter = g.terminal(&ByteSet::from_range(b'a'..=b'b'));
+    let a = byte(&mut g, b'a');
+    let word = g.symbol("word");
+    g.add_rule(word, vec![letter, word]);
+    g.add_rule(word, vec![letter]);
+    g.make_lexeme(word);
+    g.add_rule(start, vec![word, a, word]);
+    assert_same_as_earley(&g, &all_strings("abc", 6));
+
+    // left recursion, and a lexeme with a capture
+    let mut g = inlined_key_value_list();
+    let string = g.symbol("string");
+    g.make_lexeme(string);
+    let mut list = Grammar::new();
+    let start = list.start();
+    let pairs = list.symbol("pairs");
+    let comma = byte(&mut list, b',');
+    let string = list.symbol("string");
+    add_quoted_string(&mut list, string);
+    list.add_rule(pairs, vec![string]);
+    list.add_rule(pairs, vec![pairs, comma, string]);
+    list.add_rule(start, vec![pairs]);
+    list.apply_props(
+        string,
+        SymbolProps {
+            capture_name: Some("s".to_string()),
+            ..Default::default()
+        },
+    );
+    list.make_lexeme(string);
+    let inputs = [
+        r#""ab"="c","d"="e\"f""#,
+        r#""a"="""#,
+        r#""a"=b"#,
+        r#""a\x"="b""#,
+        r#""a"="b",,"#,
+    ];
+    assert_same_as_earley(&g, &inputs.map(String::from));
+    assert_same_as_earley(&list, &inputs.map(|s| s.replace('=', ",")));
+
+    let mut parser = Parser::new(list.optimize().compile().unwrap());
+    scan(&mut parser, r#""ab","c\"""#);
+    let captures = parser.captures().to_vec();
+    let mut earley = Parser::new(list.optimize().compile_without_lexemes().unwrap());
+    scan(&mut earley, r#""ab","c\"""#);
+    assert_eq!(captures, earley.captures());
+    assert_eq!(captures.len(), 2);
+    let c = &captures[1];
+    assert_eq!((c.occurrence, c.start, c.end), (1, 5, 10));
+    assert_eq!(parser.capture_bytes(c), br#""c\"""#);
+
+    // long literals are lexemes; this one shares a prefix with the other alternative
+    let mut g = Grammar::new();
+    let start = g.start();
+    let text = "abcdefghij".repeat(4);
+    let literal = text.bytes().map(|b| byte(&mut g, b)).collect::<Vec<_>>();
+    let mut other = literal[0..20].to_vec();
+    other.push(byte(&mut g, b'x'));
+    g.add_rule(start, literal);
+    g.add_rule(start, other);
+    let inputs = [
+        text.clone(),
+        format!("{}x", &text[0..20]),
+        format!("{}y", &text[0..30]),
+        format!("{}!", text),
+    ];
+    assert_same_as_earley(&g, &inputs);
+    let mut parser = Parser::new(g.optimize().compile().unwrap());
+    scan(&mut parser, &text[0..21]);
+    assert_eq!(parser.force_bytes(), text[21..].as_bytes());
+    assert!(parser.is_accepting());
+}
+
+#[test]
+fn lexemes_have_to_be_regular() {
+    // parens ::= "(" parens ")" | ""
+    let mut g = Grammar::new();
+    let start = g.start();
+    let parens = g.symbol("parens");
+    let lparen = byte(&mut g, b'(');
+    let rparen = byte(&mut g, b')');
+    g.add_rule(parens, vec![lparen, parens, rparen]);
+    g.add_rule(parens, vec![]);
+    g.add_rule(start, vec![parens]);
+    assert!(g.compile().is_ok());
+    g.make_lexeme(parens);
+    let err = g.compile().err().unwrap();
+    assert!(err.to_string().contains("not regular"), "{err}");
+    assert!(g.compile_without_lexemes().is_ok());
+
+    // captures are only allowed on the lexeme itself
+    let mut g = quoted_string();
+    let chars = g.symbol("chars");
+    let start = g.start();
+    g.apply_props(
+        chars,
+        SymbolProps {
+            capture_name: Some("chars".to_string()),
+            ..Default::default()
+        },
+    );
+    g.make_lexeme(start);
+    let err = g.compile().err().unwrap();
+    assert!(err.to_string().contains("chars"), "{err}");
+}
+
+// protobuf encoding of guidance grammars: a field with a varint, or a length-delimited one
+fn pb_varint(field: u32, mut v: u64, out: &mut Vec<u8>) {
+    out.push((field << 3) as u8);
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn pb_bytes(field: u32, data: &[u8], out: &mut Vec<u8>) {
+    out.push((field << 3 | 2) as u8);
+    assert!(data.len() < 0x80);
+    out.push(data.len() as u8);
+    out.extend_from_slice(data);
+}
+
+// what guidance sends for nodes without max_tokens
+const NO_MAX_TOKENS: u64 = 100_000_000;
+
+/// The guidance grammar for `parens ::= "(" parens ")" | ""`, where parens is a lexeme
+/// if `lexeme` is set.
+fn guidance_parens(lexeme: bool) -> Vec<u8> {
+    // nodes: 0 join(start), 1 select(parens), 2 join(nested), 3 byte "(", 4 byte ")"
+    let join = |name: &str, values: &[u8]| {
+        let mut r = vec![];
+        pb_bytes(2, values, &mut r);
+        pb_bytes(3, name.as_bytes(), &mut r);
+        pb_varint(7, NO_MAX_TOKENS, &mut r);
+        (1, r)
+    };
+    let byte = |b: u8| {
+        let mut r = vec![];
+        pb_bytes(1, &[b], &mut r);
+        (3, r)
+    };
+    let mut parens = vec![];
+    pb_varint(1, 1, &mut parens); // nullable
+    pb_bytes(2, &[2], &mut parens);
+    pb_bytes(3, b"parens", &mut parens);
+    pb_varint(7, NO_MAX_TOKENS, &mut parens);
+    pb_varint(9, lexeme as u64, &mut parens);
+    let nodes = [
+        join("start", &[1]),
+        (2, parens),
+        join("nested", &[3, 1, 4]),
+        byte(b'('),
+        byte(b')'),
+    ];
+    let mut grammar = vec![];
+    for (kind, node) in nodes {
+        let mut function = vec![];
+        pb_bytes(kind, &node, &mut function);
+        pb_bytes(1, &function, &mut grammar);
+    }
+    grammar
+}
+
+#[test]
+fn guidance_lexemes_are_kept() {
+    let g = earley_grm_from_guidance(&guidance_parens(false)).unwrap();
+    let mut parser = Parser::new(g.compile().unwrap());
+    assert_eq!(parser.scan_bytes(b"(())"), ParseResult::Accept);
+    let g = earley_grm_from_guidance(&guidance_parens(true)).unwrap();
+    let err = g.compile().err().unwrap();
+    assert!(err.to_string().contains("not regular"), "{err}");
+}
+
+// start ::= choice tail, where choice is one of `options`
+fn string_choice(options: &[&str], tail: &str, as_trie: bool) -> Parser {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let choice = g.symbol("choice");
+    if as_trie {
+        let options = options.iter().map(|o| o.as_bytes()).collect::<Vec<_>>();
+        g.add_string_choice(choice, &options);
+    } else {
+        for o in options {
+            let rhs = o.bytes().map(|b| byte(&mut g, b)).collect();
+            g.add_rule(choice, rhs);
+        }
+    }
+    let mut rhs = vec![choice];
+    rhs.extend(tail.bytes().map(|b| byte(&mut g, b)));
+    g.add_rule(start, rhs);
+    Parser::new(g.optimize().compile().unwrap())
+}
+
+#[test]
+fn string_choices_share_prefixes() {
+    let options = ["a", "ab", "abc"];
+    let mut parser = string_choice(&options, "", true);
+    assert_eq!(parser.force_bytes(), b"a");
+    assert!(parser.is_accepting());
+    // nothing more is forced; the model picks where to stop
+    assert_eq!(parser.force_bytes(), b"");
+    let parser = string_choice(&options, "", true);
+    assert_eq!(
+        byte_trace(parser, "abcd"),
+        vec![
+            (b"a".to_vec(), "b".to_string(), ParseResult::Accept, true),
+            (vec![], "c".to_string(), ParseResult::Accept, true),
+            (vec![], "".to_string(), ParseResult::Accept, true),
+            (vec![], "".to_string(), ParseResult::Reject, true),
+        ]
+    );
+
+    // forcing ends where the options differ, or the next rule may start
+    let options = ["foo", "foobar", "baz"];
+    let mut parser = string_choice(&options, ".", true);
+    assert_eq!(parser.force_bytes(), b"");
+    assert_eq!(expected(&parser), vec!["b", "f"]);
+    scan(&mut parser, "f");
+    assert_eq!(parser.force_bytes(), b"oo");
+    assert_eq!(expected(&parser), vec!["'.'", "b"]);
+    scan(&mut parser, "b");
+    assert_eq!(parser.force_bytes(), b"ar.");
+    assert!(parser.is_accepting());
+
+    // the empty option
+    let options = ["", "x", "xy"];
+    let parser = string_choice(&options, ".", true);
+    assert_eq!(
+        byte_trace(parser, "xy."),
+        vec![
+            (vec![], "'.';y".to_string(), ParseResult::Continue, false),
+            (vec![], "'.'".to_string(), ParseResult::Continue, false),
+            (b".".to_vec(), "".to_string(), ParseResult::Accept, true),
+        ]
+    );
+    let mut parser = string_choice(&options, ".", true);
+    scan(&mut parser, ".");
+    assert!(parser.is_accepting());
+
+    // the same language as plain alternatives, with fewer items
+    for (options, tail) in [
+        (&["a", "ab", "abc"][..], ""),
+        (&["", "ab", "ac", "b"][..], "c"),
+    ] {
+        for input in all_strings("abc", 4) {
+            assert_eq!(
+                byte_trace(string_choice(options, tail, true), &input),
+                byte_trace(string_choice(options, tail, false), &input),
+                "{options:?} {input:?}"
+            );
+        }
+    }
+    let mut trie = string_choice(&["abcx", "abcy", "abcz"], "", true);
+    let mut alternatives = string_choice(&["abcx", "abcy", "abcz"], "", false);
+    scan(&mut trie, "abc");
+    scan(&mut alternatives, "abc");
+    assert!(trie.chart_size() < alternatives.chart_size());
+}
+
+/// Hides Parser::allowed_byte_mask() from the trie walk.
+struct NoByteMask<'a>(&'a mut Parser);
+
+impl<'a> Recognizer for NoByteMask<'a> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.pop_bytes(num)
+    }
+    fn collapse(&mut self) {
+        self.0.collapse()
+    }
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.special_allowed(tok)
+    }
+    fn trie_started(&mut self) {
+        self.0.trie_started()
+    }
+    fn trie_finished(&mut self) {
+        self.0.trie_finished()
+    }
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.try_push_byte(byte)
+    }
+}
+
+#[test]
+fn byte_mask_allows_the_same_tokens() {
+    let env = MockTokenizerEnv::default();
+    let trie = env.tok_trie();
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "age": { "type": "integer" },
+            "name": { "type": "string" },
+            "tags": { "type": "array", "items": { "type": "string" } }
+        },
+        "required": ["age", "name"]
+    });
+    let options = JsonCompileOptions {
+        flexible_whitespace: Some(4),
+        ..Default::default()
+    };
+    let grm = earley_grm_from_json_schema(&schema, &options).unwrap();
+    let json_parser = Parser::new(grm.optimize().compile().unwrap());
+    let inputs = [
+        (
+            json_parser,
+            r#"{ "age": 42, "name": "Joe \"the\" value", "tags": ["a", "b"] }"#,
+        ),
+        (arith_parser(), "(12+3)*45+(6)"),
+    ];
+    for (mut parser, input) in inputs {
+        let mut fast = trie.alloc_token_set();
+        let mut slow = trie.alloc_token_set();
+        for tok in env.tokenize(input) {
+            trie.compute_bias(&mut parser, &mut fast);
+            trie.compute_bias(&mut NoByteMask(&mut parser), &mut slow);
+            let differ = (0..trie.vocab_size() as u32)
+                .filter(|t| fast.is_allowed(*t) != slow.is_allowed(*t))
+                .map(|t| trie.token_dbg(t))
+                .collect::<Vec<_>>();
+            assert!(differ.is_empty(), "{input:?}: {differ:?}");
+            assert!(fast.is_allowed(tok), "{input:?}: {}", trie.token_dbg(tok));
+            trie.append_token(&mut parser, tok);
+        }
+        assert!(parser.is_accepting());
+    }
+}
+
+/// Printable ASCII, a few words, and EOS.
+fn printable_trie() -> TokTrie {
+    let mut tokens: Vec<Vec<u8>> = (0x20..0x7fu8).map(|b| vec![b]).collect();
+    tokens.push(b"key".to_vec());
+    tokens.push("é".as_bytes().to_vec());
+    tokens.push(vec![]);
+    let info = TokRxInfo {
+        vocab_size: tokens.len() as u32,
+        tok_eos: tokens.len() as u32 - 1,
+    };
+    TokTrie::from(&info, &tokens)
+}
+
+#[test]
+fn validation_finds_dead_ends() {
+    // start ::= "key=" 0x01 digit | "val=" digit
+    let mut g = Grammar::new();
+    let start = g.start();
+    let digit = g.terminal(&ByteSet::from_range(b'0'..=b'9'));
+    let mut key: Vec<_> = b"key=\x01".iter().map(|b| byte(&mut g, *b)).collect();
+    key.push(digit);
+    let mut val: Vec<_> = b"val=".iter().map(|b| byte(&mut g, *b)).collect();
+    val.push(digit);
+    g.add_rule(start, key);
+    g.add_rule(start, val);
+    let grm = g.optimize().compile().unwrap();
+
+    let report = validate_grammar(&grm, &printable_trie(), ValidationConfig::default()).unwrap();
+    assert!(!report.is_ok());
+    assert!(!report.truncated);
+    assert_eq!(report.dead_ends.len(), 1, "{report}");
+    let dead_end = &report.dead_ends[0];
+    assert_eq!(dead_end.prefix, b"key=");
+    assert_eq!(dead_end.expected.len(), 1);
+    assert!(dead_end.expected[0].bytes.contains(0x01));
+    assert!(report.to_string().contains(r#"after "key=""#), "{report}");
+}
+
+#[test]
+fn validation_passes_clean_grammar() {
+    for flexible_whitespace in [None, Some(2)] {
+        let parser = json_parser(flexible_whitespace);
+        let report = validate_grammar(
+            parser.grammar(),
+            &printable_trie(),
+            ValidationConfig::default(),
+        )
+        .unwrap();
+        assert!(report.is_ok(), "{report}");
+        assert!(report.num_states > 10);
+        // the integer can be arbitrarily long, but its digits come back to the same state
+        assert!(!report.truncated);
+    }
+
+    // no token continues "caf\xc3", but no token ends there either
+    let mut g = Grammar::new();
+    let start = g.start();
+    let rhs = "café".bytes().map(|b| byte(&mut g, b)).collect();
+    g.add_rule(start, rhs);
+    let grm = g.optimize().compile().unwrap();
+    let report = validate_grammar(&grm, &printable_trie(), ValidationConfig::default()).unwrap();
+    assert!(report.is_ok(), "{report}");
+    assert!(!report.truncated);
+}
+
+// words ::= word | words " " word
+// word ::= a-z | word a-z, captured
+fn words_parser() -> Parser {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let words = g.symbol("words");
+    let word = g.symbol("word");
+    let letter = g.terminal(&ByteSet::from_range(b'a'..=b'z'));
+    let space = byte(&mut g, b' ');
+    g.add_rule(start, vec![words]);
+    g.add_rule(words, vec![word]);
+    g.add_rule(words, vec![words, space, word]);
+    g.add_rule(word, vec![letter]);
+    g.add_rule(word, vec![word, letter]);
+    g.apply_props(
+        word,
+        SymbolProps {
+            capture_name: Some("w".to_string()),
+            ..Default::default()
+        },
+    );
+    Parser::new(g.optimize().compile().unwrap())
+}
+
+#[test]
+fn restoring_drafts_keeps_the_outcome() {
+    fn check(mut parser: Parser, mut reference: Parser, input: &[u8], rng: &mut Rng) {
+        let draft = |rng: &mut Rng| {
+            let start = rng.gen_up_to(input.len() - 1);
+            let len = rng.gen_up_to(8);
+            &input[start..input.len().min(start + len)]
+        };
+        for &b in input {
+            let checkpoint = parser.checkpoint();
+            for _ in 0..3 {
+                if parser.scan_bytes(draft(rng)) != ParseResult::Reject {
+                    // nested drafts
+                    let inner = parser.checkpoint();
+                    let _ = parser.scan_bytes(draft(rng));
+                    parser.restore(&inner);
+                }
+                parser.restore(&checkpoint);
+            }
+            assert_ne!(parser.scan(b), ParseResult::Reject);
+            assert_ne!(reference.scan(b), ParseResult::Reject);
+            assert_eq!(parser.state_key(), reference.state_key());
+            assert_eq!(parser.captures(), reference.captures());
+            assert_eq!(expected(&parser), expected(&reference));
+        }
+        assert_eq!(parser.get_bytes(), input);
+        assert!(parser.is_accepting());
+    }
+
+    let mut rng = Rng::seeded(1);
+    let input = br#"{"name":"Joe","info":{"foo":10,"bar":"20"}}"#;
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "info": {
+                "type": "object",
+                "properties": {
+                    "foo": { "type": "integer" },
+                    "bar": { "type": "string" }
+                },
+                "required": ["foo", "bar"]
+            }
+        },
+        "required": ["name", "info"]
+    });
+    check(
+        schema_parser(schema.clone()),
+        schema_parser(schema),
+        input,
+        &mut rng,
+    );
+
+    // drafts make the words go on, or start new ones
+    let input = b"ab cde f gh";
+    check(words_parser(), words_parser(), input, &mut rng);
+    let mut parser = words_parser();
+    scan(&mut parser, "ab cd");
+    let checkpoint = parser.checkpoint();
+    scan(&mut parser, "e f");
+    assert_eq!(parser.captures().len(), 3);
+    assert_eq!(parser.captures()[1].end, 6);
+    parser.restore(&checkpoint);
+    let c = &parser.captures()[1];
+    assert_eq!((c.start, c.end), (3, 5));
+    assert_eq!(parser.capture_bytes(c), b"cd");
+}
+
+/// Allows any bytes, and the listed special tokens.
+struct AllowSpecial(Vec<SpecialToken>);
+
+impl Recognizer for AllowSpecial {
+    fn pop_bytes(&mut self, _num: usize) {}
+    fn collapse(&mut self) {}
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.contains(&tok)
+    }
+    fn trie_finished(&mut self) {}
+    fn try_push_byte(&mut self, _byte: u8) -> bool {
+        true
+    }
+}
+
+/// Two added special tokens, one without bytes and one with (see ByteTokenizer::add_missing_tokens());
+/// either way, only special_allowed() decides about them.
+#[test]
+fn special_tokens_are_gated_by_special_allowed() {
+    let mut words = (0..=255u8).map(|b| vec![b]).collect::<Vec<_>>();
+    let eos = words.len() as TokenId;
+    words.push(vec![]);
+    let im_start = words.len() as TokenId;
+    words.push(vec![]);
+    let im_end = words.len() as TokenId;
+    words.push(b"<|im_end|>".to_vec());
+    let info = TokRxInfo {
+        vocab_size: words.len() as u32,
+        tok_eos: eos,
+    };
+    // names of any length survive serialization
+    let long_name = format!("<|{}|>", "x".repeat(300));
+    let special = BTreeMap::from([
+        ("</s>".to_string(), eos),
+        (long_name.clone(), im_start),
+        ("<|im_end|>".to_string(), im_end),
+    ]);
+    let trie = TokTrie::from(&info, &words).with_special_tokens(&special);
+    assert!(trie.special_kind(b'a' as TokenId).is_none());
+    assert!(trie.special_kind(eos) == Some(SpecialToken::EndOfSentence));
+    assert!(trie.special_token(SpecialToken::Other(im_end)) == im_end);
+    let trie2 = TokTrie::from_bytes(&trie.serialize());
+    assert!(trie2.special_tokens() == trie.special_tokens());
+
+    let mut set = trie.alloc_token_set();
+    for allowed in [
+        vec![],
+        vec![SpecialToken::Other(im_start)],
+        vec![SpecialToken::Other(im_end), SpecialToken::EndOfSentence],
+    ] {
+        let mut r = AllowSpecial(allowed.clone());
+        trie.compute_bias(&mut r, &mut set);
+        assert!(set.is_allowed(b'<' as TokenId));
+        for tok in [eos, im_start, im_end] {
+            let kind = trie.special_kind(tok).unwrap();
+            assert!(set.is_allowed(tok) == allowed.contains(&kind));
+            assert!(trie.token_allowed(&mut r, tok) == allowed.contains(&kind));
+        }
+    }
+
+    // grammars only allow special tokens they name
+    let mut grm = Grammar::new();
+    let start = grm.start();
+    let a = grm.terminal(&ByteSet::from_byte(b'a'));
+    let marker = grm.model_variable(&long_name);
+    grm.add_rule(start, vec![a, marker, a]);
+    for resolve in [false, true] {
+        let mut grm = grm.optimize();
+        if resolve {
+            grm.resolve_special_tokens(&trie);
+        }
+        let mut parser = Parser::new(grm.compile().unwrap());
+        assert!(parser.scan(b'a') != ParseResult::Reject);
+        trie.compute_bias(&mut parser, &mut set);
+        assert!(set.is_allowed(im_start) == resolve);
+        assert!(!set.is_allowed(im_end));
+        assert!(!set.is_allowed(eos));
+    }
+}
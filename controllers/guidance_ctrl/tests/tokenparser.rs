@@ -0,0 +1,796 @@
+use aici_abi::{
+    replay::replay_session,
+    testing::{
+        record_controller_script, run_controller_script, MockHost, MockTokenizerEnv, Phase,
+        Transcript,
+    },
+    toktree::TokTrie,
+    AiciCtrl, Capture, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, SeqId,
+    TokenId, TokenizerEnv, WasmTokenizerEnv,
+};
+use aici_guidance_ctrl::{
+    earley::{
+        earley_grm_for_tools, earley_grm_from_json_schema, ByteSet, Grammar, JsonCompileOptions,
+        SymIdx, SymbolProps, ToolSpec,
+    },
+    TokenParser,
+};
+use serde_json::json;
+
+struct JsonCtrl {
+    tok_parser: TokenParser,
+    // all the tokens, prompt included, to check MidProcessArg::byte_offset against
+    tokens: Vec<TokenId>,
+    num_backtracks: usize,
+    // as reported in the results, updates and retractions included
+    captures: Vec<Capture>,
+}
+
+impl AiciCtrl for JsonCtrl {
+    fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
+        self.tokens = arg.prompt;
+        InitPromptResult::default()
+    }
+
+    fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        arg.save_tokens(&mut self.tokens);
+        if arg.backtrack > 0 {
+            self.num_backtracks += 1;
+        }
+        let trie = self.tok_parser.token_env.tok_trie();
+        let prefix = &self.tokens[..self.tokens.len() - arg.tokens.len()];
+        assert_eq!(arg.byte_offset, trie.decode(&self.tokens).len() as u64);
+        assert_eq!(arg.tokens_byte_offset(), trie.decode(prefix).len() as u64);
+        let r = self.tok_parser.mid_process(arg);
+        self.captures.extend_from_slice(&r.captures);
+        r
+    }
+}
+
+fn run_schema(schema: serde_json::Value, prefer: &str, max_tokens: usize) -> (Transcript, String) {
+    let (tr, text, _) = run_schema_ctrl(schema, &JsonCompileOptions::default(), prefer, max_tokens);
+    (tr, text)
+}
+
+fn run_schema_ctrl(
+    schema: serde_json::Value,
+    options: &JsonCompileOptions,
+    prefer: &str,
+    max_tokens: usize,
+) -> (Transcript, String, JsonCtrl) {
+    let grm = earley_grm_from_json_schema(&schema, options).unwrap();
+    run_grammar_ctrl(grm, prefer, max_tokens)
+}
+
+fn run_grammar_ctrl(
+    grm: Grammar,
+    prefer: &str,
+    max_tokens: usize,
+) -> (Transcript, String, JsonCtrl) {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let tok_parser = TokenParser::from_grammar(Box::new(env.clone()), grm).unwrap();
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: prefer.to_string(),
+            max_tokens,
+        },
+    ];
+    let mut ctrl = JsonCtrl {
+        tok_parser,
+        tokens: vec![],
+        num_backtracks: 0,
+        captures: vec![],
+    };
+    let tr = run_controller_script(&mut ctrl, script);
+    let text = tr.output_text(&env);
+    (tr, text, ctrl)
+}
+
+fn person_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "age": { "type": "integer" },
+            "name": { "type": "string" }
+        },
+        "required": ["age", "name"]
+    })
+}
+
+#[test]
+fn json_follows_valid_output() {
+    // properties come out in the order of the schema
+    let (tr, text) = run_schema(person_schema(), r#"{"age":42,"name":"Joe"}"#, 50);
+    assert_eq!(text, r#"{"age":42,"name":"Joe"}"#);
+    assert!(tr.eos || tr.stopped);
+}
+
+#[test]
+fn json_forces_keys() {
+    // the model wants something else entirely; the grammar forces the structure
+    let (tr, text) = run_schema(person_schema(), "Hello world", 20);
+    assert!(text.starts_with(r#"{"age":"#), "{text:?}");
+    assert!(!tr.eos);
+}
+
+#[test]
+fn byte_offset_follows_splices() {
+    // JsonCtrl checks byte_offset in every step; the model fighting the grammar
+    // makes the controller backtrack some of its tokens
+    let (_, text, ctrl) = run_schema_ctrl(
+        person_schema(),
+        &JsonCompileOptions::default(),
+        "Hello world",
+        20,
+    );
+    assert!(text.starts_with(r#"{"age":"#), "{text:?}");
+    assert!(ctrl.num_backtracks > 0);
+}
+
+#[test]
+fn flexible_whitespace_follows_model_formatting() {
+    let options = JsonCompileOptions {
+        flexible_whitespace: Some(16),
+        ..Default::default()
+    };
+    let value = json!({ "age": 42, "name": "Joe" });
+    let outputs = [
+        serde_json::to_string(&value).unwrap(),
+        serde_json::to_string_pretty(&value).unwrap(),
+        "{ \"age\" :42,\n\t\"name\":  \"Joe\" }".to_string(),
+    ];
+    for prefer in outputs {
+        let (tr, text, _) = run_schema_ctrl(person_schema(), &options, &prefer, 50);
+        assert_eq!(text, prefer);
+        assert!(tr.eos || tr.stopped);
+    }
+}
+
+fn tools() -> Vec<ToolSpec> {
+    serde_json::from_value(json!([
+        { "name": "get_time" },
+        {
+            "name": "get_weather",
+            "parameters": {
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"]
+            }
+        },
+        {
+            "name": "create_event",
+            "description": "Add an event to the calendar",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "title": { "type": "string" },
+                    "place": {
+                        "type": "object",
+                        "properties": {
+                            "city": { "type": "string" },
+                            "room": { "type": "integer" }
+                        },
+                        "required": ["city"]
+                    },
+                    "attendees": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "tags": { "type": "array", "items": { "type": "string" } }
+                            },
+                            "required": ["name"]
+                        },
+                        "minItems": 1
+                    }
+                },
+                "required": ["title", "attendees"]
+            }
+        }
+    ]))
+    .unwrap()
+}
+
+fn run_tools(prefer: &str, max_tokens: usize) -> (Transcript, String) {
+    let grm = earley_grm_for_tools(&tools(), &JsonCompileOptions::default()).unwrap();
+    let (tr, text, _) = run_grammar_ctrl(grm, prefer, max_tokens);
+    (tr, text)
+}
+
+#[test]
+fn tool_call_follows_valid_output() {
+    let args = json!({
+        "title": "Sync",
+        "place": { "city": "Oslo", "room": 12 },
+        "attendees": [
+            { "name": "Ann", "tags": ["host", "remote"] },
+            { "name": "Bob", "tags": [] }
+        ]
+    });
+    let calls = [
+        (r#"get_time", "arguments": {}"#.to_string(), json!({})),
+        (
+            r#"get_weather", "arguments": {"city":"Paris"}"#.to_string(),
+            json!({ "city": "Paris" }),
+        ),
+        (
+            format!(r#"create_event", "arguments": {}"#, args),
+            args.clone(),
+        ),
+    ];
+    for (call, args) in calls {
+        let prefer = format!(r#"{{"name": "{call}}}"#);
+        let (tr, text) = run_tools(&prefer, 200);
+        assert_eq!(text, prefer);
+        assert!(tr.eos || tr.stopped);
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["arguments"], args);
+    }
+}
+
+#[test]
+fn tool_call_forces_structure() {
+    // the part up to the name is forced
+    let (tr, text) = run_tools("Hello world", 20);
+    assert!(text.starts_with(r#"{"name": ""#), "{text:?}");
+    assert!(!tr.eos);
+
+    // an unknown tool can't be called
+    let (_, text) = run_tools(r#"{"name": "delete_all", "arguments": {}}"#, 20);
+    let name = text.strip_prefix(r#"{"name": ""#).unwrap();
+    assert!(
+        ["get_time", "get_weather", "create_event"]
+            .iter()
+            .any(|t| name.starts_with(&format!(r#"{t}", "arguments": "#))),
+        "{text:?}"
+    );
+
+    // nor can another tool's arguments be used, and the object has to be closed
+    let (tr, text) = run_tools(r#"{"name": "get_weather", "arguments": {"title":"x"}}"#, 20);
+    let forced = r#"{"name": "get_weather", "arguments": {"city":""#;
+    assert!(text.starts_with(forced), "{text:?}");
+    assert!(!tr.eos);
+}
+
+#[test]
+fn same_grammar_shares_token_sets() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let run = |schema: serde_json::Value| {
+        let tok_parser = TokenParser::from_json_schema(
+            Box::new(env.clone()),
+            &schema,
+            &JsonCompileOptions::default(),
+        )
+        .unwrap();
+        let mut ctrl = JsonCtrl {
+            tok_parser,
+            tokens: vec![],
+            num_backtracks: 0,
+            captures: vec![],
+        };
+        let script = vec![
+            Phase::Prompt("Hello".to_string()),
+            Phase::Generate {
+                prefer: r#"{"age":42,"name":"Joe"}"#.to_string(),
+                max_tokens: 50,
+            },
+        ];
+        run_controller_script(&mut ctrl, script);
+        let cache = ctrl.tok_parser.bias_cache();
+        (cache.hits(), cache.shared_hits(), cache.misses())
+    };
+
+    // the first sequence computes the sets, the others mostly find them on the host
+    let (_, shared_hits, misses) = run(person_schema());
+    assert_eq!(shared_hits, 0);
+    assert!(misses > 0);
+    for _ in 0..3 {
+        let (hits, shared_hits, misses) = run(person_schema());
+        let served = hits + shared_hits;
+        assert!(shared_hits > 0);
+        assert!(
+            served * 100 >= (served + misses) * 60,
+            "{served} hits, {misses} misses"
+        );
+    }
+
+    // sets of another grammar are never used
+    let mut schema = person_schema();
+    schema["properties"]["age"]["type"] = json!("string");
+    let (_, shared_hits, misses) = run(schema);
+    assert_eq!(shared_hits, 0);
+    assert!(misses > 0);
+}
+
+#[test]
+fn shared_token_sets_keep_outputs_of_divergent_sequences() {
+    let env = MockTokenizerEnv::default();
+    let run = |prefer: &str| {
+        let tok_parser = TokenParser::from_json_schema(
+            Box::new(env.clone()),
+            &person_schema(),
+            &JsonCompileOptions::default(),
+        )
+        .unwrap();
+        let mut ctrl = JsonCtrl {
+            tok_parser,
+            tokens: vec![],
+            num_backtracks: 0,
+            captures: vec![],
+        };
+        let script = vec![
+            Phase::Prompt("Hello".to_string()),
+            Phase::Generate {
+                prefer: prefer.to_string(),
+                max_tokens: 50,
+            },
+        ];
+        let tr = run_controller_script(&mut ctrl, script);
+        (
+            tr.output().to_vec(),
+            ctrl.tok_parser.bias_cache().shared_hits(),
+        )
+    };
+
+    // some of these don't follow the schema, and get forced or masked elsewhere
+    let prefers = [
+        r#"{"age":42,"name":"Joe"}"#,
+        r#"{"age":7,"name":"Ann Smith"}"#,
+        r#"{"age":1234567,"name":""}"#,
+        r#"{"name":"Bob","age":3}"#,
+        r#"{"age":"old","name":"Eve"}"#,
+        r#"{"age":-5,"name":"Zoë \"Z\""}"#,
+    ];
+    // each on its own host, so with nothing to share
+    let alone: Vec<Vec<TokenId>> = prefers
+        .iter()
+        .map(|prefer| {
+            MockHost::install(&env);
+            run(prefer).0
+        })
+        .collect();
+
+    // one after the other, finding sets stored by sequences that went elsewhere
+    MockHost::install(&env);
+    let mut total_shared_hits = 0;
+    for (prefer, expected) in prefers.iter().zip(&alone) {
+        let (output, shared_hits) = run(prefer);
+        assert_eq!(&output, expected, "{prefer}");
+        total_shared_hits += shared_hits;
+    }
+    assert!(total_shared_hits > 0);
+}
+
+#[test]
+fn free_text_reuses_token_sets() {
+    // in the answer, the current row is the same after every token
+    let answer = "the value of the item is the value of the answer";
+    let prefer = format!("{answer}\nscore: 1\nitems: a\n");
+    let (_, text, ctrl) = run_grammar_ctrl(captures_grammar(), &prefer, 60);
+    assert_eq!(text, prefer);
+    let cache = ctrl.tok_parser.bias_cache();
+    let (hits, misses) = (cache.hits(), cache.misses());
+    assert!(hits > 2 * misses, "{hits} hits, {misses} misses");
+}
+
+// Like a deployed controller: the schema is the module argument, and the tokenizer
+// comes from the host (so both are in the recording).
+fn new_recorded_ctrl(options: &JsonCompileOptions) -> JsonCtrl {
+    let schema: serde_json::Value = serde_json::from_slice(&aici_abi::arg_bytes()).unwrap();
+    let token_env = Box::new(WasmTokenizerEnv::default());
+    JsonCtrl {
+        tok_parser: TokenParser::from_json_schema(token_env, &schema, options).unwrap(),
+        tokens: vec![],
+        num_backtracks: 0,
+        captures: vec![],
+    }
+}
+
+fn literal(g: &mut Grammar, s: &str) -> SymIdx {
+    let sym = g.fresh_symbol("lit");
+    g.add_string_choice(sym, &[s.as_bytes()]);
+    sym
+}
+
+fn capture_props(name: &str) -> SymbolProps {
+    SymbolProps {
+        capture_name: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+// lhs ::= [bytes]+, as a lexeme
+fn repeated(g: &mut Grammar, lhs: SymIdx, bytes: &ByteSet, capture: Option<&str>) {
+    let b = g.terminal(bytes);
+    let inner = g.fresh_symbol("inner");
+    g.add_rule(inner, vec![b, inner]);
+    g.add_rule(inner, vec![b]);
+    g.add_rule(lhs, vec![inner]);
+    if let Some(name) = capture {
+        g.apply_props(lhs, capture_props(name));
+    }
+    g.make_lexeme(lhs);
+}
+
+// answer "\nscore: " score "\nitems:" items "\n", with answer ::= [a-z ]+, score ::= [0-9]+,
+// and items a list of " " [a-z]+; the list and each item are captured too
+fn captures_grammar() -> Grammar {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let answer = g.symbol("answer");
+    let mut letters = ByteSet::from_range(b'a'..=b'z');
+    letters.insert(b' ');
+    repeated(&mut g, answer, &letters, Some("answer"));
+    let score = g.symbol("score");
+    let digits = ByteSet::from_range(b'0'..=b'9');
+    repeated(&mut g, score, &digits, Some("score"));
+    let word = g.symbol("word");
+    repeated(&mut g, word, &ByteSet::from_range(b'a'..=b'z'), None);
+    let item = g.symbol("item");
+    let space = literal(&mut g, " ");
+    g.add_rule(item, vec![space, word]);
+    g.apply_props(item, capture_props("item"));
+    let items = g.symbol("items");
+    g.add_rule(items, vec![items, item]);
+    g.add_rule(items, vec![item]);
+    g.apply_props(items, capture_props("items"));
+    let rhs = vec![
+        answer,
+        literal(&mut g, "\nscore: "),
+        score,
+        literal(&mut g, "\nitems:"),
+        items,
+        literal(&mut g, "\n"),
+    ];
+    g.add_rule(start, rhs);
+    g
+}
+
+#[test]
+fn captures_report_byte_ranges() {
+    let (tr, text, ctrl) = run_grammar_ctrl(
+        captures_grammar(),
+        "the value\nscore: 42\nitems: the value\nmore",
+        30,
+    );
+    assert_eq!(text, "the value\nscore: 42\nitems: the value\n");
+    assert!(tr.stopped);
+
+    // every report has the bytes of its range; growing ones are reported again
+    for c in &ctrl.captures {
+        assert_eq!(c.value, text[c.start as usize..c.end as usize], "{c:?}");
+    }
+    let num_answers = ctrl.captures.iter().filter(|c| c.name == "answer").count();
+    assert!(num_answers > 1, "{:?}", ctrl.captures);
+
+    // the latest ones, in the order they first completed (the list after its first item)
+    let mut latest: Vec<&Capture> = vec![];
+    for c in &ctrl.captures {
+        let pos = latest
+            .iter()
+            .position(|l| l.name == c.name && l.occurrence == c.occurrence);
+        match pos {
+            Some(idx) if c.retracted => {
+                latest.remove(idx);
+            }
+            Some(idx) => latest[idx] = c,
+            None if c.retracted => {}
+            None => latest.push(c),
+        }
+    }
+    let spans = latest
+        .iter()
+        .map(|c| (c.name.as_str(), c.occurrence, c.start, c.end))
+        .collect::<Vec<_>>();
+    let expected = [
+        ("answer", 0, 0, 9),
+        ("score", 0, 17, 19),
+        ("item", 0, 26, 30),
+        ("items", 0, 26, 36),
+        ("item", 1, 30, 36),
+    ];
+    assert_eq!(spans, expected);
+    let parser = &ctrl.tok_parser.parser;
+    assert_eq!(parser.captures().len(), expected.len());
+    for (c, (name, occurrence, start, end)) in parser.captures().iter().zip(expected) {
+        assert_eq!(c.name, name);
+        let span = [occurrence, start, end].map(|x| x as usize);
+        assert_eq!([c.occurrence, c.start, c.end], span);
+    }
+    assert_eq!(parser.capture_bytes(&parser.captures()[3]), b" the value");
+
+    let by_name = aici_abi::captures_by_name(&ctrl.captures);
+    assert_eq!(by_name["answer"], ["the value"]);
+    assert_eq!(by_name["score"], ["42"]);
+    assert_eq!(by_name["item"], [" the", " value"]);
+    assert_eq!(by_name["items"], [" the value"]);
+}
+
+fn captures_step(parser: &mut TokenParser, backtrack: u32, tokens: Vec<TokenId>) -> Vec<Capture> {
+    let res = parser.mid_process(MidProcessArg {
+        backtrack,
+        tokens,
+        fork_group: vec![SeqId(0)],
+        fork_arg: vec![],
+        prev_timed_out: false,
+        forced_byte_prefix: vec![],
+        byte_offset: 0,
+        context_truncation: None,
+        top_logprobs: vec![],
+    });
+    res.captures
+}
+
+#[test]
+fn backtracked_captures_are_retracted() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    let mut parser = TokenParser::from_grammar(Box::new(env.clone()), captures_grammar()).unwrap();
+    let spans = |captures: &[Capture]| {
+        captures
+            .iter()
+            .map(|c| (c.name.clone(), c.value.clone(), c.start, c.end, c.retracted))
+            .collect::<Vec<_>>()
+    };
+    let span = |name: &str, value: &str, start, end| {
+        (name.to_string(), value.to_string(), start, end, false)
+    };
+    let mut reported = captures_step(&mut parser, 0, vec![]);
+    assert!(reported.is_empty(), "{reported:?}");
+
+    let mut tokens = env.tokenize("the value\nscore: ");
+    let scored = env.tokenize("42\nitems:");
+    tokens.extend_from_slice(&scored);
+    let captures = captures_step(&mut parser, 0, tokens);
+    assert_eq!(
+        spans(&captures),
+        [
+            span("answer", "the value", 0, 9),
+            span("score", "42", 17, 19)
+        ]
+    );
+    reported.extend(captures);
+
+    // dropping the score and what follows withdraws it, and only it
+    let captures = captures_step(&mut parser, scored.len() as u32, vec![]);
+    assert_eq!(captures, [Capture::retraction("score", 0)]);
+    assert!(parser.parser.captures().iter().all(|c| c.name != "score"));
+    reported.extend(captures);
+
+    let captures = captures_step(&mut parser, 0, env.tokenize("1\nitems:"));
+    assert_eq!(spans(&captures), [span("score", "1", 17, 18)]);
+    reported.extend(captures);
+
+    let by_name = aici_abi::captures_by_name(&reported);
+    assert_eq!(by_name["answer"], ["the value"]);
+    assert_eq!(by_name["score"], ["1"]);
+}
+
+const SESSION_FIXTURE: &str = "tests/fixtures/json_session.bin";
+
+#[test]
+#[ignore = "regenerates the fixture for replays_recorded_session"]
+fn record_session() {
+    let env = MockTokenizerEnv::default();
+    MockHost::install(&env);
+    MockHost::set_arg_bytes(person_schema().to_string().as_bytes());
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: "Hello world".to_string(),
+            max_tokens: 20,
+        },
+    ];
+    let (_, log) =
+        record_controller_script(|| new_recorded_ctrl(&JsonCompileOptions::default()), script);
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(SESSION_FIXTURE);
+    std::fs::write(path, log).unwrap();
+}
+
+#[test]
+fn replays_recorded_session() {
+    let log = include_bytes!("fixtures/json_session.bin");
+    let num_steps = replay_session(log, || new_recorded_ctrl(&JsonCompileOptions::default()));
+    assert!(num_steps.unwrap() > 10);
+}
+
+#[test]
+fn replay_catches_different_masks() {
+    let log = include_bytes!("fixtures/json_session.bin");
+    let options = JsonCompileOptions {
+        flexible_whitespace: Some(4),
+        ..Default::default()
+    };
+    // the opening brace is no longer forced, as whitespace may come first
+    let err = replay_session(log, || new_recorded_ctrl(&options)).unwrap_err();
+    assert!(err.to_string().starts_with("step 2 (MidProcess)"), "{err}");
+}
+
+fn string_choice_grammar(options: &[&str]) -> Grammar {
+    let mut g = Grammar::new();
+    let start = g.start();
+    let choice = g.symbol("choice");
+    let options = options.iter().map(|o| o.as_bytes()).collect::<Vec<_>>();
+    g.add_string_choice(choice, &options);
+    g.add_rule(start, vec![choice]);
+    g
+}
+
+fn string_choice_parser(env: &MockTokenizerEnv, options: &[&str]) -> TokenParser {
+    TokenParser::from_grammar(Box::new(env.clone()), string_choice_grammar(options)).unwrap()
+}
+
+// pass the tokens ("EOS" for EOS) to mid_process(); the result is the allowed tokens
+// (sorted), the forced tokens, or "stop"
+fn step(parser: &mut TokenParser, env: &MockTokenizerEnv, tokens: &[&str]) -> String {
+    let trie = env.tok_trie();
+    let tokens = tokens
+        .iter()
+        .flat_map(|t| match *t {
+            "EOS" => vec![trie.eos_token()],
+            _ => env.tokenize(t),
+        })
+        .collect();
+    let res = parser.mid_process(MidProcessArg {
+        backtrack: 0,
+        tokens,
+        fork_group: vec![SeqId(0)],
+        fork_arg: vec![],
+        prev_timed_out: false,
+        forced_byte_prefix: vec![],
+        byte_offset: 0,
+        context_truncation: None,
+        top_logprobs: vec![],
+    });
+    let token_str = |t: TokenId| {
+        if t == trie.eos_token() {
+            "EOS".to_string()
+        } else {
+            String::from_utf8_lossy(trie.token(t)).to_string()
+        }
+    };
+    match res.branches.first() {
+        None => "stop".to_string(),
+        Some(b) => match &b.sample_mask {
+            Some(set) => {
+                let mut allowed = (0..trie.vocab_size() as TokenId)
+                    .filter(|t| set.is_allowed(*t))
+                    .map(token_str)
+                    .collect::<Vec<_>>();
+                allowed.sort();
+                allowed.join(" ")
+            }
+            None => {
+                let ff = &b.splices[0].ff_tokens;
+                format!(
+                    "splice {}",
+                    ff.iter().map(|t| token_str(*t)).collect::<String>()
+                )
+            }
+        },
+    }
+}
+
+#[test]
+fn string_choice_allows_distinguishing_tokens() {
+    let env = MockTokenizerEnv::new(&["ab", "abc", "xy"]);
+    MockHost::install(&env);
+
+    // "a" is forced, but kept back, as the model may pick a longer token
+    let mut parser = string_choice_parser(&env, &["a", "ab", "abc"]);
+    assert_eq!(step(&mut parser, &env, &[]), "a ab abc");
+    assert_eq!(step(&mut parser, &env, &["a"]), "EOS b");
+    assert_eq!(step(&mut parser, &env, &["b"]), "EOS c");
+    assert_eq!(step(&mut parser, &env, &["EOS"]), "stop");
+
+    let mut parser = string_choice_parser(&env, &["", "x", "xy"]);
+    assert_eq!(step(&mut parser, &env, &[]), "EOS x xy");
+    assert_eq!(step(&mut parser, &env, &["x"]), "EOS y");
+    assert_eq!(step(&mut parser, &env, &["EOS"]), "stop");
+
+    // forcing ends where the options differ
+    let mut parser = string_choice_parser(&env, &["abcd", "abce"]);
+    assert_eq!(step(&mut parser, &env, &[]), "splice abc");
+    assert_eq!(step(&mut parser, &env, &["abc"]), "d e");
+    assert_eq!(step(&mut parser, &env, &["e"]), "stop");
+}
+
+#[test]
+fn swapped_grammar_replays_the_output() {
+    let env = MockTokenizerEnv::new(&["ab", "abc", "xy"]);
+    MockHost::install(&env);
+
+    // the output so far doesn't match, so the current grammar stays
+    let mut parser = string_choice_parser(&env, &["abcd", "abce"]);
+    assert_eq!(step(&mut parser, &env, &[]), "splice abc");
+    assert_eq!(step(&mut parser, &env, &["abc"]), "d e");
+    let err = parser
+        .swap_grammar(string_choice_grammar(&["xyz"]))
+        .unwrap_err();
+    assert!(err.to_string().contains("doesn't match"), "{err}");
+    assert_eq!(step(&mut parser, &env, &["d"]), "stop");
+
+    // "y" is only allowed by the new grammar
+    let mut parser = string_choice_parser(&env, &["abcd", "abce"]);
+    assert_eq!(step(&mut parser, &env, &[]), "splice abc");
+    assert_eq!(step(&mut parser, &env, &["abc"]), "d e");
+    parser
+        .swap_grammar(string_choice_grammar(&["abcx", "abcy"]))
+        .unwrap();
+    assert_eq!(step(&mut parser, &env, &["y"]), "stop");
+    assert_eq!(parser.parser.get_bytes(), b"abcy");
+}
+
+// Like a BPE tokenizer without the merge of "in" and "g": it never produces "ing",
+// though the model can still sample it.
+struct NoIngEnv {
+    env: MockTokenizerEnv,
+}
+
+impl TokenizerEnv for NoIngEnv {
+    fn stop(&self) -> ! {
+        self.env.stop()
+    }
+
+    fn tok_trie(&self) -> &TokTrie {
+        self.env.tok_trie()
+    }
+
+    fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
+        let trie = self.env.tok_trie();
+        let ing = trie.token_id(b"ing").unwrap();
+        let split = [trie.token_id(b"in").unwrap(), trie.token_id(b"g").unwrap()];
+        let mut r = vec![];
+        for t in self.env.tokenize_bytes(s) {
+            if t == ing {
+                r.extend_from_slice(&split);
+            } else {
+                r.push(t);
+            }
+        }
+        r
+    }
+}
+
+#[test]
+fn other_token_splits_dont_backtrack() {
+    let env = MockTokenizerEnv::new(&["in", "ing"]);
+    MockHost::install(&env);
+    // after "s" or "st", the model picks "ing", and the space is forced after it
+    let mut g = Grammar::new();
+    let start = g.start();
+    let words = g.symbol("words");
+    let word = g.symbol("word");
+    g.add_string_choice(word, &[b"sing ", b"sting "]);
+    g.add_rule(words, vec![word, words]);
+    g.add_rule(words, vec![]);
+    g.add_rule(start, vec![words]);
+    let token_env = Box::new(NoIngEnv { env: env.clone() });
+    let mut ctrl = JsonCtrl {
+        tok_parser: TokenParser::from_grammar(token_env, g).unwrap(),
+        tokens: vec![],
+        num_backtracks: 0,
+        captures: vec![],
+    };
+    let prefer = "sing sting ".repeat(20);
+    let script = vec![
+        Phase::Prompt("Hello".to_string()),
+        Phase::Generate {
+            prefer: prefer.clone(),
+            max_tokens: 50,
+        },
+    ];
+    let tr = run_controller_script(&mut ctrl, script);
+    assert_eq!(tr.sampled.len(), 50);
+    let text = tr.output_text(&env);
+    assert!(text.len() > 100 && prefer.starts_with(&text), "{text:?}");
+    assert_eq!(ctrl.num_backtracks, 0);
+
+    // the model's "ing" tokens were kept, though the tokenizer splits them
+    let no_ing = NoIngEnv { env: env.clone() };
+    assert_ne!(no_ing.tokenize(&text), tr.output());
+}
@@ -1,8 +1,14 @@
 use std::sync::Mutex;
 
-use aici_abi::{set_host, toktree::TokTrie, HostInterface, StorageCmd, StorageResp, TokenId};
+use aici_abi::{
+    set_host,
+    toktree::{TokTrie, TokenizationResult},
+    variables::Variables,
+    HostInterface, StorageCmd, StorageResp, TokenId,
+};
 use aici_native::{
-    bintokens::{self, ByteTokenizer}, setup_log, variables::Variables
+    bintokens::{self, ByteTokenizer},
+    setup_log,
 };
 use anyhow::Result;
 
@@ -10,6 +16,7 @@ pub use aici_guidance_ctrl::*;
 
 struct ParserHost {
     trie_bytes: Vec<u8>,
+    trie: TokTrie,
     tokenizer: ByteTokenizer,
     vars: Mutex<Variables>,
 }
@@ -23,7 +30,7 @@ impl HostInterface for ParserHost {
         self.trie_bytes.clone()
     }
 
-    fn return_logit_bias(&self, _vob: &aici_abi::svob::SimpleVob) -> u32 {
+    fn return_logit_bias(&self, _set: &aici_abi::svob::TokenSet) -> u32 {
         todo!()
     }
 
@@ -35,24 +42,38 @@ impl HostInterface for ParserHost {
         todo!()
     }
 
-    fn get_config(&self, _name: &str) -> i32 {
+    fn get_config(&self, name: &str) -> i32 {
+        // results are passed back as JSON, masks included
+        (name == "inline_bias") as i32
+    }
+
+    fn rand_seed(&self) -> u64 {
         0
     }
 
+    fn time_left_us(&self) -> u64 {
+        u64::MAX
+    }
+
     fn storage_cmd(&self, cmd: StorageCmd) -> StorageResp {
         let mut vars = self.vars.lock().unwrap();
         vars.process_cmd(cmd)
     }
 
+    fn tokenize_bytes_greedy(&self, s: &[u8]) -> TokenizationResult {
+        self.trie.tokenize_bytes_greedy(s)
+    }
+
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
-        let tokens = self
-            .tokenizer
-            .hf_tokenizer
-            .encode(String::from_utf8_lossy(s), false);
-        match tokens {
-            Err(e) => panic!("tokenize error: {e}"),
-            Ok(tokens) => Vec::from(tokens.get_ids()),
-        }
+        self.trie
+            .tokenize_bytes_with(s, |s| match self.tokenizer.hf_tokenizer.encode(s, false) {
+                Err(e) => panic!("tokenize error: {e}"),
+                Ok(tokens) => Vec::from(tokens.get_ids()),
+            })
+    }
+
+    fn decode_tokens(&self, toks: &[TokenId]) -> Vec<u8> {
+        self.trie.decode(toks)
     }
 
     fn self_seq_id(&self) -> aici_abi::SeqId {
@@ -72,12 +93,14 @@ pub fn init(tokenizer_name: &str) -> Result<()> {
     setup_log();
     let tokenizer = bintokens::find_tokenizer(tokenizer_name)?;
     let tokens = tokenizer.token_bytes();
-    let trie = TokTrie::from(&tokenizer.tokrx_info(), &tokens);
+    let trie =
+        TokTrie::from(&tokenizer.tokrx_info(), &tokens).with_special_tokens(&tokenizer.special);
     trie.check_against(&tokens);
 
     set_host(Box::new(ParserHost {
         tokenizer,
         trie_bytes: trie.serialize(),
+        trie,
         vars: Mutex::new(Variables::default()),
     }));
 
@@ -304,17 +304,23 @@ mod aici_mod {
     }
 
     #[rquickjs::function]
-    pub fn setVar(name: String, value: Buffer) {
+    pub fn setVar<'js>(ctx: Ctx<'js>, name: String, value: Buffer) -> Result<()> {
         let name = name.as_str();
         let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.set(name, value.0);
+        match vars.set(name, value.0) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Exception::throw_message(&ctx, &format!("{}", e))),
+        }
     }
 
     #[rquickjs::function]
-    pub fn appendVar(name: String, value: Buffer) {
+    pub fn appendVar<'js>(ctx: Ctx<'js>, name: String, value: Buffer) -> Result<()> {
         let name = name.as_str();
         let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.append(name, value.0);
+        match vars.append(name, value.0) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Exception::throw_message(&ctx, &format!("{}", e))),
+        }
     }
 
     #[rquickjs::function]
@@ -342,9 +348,16 @@ mod aici_mod {
                                 backtrack: s.get2("backtrack"),
                             })
                             .collect(),
+                        forced_byte_prefix: vec![],
+                        post_sample: false,
+                        attention_mask: vec![],
+                        fork_arg: vec![],
                     }
                 })
                 .collect(),
+            suspend: None,
+            max_remaining_tokens: None,
+            captures: vec![],
         };
 
         let mut st = GLOBAL_STATE.lock().unwrap();
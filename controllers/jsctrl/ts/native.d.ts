@@ -172,11 +172,14 @@ declare module "_aici" {
 
   /**
    * Set the value of a shared variable.
+   * Variables named "global/..." are shared by all requests, the others by the forks of this one.
+   * Throws if the storage quota would be exceeded.
    */
   function setVar(name: string, value: string | Buffer): void;
 
   /**
    * Append to the value of a shared variable.
+   * Throws if the storage quota would be exceeded.
    */
   function appendVar(name: string, value: string | Buffer): void;
 
@@ -118,17 +118,23 @@ mod _aici {
     }
 
     #[pyfunction]
-    fn set_var(name: PyStrRef, value: ArgStrOrBytesLike, _vm: &VirtualMachine) {
+    fn set_var(name: PyStrRef, value: ArgStrOrBytesLike, vm: &VirtualMachine) -> PyResult<()> {
         let name = name.as_str();
         let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.set(name, (&value.borrow_bytes()).to_vec());
+        match vars.set(name, (&value.borrow_bytes()).to_vec()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(vm.new_runtime_error(format!("{}", e))),
+        }
     }
 
     #[pyfunction]
-    fn append_var(name: PyStrRef, value: ArgStrOrBytesLike, _vm: &VirtualMachine) {
+    fn append_var(name: PyStrRef, value: ArgStrOrBytesLike, vm: &VirtualMachine) -> PyResult<()> {
         let name = name.as_str();
         let vars = &GLOBAL_STATE.lock().unwrap().vars;
-        vars.append(name, (&value.borrow_bytes()).to_vec());
+        match vars.append(name, (&value.borrow_bytes()).to_vec()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(vm.new_runtime_error(format!("{}", e))),
+        }
     }
 
     #[pyfunction]
@@ -578,10 +584,19 @@ impl AiciCtrl for Runner {
                 Branch {
                     sample_mask,
                     splices,
+                    forced_byte_prefix: vec![],
+                    post_sample: false,
+                    attention_mask: vec![],
+                    fork_arg: vec![],
                 }
             });
 
-            MidProcessResult { branches }
+            MidProcessResult {
+                branches,
+                suspend: None,
+                max_remaining_tokens: None,
+                captures: vec![],
+            }
         })
     }
 }
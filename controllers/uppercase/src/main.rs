@@ -1,10 +1,13 @@
 use aici_abi::{
-    recognizer::{FunctionalRecognizer, StackRecognizer},
+    bytes::ByteSet,
+    recognizer::{BiasCache, FunctionalRecognizer, StackRecognizer},
     tokenize,
     toktree::{SpecialToken, TokTrie},
     AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult,
 };
 
+const UPPER: ByteSet = ByteSet::from_range(b'A'..=b'Z');
+
 // This constraints enforces an upper case letter every 4th byte
 // The state is the position in the output stream
 struct QuadUpper {}
@@ -18,69 +21,91 @@ impl FunctionalRecognizer<usize> for QuadUpper {
     }
 
     fn byte_allowed(&self, state: usize, byte: u8) -> bool {
+        self.allowed_set(state).contains(byte)
+    }
+
+    fn allowed_set(&self, state: usize) -> ByteSet {
         if state % 4 == 0 {
-            byte.is_ascii_uppercase()
+            UPPER
         } else {
-            true
+            ByteSet::full()
         }
     }
 
+    fn has_fast_allowed_set(&self) -> bool {
+        true
+    }
+
     fn special_allowed(&self, _state: usize, tok: SpecialToken) -> bool {
         match tok {
             SpecialToken::EndOfSentence => false,
             _ => false,
         }
     }
+
+    // the allowed tokens only depend on where the next upper case letter goes
+    fn state_key(&self, state: usize) -> Option<u64> {
+        Some((state % 4) as u64)
+    }
 }
 
 pub struct Runner {
     toktrie: TokTrie,
-    ff_tokens: Vec<u32>,
     tokens: Vec<u32>,
+    // length of the prompt in bytes; the output starts there
+    prompt_bytes: u64,
     recognizer: StackRecognizer<usize, QuadUpper>,
+    bias_cache: BiasCache,
 }
 
 impl Runner {
     pub fn new() -> Self {
+        Self::with_trie(TokTrie::from_host())
+    }
+
+    /// The host functions are only used for prompts of at most one token,
+    /// so with the trie given, the controller can also run natively.
+    pub fn with_trie(toktrie: TokTrie) -> Self {
         Runner {
-            toktrie: TokTrie::from_host(),
+            toktrie,
             tokens: Vec::new(),
-            ff_tokens: Vec::new(),
+            prompt_bytes: 0,
             recognizer: StackRecognizer::from(QuadUpper {}),
+            bias_cache: BiasCache::default(),
         }
     }
 }
 
 impl AiciCtrl for Runner {
     fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
-        if arg.prompt.len() <= 1 {
+        let res = if arg.prompt.len() <= 1 {
             // in case no prompt was provided, invent some
-            self.ff_tokens = tokenize("Here's a tweet:\n");
-        }
-        InitPromptResult::default()
+            InitPromptResult::ff_tokens(tokenize("Here's a tweet:\n"))
+        } else {
+            InitPromptResult::default()
+        };
+        self.prompt_bytes = (self.toktrie.decode(&arg.prompt).len()
+            + self.toktrie.decode(&res.ff_tokens).len()) as u64;
+        res
     }
 
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
-        // if we have some tokens pending - send them out
-        if self.ff_tokens.len() > 0 {
-            let tokens = std::mem::take(&mut self.ff_tokens);
-            return MidProcessResult::splice(0, tokens);
-        }
-
         // store our tokens
         arg.save_tokens(&mut self.tokens);
-        // and update the state of our recognizer
-        self.toktrie
-            .append_tokens(&mut self.recognizer, &arg.tokens);
+        // the state of our recognizer is just the position in the output,
+        // which the host passes in (also after backtracking, possibly into the prompt)
+        self.recognizer
+            .reset_to(arg.byte_offset.saturating_sub(self.prompt_bytes) as usize);
 
         // stop after 50 tokens
-        if self.tokens.len() > 50 || arg.has_eos() {
+        if self.tokens.len() > 50 || arg.tokens.contains(&self.toktrie.eos_token()) {
             return MidProcessResult::stop();
         }
 
         // otherwise, compute bias according to our recognizer
         let mut set = self.toktrie.alloc_token_set();
-        self.toktrie.compute_bias(&mut self.recognizer, &mut set);
+        self.toktrie
+            .compute_bias_cached(&mut self.recognizer, &mut self.bias_cache, &mut set);
         MidProcessResult::sample(set)
     }
 }
@@ -90,3 +115,226 @@ fn main() {
 }
 
 aici_abi::aici_expose_all!(Runner, Runner::new());
+
+#[cfg(test)]
+mod tests {
+    use super::{QuadUpper, Runner};
+    use aici_abi::{
+        native::{mid_process_into, BiasBuffer, BIAS_ALLOW},
+        recognizer::{BiasCache, StackRecognizer},
+        recording::{Record, RecordKind, RecordPhase},
+        replay::replay_session,
+        testing::{
+            record_controller_script, run_controller_script, MockHost, MockTokenizerEnv, Phase,
+            SpliceAt,
+        },
+        AiciCtrl, TokenizerEnv,
+    };
+    use std::time::Instant;
+
+    fn generate(prompt: &str, prefer: &str, max_tokens: usize) -> (MockTokenizerEnv, String) {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let script = vec![
+            Phase::Prompt(prompt.to_string()),
+            Phase::Generate {
+                prefer: prefer.to_string(),
+                max_tokens,
+            },
+        ];
+        let tr = run_controller_script(&mut Runner::new(), script);
+        let text = tr.output_text(&env);
+        (env, text)
+    }
+
+    #[test]
+    fn invents_prompt() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let tr = run_controller_script(&mut Runner::new(), vec![Phase::Prompt(String::new())]);
+        assert_eq!(env.decode_tokens(&tr.tokens), b"Here's a tweet:\n");
+    }
+
+    #[test]
+    fn follows_conforming_text() {
+        // "Hello" is a single token, but it would put a lowercase letter at position 4
+        let (_, text) = generate("Hi", "HellOWorLd", 10);
+        assert_eq!(text, "HellOWorLd");
+    }
+
+    #[test]
+    fn upper_case_every_fourth_byte() {
+        let (_, text) = generate("Hi", "Hello world, this is a tweet", 100);
+        assert!(text.len() >= 50);
+        for (idx, b) in text.bytes().enumerate() {
+            if idx % 4 == 0 {
+                assert!(b.is_ascii_uppercase(), "{text:?} at {idx}");
+            }
+        }
+    }
+
+    #[test]
+    fn follows_splices() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let ff_tokens = env.tokenize("ABcd");
+        // after sampling 3 tokens, the last 2 are replaced
+        let mut ctrl = SpliceAt::new(Runner::new(), 3, 2, ff_tokens.clone());
+        let script = vec![
+            Phase::Prompt("Hi".to_string()),
+            Phase::Generate {
+                prefer: "Hello world, this is a tweet".to_string(),
+                max_tokens: 8,
+            },
+        ];
+        let tr = run_controller_script(&mut ctrl, script);
+        let output = tr.output();
+        assert_eq!(output.len(), 8 - 2 + ff_tokens.len());
+        assert_eq!(output[1..1 + ff_tokens.len()], ff_tokens);
+
+        // the last sampled token is not passed to mid_process() anymore
+        let seen = &output[..output.len() - 1];
+        assert_eq!(ctrl.ctrl.tokens, seen);
+        let num_bytes = env.decode_tokens(seen).len();
+        assert_eq!(ctrl.ctrl.recognizer.state(), num_bytes);
+    }
+
+    #[test]
+    fn bias_cache_reuses_masks() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let script = vec![
+            Phase::Prompt("Hi".to_string()),
+            Phase::Generate {
+                prefer: "Hello world, this is a tweet".to_string(),
+                max_tokens: 100,
+            },
+        ];
+        let mut runner = Runner::new();
+        run_controller_script(&mut runner, script);
+        // one mask for each position of the next upper case letter
+        let (hits, misses) = (runner.bias_cache.hits(), runner.bias_cache.misses());
+        assert!(misses <= 4, "{misses} misses");
+        assert!(
+            hits * 4 >= (hits + misses) * 3,
+            "{hits} hits, {misses} misses"
+        );
+
+        // the cached sets are the ones computed from scratch
+        let trie = env.tok_trie();
+        let mut cache = BiasCache::default();
+        let mut rec = StackRecognizer::from(QuadUpper {});
+        for state in 0..12 {
+            rec.reset_to(state);
+            let mut cached = trie.alloc_token_set();
+            trie.compute_bias_cached(&mut rec, &mut cache, &mut cached);
+            let mut fresh = trie.alloc_token_set();
+            trie.compute_bias(&mut rec, &mut fresh);
+            let differ = (0..trie.vocab_size() as u32)
+                .find(|t| cached.is_allowed(*t) != fresh.is_allowed(*t));
+            assert_eq!(differ, None, "state {state}");
+        }
+        assert_eq!((cache.hits(), cache.misses()), (8, 4));
+    }
+
+    #[test]
+    #[ignore = "micro-benchmark; run with --nocapture"]
+    fn bench_bias_cache() {
+        let env = MockTokenizerEnv::default();
+        let trie = env.tok_trie();
+        let mut rec = StackRecognizer::from(QuadUpper {});
+        let mut set = trie.alloc_token_set();
+        let steps = 10_000;
+
+        let t0 = Instant::now();
+        for state in 0..steps {
+            rec.reset_to(state);
+            trie.compute_bias(&mut rec, &mut set);
+        }
+        let plain = t0.elapsed();
+
+        let mut cache = BiasCache::default();
+        let t0 = Instant::now();
+        for state in 0..steps {
+            rec.reset_to(state);
+            trie.compute_bias_cached(&mut rec, &mut cache, &mut set);
+        }
+        let cached = t0.elapsed();
+        println!(
+            "{steps} steps: compute_bias() {plain:?}, compute_bias_cached() {cached:?} \
+             ({} hits, {} misses)",
+            cache.hits(),
+            cache.misses()
+        );
+    }
+
+    #[test]
+    #[ignore = "regenerates the fixture for replays_recorded_session"]
+    fn record_session() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let script = vec![
+            Phase::Prompt(String::new()),
+            Phase::Generate {
+                prefer: "Hello world, this is a tweet".to_string(),
+                max_tokens: 20,
+            },
+        ];
+        let (_, log) = record_controller_script(Runner::new, script);
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/session.bin");
+        std::fs::write(path, log).unwrap();
+    }
+
+    #[test]
+    fn native_masks_match_harness() {
+        let env = MockTokenizerEnv::default();
+        MockHost::install(&env);
+        let script = vec![
+            Phase::Prompt("Hi".to_string()),
+            Phase::Generate {
+                prefer: "Hello world, this is a tweet".to_string(),
+                max_tokens: 20,
+            },
+        ];
+        let (_, log) = record_controller_script(Runner::new, script);
+
+        // the recorded calls again, made directly on a controller like in the engine
+        let trie = env.tok_trie();
+        let mut native = Runner::with_trie(trie.clone());
+        let mut biases = BiasBuffer::new(trie.vocab_size());
+        let mut num_masks = 0;
+        for r in Record::parse_log(&log).unwrap() {
+            match (r.kind, r.phase) {
+                (RecordKind::ProcessArg, RecordPhase::InitPrompt) => {
+                    native.init_prompt(serde_json::from_slice(&r.data).unwrap());
+                }
+                (RecordKind::ProcessArg, RecordPhase::MidProcess) => {
+                    biases.clear();
+                    let arg = serde_json::from_slice(&r.data).unwrap();
+                    let res = mid_process_into(&mut native, arg, &mut biases);
+                    assert_eq!(res.branches.len(), 1, "step {}", r.step);
+                }
+                (RecordKind::LogitBias, RecordPhase::MidProcess) => {
+                    // the value returned by the host, then the mask
+                    let words: Vec<u32> = r.data[4..]
+                        .chunks_exact(4)
+                        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+                        .collect();
+                    for (tok, bias) in biases.mask(0).iter().enumerate() {
+                        let allowed = words[tok / 32] & (1 << (tok % 32)) != 0;
+                        assert_eq!(*bias == BIAS_ALLOW, allowed, "step {} token {tok}", r.step);
+                    }
+                    num_masks += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(num_masks, 20);
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let log = include_bytes!("../fixtures/session.bin");
+        assert!(replay_session(log, Runner::new).unwrap() > 10);
+    }
+}
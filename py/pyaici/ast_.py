@@ -70,6 +70,7 @@ def gen(
     `rx` is a regular expression to match. If `yacc` is given, it is a yacc grammar to parse.
     `stop_at` is a string to stop at.
     If `max_tokens` is given, stop after that many tokens; similarly for `max_words` and `max_bytes`.
+    Tokens with tags from `mask_tags` (see `fixed()`) are hidden from the model while generating.
     """
     if not stmts:
         stmts = []
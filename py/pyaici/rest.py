@@ -225,6 +225,10 @@ def run_controller(
                     w = s.get("WriteVar", None)
                     if w:
                         storage[w["name"]] = w["value"]
+                    wb = s.get("WriteBatch", None)
+                    if wb:
+                        for name, value in wb["writes"]:
+                            storage[name] = value
                 err = ch.get("error", "")
                 if log_level > 2:
                     l = ch["logs"].rstrip("\n")
@@ -171,6 +171,29 @@ def test_ff_1():
     )
 
 
+def test_ff_long():
+    # ~30 forced tokens are computed in a single step, not one step per token
+    forced = " one two three four five six seven eight nine ten" * 3
+    res = pyaici.rest.run_controller(
+        controller=pyaici.rest.ast_module,
+        controller_arg={
+            "steps": [
+                ast.fixed("Count:"),
+                ast.gen(max_tokens=1),
+                ast.fixed(forced),
+                ast.gen(max_tokens=1),
+            ]
+        },  # type: ignore
+        temperature=0.0,
+        max_tokens=50,
+    )
+    if res["error"]:
+        pytest.fail(res["error"])
+    assert forced.strip() in res["text"][0]
+    # gen, forced run (1-2 steps), gen, and stopping
+    assert res["usage"]["sampled_tokens"] <= 6
+
+
 def test_ff_2():
     expect(
         "Hello, 7 + 8 = 15",
@@ -202,6 +225,20 @@ def disabled_test_mask_2():
     check_mask(" French is 'bonjour'.", [])
 
 
+def test_mask_tags():
+    def steps(mask_tags):
+        return [
+            ast.fixed(" The secret password is 'parrot'.", tag="secret"),
+            ast.fixed(" Q: What is the secret password? A: The password is '"),
+            ast.gen(max_tokens=10, mask_tags=mask_tags),
+        ]
+
+    # the model no longer sees the sentence after the first few tokens
+    plain = greedy_query(wrap("Answer the question."), steps(None))
+    masked = greedy_query(wrap("Answer the question."), steps(["secret"]))
+    assert plain != masked
+
+
 def test_fork_1():
     expect(
         [
@@ -0,0 +1,93 @@
+import json
+from concurrent.futures import ThreadPoolExecutor
+
+import pyaici.rest
+
+
+def sse_events(resp):
+    for line in resp.iter_lines():
+        if not line:
+            continue
+        line = line.decode("utf-8")
+        assert line.startswith("data: ")
+        data = line[6:]
+        if data == "[DONE]":
+            break
+        yield json.loads(data)
+
+
+def test_completion():
+    resp = pyaici.rest.req(
+        "post",
+        "completions",
+        json={"model": "", "prompt": "Hello", "max_tokens": 5},
+    )
+    assert resp.status_code == 200, resp.text
+    d = resp.json()
+    assert d["object"] == "text_completion"
+    assert len(d["choices"]) == 1
+    assert d["choices"][0]["finish_reason"] in ("stop", "length")
+    assert d["usage"]["completion_tokens"] <= 5
+
+
+def test_completion_stream():
+    resp = pyaici.rest.req(
+        "post",
+        "completions",
+        json={"model": "", "prompt": "Hello", "max_tokens": 5, "stream": True},
+        stream=True,
+    )
+    assert resp.status_code == 200, resp.text
+    chunks = list(sse_events(resp))
+    # one chunk per step, rather than the whole text at the end
+    assert len(chunks) > 1
+    assert chunks[-1]["choices"][0]["finish_reason"] in ("stop", "length")
+    assert all(c["choices"][0]["finish_reason"] is None for c in chunks[:-1])
+
+
+def test_chat_completion_stream():
+    resp = pyaici.rest.req(
+        "post",
+        "chat/completions",
+        json={
+            "model": "",
+            "messages": [{"role": "user", "content": "Say hello"}],
+            "max_tokens": 5,
+            "stop": "\n",
+            "stream": True,
+        },
+        stream=True,
+    )
+    assert resp.status_code == 200, resp.text
+    chunks = list(sse_events(resp))
+    assert len(chunks) > 1
+    assert chunks[0]["object"] == "chat.completion.chunk"
+    assert chunks[-1]["choices"][0]["finish_reason"] in ("stop", "length")
+
+
+def seeded_completion(prompt: str, seed: int):
+    resp = pyaici.rest.req(
+        "post",
+        "completions",
+        json={
+            "model": "",
+            "prompt": prompt,
+            "max_tokens": 20,
+            "temperature": 1.0,
+            "seed": seed,
+            "ignore_eos": True,
+        },
+    )
+    assert resp.status_code == 200, resp.text
+    return resp.json()["choices"][0]["text"]
+
+
+def test_seed_independent_of_batch():
+    alone = seeded_completion("Once upon a time", 1234)
+    assert seeded_completion("Once upon a time", 1234) == alone
+    # the same request batched with another one samples the same tokens
+    with ThreadPoolExecutor(max_workers=2) as pool:
+        other = pool.submit(seeded_completion, "The weather today", 99)
+        batched = pool.submit(seeded_completion, "Once upon a time", 1234)
+        assert batched.result() == alone
+        other.result()
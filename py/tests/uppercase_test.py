@@ -0,0 +1,39 @@
+import os
+import subprocess
+
+import pytest
+
+import pyaici.rest
+
+prj_dir = os.path.dirname(os.path.abspath(__file__)) + "/../.."
+
+
+@pytest.fixture(scope="module")
+def uppercase_module():
+    prog = prj_dir + "/controllers/uppercase"
+    r = subprocess.run(
+        ["cargo", "build", "--release"],
+        cwd=prog,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    if r.returncode != 0:
+        pytest.fail("failed to build controllers/uppercase")
+    file_path = prj_dir + "/target/wasm32-wasi/release/aici_uppercase.wasm"
+    return pyaici.rest.upload_module(file_path)
+
+
+def test_upper_case_every_fourth_byte(uppercase_module):
+    res = pyaici.rest.run_controller(
+        controller=uppercase_module,
+        prompt="Here's a tweet:\n",
+        temperature=0.0,
+        max_tokens=40,
+    )
+    if res["error"]:
+        pytest.fail(res["error"])
+    text: str = res["text"][0]
+    assert len(text) > 0
+    for idx, b in enumerate(text.encode("utf-8")):
+        if idx % 4 == 0:
+            assert ord("A") <= b <= ord("Z"), f"{text!r} at {idx}"
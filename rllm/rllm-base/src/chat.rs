@@ -0,0 +1,1106 @@
+// Chat templates, as found in the "chat_template" field of tokenizer_config.json.
+// These are written in Jinja; we only implement the subset used in practice by
+// chat templates (Llama, Mistral, etc.): text, {{ expr }}, {% if %}, {% for %}, {% set %},
+// with trim_blocks and lstrip_blocks on, like HF transformers does.
+
+use crate::{HashMap, LoaderArgs, Repo};
+use aicirt::bail_user;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// "system", "user" or "assistant"
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+const LLAMA2_TEMPLATE: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+const MISTRAL_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}";
+
+/// Names accepted by ChatTemplate::builtin() (and --chat-template).
+pub const BUILTIN_CHAT_TEMPLATES: &[&str] = &["llama2", "mistral"];
+
+/// Special tokens that start prompts in common models, most likely first.
+const BOS_TOKENS: &[&str] = &["<s>", "<|begin_of_text|>", "<bos>", "<|startoftext|>"];
+
+/// The first of BOS_TOKENS the tokenizer has, or "" (for models without one).
+pub fn find_bos_token(has_token: impl Fn(&str) -> bool) -> &'static str {
+    BOS_TOKENS
+        .iter()
+        .find(|t| has_token(t))
+        .copied()
+        .unwrap_or("")
+}
+
+pub struct ChatTemplate {
+    nodes: Vec<Node>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    pub fn new(source: &str, bos_token: &str, eos_token: &str) -> Result<Self> {
+        let toks = lex(source)?;
+        let mut pos = 0;
+        let (nodes, end) = parse_nodes(&toks, &mut pos, &[])?;
+        if let Some(tag) = end {
+            bail!("chat template: unexpected {{% {tag} %}}");
+        }
+        Ok(ChatTemplate {
+            nodes,
+            bos_token: bos_token.to_string(),
+            eos_token: eos_token.to_string(),
+        })
+    }
+
+    pub fn builtin(name: &str, bos_token: &str, eos_token: &str) -> Result<Self> {
+        let source = match name {
+            "llama2" => LLAMA2_TEMPLATE,
+            "mistral" => MISTRAL_TEMPLATE,
+            _ => bail!(
+                "unknown chat template {name:?}; try one of {:?}",
+                BUILTIN_CHAT_TEMPLATES
+            ),
+        };
+        Self::new(source, bos_token, eos_token)
+    }
+
+    /// Use the template from tokenizer_config.json, if the model has one,
+    /// and otherwise the built-in one selected in `args`.
+    /// `bos_token` and `eos_token` are only used when the config doesn't list them;
+    /// null there means the model has no such token.
+    pub fn load(repo: &Repo, args: &LoaderArgs, bos_token: &str, eos_token: &str) -> Result<Self> {
+        let config = repo
+            .read("tokenizer_config.json")
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok());
+        if let Some(config) = config {
+            let special = |name: &str, defl: &str| match config.get(name) {
+                None => defl.to_string(),
+                Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(s)) => s.clone(),
+                Some(v) => v["content"].as_str().unwrap_or(defl).to_string(),
+            };
+            let bos_token = special("bos_token", bos_token);
+            let eos_token = special("eos_token", eos_token);
+            let source = match &config["chat_template"] {
+                serde_json::Value::String(s) => Some(s.as_str()),
+                // newer configs have a list of named templates
+                serde_json::Value::Array(lst) => lst
+                    .iter()
+                    .find(|t| t["name"] == "default")
+                    .and_then(|t| t["template"].as_str()),
+                _ => None,
+            };
+            if let Some(source) = source {
+                match Self::new(source, &bos_token, &eos_token) {
+                    Ok(t) => {
+                        log::info!("using chat template from tokenizer_config.json");
+                        return Ok(t);
+                    }
+                    Err(e) => log::warn!("can't use chat template from tokenizer_config.json: {e}"),
+                }
+            }
+        }
+        log::info!("using built-in chat template {:?}", args.chat_template);
+        Self::builtin(&args.chat_template, bos_token, eos_token)
+    }
+
+    /// Turn the conversation into a prompt; special tokens (like BOS) are included
+    /// as text, so the prompt should be tokenized without adding them again.
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let messages = messages
+            .iter()
+            .map(|m| {
+                Value::Map(vec![
+                    ("role".to_string(), Value::Str(m.role.clone())),
+                    ("content".to_string(), Value::Str(m.content.clone())),
+                ])
+            })
+            .collect();
+        let mut vars = HashMap::default();
+        vars.insert("messages".to_string(), Value::List(messages));
+        vars.insert("bos_token".to_string(), Value::Str(self.bos_token.clone()));
+        vars.insert("eos_token".to_string(), Value::Str(self.eos_token.clone()));
+        vars.insert(
+            "add_generation_prompt".to_string(),
+            Value::Bool(add_generation_prompt),
+        );
+        let mut out = String::new();
+        render_nodes(&self.nodes, &mut vars, &mut out)?;
+        Ok(out)
+    }
+}
+
+//
+// Lexing of the template text
+//
+
+#[derive(Debug)]
+enum TplTok {
+    Text(String),
+    Expr(String),
+    Stmt(String),
+}
+
+fn find_close(src: &str, start: usize, close: &str) -> Result<usize> {
+    let bytes = src.as_bytes();
+    let mut quote = None;
+    let mut i = start;
+    while i < bytes.len() {
+        let b = bytes[i];
+        match quote {
+            Some(q) => {
+                if b == b'\\' {
+                    i += 1;
+                } else if b == q {
+                    quote = None;
+                }
+            }
+            None => {
+                if b == b'\'' || b == b'"' {
+                    quote = Some(b);
+                } else if bytes[i..].starts_with(close.as_bytes()) {
+                    return Ok(i);
+                }
+            }
+        }
+        i += 1;
+    }
+    bail!("chat template: missing {close:?}")
+}
+
+fn lex(src: &str) -> Result<Vec<TplTok>> {
+    let mut toks = vec![];
+    let mut pos = 0;
+    let mut after_block = false;
+    let mut strip_next = false;
+
+    loop {
+        let next = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| src[pos..].find(open).map(|i| pos + i))
+            .min();
+        let end = next.unwrap_or(src.len());
+        let mut text = &src[pos..end];
+
+        if strip_next {
+            text = text.trim_start();
+        } else if after_block {
+            // trim_blocks
+            text = text
+                .strip_prefix("\r\n")
+                .or_else(|| text.strip_prefix('\n'))
+                .unwrap_or(text);
+        }
+
+        let open_start = match next {
+            Some(i) => i,
+            None => {
+                if !text.is_empty() {
+                    toks.push(TplTok::Text(text.to_string()));
+                }
+                return Ok(toks);
+            }
+        };
+
+        let kind = &src[open_start..open_start + 2];
+        let mut inner_start = open_start + 2;
+        if src[inner_start..].starts_with('-') {
+            text = text.trim_end();
+            inner_start += 1;
+        } else if kind != "{{" {
+            // lstrip_blocks: remove indentation before a block tag
+            let line_start = text.rfind('\n').map(|i| i + 1);
+            if line_start.is_some() || toks.is_empty() {
+                let tail = &text[line_start.unwrap_or(0)..];
+                if tail.chars().all(|c| c == ' ' || c == '\t') {
+                    text = &text[..text.len() - tail.len()];
+                }
+            }
+        }
+        if !text.is_empty() {
+            toks.push(TplTok::Text(text.to_string()));
+        }
+
+        let close = match kind {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let close_start = find_close(src, inner_start, close)?;
+        let mut inner = &src[inner_start..close_start];
+        strip_next = inner.ends_with('-');
+        if strip_next {
+            inner = &inner[..inner.len() - 1];
+        }
+        let inner = inner.trim().to_string();
+        match kind {
+            "{{" => toks.push(TplTok::Expr(inner)),
+            "{%" => toks.push(TplTok::Stmt(inner)),
+            _ => {}
+        }
+        after_block = kind != "{{";
+        pos = close_start + 2;
+    }
+}
+
+//
+// Lexing of expressions
+//
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "//", "<", ">", "+", "-", "*", "/", "%", "(", ")", "[", "]", ".", ",",
+    ":", "|", "~", "=",
+];
+
+fn lex_expr(src: &str) -> Result<Vec<Tok>> {
+    let mut toks = vec![];
+    let chars: Vec<char> = src.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            let mut s = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    i += 1;
+                    s.push(match chars[i] {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        c => c,
+                    });
+                } else {
+                    s.push(chars[i]);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                bail!("chat template: unterminated string in {src:?}");
+            }
+            i += 1;
+            toks.push(Tok::Str(s));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let s: String = chars[start..i].iter().collect();
+            toks.push(Tok::Int(s.parse()?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push(Tok::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..std::cmp::min(i + 2, chars.len())].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    toks.push(Tok::Op(op));
+                    i += op.len();
+                }
+                None => bail!("chat template: unexpected {c:?} in {src:?}"),
+            }
+        }
+    }
+    Ok(toks)
+}
+
+//
+// Parsing
+//
+
+#[derive(Debug)]
+enum Expr {
+    Const(Value),
+    Var(String),
+    List(Vec<Expr>),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Call(Box<Expr>, Vec<Expr>),
+    Filter(Box<Expr>, String),
+    Test(Box<Expr>, String, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For(String, Expr, Vec<Node>),
+    Set(String, Expr),
+}
+
+struct ExprParser {
+    toks: Vec<Tok>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn new(src: &str) -> Result<Self> {
+        Ok(ExprParser {
+            toks: lex_expr(src)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Op(o)) if *o == op)
+    }
+
+    fn is_kw(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(i)) if i == kw)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let r = self.is_op(op);
+        if r {
+            self.pos += 1;
+        }
+        r
+    }
+
+    fn eat_kw(&mut self, kw: &str) -> bool {
+        let r = self.is_kw(kw);
+        if r {
+            self.pos += 1;
+        }
+        r
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if !self.eat_op(op) {
+            bail!("chat template: expecting {op:?}, got {:?}", self.peek());
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Tok::Ident(i)) => {
+                let i = i.clone();
+                self.pos += 1;
+                Ok(i)
+            }
+            t => bail!("chat template: expecting identifier, got {t:?}"),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.toks.len()
+    }
+
+    fn expect_end(&self) -> Result<()> {
+        if !self.at_end() {
+            bail!("chat template: unexpected {:?}", self.peek());
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let e = self.or_expr()?;
+        if self.eat_kw("if") {
+            let cond = self.or_expr()?;
+            let other = if self.eat_kw("else") {
+                self.expr()?
+            } else {
+                Expr::Const(Value::Undefined)
+            };
+            Ok(Expr::Cond(Box::new(cond), Box::new(e), Box::new(other)))
+        } else {
+            Ok(e)
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Expr> {
+        let mut e = self.and_expr()?;
+        while self.eat_kw("or") {
+            e = Expr::Or(Box::new(e), Box::new(self.and_expr()?));
+        }
+        Ok(e)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr> {
+        let mut e = self.not_expr()?;
+        while self.eat_kw("and") {
+            e = Expr::And(Box::new(e), Box::new(self.not_expr()?));
+        }
+        Ok(e)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr> {
+        if self.eat_kw("not") {
+            Ok(Expr::Not(Box::new(self.not_expr()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let e = self.concat()?;
+        for op in ["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat_op(op) {
+                return Ok(Expr::Binary(op, Box::new(e), Box::new(self.concat()?)));
+            }
+        }
+        if self.eat_kw("in") {
+            return Ok(Expr::Binary("in", Box::new(e), Box::new(self.concat()?)));
+        }
+        if self.is_kw("not")
+            && matches!(self.toks.get(self.pos + 1), Some(Tok::Ident(i)) if i == "in")
+        {
+            self.pos += 2;
+            let e = Expr::Binary("in", Box::new(e), Box::new(self.concat()?));
+            return Ok(Expr::Not(Box::new(e)));
+        }
+        if self.eat_kw("is") {
+            let negated = self.eat_kw("not");
+            let test = self.ident()?;
+            return Ok(Expr::Test(Box::new(e), test, negated));
+        }
+        Ok(e)
+    }
+
+    fn concat(&mut self) -> Result<Expr> {
+        let mut e = self.additive()?;
+        while self.eat_op("~") {
+            e = Expr::Binary("~", Box::new(e), Box::new(self.additive()?));
+        }
+        Ok(e)
+    }
+
+    fn additive(&mut self) -> Result<Expr> {
+        let mut e = self.multiplicative()?;
+        loop {
+            let op = if self.eat_op("+") {
+                "+"
+            } else if self.eat_op("-") {
+                "-"
+            } else {
+                return Ok(e);
+            };
+            e = Expr::Binary(op, Box::new(e), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr> {
+        let mut e = self.unary()?;
+        loop {
+            let op = match ["*", "//", "/", "%"].iter().find(|op| self.is_op(op)) {
+                Some(op) => *op,
+                None => return Ok(e),
+            };
+            self.pos += 1;
+            e = Expr::Binary(op, Box::new(e), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat_op("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.filtered()
+        }
+    }
+
+    fn filtered(&mut self) -> Result<Expr> {
+        let mut e = self.postfix()?;
+        while self.eat_op("|") {
+            e = Expr::Filter(Box::new(e), self.ident()?);
+        }
+        Ok(e)
+    }
+
+    fn args(&mut self, close: &str) -> Result<Vec<Expr>> {
+        let mut args = vec![];
+        while !self.eat_op(close) {
+            if !args.is_empty() {
+                self.expect_op(",")?;
+            }
+            args.push(self.expr()?);
+        }
+        Ok(args)
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut e = self.primary()?;
+        loop {
+            if self.eat_op(".") {
+                e = Expr::Attr(Box::new(e), self.ident()?);
+            } else if self.eat_op("(") {
+                e = Expr::Call(Box::new(e), self.args(")")?);
+            } else if self.eat_op("[") {
+                let start = if self.is_op(":") {
+                    None
+                } else {
+                    Some(Box::new(self.expr()?))
+                };
+                if self.eat_op(":") {
+                    let stop = if self.is_op("]") {
+                        None
+                    } else {
+                        Some(Box::new(self.expr()?))
+                    };
+                    e = Expr::Slice(Box::new(e), start, stop);
+                } else {
+                    e = Expr::Index(Box::new(e), start.unwrap());
+                }
+                self.expect_op("]")?;
+            } else {
+                return Ok(e);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let tok = match self.peek() {
+            Some(t) => t.clone(),
+            None => bail!("chat template: unexpected end of expression"),
+        };
+        self.pos += 1;
+        match tok {
+            Tok::Str(s) => Ok(Expr::Const(Value::Str(s))),
+            Tok::Int(i) => Ok(Expr::Const(Value::Int(i))),
+            Tok::Ident(i) => Ok(match i.as_str() {
+                "true" | "True" => Expr::Const(Value::Bool(true)),
+                "false" | "False" => Expr::Const(Value::Bool(false)),
+                "none" | "None" => Expr::Const(Value::None),
+                _ => Expr::Var(i),
+            }),
+            Tok::Op("(") => {
+                let e = self.expr()?;
+                self.expect_op(")")?;
+                Ok(e)
+            }
+            Tok::Op("[") => Ok(Expr::List(self.args("]")?)),
+            t => bail!("chat template: unexpected {t:?}"),
+        }
+    }
+}
+
+fn parse_expr(src: &str) -> Result<Expr> {
+    let mut p = ExprParser::new(src)?;
+    let e = p.expr()?;
+    p.expect_end()?;
+    Ok(e)
+}
+
+/// Parse until one of the `stop` tags; returns the tag that ended the block (if any).
+fn parse_nodes(
+    toks: &[TplTok],
+    pos: &mut usize,
+    stop: &[&str],
+) -> Result<(Vec<Node>, Option<String>)> {
+    let mut nodes = vec![];
+    while *pos < toks.len() {
+        let tok = &toks[*pos];
+        *pos += 1;
+        let stmt = match tok {
+            TplTok::Text(t) => {
+                nodes.push(Node::Text(t.clone()));
+                continue;
+            }
+            TplTok::Expr(e) => {
+                nodes.push(Node::Output(parse_expr(e)?));
+                continue;
+            }
+            TplTok::Stmt(s) => s,
+        };
+
+        let mut p = ExprParser::new(stmt)?;
+        let keyword = p.ident()?;
+        if stop.contains(&keyword.as_str()) {
+            // the caller parses the rest of the tag (elif condition)
+            *pos -= 1;
+            return Ok((nodes, Some(keyword)));
+        }
+        match keyword.as_str() {
+            "if" => {
+                let mut branches = vec![];
+                let mut cond = p.expr()?;
+                p.expect_end()?;
+                let else_body = loop {
+                    let (body, end) = parse_nodes(toks, pos, &["elif", "else", "endif"])?;
+                    branches.push((cond, body));
+                    let end_stmt = match (&end, toks.get(*pos)) {
+                        (Some(_), Some(TplTok::Stmt(s))) => s,
+                        _ => bail!("chat template: missing {{% endif %}}"),
+                    };
+                    *pos += 1;
+                    match end.as_deref() {
+                        Some("elif") => {
+                            let mut p = ExprParser::new(end_stmt)?;
+                            p.ident()?;
+                            cond = p.expr()?;
+                            p.expect_end()?;
+                        }
+                        Some("else") => {
+                            let (body, end) = parse_nodes(toks, pos, &["endif"])?;
+                            if end.is_none() {
+                                bail!("chat template: missing {{% endif %}}");
+                            }
+                            *pos += 1;
+                            break body;
+                        }
+                        _ => break vec![],
+                    }
+                };
+                nodes.push(Node::If(branches, else_body));
+            }
+            "for" => {
+                let var = p.ident()?;
+                if !p.eat_kw("in") {
+                    bail!("chat template: expecting 'in' in {{% {stmt} %}}");
+                }
+                let iter = p.expr()?;
+                p.expect_end()?;
+                let (body, end) = parse_nodes(toks, pos, &["endfor"])?;
+                if end.is_none() {
+                    bail!("chat template: missing {{% endfor %}}");
+                }
+                *pos += 1;
+                nodes.push(Node::For(var, iter, body));
+            }
+            "set" => {
+                let var = p.ident()?;
+                p.expect_op("=")?;
+                let value = p.expr()?;
+                p.expect_end()?;
+                nodes.push(Node::Set(var, value));
+            }
+            _ => bail!("chat template: unsupported {{% {stmt} %}}"),
+        }
+    }
+    Ok((nodes, None))
+}
+
+//
+// Evaluation
+//
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(l) => !l.is_empty(),
+            Value::Map(m) => !m.is_empty(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Str(s) => s.clone(),
+            Value::List(_) | Value::Map(_) => format!("{self:?}"),
+        }
+    }
+
+    fn get(&self, key: &Value) -> Value {
+        match (self, key) {
+            (Value::Map(m), Value::Str(k)) => m
+                .iter()
+                .find(|(k2, _)| k2 == k)
+                .map(|(_, v)| v.clone())
+                .unwrap_or(Value::Undefined),
+            (Value::List(l), Value::Int(i)) => {
+                let i = if *i < 0 { l.len() as i64 + i } else { *i };
+                l.get(i as usize).cloned().unwrap_or(Value::Undefined)
+            }
+            _ => Value::Undefined,
+        }
+    }
+
+    fn len(&self) -> Result<usize> {
+        match self {
+            Value::Str(s) => Ok(s.chars().count()),
+            Value::List(l) => Ok(l.len()),
+            Value::Map(m) => Ok(m.len()),
+            _ => bail!("chat template: {self:?} has no length"),
+        }
+    }
+}
+
+fn slice_range(len: usize, start: Option<i64>, stop: Option<i64>) -> (usize, usize) {
+    let clamp = |i: i64| {
+        let i = if i < 0 { len as i64 + i } else { i };
+        i.clamp(0, len as i64) as usize
+    };
+    let start = start.map(clamp).unwrap_or(0);
+    let stop = stop.map(clamp).unwrap_or(len);
+    (start, std::cmp::max(start, stop))
+}
+
+fn as_int(v: &Value) -> Result<i64> {
+    match v {
+        Value::Int(i) => Ok(*i),
+        Value::Bool(b) => Ok(*b as i64),
+        _ => bail!("chat template: expecting a number, got {v:?}"),
+    }
+}
+
+fn eval(e: &Expr, vars: &HashMap<String, Value>) -> Result<Value> {
+    Ok(match e {
+        Expr::Const(v) => v.clone(),
+        Expr::Var(name) => vars.get(name).cloned().unwrap_or(Value::Undefined),
+        Expr::List(items) => Value::List(
+            items
+                .iter()
+                .map(|e| eval(e, vars))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        Expr::Attr(obj, name) => eval(obj, vars)?.get(&Value::Str(name.clone())),
+        Expr::Index(obj, idx) => eval(obj, vars)?.get(&eval(idx, vars)?),
+        Expr::Slice(obj, start, stop) => {
+            let obj = eval(obj, vars)?;
+            let start = match start {
+                Some(e) => Some(as_int(&eval(e, vars)?)?),
+                None => None,
+            };
+            let stop = match stop {
+                Some(e) => Some(as_int(&eval(e, vars)?)?),
+                None => None,
+            };
+            match obj {
+                Value::List(l) => {
+                    let (a, b) = slice_range(l.len(), start, stop);
+                    Value::List(l[a..b].to_vec())
+                }
+                Value::Str(s) => {
+                    let chars: Vec<char> = s.chars().collect();
+                    let (a, b) = slice_range(chars.len(), start, stop);
+                    Value::Str(chars[a..b].iter().collect())
+                }
+                v => bail!("chat template: can't slice {v:?}"),
+            }
+        }
+        Expr::Call(f, args) => {
+            let args = args
+                .iter()
+                .map(|e| eval(e, vars))
+                .collect::<Result<Vec<_>>>()?;
+            call(f, args, vars)?
+        }
+        Expr::Filter(e, name) => {
+            let v = eval(e, vars)?;
+            match (name.as_str(), &v) {
+                ("length" | "count", _) => Value::Int(v.len()? as i64),
+                ("trim", Value::Str(s)) => Value::Str(s.trim().to_string()),
+                ("upper", Value::Str(s)) => Value::Str(s.to_uppercase()),
+                ("lower", Value::Str(s)) => Value::Str(s.to_lowercase()),
+                ("string", _) => Value::Str(v.to_text()),
+                _ => bail!("chat template: unsupported filter {name} on {v:?}"),
+            }
+        }
+        Expr::Test(e, name, negated) => {
+            let v = eval(e, vars)?;
+            let r = match name.as_str() {
+                "defined" => v != Value::Undefined,
+                "undefined" => v == Value::Undefined,
+                "none" => v == Value::None,
+                "string" => matches!(v, Value::Str(_)),
+                "number" => matches!(v, Value::Int(_)),
+                "mapping" => matches!(v, Value::Map(_)),
+                _ => bail!("chat template: unsupported test {name}"),
+            };
+            Value::Bool(r != *negated)
+        }
+        Expr::Not(e) => Value::Bool(!eval(e, vars)?.truthy()),
+        Expr::Neg(e) => Value::Int(-as_int(&eval(e, vars)?)?),
+        Expr::And(a, b) => {
+            let a = eval(a, vars)?;
+            if a.truthy() {
+                eval(b, vars)?
+            } else {
+                a
+            }
+        }
+        Expr::Or(a, b) => {
+            let a = eval(a, vars)?;
+            if a.truthy() {
+                a
+            } else {
+                eval(b, vars)?
+            }
+        }
+        Expr::Cond(cond, a, b) => {
+            if eval(cond, vars)?.truthy() {
+                eval(a, vars)?
+            } else {
+                eval(b, vars)?
+            }
+        }
+        Expr::Binary(op, a, b) => binary(op, eval(a, vars)?, eval(b, vars)?)?,
+    })
+}
+
+fn binary(op: &str, a: Value, b: Value) -> Result<Value> {
+    Ok(match (op, &a, &b) {
+        ("==", _, _) => Value::Bool(a == b),
+        ("!=", _, _) => Value::Bool(a != b),
+        ("~", _, _) => Value::Str(a.to_text() + &b.to_text()),
+        ("in", _, Value::Str(s)) => Value::Bool(s.contains(&a.to_text())),
+        ("in", _, Value::List(l)) => Value::Bool(l.contains(&a)),
+        ("in", Value::Str(k), Value::Map(m)) => Value::Bool(m.iter().any(|(k2, _)| k2 == k)),
+        ("+", Value::Str(x), Value::Str(y)) => Value::Str(x.clone() + y),
+        ("+", Value::List(x), Value::List(y)) => Value::List([x.clone(), y.clone()].concat()),
+        ("<" | ">" | "<=" | ">=", Value::Str(x), Value::Str(y)) => Value::Bool(match op {
+            "<" => x < y,
+            ">" => x > y,
+            "<=" => x <= y,
+            _ => x >= y,
+        }),
+        _ => {
+            let (x, y) = (as_int(&a)?, as_int(&b)?);
+            match op {
+                "+" => Value::Int(x + y),
+                "-" => Value::Int(x - y),
+                "*" => Value::Int(x * y),
+                "/" | "//" | "%" if y == 0 => bail!("chat template: division by zero"),
+                "/" | "//" => Value::Int(x.div_euclid(y)),
+                "%" => Value::Int(x.rem_euclid(y)),
+                "<" => Value::Bool(x < y),
+                ">" => Value::Bool(x > y),
+                "<=" => Value::Bool(x <= y),
+                ">=" => Value::Bool(x >= y),
+                _ => bail!("chat template: can't apply {op} to {a:?} and {b:?}"),
+            }
+        }
+    })
+}
+
+fn call(f: &Expr, args: Vec<Value>, vars: &HashMap<String, Value>) -> Result<Value> {
+    let str_arg = |idx: usize| match args.get(idx) {
+        Some(Value::Str(s)) => Ok(s.clone()),
+        a => Err(anyhow!(
+            "chat template: expecting string argument, got {a:?}"
+        )),
+    };
+    match f {
+        Expr::Var(name) if name == "raise_exception" => {
+            bail_user!("{}", str_arg(0)?)
+        }
+        Expr::Attr(obj, method) => {
+            let obj = eval(obj, vars)?;
+            let s = match &obj {
+                Value::Str(s) => s,
+                _ => bail!("chat template: unsupported method {method} on {obj:?}"),
+            };
+            Ok(match method.as_str() {
+                "strip" => Value::Str(s.trim().to_string()),
+                "lstrip" => Value::Str(s.trim_start().to_string()),
+                "rstrip" => Value::Str(s.trim_end().to_string()),
+                "upper" => Value::Str(s.to_uppercase()),
+                "lower" => Value::Str(s.to_lowercase()),
+                "startswith" => Value::Bool(s.starts_with(&str_arg(0)?)),
+                "endswith" => Value::Bool(s.ends_with(&str_arg(0)?)),
+                _ => bail!("chat template: unsupported method {method} on {obj:?}"),
+            })
+        }
+        _ => bail!("chat template: unsupported call to {f:?}"),
+    }
+}
+
+fn render_nodes(nodes: &[Node], vars: &mut HashMap<String, Value>, out: &mut String) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Output(e) => out.push_str(&eval(e, vars)?.to_text()),
+            Node::Set(name, e) => {
+                let v = eval(e, vars)?;
+                vars.insert(name.clone(), v);
+            }
+            Node::If(branches, else_body) => {
+                let mut body = else_body;
+                for (cond, b) in branches {
+                    if eval(cond, vars)?.truthy() {
+                        body = b;
+                        break;
+                    }
+                }
+                render_nodes(body, vars, out)?;
+            }
+            Node::For(var, iter, body) => {
+                let items = match eval(iter, vars)? {
+                    Value::List(l) => l,
+                    Value::Undefined => vec![],
+                    v => bail!("chat template: can't iterate over {v:?}"),
+                };
+                // variables set in the loop body stay visible after it; good enough for templates
+                let saved = (vars.remove(var), vars.remove("loop"));
+                let n = items.len();
+                for (idx, item) in items.into_iter().enumerate() {
+                    let info = vec![
+                        ("index0".to_string(), Value::Int(idx as i64)),
+                        ("index".to_string(), Value::Int(idx as i64 + 1)),
+                        ("first".to_string(), Value::Bool(idx == 0)),
+                        ("last".to_string(), Value::Bool(idx + 1 == n)),
+                        ("length".to_string(), Value::Int(n as i64)),
+                    ];
+                    vars.insert("loop".to_string(), Value::Map(info));
+                    vars.insert(var.clone(), item);
+                    render_nodes(body, vars, out)?;
+                }
+                vars.remove(var);
+                vars.remove("loop");
+                if let Some(v) = saved.0 {
+                    vars.insert(var.clone(), v);
+                }
+                if let Some(v) = saved.1 {
+                    vars.insert("loop".to_string(), v);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_bos_token, ChatMessage, ChatTemplate};
+    use crate::{LoaderArgs, Repo};
+    use std::path::Path;
+
+    fn fixture(name: &str) -> ChatTemplate {
+        let repo = Repo::Local(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/chat")
+                .join(name),
+        );
+        ChatTemplate::load(&repo, &LoaderArgs::default(), "<s>", "</s>").unwrap()
+    }
+
+    fn conversation(system: bool) -> Vec<ChatMessage> {
+        let mut messages = vec![
+            ChatMessage::new("user", "Hi!"),
+            ChatMessage::new("assistant", "Hello, how can I help?"),
+            ChatMessage::new("user", "What is 2+2?"),
+        ];
+        if system {
+            messages.insert(0, ChatMessage::new("system", "Be brief."));
+        }
+        messages
+    }
+
+    #[test]
+    fn templates_from_tokenizer_configs() {
+        let cases = [
+            (
+                "llama-2",
+                true,
+                "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi! [/INST] Hello, how can I help? </s>\
+                 <s>[INST] What is 2+2? [/INST]",
+            ),
+            (
+                "mistral",
+                false,
+                "<s>[INST] Hi! [/INST]Hello, how can I help?</s>[INST] What is 2+2? [/INST]",
+            ),
+            (
+                "llama-3",
+                true,
+                "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+                 <|start_header_id|>user<|end_header_id|>\n\nHi!<|eot_id|>\
+                 <|start_header_id|>assistant<|end_header_id|>\n\nHello, how can I help?<|eot_id|>\
+                 <|start_header_id|>user<|end_header_id|>\n\nWhat is 2+2?<|eot_id|>\
+                 <|start_header_id|>assistant<|end_header_id|>\n\n",
+            ),
+            (
+                "qwen2",
+                false,
+                "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+                 <|im_start|>user\nHi!<|im_end|>\n\
+                 <|im_start|>assistant\nHello, how can I help?<|im_end|>\n\
+                 <|im_start|>user\nWhat is 2+2?<|im_end|>\n\
+                 <|im_start|>assistant\n",
+            ),
+            (
+                "zephyr",
+                true,
+                "<|system|>\nBe brief.</s>\n<|user|>\nHi!</s>\n\
+                 <|assistant|>\nHello, how can I help?</s>\n<|user|>\nWhat is 2+2?</s>\n\
+                 <|assistant|>\n",
+            ),
+        ];
+        for (name, system, expected) in cases {
+            let prompt = fixture(name).render(&conversation(system), true).unwrap();
+            assert_eq!(prompt, expected, "{name}");
+        }
+
+        // the BOS and EOS tokens come from the config
+        let llama3 = fixture("llama-3");
+        assert_eq!(llama3.bos_token, "<|begin_of_text|>");
+        assert_eq!(llama3.eos_token, "<|eot_id|>");
+        // Qwen has none
+        assert_eq!(fixture("qwen2").bos_token, "");
+        // and the built-in template (of LoaderArgs) is used without a config
+        let builtin = fixture("no-such-model");
+        assert_eq!(builtin.bos_token, "<s>");
+        assert_eq!(
+            builtin.render(&conversation(true), true).unwrap(),
+            cases[0].2
+        );
+    }
+
+    #[test]
+    fn bos_token_from_tokenizer() {
+        assert_eq!(find_bos_token(|t| t == "<s>" || t == "<bos>"), "<s>");
+        assert_eq!(
+            find_bos_token(|t| t == "<|begin_of_text|>"),
+            "<|begin_of_text|>"
+        );
+        assert_eq!(find_bos_token(|t| t == "<|endoftext|>"), "");
+    }
+}
@@ -0,0 +1,552 @@
+//! Subcommands of the rllm-cli tools, which run the engine directly instead of serving
+//! it over HTTP. The backends provide the binaries, flattening RllmCliArgs (the same
+//! model options as the server) and their own model arguments into each subcommand.
+//! The results go to stdout (one JSON value with --json), and logs to stderr.
+
+use crate::{
+    config::{PreemptionMode, SamplingParams},
+    seq::{RequestOutput, Token},
+    EngineListener, LoaderArgs, ModelExec, RequestMeta, RllmEngine, StepStats, StepTiming,
+    SyntheticBatch,
+};
+use aicirt::{bail_user, UserError};
+use anyhow::{anyhow, Result};
+use clap::Args;
+use rand::{rngs::StdRng, Rng as _, SeedableRng};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Args, Debug)]
+pub struct GenerateArgs {
+    /// Text to continue
+    #[arg(long, help_heading = "Generation")]
+    pub prompt: String,
+
+    /// Maximum number of tokens to generate
+    #[arg(long, default_value_t = 128, help_heading = "Generation")]
+    pub max_tokens: usize,
+
+    /// Sampling temperature
+    #[arg(long, default_value_t = 0.7, help_heading = "Generation")]
+    pub temperature: f32,
+
+    /// Always pick the most likely token (the same as --temperature 0)
+    #[arg(long, default_value_t = false, help_heading = "Generation")]
+    pub greedy: bool,
+
+    /// Only sample from the most likely tokens, up to this total probability
+    #[arg(long, default_value_t = 1.0, help_heading = "Generation")]
+    pub top_p: f32,
+
+    /// Seed for sampling; random by default (the one used is printed with --json)
+    #[arg(long, help_heading = "Generation")]
+    pub seed: Option<u64>,
+
+    /// Print the text as it's generated (with --json, one line per step)
+    #[arg(long, default_value_t = false, help_heading = "Generation")]
+    pub stream: bool,
+
+    /// Print JSON instead of text
+    #[arg(long, default_value_t = false, help_heading = "Generation")]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct TokenizeArgs {
+    /// Text to tokenize
+    #[arg(long, help_heading = "Tokenize")]
+    pub text: String,
+
+    /// Add special tokens (like BOS), as for prompts
+    #[arg(long, default_value_t = false, help_heading = "Tokenize")]
+    pub special: bool,
+
+    /// Print JSON instead of text
+    #[arg(long, default_value_t = false, help_heading = "Tokenize")]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Batch sizes to run, each with --prompt-len random prompt tokens per sequence;
+    /// every size is run --bench-runs times, after an untimed run
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "1,8,32",
+        help_heading = "Bench"
+    )]
+    pub batch_sizes: Vec<usize>,
+
+    /// Prompt tokens of each sequence, all prefilled in one step
+    #[arg(long, default_value_t = 512, help_heading = "Bench")]
+    pub prompt_len: usize,
+
+    /// Tokens generated by each sequence
+    #[arg(long, default_value_t = 128, help_heading = "Bench")]
+    pub gen_len: usize,
+
+    /// Instead of the batch sizes, add this many requests with random prompts at once,
+    /// and run them through the scheduler as when serving, timing every step;
+    /// with a small --gpu-blocks, the steps include preemptions (and KV cache swaps)
+    #[arg(long, help_heading = "Bench")]
+    pub requests: Option<usize>,
+
+    /// Print JSON instead of text
+    #[arg(long, default_value_t = false, help_heading = "Bench")]
+    pub json: bool,
+}
+
+/// Exit code for errors caused by the arguments (like the server's).
+pub const EXIT_USER_ERROR: i32 = 10;
+/// Exit code for other errors.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Print the error to stderr and exit, with EXIT_USER_ERROR or EXIT_FAILURE.
+pub fn exit_on_error(r: Result<()>) {
+    if let Err(e) = r {
+        eprintln!("error: {e:#}");
+        let user_error =
+            UserError::is_self(&e) || e.downcast_ref::<crate::AddRequestError>().is_some();
+        std::process::exit(if user_error {
+            EXIT_USER_ERROR
+        } else {
+            EXIT_FAILURE
+        });
+    }
+}
+
+/// Generate a continuation of the prompt, printing the text as the engine decodes it
+/// (see SeqOutput::new_text).
+pub fn generate<ME: ModelExec>(
+    args: &GenerateArgs,
+    loader_args: LoaderArgs,
+    model_args: ME::ModelLoaderArgs,
+) -> Result<()> {
+    let mut sampling_params = SamplingParams::default();
+    sampling_params.max_tokens = args.max_tokens;
+    sampling_params.temperature = if args.greedy { 0.0 } else { args.temperature };
+    sampling_params.top_p = args.top_p;
+    sampling_params.seed = args.seed;
+    sampling_params.verify_args()?;
+
+    let mut engine = ME::load_rllm_engine(loader_args, model_args)?;
+    let t0 = Instant::now();
+    engine.add_request("cli".to_string(), &args.prompt, sampling_params)?;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut text = String::new();
+    let mut last: Option<RequestOutput> = None;
+    while engine.num_pending_requests() > 0 {
+        for outp in engine.step()? {
+            let new_text = &outp.seq_outputs[0].new_text;
+            if args.stream && (new_text.len() > 0 || outp.is_final) {
+                if args.json {
+                    writeln!(stdout, "{}", json!({ "text": new_text }))?;
+                } else {
+                    write!(stdout, "{new_text}")?;
+                }
+                stdout.flush()?;
+            }
+            text.push_str(new_text);
+            last = Some(outp);
+        }
+    }
+    let outp = last.ok_or_else(|| anyhow!("no output from the engine"))?;
+    let so = &outp.seq_outputs[0];
+    let elapsed = t0.elapsed();
+    // includes the prompt; compare runs with the same one
+    let tokens_per_s = outp.usage.gen_tokens as f64 / elapsed.as_secs_f64();
+    let stats = engine.stats();
+    log::info!(
+        "{} prompt + {} generated tokens in {:.2}s ({:.1} tokens/s)",
+        outp.usage.prompt_tokens,
+        outp.usage.gen_tokens,
+        elapsed.as_secs_f64(),
+        tokens_per_s
+    );
+    if stats.draft_tokens > 0 {
+        log::info!(
+            "draft tokens: {} accepted of {} proposed",
+            stats.draft_tokens_accepted,
+            stats.draft_tokens
+        );
+    }
+
+    if args.json {
+        let r = json!({
+            "text": text,
+            "tokens": so.output_tokens,
+            "finish_reason": so.finish_reason.as_ref().map(|r| r.short_name()),
+            "usage": outp.usage,
+            "seed": outp.seed,
+            "elapsed_s": elapsed.as_secs_f64(),
+            "gen_tokens_per_s": tokens_per_s,
+            "draft_tokens": stats.draft_tokens,
+            "draft_tokens_accepted": stats.draft_tokens_accepted,
+        });
+        writeln!(stdout, "{r}")?;
+    } else if args.stream {
+        writeln!(stdout)?;
+    } else {
+        writeln!(stdout, "{text}")?;
+    }
+    Ok(())
+}
+
+/// Print the tokens of the text with the bytes they stand for, as the controllers see them
+/// (TokTrie), to check that the tokenizer and the token trie agree.
+pub fn tokenize<ME: ModelExec>(args: &TokenizeArgs, mut loader_args: LoaderArgs) -> Result<()> {
+    let (tokenizer, tok_trie, _) = RllmEngine::<ME>::load_tokenizer(&mut loader_args)?;
+    let tokens = tokenizer
+        .encode(args.text.as_str(), args.special)
+        .map_err(anyhow::Error::msg)?
+        .get_ids()
+        .to_vec();
+
+    let mut stdout = std::io::stdout().lock();
+    if args.json {
+        let tokens = tokens
+            .iter()
+            .map(|&t| {
+                json!({
+                    "id": t,
+                    "bytes": tok_trie.token(t),
+                    "text": String::from_utf8_lossy(tok_trie.token(t)),
+                })
+            })
+            .collect::<Vec<_>>();
+        writeln!(stdout, "{}", json!({ "tokens": tokens }))?;
+    } else {
+        for &t in &tokens {
+            writeln!(stdout, "{t}\t{}", tok_trie.token_dbg(t))?;
+        }
+    }
+    Ok(())
+}
+
+/// Throughput of one batch size in bench(); the times are medians over the runs.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub batch_size: usize,
+    pub prompt_len: usize,
+    pub gen_len: usize,
+    pub runs: usize,
+    pub prefill_ms: f64,
+    pub decode_ms: f64,
+    pub total_ms: f64,
+    /// Prompt tokens (of all sequences) per second of prefill.
+    pub prefill_tokens_per_s: f64,
+    /// Generated tokens (of all sequences) per second of decoding.
+    pub decode_tokens_per_s: f64,
+    /// Difference between the slowest and fastest run, relative to the fastest.
+    pub spread: f64,
+}
+
+impl BenchReport {
+    pub fn new(batch: SyntheticBatch, timings: &[StepTiming]) -> Self {
+        assert!(timings.len() > 0);
+        let median = |v: &[f64]| v[v.len() / 2];
+        let totals = sorted_ms(timings, |t| t.total());
+        let prefill_ms = median(&sorted_ms(timings, |t| t.prefill));
+        let decode_ms = median(&sorted_ms(timings, |t| t.decode));
+        let num_decode_steps = timings[0].num_decode_steps;
+        let per_s = |tokens: usize, ms: f64| {
+            if ms > 0.0 {
+                tokens as f64 / ms * 1000.0
+            } else {
+                0.0
+            }
+        };
+        BenchReport {
+            batch_size: batch.batch_size,
+            prompt_len: batch.prompt_len,
+            gen_len: batch.gen_len,
+            runs: timings.len(),
+            prefill_ms,
+            decode_ms,
+            total_ms: median(&totals),
+            prefill_tokens_per_s: per_s(batch.batch_size * batch.prompt_len, prefill_ms),
+            decode_tokens_per_s: per_s(batch.batch_size * num_decode_steps, decode_ms),
+            spread: (totals[totals.len() - 1] - totals[0]) / totals[0],
+        }
+    }
+}
+
+fn sorted_ms(timings: &[StepTiming], f: impl Fn(&StepTiming) -> Duration) -> Vec<f64> {
+    let mut v = timings
+        .iter()
+        .map(|t| f(t).as_secs_f64() * 1000.0)
+        .collect::<Vec<_>>();
+    v.sort_by(|a, b| a.total_cmp(b));
+    v
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>5} {:>14.1} {:>14.1} {:>11.2} {:>11.2} {:>7.1}%",
+            self.batch_size,
+            self.prefill_tokens_per_s,
+            self.decode_tokens_per_s,
+            self.prefill_ms,
+            self.decode_ms,
+            self.spread * 100.0
+        )
+    }
+}
+
+/// Latency of the steps of bench_requests(), over all the timed runs.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepLatencyReport {
+    pub requests: usize,
+    pub prompt_len: usize,
+    pub gen_len: usize,
+    pub runs: usize,
+    /// Steps running any sequences.
+    pub steps: usize,
+    pub preemptions: usize,
+    pub median_step_ms: f64,
+    pub p90_step_ms: f64,
+    pub max_step_ms: f64,
+    /// Median over the runs of the time to finish all the requests.
+    pub total_ms: f64,
+}
+
+impl StepLatencyReport {
+    fn new(args: &BenchArgs, requests: usize, log: &StepLog, mut run_ms: Vec<f64>) -> Self {
+        assert!(!log.step_ms.is_empty() && !run_ms.is_empty());
+        let mut step_ms = log.step_ms.clone();
+        step_ms.sort_by(|a, b| a.total_cmp(b));
+        run_ms.sort_by(|a, b| a.total_cmp(b));
+        let at = |v: &[f64], frac: f64| v[((v.len() - 1) as f64 * frac).round() as usize];
+        StepLatencyReport {
+            requests,
+            prompt_len: args.prompt_len,
+            gen_len: args.gen_len,
+            runs: run_ms.len(),
+            steps: step_ms.len(),
+            preemptions: log.preemptions,
+            median_step_ms: at(&step_ms, 0.5),
+            p90_step_ms: at(&step_ms, 0.9),
+            max_step_ms: at(&step_ms, 1.0),
+            total_ms: at(&run_ms, 0.5),
+        }
+    }
+}
+
+impl std::fmt::Display for StepLatencyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>8} {:>6} {:>11} {:>10.2} {:>10.2} {:>10.2} {:>10.1}",
+            self.requests,
+            self.steps,
+            self.preemptions,
+            self.median_step_ms,
+            self.p90_step_ms,
+            self.max_step_ms,
+            self.total_ms
+        )
+    }
+}
+
+#[derive(Default)]
+struct StepLog {
+    step_ms: Vec<f64>,
+    preemptions: usize,
+}
+
+/// Records the durations of the steps running any sequences, and counts preemptions.
+struct StepLogger(Arc<Mutex<StepLog>>);
+
+impl EngineListener for StepLogger {
+    fn on_preempted(&mut self, _req: &RequestMeta, _mode: PreemptionMode) {
+        self.0.lock().unwrap().preemptions += 1;
+    }
+    fn on_step_complete(&mut self, stats: &StepStats) {
+        if stats.num_seqs > 0 {
+            let ms = stats.duration.as_secs_f64() * 1000.0;
+            self.0.lock().unwrap().step_ms.push(ms);
+        }
+    }
+}
+
+/// Add `requests` greedy requests with random prompts of --prompt-len tokens, generating
+/// --gen-len tokens each, and step the engine until they're done; `runs` times, after
+/// an untimed run.
+fn bench_requests<ME: ModelExec>(
+    engine: &mut RllmEngine<ME>,
+    args: &BenchArgs,
+    requests: usize,
+    runs: usize,
+) -> Result<StepLatencyReport> {
+    if requests == 0 || args.prompt_len == 0 || args.gen_len == 0 {
+        bail_user!("need at least one request, prompt token and generated token");
+    }
+    let log = Arc::new(Mutex::new(StepLog::default()));
+    engine.add_listener(Box::new(StepLogger(log.clone())));
+    let vocab_size = engine.tok_trie.vocab_size() as Token;
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut run_ms = Vec::new();
+    for run in 0..=runs {
+        let t0 = Instant::now();
+        for idx in 0..requests {
+            let tokens = (0..args.prompt_len)
+                .map(|_| rng.gen_range(0..vocab_size))
+                .collect();
+            let mut sampling_params = SamplingParams::default();
+            sampling_params.max_tokens = args.gen_len;
+            sampling_params.temperature = 0.0;
+            sampling_params.ignore_eos = true;
+            engine.add_request_tokens(format!("bench-{run}-{idx}"), tokens, sampling_params)?;
+        }
+        while engine.num_pending_requests() > 0 {
+            engine.step()?;
+        }
+        if run == 0 {
+            // the first run includes kernel selection and CUDA graph capture
+            *log.lock().unwrap() = StepLog::default();
+        } else {
+            run_ms.push(t0.elapsed().as_secs_f64() * 1000.0);
+        }
+    }
+    let log = log.lock().unwrap();
+    Ok(StepLatencyReport::new(args, requests, &log, run_ms))
+}
+
+/// Time prefill and decode of random prompts for each of the batch sizes
+/// (see RllmEngine::bench_step()), `runs` times each; or the steps of requests
+/// (see BenchArgs::requests).
+pub fn bench<ME: ModelExec>(
+    args: &BenchArgs,
+    runs: usize,
+    loader_args: LoaderArgs,
+    model_args: ME::ModelLoaderArgs,
+) -> Result<()> {
+    if args.batch_sizes.is_empty() || runs == 0 {
+        bail_user!("need at least one batch size and run");
+    }
+    let mut engine = ME::load_rllm_engine(loader_args, model_args)?;
+    let mut stdout = std::io::stdout().lock();
+
+    if let Some(requests) = args.requests {
+        let report = bench_requests(&mut engine, args, requests, runs)?;
+        if args.json {
+            writeln!(stdout, "{}", serde_json::to_string(&[report])?)?;
+        } else {
+            writeln!(
+                stdout,
+                "{} prompt + {} generated tokens per request; {runs} runs",
+                args.prompt_len, args.gen_len
+            )?;
+            writeln!(
+                stdout,
+                "{:>8} {:>6} {:>11} {:>10} {:>10} {:>10} {:>10}",
+                "requests", "steps", "preemptions", "step ms", "p90 ms", "max ms", "total ms"
+            )?;
+            writeln!(stdout, "{report}")?;
+        }
+        return Ok(());
+    }
+
+    if !args.json {
+        writeln!(
+            stdout,
+            "{} prompt + {} generated tokens per sequence; medians of {runs} runs",
+            args.prompt_len, args.gen_len
+        )?;
+        writeln!(
+            stdout,
+            "{:>5} {:>14} {:>14} {:>11} {:>11} {:>8}",
+            "batch", "prefill tok/s", "decode tok/s", "prefill ms", "decode ms", "spread"
+        )?;
+    }
+
+    let mut reports = Vec::new();
+    for &batch_size in &args.batch_sizes {
+        let batch = SyntheticBatch {
+            batch_size,
+            prompt_len: args.prompt_len,
+            gen_len: args.gen_len,
+        };
+        // the first run of a shape includes kernel selection and CUDA graph capture
+        engine.bench_step(batch)?;
+        let timings = (0..runs)
+            .map(|_| engine.bench_step(batch))
+            .collect::<Result<Vec<_>>>()?;
+        let report = BenchReport::new(batch, &timings);
+        if !args.json {
+            writeln!(stdout, "{report}")?;
+        }
+        reports.push(report);
+    }
+
+    if args.json {
+        writeln!(stdout, "{}", serde_json::to_string(&reports)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BenchArgs, BenchReport, StepLatencyReport, StepLog};
+    use crate::{StepTiming, SyntheticBatch};
+    use std::time::Duration;
+
+    #[test]
+    fn bench_report_uses_medians() {
+        let batch = SyntheticBatch {
+            batch_size: 8,
+            prompt_len: 500,
+            gen_len: 11,
+        };
+        let timings = [100, 120, 300]
+            .iter()
+            .map(|&ms| StepTiming {
+                prefill: Duration::from_millis(ms),
+                decode: Duration::from_millis(2 * ms),
+                num_decode_steps: 10,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let r = BenchReport::new(batch, &timings);
+        assert_eq!(
+            (r.prefill_ms, r.decode_ms, r.total_ms),
+            (120.0, 240.0, 360.0)
+        );
+        // 8 * 500 prompt tokens in 120ms, 8 * 10 decoded ones in 240ms
+        assert!((r.prefill_tokens_per_s - 33333.3).abs() < 0.1);
+        assert!((r.decode_tokens_per_s - 333.3).abs() < 0.1);
+        assert_eq!(r.spread, 2.0);
+    }
+
+    #[test]
+    fn step_latency_report_uses_percentiles() {
+        let args = BenchArgs {
+            batch_sizes: vec![1],
+            prompt_len: 100,
+            gen_len: 20,
+            requests: Some(8),
+            json: false,
+        };
+        let log = StepLog {
+            // steps with swaps take longer
+            step_ms: (1..=10).rev().map(|ms| ms as f64).collect(),
+            preemptions: 3,
+        };
+        let r = StepLatencyReport::new(&args, 8, &log, vec![300.0, 100.0, 200.0]);
+        assert_eq!((r.steps, r.preemptions, r.runs), (10, 3, 3));
+        assert_eq!(
+            (r.median_step_ms, r.p90_step_ms, r.max_step_ms),
+            (6.0, 9.0, 10.0)
+        );
+        assert_eq!(r.total_ms, 200.0);
+    }
+}
@@ -1,9 +1,11 @@
 // based on https://github.com/vllm-project/vllm/blob/b9fe4616f98b77b4b9458bce203aa6544cb31ef2/vllm/config.py
 
-use crate::ModelExec;
+use crate::{seq::Token, ModelExec, SamplerHook};
+use aici_abi::svob::SimpleVob;
 use aicirt::{bail_user, valid_module_or_tag};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
 
 #[derive(Debug)]
 pub struct RllmConfig<ME: ModelExec> {
@@ -47,6 +49,75 @@ pub struct SchedulerConfig {
     pub max_num_seqs: usize,
     /// Maximum length of a sequence (including prompt and generated text).
     pub max_model_len: usize,
+    /// Queued sequence groups get their priority bumped by one for every this many
+    /// milliseconds they have been waiting (to avoid starvation). 0 disables this.
+    pub priority_boost_ms: u64,
+    /// Maximum number of prompt tokens to compute KV for a single sequence in one iteration.
+    /// Longer prompts are prefilled in chunks over several iterations.
+    pub max_prefill_tokens: usize,
+    /// Maximum number of prompt tokens (of all sequences) to compute KV for in one iteration.
+    /// The running sequences get their tokens first; prompts share the rest of
+    /// max_num_batched_tokens, up to this limit, so a long prompt is prefilled in chunks
+    /// while the others keep generating.
+    pub max_prefill_tokens_per_step: usize,
+    /// What to do with the KV cache of sequence groups preempted for lack of space.
+    pub preemption_mode: PreemptionMode,
+    /// With PreemptionMode::Auto, sequence groups up to this many tokens are recomputed,
+    /// longer ones are swapped out.
+    pub recompute_max_len: usize,
+    /// Fraction of GPU blocks that must remain free after admitting a prompt.
+    pub gpu_watermark: f32,
+    /// A prompt is only admitted when enough GPU blocks stay free for every running
+    /// sequence to generate this many more tokens (fewer when max_tokens or the
+    /// controller's hint say so); this avoids admitting prompts just to preempt them.
+    pub admission_lookahead: usize,
+    /// Report a livelock after this many consecutive steps without a sampled token
+    /// (while there are unfinished requests).
+    pub livelock_steps: usize,
+    /// New requests fail with AddRequestError::QueueFull while this many sequence groups
+    /// are waiting to be scheduled (including preempted ones). 0 means no limit.
+    pub max_waiting_groups: usize,
+    /// Like max_waiting_groups, for the sum of the prompt lengths of the waiting groups;
+    /// a prompt is accepted when nothing is waiting, however long it is. 0 means no limit.
+    pub max_queued_tokens: usize,
+}
+
+/// Preemption modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PreemptionMode {
+    /// Swap out the blocks of the preempted sequences to CPU memory
+    /// and swap them back in when the sequences are resumed.
+    Swap,
+
+    /// Discard the blocks of the preempted sequences and
+    /// recompute them when the sequences are resumed, treating the sequences as
+    /// new prompts (including the tokens generated so far).
+    /// Groups with more than one running sequence can't be recomputed, and are swapped.
+    Recompute,
+
+    /// Recompute short sequences, and swap long ones (see recompute_max_len).
+    Auto,
+}
+
+/// How the model weights are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum WeightFormat {
+    /// GGUF if the model file ends in .gguf, safetensors otherwise.
+    #[default]
+    Auto,
+    /// model.safetensors, or the files listed in model.safetensors.index.json.
+    Safetensors,
+    /// GGUF import (dequantized): a single .gguf file (see LoaderArgs::file), with the
+    /// config and tokenizer in its metadata. Quantized weights are dequantized on load,
+    /// to the type of the model, so they take as much memory as unquantized ones.
+    Gguf,
+}
+
+impl SchedulerConfig {
+    pub fn is_chunked_prefill(&self) -> bool {
+        std::cmp::min(self.max_prefill_tokens, self.max_prefill_tokens_per_step)
+            < self.max_model_len
+    }
 }
 
 pub const SAMPLING_EPS: f32 = 1e-5;
@@ -58,6 +129,19 @@ pub enum EarlyStopping {
     Never,
 }
 
+/// What happens when a sequence grows past SchedulerConfig::max_model_len.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Truncation {
+    /// Finish the sequence with FinishReason::LengthError.
+    #[default]
+    Error,
+    /// Drop tokens from the model's context, keeping the first `keep_first` tokens
+    /// (e.g., the instructions) and the most recent ones, and continue generating.
+    /// The output still has all the tokens; controllers are told about the dropped ones
+    /// (see aici_abi::MidProcessArg::context_truncation).
+    SlidingWindow { keep_first: usize },
+}
+
 /// Sampling parameters for text generation.
 ///
 /// Overall, we follow the sampling parameters from the OpenAI text completion
@@ -95,6 +179,17 @@ pub struct SamplingParams {
     /// Integer that controls the number of top tokens to consider. Default is -1.
     pub top_k: isize,
 
+    /// Float that drops the tokens less likely than min_p times the most likely one.
+    /// Default is 0.0 (no filtering). See SampleRow for how the filters combine.
+    #[serde(default)]
+    pub min_p: f32,
+
+    /// Float that keeps the tokens whose information content is closest to the entropy
+    /// of the distribution (locally typical sampling), up to this total probability.
+    /// Default is 1.0 (no filtering).
+    #[serde(default = "default_typical_p")]
+    pub typical_p: f32,
+
     /// Whether to use beam search instead of sampling.
     pub use_beam_search: bool,
 
@@ -107,14 +202,74 @@ pub struct SamplingParams {
     /// List of strings that stop the generation when they are generated.
     pub stop: Vec<String>,
 
+    /// Whether to include the matched stop string in the output text. Default is false.
+    pub include_stop_str_in_output: bool,
+
     /// Whether to ignore the EOS token and continue generating tokens after the EOS token is generated.
     pub ignore_eos: bool,
 
+    /// Tokens that finish the sequence like EOS, in addition to the model's EOS tokens
+    /// (see RllmEngine::eos_token_ids()); they apply also with ignore_eos.
+    #[serde(default)]
+    pub stop_token_ids: Vec<Token>,
+
     /// Maximum number of tokens to generate per output sequence.
     pub max_tokens: usize,
 
+    /// EOS is not sampled until this many tokens are generated (its logit is set
+    /// to -inf after the controller bias). A controller can still stop earlier,
+    /// or force EOS (including by allowing only EOS). Default is 0.
+    pub min_tokens: usize,
+
     /// Number of log probabilities to return per output token.
     pub logprobs: Option<i32>,
+
+    /// Scheduling priority; groups with higher priority are scheduled first
+    /// and preempted last. Default is 0.
+    pub priority: i32,
+
+    /// Finish the request with FinishReason::DeadlineExceeded when it's still unfinished
+    /// this long after arrival (including time spent waiting or swapped out).
+    pub deadline: Option<Duration>,
+
+    /// Limit on the prompt tokens plus the tokens generated by all sequences of the request;
+    /// when reached, the request is finished with FinishReason::MaxTokensReached.
+    pub max_total_tokens: Option<usize>,
+
+    /// Name of the LoRA adapter (see LoaderArgs::lora_adapters) to generate with;
+    /// None uses the base model.
+    pub lora: Option<String>,
+
+    /// Seed for sampling; sequence i of the request samples from a generator seeded
+    /// with logits::child_seed(seed, i), so the output doesn't depend on the batch.
+    /// None draws a random seed, which is reported in RequestOutput::seed.
+    pub seed: Option<u64>,
+
+    /// What to do when the sequence outgrows the model's context. Default is Truncation::Error.
+    #[serde(default)]
+    pub context_truncation: Truncation,
+
+    /// Keep the KV cache of the sequence when it finishes (with EOS, a stop string,
+    /// or max_tokens), so that RllmEngine::extend_request() can continue it without
+    /// recomputing the prompt. Requires n and best_of of 1, and no controller.
+    #[serde(default)]
+    pub retain_after_finish: bool,
+
+    /// Tokens that are never sampled (their logits are set to -inf before sampling),
+    /// e.g., to keep generation to a subset of the vocabulary. Only for requests
+    /// without a controller; controllers can use TokTrie::with_banned_tokens() instead.
+    /// EOS and stop_token_ids can't be banned.
+    #[serde(default)]
+    pub token_ban_list: Option<Arc<SimpleVob>>,
+
+    /// Called before each token of the request is picked; see SamplerHook.
+    /// Not part of snapshots (requests are restored without it).
+    #[serde(skip)]
+    pub sampler_hook: Option<Arc<dyn SamplerHook>>,
+}
+
+fn default_typical_p() -> f32 {
+    1.0
 }
 
 impl SamplingParams {
@@ -130,13 +285,27 @@ impl SamplingParams {
             temperature: 0.0,
             top_p: 1.0,
             top_k: -1,
+            min_p: 0.0,
+            typical_p: 1.0,
             use_beam_search: false,
             length_penalty: 1.0,
             early_stopping: EarlyStopping::False,
             stop: Vec::new(),
+            include_stop_str_in_output: false,
             ignore_eos: false,
+            stop_token_ids: Vec::new(),
             max_tokens: 16,
+            min_tokens: 0,
             logprobs: None,
+            priority: 0,
+            deadline: None,
+            max_total_tokens: None,
+            lora: None,
+            seed: None,
+            context_truncation: Truncation::Error,
+            retain_after_finish: false,
+            token_ban_list: None,
+            sampler_hook: None,
         };
         r.verify_args().unwrap();
         r
@@ -169,6 +338,15 @@ impl SamplingParams {
         if self.n < 1 {
             bail_user!("n must be at least 1, got {}.", self.n);
         }
+        if self.controller.is_some() && self.best_of > 1 {
+            bail_user!("best_of must be 1 with a controller; controllers can fork sequences.");
+        }
+        if self.retain_after_finish && (self.best_of > 1 || self.controller.is_some()) {
+            bail_user!("retain_after_finish requires best_of of 1 and no controller.");
+        }
+        if self.token_ban_list.is_some() && self.controller.is_some() {
+            bail_user!("token_ban_list is not supported with a controller.");
+        }
         if self.best_of < self.n {
             bail_user!(
                 "best_of must be greater than or equal to n, got n={} and best_of={}.",
@@ -203,14 +381,30 @@ impl SamplingParams {
                 self.top_k
             );
         }
+        if !(self.min_p >= 0.0 && self.min_p <= 1.0) {
+            bail_user!("min_p must be in [0, 1], got {}.", self.min_p);
+        }
+        if !(self.typical_p > 0.0 && self.typical_p <= 1.0) {
+            bail_user!("typical_p must be in (0, 1], got {}.", self.typical_p);
+        }
         if self.max_tokens < 1 {
             bail_user!("max_tokens must be at least 1, got {}.", self.max_tokens);
         }
+        if self.min_tokens > self.max_tokens {
+            bail_user!(
+                "min_tokens must be at most max_tokens ({}), got {}.",
+                self.max_tokens,
+                self.min_tokens
+            );
+        }
         if let Some(logprobs) = self.logprobs {
             if logprobs < 0 {
                 bail_user!("logprobs must be non-negative, got {}.", logprobs);
             }
         }
+        if self.stop.iter().any(|s| s.is_empty()) {
+            bail_user!("stop strings must be non-empty.");
+        }
         Ok(())
     }
 
@@ -231,6 +425,9 @@ impl SamplingParams {
             if self.top_k != -1 {
                 bail_user!("top_k must be -1 when using beam search.");
             }
+            if self.min_p > SAMPLING_EPS || self.typical_p < 1.0 - SAMPLING_EPS {
+                bail_user!("min_p must be 0 and typical_p 1 when using beam search.");
+            }
             Ok(())
         } else {
             Ok(())
@@ -267,6 +464,9 @@ impl SamplingParams {
             if self.top_k != -1 {
                 bail_user!("top_k must be -1 when using greedy sampling.");
             }
+            if self.min_p > SAMPLING_EPS || self.typical_p < 1.0 - SAMPLING_EPS {
+                bail_user!("min_p must be 0 and typical_p 1 when using greedy sampling.");
+            }
         }
         Ok(())
     }
@@ -275,10 +475,32 @@ impl SamplingParams {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AiciConfig {
     pub max_fuel: usize,
+    /// How many tokens post_sample() can reject (with resampling) in one step,
+    /// before the sequence fails.
+    pub max_post_sample_retries: usize,
+    /// Time limit of mid_process() of native controllers (see
+    /// RllmEngine::add_native_request()); they can't be interrupted, so late calls
+    /// only count towards native_max_timeout_steps.
+    pub native_max_step_ms: u64,
+    /// A native controller fails when mid_process() is late in more than this many
+    /// steps in a row.
+    pub native_max_timeout_steps: usize,
+    /// Like native_max_step_ms, for post_sample(); a late call fails the controller.
+    pub native_max_post_sample_ms: u64,
+    /// Like native_max_post_sample_ms, for init_prompt().
+    pub native_max_init_ms: u64,
 }
 
 impl Default for AiciConfig {
     fn default() -> Self {
-        Self { max_fuel: 0 }
+        Self {
+            max_fuel: 0,
+            max_post_sample_retries: 8,
+            // the defaults of aicirt for wasm controllers
+            native_max_step_ms: 50,
+            native_max_timeout_steps: 10,
+            native_max_post_sample_ms: 10,
+            native_max_init_ms: 1000,
+        }
     }
 }
@@ -0,0 +1,441 @@
+//! The controllers of the requests run either in aicirt (wasm; see iface.rs), or natively
+//! in the engine process (see RllmEngine::add_native_request()). The engine talks to both
+//! through Controllers, so the step code doesn't care where a controller runs.
+
+use crate::{config::AiciConfig, iface::AiciRtIface, HashMap};
+use aici_abi::{
+    native::{mid_process_into, BiasBuffer, NativeCtrl, BIAS_DISALLOW},
+    InitPromptArg, InitPromptResult, MidProcessArg, PostSampleArg, ProcessResultOffset, TokenId,
+};
+use aicirt::api::{
+    AiciMidOp, AiciMidProcessReq, AiciMidProcessResp, AiciPostSampleReq, AiciPostSampleResp,
+    BiasType, ModuleInstId, SequenceResult,
+};
+use anyhow::{bail, Result};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+/// Runs the controllers of (some of) the sequences.
+pub(crate) trait CtrlRuntime {
+    fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()>;
+
+    fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp>;
+
+    /// The masks of `resp` (as returned by the last finish_mid_process()), as logit biases.
+    fn biases(&self, resp: &AiciMidProcessResp) -> &[f32];
+
+    /// Must not be called while mid_process is pending.
+    fn post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp>;
+}
+
+impl CtrlRuntime for AiciRtIface {
+    fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()> {
+        AiciRtIface::start_mid_process(self, req)
+    }
+
+    fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
+        AiciRtIface::finish_mid_process(self)
+    }
+
+    fn biases(&self, resp: &AiciMidProcessResp) -> &[f32] {
+        self.bin_shm.slice_at_byte_offset::<f32>(
+            resp.first_mask_byte_offset,
+            resp.mask_num_elts * resp.num_masks,
+        )
+    }
+
+    fn post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp> {
+        AiciRtIface::post_sample(self, req)
+    }
+}
+
+/// Native controllers, by sequence id. They run in finish_mid_process(), which the engine
+/// calls after starting the model forward pass, like aicirt runs the wasm controllers
+/// in the meantime.
+/// The calls can't be interrupted, so the time limits are checked when they return, like
+/// in aicirt: init_prompt() and post_sample() over their limits fail the sequence, and so
+/// does mid_process() when late in more than AiciConfig::native_max_timeout_steps steps
+/// in a row (the late results are used, with MidProcessArg::prev_timed_out set in the next
+/// call). Forking is not supported, and a controller that panics fails its sequence.
+pub(crate) struct NativeRuntime {
+    ctrls: HashMap<ModuleInstId, NativeCtrl>,
+    // Branch::fork_arg of the last result, if any
+    fork_args: HashMap<ModuleInstId, Vec<u8>>,
+    // consecutive late mid_process() calls
+    num_timeouts: HashMap<ModuleInstId, usize>,
+    pending: Option<Vec<AiciMidOp>>,
+    biases: BiasBuffer,
+    max_step: Duration,
+    max_timeout_steps: usize,
+    max_post_sample: Duration,
+    max_init: Duration,
+}
+
+impl NativeRuntime {
+    pub fn new(vocab_size: usize, config: &AiciConfig) -> Self {
+        NativeRuntime {
+            ctrls: HashMap::default(),
+            fork_args: HashMap::default(),
+            num_timeouts: HashMap::default(),
+            pending: None,
+            biases: BiasBuffer::new(vocab_size),
+            max_step: Duration::from_millis(config.native_max_step_ms),
+            max_timeout_steps: config.native_max_timeout_steps,
+            max_post_sample: Duration::from_millis(config.native_max_post_sample_ms),
+            max_init: Duration::from_millis(config.native_max_init_ms),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ctrls.is_empty()
+    }
+
+    pub fn contains(&self, id: ModuleInstId) -> bool {
+        self.ctrls.contains_key(&id)
+    }
+
+    /// Call init_prompt() on a new controller; see insert().
+    pub fn init_prompt(
+        &self,
+        ctrl: &mut NativeCtrl,
+        prompt: Vec<TokenId>,
+    ) -> SequenceResult<InitPromptResult> {
+        let r = timed_call("init_prompt", || ctrl.init_prompt(InitPromptArg { prompt }));
+        check_limit("init_prompt", self.max_init, r)
+    }
+
+    /// The controller is dropped when the sequence is freed (or fails).
+    pub fn insert(&mut self, id: ModuleInstId, ctrl: NativeCtrl) {
+        self.ctrls.insert(id, ctrl);
+    }
+
+    fn mid_process(&mut self, op: AiciMidOp) -> SequenceResult<ProcessResultOffset> {
+        let ctrl = match self.ctrls.get_mut(&op.id) {
+            Some(ctrl) => ctrl,
+            None => {
+                return SequenceResult::from_error(format!("no native controller for {}", op.id))
+            }
+        };
+        let arg = MidProcessArg {
+            backtrack: op.backtrack,
+            tokens: op.tokens,
+            fork_group: vec![aici_abi::SeqId(op.id as u32)],
+            fork_arg: self.fork_args.remove(&op.id).unwrap_or_default(),
+            prev_timed_out: self.num_timeouts.contains_key(&op.id),
+            forced_byte_prefix: op.forced_byte_prefix,
+            byte_offset: op.byte_offset,
+            context_truncation: op.context_truncation,
+            top_logprobs: op.top_logprobs,
+        };
+        let biases = &mut self.biases;
+        let mut r = timed_call("mid_process", || {
+            mid_process_into(ctrl.as_mut(), arg, biases)
+        });
+        if r.error.is_empty() && Duration::from_micros(r.micros) > self.max_step {
+            let n = self.num_timeouts.entry(op.id).or_insert(0);
+            *n += 1;
+            if *n > self.max_timeout_steps {
+                r = SequenceResult {
+                    micros: r.micros,
+                    ..SequenceResult::from_error(format!(
+                        "native controller: mid_process() over the limit of {:?} in {} steps \
+                         in a row",
+                        self.max_step, *n
+                    ))
+                };
+            } else {
+                r.logs.push_str(&format!(
+                    "⏲ timeout [deadline: {}ms; step {}/{}]\n",
+                    self.max_step.as_millis(),
+                    *n,
+                    self.max_timeout_steps
+                ));
+            }
+        } else {
+            self.num_timeouts.remove(&op.id);
+        }
+        if r.result
+            .as_ref()
+            .map_or(false, |res| res.branches.len() > 1)
+        {
+            r = SequenceResult {
+                micros: r.micros,
+                ..SequenceResult::from_error("native controllers can't fork".to_string())
+            };
+        }
+        if r.error.len() > 0 {
+            self.ctrls.remove(&op.id);
+            self.num_timeouts.remove(&op.id);
+        }
+        match r.result.as_ref().and_then(|res| res.branches.first()) {
+            Some(b) if !b.fork_arg.is_empty() => {
+                self.fork_args.insert(op.id, b.fork_arg.clone());
+            }
+            _ => {}
+        }
+        r
+    }
+}
+
+impl CtrlRuntime for NativeRuntime {
+    fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()> {
+        assert!(self.pending.is_none());
+        for id in req.freed.iter() {
+            self.ctrls.remove(id);
+            self.fork_args.remove(id);
+            self.num_timeouts.remove(id);
+        }
+        self.pending = Some(req.ops);
+        Ok(())
+    }
+
+    fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
+        let ops = match self.pending.take() {
+            Some(ops) => ops,
+            None => bail!("mid_process was not started"),
+        };
+        self.biases.clear();
+        let seqs = ops
+            .into_iter()
+            .map(|op| (op.id, self.mid_process(op)))
+            .collect();
+        let num_elts = self.biases.num_elts();
+        Ok(AiciMidProcessResp {
+            seqs,
+            dtype: BiasType::F32.to_string(),
+            first_mask_byte_offset: 0,
+            mask_num_bytes: num_elts * std::mem::size_of::<f32>(),
+            mask_num_elts: num_elts,
+            num_masks: self.biases.num_masks(),
+        })
+    }
+
+    fn biases(&self, resp: &AiciMidProcessResp) -> &[f32] {
+        let data = self.biases.data();
+        assert!(data.len() == resp.num_masks * resp.mask_num_elts);
+        data
+    }
+
+    fn post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp> {
+        assert!(self.pending.is_none());
+        let mut seqs = HashMap::default();
+        for op in req.ops {
+            let r = match self.ctrls.get_mut(&op.id) {
+                Some(ctrl) => {
+                    let r = timed_call("post_sample", || {
+                        ctrl.post_sample(PostSampleArg { token: op.token })
+                    });
+                    check_limit("post_sample", self.max_post_sample, r)
+                }
+                None => SequenceResult::from_error(format!("no native controller for {}", op.id)),
+            };
+            if r.error.len() > 0 {
+                self.ctrls.remove(&op.id);
+                self.num_timeouts.remove(&op.id);
+            }
+            seqs.insert(op.id, r);
+        }
+        Ok(AiciPostSampleResp { seqs })
+    }
+}
+
+// a panic fails the sequence instead of the engine
+fn timed_call<T>(what: &str, f: impl FnOnce() -> T) -> SequenceResult<T> {
+    let t0 = Instant::now();
+    let r = catch_unwind(AssertUnwindSafe(f));
+    let micros = t0.elapsed().as_micros() as u64;
+    match r {
+        Ok(result) => SequenceResult {
+            result: Some(result),
+            error: String::new(),
+            controller_error: false,
+            storage: vec![],
+            logs: String::new(),
+            micros,
+        },
+        Err(e) => {
+            let msg = match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                (Some(msg), _) => msg.to_string(),
+                (_, Some(msg)) => msg.clone(),
+                _ => "unknown panic".to_string(),
+            };
+            SequenceResult {
+                micros,
+                ..SequenceResult::from_error(format!(
+                    "native controller panicked in {what}(): {msg}"
+                ))
+            }
+        }
+    }
+}
+
+// fails the sequence when the call took longer than `limit`
+fn check_limit<T>(what: &str, limit: Duration, r: SequenceResult<T>) -> SequenceResult<T> {
+    let elapsed = Duration::from_micros(r.micros);
+    if r.error.len() > 0 || elapsed <= limit {
+        return r;
+    }
+    SequenceResult {
+        micros: r.micros,
+        ..SequenceResult::from_error(format!(
+            "native controller: {what}() took {elapsed:?}, over the limit of {limit:?}"
+        ))
+    }
+}
+
+// where the masks of the last finish_mid_process() are
+enum MaskSource {
+    Aicirt,
+    Native,
+    Merged,
+}
+
+/// The controllers in aicirt (when there is one, see RllmEngine::set_aicirt()), and the
+/// native ones. When both return masks in a step, they are copied into one buffer,
+/// with the native ones after the ones from aicirt.
+pub(crate) struct Controllers {
+    aicirt: Option<AiciRtIface>,
+    native: NativeRuntime,
+    masks: MaskSource,
+    merged: Vec<f32>,
+    mid_pending: bool,
+}
+
+impl Controllers {
+    pub fn new(vocab_size: usize, config: &AiciConfig) -> Self {
+        Controllers {
+            aicirt: None,
+            native: NativeRuntime::new(vocab_size, config),
+            masks: MaskSource::Native,
+            merged: Vec::new(),
+            mid_pending: false,
+        }
+    }
+
+    pub fn set_aicirt(&mut self, aicirt: AiciRtIface) {
+        self.aicirt = Some(aicirt);
+    }
+
+    pub fn aicirt(&self) -> Option<&AiciRtIface> {
+        self.aicirt.as_ref()
+    }
+
+    pub fn native(&mut self) -> &mut NativeRuntime {
+        &mut self.native
+    }
+
+    /// Whether mid_process has to run in the step; aicirt has to be told about
+    /// freed sequences even when none of its controllers runs.
+    pub fn is_active(&self) -> bool {
+        self.aicirt.is_some() || !self.native.is_empty()
+    }
+
+    /// Whether start_mid_process() was called, but finish_mid_process() was not.
+    pub fn mid_pending(&self) -> bool {
+        self.mid_pending
+    }
+}
+
+impl CtrlRuntime for Controllers {
+    fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()> {
+        let (native_ops, ops): (Vec<_>, Vec<_>) = req
+            .ops
+            .into_iter()
+            .partition(|op| self.native.contains(op.id));
+        self.native.start_mid_process(AiciMidProcessReq {
+            ops: native_ops,
+            freed: req.freed.clone(),
+            freed_req_ids: Vec::new(),
+        })?;
+        match &mut self.aicirt {
+            Some(aicirt) => aicirt.start_mid_process(AiciMidProcessReq {
+                ops,
+                freed: req.freed,
+                freed_req_ids: req.freed_req_ids,
+            })?,
+            None => {
+                if ops.len() > 0 {
+                    bail!("controllers are not supported without aicirt");
+                }
+            }
+        }
+        self.mid_pending = true;
+        Ok(())
+    }
+
+    fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
+        self.mid_pending = false;
+        let native = self.native.finish_mid_process()?;
+        let aicirt = match &mut self.aicirt {
+            Some(aicirt) => aicirt,
+            None => {
+                self.masks = MaskSource::Native;
+                return Ok(native);
+            }
+        };
+        let mut resp = aicirt.finish_mid_process()?;
+        if native.num_masks == 0 {
+            resp.seqs.extend(native.seqs);
+            self.masks = MaskSource::Aicirt;
+            return Ok(resp);
+        }
+
+        let num_elts = resp.mask_num_elts;
+        self.merged.clear();
+        self.merged.extend_from_slice(aicirt.biases(&resp));
+        for idx in 0..native.num_masks {
+            let row = self.native.biases.mask(idx);
+            let len = std::cmp::min(row.len(), num_elts);
+            self.merged.extend_from_slice(&row[..len]);
+            self.merged
+                .resize(self.merged.len() + num_elts - len, BIAS_DISALLOW);
+        }
+        let offset = resp.num_masks;
+        for (id, r) in native.seqs {
+            let r = r.map_result(|res| ProcessResultOffset {
+                branches: res
+                    .branches
+                    .iter()
+                    .map(|b| b.map_mask(|idx| idx + offset))
+                    .collect(),
+                ..res
+            });
+            resp.seqs.insert(id, r);
+        }
+        resp.num_masks += native.num_masks;
+        self.masks = MaskSource::Merged;
+        Ok(resp)
+    }
+
+    fn biases(&self, resp: &AiciMidProcessResp) -> &[f32] {
+        match self.masks {
+            MaskSource::Aicirt => self.aicirt.as_ref().unwrap().biases(resp),
+            MaskSource::Native => self.native.biases(resp),
+            MaskSource::Merged => {
+                assert!(self.merged.len() == resp.num_masks * resp.mask_num_elts);
+                &self.merged
+            }
+        }
+    }
+
+    fn post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp> {
+        let (native_ops, ops): (Vec<_>, Vec<_>) = req
+            .ops
+            .into_iter()
+            .partition(|op| self.native.contains(op.id));
+        let mut resp = self
+            .native
+            .post_sample(AiciPostSampleReq { ops: native_ops })?;
+        if ops.len() > 0 {
+            let aicirt = match &mut self.aicirt {
+                Some(aicirt) => aicirt,
+                None => bail!("controllers are not supported without aicirt"),
+            };
+            resp.seqs
+                .extend(aicirt.post_sample(AiciPostSampleReq { ops })?.seqs);
+        }
+        Ok(resp)
+    }
+}
@@ -1,26 +1,42 @@
 use crate::{
-    config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig},
+    chat::find_bos_token,
+    config::{ParallelConfig, RllmConfig, SamplingParams, SchedulerConfig, Truncation},
+    controllers::{Controllers, CtrlRuntime as _},
+    eos::resolve_eos_tokens,
     iface::AiciRtIface,
+    metrics::{LatencyMetrics, MetricsWriter},
     seq::{
         FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, SequenceGroup, Token,
         TokenUsage,
     },
+    tokcache::TokenizeCache,
     util::get_setting,
-    AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
-    SequenceManager, TBlockSpaceManager as _,
+    AiciBias as _, CacheReport, ChatMessage, ChatTemplate, EngineListener, EngineSnapshot, HashMap,
+    HashSet, LoaderArgs, LogitsProcessor, ModelExec, PriorityStats, Repo, RequestSnapshot,
+    SampleRow, Scheduler, SchedulerOutputs, SequenceManager, StepStats, TBlockSpaceManager as _,
+    SNAPSHOT_VERSION,
 };
-use aici_abi::{toktree::TokTrie, Splice};
-use aicirt::{
-    api::{AiciMidOp, AiciMidProcessReq, ModuleInstId, SequenceResult},
-    with_timer, TimerRef, TimerSet,
+use aici_abi::{
+    native::NativeCtrl, toktree::TokTrie, Branch, InitPromptResult, PostSampleResult,
+    ProcessResultOffset, Splice, MAX_TOP_LOGPROBS,
 };
-use anyhow::{bail, Error as E, Result};
-use hf_hub::{
-    api::sync::{Api, ApiRepo},
-    RepoType,
+use aicirt::{
+    api::{
+        AiciMidOp, AiciMidProcessReq, AiciPostSampleOp, AiciPostSampleReq, AuthInfo,
+        InstantiateReq, ModuleInstId, SequenceResult,
+    },
+    bail_user, with_timer, TimerRef, TimerSet,
 };
+use anyhow::{anyhow, bail, Result};
+use rand::{rngs::StdRng, Rng as _, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, ops::Deref, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokenizers::Tokenizer;
 
 #[derive(Clone)]
@@ -42,79 +58,384 @@ pub struct AddRequest {
     pub prompt: Vec<Token>,
     pub sampling_params: SamplingParams,
     pub expected: Option<ExpectedGeneration>,
-    pub init_result: Option<SequenceResult>,
+    pub init_result: Option<SequenceResult<InitPromptResult>>,
+    /// Who started the controller (see aicirt::api::InstantiateReq); when the request is
+    /// restored from a snapshot, the controller is restarted on their behalf.
+    pub auth: Option<AuthInfo>,
 }
 
-pub enum Repo {
-    Api(ApiRepo),
-    Local(String),
+/// Reasons for rejecting a request in [`RllmEngine::queue_request`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddRequestError {
+    /// The prompt has no tokens and there is no controller to supply them.
+    EmptyPrompt,
+    /// The prompt leaves no room for generating even a single token.
+    PromptTooLong { prompt_len: usize, max: usize },
+    /// SamplingParams.max_tokens is 0.
+    ZeroMaxTokens,
+    /// Truncation::SlidingWindow keeps no room for the recent tokens.
+    KeepFirstTooLong { keep_first: usize, max: usize },
+    /// The waiting queue is at SchedulerConfig::max_waiting_groups or max_queued_tokens;
+    /// the hint is how long it takes, at the recent pace, to make room for the request.
+    QueueFull { retry_after_hint: Duration },
 }
 
-impl Repo {
-    pub fn from(args: &LoaderArgs) -> Result<Repo> {
-        match &args.local_weights {
-            Some(path) => Ok(Repo::Local(path.to_owned() + "/")),
-            None => {
-                let api = Api::new()?;
-                let model_id = args.model_id.clone();
-                let revision = args.revision.clone().unwrap_or("main".to_string());
-                let api = api.repo(hf_hub::Repo::with_revision(
-                    model_id,
-                    RepoType::Model,
-                    revision,
-                ));
-                Ok(Repo::Api(api))
-            }
+impl Display for AddRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddRequestError::EmptyPrompt => write!(f, "prompt is empty"),
+            AddRequestError::PromptTooLong { prompt_len, max } => write!(
+                f,
+                "prompt is too long ({} tokens); maximum is {} tokens",
+                prompt_len, max
+            ),
+            AddRequestError::ZeroMaxTokens => write!(f, "max_tokens must be at least 1"),
+            AddRequestError::KeepFirstTooLong { keep_first, max } => write!(
+                f,
+                "context_truncation keeps too many first tokens ({}); maximum is {}",
+                keep_first, max
+            ),
+            AddRequestError::QueueFull { retry_after_hint } => write!(
+                f,
+                "too many requests are waiting; retry in {:.1}s",
+                retry_after_hint.as_secs_f64()
+            ),
         }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn is_local(&self) -> bool {
-        match self {
-            Repo::Api(_) => false,
-            Repo::Local(_) => true,
-        }
+impl std::error::Error for AddRequestError {}
+
+/// Weight of the latest sample in the moving averages of step timings.
+const TIMING_EMA_ALPHA: f64 = 0.1;
+
+/// Bounds of AddRequestError::QueueFull::retry_after_hint; the default is used
+/// before any steps.
+const MIN_RETRY_AFTER: Duration = Duration::from_millis(50);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Seed of the random prompts of synthetic batches, so that benchmark runs are comparable.
+const SYNTHETIC_SEED: u64 = 42;
+
+/// Moving averages of what leaves the waiting queue in the steps that ran the model,
+/// for AddRequestError::QueueFull::retry_after_hint.
+#[derive(Debug, Default)]
+struct QueueThroughput {
+    num_steps: usize,
+    step_secs: f64,
+    groups: f64,
+    prompt_tokens: f64,
+}
+
+impl QueueThroughput {
+    fn observe(&mut self, stats: &StepStats) {
+        let alpha = if self.num_steps == 0 {
+            1.0
+        } else {
+            TIMING_EMA_ALPHA
+        };
+        self.num_steps += 1;
+        self.step_secs += alpha * (stats.duration.as_secs_f64() - self.step_secs);
+        self.groups += alpha * (stats.started_groups as f64 - self.groups);
+        self.prompt_tokens += alpha * (stats.started_prompt_tokens as f64 - self.prompt_tokens);
     }
 
-    pub fn get(&self, filename: &str) -> Result<PathBuf> {
-        match self {
-            Repo::Api(api) => api.get(filename).map_err(E::msg),
-            Repo::Local(path) => {
-                let p: PathBuf = (path.to_owned() + filename).into();
-                if p.exists() {
-                    Ok(p)
-                } else {
-                    bail!("file {p:?} doesn't exists")
-                }
-            }
+    /// How long it takes for this many groups and prompt tokens to leave the queue.
+    fn time_to_drain(&self, groups: usize, prompt_tokens: usize) -> Duration {
+        if self.num_steps == 0 {
+            return DEFAULT_RETRY_AFTER;
         }
+        let steps = |n: usize, per_step: f64| {
+            if n == 0 {
+                0.0
+            } else if per_step > 0.0 {
+                n as f64 / per_step
+            } else {
+                f64::INFINITY
+            }
+        };
+        let steps = steps(groups, self.groups).max(steps(prompt_tokens, self.prompt_tokens));
+        let secs = (steps * self.step_secs).min(MAX_RETRY_AFTER.as_secs_f64());
+        Duration::from_secs_f64(secs).max(MIN_RETRY_AFTER)
     }
+}
 
-    #[allow(dead_code)]
-    pub fn read(&self, filename: &str) -> Result<Vec<u8>> {
-        std::fs::read(self.get(filename)?).map_err(E::msg)
-    }
+/// The masks of the controllers in a step, one row of `num_elts` biases per mask;
+/// with speculative decoding, there can be several mid_process results in a step.
+struct MaskRows {
+    data: Vec<f32>,
+    num_masks: usize,
+    num_elts: usize,
 }
 
-impl Display for Repo {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Repo::Api(api) => write!(f, "{}", api.url("")),
-            Repo::Local(path) => write!(f, "{}", path),
+impl MaskRows {
+    /// Add the masks of another result; returns the index of the first one.
+    fn extend(&mut self, data: &[f32], num_masks: usize, num_elts: usize) -> Result<usize> {
+        if num_masks == 0 {
+            return Ok(self.num_masks);
+        }
+        if self.num_masks == 0 {
+            self.num_elts = num_elts;
+        } else if num_elts != self.num_elts {
+            bail!(
+                "masks of {num_elts} elements, after ones of {}",
+                self.num_elts
+            );
         }
+        self.data.extend_from_slice(&data[..num_masks * num_elts]);
+        self.num_masks += num_masks;
+        Ok(self.num_masks - num_masks)
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Stats {
+pub struct EngineStats {
+    /// Number of engine steps so far.
+    pub num_steps: usize,
+    /// Cumulative number of tokens generated (sampled).
+    pub generated_tokens: usize,
+    /// Cumulative number of prompt (and fast-forwarded) tokens processed.
+    pub prompt_tokens: usize,
+    pub num_running_seqs: usize,
+    pub num_waiting_seqs: usize,
+    pub num_swapped_seqs: usize,
+    /// Moving average of model forward pass time per step.
+    pub avg_model_fwd_us: f64,
+    /// Moving average of sampling time per step (including AICI bias).
+    pub avg_sample_us: f64,
+    pub num_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
     pub free_gpu_blocks: usize,
     pub free_cpu_blocks: usize,
+    pub prefix_cache_hits: usize,
+    pub prefix_cache_misses: usize,
+    /// Number of sequences finished because of NaN/inf logits.
+    pub numerical_errors: usize,
+    /// Number of requests finished because of SamplingParams.deadline.
+    pub deadline_exceeded: usize,
+    /// Number of times no token was sampled for SchedulerConfig::livelock_steps steps in a row.
+    pub livelocks: usize,
+    /// Number of sampled tokens rejected by controllers in post_sample().
+    pub post_sample_rejected: usize,
+    /// Number of sequences failed because post_sample() rejected too many tokens in a step.
+    pub post_sample_failures: usize,
+    /// Cumulative number of tokens proposed by the draft model (LoaderArgs::draft_model).
+    pub draft_tokens: usize,
+    /// Of draft_tokens, the ones the model agreed with (these count as generated).
+    pub draft_tokens_accepted: usize,
+    /// Prompts found in the tokenization cache (LoaderArgs::tokenize_cache_entries).
+    pub tokenize_cache_hits: usize,
+    pub tokenize_cache_misses: usize,
+    /// Size of the prompts and tokens in the cache.
+    pub tokenize_cache_bytes: usize,
+    /// Sequence groups in the waiting queue (see SchedulerConfig::max_waiting_groups),
+    /// and the sum of their prompt lengths.
+    pub num_waiting_groups: usize,
+    pub queued_prompt_tokens: usize,
+    /// Requests accepted into the waiting queue.
+    pub requests_admitted: usize,
+    /// Requests rejected with AddRequestError::QueueFull.
+    pub requests_rejected: usize,
+    pub priority_stats: HashMap<i32, PriorityStats>,
+    /// KV cache blocks held by the requests not being stepped.
+    pub cache: CacheReport,
 }
 
-impl Stats {
+impl EngineStats {
     pub fn same_as(&self, other: &Self) -> bool {
         self.free_gpu_blocks == other.free_gpu_blocks
             && self.free_cpu_blocks == other.free_cpu_blocks
+            && self.prefix_cache_hits == other.prefix_cache_hits
+            && self.prefix_cache_misses == other.prefix_cache_misses
+    }
+}
+
+/// A request in flight when RllmEngine::drain() was called.
+#[derive(Debug, Clone)]
+pub struct DrainedRequest {
+    pub request_id: String,
+    /// Of the first sequence; FinishReason::Interrupted when the deadline passed first.
+    pub finish_reason: FinishReason,
+    /// Tokens generated so far (in all sequences); they are in the final output.
+    pub gen_tokens: usize,
+    /// The request was interrupted while swapped out.
+    pub swapped_out: bool,
+}
+
+/// What RllmEngine::drain() did.
+#[derive(Debug, Clone)]
+pub struct DrainReport {
+    /// Outputs of the steps taken while draining, including the final ones
+    /// of the interrupted requests.
+    pub outputs: Vec<RequestOutput>,
+    /// The requests that were running, swapped out, or preempted, in order of their
+    /// final outputs.
+    pub requests: Vec<DrainedRequest>,
+    /// The requests that were still waiting to be scheduled, taken out of the engine;
+    /// they can be persisted, or queued again with RllmEngine::restore_requests().
+    pub waiting: EngineSnapshot,
+    /// Waiting requests with a native controller, which can't be in `waiting`;
+    /// they are dropped.
+    pub dropped: Vec<String>,
+}
+
+impl DrainReport {
+    pub fn num_interrupted(&self) -> usize {
+        self.requests
+            .iter()
+            .filter(|r| r.finish_reason == FinishReason::Interrupted)
+            .count()
+    }
+}
+
+impl Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "step {}: gen {} prompt {} tokens; seqs running {} waiting {} swapped {}; \
+             free blocks gpu {}/{} cpu {}/{}; model_fwd {:.0}us sample {:.0}us",
+            self.num_steps,
+            self.generated_tokens,
+            self.prompt_tokens,
+            self.num_running_seqs,
+            self.num_waiting_seqs,
+            self.num_swapped_seqs,
+            self.free_gpu_blocks,
+            self.num_gpu_blocks,
+            self.free_cpu_blocks,
+            self.num_cpu_blocks,
+            self.avg_model_fwd_us,
+            self.avg_sample_us,
+        )?;
+        if self.draft_tokens > 0 {
+            write!(
+                f,
+                "; draft tokens accepted {}/{}",
+                self.draft_tokens_accepted, self.draft_tokens
+            )?;
+        }
+        if self.tokenize_cache_hits + self.tokenize_cache_misses > 0 {
+            write!(
+                f,
+                "; tokenize cache hits {}/{}",
+                self.tokenize_cache_hits,
+                self.tokenize_cache_hits + self.tokenize_cache_misses
+            )?;
+        }
+        if self.requests_rejected > 0 {
+            write!(
+                f,
+                "; requests rejected {}/{}",
+                self.requests_rejected,
+                self.requests_rejected + self.requests_admitted
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Shape of a batch run by RllmEngine::bench_step().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntheticBatch {
+    /// Number of sequences.
+    pub batch_size: usize,
+    /// Prompt tokens of each sequence, all prefilled in the first step.
+    pub prompt_len: usize,
+    /// Tokens generated by each sequence: one after the prefill, and one per decode step.
+    pub gen_len: usize,
+}
+
+impl Display for SyntheticBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x({}+{})",
+            self.batch_size, self.prompt_len, self.gen_len
+        )
+    }
+}
+
+impl FromStr for SyntheticBatch {
+    type Err = anyhow::Error;
+
+    /// Parses "BATCH_SIZE,PROMPT_LEN,GEN_LEN".
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split(',')
+            .map(|p| p.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("invalid batch shape {s:?}: {e}"))?;
+        match parts[..] {
+            [batch_size, prompt_len, gen_len] => Ok(SyntheticBatch {
+                batch_size,
+                prompt_len,
+                gen_len,
+            }),
+            _ => bail!("invalid batch shape {s:?}; expecting BATCH_SIZE,PROMPT_LEN,GEN_LEN"),
+        }
+    }
+}
+
+/// Where the time of RllmEngine::bench_step() went.
+#[derive(Debug, Clone, Default)]
+pub struct StepTiming {
+    /// Forward pass of the prompts (a single step).
+    pub prefill: Duration,
+    /// Forward passes of all the decode steps.
+    pub decode: Duration,
+    pub num_decode_steps: usize,
+    /// Sampling in all steps (on the device, if the model supports it).
+    pub sampling: Duration,
+    /// Allocating KV cache blocks (for the prompts and every decode step) and freeing them.
+    pub cache_ops: Duration,
+}
+
+impl StepTiming {
+    pub fn forward(&self) -> Duration {
+        self.prefill + self.decode
+    }
+
+    pub fn total(&self) -> Duration {
+        self.forward() + self.sampling + self.cache_ops
+    }
+}
+
+impl Display for StepTiming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "prefill {:.2}ms; decode {:.2}ms ({:.2}ms/step); sample {:.2}ms; cache {:.2}ms",
+            ms(self.prefill),
+            ms(self.decode),
+            ms(self.decode) / std::cmp::max(self.num_decode_steps, 1) as f64,
+            ms(self.sampling),
+            ms(self.cache_ops),
+        )
+    }
+}
+
+/// Batch shapes run by RllmEngine::warmup().
+#[derive(Debug, Clone)]
+pub struct WarmupProfile {
+    /// Empty means the model's batch size buckets (see ModelExec::batch_size_buckets())
+    /// and SchedulerConfig::max_num_seqs; sizes above max_num_seqs are skipped.
+    pub batch_sizes: Vec<usize>,
+    /// Prompt lengths run with every batch size; shortened to fit the token limits
+    /// of the scheduler.
+    pub prompt_lens: Vec<usize>,
+    /// Tokens generated in each run, see SyntheticBatch::gen_len.
+    pub gen_len: usize,
+}
+
+impl Default for WarmupProfile {
+    fn default() -> Self {
+        WarmupProfile {
+            batch_sizes: Vec::new(),
+            prompt_lens: vec![16, 512],
+            gen_len: 3,
+        }
     }
 }
 
@@ -122,15 +443,52 @@ pub struct RllmEngine<ME: ModelExec> {
     pub config: Arc<RllmConfig<ME>>,
     pub tokenizer: Arc<Tokenizer>,
     pub tok_trie: Arc<TokTrie>,
+    pub chat_template: ChatTemplate,
     pub model_id: String,
     pub tmodel: ME,
     pub(crate) step_no: usize,
     req_id_cnt: usize,
     #[allow(dead_code)]
     pub alt: usize,
-    pub eos_token_id: Token,
+    /// See eos_token_ids().
+    eos_token_ids: Vec<Token>,
     pub space_token_id: Token,
     pub num_errors: usize,
+    panic_on_nan: bool,
+    num_numerical_errors: usize,
+    lora_adapters: Vec<String>,
+    tokenize_cache: TokenizeCache,
+
+    num_gen_tokens: usize,
+    num_prompt_tokens: usize,
+    /// Consecutive steps without a sampled token; see SchedulerConfig::livelock_steps.
+    steps_without_tokens: usize,
+    num_livelocks: usize,
+    num_requests_admitted: usize,
+    num_requests_rejected: usize,
+    queue_throughput: QueueThroughput,
+    /// See pause().
+    paused: bool,
+    num_post_sample_rejected: usize,
+    num_post_sample_failures: usize,
+    /// DraftModelArgs::num_tokens; 0 without a draft model.
+    num_draft_tokens: usize,
+    num_drafted: usize,
+    num_draft_accepted: usize,
+    /// For sequences with draft tokens in the current step: how many logits rows before
+    /// the last one the token after the accepted drafts is sampled from.
+    draft_logits_back: HashMap<usize, usize>,
+    /// For the same sequences, the number of accepted drafts.
+    drafts_accepted: HashMap<usize, usize>,
+    avg_model_fwd_us: f64,
+    avg_sample_us: f64,
+    latency: LatencyMetrics,
+    log_stats_steps: usize,
+    /// Filled in by step_inner(), for EngineListener::on_step_complete().
+    step_stats: StepStats,
+    /// Outputs of other requests from the steps run by classify(), returned
+    /// from the next step().
+    held_outputs: Vec<RequestOutput>,
 
     pub timers: TimerSet,
 
@@ -139,6 +497,7 @@ pub struct RllmEngine<ME: ModelExec> {
     tim_schedule: TimerRef,
     tim_aici_mid: TimerRef,
     tim_run_model: TimerRef,
+    tim_draft: TimerRef,
 
     pub(crate) tim_model_fwd: TimerRef,
     tim_sample: TimerRef,
@@ -146,7 +505,7 @@ pub struct RllmEngine<ME: ModelExec> {
     tim_aici_bias: TimerRef,
     tim_logit_sample: TimerRef,
 
-    aicirt: Option<AiciRtIface>,
+    ctrls: Controllers,
 
     scheduler: Scheduler<ME>,
     seq_mgr: Arc<ME::SequenceManager>,
@@ -168,16 +527,42 @@ impl<ME: ModelExec> RllmEngine<ME> {
         let rllm_config = RllmConfig {
             model: model_config,
             meta: model_meta,
-            parallel: ParallelConfig::single(),
+            parallel: ParallelConfig {
+                pipeline_parallel_size: args.pipeline_parallel_size,
+                tensor_parallel_size: 1,
+            },
             scheduler: SchedulerConfig {
                 max_num_batched_tokens: model_len,
                 max_num_kv_tokens: model_len * 10,
                 max_num_seqs: 100,
                 max_model_len: model_len,
+                priority_boost_ms: args.priority_boost_ms,
+                max_prefill_tokens: args.max_prefill_tokens.unwrap_or(model_len),
+                max_prefill_tokens_per_step: args.max_prefill_tokens_per_step.unwrap_or(model_len),
+                preemption_mode: args.preemption_mode,
+                recompute_max_len: args.recompute_max_len,
+                gpu_watermark: args.gpu_watermark,
+                admission_lookahead: args.admission_lookahead,
+                livelock_steps: args.livelock_steps,
+                max_waiting_groups: args.max_waiting_groups,
+                max_queued_tokens: args.max_queued_tokens,
             },
             aici,
         };
 
+        if rllm_config.scheduler.max_prefill_tokens == 0 {
+            bail!("max_prefill_tokens must be positive");
+        }
+        if rllm_config.scheduler.max_prefill_tokens_per_step == 0 {
+            bail!("max_prefill_tokens_per_step must be positive");
+        }
+        if rllm_config.parallel.pipeline_parallel_size == 0 {
+            bail!("pipeline_parallel_size must be positive");
+        }
+        if rllm_config.scheduler.livelock_steps == 0 {
+            bail!("livelock_steps must be positive");
+        }
+
         ME::verify_args(&rllm_config)?;
 
         Ok(rllm_config)
@@ -189,11 +574,35 @@ impl<ME: ModelExec> RllmEngine<ME> {
         block_space_manager: ME::BlockSpaceManager,
         rllm_config: Arc<RllmConfig<ME>>,
     ) -> Result<Self> {
-        let (tokenizer, tok_trie) = RllmEngine::<ME>::load_tokenizer(&mut args)?;
-        let eos_token_id = tok_trie.info().tok_eos;
+        let (tokenizer, tok_trie, eos_token_ids) = RllmEngine::<ME>::load_tokenizer(&mut args)?;
+        Self::build_with_tokenizer(
+            args,
+            tmodel,
+            block_space_manager,
+            rllm_config,
+            tokenizer,
+            tok_trie,
+            eos_token_ids,
+        )
+    }
+
+    /// Like build(), with the tokenizer already loaded (see load_tokenizer()).
+    pub fn build_with_tokenizer(
+        args: LoaderArgs,
+        tmodel: ME,
+        block_space_manager: ME::BlockSpaceManager,
+        rllm_config: Arc<RllmConfig<ME>>,
+        tokenizer: Tokenizer,
+        mut tok_trie: TokTrie,
+        eos_token_ids: Vec<Token>,
+    ) -> Result<Self> {
+        // biases have to cover all the logits, which can be more than the tokens
+        tok_trie.set_model_vocab_size(rllm_config.meta.vocab_size)?;
         let space_token_id = tok_trie.greedy_tokenize(b" ")[0];
         let repo = Repo::from(&args)?;
+        let chat_template = Self::load_chat_template(&args, &tokenizer, &tok_trie)?;
 
+        let ctrls = Controllers::new(tok_trie.model_vocab_size(), &rllm_config.aici);
         let scheduler = Scheduler::new(
             tmodel.sequence_manager(),
             block_space_manager,
@@ -215,21 +624,51 @@ impl<ME: ModelExec> RllmEngine<ME> {
             config: rllm_config,
             tokenizer: Arc::new(tokenizer),
             tok_trie: Arc::new(tok_trie),
+            chat_template,
             model_id,
             seq_mgr: tmodel.sequence_manager(),
             tmodel,
             step_no: 0,
             req_id_cnt: 0,
             num_errors: 0,
-            eos_token_id,
+            panic_on_nan: args.panic_on_nan,
+            num_numerical_errors: 0,
+            lora_adapters: args.lora_adapters.iter().map(|(n, _)| n.clone()).collect(),
+            tokenize_cache: TokenizeCache::new(
+                args.tokenize_cache_entries,
+                args.tokenize_cache_bytes,
+            ),
+            num_gen_tokens: 0,
+            num_prompt_tokens: 0,
+            step_stats: StepStats::default(),
+            held_outputs: Vec::new(),
+            steps_without_tokens: 0,
+            num_livelocks: 0,
+            num_requests_admitted: 0,
+            num_requests_rejected: 0,
+            queue_throughput: QueueThroughput::default(),
+            paused: false,
+            num_post_sample_rejected: 0,
+            num_post_sample_failures: 0,
+            num_draft_tokens: args.draft_model.as_ref().map_or(0, |d| d.num_tokens),
+            num_drafted: 0,
+            num_draft_accepted: 0,
+            draft_logits_back: HashMap::default(),
+            drafts_accepted: HashMap::default(),
+            avg_model_fwd_us: 0.0,
+            avg_sample_us: 0.0,
+            latency: LatencyMetrics::default(),
+            log_stats_steps: args.log_stats_steps,
+            eos_token_ids,
             space_token_id,
             alt: args.alt,
             scheduler,
-            aicirt: None,
+            ctrls,
             tim_step: timers.new_timer("step"),
             tim_schedule: timers.new_timer("step.schedule"),
             tim_aici_mid: timers.new_timer("step.aici_mid"),
             tim_run_model: timers.new_timer("step.run_model"),
+            tim_draft: timers.new_timer("step.run_model.draft"),
             tim_model_fwd: timers.new_timer("step.run_model.model_fwd"),
             tim_sample: timers.new_timer("step.run_model.sample"),
             tim_aici_bias: timers.new_timer("step.run_model.sample.aici_bias"),
@@ -238,21 +677,54 @@ impl<ME: ModelExec> RllmEngine<ME> {
         })
     }
 
-    pub fn load_tokenizer(args: &mut LoaderArgs) -> Result<(Tokenizer, TokTrie)> {
-        let byte_tokenizer = aicirt::bintokens::find_tokenizer(&args.tokenizer)?;
+    /// Also returns the EOS tokens (see resolve_eos_tokens()); the first one is the EOS
+    /// token of the trie, and all of them are special tokens in it.
+    pub fn load_tokenizer(args: &mut LoaderArgs) -> Result<(Tokenizer, TokTrie, Vec<Token>)> {
+        let mut byte_tokenizer = aicirt::bintokens::find_tokenizer(&args.tokenizer)?;
+        let hf_tokenizer = &byte_tokenizer.hf_tokenizer;
+        let eos_token_ids =
+            resolve_eos_tokens(&Repo::from(args)?, |t| hf_tokenizer.token_to_id(t))?;
+        byte_tokenizer.set_eos_tokens(&eos_token_ids)?;
         let tokens = byte_tokenizer.token_bytes();
         log::info!(
             "TokTrie building: {:?} wl={}",
             byte_tokenizer.tokrx_info(),
             tokens.len()
         );
-        let trie = TokTrie::from(&byte_tokenizer.tokrx_info(), &tokens);
+        let trie = TokTrie::from(&byte_tokenizer.tokrx_info(), &tokens)
+            .with_special_tokens(&byte_tokenizer.special);
         trie.check_against(&tokens);
-        Ok((byte_tokenizer.hf_tokenizer, trie))
+        Ok((byte_tokenizer.hf_tokenizer, trie, eos_token_ids))
+    }
+
+    /// See ChatTemplate::load().
+    pub fn load_chat_template(
+        args: &LoaderArgs,
+        tokenizer: &Tokenizer,
+        tok_trie: &TokTrie,
+    ) -> Result<ChatTemplate> {
+        let repo = Repo::from(args)?;
+        let eos_token = tokenizer
+            .id_to_token(tok_trie.info().tok_eos)
+            .unwrap_or("</s>".to_string());
+        let bos_token = find_bos_token(|t| tokenizer.token_to_id(t).is_some());
+        ChatTemplate::load(&repo, args, bos_token, &eos_token)
+    }
+
+    /// The tokens that finish a sequence (unless SamplingParams::ignore_eos);
+    /// the first one is the EOS token of the controllers' TokTrie.
+    pub fn eos_token_ids(&self) -> &[Token] {
+        &self.eos_token_ids
+    }
+
+    /// Whether generating `tok` finishes a sequence with `params`.
+    fn is_stop_token(&self, tok: Token, params: &SamplingParams) -> bool {
+        (!params.ignore_eos && self.eos_token_ids.contains(&tok))
+            || params.stop_token_ids.contains(&tok)
     }
 
     pub fn set_aicirt(&mut self, aicirt: AiciRtIface) {
-        self.aicirt = Some(aicirt);
+        self.ctrls.set_aicirt(aicirt);
     }
 
     pub fn gen_req_id(&mut self) -> String {
@@ -260,10 +732,101 @@ impl<ME: ModelExec> RllmEngine<ME> {
         format!("_{}", self.req_id_cnt)
     }
 
+    /// Call `listener` on lifecycle events of requests, and after every step().
+    pub fn add_listener(&mut self, listener: Box<dyn EngineListener + Send>) {
+        self.scheduler.listeners.add(listener);
+    }
+
     pub fn abort_request(&mut self, request_id: &str) {
         self.scheduler.abort_seq_group(request_id);
     }
 
+    /// Continue a request that finished with SamplingParams::retain_after_finish, with
+    /// `additional_prompt` appended after its tokens so far (e.g., the next turn of a chat).
+    /// The request then generates again through step(), with up to max_tokens new tokens;
+    /// the outputs only have these. While the KV cache of the request is kept, only the new
+    /// tokens are prefilled. The cache is evicted when other requests need the blocks, and
+    /// then all the tokens are recomputed. Retained requests are kept until extended or
+    /// aborted with abort_request().
+    /// Fails while the previous generation is still running (until its final output).
+    pub fn extend_request(&mut self, request_id: &str, additional_prompt: &str) -> Result<()> {
+        let tokens = self.tokenize(additional_prompt, false)?;
+        if tokens.is_empty() {
+            return Err(AddRequestError::EmptyPrompt.into());
+        }
+        self.scheduler.extend_seq_group(request_id, &tokens)
+    }
+
+    /// Fork an in-flight request into `n` new requests with the same sampling parameters;
+    /// see fork_request_with().
+    pub fn fork_request(&mut self, request_id: &str, n: usize) -> Result<Vec<String>> {
+        self.fork_request_with(request_id, vec![None; n])
+    }
+
+    /// Fork an in-flight request into new requests, one for each of `params`
+    /// (None keeps the sampling parameters of the original).
+    /// The forks start with the tokens generated so far, sharing the KV cache blocks
+    /// with the original (copy-on-write), and then continue independently through step(),
+    /// each with a fresh random number generator (seeded with SamplingParams::seed
+    /// when the new parameters have one).
+    /// Requests with a controller can't be forked this way (the controller can fork instead).
+    /// Returns the ids of the new requests.
+    pub fn fork_request_with(
+        &mut self,
+        request_id: &str,
+        params: Vec<Option<SamplingParams>>,
+    ) -> Result<Vec<String>> {
+        for p in params.iter().flatten() {
+            if p.controller.is_some() {
+                bail_user!("forks can't have a controller");
+            }
+            p.verify_args()?;
+        }
+        let req_ids: Vec<String> = params.iter().map(|_| self.gen_req_id()).collect();
+        let seq_mgr = self.seq_mgr.deref();
+        self.scheduler.fork_seq_group(request_id, |sg| {
+            if sg.has_controller() {
+                bail_user!("request {request_id} has a controller; it can't be forked");
+            }
+            Ok(req_ids
+                .iter()
+                .zip(params.into_iter())
+                .map(|(req_id, params)| {
+                    // inherited parameters would replay the stream of the original
+                    let seed = params
+                        .as_ref()
+                        .and_then(|p| p.seed)
+                        .unwrap_or_else(rand::random);
+                    let sampling_params = params.unwrap_or_else(|| sg.sampling_params.clone());
+                    let seqs = sg
+                        .seqs
+                        .iter()
+                        .filter(|seq| !seq.is_finished())
+                        .map(|seq| seq.fork_as(seq_mgr, seq_mgr.new_sequence(), seq.index))
+                        .collect();
+                    log::debug!("fork_request: {} -> {}", sg.request_id, req_id);
+                    SequenceGroup {
+                        request_id: req_id.clone(),
+                        prompt: sg.prompt.clone(),
+                        seqs,
+                        logits_processor: LogitsProcessor::new(&sampling_params, seed),
+                        sampling_params,
+                        arrival_time: Instant::now(),
+                        first_scheduled_time: None,
+                        first_token_time: None,
+                        max_index: sg.max_index,
+                        usage: TokenUsage::default(),
+                        native_ctrl: false,
+                        auth: None,
+                        ctrl_prompt: Vec::new(),
+                        restored: Vec::new(),
+                    }
+                })
+                .collect())
+        })?;
+        Ok(req_ids)
+    }
+
     pub fn num_pending_requests(&self) -> usize {
         self.scheduler.get_num_unfinished_seq_groups()
     }
@@ -276,36 +839,509 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(tokens.get_ids().to_vec())
     }
 
+    /// Like tokenize(), going through the tokenization cache, if enabled.
+    fn tokenize_prompt(&mut self, text: &str, add_special_tokens: bool) -> Result<Vec<Token>> {
+        if !self.tokenize_cache.is_enabled() {
+            return self.tokenize(text, add_special_tokens);
+        }
+        if let Some(tokens) = self.tokenize_cache.get(text, add_special_tokens) {
+            return Ok(tokens);
+        }
+        let tokens = self.tokenize(text, add_special_tokens)?;
+        self.tokenize_cache
+            .insert(text, add_special_tokens, &tokens);
+        Ok(tokens)
+    }
+
     pub fn queue_request(&mut self, req: AddRequest) -> Result<()> {
-        let mut seq = Sequence::new(self.seq_mgr.new_sequence(), &req.prompt);
+        self.queue_request_with(req, None)
+    }
+
+    // `native` is the controller from add_native_request(), already past init_prompt()
+    fn queue_request_with(&mut self, req: AddRequest, native: Option<NativeCtrl>) -> Result<()> {
+        let request_id = req.request_id.clone();
+        let instantiated = req.init_result.is_some();
+        let r = self.queue_request_inner(req, native);
+        if r.is_err() && instantiated {
+            // aicirt keeps the instance until the first mid_process() otherwise
+            self.scheduler.free_req_id(&request_id);
+        }
+        r
+    }
+
+    fn queue_request_inner(&mut self, req: AddRequest, native: Option<NativeCtrl>) -> Result<()> {
+        if req.sampling_params.controller.is_some() {
+            // the controller runs in aicirt; it has to be instantiated there
+            // (with the prompt) before the sequence reaches the scheduler
+            if self.ctrls.aicirt().is_none() {
+                bail_user!("controllers are not supported without aicirt");
+            }
+            if req.init_result.is_none() {
+                bail_user!("controller was not instantiated for {}", req.request_id);
+            }
+        }
+        if let Some(lora) = &req.sampling_params.lora {
+            if !self.lora_adapters.contains(lora) {
+                bail_user!(
+                    "unknown LoRA adapter '{}'; available: {:?}",
+                    lora,
+                    self.lora_adapters
+                );
+            }
+        }
+        let vocab_size = self.tok_trie.vocab_size() as Token;
+        if let Some(tok) = req
+            .sampling_params
+            .stop_token_ids
+            .iter()
+            .find(|t| **t >= vocab_size)
+        {
+            bail_user!("stop token {tok} is out of the vocabulary of {vocab_size}");
+        }
+        if let Some(banned) = &req.sampling_params.token_ban_list {
+            if let Some(tok) = self
+                .eos_token_ids
+                .iter()
+                .chain(&req.sampling_params.stop_token_ids)
+                .find(|t| (**t as usize) < banned.len() && banned.is_allowed(**t))
+            {
+                bail_user!(
+                    "token {} finishes the sequence; it can't be banned",
+                    self.tok_trie.token_dbg(*tok)
+                );
+            }
+        }
+
+        // needed to restart the controller, when restoring from a snapshot
+        let ctrl_prompt = if req.sampling_params.controller.is_some() || native.is_some() {
+            req.prompt.clone()
+        } else {
+            Vec::new()
+        };
+        let mut prompt_tokens = req.prompt;
+        let mut aici_logs = Vec::new();
+        let mut top_logprobs = 0;
         match req.init_result {
-            Some(r) => seq.aici_logs.push(r.clone()),
+            Some(r) => {
+                // controller can extend the prompt before the first mid_process()
+                if let Some(res) = &r.result {
+                    if let Some(tok) = res.ff_tokens.iter().find(|t| **t >= vocab_size) {
+                        bail_user!(
+                            "controller's ff_token {tok} is out of the vocabulary of {vocab_size}"
+                        );
+                    }
+                    prompt_tokens.extend_from_slice(&res.ff_tokens);
+                    top_logprobs = Self::num_top_logprobs(res);
+                }
+                aici_logs.push(r.clone_with(None));
+            }
             None => {}
         }
-        seq.expected = req.expected;
 
-        let logits_processor = LogitsProcessor::new(&req.sampling_params);
+        if req.sampling_params.max_tokens == 0 {
+            return Err(AddRequestError::ZeroMaxTokens.into());
+        }
+        // with a controller, an empty prompt is fine (see step_inner())
+        if prompt_tokens.is_empty() && req.sampling_params.controller.is_none() && native.is_none()
+        {
+            return Err(AddRequestError::EmptyPrompt.into());
+        }
+        // leave room for at least one generated token
+        let max = self.config.scheduler.max_model_len - 1;
+        if prompt_tokens.len() > max {
+            return Err(AddRequestError::PromptTooLong {
+                prompt_len: prompt_tokens.len(),
+                max,
+            }
+            .into());
+        }
+        if let Truncation::SlidingWindow { keep_first } = req.sampling_params.context_truncation {
+            // leave room for at least one recent token, and the next one
+            let max = self.config.scheduler.max_model_len - 2;
+            if keep_first > max {
+                return Err(AddRequestError::KeepFirstTooLong { keep_first, max }.into());
+            }
+        }
+        self.check_queue(prompt_tokens.len())?;
         let prompt = self
             .tokenizer
-            .decode(&req.prompt, false)
+            .decode(&prompt_tokens, false)
             .map_err(anyhow::Error::msg)?;
 
+        let mut seq = Sequence::new(self.seq_mgr.new_sequence(), &prompt_tokens);
+        seq.aici_logs = aici_logs;
+        seq.expected = req.expected;
+        seq.top_logprobs = top_logprobs;
+        let native_ctrl = native.is_some();
+        if let Some(ctrl) = native {
+            self.ctrls.native().insert(seq.seq_id.to_num(), ctrl);
+        }
+
+        let seed = req.sampling_params.seed.unwrap_or_else(rand::random);
+        let logits_processor = LogitsProcessor::new(&req.sampling_params, seed);
+
         let sg = SequenceGroup {
             request_id: req.request_id,
             prompt,
             seqs: vec![seq],
             sampling_params: req.sampling_params,
             arrival_time: Instant::now(),
+            first_scheduled_time: None,
+            first_token_time: None,
             logits_processor,
             max_index: 0,
             usage: TokenUsage::default(),
+            native_ctrl,
+            auth: req.auth,
+            ctrl_prompt,
+            restored: Vec::new(),
         };
 
         self.scheduler.add_seq_group(sg);
+        self.num_requests_admitted += 1;
+
+        Ok(())
+    }
+
+    /// Fail with AddRequestError::QueueFull when a prompt of `prompt_len` tokens doesn't
+    /// fit in the waiting queue; see SchedulerConfig::max_waiting_groups.
+    fn check_queue(&mut self, prompt_len: usize) -> Result<(), AddRequestError> {
+        match self.queue_full(prompt_len) {
+            Some(retry_after_hint) => {
+                self.num_requests_rejected += 1;
+                Err(AddRequestError::QueueFull { retry_after_hint })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The retry_after_hint for a prompt of `prompt_len` tokens, if it doesn't fit.
+    fn queue_full(&self, prompt_len: usize) -> Option<Duration> {
+        let (max_groups, max_tokens) = (
+            self.config.scheduler.max_waiting_groups,
+            self.config.scheduler.max_queued_tokens,
+        );
+        let (groups, tokens) = self.scheduler.get_waiting_queue_size();
+        // what has to leave the queue first
+        let excess_groups = if max_groups > 0 && groups >= max_groups {
+            groups + 1 - max_groups
+        } else {
+            0
+        };
+        let excess_tokens = if max_tokens > 0 && groups > 0 && tokens + prompt_len > max_tokens {
+            std::cmp::min(tokens + prompt_len - max_tokens, tokens)
+        } else {
+            0
+        };
+        if excess_groups == 0 && excess_tokens == 0 {
+            None
+        } else {
+            Some(
+                self.queue_throughput
+                    .time_to_drain(excess_groups, excess_tokens),
+            )
+        }
+    }
+
+    /// The unfinished requests (waiting, running, or swapped out), to be continued
+    /// in another engine with restore(). The KV cache is not included, and neither are
+    /// native controllers (see add_native_request()); the requests that had one are
+    /// restored with restore_native_request().
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let mut requests = Vec::new();
+        self.scheduler.for_each_sg(|sg| {
+            if sg.is_finished() {
+                return;
+            }
+            requests.push((sg.arrival_time, Self::request_snapshot(sg)));
+        });
+        requests.sort_by_key(|(arrival, _)| *arrival);
+        self.engine_snapshot(requests.into_iter().map(|(_, req)| req).collect())
+    }
+
+    fn engine_snapshot(&self, requests: Vec<RequestSnapshot>) -> EngineSnapshot {
+        EngineSnapshot {
+            version: SNAPSHOT_VERSION,
+            model_id: self.model_id.clone(),
+            requests,
+        }
+    }
+
+    fn request_snapshot(sg: &SequenceGroup) -> RequestSnapshot {
+        let seqs = sg
+            .seqs
+            .iter()
+            .filter(|seq| !seq.is_finished())
+            .map(|seq| seq.to_snapshot(sg.logits_processor.num_draws(seq.index)))
+            // not added yet, after a restore
+            .chain(sg.restored.iter().cloned())
+            .collect();
+        RequestSnapshot {
+            request_id: sg.request_id.clone(),
+            prompt: sg.prompt.clone(),
+            sampling_params: sg.sampling_params.clone(),
+            seed: sg.logits_processor.seed,
+            max_index: sg.max_index,
+            usage: sg.usage.clone(),
+            auth: sg.auth.clone(),
+            ctrl_prompt: sg.ctrl_prompt.clone(),
+            native_ctrl: sg.native_ctrl,
+            seqs,
+        }
+    }
+
+    /// Make step() a no-op, returning no outputs, until resume(). The requests keep
+    /// all their state, including the KV cache, so they continue after resume() as if
+    /// nothing happened; they can also be taken out with snapshot() in the meantime.
+    /// Requests can still be added; they wait for resume().
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Undo pause(), and start admitting new requests again after drain().
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.scheduler.set_admit_new(true);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Wind down the engine, e.g., before the machine goes away: stop admitting new
+    /// requests, and step until the ones already started finish, or `deadline` passes.
+    /// The ones left then are finished with FinishReason::Interrupted; their final
+    /// outputs have the tokens generated so far. Swapped out requests are swapped back
+    /// in as usual, and preempted ones recomputed, while there is time.
+    /// The requests that were never scheduled are taken out of the engine, as a snapshot
+    /// (see DrainReport::waiting). New requests are not admitted until resume().
+    /// Fails when the engine is paused.
+    pub fn drain(&mut self, deadline: Duration) -> Result<DrainReport> {
+        if self.paused {
+            bail!("can't drain a paused engine");
+        }
+        let end = Instant::now() + deadline;
+        self.scheduler.set_admit_new(false);
+
+        let waiting = self
+            .scheduler
+            .take_unscheduled()
+            .iter()
+            .map(Self::request_snapshot)
+            .collect();
+
+        let mut outputs = Vec::new();
+        while self.scheduler.has_unfinished_seqs() && Instant::now() < end {
+            outputs.extend(self.step()?);
+        }
+        let swapped = self.scheduler.interrupt_all();
+        if self.scheduler.has_unfinished_seqs() {
+            // the interrupted requests are dropped, with their final outputs
+            outputs.extend(self.step()?);
+        }
+        if !swapped.is_empty() {
+            log::info!("drain: {} swapped out requests interrupted", swapped.len());
+        }
+
+        let requests = outputs
+            .iter()
+            .filter(|out| out.is_final)
+            .map(|out| DrainedRequest {
+                request_id: out.request_id.clone(),
+                finish_reason: out
+                    .seq_outputs
+                    .first()
+                    .and_then(|so| so.finish_reason.clone())
+                    .unwrap_or(FinishReason::Failed),
+                gen_tokens: out.usage.gen_tokens,
+                swapped_out: swapped.contains(&out.request_id),
+            })
+            .collect();
+        Ok(DrainReport {
+            outputs,
+            requests,
+            waiting: self.engine_snapshot(waiting),
+            dropped,
+        })
+    }
+
+    /// Load the model and queue the requests from the snapshot; see restore_requests().
+    pub fn restore(
+        args: LoaderArgs,
+        model_args: ME::ModelLoaderArgs,
+        snap: EngineSnapshot,
+    ) -> Result<Self> {
+        let mut engine = ME::load_rllm_engine(args, model_args)?;
+        let skipped = engine.restore_requests(snap)?;
+        if skipped.len() > 0 {
+            log::warn!("requests not restored: {:?}", skipped);
+        }
+        Ok(engine)
+    }
+
+    /// Queue the requests from a snapshot (see snapshot()). The sequences first recompute
+    /// the KV cache for all their tokens, and then continue sampling with the same seed
+    /// and random stream, so with the same logits they generate the same tokens as they
+    /// would have without the snapshot. With several sequences (n > 1, best_of), only the
+    /// first one waits for prefill; the others are added once it's done, sharing its KV
+    /// cache for the tokens they have in common.
+    /// Controllers are restarted with their original prompt, on behalf of the same user
+    /// (this needs aicirt; see set_aicirt()), and get all the tokens since then in their
+    /// first mid_process() call, which replays their state if they are deterministic.
+    /// Requests whose controller forked are not restored, since the forks can't be
+    /// replayed; neither are ones whose controller fails to start, nor ones with a native
+    /// controller (see restore_native_request()). Returns their ids.
+    pub fn restore_requests(&mut self, snap: EngineSnapshot) -> Result<Vec<String>> {
+        if snap.model_id != self.model_id {
+            bail_user!(
+                "snapshot is for model {}, not {}",
+                snap.model_id,
+                self.model_id
+            );
+        }
+        let mut skipped = Vec::new();
+        for req in snap.requests {
+            let request_id = req.request_id.clone();
+            if let Err(e) = self.restore_request(req, None) {
+                log::warn!("{request_id} not restored: {e}");
+                skipped.push(request_id);
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Like restore_requests(), for a request from the snapshot that had a native
+    /// controller; `ctrl` is a new one, like the one the request was added with.
+    /// It's started with the original prompt, and replays the tokens since then
+    /// in its first mid_process() call.
+    pub fn restore_native_request(&mut self, req: RequestSnapshot, ctrl: NativeCtrl) -> Result<()> {
+        self.restore_request(req, Some(ctrl))
+    }
+
+    fn restore_request(
+        &mut self,
+        mut req: RequestSnapshot,
+        native: Option<NativeCtrl>,
+    ) -> Result<()> {
+        if req.seqs.is_empty() {
+            bail_user!("no unfinished sequences");
+        }
+        if req.native_ctrl != native.is_some() {
+            if req.native_ctrl {
+                bail_user!("the request had a native controller; see restore_native_request()");
+            } else {
+                bail_user!("the request had no native controller");
+            }
+        }
+        if req.sampling_params.controller.is_some() && req.seqs.len() > 1 {
+            bail_user!("the controller forked; the forks can't be replayed");
+        }
+        let mut seq = Sequence::from_snapshot(self.seq_mgr.new_sequence(), &req.seqs[0]);
+        if let Some(module_id) = &req.sampling_params.controller {
+            if let Err(e) = self.restart_controller(&req, module_id, &mut seq) {
+                self.seq_mgr.delete(seq.seq_id);
+                return Err(e);
+            }
+        }
+        if let Some(mut ctrl) = native {
+            let r = self
+                .ctrls
+                .native()
+                .init_prompt(&mut ctrl, req.ctrl_prompt.clone());
+            if r.error.len() > 0 {
+                self.seq_mgr.delete(seq.seq_id);
+                bail_user!("controller failed to restart: {}", r.error);
+            }
+            Self::replay_controller(&req, &r, &mut seq);
+            self.ctrls.native().insert(seq.seq_id.to_num(), ctrl);
+        }
+        let mut logits_processor = LogitsProcessor::new(&req.sampling_params, req.seed);
+        for s in req.seqs.iter() {
+            logits_processor.skip_draws(s.index, s.num_draws);
+        }
+        log::debug!(
+            "restored {}: {} tokens, {} sequences",
+            req.request_id,
+            seq.get_len(),
+            req.seqs.len()
+        );
+        let restored = req.seqs.split_off(1);
+        self.scheduler.add_seq_group(SequenceGroup {
+            request_id: req.request_id,
+            prompt: req.prompt,
+            seqs: vec![seq],
+            sampling_params: req.sampling_params,
+            arrival_time: Instant::now(),
+            first_scheduled_time: None,
+            first_token_time: None,
+            logits_processor,
+            max_index: req.max_index,
+            usage: req.usage,
+            native_ctrl: req.native_ctrl,
+            auth: req.auth,
+            ctrl_prompt: req.ctrl_prompt,
+            restored,
+        });
+        Ok(())
+    }
 
+    /// Start the controller again, with the prompt it was first started with, and set up
+    /// its first mid_process() call to take it from there to the tokens of `seq`.
+    fn restart_controller(
+        &self,
+        req: &RequestSnapshot,
+        module_id: &str,
+        seq: &mut Sequence,
+    ) -> Result<()> {
+        let aicirt = match self.ctrls.aicirt() {
+            Some(aicirt) => aicirt,
+            None => bail_user!("controllers are not supported without aicirt"),
+        };
+        let auth = req.auth.clone().unwrap_or_else(AuthInfo::local_user);
+        let r = futures::executor::block_on(aicirt.side_cmd.instantiate(
+            InstantiateReq {
+                req_id: req.request_id.clone(),
+                prompt: serde_json::json!(req.ctrl_prompt),
+                module_id: module_id.to_string(),
+                module_arg: serde_json::json!(req.sampling_params.controller_arg),
+            },
+            auth,
+        ))?;
+        if r.error.len() > 0 {
+            bail_user!("controller failed to restart: {}", r.error);
+        }
+        Self::replay_controller(req, &r, seq);
         Ok(())
     }
 
+    /// Set up the first mid_process() call of a restarted controller (`r` is the result
+    /// of its init_prompt()) to take it to the tokens of `seq`.
+    fn replay_controller(
+        req: &RequestSnapshot,
+        r: &SequenceResult<InitPromptResult>,
+        seq: &mut Sequence,
+    ) {
+        // the controller is at its prompt and ff_tokens; backtrack to where the sequence
+        // went another way (if at all), and continue with the tokens of the sequence
+        let mut ctrl_tokens = req.ctrl_prompt.clone();
+        if let Some(res) = &r.result {
+            ctrl_tokens.extend_from_slice(&res.ff_tokens);
+            seq.top_logprobs = Self::num_top_logprobs(res);
+        }
+        let tokens = seq.all_tokens();
+        let common = ctrl_tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        seq.mid_op = Some(AiciMidOp {
+            backtrack: (ctrl_tokens.len() - common) as u32,
+            tokens: tokens[common..].to_vec(),
+            ..seq.defl_mid_op()
+        });
+        seq.aici_logs.push(r.clone_with(None));
+    }
+
     pub fn add_expected_generation(
         &mut self,
         exp_gen: ExpectedGeneration,
@@ -321,6 +1357,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
             },
             expected: Some(exp_gen),
             init_result: None,
+            auth: None,
         })
     }
 
@@ -330,87 +1367,364 @@ impl<ME: ModelExec> RllmEngine<ME> {
         prompt: &str,
         sampling_params: SamplingParams,
     ) -> Result<()> {
-        let tokens = self.tokenize(prompt, true)?;
-        self.queue_request(AddRequest {
-            request_id,
-            prompt: tokens,
-            sampling_params,
-            expected: None,
-            init_result: None,
-        })
+        self.add_request_inner(request_id, prompt, sampling_params, true)
     }
 
-    fn aici_bias(
+    /// With `encode_special` false, the prompt is expected to contain special tokens
+    /// (like BOS) already, as in prompts rendered from a chat template.
+    fn add_request_inner(
         &mut self,
-        sched_out: &mut SchedulerOutputs,
-    ) -> Result<(ME::AiciBias, HashMap<usize, usize>)> {
-        let mut seq_id_mapping = HashMap::default();
-        let vocab_size = self.tok_trie.vocab_size();
-        if self.aicirt.is_none() {
+        request_id: String,
+        prompt: &str,
+        sampling_params: SamplingParams,
+        encode_special: bool,
+    ) -> Result<()> {
+        let tokens = self.tokenize_prompt(prompt, encode_special)?;
+        self.add_request_tokens(request_id, tokens, sampling_params)
+    }
+
+    /// Like add_request(), but when the waiting queue is full (AddRequestError::QueueFull),
+    /// `wait` is called with the hint, and the request is tried again, until it fits.
+    /// `wait` has to make room, typically by calling step() (and handling its outputs);
+    /// its errors are returned.
+    pub fn add_request_blocking(
+        &mut self,
+        request_id: String,
+        prompt: &str,
+        sampling_params: SamplingParams,
+        wait: &mut dyn FnMut(&mut Self, Duration) -> Result<()>,
+    ) -> Result<()> {
+        let tokens = self.tokenize_prompt(prompt, true)?;
+        while let Some(retry_after_hint) = self.queue_full(tokens.len()) {
+            wait(self, retry_after_hint)?;
+        }
+        self.add_request_tokens(request_id, tokens, sampling_params)
+    }
+
+    /// Like add_request(), with the prompt already tokenized (e.g., sent as token ids),
+    /// so it doesn't go through the tokenizer.
+    pub fn add_request_tokens(
+        &mut self,
+        request_id: String,
+        tokens: Vec<Token>,
+        sampling_params: SamplingParams,
+    ) -> Result<()> {
+        let vocab_size = self.tok_trie.vocab_size() as Token;
+        if let Some(tok) = tokens.iter().find(|t| **t >= vocab_size) {
+            bail_user!("prompt token {tok} is out of the vocabulary of {vocab_size}");
+        }
+        self.queue_request(AddRequest {
+            request_id,
+            prompt: tokens,
+            sampling_params,
+            expected: None,
+            init_result: None,
+            auth: None,
+        })
+    }
+
+    /// Like add_request(), but with a controller running natively, in the engine process,
+    /// instead of one in aicirt: it's called directly, without wasm or JSON in between.
+    /// Native controllers can't use the host functions of wasm ones (they get the TokTrie
+    /// with tok_trie instead), nor fork; see aici_abi::native for some built-in ones.
+    /// Calls over their time limits (see AiciConfig::native_max_step_ms) fail the sequence,
+    /// as in aicirt.
+    pub fn add_native_request(
+        &mut self,
+        request_id: String,
+        prompt: &str,
+        sampling_params: SamplingParams,
+        mut ctrl: NativeCtrl,
+    ) -> Result<()> {
+        if sampling_params.controller.is_some() {
+            bail_user!("{request_id} can't have a native controller and a wasm one");
+        }
+        if sampling_params.best_of > 1 {
+            bail_user!("best_of must be 1 with a controller");
+        }
+        if sampling_params.retain_after_finish {
+            bail_user!("retain_after_finish is not supported with a controller");
+        }
+        if sampling_params.token_ban_list.is_some() {
+            bail_user!("token_ban_list is not supported with a controller");
+        }
+        sampling_params.verify_args()?;
+        let tokens = self.tokenize_prompt(prompt, true)?;
+        // before running the controller
+        self.check_queue(tokens.len())?;
+        let r = self.ctrls.native().init_prompt(&mut ctrl, tokens.clone());
+        if r.error.len() > 0 {
+            bail_user!("controller for {request_id} failed to start: {}", r.error);
+        }
+        self.queue_request_with(
+            AddRequest {
+                request_id,
+                prompt: tokens,
+                sampling_params,
+                expected: None,
+                init_result: Some(r),
+                auth: None,
+            },
+            Some(ctrl),
+        )
+    }
+
+    /// Without a controller, a request with best_of > 1 starts with a single sequence,
+    /// which is forked once the prompt is prefilled, right before the first token is sampled.
+    /// The forks share the prompt KV blocks (copied on write), and are sampled independently.
+    fn fork_best_of(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        seq_id_mapping: &mut HashMap<usize, usize>,
+    ) {
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            // max_index > 0 means we already forked
+            if sg.sampling_params.best_of <= 1 || sg.has_controller() || sg.max_index > 0 {
+                continue;
+            }
+            let seq = sg.only_seq();
+            if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
+                continue;
+            }
+            let mut to_add = Vec::new();
+            for index in 1..sg.sampling_params.best_of {
+                let new_id = self.seq_mgr.new_sequence();
+                let copy = seq.fork_as(self.seq_mgr.deref(), new_id, index);
+                log::debug!("best_of fork: {:?} -> {:?}", seq.seq_id, copy.seq_id);
+                // the forks don't have logits of their own in this step
+                seq_id_mapping.insert(copy.seq_id.to_num(), seq.seq_id.to_num());
+                to_add.push(copy);
+            }
+            sg.max_index = to_add.len();
+            sg.seqs.extend(to_add);
+        }
+    }
+
+    /// The sequences of a restored request other than the first one (see restore_requests())
+    /// are added once it's prefilled, right before it samples its next token. They compute
+    /// the KV cache for the rest of their tokens in the next step.
+    fn fork_restored(&mut self, sched_out: &mut SchedulerOutputs) {
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            if sg.restored.is_empty() {
+                continue;
+            }
+            let seq = &sg.seqs[0];
+            if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
+                continue;
+            }
+            let restored = std::mem::take(&mut sg.restored);
+            let forks: Vec<_> = restored
+                .iter()
+                .map(|snap| {
+                    let new_id = self.seq_mgr.new_sequence();
+                    let copy = seq.restore_fork(self.seq_mgr.deref(), new_id, snap);
+                    log::debug!("restored fork: {:?} -> {:?}", seq.seq_id, copy.seq_id);
+                    copy
+                })
+                .collect();
+            sg.seqs.extend(forks);
+        }
+    }
+
+    fn aici_bias(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+    ) -> Result<(ME::AiciBias, HashMap<usize, usize>)> {
+        let mut seq_id_mapping = HashMap::default();
+        let vocab_size = self.tok_trie.model_vocab_size();
+        if !self.ctrls.mid_pending() {
             return Ok((self.tmodel.empty_bias(vocab_size), seq_id_mapping));
         }
 
-        let mid_res = self.aicirt.as_mut().unwrap().finish_mid_process()?;
+        let mid_res = self.ctrls.finish_mid_process()?;
+        let batch_size = sched_out.num_running_seqs();
+        for r in mid_res.seqs.values() {
+            self.latency
+                .controller_mid_process
+                .observe(batch_size, Duration::from_micros(r.micros));
+        }
 
+        let mut has_drafts = false;
         for sg in sched_out.next_seq_groups.iter_mut() {
-            if sg.sampling_params.controller.is_none() {
+            if !sg.has_controller() {
                 continue;
             }
             let mut to_add = Vec::new();
             for seq in sg.seqs.iter_mut() {
-                if seq.sched_phase != SchedulingPhase::Running {
+                if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
                     continue;
                 }
                 assert!(seq.has_aici);
-                match self.save_aici_log(seq, &mid_res.seqs) {
-                    Some(resp) => {
-                        if resp.branches.is_empty() {
-                            self.scheduler.finish_seq(seq, FinishReason::AiciStop);
-                            continue;
-                        }
-                        for (idx, b) in resp.branches.iter().enumerate() {
-                            if idx == 0 {
-                                seq.aici_sampling = Some(b.clone());
-                                seq.mid_op = Some(seq.defl_mid_op());
-                            } else {
-                                let new_id = self.seq_mgr.new_sequence();
-                                let mut copy =
-                                    seq.fork_as(self.seq_mgr.deref(), new_id, sg.max_index + 1);
-                                log::debug!("forked: {:?} -> {:?}", seq.seq_id, copy.seq_id);
-                                seq_id_mapping.insert(copy.seq_id.to_num(), seq.seq_id.to_num());
-                                sg.max_index += 1;
-                                copy.aici_sampling = Some(b.clone());
-                                copy.mid_op = Some(AiciMidOp {
-                                    clone_id: Some(seq.seq_id.to_num()),
-                                    clone_idx: Some(idx),
-                                    ..copy.defl_mid_op()
-                                });
-                                to_add.push(copy);
-                            }
-                        }
-                    }
-                    _ => {
-                        assert!(seq.sched_phase != SchedulingPhase::Running);
+                if seq.num_draft_tokens() > 0 {
+                    if Self::mask_only(seq, &mid_res.seqs) {
+                        has_drafts = true;
+                    } else {
+                        self.accept_drafts(&mut sg.usage, seq, 0);
                     }
                 }
+                to_add.extend(self.take_mid_result(
+                    seq,
+                    &mut sg.max_index,
+                    &mid_res.seqs,
+                    0,
+                    &mut seq_id_mapping,
+                ));
             }
             sg.seqs.extend(to_add);
         }
 
-        let shm = &self.aicirt.as_mut().unwrap().bin_shm;
-        let slice = shm.slice_at_byte_offset::<f32>(mid_res.first_mask_byte_offset,
-            mid_res.mask_num_elts * mid_res.num_masks);
+        if has_drafts {
+            let mut masks = MaskRows {
+                data: self.ctrls.biases(&mid_res).to_vec(),
+                num_masks: mid_res.num_masks,
+                num_elts: mid_res.mask_num_elts,
+            };
+            self.verify_ctrl_drafts(sched_out, &mut masks, &mut seq_id_mapping)?;
+            let bias = self
+                .tmodel
+                .new_bias(&masks.data, masks.num_masks, masks.num_elts);
+            return Ok((bias, seq_id_mapping));
+        }
+        if mid_res.num_masks == 0 {
+            return Ok((self.tmodel.empty_bias(vocab_size), seq_id_mapping));
+        }
+        let slice = self.ctrls.biases(&mid_res);
         Ok((
             self.tmodel.new_bias(slice, mid_res.num_masks, mid_res.mask_num_elts),
             seq_id_mapping,
         ))
     }
 
+    /// Take the result of mid_process for the sequence: the stop, or the branches to follow,
+    /// with the masks at `mask_offset` in the bias. The forks (for branches after the first)
+    /// are returned, to be added to the group.
+    fn take_mid_result(
+        &self,
+        seq: &mut Sequence,
+        max_index: &mut usize,
+        mid_seqs: &HashMap<ModuleInstId, SequenceResult<ProcessResultOffset>>,
+        mask_offset: usize,
+        seq_id_mapping: &mut HashMap<usize, usize>,
+    ) -> Vec<Sequence> {
+        let mut to_add = Vec::new();
+        let resp = match self.save_aici_log(seq, mid_seqs) {
+            Some(resp) => resp,
+            None => {
+                assert!(seq.sched_phase != SchedulingPhase::Running);
+                return to_add;
+            }
+        };
+        // the last ones may come with the stop
+        seq.add_captures(&resp.captures);
+        if resp.branches.is_empty() {
+            self.scheduler.finish_seq(seq, FinishReason::AiciStop);
+            return to_add;
+        }
+        if let Some(n) = resp.max_remaining_tokens {
+            seq.set_max_remaining_hint(n);
+        }
+        for (idx, b) in resp.branches.iter().enumerate() {
+            let b = b.map_mask(|m| m + mask_offset);
+            if idx == 0 {
+                if !self.check_splices(seq, &b)
+                    || !self.apply_attention_mask(seq, &b.attention_mask)
+                {
+                    break;
+                }
+                seq.aici_sampling = Some(b);
+                seq.mid_op = Some(seq.defl_mid_op());
+            } else {
+                let new_id = self.seq_mgr.new_sequence();
+                let mut copy = seq.fork_as(self.seq_mgr.deref(), new_id, *max_index + 1);
+                log::debug!("forked: {:?} -> {:?}", seq.seq_id, copy.seq_id);
+                seq_id_mapping.insert(copy.seq_id.to_num(), seq.seq_id.to_num());
+                *max_index += 1;
+                // a fork with an invalid mask is finished, but still reported
+                if self.check_splices(&mut copy, &b)
+                    && self.apply_attention_mask(&mut copy, &b.attention_mask)
+                {
+                    copy.aici_sampling = Some(b);
+                    copy.mid_op = Some(AiciMidOp {
+                        clone_id: Some(seq.seq_id.to_num()),
+                        clone_idx: Some(idx),
+                        ..copy.defl_mid_op()
+                    });
+                }
+                to_add.push(copy);
+            }
+        }
+        to_add
+    }
+
+    /// Whether the result of mid_process only gives the mask for the next token of the
+    /// sequence; then its draft tokens can be checked against it (see verify_ctrl_drafts()).
+    fn mask_only(
+        seq: &Sequence,
+        mid_seqs: &HashMap<ModuleInstId, SequenceResult<ProcessResultOffset>>,
+    ) -> bool {
+        let res = match mid_seqs.get(&seq.seq_id.to_num()) {
+            Some(r) if r.error.is_empty() => r.result.as_ref(),
+            _ => None,
+        };
+        match res.map(|res| (res, res.branches.as_slice())) {
+            Some((res, [b])) => {
+                res.suspend.is_none()
+                    && b.sample_mask.is_some()
+                    && b.splices.is_empty()
+                    && b.forced_byte_prefix.is_empty()
+                    && !b.post_sample
+                    && b.attention_mask.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Hide the tokens masked by the controller (Branch::attention_mask) from attention;
+    /// an invalid mask fails the sequence.
+    fn apply_attention_mask(&self, seq: &mut Sequence, mask: &[f32]) -> bool {
+        match seq.set_attention_mask(mask) {
+            Ok(()) => true,
+            Err(e) => {
+                self.fail_ctrl(seq, format!("invalid attention mask: {e}"));
+                false
+            }
+        }
+    }
+
+    /// Tokens of the splices of the branch have to be in the vocabulary; otherwise,
+    /// the sequence fails.
+    fn check_splices<S>(&self, seq: &mut Sequence, branch: &Branch<S>) -> bool {
+        let vocab_size = self.tok_trie.vocab_size() as Token;
+        let tokens = branch
+            .splices
+            .iter()
+            .flat_map(|s| s.when_sampled.iter().chain(&s.ff_tokens));
+        let bad = tokens.copied().find(|t| *t >= vocab_size);
+        match bad {
+            None => true,
+            Some(tok) => {
+                let msg = format!("splice token {tok} is out of the vocabulary of {vocab_size}");
+                self.fail_ctrl(seq, msg);
+                false
+            }
+        }
+    }
+
+    /// Finish the sequence with a ControllerError, also reported in its logs.
+    fn fail_ctrl(&self, seq: &mut Sequence, msg: String) {
+        log::warn!("seq {}: {msg}", seq.seq_id);
+        let mut log = SequenceResult::from_error(msg.clone());
+        log.controller_error = true;
+        seq.aici_logs.push(log);
+        self.scheduler
+            .finish_seq(seq, FinishReason::ControllerError(msg));
+    }
+
     fn check_expected(&mut self, mut logits: Vec<f32>, req_id: &str, seq: &mut Sequence) -> Token {
         let exp = seq.expected.as_ref().unwrap();
-        let idx = seq.get_len() - exp.prompt.len();
+        let idx = seq.all_tokens().len() - exp.prompt.len();
         let next_token = if idx >= exp.output.len() {
-            self.eos_token_id
+            self.eos_token_ids[0]
         } else {
             let out = &exp.output[idx];
             let mut max_err = 0.0;
@@ -482,140 +1796,870 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(self.dropped_outputs(sched_out))
     }
 
-    fn sample(&mut self, sched_out: &mut SchedulerOutputs) -> Result<Vec<RequestOutput>> {
-        let (aici_bias, seq_id_mapping) =
-            with_timer!(self.tim_aici_bias, self.aici_bias(sched_out)?);
+    /// Whether the token for seq comes from ModelExec::sample_batch().
+    fn needs_sampling(seq: &Sequence) -> bool {
+        let forced_splice = match &seq.aici_sampling {
+            Some(b) => b.sample_mask.is_none(),
+            None => false,
+        };
+        seq.sched_phase == SchedulingPhase::Running
+            && !seq.is_prefilling()
+            && !forced_splice
+            && seq.expected.is_none()
+            && seq.forced_tokens().is_none()
+    }
 
-        for sg in sched_out.next_seq_groups.iter_mut() {
-            for seq in sg.seqs.iter_mut() {
-                if seq.sched_phase != SchedulingPhase::Running {
-                    continue;
-                }
+    fn num_top_logprobs(res: &InitPromptResult) -> usize {
+        std::cmp::min(res.top_logprobs, MAX_TOP_LOGPROBS) as usize
+    }
 
-                let sidx = seq.seq_id.to_num();
-                let sidx = seq_id_mapping.get(&sidx).unwrap_or(&sidx);
-                let mut logits = self.tmodel.get_logits(*sidx);
+    /// The token fed to seq instead of a sampled one, in classify().
+    fn next_forced_token(seq: &Sequence) -> Option<Token> {
+        if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
+            return None;
+        }
+        seq.forced_tokens().map(|t| t[seq.get_gen_len()])
+    }
 
-                let mut info = "";
+    fn raw_logits(&self, seq: &Sequence, seq_id_mapping: &HashMap<usize, usize>) -> ME::Tensor {
+        let sidx = seq.seq_id.to_num();
+        // forks made in this step have the logits of their parent
+        let sidx = *seq_id_mapping.get(&sidx).unwrap_or(&sidx);
+        match self.draft_logits_back.get(&sidx) {
+            Some(&back) => self.tmodel.get_logits_at(sidx, back),
+            None => self.tmodel.get_logits(sidx),
+        }
+    }
 
-                let splice = match &seq.aici_sampling {
-                    Some(b) if b.sample_mask.is_none() => {
-                        assert!(b.splices.len() == 1);
-                        let s = &b.splices[0];
-                        assert!(s.when_sampled.is_empty());
-                        info = " force splice";
-                        s.clone()
-                    }
-                    _ => {
-                        match &seq.aici_sampling {
-                            Some(b) => {
-                                let seq_idx = b.sample_mask.unwrap();
-                                aici_bias.apply(&mut logits, seq_idx);
-                            }
-                            None => {}
-                        }
+    fn apply_bias(seq: &Sequence, logits: &mut ME::Tensor, aici_bias: &ME::AiciBias) {
+        if let Some(b) = &seq.aici_sampling {
+            aici_bias.apply(logits, b.sample_mask.unwrap());
+        }
+    }
 
-                        let next_token = if seq.expected.is_some() {
-                            let logits = ME::tensor_to_vec1(&logits);
-                            self.check_expected(logits, &sg.request_id, seq)
-                        } else {
-                            with_timer!(
-                                self.tim_logit_sample,
-                                self.tmodel.sample(&mut sg.logits_processor, &logits)?
-                            )
-                        };
+    fn biased_logits(
+        &self,
+        seq: &Sequence,
+        seq_id_mapping: &HashMap<usize, usize>,
+        aici_bias: &ME::AiciBias,
+    ) -> ME::Tensor {
+        let mut logits = self.raw_logits(seq, seq_id_mapping);
+        Self::apply_bias(seq, &mut logits, aici_bias);
+        logits
+    }
 
-                        let splices = seq
-                            .aici_sampling
-                            .as_ref()
-                            .map(|s| s.splices.clone())
-                            .unwrap_or_default();
+    /// Sample a token for each row with ModelExec::sample_batch(), after calling the
+    /// SamplerHooks of the rows (see SampleRow::call_hook()).
+    /// When a hook fails, or leaves no tokens, its sequence is finished; the ids
+    /// of these are returned.
+    fn sample_rows(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        row_seqs: &[(usize, usize)],
+        logits: &[ME::Tensor],
+        rows: &mut [SampleRow],
+    ) -> Result<(Vec<Token>, HashSet<usize>)> {
+        let mut errors = Vec::new();
+        for (r, row) in rows.iter_mut().enumerate() {
+            if let Err(e) = row.call_hook() {
+                errors.push((r, e));
+            }
+        }
+        let sampled = self.tmodel.sample_batch(logits, rows)?;
+        for (r, row) in rows.iter().enumerate() {
+            if row.hook_bias.bans(sampled[r]) && !errors.iter().any(|(e, _)| *e == r) {
+                errors.push((r, anyhow!("the sampler hook left no tokens")));
+            }
+        }
 
-                        let candidates = splices
-                            .iter()
-                            .filter(|s| s.when_sampled.contains(&next_token))
-                            .collect::<Vec<_>>();
-                        if candidates.len() > 1 {
-                            log::warn!(
-                                "sample *{}: multiple splices for token {}",
-                                seq.seq_id,
-                                self.tok_trie.token_dbg(next_token)
-                            );
-                            // TODO finish seq
-                        }
+        let mut failed = HashSet::default();
+        for (r, e) in errors {
+            let (g, s) = row_seqs[r];
+            let seq = &mut sched_out.next_seq_groups[g].seqs[s];
+            log::warn!("seq {}: {e}", seq.seq_id);
+            self.scheduler.finish_seq(seq, FinishReason::Failed);
+            failed.insert(seq.seq_id.to_num());
+        }
+        Ok((sampled, failed))
+    }
 
-                        if candidates.len() > 0 {
-                            info = " splice";
-                            log::trace!(
-                                "sample *{}: splice from {}",
-                                seq.seq_id,
-                                self.tok_trie.token_dbg(next_token)
-                            );
-                            candidates[0].clone()
+    /// Sample again, from the tokens allowed by the controller that fit `prefix`.
+    /// Returns None if there are no such tokens.
+    fn resample_with_prefix(
+        &self,
+        seq: &Sequence,
+        seq_id_mapping: &HashMap<usize, usize>,
+        aici_bias: &ME::AiciBias,
+        row: &SampleRow,
+        prefix: &[u8],
+    ) -> Option<Token> {
+        let mut allowed = self.tok_trie.alloc_token_set();
+        if !self.tok_trie.tokens_fitting_prefix(prefix, &mut allowed) {
+            return None;
+        }
+        let logits = self.biased_logits(seq, seq_id_mapping, aici_bias);
+        let mut logits = ME::tensor_to_vec1(&logits);
+        for (idx, l) in logits.iter_mut().enumerate() {
+            if idx >= allowed.len() || !allowed.is_allowed(idx as Token) {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+        if logits.iter().all(|l| *l == f32::NEG_INFINITY) {
+            return None;
+        }
+        match row.sample_host(&logits) {
+            Ok(tok) => Some(tok),
+            Err(e) => {
+                log::warn!("seq {}: {e}", seq.seq_id);
+                None
+            }
+        }
+    }
+
+    /// Pass the sampled tokens to the controllers that asked for it (see
+    /// aici_abi::AiciCtrl::post_sample()); `sampled[i]` is for the sequence at `row_seqs[i]`
+    /// (group and sequence index). Rejected tokens are resampled in place, up to
+    /// AiciConfig::max_post_sample_retries times.
+    /// Returns the ids of the sequences that get no token in this step (they are either
+    /// finished, or the controller rejected the token without resampling).
+    fn aici_post_sample(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        row_seqs: &[(usize, usize)],
+        rows: &[SampleRow],
+        sampled: &mut [Token],
+        seq_id_mapping: &HashMap<usize, usize>,
+        aici_bias: &ME::AiciBias,
+    ) -> Result<HashSet<usize>> {
+        let mut dropped = HashSet::default();
+        if !self.ctrls.is_active() {
+            return Ok(dropped);
+        }
+        let max_retries = self.config.aici.max_post_sample_retries;
+
+        let mut pending: Vec<usize> = (0..row_seqs.len())
+            .filter(|&r| {
+                let (g, s) = row_seqs[r];
+                let seq = &sched_out.next_seq_groups[g].seqs[s];
+                seq.sched_phase == SchedulingPhase::Running
+                    && seq.aici_sampling.as_ref().map_or(false, |b| b.post_sample)
+            })
+            .collect();
+        let mut rejected: HashMap<usize, Vec<Token>> = HashMap::default();
+
+        while !pending.is_empty() {
+            let ops = pending
+                .iter()
+                .map(|&r| {
+                    let (g, s) = row_seqs[r];
+                    AiciPostSampleOp {
+                        id: sched_out.next_seq_groups[g].seqs[s].seq_id.to_num(),
+                        token: sampled[r],
+                    }
+                })
+                .collect();
+            let resp = self.ctrls.post_sample(AiciPostSampleReq { ops })?;
+
+            let mut next_pending = Vec::new();
+            for r in pending {
+                let (g, s) = row_seqs[r];
+                let seq = &mut sched_out.next_seq_groups[g].seqs[s];
+                let seq_id = seq.seq_id.to_num();
+                let res = match self.save_aici_log(seq, &resp.seqs) {
+                    Some(res) => res.clone(),
+                    None => {
+                        if seq.sched_phase == SchedulingPhase::Running {
+                            self.scheduler.finish_seq(seq, FinishReason::Failed);
+                        }
+                        dropped.insert(seq_id);
+                        continue;
+                    }
+                };
+                match res {
+                    PostSampleResult::Accept => {}
+                    PostSampleResult::Replace { token } => sampled[r] = token,
+                    PostSampleResult::Reject { resample: false } => {
+                        self.num_post_sample_rejected += 1;
+                        dropped.insert(seq_id);
+                    }
+                    PostSampleResult::Reject { resample: true } => {
+                        self.num_post_sample_rejected += 1;
+                        let rej = rejected.entry(r).or_default();
+                        rej.push(sampled[r]);
+                        // resample on the CPU, like resample_with_prefix()
+                        let logits = if rej.len() > max_retries {
+                            None
                         } else {
-                            Splice {
-                                backtrack: 0,
-                                ff_tokens: vec![next_token],
-                                when_sampled: vec![],
+                            let logits = self.biased_logits(seq, seq_id_mapping, aici_bias);
+                            let mut logits = ME::tensor_to_vec1(&logits);
+                            for t in rej.iter() {
+                                logits[*t as usize] = f32::NEG_INFINITY;
+                            }
+                            Some(logits).filter(|l| l.iter().any(|l| *l != f32::NEG_INFINITY))
+                        };
+                        match logits.map(|l| rows[r].sample_host(&l)) {
+                            Some(Ok(tok)) => {
+                                sampled[r] = tok;
+                                next_pending.push(r);
+                            }
+                            Some(Err(e)) => {
+                                log::warn!("seq {seq_id}: {e}");
+                                self.scheduler.finish_seq(seq, FinishReason::Failed);
+                                dropped.insert(seq_id);
+                            }
+                            None => {
+                                log::warn!(
+                                    "seq {seq_id}: post_sample() rejected {} tokens",
+                                    rej.len()
+                                );
+                                self.num_post_sample_failures += 1;
+                                self.scheduler.finish_seq(seq, FinishReason::Failed);
+                                dropped.insert(seq_id);
                             }
                         }
                     }
-                };
+                }
+            }
+            pending = next_pending;
+        }
 
-                log::trace!(
-                    "sample *{}:{} {} {}",
-                    seq.seq_id,
-                    info,
-                    if splice.backtrack == 0 {
-                        "".to_string()
-                    } else {
-                        format!("backtrack:{}", splice.backtrack)
-                    },
-                    self.tok_trie.tokens_dbg(&splice.ff_tokens),
-                );
+        Ok(dropped)
+    }
 
-                seq.splice_tokens(
-                    self.seq_mgr.deref(),
-                    splice.backtrack as usize,
-                    &splice.ff_tokens,
-                );
+    /// Number of tokens the draft model can propose for the group, to be verified in this
+    /// step; 0 unless its only sequence samples greedily, with nothing but the model (and
+    /// the masks of its controller) deciding the tokens, e.g., no hook or top logprobs
+    /// for the controller. The tokens are also limited so that the sequence doesn't get
+    /// longer than it could without them.
+    fn max_draft_tokens(&self, sg: &SequenceGroup) -> usize {
+        let seq = match sg.seqs.as_slice() {
+            [seq] => seq,
+            _ => return 0,
+        };
+        let params = &sg.sampling_params;
+        if !Self::needs_sampling(seq)
+            || seq.num_query_tokens() != 1
+            || seq.top_logprobs > 0
+            || params.best_of > 1
+            || sg.logits_processor.temperature.is_some()
+            || sg.logits_processor.hook.is_some()
+            || seq.get_gen_len() < params.min_tokens
+        {
+            return 0;
+        }
+        // a token is sampled after the drafts
+        let mut n = std::cmp::min(
+            self.num_draft_tokens,
+            seq.remaining_token_bound(params.max_tokens)
+                .saturating_sub(1),
+        );
+        // the sampled token has to fit too
+        n = std::cmp::min(
+            n,
+            self.config
+                .scheduler
+                .max_model_len
+                .saturating_sub(seq.get_len() + 1),
+        );
+        if let Some(max_total) = params.max_total_tokens {
+            n = std::cmp::min(n, max_total.saturating_sub(sg.total_tokens() + 1));
+        }
+        n
+    }
 
-                let has_eos = splice.ff_tokens.contains(&self.eos_token_id);
+    /// Speculative decoding: extend the sequences that can speculate (see max_draft_tokens())
+    /// with tokens proposed by the draft model, one at a time. run() computes logits after
+    /// each of them, and verify_drafts() keeps the ones the model agrees with.
+    fn propose_drafts(&mut self, sched_out: &mut SchedulerOutputs) -> Result<()> {
+        // the draft model runs before the blocks are copied
+        if self.num_draft_tokens == 0 || !sched_out.blocks_to_copy.is_empty() {
+            return Ok(());
+        }
+        let bm = &self.scheduler.block_manager;
+        let mut free_blocks = bm
+            .get_num_free_gpu_blocks()
+            .saturating_sub(bm.get_watermark_blocks());
+        let mut spec = Vec::new();
+        for (g, sg) in sched_out.next_seq_groups.iter().enumerate() {
+            let n = self.max_draft_tokens(sg);
+            if n == 0 {
+                continue;
+            }
+            let needed = bm.num_blocks_to_grow(&sg.seqs[0], n);
+            if needed <= free_blocks {
+                free_blocks -= needed;
+                spec.push((g, n));
+            }
+        }
 
-                if seq.has_aici {
-                    seq.mid_op.as_mut().unwrap().tokens = splice.ff_tokens;
-                    seq.mid_op.as_mut().unwrap().backtrack = splice.backtrack;
+        let mut copies = SchedulerOutputs::new();
+        for i in 0..self.num_draft_tokens {
+            spec.retain(|&(_, n)| n > i);
+            if spec.is_empty() {
+                break;
+            }
+            let seqs = spec
+                .iter()
+                .map(|&(g, _)| &sched_out.next_seq_groups[g].seqs[0])
+                .collect::<Vec<_>>();
+            let tokens = self.tmodel.draft_next_tokens(&seqs)?;
+            for ((g, n), tok) in spec.iter_mut().zip(tokens) {
+                let sg = &mut sched_out.next_seq_groups[*g];
+                let seq = &mut sg.seqs[0];
+                seq.push_draft_token(tok);
+                self.scheduler.block_manager.append_slots(seq, &mut copies);
+                if self.is_stop_token(tok, &sg.sampling_params) {
+                    *n = i + 1;
                 }
+            }
+        }
+        // the scheduler already made the last block of each sequence writable
+        assert!(copies.blocks_to_copy.is_empty());
+        Ok(())
+    }
 
-                if !sg.sampling_params.ignore_eos && has_eos {
-                    self.scheduler.finish_seq(seq, FinishReason::FoundEos);
-                } else if seq.get_gen_len() >= sg.sampling_params.max_tokens {
-                    self.scheduler
-                        .finish_seq(seq, FinishReason::MaxTokensReached);
+    /// Keep the draft tokens (see propose_drafts()) that the model picks itself, given
+    /// the ones before, and drop the rest with their KV. The token after the accepted
+    /// ones is then sampled as usual, from the logits at its position (see raw_logits()),
+    /// so the sequence gets the same tokens as without the drafts, only more of them in
+    /// one step. The drafts of sequences with controllers are already checked, in
+    /// verify_ctrl_drafts().
+    fn verify_drafts(&mut self, sched_out: &mut SchedulerOutputs) -> Result<()> {
+        let mut logits = Vec::new();
+        let mut rows = Vec::new();
+        let mut spec = Vec::new();
+        for (g, sg) in sched_out.next_seq_groups.iter_mut().enumerate() {
+            for (s, seq) in sg.seqs.iter().enumerate() {
+                let n = seq.num_draft_tokens();
+                if n == 0 {
+                    continue;
+                }
+                let row = sg.logits_processor.next_row(seq, self.step_no);
+                for back in (0..=n).rev() {
+                    logits.push(self.tmodel.get_logits_at(seq.seq_id.to_num(), back));
+                    rows.push(row.clone());
                 }
+                spec.push((g, s));
             }
         }
+        if spec.is_empty() {
+            return Ok(());
+        }
+        let finite = self.tmodel.logits_finite(&logits);
+        let picked = self.tmodel.sample_batch(&logits, &rows)?;
 
-        let mut outputs = self.dropped_outputs(sched_out);
-        outputs.extend(
-            sched_out
-                .next_seq_groups
-                .iter_mut()
-                .map(|sg| self.req_output(sg, false)),
+        let mut off = 0;
+        for (g, s) in spec {
+            let sg = &mut sched_out.next_seq_groups[g];
+            let seq = &sg.seqs[s];
+            let n = seq.num_draft_tokens();
+            let start = seq.all_tokens().len() - n;
+            let mut num_ok = 0;
+            while num_ok < n
+                && finite[off + num_ok]
+                && picked[off + num_ok] == seq.all_tokens()[start + num_ok]
+                && !self.ends_sequence(seq, &sg.sampling_params, start + num_ok + 1)
+            {
+                num_ok += 1;
+            }
+            off += n + 1;
+            self.accept_drafts(&mut sg.usage, &mut sg.seqs[s], num_ok);
+        }
+        Ok(())
+    }
+
+    /// Verify the drafts of the sequences with controllers, whose mid_process result
+    /// (for the token after the ones in the KV cache) is only a mask. Like in verify_drafts(),
+    /// the model picks the token at each position, but with the mask of the controller;
+    /// an accepted draft is passed to the controller right away (as if it was sampled in
+    /// a step of its own), and the next one checked with the mask it returns. This stops
+    /// at the first rejected draft, or when the controller returns anything but a mask
+    /// (e.g., a splice, or the stop), which is then taken as usual. The token after
+    /// the accepted drafts is sampled with the last result; the masks of all the results
+    /// are in `masks` (the ones from the start of the step first).
+    fn verify_ctrl_drafts(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        masks: &mut MaskRows,
+        seq_id_mapping: &mut HashMap<usize, usize>,
+    ) -> Result<()> {
+        // the groups with drafts still to verify; they have one sequence (the forks
+        // of the first result are made after the drafts are dropped)
+        let mut spec: Vec<usize> = (0..sched_out.next_seq_groups.len())
+            .filter(|&g| {
+                let sg = &sched_out.next_seq_groups[g];
+                sg.has_controller()
+                    && sg.seqs[0].sched_phase == SchedulingPhase::Running
+                    && sg.seqs[0].num_draft_tokens() > 0
+            })
+            .collect();
+        // the number of drafts accepted so far, the same for all of them
+        let mut num_ok = 0;
+        loop {
+            let mut checked = Vec::new();
+            for g in spec {
+                let sg = &mut sched_out.next_seq_groups[g];
+                if sg.seqs[0].num_draft_tokens() == num_ok {
+                    self.accept_drafts(&mut sg.usage, &mut sg.seqs[0], num_ok);
+                } else {
+                    checked.push(g);
+                }
+            }
+            if checked.is_empty() {
+                return Ok(());
+            }
+
+            // the logits are checked before the masks are applied (they use -inf)
+            let mut logits = Vec::new();
+            let mut rows = Vec::new();
+            for &g in &checked {
+                let sg = &sched_out.next_seq_groups[g];
+                let seq = &sg.seqs[0];
+                let back = seq.num_draft_tokens() - num_ok;
+                logits.push(self.tmodel.get_logits_at(seq.seq_id.to_num(), back));
+                rows.push(sg.logits_processor.next_row(seq, self.step_no));
+            }
+            let finite = self.tmodel.logits_finite(&logits);
+            let bias = self
+                .tmodel
+                .new_bias(&masks.data, masks.num_masks, masks.num_elts);
+            for (&g, logits) in checked.iter().zip(logits.iter_mut()) {
+                Self::apply_bias(&sched_out.next_seq_groups[g].seqs[0], logits, &bias);
+            }
+            let picked = self.tmodel.sample_batch(&logits, &rows)?;
+
+            let mut ops = Vec::new();
+            let mut accepted = Vec::new();
+            for ((g, ok), tok) in checked.into_iter().zip(finite).zip(picked) {
+                let sg = &mut sched_out.next_seq_groups[g];
+                let seq = &mut sg.seqs[0];
+                let start = seq.all_tokens().len() - seq.num_draft_tokens();
+                let pos = start + num_ok;
+                if !ok
+                    || tok != seq.all_tokens()[pos]
+                    || self.ends_sequence(seq, &sg.sampling_params, pos + 1)
+                {
+                    self.accept_drafts(&mut sg.usage, seq, num_ok);
+                    continue;
+                }
+                // the byte offset is after the token, like in aici_mid()
+                let after: usize = seq.all_tokens()[pos + 1..]
+                    .iter()
+                    .map(|t| self.tok_trie.token_len(*t))
+                    .sum();
+                ops.push(AiciMidOp {
+                    tokens: vec![tok],
+                    byte_offset: (seq.get_byte_len(&self.tok_trie) - after) as u64,
+                    ..seq.defl_mid_op()
+                });
+                accepted.push(g);
+            }
+            if accepted.is_empty() {
+                return Ok(());
+            }
+            num_ok += 1;
+
+            self.ctrls.start_mid_process(AiciMidProcessReq {
+                ops,
+                freed: Vec::new(),
+                freed_req_ids: Vec::new(),
+            })?;
+            let mid_res = self.ctrls.finish_mid_process()?;
+            let offset = masks.extend(
+                self.ctrls.biases(&mid_res),
+                mid_res.num_masks,
+                mid_res.mask_num_elts,
+            )?;
+            spec = Vec::new();
+            for g in accepted {
+                let sg = &mut sched_out.next_seq_groups[g];
+                let seq = &mut sg.seqs[0];
+                if Self::mask_only(seq, &mid_res.seqs) {
+                    spec.push(g);
+                } else {
+                    self.accept_drafts(&mut sg.usage, seq, num_ok);
+                }
+                let to_add = self.take_mid_result(
+                    &mut sg.seqs[0],
+                    &mut sg.max_index,
+                    &mid_res.seqs,
+                    offset,
+                    seq_id_mapping,
+                );
+                sg.seqs.extend(to_add);
+            }
+        }
+    }
+
+    /// Whether the token at `len - 1` finishes the sequence (EOS or a stop string);
+    /// such a draft is not accepted, but sampled after the accepted ones.
+    fn ends_sequence(&self, seq: &Sequence, params: &SamplingParams, len: usize) -> bool {
+        self.is_stop_token(seq.all_tokens()[len - 1], params)
+            || seq
+                .find_stop_string(
+                    &self.tok_trie,
+                    &params.stop,
+                    len,
+                    1,
+                    params.include_stop_str_in_output,
+                )
+                .is_some()
+    }
+
+    /// Keep the first `num_ok` draft tokens of the sequence, and drop the rest with
+    /// their KV; the next token is then sampled from the logits after the kept ones.
+    fn accept_drafts(&mut self, usage: &mut TokenUsage, seq: &mut Sequence, num_ok: usize) {
+        let n = seq.num_draft_tokens();
+        seq.accept_draft_tokens(self.seq_mgr.deref(), num_ok);
+        log::trace!("seq {}: accepted {num_ok}/{n} draft tokens", seq.seq_id);
+        // as if they were generated one per step
+        usage.gen_tokens += num_ok;
+        usage.prompt_tokens += num_ok;
+        self.num_gen_tokens += num_ok;
+        self.num_prompt_tokens += num_ok;
+        self.num_drafted += n;
+        self.num_draft_accepted += num_ok;
+        let seq_id = seq.seq_id.to_num();
+        self.draft_logits_back.insert(seq_id, n - num_ok);
+        self.drafts_accepted.insert(seq_id, num_ok);
+    }
+
+    fn sample(&mut self, sched_out: &mut SchedulerOutputs) -> Result<Vec<RequestOutput>> {
+        self.draft_logits_back.clear();
+        self.drafts_accepted.clear();
+        let (aici_bias, mut seq_id_mapping) =
+            with_timer!(self.tim_aici_bias, self.aici_bias(sched_out)?);
+        self.fork_best_of(sched_out, &mut seq_id_mapping);
+        self.fork_restored(sched_out);
+        self.verify_drafts(sched_out)?;
+        let had_first_token: Vec<bool> = sched_out
+            .next_seq_groups
+            .iter()
+            .map(|sg| sg.first_token_time.is_some())
+            .collect();
+
+        // check for NaN/inf before the bias is applied (it uses -inf)
+        let mut batch_logits = Vec::new();
+        for sg in sched_out.next_seq_groups.iter() {
+            for seq in sg.seqs.iter() {
+                if Self::needs_sampling(seq) {
+                    batch_logits.push(self.raw_logits(seq, &seq_id_mapping));
+                }
+            }
+        }
+        let mut finite = self.tmodel.logits_finite(&batch_logits).into_iter();
+
+        // for the controllers that asked, the most likely tokens before the mask
+        let mut top_logits = Vec::new();
+        let mut top_ks = Vec::new();
+        let mut top_seqs = Vec::new();
+        for sg in sched_out.next_seq_groups.iter() {
+            for seq in sg.seqs.iter() {
+                if seq.top_logprobs > 0 && Self::needs_sampling(seq) {
+                    top_logits.push(self.raw_logits(seq, &seq_id_mapping));
+                    top_ks.push(seq.top_logprobs);
+                    top_seqs.push(seq.seq_id.to_num());
+                }
+            }
+        }
+        let mut top_logprobs: HashMap<usize, Vec<(Token, f32)>> = top_seqs
+            .into_iter()
+            .zip(self.tmodel.top_logprobs(&top_logits, &top_ks))
+            .collect();
+
+        // forced tokens are scored, not sampled
+        let mut forced_logits = Vec::new();
+        let mut forced_tokens = Vec::new();
+        for sg in sched_out.next_seq_groups.iter() {
+            for seq in sg.seqs.iter() {
+                if let Some(tok) = Self::next_forced_token(seq) {
+                    forced_logits.push(self.raw_logits(seq, &seq_id_mapping));
+                    forced_tokens.push(tok);
+                }
+            }
+        }
+        let mut forced_logprobs = self
+            .tmodel
+            .token_logprobs(&forced_logits, &forced_tokens)
+            .into_iter();
+
+        // sample all sequences in one go, so the model can do it on the device
+        let mut raw_logits = batch_logits.into_iter();
+        let mut batch_logits = Vec::new();
+        let mut batch_rows = Vec::new();
+        let mut row_seqs = Vec::new();
+        let mut wants_logprob = Vec::new();
+        for (g, sg) in sched_out.next_seq_groups.iter_mut().enumerate() {
+            for (s, seq) in sg.seqs.iter_mut().enumerate() {
+                if !Self::needs_sampling(seq) {
+                    continue;
+                }
+                let mut logits = raw_logits.next().unwrap();
+                if !finite.next().unwrap() {
+                    log::error!(
+                        "step {}: NaN/inf logits for seq {} in {}",
+                        self.step_no,
+                        seq.seq_id,
+                        sg.request_id
+                    );
+                    if self.panic_on_nan {
+                        panic!("NaN/inf logits (--panic-on-nan)");
+                    }
+                    self.num_numerical_errors += 1;
+                    self.scheduler.finish_seq(seq, FinishReason::NumericalError);
+                    continue;
+                }
+                Self::apply_bias(seq, &mut logits, &aici_bias);
+                batch_logits.push(logits);
+                let mut row = sg.logits_processor.next_row(seq, self.step_no);
+                if seq.get_gen_len() < sg.sampling_params.min_tokens {
+                    row.suppress_tokens = self.eos_token_ids.clone();
+                    row.suppress_tokens
+                        .extend_from_slice(&sg.sampling_params.stop_token_ids);
+                }
+                batch_rows.push(row);
+                row_seqs.push((g, s));
+                wants_logprob.push(sg.sampling_params.best_of > 1);
+            }
+        }
+        let (mut sampled, hook_failed) = with_timer!(
+            self.tim_logit_sample,
+            self.sample_rows(sched_out, &row_seqs, &batch_logits, &mut batch_rows)?
+        );
+        let mut post_dropped = self.aici_post_sample(
+            sched_out,
+            &row_seqs,
+            &batch_rows,
+            &mut sampled,
+            &seq_id_mapping,
+            &aici_bias,
+        )?;
+        post_dropped.extend(hook_failed);
+
+        let mut lp_logits = Vec::new();
+        let mut lp_tokens = Vec::new();
+        for ((logits, tok), wants) in batch_logits.into_iter().zip(&sampled).zip(wants_logprob) {
+            if wants {
+                lp_logits.push(logits);
+                lp_tokens.push(*tok);
+            }
+        }
+        let mut logprobs = self
+            .tmodel
+            .token_logprobs(&lp_logits, &lp_tokens)
+            .into_iter();
+        let mut sampled = sampled.into_iter();
+        let mut rows = batch_rows.into_iter();
+
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            for seq in sg.seqs.iter_mut() {
+                if post_dropped.contains(&seq.seq_id.to_num()) {
+                    // the controller rejected the token (or the sampler hook failed);
+                    // mid_op has no tokens
+                    sampled.next();
+                    rows.next();
+                    if sg.sampling_params.best_of > 1 {
+                        logprobs.next();
+                    }
+                    continue;
+                }
+                // no sampling until the whole prompt is prefilled
+                if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
+                    continue;
+                }
+
+                let mut info = "";
+                let mut forced_byte_prefix = vec![];
+
+                let splice = match &seq.aici_sampling {
+                    Some(b) if b.sample_mask.is_none() => {
+                        assert!(b.splices.len() == 1);
+                        let s = &b.splices[0];
+                        assert!(s.when_sampled.is_empty());
+                        info = " force splice";
+                        s.clone()
+                    }
+                    _ => {
+                        let mut prefix_splice = None;
+                        let next_token = if seq.expected.is_some() {
+                            let logits = self.biased_logits(seq, &seq_id_mapping, &aici_bias);
+                            let logits = ME::tensor_to_vec1(&logits);
+                            self.check_expected(logits, &sg.request_id, seq)
+                        } else if let Some(tok) = Self::next_forced_token(seq) {
+                            seq.cumulative_logprob += forced_logprobs.next().unwrap();
+                            tok
+                        } else {
+                            let mut tok = sampled.next().unwrap();
+                            let row = rows.next().unwrap();
+                            if sg.sampling_params.best_of > 1 {
+                                seq.cumulative_logprob += logprobs.next().unwrap();
+                            }
+                            let prefix = seq
+                                .aici_sampling
+                                .as_ref()
+                                .map(|b| b.forced_byte_prefix.clone())
+                                .unwrap_or_default();
+                            if !prefix.is_empty() && !self.tok_trie.token_fits_prefix(tok, &prefix)
+                            {
+                                match self.resample_with_prefix(
+                                    seq,
+                                    &seq_id_mapping,
+                                    &aici_bias,
+                                    &row,
+                                    &prefix,
+                                ) {
+                                    Some(t) => {
+                                        info = " resampled";
+                                        tok = t;
+                                    }
+                                    None => {
+                                        prefix_splice = Some(Splice {
+                                            when_sampled: vec![],
+                                            backtrack: 0,
+                                            ff_tokens: self.tok_trie.greedy_tokenize(&prefix),
+                                        });
+                                    }
+                                }
+                            }
+                            forced_byte_prefix = prefix;
+                            tok
+                        };
+
+                        let splices = seq
+                            .aici_sampling
+                            .as_ref()
+                            .map(|s| s.splices.clone())
+                            .unwrap_or_default();
+
+                        let candidates = splices
+                            .iter()
+                            .filter(|s| s.when_sampled.contains(&next_token))
+                            .collect::<Vec<_>>();
+                        if candidates.len() > 1 {
+                            log::warn!(
+                                "sample *{}: multiple splices for token {}",
+                                seq.seq_id,
+                                self.tok_trie.token_dbg(next_token)
+                            );
+                            // TODO finish seq
+                        }
+
+                        if let Some(s) = prefix_splice {
+                            info = " prefix splice";
+                            s
+                        } else if candidates.len() > 0 {
+                            info = " splice";
+                            log::trace!(
+                                "sample *{}: splice from {}",
+                                seq.seq_id,
+                                self.tok_trie.token_dbg(next_token)
+                            );
+                            candidates[0].clone()
+                        } else {
+                            Splice {
+                                backtrack: 0,
+                                ff_tokens: vec![next_token],
+                                when_sampled: vec![],
+                            }
+                        }
+                    }
+                };
+
+                log::trace!(
+                    "sample *{}:{} {} {}",
+                    seq.seq_id,
+                    info,
+                    if splice.backtrack == 0 {
+                        "".to_string()
+                    } else {
+                        format!("backtrack:{}", splice.backtrack)
+                    },
+                    self.tok_trie.tokens_dbg(&splice.ff_tokens),
+                );
+
+                seq.splice_tokens(
+                    self.seq_mgr.deref(),
+                    splice.backtrack as usize,
+                    &splice.ff_tokens,
+                );
+                sg.first_token_time.get_or_insert_with(Instant::now);
+
+                let has_eos = splice
+                    .ff_tokens
+                    .iter()
+                    .any(|t| self.is_stop_token(*t, &sg.sampling_params));
+                let num_new = splice.ff_tokens.len()
+                    + self.drafts_accepted.get(&seq.seq_id.to_num()).unwrap_or(&0);
+
+                if seq.has_aici {
+                    seq.mid_op.as_mut().unwrap().tokens = splice.ff_tokens;
+                    seq.mid_op.as_mut().unwrap().backtrack = splice.backtrack;
+                    seq.mid_op.as_mut().unwrap().forced_byte_prefix = forced_byte_prefix;
+                    seq.mid_op.as_mut().unwrap().top_logprobs = top_logprobs
+                        .remove(&seq.seq_id.to_num())
+                        .unwrap_or_default();
+                }
+
+                if has_eos {
+                    self.scheduler.finish_seq(seq, FinishReason::FoundEos);
+                } else if seq.check_stop_strings(
+                    &self.tok_trie,
+                    &sg.sampling_params.stop,
+                    num_new,
+                    sg.sampling_params.include_stop_str_in_output,
+                ) {
+                    self.scheduler.finish_seq(seq, FinishReason::StopString);
+                } else if seq.get_gen_len() >= sg.sampling_params.max_tokens
+                    || seq.forced_tokens().map(|t| t.len()) == Some(seq.get_gen_len())
+                {
+                    self.scheduler
+                        .finish_seq(seq, FinishReason::MaxTokensReached);
+                }
+            }
+        }
+
+        let batch_size = sched_out.num_running_seqs();
+        for (sg, had) in sched_out.next_seq_groups.iter().zip(had_first_token) {
+            if let (false, Some(t)) = (had, sg.first_token_time) {
+                self.latency
+                    .time_to_first_token
+                    .observe(batch_size, t.saturating_duration_since(sg.arrival_time));
+                self.scheduler.listeners.first_token(sg);
+            }
+        }
+
+        let mut outputs = self.dropped_outputs(sched_out);
+        outputs.extend(
+            sched_out
+                .next_seq_groups
+                .iter_mut()
+                .map(|sg| self.req_output(sg, false)),
         );
 
         Ok(outputs)
     }
 
     fn req_output(&self, sg: &mut SequenceGroup, is_final: bool) -> RequestOutput {
+        let stop = &sg.sampling_params.stop;
+        let now = Instant::now();
         RequestOutput {
             request_id: sg.request_id.clone(),
             seq_outputs: sg
                 .seqs
                 .iter_mut()
-                .map(|seq| seq.gen_output(&self.tok_trie))
+                .map(|seq| {
+                    let mut so = seq.gen_output(&self.tok_trie, stop);
+                    so.set_durations(
+                        sg.arrival_time,
+                        sg.first_scheduled_time,
+                        sg.first_token_time,
+                        seq.finish_time.unwrap_or(now),
+                    );
+                    so
+                })
                 .collect(),
             usage: sg.usage.clone(),
+            seed: sg.logits_processor.seed,
             is_final,
         }
     }
@@ -626,20 +2670,64 @@ impl<ME: ModelExec> RllmEngine<ME> {
             return Ok(self.empty_outputs(sched_out)?);
         }
 
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "run_model",
+            step_no = self.step_no,
+            batch_size = sched_out.num_running_seqs(),
+            num_tokens = sched_out.num_batched_tokens
+        )
+        .entered();
+
+        with_timer!(self.tim_draft, self.propose_drafts(sched_out)?);
+
+        let usage0 = Self::sum_usage(sched_out);
+        let t0 = Instant::now();
         self.tmodel.run(
             self.tok_trie.vocab_size(),
             &self.tim_model_fwd,
             self.step_no,
             sched_out,
         )?;
+        let t1 = Instant::now();
+        let usage1 = Self::sum_usage(sched_out);
+        self.num_gen_tokens += usage1.gen_tokens - usage0.gen_tokens;
+        self.num_prompt_tokens += usage1.prompt_tokens - usage0.prompt_tokens;
 
         let r = with_timer!(self.tim_sample, { self.sample(sched_out) });
 
+        let batch_size = sched_out.num_running_seqs();
+        self.latency.model_fwd.observe(batch_size, t1 - t0);
+        self.latency.sample.observe(batch_size, t1.elapsed());
+        self.update_timing(t1 - t0, t1.elapsed());
+
         self.tmodel.finalize_run()?;
 
         r
     }
 
+    fn sum_usage(sched_out: &SchedulerOutputs) -> TokenUsage {
+        let mut r = TokenUsage::default();
+        for sg in sched_out.next_seq_groups.iter() {
+            r.gen_tokens += sg.usage.gen_tokens;
+            r.prompt_tokens += sg.usage.prompt_tokens;
+        }
+        r
+    }
+
+    fn update_timing(&mut self, model_fwd: std::time::Duration, sample: std::time::Duration) {
+        let ema = |avg: f64, d: std::time::Duration| {
+            let us = d.as_micros() as f64;
+            if avg == 0.0 {
+                us
+            } else {
+                avg + TIMING_EMA_ALPHA * (us - avg)
+            }
+        };
+        self.avg_model_fwd_us = ema(self.avg_model_fwd_us, model_fwd);
+        self.avg_sample_us = ema(self.avg_sample_us, sample);
+    }
+
     pub fn seq_output_text(&self, seq_output: &SeqOutput) -> Result<String> {
         let generated = self
             .tokenizer
@@ -656,7 +2744,12 @@ impl<ME: ModelExec> RllmEngine<ME> {
         if let Some(r) = seqs.get(&seq.seq_id.to_num()) {
             seq.aici_logs.push(r.clone_with(None));
             if r.error.len() > 0 {
-                self.scheduler.finish_seq(seq, FinishReason::Failed);
+                let reason = if r.controller_error {
+                    FinishReason::ControllerError(r.error.trim().to_string())
+                } else {
+                    FinishReason::Failed
+                };
+                self.scheduler.finish_seq(seq, reason);
                 return None;
             }
             match &r.result {
@@ -669,41 +2762,45 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     fn aici_mid(&mut self, sched_out: &mut SchedulerOutputs) -> Result<()> {
-        if self.aicirt.is_none() {
+        if !self.ctrls.is_active() {
             return Ok(());
         }
 
         let mut mid_ops = Vec::new();
 
         for sg in sched_out.next_seq_groups.iter_mut() {
-            if sg.sampling_params.controller.is_none() {
+            if !sg.has_controller() {
                 continue;
             }
 
             for seq in sg.seqs.iter_mut() {
-                if seq.sched_phase != SchedulingPhase::Running {
+                if seq.sched_phase != SchedulingPhase::Running || seq.is_prefilling() {
                     continue;
                 }
 
-                if seq.has_aici {
-                    mid_ops.push(seq.mid_op.take().unwrap());
+                let mut op = if seq.has_aici {
+                    seq.mid_op.take().unwrap()
                 } else {
                     seq.has_aici = true;
-                    mid_ops.push(AiciMidOp {
+                    // restored sequences replay their tokens (see restart_controller())
+                    let op = seq.mid_op.take().unwrap_or_else(|| seq.defl_mid_op());
+                    AiciMidOp {
                         req_id: Some(sg.request_id.clone()),
-                        ..seq.defl_mid_op()
-                    });
-                }
+                        ..op
+                    }
+                };
+                // the backtrack and tokens are already applied to the sequence
+                op.byte_offset = seq.get_byte_len(&self.tok_trie) as u64;
+                op.context_truncation = seq.context_truncation.take();
+                mid_ops.push(op);
             }
         }
 
-        self.aicirt
-            .as_mut()
-            .unwrap()
-            .start_mid_process(AiciMidProcessReq {
-                ops: mid_ops,
-                freed: self.scheduler.get_freed_seq_ids(),
-            })?;
+        self.ctrls.start_mid_process(AiciMidProcessReq {
+            ops: mid_ops,
+            freed: self.scheduler.get_freed_seq_ids(),
+            freed_req_ids: self.scheduler.get_freed_req_ids(),
+        })?;
 
         Ok(())
     }
@@ -715,14 +2812,44 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     pub fn step(&mut self) -> Result<Vec<RequestOutput>> {
+        if self.paused {
+            return Ok(Vec::new());
+        }
+        let t0 = Instant::now();
+        let num_gen_tokens = self.num_gen_tokens;
+        let num_prompt_tokens = self.num_prompt_tokens;
         let r = with_timer!(self.tim_step, self.step_inner());
 
+        let stats = StepStats {
+            gen_tokens: self.num_gen_tokens - num_gen_tokens,
+            prompt_tokens: self.num_prompt_tokens - num_prompt_tokens,
+            num_outputs: r.as_ref().map_or(0, |outputs| outputs.len()),
+            duration: t0.elapsed(),
+            ..std::mem::take(&mut self.step_stats)
+        };
+        if stats.num_seqs > 0 {
+            self.latency.step.observe(stats.num_seqs, stats.duration);
+            self.queue_throughput.observe(&stats);
+        }
+        self.scheduler.listeners.step_complete(&stats);
+
         if self.step_no % 20 == 0 {
             log::debug!("timers\n{}", self.timers.pp());
             self.timers.reset();
         }
 
-        r
+        if self.log_stats_steps > 0 && self.step_no % self.log_stats_steps == 0 {
+            log::info!("{}", self.stats());
+        }
+
+        match r {
+            Ok(outputs) if !self.held_outputs.is_empty() => {
+                let mut held = std::mem::take(&mut self.held_outputs);
+                held.extend(outputs);
+                Ok(held)
+            }
+            r => r,
+        }
     }
 
     fn step_inner(&mut self) -> Result<Vec<RequestOutput>> {
@@ -736,7 +2863,29 @@ impl<ME: ModelExec> RllmEngine<ME> {
             }
         });
 
+        let t_sched = Instant::now();
         let mut sched_out = with_timer!(self.tim_schedule, self.scheduler.schedule());
+        let batch_size = sched_out.num_running_seqs();
+        let (mut started_groups, mut started_prompt_tokens) = (0, 0);
+        for sg in sched_out.next_seq_groups.iter() {
+            // scheduled for the first time in this step
+            if let Some(t) = sg.first_scheduled_time.filter(|t| *t >= t_sched) {
+                self.latency
+                    .queue_wait
+                    .observe(batch_size, t.saturating_duration_since(sg.arrival_time));
+                started_groups += 1;
+                started_prompt_tokens += sg.seqs[0].prompt_len;
+            }
+        }
+        let num_gen_tokens = self.num_gen_tokens;
+        self.step_stats = StepStats {
+            step_no: self.step_no,
+            num_seqs: sched_out.num_running_seqs(),
+            num_batched_tokens: sched_out.num_batched_tokens,
+            started_groups,
+            started_prompt_tokens,
+            ..Default::default()
+        };
 
         with_timer!(self.tim_aici_mid, self.aici_mid(&mut sched_out)?);
 
@@ -749,14 +2898,38 @@ impl<ME: ModelExec> RllmEngine<ME> {
         // we run step_finished() regardless if model failed
         self.scheduler.step_finished(sched_out);
 
+        let check_steps = get_setting("cache_check_steps") as usize;
+        if check_steps > 0 && self.step_no % check_steps == 0 {
+            self.scheduler.block_manager.check_cache_counters();
+        }
+
         let outputs = outputs?;
         if outputs.is_empty() {
             assert!(!self.scheduler.has_unfinished_seqs());
         }
+        self.check_livelock(num_gen_tokens);
 
         Ok(outputs)
     }
 
+    /// Report when the engine keeps stepping (e.g., preempting and recomputing sequences)
+    /// without sampling anything.
+    fn check_livelock(&mut self, num_gen_tokens_before: usize) {
+        if self.num_gen_tokens > num_gen_tokens_before || !self.scheduler.has_unfinished_seqs() {
+            self.steps_without_tokens = 0;
+            return;
+        }
+        self.steps_without_tokens += 1;
+        if self.steps_without_tokens == self.config.scheduler.livelock_steps {
+            self.num_livelocks += 1;
+            log::warn!(
+                "livelock? no tokens sampled in {} steps; {}",
+                self.steps_without_tokens,
+                self.stats()
+            );
+        }
+    }
+
     fn decode_seq(&self, tokens: &Vec<Token>) -> Result<String> {
         let generated = self
             .tokenizer
@@ -766,8 +2939,27 @@ impl<ME: ModelExec> RllmEngine<ME> {
     }
 
     pub fn generate(&mut self, prompt: &str, sampling_params: SamplingParams) -> Result<String> {
+        self.generate_inner(prompt, sampling_params, true)
+    }
+
+    /// Like generate(), but the prompt is built from the messages with the model's chat template.
+    pub fn generate_chat(
+        &mut self,
+        messages: &[ChatMessage],
+        sampling_params: SamplingParams,
+    ) -> Result<String> {
+        let prompt = self.chat_template.render(messages, true)?;
+        self.generate_inner(&prompt, sampling_params, false)
+    }
+
+    fn generate_inner(
+        &mut self,
+        prompt: &str,
+        sampling_params: SamplingParams,
+        encode_special: bool,
+    ) -> Result<String> {
         let req_id = self.gen_req_id();
-        self.add_request(req_id, prompt, sampling_params)?;
+        self.add_request_inner(req_id, prompt, sampling_params, encode_special)?;
 
         let mut outputs = Vec::new();
         let t0 = Instant::now();
@@ -776,8 +2968,14 @@ impl<ME: ModelExec> RllmEngine<ME> {
             let outp = self.step()?;
             if !outp.is_empty() {
                 assert!(outp.len() == 1);
-                assert!(outp[0].seq_outputs.len() == 1);
-                outputs = outp[0].seq_outputs[0].output_tokens.clone();
+                // with best_of > 1, return the most likely sequence
+                outputs = outp[0]
+                    .seq_outputs
+                    .iter()
+                    .max_by(|a, b| a.cumulative_logprob.total_cmp(&b.cumulative_logprob))
+                    .unwrap()
+                    .output_tokens
+                    .clone();
             }
         }
 
@@ -792,10 +2990,1909 @@ impl<ME: ModelExec> RllmEngine<ME> {
         Ok(self.decode_seq(&outputs)?)
     }
 
-    pub fn get_stats(&self) -> Stats {
-        Stats {
-            free_gpu_blocks: self.scheduler.block_manager.get_num_free_gpu_blocks(),
-            free_cpu_blocks: self.scheduler.block_manager.get_num_free_cpu_blocks(),
+    /// Score each of `options` as the continuation of `prompt`, without sampling.
+    /// The prompt is prefilled once, and then forked into one sequence per option,
+    /// which is fed the option's tokens (teacher forcing) to sum up their log-probabilities.
+    /// Returns the options with the softmax of these sums, in the order given.
+    /// Options are tokenized in context, after the prompt, so they should include
+    /// the leading space when they follow a word.
+    /// Other requests make progress in the steps it runs; their outputs are returned
+    /// from the next step().
+    pub fn classify(&mut self, prompt: &str, options: &[&str]) -> Result<Vec<(String, f32)>> {
+        if options.is_empty() {
+            bail_user!("no options to classify with");
+        }
+        if self.paused {
+            bail_user!("can't classify while the engine is paused");
+        }
+        let prompt_tokens = self.tokenize(prompt, true)?;
+        let full = options
+            .iter()
+            .map(|opt| self.tokenize(&format!("{prompt}{opt}"), true))
+            .collect::<Result<Vec<_>>>()?;
+        let (prefix, continuations) = split_choice_tokens(&prompt_tokens, &full);
+
+        let req_id = self.gen_req_id();
+        self.queue_request(AddRequest {
+            request_id: req_id.clone(),
+            prompt: prefix,
+            sampling_params: SamplingParams {
+                n: options.len(),
+                best_of: options.len(),
+                max_tokens: continuations.iter().map(|t| t.len()).max().unwrap(),
+                ignore_eos: true,
+                ..SamplingParams::default()
+            },
+            expected: None,
+            init_result: None,
+            auth: None,
+        })?;
+        // fork_best_of() forks the sequence once the prefix is prefilled
+        self.scheduler.for_each_waiting_sg(|sg| {
+            if sg.request_id == req_id {
+                sg.seqs[0].forced_options = continuations.clone();
+            }
+        });
+
+        let mut seq_outputs = None;
+        while seq_outputs.is_none() {
+            if !self.scheduler.has_unfinished_seqs() {
+                bail!("the request scoring the options was dropped");
+            }
+            for outp in self.step()? {
+                if outp.request_id != req_id {
+                    self.held_outputs.push(outp);
+                } else if outp.is_final {
+                    seq_outputs = Some(outp.seq_outputs);
+                }
+            }
+        }
+
+        let mut logprobs = vec![f32::NAN; options.len()];
+        for so in seq_outputs.unwrap() {
+            if so.finish_reason != Some(FinishReason::MaxTokensReached) {
+                bail!("scoring option {:?} failed: {:?}", options[so.index], so.finish_reason);
+            }
+            logprobs[so.index] = so.cumulative_logprob;
+        }
+        if logprobs.iter().any(|lp| lp.is_nan()) {
+            bail!("not all options were scored");
+        }
+        Ok(options
+            .iter()
+            .map(|opt| opt.to_string())
+            .zip(normalize_logprobs(&logprobs))
+            .collect())
+    }
+
+    /// Run synthetic batches of every shape in `profile` through the model, so that
+    /// kernel selection, autotuning and CUDA graph capture are done before the first request.
+    /// The engine has to be idle.
+    pub fn warmup(&mut self, profile: WarmupProfile) -> Result<()> {
+        let t0 = Instant::now();
+        let shapes = self.warmup_shapes(&profile);
+        for batch in &shapes {
+            let timing = self.bench_step(*batch)?;
+            log::debug!("warmup {batch}: {timing}");
+        }
+        log::info!(
+            "warmup: {} batch shapes in {:.2}s",
+            shapes.len(),
+            t0.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+
+    fn warmup_shapes(&self, profile: &WarmupProfile) -> Vec<SyntheticBatch> {
+        let cfg = &self.config.scheduler;
+        let mut batch_sizes = profile.batch_sizes.clone();
+        if batch_sizes.is_empty() {
+            batch_sizes = self.tmodel.batch_size_buckets();
+            batch_sizes.push(cfg.max_num_seqs);
+        }
+        batch_sizes.retain(|&n| n > 0 && n <= cfg.max_num_seqs);
+        batch_sizes.sort();
+        batch_sizes.dedup();
+
+        let gen_len = profile.gen_len.clamp(1, cfg.max_model_len - 1);
+        let mut shapes = Vec::new();
+        for &batch_size in &batch_sizes {
+            let max_prompt = std::cmp::min(
+                cfg.max_num_batched_tokens / batch_size,
+                cfg.max_model_len - gen_len,
+            );
+            for &prompt_len in &profile.prompt_lens {
+                let batch = SyntheticBatch {
+                    batch_size,
+                    prompt_len: std::cmp::min(prompt_len, max_prompt),
+                    gen_len,
+                };
+                if batch.prompt_len > 0 && !shapes.contains(&batch) {
+                    shapes.push(batch);
+                }
+            }
         }
+        shapes
+    }
+
+    /// Prefill and then decode a batch of random tokens, bypassing the tokenizer,
+    /// the scheduler and aicirt. The KV cache blocks are freed afterwards.
+    /// The engine has to be idle.
+    pub fn bench_step(&mut self, batch: SyntheticBatch) -> Result<StepTiming> {
+        let cfg = &self.config.scheduler;
+        if self.scheduler.has_unfinished_seqs() {
+            bail_user!("can't run synthetic batches with requests in progress");
+        }
+        if batch.batch_size == 0 || batch.prompt_len == 0 || batch.gen_len == 0 {
+            bail_user!("synthetic batch {batch} is empty");
+        }
+        if batch.batch_size > cfg.max_num_seqs
+            || batch.batch_size * batch.prompt_len > cfg.max_num_batched_tokens
+            || batch.prompt_len + batch.gen_len > cfg.max_model_len
+        {
+            bail_user!(
+                "synthetic batch {batch} exceeds the limits: {} seqs, {} batched tokens, {} tokens per seq",
+                cfg.max_num_seqs,
+                cfg.max_num_batched_tokens,
+                cfg.max_model_len
+            );
+        }
+
+        let mut timing = StepTiming::default();
+        let mut sched_out = SchedulerOutputs::new();
+        let r = self.run_synthetic(&batch, &mut sched_out, &mut timing);
+        // also when the run failed
+        let t0 = Instant::now();
+        for sg in sched_out.next_seq_groups.iter() {
+            self.seq_mgr.delete(sg.seqs[0].seq_id);
+        }
+        timing.cache_ops += t0.elapsed();
+        r?;
+        Ok(timing)
+    }
+
+    fn run_synthetic(
+        &mut self,
+        batch: &SyntheticBatch,
+        sched_out: &mut SchedulerOutputs,
+        timing: &mut StepTiming,
+    ) -> Result<()> {
+        let vocab_size = self.tok_trie.vocab_size();
+        let sampling_params = SamplingParams {
+            temperature: 1.0,
+            top_p: 0.9,
+            ..SamplingParams::default()
+        };
+        let mut rng = StdRng::seed_from_u64(SYNTHETIC_SEED);
+
+        let t0 = Instant::now();
+        for idx in 0..batch.batch_size {
+            // random prompts never hit the prefix cache, so every run prefills all tokens
+            let tokens: Vec<Token> = (0..batch.prompt_len)
+                .map(|_| rng.gen_range(0..vocab_size as Token))
+                .collect();
+            let mut sg = SequenceGroup {
+                request_id: format!("synthetic-{idx}"),
+                prompt: String::new(),
+                seqs: vec![Sequence::new(self.seq_mgr.new_sequence(), &tokens)],
+                sampling_params: sampling_params.clone(),
+                arrival_time: Instant::now(),
+                first_scheduled_time: None,
+                first_token_time: None,
+                logits_processor: LogitsProcessor::new(&sampling_params, idx as u64),
+                max_index: 0,
+                usage: TokenUsage::default(),
+                native_ctrl: false,
+                auth: None,
+                ctrl_prompt: Vec::new(),
+                restored: Vec::new(),
+            };
+            if !self.scheduler.block_manager.can_allocate(&sg, 0) {
+                bail_user!("not enough KV cache blocks for synthetic batch {batch}");
+            }
+            self.scheduler.block_manager.allocate(&mut sg);
+            sg.seqs[0].sched_phase = SchedulingPhase::Running;
+            sched_out.next_seq_groups.push(sg);
+        }
+        sched_out.prompt_run = true;
+        sched_out.num_batched_tokens = batch.batch_size * batch.prompt_len;
+        timing.cache_ops += t0.elapsed();
+
+        for step in 0..batch.gen_len {
+            let t0 = Instant::now();
+            self.tmodel
+                .run(vocab_size, &self.tim_model_fwd, self.step_no, sched_out)?;
+            self.tmodel.synchronize();
+            if step == 0 {
+                timing.prefill += t0.elapsed();
+            } else {
+                timing.decode += t0.elapsed();
+                timing.num_decode_steps += 1;
+            }
+
+            let t0 = Instant::now();
+            let mut logits = Vec::new();
+            let mut rows = Vec::new();
+            for sg in sched_out.next_seq_groups.iter_mut() {
+                logits.push(self.tmodel.get_logits(sg.seqs[0].seq_id.to_num()));
+                rows.push(sg.logits_processor.next_row(&sg.seqs[0], step));
+            }
+            let sampled = self.tmodel.sample_batch(&logits, &rows)?;
+            timing.sampling += t0.elapsed();
+            self.tmodel.finalize_run()?;
+
+            if step + 1 == batch.gen_len {
+                break;
+            }
+
+            let t0 = Instant::now();
+            sched_out.prompt_run = false;
+            sched_out.num_batched_tokens = batch.batch_size;
+            sched_out.blocks_to_copy.clear();
+            // append_slots() takes the outputs, so the groups are moved out meanwhile
+            let mut groups = std::mem::take(&mut sched_out.next_seq_groups);
+            let mut fits = true;
+            for (sg, tok) in groups.iter_mut().zip(sampled) {
+                sg.seqs[0].append_tokens(&[tok]);
+                fits = fits && self.scheduler.block_manager.can_append_slot(sg);
+                if fits {
+                    self.scheduler
+                        .block_manager
+                        .append_slots(&mut sg.seqs[0], sched_out);
+                }
+            }
+            sched_out.next_seq_groups = groups;
+            timing.cache_ops += t0.elapsed();
+            if !fits {
+                bail_user!("not enough KV cache blocks for synthetic batch {batch}");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        let (prefix_cache_hits, prefix_cache_misses) =
+            self.scheduler.block_manager.get_prefix_cache_stats();
+        let (num_waiting_seqs, num_running_seqs, num_swapped_seqs) = self.scheduler.get_num_seqs();
+        let (num_waiting_groups, queued_prompt_tokens) = self.scheduler.get_waiting_queue_size();
+        EngineStats {
+            num_steps: self.step_no,
+            generated_tokens: self.num_gen_tokens,
+            prompt_tokens: self.num_prompt_tokens,
+            num_running_seqs,
+            num_waiting_seqs,
+            num_swapped_seqs,
+            avg_model_fwd_us: self.avg_model_fwd_us,
+            avg_sample_us: self.avg_sample_us,
+            num_gpu_blocks: self.scheduler.block_manager.get_num_gpu_blocks(),
+            num_cpu_blocks: self.scheduler.block_manager.get_num_cpu_blocks(),
+            free_gpu_blocks: self.scheduler.block_manager.get_num_free_gpu_blocks(),
+            free_cpu_blocks: self.scheduler.block_manager.get_num_free_cpu_blocks(),
+            prefix_cache_hits,
+            prefix_cache_misses,
+            numerical_errors: self.num_numerical_errors,
+            deadline_exceeded: self.scheduler.get_num_deadline_exceeded(),
+            livelocks: self.num_livelocks,
+            post_sample_rejected: self.num_post_sample_rejected,
+            post_sample_failures: self.num_post_sample_failures,
+            draft_tokens: self.num_drafted,
+            draft_tokens_accepted: self.num_draft_accepted,
+            tokenize_cache_hits: self.tokenize_cache.hits,
+            tokenize_cache_misses: self.tokenize_cache.misses,
+            tokenize_cache_bytes: self.tokenize_cache.num_bytes(),
+            num_waiting_groups,
+            queued_prompt_tokens,
+            requests_admitted: self.num_requests_admitted,
+            requests_rejected: self.num_requests_rejected,
+            priority_stats: self.scheduler.get_priority_stats(),
+            cache: self.scheduler.cache_report(),
+        }
+    }
+
+    /// Latency histograms since the engine was built; e.g., for
+    /// `latency().time_to_first_token.total().quantile(0.99)`.
+    pub fn latency(&self) -> &LatencyMetrics {
+        &self.latency
+    }
+
+    /// The counters and gauges of stats(), and the latency histograms, in the Prometheus
+    /// text exposition format, to be served at /metrics.
+    pub fn metrics_text(&self) -> String {
+        let stats = self.stats();
+        let lat = &self.latency;
+        let mut w = MetricsWriter::default();
+        w.counter("rllm_steps_total", "Engine steps.", stats.num_steps);
+        w.counter(
+            "rllm_generated_tokens_total",
+            "Tokens generated (sampled).",
+            stats.generated_tokens,
+        );
+        w.counter(
+            "rllm_prompt_tokens_total",
+            "Prompt (and fast-forwarded) tokens processed.",
+            stats.prompt_tokens,
+        );
+        w.counter(
+            "rllm_prefix_cache_hits_total",
+            "Prompt blocks found in the prefix cache.",
+            stats.prefix_cache_hits,
+        );
+        w.counter(
+            "rllm_prefix_cache_misses_total",
+            "Prompt blocks not found in the prefix cache.",
+            stats.prefix_cache_misses,
+        );
+        w.counter(
+            "rllm_tokenize_cache_hits_total",
+            "Prompts found in the tokenization cache.",
+            stats.tokenize_cache_hits,
+        );
+        w.counter(
+            "rllm_tokenize_cache_misses_total",
+            "Prompts not found in the tokenization cache.",
+            stats.tokenize_cache_misses,
+        );
+        w.counter(
+            "rllm_requests_admitted_total",
+            "Requests accepted into the waiting queue.",
+            stats.requests_admitted,
+        );
+        w.counter(
+            "rllm_requests_rejected_total",
+            "Requests rejected because the waiting queue was full.",
+            stats.requests_rejected,
+        );
+        w.counter(
+            "rllm_numerical_errors_total",
+            "Sequences finished because of NaN/inf logits.",
+            stats.numerical_errors,
+        );
+        w.counter(
+            "rllm_deadline_exceeded_total",
+            "Requests finished because of their deadline.",
+            stats.deadline_exceeded,
+        );
+        w.counter(
+            "rllm_livelocks_total",
+            "Times no token was sampled for many steps in a row.",
+            stats.livelocks,
+        );
+        w.counter(
+            "rllm_post_sample_rejected_total",
+            "Sampled tokens rejected by controllers.",
+            stats.post_sample_rejected,
+        );
+        w.counter(
+            "rllm_post_sample_failures_total",
+            "Sequences failed because controllers rejected too many tokens.",
+            stats.post_sample_failures,
+        );
+        w.counter(
+            "rllm_draft_tokens_total",
+            "Tokens proposed by the draft model.",
+            stats.draft_tokens,
+        );
+        w.counter(
+            "rllm_draft_tokens_accepted_total",
+            "Draft tokens the model agreed with.",
+            stats.draft_tokens_accepted,
+        );
+        w.gauge(
+            "rllm_running_seqs",
+            "Sequences on the GPU.",
+            stats.num_running_seqs,
+        );
+        w.gauge(
+            "rllm_waiting_seqs",
+            "Sequences waiting to be scheduled.",
+            stats.num_waiting_seqs,
+        );
+        w.gauge(
+            "rllm_waiting_groups",
+            "Requests waiting to be scheduled, including preempted ones.",
+            stats.num_waiting_groups,
+        );
+        w.gauge(
+            "rllm_queued_prompt_tokens",
+            "Prompt tokens of the requests waiting to be scheduled.",
+            stats.queued_prompt_tokens,
+        );
+        w.gauge(
+            "rllm_swapped_seqs",
+            "Sequences swapped out to the CPU.",
+            stats.num_swapped_seqs,
+        );
+        w.gauge(
+            "rllm_gpu_blocks",
+            "KV cache blocks on the GPU.",
+            stats.num_gpu_blocks,
+        );
+        w.gauge(
+            "rllm_gpu_blocks_free",
+            "Free KV cache blocks on the GPU.",
+            stats.free_gpu_blocks,
+        );
+        w.gauge(
+            "rllm_cpu_blocks",
+            "KV cache blocks on the CPU.",
+            stats.num_cpu_blocks,
+        );
+        w.gauge(
+            "rllm_cpu_blocks_free",
+            "Free KV cache blocks on the CPU.",
+            stats.free_cpu_blocks,
+        );
+        w.gauge(
+            "rllm_tokenize_cache_bytes",
+            "Size of the prompts and tokens in the tokenization cache.",
+            stats.tokenize_cache_bytes,
+        );
+        w.histogram(
+            "rllm_time_to_first_token_seconds",
+            "Time from arrival of a request until its first token.",
+            &lat.time_to_first_token,
+        );
+        w.histogram(
+            "rllm_queue_wait_seconds",
+            "Time from arrival of a request until it is first scheduled.",
+            &lat.queue_wait,
+        );
+        w.histogram(
+            "rllm_step_seconds",
+            "Engine steps that ran the model.",
+            &lat.step,
+        );
+        w.histogram(
+            "rllm_model_forward_seconds",
+            "Model forward passes.",
+            &lat.model_fwd,
+        );
+        w.histogram(
+            "rllm_sample_seconds",
+            "Sampling, including the controller biases.",
+            &lat.sample,
+        );
+        w.histogram(
+            "rllm_controller_mid_process_seconds",
+            "Controller mid_process() calls, per sequence.",
+            &lat.controller_mid_process,
+        );
+        w.finish()
+    }
+}
+
+/// Split the tokens of the prompt followed by each option into the prefix shared by all
+/// of them (and by the prompt alone), and the rest for each option, which has at least
+/// one token. The prompt's last tokens are part of the rest when the option changes
+/// their tokenization (e.g., a trailing space merged into the option's first word).
+fn split_choice_tokens(prompt: &[Token], full: &[Vec<Token>]) -> (Vec<Token>, Vec<Vec<Token>>) {
+    let mut len = prompt.len();
+    for toks in full {
+        let common = toks.iter().zip(prompt).take_while(|(a, b)| a == b).count();
+        len = len.min(common).min(toks.len().saturating_sub(1));
+    }
+    let rest = full.iter().map(|toks| toks[len..].to_vec()).collect();
+    (prompt[..len].to_vec(), rest)
+}
+
+/// Softmax over the options' total log-probabilities.
+fn normalize_logprobs(logprobs: &[f32]) -> Vec<f32> {
+    let max = logprobs.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let exps: Vec<f32> = logprobs.iter().map(|lp| (lp - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.iter().map(|e| e / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        normalize_logprobs, split_choice_tokens, AddRequest, AddRequestError, QueueThroughput,
+        RllmEngine, StepStats, SyntheticBatch, DEFAULT_RETRY_AFTER, MAX_RETRY_AFTER,
+        MIN_RETRY_AFTER,
+    };
+    use crate::{
+        config::{AiciConfig, ModelMeta, PreemptionMode, RllmConfig, SamplingParams},
+        seq::{FinishReason, RequestOutput, SchedulingPhase, SeqOutput, Sequence, Token},
+        testing::{mock_cache, MockBlocks, MockSeqMgr, Recorder},
+        AiciBias, DraftModelArgs, EngineSnapshot, HashMap, HookBias, LoaderArgs, ModelExec,
+        SamplerCtx, SamplerHook, SchedulerOutputs, TBlockSpaceManager,
+    };
+    use aici_abi::{
+        bytes::TokRxInfo, native::RegexCtrl, testing::SpliceAt, toktree::TokTrie, AiciCtrl,
+        InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, PostSampleArg,
+        PostSampleResult,
+    };
+    use aicirt::{api::SequenceResult, TimerRef};
+    use anyhow::{bail, Result};
+    use serde_json::json;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokenizers::Tokenizer;
+
+    /// Tokens of the toy model; "Ġ" is the space (as in byte-level tokenizers).
+    const VOCAB: &[&str] = &["</s>", "Ġ", "a", "b", "c", "d", "e", "f", "g", "h"];
+    const EOS: Token = 0;
+    const MAX_LEN: usize = 64;
+
+    type LogitsFn = Box<dyn Fn(&[Token]) -> Vec<f32> + Send>;
+
+    /// The toy model: `logits_fn` computes the logits of each position from the tokens
+    /// up to and including it. It never runs out of KV cache, unless given a budget
+    /// (see MockCache).
+    struct ToyExec {
+        logits_fn: LogitsFn,
+        /// The draft model, proposing the most likely token of its logits.
+        draft_fn: Option<LogitsFn>,
+        /// For each sequence run in the step, the logits of its last
+        /// num_draft_tokens() + 1 positions.
+        logits: HashMap<usize, Vec<Vec<f32>>>,
+        seq_mgr: Arc<MockSeqMgr>,
+    }
+
+    struct ToyBias {
+        vocab_size: usize,
+        bias: Option<Vec<f32>>,
+    }
+
+    impl AiciBias<Vec<f32>> for ToyBias {
+        fn apply(&self, logits: &mut Vec<f32>, seq_id: usize) {
+            let bias = self.bias.as_ref().unwrap();
+            let bias = &bias[seq_id * self.vocab_size..(seq_id + 1) * self.vocab_size];
+            for (l, b) in logits.iter_mut().zip(bias) {
+                *l += b;
+            }
+        }
+    }
+
+    impl ModelExec for ToyExec {
+        type Tensor = Vec<f32>;
+        type BlockSpaceManager = MockBlocks<Self>;
+        type AiciBias = ToyBias;
+        type ModelConfig = ();
+        type ModelLoaderArgs = ();
+        type SequenceManager = MockSeqMgr;
+
+        fn tensor_to_vec1(tensor: &Vec<f32>) -> Vec<f32> {
+            tensor.clone()
+        }
+        fn load_model_config(_args: &LoaderArgs, _model_args: &mut ()) -> Result<(ModelMeta, ())> {
+            let meta = ModelMeta {
+                id: "toy".to_string(),
+                max_sequence_length: MAX_LEN,
+                vocab_size: VOCAB.len(),
+                tok_vocab_size: VOCAB.len(),
+            };
+            Ok((meta, ()))
+        }
+        fn verify_args(_args: &RllmConfig<Self>) -> Result<()> {
+            Ok(())
+        }
+        fn load_rllm_engine(_args: LoaderArgs, _model_args: ()) -> Result<RllmEngine<Self>> {
+            unreachable!("not used by these tests")
+        }
+        fn sequence_manager(&self) -> Arc<MockSeqMgr> {
+            self.seq_mgr.clone()
+        }
+        fn run(
+            &mut self,
+            _vocab_size: usize,
+            _tim: &TimerRef,
+            _step_no: usize,
+            sched_out: &mut SchedulerOutputs,
+        ) -> Result<()> {
+            self.logits.clear();
+            for sg in sched_out.next_seq_groups.iter_mut() {
+                for seq in sg.seqs.iter_mut() {
+                    if seq.sched_phase != SchedulingPhase::Running {
+                        continue;
+                    }
+                    // like the paged attention backend
+                    let q_len = seq.num_query_tokens();
+                    if !seq.is_prefilling() {
+                        sg.usage.gen_tokens += 1;
+                        let k_len = seq.get_kv_len();
+                        let tokens: Vec<Token> = (0..k_len).map(|i| seq.get_token(i)).collect();
+                        let first = k_len - seq.num_draft_tokens();
+                        let logits = (first..=k_len)
+                            .map(|end| (self.logits_fn)(&tokens[..end]))
+                            .collect();
+                        self.logits.insert(seq.seq_id.to_num(), logits);
+                    }
+                    sg.usage.prompt_tokens += q_len - seq.num_draft_tokens();
+                    seq.sync_computed_kv();
+                }
+            }
+            Ok(())
+        }
+        fn get_logits(&self, seq_id: usize) -> Vec<f32> {
+            self.get_logits_at(seq_id, 0)
+        }
+        fn get_logits_at(&self, seq_id: usize, back: usize) -> Vec<f32> {
+            let logits = &self.logits[&seq_id];
+            logits[logits.len() - 1 - back].clone()
+        }
+        fn draft_next_tokens(&mut self, seqs: &[&Sequence]) -> Result<Vec<u32>> {
+            let draft_fn = self.draft_fn.as_ref().unwrap();
+            let tokens = seqs.iter().map(|seq| {
+                let logits = draft_fn(seq.all_tokens());
+                (0..logits.len())
+                    .max_by(|a, b| logits[*a].total_cmp(&logits[*b]).then(b.cmp(a)))
+                    .unwrap() as Token
+            });
+            Ok(tokens.collect())
+        }
+        fn finalize_run(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn empty_bias(&self, vocab_size: usize) -> ToyBias {
+            ToyBias {
+                vocab_size,
+                bias: None,
+            }
+        }
+        fn new_bias(&self, slice: &[f32], num_seqs: usize, vocab_size: usize) -> ToyBias {
+            assert!(slice.len() == num_seqs * vocab_size);
+            ToyBias {
+                vocab_size,
+                bias: Some(slice.to_vec()),
+            }
+        }
+    }
+
+    fn toy_tokenizer() -> (Tokenizer, TokTrie) {
+        let vocab: serde_json::Map<_, _> = VOCAB
+            .iter()
+            .enumerate()
+            .map(|(id, t)| (t.to_string(), json!(id)))
+            .collect();
+        let byte_level = json!({
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": true,
+            "use_regex": false,
+        });
+        let json = json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": EOS,
+                "content": VOCAB[EOS as usize],
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }],
+            "normalizer": null,
+            "pre_tokenizer": byte_level,
+            "post_processor": null,
+            "decoder": byte_level,
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": vocab,
+                "merges": [],
+            },
+        });
+        let tokenizer = Tokenizer::from_bytes(json.to_string()).unwrap();
+
+        let words = VOCAB
+            .iter()
+            .map(|t| match *t {
+                "</s>" => vec![],
+                "Ġ" => b" ".to_vec(),
+                t => t.as_bytes().to_vec(),
+            })
+            .collect();
+        let info = TokRxInfo {
+            vocab_size: VOCAB.len() as u32,
+            tok_eos: EOS,
+        };
+        (tokenizer, TokTrie::from(&info, &words))
+    }
+
+    /// The engine with the toy model; the weights folder doesn't exist, so the built-in
+    /// chat template is used.
+    fn toy_engine_with(args: LoaderArgs, logits_fn: LogitsFn) -> RllmEngine<ToyExec> {
+        try_toy_engine(args, logits_fn, VOCAB.len()).unwrap()
+    }
+
+    /// Like toy_engine_with(), with `model_vocab_size` logits instead of one per token.
+    fn try_toy_engine(
+        args: LoaderArgs,
+        logits_fn: LogitsFn,
+        model_vocab_size: usize,
+    ) -> Result<RllmEngine<ToyExec>> {
+        let args = LoaderArgs {
+            local_weights: Some("/nonexistent/toy".to_string()),
+            ..args
+        };
+        let mut config = RllmEngine::<ToyExec>::build_config(&args, &mut ())?;
+        config.meta.vocab_size = model_vocab_size;
+        let (seq_mgr, blocks) = mock_cache(None);
+        let tmodel = ToyExec {
+            logits_fn,
+            draft_fn: None,
+            logits: HashMap::default(),
+            seq_mgr,
+        };
+        let (tokenizer, tok_trie) = toy_tokenizer();
+        RllmEngine::build_with_tokenizer(
+            args,
+            tmodel,
+            blocks,
+            Arc::new(config),
+            tokenizer,
+            tok_trie,
+            vec![EOS],
+        )
+    }
+
+    /// The toy model that always continues with the letter after the last token
+    /// (wrapping around from h to a), and never ends the sequence itself.
+    fn next_letter(tokens: &[Token]) -> Vec<f32> {
+        let last = *tokens.last().unwrap();
+        let next = if last >= 2 && (last as usize) < VOCAB.len() - 1 {
+            last + 1
+        } else {
+            2
+        };
+        (0..VOCAB.len() as Token)
+            .map(|t| if t == next { 10.0 } else { 0.0 })
+            .collect()
+    }
+
+    fn toy_engine() -> RllmEngine<ToyExec> {
+        toy_engine_with(LoaderArgs::default(), Box::new(next_letter))
+    }
+
+    fn greedy(max_tokens: usize) -> SamplingParams {
+        SamplingParams {
+            max_tokens,
+            ..SamplingParams::default()
+        }
+    }
+
+    /// Step until all requests finish; the outputs of all steps.
+    fn run_all(engine: &mut RllmEngine<ToyExec>) -> Vec<RequestOutput> {
+        let mut outputs = Vec::new();
+        for _ in 0..1000 {
+            if engine.num_pending_requests() == 0 {
+                return outputs;
+            }
+            outputs.extend(engine.step().unwrap());
+        }
+        panic!("requests not finished after 1000 steps");
+    }
+
+    #[test]
+    fn options_continue_the_shared_prefix() {
+        // "Sentiment: " + " positive"/" negative", where the trailing space
+        // merges with the option
+        let prompt = vec![1, 10, 11, 12];
+        let full = vec![vec![1, 10, 11, 20, 21], vec![1, 10, 11, 30]];
+        let (prefix, rest) = split_choice_tokens(&prompt, &full);
+        assert_eq!(prefix, vec![1, 10, 11]);
+        assert_eq!(rest, vec![vec![20, 21], vec![30]]);
+
+        // an option that adds no tokens still gets one to score
+        let full = vec![vec![1, 10, 11, 12], vec![1, 10, 11, 12, 40]];
+        let (prefix, rest) = split_choice_tokens(&prompt, &full);
+        assert_eq!(prefix, vec![1, 10, 11]);
+        assert_eq!(rest, vec![vec![12], vec![12, 40]]);
+    }
+
+    #[test]
+    fn scores_sum_to_one() {
+        let scores = normalize_logprobs(&[-1.0, -3.5, -0.2, -200.0]);
+        assert!((scores.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(scores[2] > scores[0] && scores[0] > scores[1] && scores[1] > scores[3]);
+        assert_eq!(normalize_logprobs(&[-2.0, -2.0]), vec![0.5, 0.5]);
+    }
+
+    /// Prefers b, and then c, over the other tokens.
+    fn prefer_b(_tokens: &[Token]) -> Vec<f32> {
+        VOCAB
+            .iter()
+            .map(|t| match *t {
+                "b" => 3.0,
+                "c" => 1.0,
+                _ => 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classify_scores_the_options() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(prefer_b));
+        // finishes while the options are scored
+        engine
+            .add_request_tokens("other".to_string(), vec![2], greedy(2))
+            .unwrap();
+        engine.step().unwrap();
+
+        let scores = engine.classify("a", &["b", "cd", "e"]).unwrap();
+        let lse = (3.0f32.exp() + 1.0f32.exp() + (VOCAB.len() - 2) as f32).ln();
+        let expected = normalize_logprobs(&[3.0 - lse, 1.0 - 2.0 * lse, -lse]);
+        assert_eq!(scores.len(), 3);
+        let names = ["b", "cd", "e"];
+        for ((opt, score), (name, exp)) in scores.iter().zip(names.iter().zip(expected)) {
+            assert_eq!(opt, *name);
+            assert!((score - exp).abs() < 1e-4, "{opt}: {score} vs {exp}");
+        }
+        assert!((scores.iter().map(|(_, s)| s).sum::<f32>() - 1.0).abs() < 1e-5);
+
+        // the best option is what greedy generation starts with
+        let best = scores.iter().max_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+        engine
+            .add_request_tokens("greedy".to_string(), vec![2], greedy(1))
+            .unwrap();
+        // and the output of the other request, from a step of classify(), comes next
+        let outputs = run_all(&mut engine);
+        assert!(outputs.iter().all(|o| !o.request_id.starts_with('_')));
+        let tokens = final_tokens(outputs);
+        assert_eq!(engine.tok_trie.decode_str(&tokens["greedy"][0]), best.0);
+        assert_eq!(tokens["other"], [vec![3, 3]]);
+    }
+
+    #[test]
+    fn batch_shapes_parse() {
+        let batch: SyntheticBatch = "8, 128,32".parse().unwrap();
+        assert_eq!(
+            batch,
+            SyntheticBatch {
+                batch_size: 8,
+                prompt_len: 128,
+                gen_len: 32
+            }
+        );
+        assert_eq!(batch.to_string(), "8x(128+32)");
+        assert!("8,128".parse::<SyntheticBatch>().is_err());
+        assert!("8,x,32".parse::<SyntheticBatch>().is_err());
+    }
+
+    #[test]
+    fn synthetic_batches_are_reproducible() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let run = || {
+            let model_seen = seen.clone();
+            let logits_fn = move |tokens: &[Token]| {
+                model_seen.lock().unwrap().push(tokens.to_vec());
+                any_letter(tokens)
+            };
+            let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(logits_fn));
+            let batch = SyntheticBatch {
+                batch_size: 2,
+                prompt_len: 5,
+                gen_len: 3,
+            };
+            engine.bench_step(batch).unwrap();
+            std::mem::take(&mut *seen.lock().unwrap())
+        };
+        let first = run();
+        assert!(!first.is_empty());
+        assert_eq!(run(), first);
+    }
+
+    #[test]
+    fn retry_after_follows_the_queue_pace() {
+        let step = |started_groups, started_prompt_tokens| StepStats {
+            duration: Duration::from_millis(100),
+            started_groups,
+            started_prompt_tokens,
+            ..Default::default()
+        };
+        let mut tp = QueueThroughput::default();
+        assert_eq!(tp.time_to_drain(1, 0), DEFAULT_RETRY_AFTER);
+        // 2 groups with 100 prompt tokens leave the queue every 100ms
+        tp.observe(&step(2, 100));
+        assert_eq!(tp.time_to_drain(4, 0).as_millis(), 200);
+        assert_eq!(tp.time_to_drain(1, 500).as_millis(), 500);
+        assert_eq!(tp.time_to_drain(0, 1), MIN_RETRY_AFTER);
+        // and then nothing does
+        for _ in 0..1000 {
+            tp.observe(&step(0, 0));
+        }
+        assert_eq!(tp.time_to_drain(1, 0), MAX_RETRY_AFTER);
+    }
+
+    #[test]
+    fn full_queue_rejects_requests() {
+        let args = LoaderArgs {
+            max_waiting_groups: 1,
+            ..LoaderArgs::default()
+        };
+        let mut engine = toy_engine_with(args, Box::new(next_letter));
+        engine
+            .add_request_tokens("a".to_string(), vec![2, 3], greedy(3))
+            .unwrap();
+        let err = engine
+            .add_request_tokens("b".to_string(), vec![4], greedy(3))
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<AddRequestError>(),
+                Some(AddRequestError::QueueFull { .. })
+            ),
+            "{err}"
+        );
+        let stats = engine.stats();
+        assert_eq!(stats.num_waiting_groups, 1);
+        assert_eq!((stats.requests_admitted, stats.requests_rejected), (1, 1));
+
+        // once "a" leaves the queue, "b" fits
+        engine.step().unwrap();
+        assert_eq!(engine.stats().num_waiting_groups, 0);
+        engine
+            .add_request_tokens("b".to_string(), vec![4], greedy(3))
+            .unwrap();
+        let stats = engine.stats();
+        assert_eq!((stats.requests_admitted, stats.requests_rejected), (2, 1));
+    }
+
+    #[test]
+    fn stats_follow_the_steps() {
+        let mut engine = toy_engine();
+        let prompts: [&[Token]; 3] = [&[2, 3], &[4], &[5, 6, 7, 1]];
+        for (i, prompt) in prompts.iter().enumerate() {
+            engine
+                .add_request_tokens(format!("r{i}"), prompt.to_vec(), greedy(3 + 2 * i))
+                .unwrap();
+        }
+
+        // the latest output of every request
+        let mut latest: HashMap<String, RequestOutput> = HashMap::default();
+        let mut steps = 0;
+        while engine.num_pending_requests() > 0 {
+            let before = engine.stats();
+            for out in engine.step().unwrap() {
+                latest.insert(out.request_id.clone(), out);
+            }
+            steps += 1;
+            let stats = engine.stats();
+            assert_eq!(stats.num_steps, steps);
+            assert!(stats.generated_tokens > before.generated_tokens);
+            let gen_len: usize = latest
+                .values()
+                .flat_map(|out| out.seq_outputs.iter())
+                .map(|so| so.output_tokens.len())
+                .sum();
+            assert_eq!(stats.generated_tokens, gen_len, "step {steps}");
+            let usage_gen: usize = latest.values().map(|out| out.usage.gen_tokens).sum();
+            let usage_prompt: usize = latest.values().map(|out| out.usage.prompt_tokens).sum();
+            assert_eq!(stats.generated_tokens, usage_gen);
+            assert_eq!(stats.prompt_tokens, usage_prompt);
+        }
+
+        let stats = engine.stats();
+        assert_eq!(stats.generated_tokens, 3 + 5 + 7);
+        // the prompts, then one query token for every generated token but the last
+        let prompt_len: usize = prompts.iter().map(|p| p.len()).sum();
+        assert_eq!(stats.prompt_tokens, prompt_len + (3 + 5 + 7) - 3);
+        assert_eq!(stats.num_running_seqs, 0);
+        assert_eq!(stats.num_waiting_seqs, 0);
+        assert!(latest.values().all(|out| out.is_final));
+        // the toy model counts up from the last prompt token
+        let out = &latest["r0"].seq_outputs[0];
+        assert_eq!(out.output_tokens, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn outputs_report_counts_and_durations() {
+        // a slow model, so that every step takes some time
+        let slow_letter: LogitsFn = Box::new(|tokens| {
+            std::thread::sleep(Duration::from_millis(2));
+            next_letter(tokens)
+        });
+        let mut engine = toy_engine_with(LoaderArgs::default(), slow_letter);
+        let t0 = Instant::now();
+        engine
+            .add_request_tokens("short".to_string(), vec![2, 3], greedy(3))
+            .unwrap();
+        engine
+            .add_request_tokens("long".to_string(), vec![4, 5, 6, 7], greedy(8))
+            .unwrap();
+        let outputs = run_all(&mut engine);
+        let elapsed = t0.elapsed();
+
+        for (id, prompt_len, gen_len) in [("short", 2, 3), ("long", 4, 8)] {
+            let seq_outputs: Vec<_> = outputs
+                .iter()
+                .filter(|out| out.request_id == id)
+                .map(|out| out.seq_outputs[0].clone())
+                .collect();
+            // only the last output is final
+            let (last, rest) = seq_outputs.split_last().unwrap();
+            assert!(last.is_final, "{id}");
+            assert!(rest.iter().all(|so| !so.is_final), "{id}");
+            assert_eq!(last.prompt_token_count, prompt_len, "{id}");
+            assert_eq!(last.gen_token_count, gen_len, "{id}");
+            assert_eq!(last.finish_reason, Some(FinishReason::MaxTokensReached));
+
+            let total =
+                |so: &SeqOutput| so.queued_duration + so.prefill_duration + so.decode_duration;
+            for w in seq_outputs.windows(2) {
+                assert!(total(&w[0]) <= total(&w[1]), "{id}");
+                assert_eq!(w[0].queued_duration, w[1].queued_duration, "{id}");
+            }
+            assert!(last.prefill_duration > Duration::ZERO, "{id}");
+            assert!(total(last) <= elapsed, "{id}");
+            if id == "long" {
+                // the long request finishes in the last step
+                assert!(total(last) * 2 >= elapsed, "{:?} {elapsed:?}", total(last));
+                assert!(last.decode_duration > Duration::ZERO);
+            }
+        }
+    }
+
+    /// Like next_letter(), with NaN logits after a "g".
+    fn nan_after_g(tokens: &[Token]) -> Vec<f32> {
+        let mut logits = next_letter(tokens);
+        if *tokens.last().unwrap() == 8 {
+            logits[3] = f32::NAN;
+        }
+        logits
+    }
+
+    #[test]
+    fn nan_logits_finish_the_sequence() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(nan_after_g));
+        engine
+            .add_request_tokens("ok".to_string(), vec![2], greedy(4))
+            .unwrap();
+        engine
+            .add_request_tokens("nan".to_string(), vec![7], greedy(4))
+            .unwrap();
+        let mut latest: HashMap<String, RequestOutput> = HashMap::default();
+        for out in run_all(&mut engine) {
+            latest.insert(out.request_id.clone(), out);
+        }
+        // "g" is sampled, and the logits after it are not
+        let out = &latest["nan"].seq_outputs[0];
+        assert_eq!(out.output_tokens, vec![8]);
+        assert_eq!(out.finish_reason, Some(FinishReason::NumericalError));
+        // the other sequence in the batch goes on
+        let out = &latest["ok"].seq_outputs[0];
+        assert_eq!(out.output_tokens, vec![3, 4, 5, 6]);
+        assert_eq!(out.finish_reason, Some(FinishReason::MaxTokensReached));
+        assert_eq!(engine.stats().numerical_errors, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "NaN/inf logits")]
+    fn nan_logits_can_panic() {
+        let args = LoaderArgs {
+            panic_on_nan: true,
+            ..LoaderArgs::default()
+        };
+        let mut engine = toy_engine_with(args, Box::new(nan_after_g));
+        engine
+            .add_request_tokens("nan".to_string(), vec![7], greedy(4))
+            .unwrap();
+        run_all(&mut engine);
+    }
+
+    /// All the letters are equally likely.
+    fn any_letter(_tokens: &[Token]) -> Vec<f32> {
+        (0..VOCAB.len())
+            .map(|t| if t >= 2 { 0.0 } else { -100.0 })
+            .collect()
+    }
+
+    #[test]
+    fn best_of_forks_after_the_prompt() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(any_letter));
+        let params = SamplingParams {
+            n: 3,
+            best_of: 3,
+            temperature: 1.0,
+            seed: Some(7),
+            ..greedy(5)
+        };
+        engine
+            .add_request_tokens("r".to_string(), vec![2], params)
+            .unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        assert!(out.is_final);
+        assert_eq!(out.seq_outputs.len(), 3);
+        let mut indices = out.seq_outputs.iter().map(|s| s.index).collect::<Vec<_>>();
+        indices.sort();
+        assert_eq!(indices, vec![0, 1, 2]);
+        for s in &out.seq_outputs {
+            assert_eq!(s.output_tokens.len(), 5);
+            assert_eq!(s.finish_reason, Some(FinishReason::MaxTokensReached));
+            // each of the 8 letters has probability 1/8
+            let expected = 5.0 * (1.0f32 / 8.0).ln();
+            assert!(
+                (s.cumulative_logprob - expected).abs() < 1e-3,
+                "{}",
+                s.cumulative_logprob
+            );
+        }
+        // the forks are sampled independently
+        let outputs = &out.seq_outputs;
+        assert!(
+            outputs[0].output_tokens != outputs[1].output_tokens
+                || outputs[1].output_tokens != outputs[2].output_tokens
+        );
+    }
+
+    /// Outputs of three seeded requests, and the (swapped_out, recomputed) counts;
+    /// each needs 8 blocks in the end, and they don't fit in 12 together.
+    fn run_preempted(
+        mode: PreemptionMode,
+        gpu_budget: Option<usize>,
+    ) -> (Vec<(String, Vec<Token>)>, (usize, usize)) {
+        let args = LoaderArgs {
+            preemption_mode: mode,
+            ..LoaderArgs::default()
+        };
+        let mut engine = toy_engine_with(args, Box::new(any_letter));
+        engine.seq_mgr.cache.lock().unwrap().gpu_budget = gpu_budget;
+        for i in 0..3 {
+            let params = SamplingParams {
+                temperature: 1.0,
+                seed: Some(i),
+                ..greedy(20)
+            };
+            engine
+                .add_request_tokens(format!("r{i}"), vec![2; 9], params)
+                .unwrap();
+        }
+        let mut outputs = run_all(&mut engine)
+            .into_iter()
+            .filter(|o| o.is_final)
+            .map(|o| (o.request_id, o.seq_outputs[0].output_tokens.clone()))
+            .collect::<Vec<_>>();
+        outputs.sort();
+        let stats = engine.scheduler.get_priority_stats();
+        let swapped_out = stats.values().map(|s| s.swapped_out).sum();
+        let recomputed = stats.values().map(|s| s.recomputed).sum();
+        (outputs, (swapped_out, recomputed))
+    }
+
+    #[test]
+    fn preemption_modes_give_the_same_outputs() {
+        let (expected, counts) = run_preempted(PreemptionMode::Swap, None);
+        assert_eq!(counts, (0, 0));
+        assert_eq!(expected.len(), 3);
+        assert!(expected.iter().all(|(_, toks)| toks.len() == 20));
+
+        let (swapped, (swapped_out, recomputed)) = run_preempted(PreemptionMode::Swap, Some(12));
+        assert!(swapped_out > 0 && recomputed == 0);
+        assert_eq!(swapped, expected);
+
+        let (recomputed_outputs, (swapped_out, recomputed)) =
+            run_preempted(PreemptionMode::Recompute, Some(12));
+        assert!(swapped_out == 0 && recomputed > 0);
+        assert_eq!(recomputed_outputs, expected);
+    }
+
+    #[test]
+    fn listeners_get_lifecycle_events_in_order() {
+        // nothing is reserved for the running sequences, so both prompts start, and 3 blocks
+        // don't hold both sequences after the first token
+        let args = LoaderArgs {
+            admission_lookahead: 0,
+            ..LoaderArgs::default()
+        };
+        let mut engine = toy_engine_with(args, Box::new(next_letter));
+        engine.seq_mgr.cache.lock().unwrap().gpu_budget = Some(3);
+        let events = Arc::new(Mutex::new(vec![]));
+        engine.add_listener(Box::new(Recorder(events.clone())));
+        // req2 has the lower priority, so it is preempted, and recomputed once req1 is done
+        for (id, priority) in [("req1", 1), ("req2", 0)] {
+            let params = SamplingParams {
+                priority,
+                ..greedy(4)
+            };
+            engine
+                .add_request_tokens(id.to_string(), vec![2, 3, 4, 5], params)
+                .unwrap();
+        }
+        run_all(&mut engine);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "added req1",
+                "added req2",
+                "scheduled req1",
+                "scheduled req2",
+                "first token req1",
+                "first token req2",
+                "step 1 (2 seqs)",
+                "preempted req2 Recompute",
+                "step 2 (1 seqs)",
+                "step 3 (1 seqs)",
+                "step 4 (1 seqs)",
+                "finished req1 MaxTokensReached",
+                "scheduled req2",
+                "step 5 (1 seqs)",
+                "step 6 (1 seqs)",
+                "step 7 (1 seqs)",
+                "finished req2 MaxTokensReached",
+                "step 8 (0 seqs)",
+            ]
+        );
+    }
+
+    #[test]
+    fn forks_continue_after_the_shared_tokens() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(any_letter));
+        let sampled = |seed| SamplingParams {
+            temperature: 1.0,
+            seed: Some(seed),
+            ..greedy(10)
+        };
+        engine
+            .add_request_tokens("r".to_string(), vec![2], sampled(3))
+            .unwrap();
+        let mut prefix = vec![];
+        while prefix.len() < 5 {
+            for out in engine.step().unwrap() {
+                prefix = out.seq_outputs[0].output_tokens.clone();
+            }
+        }
+        assert_eq!(prefix.len(), 5);
+
+        let forks = engine
+            .fork_request_with("r", vec![None, Some(sampled(11))])
+            .unwrap();
+        assert_eq!(forks.len(), 2);
+        let mut outputs = HashMap::default();
+        for out in run_all(&mut engine) {
+            if out.is_final {
+                outputs.insert(out.request_id, out.seq_outputs[0].output_tokens.clone());
+            }
+        }
+        assert_eq!(outputs.len(), 3);
+        let tails: Vec<&[Token]> = ["r", forks[0].as_str(), forks[1].as_str()]
+            .iter()
+            .map(|id| {
+                let tokens = &outputs[*id];
+                assert_eq!(tokens.len(), 10);
+                assert_eq!(tokens[..5], prefix[..]);
+                &tokens[5..]
+            })
+            .collect();
+        // the forks are sampled independently
+        assert!(tails[0] != tails[1] || tails[1] != tails[2]);
+
+        // "r" is gone
+        assert!(engine.fork_request("r", 1).is_err());
+    }
+
+    /// A seeded request with one sequence, and one with three.
+    fn queue_seeded(engine: &mut RllmEngine<ToyExec>) {
+        let sampled = |seed, n| SamplingParams {
+            n,
+            best_of: n,
+            temperature: 1.0,
+            seed: Some(seed),
+            ..greedy(20)
+        };
+        engine
+            .add_request_tokens("one".to_string(), vec![2, 3], sampled(1, 1))
+            .unwrap();
+        engine
+            .add_request_tokens("three".to_string(), vec![4], sampled(2, 3))
+            .unwrap();
+    }
+
+    /// The tokens of each sequence (by index) of each request, when it finished.
+    fn final_tokens(outputs: Vec<RequestOutput>) -> HashMap<String, Vec<Vec<Token>>> {
+        outputs
+            .into_iter()
+            .filter(|o| o.is_final)
+            .map(|o| {
+                let mut seqs = o.seq_outputs;
+                seqs.sort_by_key(|s| s.index);
+                let tokens = seqs.into_iter().map(|s| s.output_tokens).collect();
+                (o.request_id, tokens)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restored_requests_continue_the_same_way() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(any_letter));
+        queue_seeded(&mut engine);
+        let expected = final_tokens(run_all(&mut engine));
+        assert_eq!(expected.len(), 2);
+        assert_eq!(expected["three"].len(), 3);
+
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(any_letter));
+        queue_seeded(&mut engine);
+        for _ in 0..10 {
+            engine.step().unwrap();
+        }
+        let mut buf = Vec::new();
+        engine.snapshot().write_to(&mut buf).unwrap();
+        let mut snap = EngineSnapshot::read_from(&buf[..]).unwrap();
+        assert_eq!(snap.requests.len(), 2);
+        let three = snap.requests.iter().find(|r| r.request_id == "three");
+        let seqs = &three.unwrap().seqs;
+        assert_eq!(seqs.len(), 3);
+        // mid-generation
+        assert!(seqs.iter().all(|seq| seq.tokens.len() > 1 + 5));
+        // without aicirt, controllers can't be restarted; the other requests are restored
+        let mut ctrl = snap.requests[0].clone();
+        ctrl.request_id = "ctrl".to_string();
+        ctrl.sampling_params.controller = Some("ctrl".to_string());
+        snap.requests.insert(0, ctrl);
+
+        let mut restored = toy_engine_with(LoaderArgs::default(), Box::new(any_letter));
+        let skipped = restored.restore_requests(snap).unwrap();
+        assert_eq!(skipped, vec!["ctrl".to_string()]);
+        assert_eq!(restored.num_pending_requests(), 2);
+        assert_eq!(final_tokens(run_all(&mut restored)), expected);
+    }
+
+    #[test]
+    fn native_requests_are_restored() {
+        let add = |engine: &mut RllmEngine<ToyExec>| {
+            let ctrl = RegexCtrl::new(engine.tok_trie.clone(), "[b-d]+").unwrap();
+            engine
+                .add_native_request("r".to_string(), "a", greedy(3), Box::new(ctrl))
+                .unwrap();
+        };
+        let mut engine = toy_engine();
+        add(&mut engine);
+        let expected = final_tokens(run_all(&mut engine));
+        assert_eq!(expected["r"], vec![vec![3, 4, 5]]);
+
+        let mut engine = toy_engine();
+        add(&mut engine);
+        for _ in 0..2 {
+            engine.step().unwrap();
+        }
+        let mut buf = Vec::new();
+        engine.snapshot().write_to(&mut buf).unwrap();
+        let mut snap = EngineSnapshot::read_from(&buf[..]).unwrap();
+        assert_eq!(snap.requests.len(), 1);
+        assert!(snap.requests[0].native_ctrl);
+        let seq = &snap.requests[0].seqs[0];
+        assert_eq!(seq.tokens[seq.prompt_len..], [3, 4]);
+
+        // the controller has to be given again
+        let mut restored = toy_engine();
+        let skipped = restored.restore_requests(snap.clone()).unwrap();
+        assert_eq!(skipped, vec!["r".to_string()]);
+        let ctrl = RegexCtrl::new(restored.tok_trie.clone(), "[b-d]+").unwrap();
+        let req = snap.requests.pop().unwrap();
+        restored
+            .restore_native_request(req, Box::new(ctrl))
+            .unwrap();
+        assert_eq!(final_tokens(run_all(&mut restored)), expected);
+    }
+
+    /// Like next_letter(), but wrong after "d" (continuing with "b").
+    fn next_letter_draft(tokens: &[Token]) -> Vec<f32> {
+        if tokens.last() == Some(&5) {
+            return prefer_b(tokens);
+        }
+        next_letter(tokens)
+    }
+
+    #[test]
+    fn speculation_keeps_greedy_outputs() {
+        // the tokens of a plain request and of one with a controller, and the number
+        // of accepted drafts of each
+        let run = |num_draft_tokens: usize, ctrl: bool| {
+            let args = LoaderArgs {
+                draft_model: Some(DraftModelArgs {
+                    model_id: "toy-draft".to_string(),
+                    revision: None,
+                    local_weights: None,
+                    num_tokens: num_draft_tokens,
+                }),
+                ..LoaderArgs::default()
+            };
+            let mut engine = toy_engine_with(args, Box::new(next_letter));
+            engine.tmodel.draft_fn = Some(Box::new(next_letter_draft));
+            if ctrl {
+                let ctrl = RegexCtrl::new(engine.tok_trie.clone(), "[b-f]+").unwrap();
+                engine
+                    .add_native_request("r".to_string(), "a", greedy(12), Box::new(ctrl))
+                    .unwrap();
+            } else {
+                engine
+                    .add_request("r".to_string(), "a", greedy(12))
+                    .unwrap();
+            }
+            let tokens = final_tokens(run_all(&mut engine));
+            (tokens, engine.stats().draft_tokens_accepted)
+        };
+        for ctrl in [false, true] {
+            let (expected, _) = run(0, ctrl);
+            let (tokens, accepted) = run(3, ctrl);
+            assert_eq!(tokens, expected, "ctrl: {ctrl}");
+            assert!(accepted > 0, "ctrl: {ctrl}");
+        }
+    }
+
+    #[test]
+    fn sequences_end_at_max_model_len() {
+        let mut engine = toy_engine();
+        // the longest prompt leaves room for one token
+        engine
+            .add_request_tokens("long".to_string(), vec![2; MAX_LEN - 1], greedy(10))
+            .unwrap();
+        let err = engine
+            .add_request_tokens("too-long".to_string(), vec![2; MAX_LEN], greedy(10))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AddRequestError>(),
+            Some(&AddRequestError::PromptTooLong {
+                prompt_len: MAX_LEN,
+                max: MAX_LEN - 1
+            })
+        );
+        engine
+            .add_request_tokens("short".to_string(), vec![2; MAX_LEN - 3], greedy(10))
+            .unwrap();
+
+        let outputs = run_all(&mut engine);
+        let last = |id: &str| {
+            let out = outputs.iter().rfind(|out| out.request_id == id).unwrap();
+            assert!(out.is_final);
+            out.seq_outputs[0].clone()
+        };
+        for (id, gen_len) in [("long", 1), ("short", 3)] {
+            let so = last(id);
+            assert_eq!(so.output_tokens.len(), gen_len, "{id}");
+            assert_eq!(so.finish_reason, Some(FinishReason::LengthError), "{id}");
+        }
+    }
+
+    #[test]
+    fn rejected_controllers_are_freed() {
+        let mut engine = toy_engine();
+        let req = AddRequest {
+            request_id: "ctrl".to_string(),
+            prompt: vec![2, 3],
+            sampling_params: SamplingParams {
+                controller: Some("ctrl".to_string()),
+                ..greedy(4)
+            },
+            expected: None,
+            init_result: Some(SequenceResult::from_error(String::new())),
+            auth: None,
+        };
+        // the toy engine has no aicirt; the instance is freed all the same
+        assert!(engine.queue_request(req).is_err());
+        assert_eq!(
+            engine.scheduler.get_freed_req_ids(),
+            vec!["ctrl".to_string()]
+        );
+    }
+
+    #[test]
+    fn biases_cover_padded_logits() {
+        // the model can't have fewer logits than the tokenizer has tokens
+        let logits_fn: LogitsFn = Box::new(next_letter);
+        assert!(try_toy_engine(LoaderArgs::default(), logits_fn, VOCAB.len() - 1).is_err());
+
+        // the model prefers the last padding token, which masks have to disallow
+        let padded = VOCAB.len() + 6;
+        let logits_fn: LogitsFn = Box::new(move |tokens| {
+            let mut logits = next_letter(tokens);
+            logits.resize(padded, 0.0);
+            logits[padded - 1] = 20.0;
+            logits
+        });
+        let mut engine = try_toy_engine(LoaderArgs::default(), logits_fn, padded).unwrap();
+        assert_eq!(engine.tok_trie.vocab_size(), VOCAB.len());
+        assert_eq!(engine.tok_trie.model_vocab_size(), padded);
+        let ctrl = RegexCtrl::new(engine.tok_trie.clone(), "[a-h]+").unwrap();
+        engine
+            .add_native_request("r".to_string(), "a", greedy(3), Box::new(ctrl))
+            .unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![3, 4, 5]);
+    }
+
+    /// Allows all tokens, but rejects the sampled ones in `banned`, asking for another.
+    struct Reject {
+        trie: TokTrie,
+        banned: Vec<Token>,
+        seen: Arc<Mutex<Vec<Token>>>,
+    }
+
+    impl AiciCtrl for Reject {
+        fn mid_process(&mut self, _arg: MidProcessArg) -> MidProcessResult {
+            let mut set = self.trie.alloc_token_set();
+            set.set_all(true);
+            MidProcessResult::sample(set).with_post_sample()
+        }
+
+        fn post_sample(&mut self, arg: PostSampleArg) -> PostSampleResult {
+            self.seen.lock().unwrap().push(arg.token);
+            if self.banned.contains(&arg.token) {
+                PostSampleResult::Reject { resample: true }
+            } else {
+                PostSampleResult::Accept
+            }
+        }
+    }
+
+    fn add_rejecting(
+        engine: &mut RllmEngine<ToyExec>,
+        banned: Vec<Token>,
+    ) -> Arc<Mutex<Vec<Token>>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let ctrl = Reject {
+            trie: engine.tok_trie.as_ref().clone(),
+            banned,
+            seen: seen.clone(),
+        };
+        engine
+            .add_native_request("r".to_string(), "a", greedy(3), Box::new(ctrl))
+            .unwrap();
+        seen
+    }
+
+    #[test]
+    fn rejected_tokens_are_resampled() {
+        // b is the most likely token, and then c
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(prefer_b));
+        let seen = add_rejecting(&mut engine, vec![3]);
+        let out = run_all(&mut engine).pop().unwrap();
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![4, 4, 4]);
+        assert_eq!(
+            out.seq_outputs[0].finish_reason,
+            Some(FinishReason::MaxTokensReached)
+        );
+        // every step samples b first
+        assert_eq!(*seen.lock().unwrap(), vec![3, 4, 3, 4, 3, 4]);
+        let stats = engine.stats();
+        assert_eq!(stats.post_sample_rejected, 3);
+        assert_eq!(stats.post_sample_failures, 0);
+    }
+
+    #[test]
+    fn too_many_rejections_fail_the_sequence() {
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(prefer_b));
+        let retries = engine.config.aici.max_post_sample_retries;
+        let seen = add_rejecting(&mut engine, (0..VOCAB.len() as Token).collect());
+        let out = run_all(&mut engine).pop().unwrap();
+        assert!(out.seq_outputs[0].output_tokens.is_empty());
+        assert_eq!(out.seq_outputs[0].finish_reason, Some(FinishReason::Failed));
+        assert_eq!(seen.lock().unwrap().len(), retries + 1);
+        let stats = engine.stats();
+        assert_eq!(stats.post_sample_rejected, retries + 1);
+        assert_eq!(stats.post_sample_failures, 1);
+    }
+
+    /// Asks for the top `k` logprobs, keeps the ones it gets with each token, and bans b.
+    struct TopLogprobs {
+        trie: TokTrie,
+        k: u32,
+        seen: Arc<Mutex<Vec<(Vec<Token>, Vec<(Token, f32)>)>>>,
+    }
+
+    impl AiciCtrl for TopLogprobs {
+        fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
+            InitPromptResult::default().with_top_logprobs(self.k)
+        }
+
+        fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+            self.seen
+                .lock()
+                .unwrap()
+                .push((arg.tokens.clone(), arg.top_logprobs.clone()));
+            let mut set = self.trie.alloc_token_set();
+            set.set_all(true);
+            set.disallow_token(3);
+            MidProcessResult::sample(set)
+        }
+    }
+
+    #[test]
+    fn top_logprobs_are_taken_before_the_mask() {
+        // b is the most likely token, and then c
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(prefer_b));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let ctrl = TopLogprobs {
+            trie: engine.tok_trie.as_ref().clone(),
+            k: 3,
+            seen: seen.clone(),
+        };
+        engine
+            .add_native_request("r".to_string(), "a", greedy(3), Box::new(ctrl))
+            .unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        // the mask leaves c
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![4, 4, 4]);
+
+        let lse = (3.0f32.exp() + 1.0f32.exp() + (VOCAB.len() - 2) as f32).ln();
+        let seen = seen.lock().unwrap();
+        // nothing was sampled before the first call
+        assert!(seen[0].1.is_empty(), "{seen:?}");
+        let sampled: Vec<_> = seen
+            .iter()
+            .filter(|(tokens, _)| !tokens.is_empty())
+            .collect();
+        assert!(sampled.len() >= 2, "{seen:?}");
+        for (tokens, top) in sampled {
+            assert_eq!(*tokens, vec![4]);
+            // b is reported, though the mask banned it
+            assert_eq!(top.len(), 3);
+            assert_eq!((top[0].0, top[1].0), (3, 4));
+            assert!(![3, 4].contains(&top[2].0));
+            for ((_, logprob), logit) in top.iter().zip([3.0, 1.0, 0.0]) {
+                assert!((logprob - (logit - lse)).abs() < 1e-5, "{top:?}");
+            }
+        }
+    }
+
+    /// Extends the prompt with `prompt_ff`, splices in `splice` in the first step,
+    /// and then allows all tokens; keeps the tokens it was called with.
+    struct FastForward {
+        trie: TokTrie,
+        prompt_ff: Vec<Token>,
+        splice: Vec<Token>,
+        seen: Arc<Mutex<Vec<Vec<Token>>>>,
+    }
+
+    impl AiciCtrl for FastForward {
+        fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
+            InitPromptResult::ff_tokens(self.prompt_ff.clone())
+        }
+
+        fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+            self.seen.lock().unwrap().push(arg.tokens);
+            if !self.splice.is_empty() {
+                return MidProcessResult::splice(0, std::mem::take(&mut self.splice));
+            }
+            let mut set = self.trie.alloc_token_set();
+            set.set_all(true);
+            MidProcessResult::sample(set)
+        }
+    }
+
+    fn add_fast_forward(
+        engine: &mut RllmEngine<ToyExec>,
+        prompt_ff: Vec<Token>,
+        splice: Vec<Token>,
+    ) -> Result<Arc<Mutex<Vec<Vec<Token>>>>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let ctrl = FastForward {
+            trie: engine.tok_trie.as_ref().clone(),
+            prompt_ff,
+            splice,
+            seen: seen.clone(),
+        };
+        engine.add_native_request("r".to_string(), "a", greedy(3), Box::new(ctrl))?;
+        Ok(seen)
+    }
+
+    #[test]
+    fn ctrl_ff_tokens_are_appended() {
+        // "bc" after the prompt "a"; the model continues with the letters after c
+        let mut engine = toy_engine();
+        add_fast_forward(&mut engine, vec![3, 4], vec![]).unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![5, 6, 7]);
+
+        // "f" spliced in, and then the letters after it
+        let mut engine = toy_engine();
+        let seen = add_fast_forward(&mut engine, vec![], vec![7]).unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![7, 8, 9]);
+        // and the controller is told about it
+        let seen = seen.lock().unwrap();
+        let idx = seen.iter().position(|t| *t == [7]).unwrap();
+        assert_eq!(seen[idx + 1], [8]);
+    }
+
+    #[test]
+    fn ctrl_ff_tokens_out_of_vocabulary_are_rejected() {
+        let oov = VOCAB.len() as Token;
+        let mut engine = toy_engine();
+        let err = add_fast_forward(&mut engine, vec![3, oov], vec![]).unwrap_err();
+        assert!(err.to_string().contains("out of the vocabulary"), "{err}");
+        assert_eq!(engine.num_pending_requests(), 0);
+
+        // the splice fails the sequence, before anything is appended
+        let mut engine = toy_engine();
+        add_fast_forward(&mut engine, vec![], vec![7, oov]).unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        let so = &out.seq_outputs[0];
+        assert!(so.output_tokens.is_empty());
+        match &so.finish_reason {
+            Some(FinishReason::ControllerError(msg)) => {
+                assert!(msg.contains(&format!("splice token {oov}")), "{msg}")
+            }
+            r => panic!("{r:?}"),
+        }
+    }
+
+    #[test]
+    fn ctrl_backtrack_frees_blocks() {
+        let mut engine = toy_engine();
+        engine.seq_mgr.cache.lock().unwrap().gpu_budget = Some(10);
+        let ctrl = FastForward {
+            trie: engine.tok_trie.as_ref().clone(),
+            prompt_ff: vec![],
+            splice: vec![],
+            seen: Arc::new(Mutex::new(Vec::new())),
+        };
+        // in the sixth step, with "abcdef" (2 blocks), back to "ab", and "a" after it
+        let ctrl = SpliceAt::new(ctrl, 5, 4, vec![2]);
+        engine
+            .add_native_request("r".to_string(), "a", greedy(6), Box::new(ctrl))
+            .unwrap();
+        let mut held = vec![];
+        let mut outputs = vec![];
+        while engine.num_pending_requests() > 0 {
+            outputs.extend(engine.step().unwrap());
+            let free = engine.scheduler.block_manager.get_num_free_gpu_blocks();
+            held.push(10 - free);
+        }
+        assert_eq!(held[..7], [1, 1, 1, 1, 2, 1, 1]);
+        assert_eq!(*held.last().unwrap(), 0);
+        let out = outputs.pop().unwrap();
+        assert_eq!(out.seq_outputs[0].output_tokens, vec![3, 2, 3, 4, 5, 6]);
+    }
+
+    /// Allows all tokens, taking `delay` in every call; keeps MidProcessArg::prev_timed_out.
+    struct Slow {
+        trie: TokTrie,
+        delay: Duration,
+        timed_out: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl AiciCtrl for Slow {
+        fn init_prompt(&mut self, _arg: InitPromptArg) -> InitPromptResult {
+            std::thread::sleep(self.delay);
+            InitPromptResult::default()
+        }
+
+        fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+            self.timed_out.lock().unwrap().push(arg.prev_timed_out);
+            std::thread::sleep(self.delay);
+            let mut set = self.trie.alloc_token_set();
+            set.set_all(true);
+            MidProcessResult::sample(set)
+        }
+    }
+
+    #[test]
+    fn late_native_controllers_fail() {
+        let add_slow = |aici: AiciConfig| {
+            let args = LoaderArgs {
+                aici,
+                ..LoaderArgs::default()
+            };
+            let mut engine = toy_engine_with(args, Box::new(next_letter));
+            let timed_out = Arc::new(Mutex::new(Vec::new()));
+            let ctrl = Slow {
+                trie: engine.tok_trie.as_ref().clone(),
+                delay: Duration::from_millis(5),
+                timed_out: timed_out.clone(),
+            };
+            let r = engine.add_native_request("r".to_string(), "a", greedy(10), Box::new(ctrl));
+            (engine, r, timed_out)
+        };
+
+        // late in 2 steps is fine, not in the third one
+        let (mut engine, r, timed_out) = add_slow(AiciConfig {
+            native_max_step_ms: 1,
+            native_max_timeout_steps: 2,
+            ..AiciConfig::default()
+        });
+        r.unwrap();
+        let out = run_all(&mut engine).pop().unwrap();
+        let so = &out.seq_outputs[0];
+        assert_eq!(so.output_tokens, vec![3, 4]);
+        assert_eq!(so.finish_reason, Some(FinishReason::Failed));
+        assert_eq!(*timed_out.lock().unwrap(), vec![false, true, true]);
+
+        let (_, r, _) = add_slow(AiciConfig {
+            native_max_init_ms: 1,
+            ..AiciConfig::default()
+        });
+        let err = r.unwrap_err();
+        assert!(err.to_string().contains("over the limit"), "{err}");
+    }
+
+    /// Bans the tokens, and keeps the outputs it was called with; fails with `fail`.
+    #[derive(Default)]
+    struct BanHook {
+        banned: Vec<Token>,
+        fail: bool,
+        calls: Mutex<Vec<Vec<Token>>>,
+    }
+
+    impl SamplerHook for BanHook {
+        fn adjust(&self, bias: &mut HookBias, ctx: &SamplerCtx) -> Result<()> {
+            self.calls.lock().unwrap().push(ctx.output_tokens.clone());
+            if self.fail {
+                bail!("hook failed");
+            }
+            for t in &self.banned {
+                bias.ban(*t);
+            }
+            Ok(())
+        }
+    }
+
+    fn run_hooked(params: SamplingParams, hook: BanHook) -> (SeqOutput, Arc<BanHook>) {
+        // b is the most likely token, and then c
+        let mut engine = toy_engine_with(LoaderArgs::default(), Box::new(prefer_b));
+        let hook = Arc::new(hook);
+        let params = SamplingParams {
+            sampler_hook: Some(hook.clone()),
+            ..params
+        };
+        engine
+            .add_request_tokens("h".to_string(), vec![2], params)
+            .unwrap();
+        let mut out = run_all(&mut engine).pop().unwrap();
+        (out.seq_outputs.pop().unwrap(), hook)
+    }
+
+    #[test]
+    fn sampler_hook_bans_tokens() {
+        let ban_b = || BanHook {
+            banned: vec![3],
+            ..BanHook::default()
+        };
+        let (out, hook) = run_hooked(greedy(5), ban_b());
+        assert_eq!(out.output_tokens, vec![4; 5]);
+        // called before each token, with the ones before it
+        let calls = hook.calls.lock().unwrap();
+        assert_eq!(*calls, (0..5).map(|n| vec![4; n]).collect::<Vec<_>>());
+
+        let sampled = SamplingParams {
+            temperature: 1.0,
+            seed: Some(1),
+            min_tokens: 20,
+            ..greedy(20)
+        };
+        let (out, hook) = run_hooked(sampled.clone(), ban_b());
+        assert_eq!(out.output_tokens.len(), 20);
+        assert!(!out.output_tokens.contains(&3));
+        assert_eq!(hook.calls.lock().unwrap().len(), 20);
+        // b is sampled without it
+        let (out, _) = run_hooked(sampled, BanHook::default());
+        assert!(out.output_tokens.contains(&3));
+    }
+
+    #[test]
+    fn sampler_hook_failures_fail_the_sequence() {
+        let (out, _) = run_hooked(
+            greedy(5),
+            BanHook {
+                fail: true,
+                ..BanHook::default()
+            },
+        );
+        assert!(out.output_tokens.is_empty());
+        assert_eq!(out.finish_reason, Some(FinishReason::Failed));
+
+        // top-k leaves only b, which the hook bans
+        let params = SamplingParams {
+            temperature: 1.0,
+            top_k: 1,
+            ..greedy(5)
+        };
+        let ban_b = BanHook {
+            banned: vec![3],
+            ..BanHook::default()
+        };
+        let (out, hook) = run_hooked(params, ban_b);
+        assert!(out.output_tokens.is_empty());
+        assert_eq!(out.finish_reason, Some(FinishReason::Failed));
+        assert_eq!(hook.calls.lock().unwrap().len(), 1);
     }
 }
@@ -0,0 +1,108 @@
+//! Which tokens end generation; see resolve_eos_tokens().
+
+use crate::{seq::Token, Repo};
+use aicirt::bintokens::EOS_TOKENS;
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// Files with `eos_token_id`, in the order they are consulted.
+const EOS_CONFIG_FILES: &[&str] = &["generation_config.json", "config.json"];
+
+/// `eos_token_id` of a model config: a single id, or a list of them (like in Llama 3).
+fn eos_ids_from_config(config: &Value) -> Option<Vec<Token>> {
+    let ids = match config.get("eos_token_id")? {
+        Value::Array(ids) => ids
+            .iter()
+            .map(|id| id.as_u64())
+            .collect::<Option<Vec<_>>>()?,
+        id => vec![id.as_u64()?],
+    };
+    if ids.is_empty() {
+        None
+    } else {
+        Some(ids.into_iter().map(|id| id as Token).collect())
+    }
+}
+
+/// The tokens that end generation: `eos_token_id` from generation_config.json,
+/// or else from config.json, or else all the well-known EOS tokens
+/// (bintokens::EOS_TOKENS) the tokenizer has.
+/// The first one is the EOS token the controllers see.
+pub fn resolve_eos_tokens(
+    repo: &Repo,
+    token_to_id: impl Fn(&str) -> Option<Token>,
+) -> Result<Vec<Token>> {
+    for file in EOS_CONFIG_FILES {
+        let ids = repo
+            .read(file)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok())
+            .and_then(|config| eos_ids_from_config(&config));
+        if let Some(ids) = ids {
+            log::info!("EOS tokens from {file}: {ids:?}");
+            return Ok(ids);
+        }
+    }
+    let ids: Vec<Token> = EOS_TOKENS.iter().filter_map(|&t| token_to_id(t)).collect();
+    if ids.is_empty() {
+        bail!(
+            "can't find the EOS token: no eos_token_id in {}, and the tokenizer has none of {}",
+            EOS_CONFIG_FILES.join(" or "),
+            EOS_TOKENS.join(" ")
+        );
+    }
+    log::info!("EOS tokens from the tokenizer: {ids:?}");
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_eos_tokens;
+    use crate::Repo;
+    use std::path::Path;
+
+    fn fixture(name: &str) -> Repo {
+        Repo::Local(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/eos")
+                .join(name),
+        )
+    }
+
+    #[test]
+    fn eos_from_model_configs() {
+        let no_tokens = |_: &str| None;
+        assert_eq!(
+            resolve_eos_tokens(&fixture("llama-2"), no_tokens).unwrap(),
+            vec![2]
+        );
+        // generation_config.json lists the end of turn token too
+        assert_eq!(
+            resolve_eos_tokens(&fixture("llama-3"), no_tokens).unwrap(),
+            vec![128001, 128009]
+        );
+        // only in config.json
+        assert_eq!(
+            resolve_eos_tokens(&fixture("gpt-neox"), no_tokens).unwrap(),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn eos_from_tokenizer() {
+        let repo = fixture("no-such-model");
+        let vocab = |t: &str| match t {
+            "<|im_end|>" => Some(7),
+            "<|endoftext|>" => Some(3),
+            _ => None,
+        };
+        assert_eq!(resolve_eos_tokens(&repo, vocab).unwrap(), vec![3, 7]);
+
+        let err = resolve_eos_tokens(&repo, |_| None).unwrap_err().to_string();
+        assert!(
+            err.contains("generation_config.json or config.json"),
+            "{err}"
+        );
+        assert!(err.contains("</s> <|endoftext|>"), "{err}");
+    }
+}
@@ -1,13 +1,13 @@
 use std::{fmt::Display, sync::Arc};
 
 use aicirt::TimerRef;
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::{
     config::{ModelMeta, RllmConfig},
-    scheduler::SchedulerOutputs,
+    scheduler::{CachePoolUsage, SchedulerOutputs, SeqCacheUsage},
     seq::{Sequence, SequenceGroup},
-    HashMap, LoaderArgs, LogitsProcessor, RllmEngine,
+    HashMap, LoaderArgs, RllmEngine, SampleRow,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -38,7 +38,9 @@ impl Display for SeqId {
 pub trait SequenceManager {
     fn new_sequence(&self) -> SeqId;
     fn copy(&self, src: SeqId, dst: SeqId, length: usize);
-    fn trim(&self, seq: SeqId, length: usize);
+    /// Returns the number of tokens that still have KV computed;
+    /// can be less than `length` if the backend already dropped some of it.
+    fn trim(&self, seq: SeqId, length: usize) -> usize;
     fn delete(&self, seq: SeqId);
 }
 
@@ -74,15 +76,102 @@ pub trait ModelExec: Sized {
     fn get_logits(&self, seq_id: usize) -> Self::Tensor;
     fn finalize_run(&mut self) -> Result<()>;
 
+    /// Logits `back` positions before the last query token of the sequence; run() computes
+    /// them for the last Sequence::num_draft_tokens() + 1 positions. get_logits() is `back` 0.
+    fn get_logits_at(&self, seq_id: usize, back: usize) -> Self::Tensor {
+        assert!(back == 0, "no logits before the last token");
+        self.get_logits(seq_id)
+    }
+
+    /// Run the draft model (LoaderArgs::draft_model) on the sequences, and return the most
+    /// likely next token of each. The engine calls it again after appending the tokens,
+    /// before run() verifies all of them; the backend keeps the KV cache of the draft model
+    /// in step with the sequences (computing it for all of their tokens the first time).
+    fn draft_next_tokens(&mut self, _seqs: &[&Sequence]) -> Result<Vec<u32>> {
+        bail!("no draft model")
+    }
+
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias;
-    fn new_bias(&self, slice: &'static [f32], num_seqs: usize, vocab_size: usize)
-        -> Self::AiciBias;
+    fn new_bias(&self, slice: &[f32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias;
+
+    /// For each row of logits (as returned by get_logits()), check if it's all finite.
+    /// The default copies every row to the host.
+    fn logits_finite(&self, logits: &[Self::Tensor]) -> Vec<bool> {
+        logits
+            .iter()
+            .map(|l| Self::tensor_to_vec1(l).iter().all(|x| x.is_finite()))
+            .collect()
+    }
+
+    /// Log-probability of the token in each row of logits (the softmax is over the row).
+    /// The default copies every row to the host.
+    fn token_logprobs(&self, logits: &[Self::Tensor], tokens: &[u32]) -> Vec<f32> {
+        assert!(logits.len() == tokens.len());
+        logits
+            .iter()
+            .zip(tokens)
+            .map(|(l, t)| {
+                let l = Self::tensor_to_vec1(l);
+                let max = l.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+                let lse = max + l.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+                l[*t as usize] - lse
+            })
+            .collect()
+    }
 
-    fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
+    /// The `ks[i]` most likely tokens in row `i` of logits, with their log-probabilities,
+    /// most likely first. The default copies every row to the host.
+    fn top_logprobs(&self, logits: &[Self::Tensor], ks: &[usize]) -> Vec<Vec<(u32, f32)>> {
+        assert!(logits.len() == ks.len());
+        logits
+            .iter()
+            .zip(ks)
+            .map(|(l, k)| {
+                let l = Self::tensor_to_vec1(l);
+                let max = l.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+                let lse = max + l.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+                let mut top = (0..l.len() as u32).collect::<Vec<_>>();
+                let k = std::cmp::min(*k, top.len());
+                if k > 0 && k < top.len() {
+                    top.select_nth_unstable_by(k - 1, |a, b| {
+                        l[*b as usize].total_cmp(&l[*a as usize])
+                    });
+                }
+                top.truncate(k);
+                top.sort_by(|a, b| l[*b as usize].total_cmp(&l[*a as usize]));
+                top.into_iter().map(|t| (t, l[t as usize] - lse)).collect()
+            })
+            .collect()
+    }
+
+    /// Sample one token for each row of logits (as returned by get_logits() and biased).
+    /// The hooks were already called; their SampleRow::hook_bias is applied here, like in
+    /// SampleRow::sample(). Backends can fall back to SampleRow::sample() for parameters
+    /// they don't implement on the device.
+    /// The default copies every row to the host and samples there.
+    fn sample_batch(&self, logits: &[Self::Tensor], rows: &[SampleRow]) -> Result<Vec<u32>> {
+        assert!(logits.len() == rows.len());
+        Ok(logits
+            .iter()
+            .zip(rows)
+            .map(|(l, row)| row.sample(&Self::tensor_to_vec1(l)))
+            .collect())
+    }
+
+    /// Wait for the work queued by run() to finish; used when timing the forward pass.
+    fn synchronize(&self) {}
+
+    /// Batch sizes (numbers of sequences) the model has specialized code paths for;
+    /// RllmEngine::warmup() runs all of them by default.
+    fn batch_size_buckets(&self) -> Vec<usize> {
+        vec![1]
+    }
 }
 
 pub trait TBlockSpaceManager<ME: ModelExec> {
-    fn can_allocate(&self, _seq_group: &SequenceGroup) -> bool;
+    /// Check if the prompt of the group fits, leaving the watermark and
+    /// `reserved_blocks` (for the future needs of running sequences) free.
+    fn can_allocate(&self, _seq_group: &SequenceGroup, reserved_blocks: usize) -> bool;
     fn allocate(&mut self, seq_group: &mut SequenceGroup);
 
     fn can_append_slot(&self, _seq_group: &SequenceGroup) -> bool;
@@ -90,6 +179,51 @@ pub trait TBlockSpaceManager<ME: ModelExec> {
     fn get_num_free_gpu_blocks(&self) -> usize;
     fn get_num_free_cpu_blocks(&self) -> usize;
 
+    /// Number of GPU blocks to allocate on top of the current ones, so that the sequence
+    /// can hold `num_tokens` tokens more than it has now; 0 if not applicable.
+    fn num_blocks_to_grow(&self, _seq: &Sequence, _num_tokens: usize) -> usize {
+        0
+    }
+
+    /// Total number of KV cache blocks; 0 if not applicable.
+    fn get_num_gpu_blocks(&self) -> usize {
+        0
+    }
+    fn get_num_cpu_blocks(&self) -> usize {
+        0
+    }
+
+    /// Number of (hit, missed) full prompt blocks looked up in prefix cache.
+    fn get_prefix_cache_stats(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    /// Blocks charged to the sequence; zeros if not applicable.
+    fn seq_cache_usage(&self, _seq: &Sequence) -> SeqCacheUsage {
+        SeqCacheUsage::default()
+    }
+
+    /// Usage of the (GPU, CPU) pools; without block tracking, only the counts are set.
+    fn pool_usage(&self) -> (CachePoolUsage, CachePoolUsage) {
+        let pool = |num_blocks, free_blocks| CachePoolUsage {
+            num_blocks,
+            free_blocks,
+            ..Default::default()
+        };
+        (
+            pool(self.get_num_gpu_blocks(), self.get_num_free_gpu_blocks()),
+            pool(self.get_num_cpu_blocks(), self.get_num_free_cpu_blocks()),
+        )
+    }
+
+    fn get_watermark_blocks(&self) -> usize {
+        0
+    }
+
+    /// Check the incrementally maintained block counters against a scan of all blocks;
+    /// panics on mismatch. See the cache_check_steps setting.
+    fn check_cache_counters(&self) {}
+
     fn can_swap_in(&self, _seq_group: &SequenceGroup) -> bool {
         false
     }
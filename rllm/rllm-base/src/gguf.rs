@@ -0,0 +1,1027 @@
+//! Reading GGUF files (as used by llama.cpp): metadata, tensors (dequantized to f32),
+//! and the tokenizer, converted to what loading a HuggingFace checkpoint gives.
+
+use crate::HashMap;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+// ggml tensor types
+const GGML_F32: u32 = 0;
+const GGML_F16: u32 = 1;
+const GGML_Q4_0: u32 = 2;
+const GGML_Q4_1: u32 = 3;
+const GGML_Q8_0: u32 = 8;
+const GGML_BF16: u32 = 30;
+
+// tokenizer.ggml.token_type
+const TOKEN_UNKNOWN: i32 = 2;
+const TOKEN_CONTROL: i32 = 3;
+const TOKEN_USER_DEFINED: i32 = 4;
+const TOKEN_BYTE: i32 = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            GgufValue::F32(v) => Some(v as f64),
+            GgufValue::F64(v) => Some(v),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    /// In ggml order: the first dimension is the innermost (contiguous) one.
+    pub dims: Vec<u64>,
+    pub ggml_type: u32,
+    /// Relative to the start of the tensor data.
+    offset: u64,
+}
+
+impl GgufTensorInfo {
+    pub fn num_elements(&self) -> usize {
+        self.dims.iter().product::<u64>() as usize
+    }
+
+    /// Dimensions in the (row-major) order of torch and safetensors.
+    pub fn shape(&self) -> Vec<usize> {
+        self.dims.iter().rev().map(|&d| d as usize).collect()
+    }
+
+    // (elements per block, bytes per block)
+    fn block_layout(&self) -> Result<(usize, usize)> {
+        Ok(match self.ggml_type {
+            GGML_F32 => (1, 4),
+            GGML_F16 | GGML_BF16 => (1, 2),
+            GGML_Q4_0 => (32, 18),
+            GGML_Q4_1 => (32, 20),
+            GGML_Q8_0 => (32, 34),
+            t => bail!("tensor {}: unsupported ggml type {t}", self.name),
+        })
+    }
+
+    fn num_bytes(&self) -> Result<usize> {
+        let (block_elts, block_bytes) = self.block_layout()?;
+        let n = self.num_elements();
+        ensure!(
+            n.is_multiple_of(block_elts),
+            "tensor {}: {n} elements don't fill whole blocks of {block_elts}",
+            self.name
+        );
+        Ok(n / block_elts * block_bytes)
+    }
+}
+
+/// A weight of a GGUF file, named and shaped like in the HuggingFace checkpoint.
+pub struct HfWeight {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+pub struct GgufFile {
+    data: memmap2::Mmap,
+    pub version: u32,
+    metadata: HashMap<String, GgufValue>,
+    tensors: Vec<GgufTensorInfo>,
+    data_offset: usize,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| anyhow!("unexpected end of file at offset {}", self.pos))?;
+        let r = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(r)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let n = self.u64()?;
+        // every element takes at least a byte, so this also bounds allocations
+        ensure!(
+            n <= (self.data.len() - self.pos) as u64,
+            "length {n} at offset {} past the end of file",
+            self.pos
+        );
+        Ok(n as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.len()?;
+        let offset = self.pos;
+        String::from_utf8(self.bytes(n)?.to_vec())
+            .map_err(|_| anyhow!("invalid UTF-8 in string at offset {offset}"))
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::U8(self.array::<1>()?[0]),
+            1 => GgufValue::I8(self.array::<1>()?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => GgufValue::Bool(self.array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let elt_type = self.u32()?;
+                ensure!(elt_type != 9, "nested arrays are not supported");
+                let n = self.len()?;
+                let mut elts = Vec::with_capacity(n);
+                for _ in 0..n {
+                    elts.push(self.value(elt_type)?);
+                }
+                GgufValue::Array(elts)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+            t => bail!("unknown metadata value type {t}"),
+        })
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    half::f16::from_bits(bits).to_f32()
+}
+
+fn bf16_to_f32(bits: u16) -> f32 {
+    f32::from_bits((bits as u32) << 16)
+}
+
+fn dequantize(ggml_type: u32, src: &[u8], dst: &mut Vec<f32>) {
+    let f16_at = |b: &[u8], i: usize| f16_to_f32(u16::from_le_bytes([b[i], b[i + 1]]));
+    match ggml_type {
+        GGML_F32 => dst.extend(
+            src.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        ),
+        GGML_F16 => dst.extend(src.chunks_exact(2).map(|b| f16_at(b, 0))),
+        GGML_BF16 => dst.extend(
+            src.chunks_exact(2)
+                .map(|b| bf16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+        ),
+        // low nibbles are the first 16 elements of a block, high nibbles the rest
+        GGML_Q4_0 => {
+            for blk in src.chunks_exact(18) {
+                let d = f16_at(blk, 0);
+                let qs = &blk[2..];
+                dst.extend(qs.iter().map(|q| ((q & 0xf) as i32 - 8) as f32 * d));
+                dst.extend(qs.iter().map(|q| ((q >> 4) as i32 - 8) as f32 * d));
+            }
+        }
+        GGML_Q4_1 => {
+            for blk in src.chunks_exact(20) {
+                let (d, m) = (f16_at(blk, 0), f16_at(blk, 2));
+                let qs = &blk[4..];
+                dst.extend(qs.iter().map(|q| (q & 0xf) as f32 * d + m));
+                dst.extend(qs.iter().map(|q| (q >> 4) as f32 * d + m));
+            }
+        }
+        GGML_Q8_0 => {
+            for blk in src.chunks_exact(34) {
+                let d = f16_at(blk, 0);
+                dst.extend(blk[2..].iter().map(|q| *q as i8 as f32 * d));
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Undo the reordering of the rows of Q and K projections done by llama.cpp's
+/// conversion (which makes the rotary embedding act on adjacent pairs of values).
+fn unpermute_rows(data: &[f32], num_rows: usize, num_heads: usize) -> Result<Vec<f32>> {
+    ensure!(
+        num_heads > 0 && num_rows.is_multiple_of(2 * num_heads),
+        "can't split {num_rows} rows into {num_heads} heads"
+    );
+    let row_len = data.len() / num_rows;
+    let half = num_rows / num_heads / 2;
+    let mut res = Vec::with_capacity(data.len());
+    for head in 0..num_heads {
+        for part in 0..2 {
+            for i in 0..half {
+                let src = head * 2 * half + i * 2 + part;
+                res.extend_from_slice(&data[src * row_len..(src + 1) * row_len]);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Name of the tensor in HuggingFace Llama checkpoints.
+fn llama_hf_name(name: &str) -> Option<String> {
+    let fixed = match name {
+        "token_embd.weight" => Some("model.embed_tokens.weight"),
+        "output_norm.weight" => Some("model.norm.weight"),
+        "output.weight" => Some("lm_head.weight"),
+        _ => None,
+    };
+    if let Some(n) = fixed {
+        return Some(n.to_string());
+    }
+    let rest = name.strip_prefix("blk.")?;
+    let (layer, rest) = rest.split_once('.')?;
+    let layer: usize = layer.parse().ok()?;
+    let hf = match rest {
+        "attn_norm.weight" => "input_layernorm.weight",
+        "attn_q.weight" => "self_attn.q_proj.weight",
+        "attn_k.weight" => "self_attn.k_proj.weight",
+        "attn_v.weight" => "self_attn.v_proj.weight",
+        "attn_output.weight" => "self_attn.o_proj.weight",
+        "ffn_norm.weight" => "post_attention_layernorm.weight",
+        "ffn_gate.weight" => "mlp.gate_proj.weight",
+        "ffn_up.weight" => "mlp.up_proj.weight",
+        "ffn_down.weight" => "mlp.down_proj.weight",
+        _ => return None,
+    };
+    Some(format!("model.layers.{layer}.{hf}"))
+}
+
+impl GgufFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("can't open {path:?}"))?;
+        let data = unsafe { memmap2::MmapOptions::new().map(&file)? };
+        Self::parse(data).with_context(|| format!("invalid GGUF file {path:?}"))
+    }
+
+    fn parse(data: memmap2::Mmap) -> Result<Self> {
+        let mut r = Reader {
+            data: &data,
+            pos: 0,
+        };
+        ensure!(r.bytes(4)? == MAGIC, "not a GGUF file");
+        let version = r.u32()?;
+        ensure!(
+            version == 2 || version == 3,
+            "unsupported GGUF version {version}"
+        );
+        let num_tensors = r.len()?;
+        let num_kv = r.len()?;
+
+        let mut metadata = HashMap::default();
+        for _ in 0..num_kv {
+            let key = r.string()?;
+            let value_type = r.u32()?;
+            let value = r
+                .value(value_type)
+                .with_context(|| format!("metadata {key}"))?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::with_capacity(num_tensors);
+        for _ in 0..num_tensors {
+            let name = r.string()?;
+            let num_dims = r.u32()?;
+            ensure!(num_dims <= 4, "tensor {name}: {num_dims} dimensions");
+            let dims = (0..num_dims).map(|_| r.u64()).collect::<Result<Vec<_>>>()?;
+            let ggml_type = r.u32()?;
+            let offset = r.u64()?;
+            tensors.push(GgufTensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            Some(v) => v
+                .as_u64()
+                .filter(|a| *a > 0)
+                .ok_or_else(|| anyhow!("invalid general.alignment {v:?}"))?,
+            None => DEFAULT_ALIGNMENT,
+        };
+        let data_offset = (r.pos as u64).next_multiple_of(alignment) as usize;
+        let data_len = data.len().saturating_sub(data_offset) as u64;
+        for t in &tensors {
+            let end = t.offset.checked_add(t.num_bytes()? as u64);
+            ensure!(
+                t.offset % alignment == 0 && end.is_some_and(|end| end <= data_len),
+                "tensor {}: data at {} past the end of file",
+                t.name,
+                t.offset
+            );
+        }
+
+        Ok(GgufFile {
+            data,
+            version,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    fn get(&self, key: &str) -> Result<&GgufValue> {
+        self.metadata(key)
+            .ok_or_else(|| anyhow!("missing metadata {key}"))
+    }
+
+    fn get_u64(&self, key: &str) -> Result<u64> {
+        let v = self.get(key)?;
+        v.as_u64()
+            .ok_or_else(|| anyhow!("metadata {key}: expecting an integer, got {v:?}"))
+    }
+
+    fn get_str(&self, key: &str) -> Result<&str> {
+        let v = self.get(key)?;
+        v.as_str()
+            .ok_or_else(|| anyhow!("metadata {key}: expecting a string, got {v:?}"))
+    }
+
+    fn get_array(&self, key: &str) -> Result<&[GgufValue]> {
+        let v = self.get(key)?;
+        v.as_array()
+            .ok_or_else(|| anyhow!("metadata {key}: expecting an array"))
+    }
+
+    pub fn architecture(&self) -> Result<&str> {
+        self.get_str("general.architecture")
+    }
+
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// Values of the tensor, in row-major order (see GgufTensorInfo::shape()).
+    pub fn dequantize(&self, t: &GgufTensorInfo) -> Result<Vec<f32>> {
+        let start = self.data_offset + t.offset as usize;
+        let src = &self.data[start..start + t.num_bytes()?];
+        let mut res = Vec::with_capacity(t.num_elements());
+        dequantize(t.ggml_type, src, &mut res);
+        Ok(res)
+    }
+
+    fn llama_u64(&self, key: &str) -> Result<u64> {
+        self.get_u64(&format!("llama.{key}"))
+    }
+
+    fn ensure_llama(&self) -> Result<()> {
+        let arch = self.architecture()?;
+        ensure!(
+            arch == "llama",
+            "unsupported GGUF architecture {arch:?}; only llama is supported"
+        );
+        Ok(())
+    }
+
+    fn vocab_size(&self) -> Result<u64> {
+        match self.llama_u64("vocab_size") {
+            Ok(n) => Ok(n),
+            Err(_) => Ok(self.get_array("tokenizer.ggml.tokens")?.len() as u64),
+        }
+    }
+
+    /// The config.json of the equivalent HuggingFace checkpoint.
+    /// The weights are dequantized when loaded, so they are reported as float16.
+    pub fn hf_config(&self) -> Result<Value> {
+        self.ensure_llama()?;
+        let hidden_size = self.llama_u64("embedding_length")?;
+        let num_heads = self.llama_u64("attention.head_count")?;
+        let num_kv_heads = self
+            .llama_u64("attention.head_count_kv")
+            .unwrap_or(num_heads);
+        ensure!(
+            num_heads > 0 && hidden_size % num_heads == 0,
+            "embedding length {hidden_size} not divisible by {num_heads} heads"
+        );
+        if let Ok(rope_dims) = self.llama_u64("rope.dimension_count") {
+            ensure!(
+                rope_dims == hidden_size / num_heads,
+                "partial rotary embeddings ({rope_dims} dimensions) are not supported"
+            );
+        }
+        let rope_theta = match self.metadata("llama.rope.freq_base") {
+            Some(v) => v
+                .as_f64()
+                .ok_or_else(|| anyhow!("invalid llama.rope.freq_base {v:?}"))?,
+            None => 10_000.0,
+        };
+        let eps_key = "llama.attention.layer_norm_rms_epsilon";
+        let rms_norm_eps = self
+            .get(eps_key)?
+            .as_f64()
+            .ok_or_else(|| anyhow!("invalid {eps_key}"))?;
+        Ok(json!({
+            "model_type": "llama",
+            "hidden_size": hidden_size,
+            "intermediate_size": self.llama_u64("feed_forward_length")?,
+            "vocab_size": self.vocab_size()?,
+            "num_hidden_layers": self.llama_u64("block_count")?,
+            "num_attention_heads": num_heads,
+            "num_key_value_heads": num_kv_heads,
+            "rms_norm_eps": rms_norm_eps,
+            "max_position_embeddings": self.llama_u64("context_length")?,
+            "rope_theta": rope_theta,
+            "torch_dtype": "float16",
+        }))
+    }
+
+    /// The weights, dequantized, with the names and layout of a HuggingFace checkpoint.
+    /// Models without a separate output layer get the token embeddings as lm_head.
+    pub fn hf_weights(&self) -> Result<impl Iterator<Item = Result<HfWeight>> + '_> {
+        self.ensure_llama()?;
+        let num_heads = self.llama_u64("attention.head_count")? as usize;
+        let num_kv_heads = self
+            .llama_u64("attention.head_count_kv")
+            .map_or(num_heads, |n| n as usize);
+        let mut names: Vec<_> = self.tensors.iter().map(|t| (t, None)).collect();
+        if self.tensor_info("output.weight").is_none() {
+            if let Some(emb) = self.tensor_info("token_embd.weight") {
+                names.push((emb, Some("lm_head.weight".to_string())));
+            }
+        }
+        Ok(names.into_iter().map(move |(t, name)| {
+            let name = name
+                .or_else(|| llama_hf_name(&t.name))
+                .ok_or_else(|| anyhow!("unknown GGUF tensor {}", t.name))?;
+            self.hf_weight(t, name, num_heads, num_kv_heads)
+        }))
+    }
+
+    fn hf_weight(
+        &self,
+        t: &GgufTensorInfo,
+        name: String,
+        num_heads: usize,
+        num_kv_heads: usize,
+    ) -> Result<HfWeight> {
+        let shape = t.shape();
+        let mut data = self.dequantize(t)?;
+        if name.ends_with("q_proj.weight") {
+            data = unpermute_rows(&data, shape[0], num_heads)?;
+        } else if name.ends_with("k_proj.weight") {
+            data = unpermute_rows(&data, shape[0], num_kv_heads)?;
+        }
+        Ok(HfWeight { name, shape, data })
+    }
+
+    /// The tokenizer.json equivalent to the tokenizer.ggml.* metadata; "llama"
+    /// (sentencepiece BPE with byte fallback) and "gpt2" (byte-level BPE) are supported.
+    pub fn tokenizer_json(&self) -> Result<Value> {
+        let model = self.get_str("tokenizer.ggml.model")?;
+        let tokens = self
+            .get_array("tokenizer.ggml.tokens")?
+            .iter()
+            .map(|t| {
+                t.as_str()
+                    .ok_or_else(|| anyhow!("tokenizer.ggml.tokens: expecting strings"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let token_types = match self.metadata("tokenizer.ggml.token_type") {
+            Some(v) => {
+                let types = v
+                    .as_array()
+                    .and_then(|a| {
+                        a.iter()
+                            .map(|t| t.as_u64().map(|t| t as i32))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or_else(|| anyhow!("invalid tokenizer.ggml.token_type"))?;
+                ensure!(
+                    types.len() == tokens.len(),
+                    "tokenizer.ggml.token_type: {} types for {} tokens",
+                    types.len(),
+                    tokens.len()
+                );
+                types
+            }
+            None => vec![1; tokens.len()],
+        };
+
+        let added_tokens: Vec<Value> = tokens
+            .iter()
+            .zip(&token_types)
+            .enumerate()
+            .filter(|(_, (_, &tt))| {
+                tt == TOKEN_CONTROL || tt == TOKEN_UNKNOWN || tt == TOKEN_USER_DEFINED
+            })
+            .map(|(id, (content, &tt))| {
+                json!({
+                    "id": id,
+                    "content": content,
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": tt == TOKEN_USER_DEFINED,
+                    "special": tt != TOKEN_USER_DEFINED,
+                })
+            })
+            .collect();
+        let vocab: serde_json::Map<String, Value> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, t)| (t.to_string(), json!(id)))
+            .collect();
+        ensure!(
+            vocab.len() == tokens.len(),
+            "tokenizer.ggml.tokens has duplicates"
+        );
+
+        let res = match model {
+            "llama" => {
+                let scores = self
+                    .get_array("tokenizer.ggml.scores")?
+                    .iter()
+                    .map(|s| s.as_f64())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow!("tokenizer.ggml.scores: expecting numbers"))?;
+                ensure!(
+                    scores.len() == tokens.len(),
+                    "tokenizer.ggml.scores: {} scores for {} tokens",
+                    scores.len(),
+                    tokens.len()
+                );
+                let unk = match self.metadata("tokenizer.ggml.unknown_token_id") {
+                    Some(id) => id.as_u64().map(|id| id as usize),
+                    None => token_types.iter().position(|&tt| tt == TOKEN_UNKNOWN),
+                };
+                let unk = unk.and_then(|id| tokens.get(id));
+                let merges = spm_merges(&tokens, &token_types, &scores);
+                json!({
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": added_tokens,
+                    "normalizer": {
+                        "type": "Sequence",
+                        "normalizers": [
+                            {"type": "Prepend", "prepend": "\u{2581}"},
+                            {"type": "Replace", "pattern": {"String": " "}, "content": "\u{2581}"},
+                        ],
+                    },
+                    "pre_tokenizer": null,
+                    "post_processor": null,
+                    "decoder": {
+                        "type": "Sequence",
+                        "decoders": [
+                            {"type": "Replace", "pattern": {"String": "\u{2581}"}, "content": " "},
+                            {"type": "ByteFallback"},
+                            {"type": "Fuse"},
+                            {"type": "Strip", "content": " ", "start": 1, "stop": 0},
+                        ],
+                    },
+                    "model": {
+                        "type": "BPE",
+                        "dropout": null,
+                        "unk_token": unk,
+                        "continuing_subword_prefix": null,
+                        "end_of_word_suffix": null,
+                        "fuse_unk": true,
+                        "byte_fallback": true,
+                        "vocab": vocab,
+                        "merges": merges,
+                    },
+                })
+            }
+            "gpt2" => {
+                let merges = self
+                    .get_array("tokenizer.ggml.merges")?
+                    .iter()
+                    .map(|m| m.as_str().map(|m| m.to_string()))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| anyhow!("tokenizer.ggml.merges: expecting strings"))?;
+                json!({
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": added_tokens,
+                    "normalizer": null,
+                    "pre_tokenizer": {
+                        "type": "ByteLevel",
+                        "add_prefix_space": false,
+                        "trim_offsets": true,
+                        "use_regex": true,
+                    },
+                    "post_processor": null,
+                    "decoder": {
+                        "type": "ByteLevel",
+                        "add_prefix_space": true,
+                        "trim_offsets": true,
+                        "use_regex": true,
+                    },
+                    "model": {
+                        "type": "BPE",
+                        "dropout": null,
+                        "unk_token": null,
+                        "continuing_subword_prefix": null,
+                        "end_of_word_suffix": null,
+                        "fuse_unk": false,
+                        "byte_fallback": false,
+                        "vocab": vocab,
+                        "merges": merges,
+                    },
+                })
+            }
+            _ => bail!("unsupported tokenizer.ggml.model {model:?}"),
+        };
+        Ok(res)
+    }
+}
+
+/// Write the tokenizer of the GGUF file to a tokenizer.json in the temporary folder
+/// (named after the path of the GGUF file), and return the path of the tokenizer.json.
+pub fn write_tokenizer(gguf_path: &Path) -> Result<PathBuf> {
+    let json = GgufFile::open(gguf_path)?.tokenizer_json()?;
+    let abs_path = std::fs::canonicalize(gguf_path)?;
+    let hash = Sha256::digest(abs_path.to_string_lossy().as_bytes());
+    let path = std::env::temp_dir().join(format!("rllm-gguf-{hash:x}.tokenizer.json"));
+    std::fs::write(&path, serde_json::to_vec(&json)?)
+        .with_context(|| format!("can't write {path:?}"))?;
+    Ok(path)
+}
+
+/// BPE merges reproducing sentencepiece's tokenization (like HuggingFace's conversion):
+/// every split of a piece into two pieces, ordered by the score of the merged piece.
+fn spm_merges(tokens: &[&str], token_types: &[i32], scores: &[f64]) -> Vec<String> {
+    let ids: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| token_types[*id] != TOKEN_BYTE && token_types[*id] != TOKEN_CONTROL)
+        .map(|(id, t)| (*t, id))
+        .collect();
+    let mut merges = Vec::new();
+    for (id, piece) in tokens.iter().enumerate() {
+        if !ids.contains_key(piece) {
+            continue;
+        }
+        let mut local = piece
+            .char_indices()
+            .skip(1)
+            .filter_map(|(split, _)| {
+                let (l, r) = piece.split_at(split);
+                Some((*ids.get(l)?, *ids.get(r)?, l, r))
+            })
+            .collect::<Vec<_>>();
+        local.sort_by_key(|(l, r, _, _)| (*l, *r));
+        merges.extend(local.into_iter().map(|(_, _, l, r)| (scores[id], l, r)));
+    }
+    // stable, so merges of the same piece keep their order
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    merges
+        .into_iter()
+        .map(|(_, l, r)| format!("{l} {r}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes GGUF v3 files, for tests.
+    #[derive(Default)]
+    struct Writer {
+        kv: Vec<u8>,
+        num_kv: u64,
+        tensors: Vec<(String, Vec<u64>, u32, Vec<u8>)>,
+    }
+
+    fn put_str(buf: &mut Vec<u8>, s: &str) {
+        buf.extend((s.len() as u64).to_le_bytes());
+        buf.extend(s.as_bytes());
+    }
+
+    impl Writer {
+        fn key(&mut self, key: &str, value_type: u32) -> &mut Vec<u8> {
+            self.num_kv += 1;
+            put_str(&mut self.kv, key);
+            self.kv.extend(value_type.to_le_bytes());
+            &mut self.kv
+        }
+
+        fn u32(&mut self, key: &str, v: u32) {
+            self.key(key, 4).extend(v.to_le_bytes());
+        }
+
+        fn f32(&mut self, key: &str, v: f32) {
+            self.key(key, 6).extend(v.to_le_bytes());
+        }
+
+        fn str(&mut self, key: &str, v: &str) {
+            put_str(self.key(key, 8), v);
+        }
+
+        fn array(&mut self, key: &str, elt_type: u32, elts: Vec<Vec<u8>>) {
+            let buf = self.key(key, 9);
+            buf.extend(elt_type.to_le_bytes());
+            buf.extend((elts.len() as u64).to_le_bytes());
+            buf.extend(elts.concat());
+        }
+
+        fn strs(&mut self, key: &str, v: &[&str]) {
+            let elts = v
+                .iter()
+                .map(|s| {
+                    let mut b = vec![];
+                    put_str(&mut b, s);
+                    b
+                })
+                .collect();
+            self.array(key, 8, elts);
+        }
+
+        fn tensor(&mut self, name: &str, dims: &[u64], ggml_type: u32, data: Vec<u8>) {
+            self.tensors
+                .push((name.to_string(), dims.to_vec(), ggml_type, data));
+        }
+
+        fn write(&self, path: &Path) {
+            let mut buf = MAGIC.to_vec();
+            buf.extend(3u32.to_le_bytes());
+            buf.extend((self.tensors.len() as u64).to_le_bytes());
+            buf.extend(self.num_kv.to_le_bytes());
+            buf.extend(&self.kv);
+            let mut offset = 0u64;
+            for (name, dims, ggml_type, data) in &self.tensors {
+                put_str(&mut buf, name);
+                buf.extend((dims.len() as u32).to_le_bytes());
+                for d in dims {
+                    buf.extend(d.to_le_bytes());
+                }
+                buf.extend(ggml_type.to_le_bytes());
+                buf.extend(offset.to_le_bytes());
+                offset += (data.len() as u64).next_multiple_of(DEFAULT_ALIGNMENT);
+            }
+            for (_, _, _, data) in &self.tensors {
+                buf.resize(
+                    (buf.len() as u64).next_multiple_of(DEFAULT_ALIGNMENT) as usize,
+                    0,
+                );
+                buf.extend(data);
+            }
+            std::fs::write(path, buf).unwrap();
+        }
+    }
+
+    fn f32_bytes(v: &[f32]) -> Vec<u8> {
+        v.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn f16_bytes(x: f32) -> [u8; 2] {
+        half::f16::from_f32(x).to_bits().to_le_bytes()
+    }
+
+    const TOKENS: &[&str] = &[
+        "<unk>",
+        "<s>",
+        "</s>",
+        "<0x0A>",
+        "\u{2581}",
+        "h",
+        "e",
+        "l",
+        "o",
+        "he",
+        "ll",
+        "llo",
+        "hello",
+        "\u{2581}hello",
+    ];
+
+    // a two-layer llama with a hidden size of 4 (2 heads), and a vocabulary of TOKENS
+    fn tiny_llama(path: &Path) {
+        let mut w = Writer::default();
+        w.str("general.architecture", "llama");
+        w.u32("llama.embedding_length", 4);
+        w.u32("llama.feed_forward_length", 8);
+        w.u32("llama.block_count", 2);
+        w.u32("llama.attention.head_count", 2);
+        w.u32("llama.context_length", 128);
+        w.f32("llama.attention.layer_norm_rms_epsilon", 1e-5);
+        w.str("tokenizer.ggml.model", "llama");
+        w.strs("tokenizer.ggml.tokens", TOKENS);
+        let scores = (0..TOKENS.len()).map(|i| (i as f32).to_le_bytes().to_vec());
+        w.array("tokenizer.ggml.scores", 6, scores.collect());
+        let types = TOKENS.iter().map(|t| match *t {
+            "<unk>" => 2,
+            "<s>" | "</s>" => 3,
+            "<0x0A>" => 6,
+            _ => 1i32,
+        });
+        let types = types.map(|t| t.to_le_bytes().to_vec());
+        w.array("tokenizer.ggml.token_type", 5, types.collect());
+
+        let vocab = TOKENS.len() as u64;
+        let emb: Vec<f32> = (0..vocab * 4).map(|i| i as f32).collect();
+        w.tensor("token_embd.weight", &[4, vocab], GGML_F32, f32_bytes(&emb));
+        w.tensor("output_norm.weight", &[4], GGML_F32, f32_bytes(&[1.0; 4]));
+        for layer in 0..2 {
+            let name = |n: &str| format!("blk.{layer}.{n}.weight");
+            // row r of Q holds r in all the columns
+            let q: Vec<f32> = (0..16).map(|i| (i / 4) as f32).collect();
+            w.tensor(&name("attn_q"), &[4, 4], GGML_F32, f32_bytes(&q));
+            let f16s = (0..16).flat_map(|i| f16_bytes(i as f32 / 2.0)).collect();
+            w.tensor(&name("attn_k"), &[4, 4], GGML_F16, f16s);
+            w.tensor(&name("attn_v"), &[4, 4], GGML_F32, f32_bytes(&[0.5; 16]));
+            w.tensor(
+                &name("attn_output"),
+                &[4, 4],
+                GGML_F32,
+                f32_bytes(&[0.0; 16]),
+            );
+            w.tensor(&name("attn_norm"), &[4], GGML_F32, f32_bytes(&[1.0; 4]));
+            w.tensor(&name("ffn_norm"), &[4], GGML_F32, f32_bytes(&[1.0; 4]));
+            // q4_0: d = 0.5, quants 0..16 in the low nibbles, 15 in the high ones
+            let mut q4 = f16_bytes(0.5).to_vec();
+            q4.extend((0..16u8).map(|q| q | 0xf0));
+            w.tensor(&name("ffn_gate"), &[32, 1], GGML_Q4_0, q4);
+            let mut q8 = f16_bytes(0.25).to_vec();
+            q8.extend((0..32).map(|q| (q as i8 - 16) as u8));
+            w.tensor(&name("ffn_up"), &[32, 1], GGML_Q8_0, q8);
+            w.tensor(&name("ffn_down"), &[32, 1], GGML_F32, f32_bytes(&[0.0; 32]));
+        }
+        w.write(path);
+    }
+
+    fn tiny_llama_file(name: &str) -> (std::path::PathBuf, GgufFile) {
+        let path = std::env::temp_dir().join(format!("rllm-gguf-{}-{name}", std::process::id()));
+        tiny_llama(&path);
+        let gguf = GgufFile::open(&path).unwrap();
+        (path, gguf)
+    }
+
+    #[test]
+    fn loads_metadata_and_config() {
+        let (path, gguf) = tiny_llama_file("config.gguf");
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(gguf.version, 3);
+        assert_eq!(gguf.architecture().unwrap(), "llama");
+        assert_eq!(gguf.tensors().len(), 2 + 2 * 9);
+        let cfg = gguf.hf_config().unwrap();
+        assert_eq!(cfg["hidden_size"], 4);
+        assert_eq!(cfg["num_key_value_heads"], 2);
+        assert_eq!(cfg["vocab_size"], TOKENS.len());
+        assert_eq!(cfg["max_position_embeddings"], 128);
+        assert_eq!(cfg["rope_theta"], 10_000.0);
+    }
+
+    #[test]
+    fn loads_weights() {
+        let (path, gguf) = tiny_llama_file("weights.gguf");
+        std::fs::remove_file(path).unwrap();
+        let weights: HashMap<_, _> = gguf
+            .hf_weights()
+            .unwrap()
+            .map(|w| w.map(|w| (w.name.clone(), w)))
+            .collect::<Result<_>>()
+            .unwrap();
+        // no output.weight: tied to the embeddings
+        assert_eq!(weights.len(), 2 + 2 * 9 + 1);
+        let emb = &weights["model.embed_tokens.weight"];
+        assert_eq!(emb.shape, vec![TOKENS.len(), 4]);
+        assert_eq!(&emb.data[4..8], &[4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(weights["lm_head.weight"].data, emb.data);
+
+        // two rows per head: nothing to reorder
+        let q = &weights["model.layers.1.self_attn.q_proj.weight"];
+        assert_eq!(q.shape, vec![4, 4]);
+        let rows: Vec<f32> = q.data.chunks(4).map(|r| r[0]).collect();
+        assert_eq!(rows, vec![0.0, 1.0, 2.0, 3.0]);
+        // with one head, the interleaved halves are put back one after the other
+        let rows = unpermute_rows(&[0.0, 1.0, 2.0, 3.0], 4, 1).unwrap();
+        assert_eq!(rows, vec![0.0, 2.0, 1.0, 3.0]);
+        let k = &weights["model.layers.0.self_attn.k_proj.weight"];
+        assert_eq!(&k.data[4..8], &[2.0, 2.5, 3.0, 3.5]);
+
+        let gate = &weights["model.layers.0.mlp.gate_proj.weight"];
+        assert_eq!(gate.shape, vec![1, 32]);
+        assert_eq!(gate.data[..3], [-4.0, -3.5, -3.0]);
+        assert_eq!(gate.data[16..], [3.5; 16]);
+        let up = &weights["model.layers.0.mlp.up_proj.weight"];
+        assert_eq!(up.data[0], -4.0);
+        assert_eq!(up.data[31], 3.75);
+    }
+
+    #[test]
+    fn converts_the_tokenizer() {
+        let (path, gguf) = tiny_llama_file("tokenizer.gguf");
+        std::fs::remove_file(path).unwrap();
+        let json = gguf.tokenizer_json().unwrap();
+        assert_eq!(json["model"]["unk_token"], "<unk>");
+        let added: Vec<_> = json["added_tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(added, vec!["<unk>", "<s>", "</s>"]);
+        // by the score of the merged piece, highest first
+        let merges = json["model"]["merges"].as_array().unwrap();
+        assert_eq!(merges[0], "\u{2581} hello");
+        assert_eq!(merges[1], "he llo");
+
+        let tok = tokenizers::Tokenizer::from_bytes(json.to_string()).unwrap();
+        let enc = tok.encode("hello hello\n", false).unwrap();
+        assert_eq!(
+            enc.get_tokens(),
+            &["\u{2581}hello", "\u{2581}hello", "<0x0A>"]
+        );
+        assert_eq!(tok.decode(enc.get_ids(), false).unwrap(), "hello hello\n");
+    }
+
+    #[test]
+    fn writes_the_tokenizer() {
+        let (path, _) = tiny_llama_file("write.gguf");
+        let tok_path = write_tokenizer(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let tok = tokenizers::Tokenizer::from_file(&tok_path).unwrap();
+        std::fs::remove_file(tok_path).unwrap();
+        assert_eq!(tok.get_vocab_size(true), TOKENS.len());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let path = std::env::temp_dir().join(format!("rllm-gguf-{}-bad", std::process::id()));
+        tiny_llama(&path);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+        let err = GgufFile::open(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            format!("{err:#}").contains("past the end of file"),
+            "{err:#}"
+        );
+    }
+}
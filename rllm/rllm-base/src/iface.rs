@@ -2,11 +2,13 @@ use crate::HashMap;
 use aici_abi::{
     bytes::{limit_bytes, limit_str},
     toktree::TokTrie,
+    InitPromptResult,
 };
 use aicirt::{
     api::{
-        AiciMidProcessReq, AiciMidProcessResp, AuthInfo, GetTagsResp, InstantiateReq, MkModuleReq,
-        MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        AiciMidProcessReq, AiciMidProcessResp, AiciPostSampleReq, AiciPostSampleResp, AuthInfo,
+        GetTagsResp, InstantiateReq, MkModuleReq, MkModuleResp, SequenceResult, SetTagsReq,
+        TokensResp,
     },
     futexshm::ClientChannel,
     msgchannel::MessageChannel,
@@ -123,6 +125,8 @@ pub struct AiciRtIface {
 pub struct Args {
     pub aicirt: String,
     pub tokenizer: String,
+    /// Size of the model logits; see TokTrie::model_vocab_size().
+    pub model_vocab_size: usize,
     pub json_size: usize,
     pub bin_size: usize,
     pub shm_prefix: String,
@@ -148,6 +152,8 @@ impl AiciRtIface {
         cmd_bld
             .arg("--tokenizer")
             .arg(&args.tokenizer)
+            .arg("--model-vocab-size")
+            .arg(&args.model_vocab_size.to_string())
             .arg("--json-size")
             .arg(&args.json_size.to_string())
             .arg("--bin-size")
@@ -236,6 +242,12 @@ impl AiciRtIface {
         self.pending_mid_size = usize::MAX;
         Ok(r)
     }
+
+    /// Must not be called while mid_process is pending.
+    pub fn post_sample(&mut self, req: AiciPostSampleReq) -> Result<AiciPostSampleResp> {
+        assert!(self.pending_mid_size == usize::MAX);
+        self.cmd.exec("post_sample", req)
+    }
 }
 
 #[derive(Clone)]
@@ -284,7 +296,7 @@ impl AsyncCmdChannel {
         &self,
         req: InstantiateReq,
         authinfo: AuthInfo,
-    ) -> Result<SequenceResult> {
+    ) -> Result<SequenceResult<InitPromptResult>> {
         self.exec("instantiate", req, authinfo).await
     }
 
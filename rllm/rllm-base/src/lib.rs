@@ -1,22 +1,39 @@
+mod chat;
+pub mod cli;
 pub mod seq;
 
 // vllm modules
 pub mod config;
+mod controllers;
 mod engine;
+mod eos;
 mod exec;
 mod expected;
+pub mod gguf;
 pub mod iface;
+mod listener;
 mod logits;
+mod metrics;
+mod repo;
 mod scheduler;
 pub mod server;
+mod snapshot;
+#[cfg(test)]
+mod testing;
+mod tokcache;
 pub mod util;
 
-use config::AiciConfig;
+pub use chat::{ChatMessage, ChatTemplate, BUILTIN_CHAT_TEMPLATES};
+use config::{AiciConfig, PreemptionMode, WeightFormat};
 pub use engine::*;
 pub use exec::*;
-pub use logits::LogitsProcessor;
+pub use listener::{EngineListener, RequestMeta, StepStats, UsageStats};
+pub use logits::{HookBias, HookCall, LogitsProcessor, SampleRow, SamplerCtx, SamplerHook};
+pub use metrics::{BatchHistogram, Histogram, LatencyMetrics};
+pub use repo::*;
 pub use scheduler::*;
-use std::sync::atomic::AtomicBool;
+pub use snapshot::*;
+use std::{path::PathBuf, sync::atomic::AtomicBool};
 
 pub use aicirt::HashMap;
 pub use aicirt::HashSet;
@@ -26,11 +43,60 @@ pub struct LoaderArgs {
     pub model_id: String,
     pub revision: Option<String>,
     pub file: Option<String>,
+    pub format: WeightFormat,
     pub local_weights: Option<String>,
+    /// Only use files already in the HuggingFace cache.
+    pub offline: bool,
     pub alt: usize,
+    pub max_prefill_tokens: Option<usize>,
+    /// See SchedulerConfig::max_prefill_tokens_per_step; None for the model length.
+    pub max_prefill_tokens_per_step: Option<usize>,
+    pub preemption_mode: PreemptionMode,
+    /// Threshold for PreemptionMode::Auto.
+    pub recompute_max_len: usize,
+    /// See SchedulerConfig::gpu_watermark.
+    pub gpu_watermark: f32,
+    /// See SchedulerConfig::admission_lookahead.
+    pub admission_lookahead: usize,
+    /// See SchedulerConfig::livelock_steps.
+    pub livelock_steps: usize,
+    /// See SchedulerConfig::priority_boost_ms.
+    pub priority_boost_ms: u64,
+    /// See SchedulerConfig::max_waiting_groups.
+    pub max_waiting_groups: usize,
+    /// See SchedulerConfig::max_queued_tokens.
+    pub max_queued_tokens: usize,
+    /// Log engine stats every this many steps; 0 disables.
+    pub log_stats_steps: usize,
+    /// Split the model layers across this many GPUs.
+    pub pipeline_parallel_size: usize,
+    /// Built-in chat template, for models without one in tokenizer_config.json.
+    pub chat_template: String,
+    /// Panic (instead of finishing the sequence) when the model produces NaN/inf logits.
+    pub panic_on_nan: bool,
+    /// LoRA adapters (name, folder with adapter_config.json and adapter_model.safetensors)
+    /// loaded next to the base model; requests pick one with SamplingParams::lora.
+    pub lora_adapters: Vec<(String, PathBuf)>,
+    /// Smaller model proposing tokens for the main one to verify (speculative decoding).
+    pub draft_model: Option<DraftModelArgs>,
+    /// Keep the tokens of up to this many prompts of RllmEngine::add_request(), for
+    /// workloads where the same prompts (e.g., templates) recur; 0 disables the cache.
+    pub tokenize_cache_entries: usize,
+    /// Limit on the size of the prompts and tokens in the cache.
+    pub tokenize_cache_bytes: usize,
     pub aici: AiciConfig,
 }
 
+/// A draft model for speculative decoding; it has to use the tokenizer of the main model.
+#[derive(Debug, Clone)]
+pub struct DraftModelArgs {
+    pub model_id: String,
+    pub revision: Option<String>,
+    pub local_weights: Option<String>,
+    /// Tokens proposed in each step, all verified in one forward pass of the main model.
+    pub num_tokens: usize,
+}
+
 impl Default for LoaderArgs {
     fn default() -> Self {
         Self {
@@ -38,9 +104,42 @@ impl Default for LoaderArgs {
             model_id: "NousResearch/Llama-2-7b-hf".to_string(),
             revision: None,
             local_weights: None,
+            offline: false,
             file: None,
+            format: WeightFormat::Auto,
             aici: AiciConfig::default(),
             alt: 0,
+            max_prefill_tokens: None,
+            max_prefill_tokens_per_step: None,
+            preemption_mode: PreemptionMode::Recompute,
+            recompute_max_len: 512,
+            gpu_watermark: 0.01,
+            admission_lookahead: 16,
+            livelock_steps: 100,
+            priority_boost_ms: 10_000,
+            max_waiting_groups: 0,
+            max_queued_tokens: 0,
+            log_stats_steps: 0,
+            pipeline_parallel_size: 1,
+            chat_template: "llama2".to_string(),
+            panic_on_nan: false,
+            lora_adapters: Vec::new(),
+            draft_model: None,
+            tokenize_cache_entries: 0,
+            tokenize_cache_bytes: 64 << 20,
+        }
+    }
+}
+
+impl LoaderArgs {
+    /// The format of the weights, with WeightFormat::Auto resolved from the model file name.
+    pub fn weight_format(&self) -> WeightFormat {
+        match self.format {
+            WeightFormat::Auto => match &self.file {
+                Some(f) if f.ends_with(".gguf") => WeightFormat::Gguf,
+                _ => WeightFormat::Safetensors,
+            },
+            f => f,
         }
     }
 }
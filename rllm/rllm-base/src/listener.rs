@@ -0,0 +1,175 @@
+//! Callbacks on the lifecycle of requests in the engine, e.g., to feed telemetry;
+//! see RllmEngine::add_listener().
+
+use crate::{
+    config::PreemptionMode,
+    seq::{split_durations, FinishReason, SequenceGroup, TokenUsage},
+};
+use std::{
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+/// The request an EngineListener is called about.
+#[derive(Debug, Clone)]
+pub struct RequestMeta {
+    pub request_id: String,
+    /// Tokens in the prompt (including the ones a controller added before the first step).
+    pub prompt_len: usize,
+    /// SamplingParams::priority.
+    pub priority: i32,
+    pub arrival_time: Instant,
+}
+
+impl RequestMeta {
+    fn new(sg: &SequenceGroup) -> Self {
+        RequestMeta {
+            request_id: sg.request_id.clone(),
+            prompt_len: sg.seqs.first().map_or(0, |seq| seq.prompt_len),
+            priority: sg.sampling_params.priority,
+            arrival_time: sg.arrival_time,
+        }
+    }
+}
+
+/// Totals of a finished request; the durations split the time from arrival to the end
+/// like in SeqOutput.
+#[derive(Debug, Clone)]
+pub struct UsageStats {
+    pub usage: TokenUsage,
+    pub queued_duration: Duration,
+    pub prefill_duration: Duration,
+    pub decode_duration: Duration,
+}
+
+/// What happened in an engine step.
+#[derive(Debug, Clone, Default)]
+pub struct StepStats {
+    pub step_no: usize,
+    /// Sequences run by the model; 0 when nothing was scheduled.
+    pub num_seqs: usize,
+    pub num_batched_tokens: usize,
+    /// Tokens sampled in the step.
+    pub gen_tokens: usize,
+    /// Prompt (and fast-forwarded) tokens computed in the step.
+    pub prompt_tokens: usize,
+    /// Sequence groups scheduled for the first time in the step (leaving the waiting
+    /// queue), and the sum of their prompt lengths.
+    pub started_groups: usize,
+    pub started_prompt_tokens: usize,
+    /// Number of outputs returned by RllmEngine::step().
+    pub num_outputs: usize,
+    pub duration: Duration,
+}
+
+/// Callbacks on engine events, added with RllmEngine::add_listener().
+/// They are called synchronously, on the engine thread, so they should be quick.
+/// A panic in a callback is logged and otherwise ignored.
+pub trait EngineListener {
+    /// The request was queued: added, forked from another one, or extended (again).
+    fn on_request_added(&mut self, _req: &RequestMeta) {}
+
+    /// The request got onto the GPU: from the waiting queue (also after a preemption
+    /// that dropped its KV cache), swapped back in, or extended with its KV cache.
+    fn on_scheduled(&mut self, _req: &RequestMeta) {}
+
+    /// The first token was generated (sampled or forced) for the request.
+    fn on_first_token(&mut self, _req: &RequestMeta) {}
+
+    /// The request was taken off the GPU to make room for others; with
+    /// PreemptionMode::Swap its KV cache went to CPU memory, otherwise it is recomputed.
+    fn on_preempted(&mut self, _req: &RequestMeta, _mode: PreemptionMode) {}
+
+    /// The request is done; called in the step that returns its final output.
+    /// With several sequences, the reason is the one of the first sequence.
+    fn on_finished(&mut self, _req: &RequestMeta, _reason: &FinishReason, _usage: &UsageStats) {}
+
+    /// Called at the end of every RllmEngine::step(), after the other events of the step.
+    fn on_step_complete(&mut self, _stats: &StepStats) {}
+}
+
+/// The listeners of the engine; kept by the scheduler, which raises most of the events.
+#[derive(Default)]
+pub(crate) struct Listeners {
+    listeners: Vec<Box<dyn EngineListener + Send>>,
+}
+
+impl Listeners {
+    pub fn add(&mut self, listener: Box<dyn EngineListener + Send>) {
+        self.listeners.push(listener);
+    }
+
+    fn notify(&mut self, event: &str, mut f: impl FnMut(&mut dyn EngineListener)) {
+        for listener in self.listeners.iter_mut() {
+            let r = std::panic::catch_unwind(AssertUnwindSafe(|| f(listener.as_mut())));
+            if r.is_err() {
+                log::error!("engine listener panicked in {event}");
+            }
+        }
+    }
+
+    fn notify_req(
+        &mut self,
+        event: &str,
+        sg: &SequenceGroup,
+        mut f: impl FnMut(&mut dyn EngineListener, &RequestMeta),
+    ) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let req = RequestMeta::new(sg);
+        self.notify(event, |l| f(l, &req));
+    }
+
+    pub fn request_added(&mut self, sg: &SequenceGroup) {
+        self.notify_req("on_request_added", sg, |l, req| l.on_request_added(req));
+    }
+
+    pub fn scheduled(&mut self, sg: &SequenceGroup) {
+        self.notify_req("on_scheduled", sg, |l, req| l.on_scheduled(req));
+    }
+
+    pub fn first_token(&mut self, sg: &SequenceGroup) {
+        self.notify_req("on_first_token", sg, |l, req| l.on_first_token(req));
+    }
+
+    pub fn preempted(&mut self, sg: &SequenceGroup, mode: PreemptionMode) {
+        self.notify_req("on_preempted", sg, |l, req| l.on_preempted(req, mode));
+    }
+
+    pub fn finished(&mut self, sg: &SequenceGroup) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let reason = sg
+            .seqs
+            .first()
+            .and_then(|seq| seq.finish_reason())
+            .unwrap_or(FinishReason::Failed);
+        let end = sg
+            .seqs
+            .iter()
+            .filter_map(|seq| seq.finish_time)
+            .max()
+            .unwrap_or_else(Instant::now);
+        let (queued_duration, prefill_duration, decode_duration) = split_durations(
+            sg.arrival_time,
+            sg.first_scheduled_time,
+            sg.first_token_time,
+            end,
+        );
+        let usage = UsageStats {
+            usage: sg.usage.clone(),
+            queued_duration,
+            prefill_duration,
+            decode_duration,
+        };
+        self.notify_req("on_finished", sg, |l, req| {
+            l.on_finished(req, &reason, &usage)
+        });
+    }
+
+    pub fn step_complete(&mut self, stats: &StepStats) {
+        self.notify("on_step_complete", |l| l.on_step_complete(stats));
+    }
+}
@@ -1,16 +1,115 @@
 // based on https://github.com/huggingface/candle/blob/main/candle-transformers/src/generation/mod.rs
 
-use crate::config::{SamplingParams, SAMPLING_EPS};
-use rand::SeedableRng;
+use crate::{
+    config::{SamplingParams, SAMPLING_EPS},
+    seq::{Sequence, Token},
+    HashMap,
+};
+use aici_abi::svob::SimpleVob;
+use anyhow::{bail, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{fmt::Debug, sync::Arc};
+
+/// Changes the logits of a sequence right before its next token is picked, e.g.,
+/// to try out a sampling strategy without changing the engine; see
+/// SamplingParams::sampler_hook.
+pub trait SamplerHook: Send + Sync {
+    /// The logits stay on the device, with the rest of the batch; `bias` is added to them
+    /// there, after they are divided by the temperature, and the tokens dropped by top-k,
+    /// top-p, min-p and typical-p are -inf (with greedy sampling, to the logits as the model
+    /// returned them, after the controller bias). Banned tokens are not sampled; when
+    /// no token is left, or on an error, the sequence fails.
+    fn adjust(&self, bias: &mut HookBias, ctx: &SamplerCtx) -> Result<()>;
+}
+
+impl Debug for dyn SamplerHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SamplerHook")
+    }
+}
+
+/// What a SamplerHook gets to know about the sequence.
+#[derive(Debug, Clone)]
+pub struct SamplerCtx {
+    /// Sequence::index
+    pub seq_index: usize,
+    /// The tokens generated so far (not including the prompt).
+    pub output_tokens: Vec<Token>,
+    /// Number of the engine step.
+    pub step_no: usize,
+}
+
+/// A SamplerHook, with the sequence to call it for.
+#[derive(Debug, Clone)]
+pub struct HookCall {
+    pub hook: Arc<dyn SamplerHook>,
+    pub ctx: SamplerCtx,
+}
+
+/// Values a SamplerHook adds to the logits of some tokens.
+#[derive(Debug, Clone, Default)]
+pub struct HookBias {
+    entries: Vec<(u32, f32)>,
+}
+
+impl HookBias {
+    /// Add `value` to the logit of `tok`; values for the same token add up.
+    pub fn add(&mut self, tok: u32, value: f32) {
+        self.entries.push((tok, value));
+    }
+
+    /// `tok` is not sampled.
+    pub fn ban(&mut self, tok: u32) {
+        self.add(tok, f32::NEG_INFINITY);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// (token, value) pairs, in the order they were added.
+    pub fn entries(&self) -> &[(u32, f32)] {
+        &self.entries
+    }
+
+    /// Whether ban() was called for `tok` (or values added up to -inf).
+    pub fn bans(&self, tok: u32) -> bool {
+        self.entries
+            .iter()
+            .filter(|(t, _)| *t == tok)
+            .map(|(_, v)| *v)
+            .sum::<f32>()
+            == f32::NEG_INFINITY
+    }
+
+    fn apply(&self, logits: &mut [f32]) {
+        for &(tok, value) in &self.entries {
+            if let Some(l) = logits.get_mut(tok as usize) {
+                *l += value;
+            }
+        }
+    }
+}
 
 pub struct LogitsProcessor {
-    pub rng: rand::rngs::StdRng,
+    /// Seed of the request; see SamplingParams::seed.
+    pub seed: u64,
+    /// One generator per sequence index, so that the tokens of a sequence
+    /// don't depend on its siblings, or on other requests in the batch.
+    rngs: HashMap<usize, SeqRng>,
     pub temperature: Option<f32>,
     pub top_p: f32,
+    /// 0 means no top-k filtering.
+    pub top_k: usize,
+    pub min_p: f32,
+    pub typical_p: f32,
+    /// SamplingParams::token_ban_list
+    pub banned: Option<Arc<SimpleVob>>,
+    pub hook: Option<Arc<dyn SamplerHook>>,
 }
 
 impl LogitsProcessor {
-    pub fn new(sampling_params: &SamplingParams) -> Self {
+    pub fn new(sampling_params: &SamplingParams, seed: u64) -> Self {
         let temperature = if sampling_params.temperature < SAMPLING_EPS {
             None
         } else {
@@ -18,10 +117,491 @@ impl LogitsProcessor {
         };
 
         Self {
-            rng: rand::rngs::StdRng::from_entropy(),
-            // seed_from_u64(42),
+            seed,
+            rngs: HashMap::default(),
             temperature,
             top_p: sampling_params.top_p,
+            top_k: if sampling_params.top_k > 0 {
+                sampling_params.top_k as usize
+            } else {
+                0
+            },
+            min_p: sampling_params.min_p,
+            typical_p: sampling_params.typical_p,
+            banned: sampling_params.token_ban_list.clone(),
+            hook: sampling_params.sampler_hook.clone(),
+        }
+    }
+
+    /// Parameters for sampling the next token of the sequence. All randomness is drawn here,
+    /// so results only depend on the seed of the processor, not on how the sequences
+    /// are batched.
+    pub fn next_row(&mut self, seq: &Sequence, step_no: usize) -> SampleRow {
+        SampleRow {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            uniform: if self.temperature.is_some() {
+                self.seq_rng(seq.index).draw()
+            } else {
+                0.0
+            },
+            suppress_tokens: vec![],
+            banned: self.banned.clone(),
+            hook_bias: HookBias::default(),
+            hook: self.hook.as_ref().map(|hook| HookCall {
+                hook: hook.clone(),
+                ctx: SamplerCtx {
+                    seq_index: seq.index,
+                    output_tokens: seq.all_tokens()[seq.prompt_len..].to_vec(),
+                    step_no,
+                },
+            }),
+        }
+    }
+
+    /// Number of random numbers drawn so far for the sequence with the given index.
+    pub fn num_draws(&self, seq_index: usize) -> u64 {
+        self.rngs.get(&seq_index).map_or(0, |r| r.num_draws)
+    }
+
+    /// Continue the stream of the sequence with the given index after `num_draws` draws
+    /// (see num_draws()), e.g., for a sequence restored from an EngineSnapshot.
+    pub fn skip_draws(&mut self, seq_index: usize, num_draws: u64) {
+        let rng = self.seq_rng(seq_index);
+        while rng.num_draws < num_draws {
+            rng.draw();
+        }
+    }
+
+    fn seq_rng(&mut self, seq_index: usize) -> &mut SeqRng {
+        let seed = self.seed;
+        self.rngs.entry(seq_index).or_insert_with(|| SeqRng {
+            rng: StdRng::seed_from_u64(child_seed(seed, seq_index)),
+            num_draws: 0,
+        })
+    }
+}
+
+struct SeqRng {
+    rng: StdRng,
+    num_draws: u64,
+}
+
+impl SeqRng {
+    fn draw(&mut self) -> f32 {
+        self.num_draws += 1;
+        self.rng.gen::<f32>()
+    }
+}
+
+/// Seed for the sequence with the given index; mixes the two with splitmix64,
+/// so that nearby seeds or indices give unrelated streams.
+pub fn child_seed(seed: u64, seq_index: usize) -> u64 {
+    let mut z = seed ^ (seq_index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Sampling parameters for one row of logits; see ModelExec::sample_batch().
+///
+/// The logits are divided by the temperature first. Then top-k, top-p and min-p each keep
+/// a prefix of the tokens sorted by descending probability; they all look at the same
+/// distribution, so a token is kept when all of them keep it. Typical-p then picks from
+/// the tokens left (with their probabilities renormalized). The hook bias, if any,
+/// comes last, right before the token is picked.
+#[derive(Debug, Clone)]
+pub struct SampleRow {
+    /// None means greedy (argmax) sampling.
+    pub temperature: Option<f32>,
+    pub top_p: f32,
+    /// 0 means no top-k filtering.
+    pub top_k: usize,
+    /// Drop tokens less likely than min_p times the most likely one; 0 means no filtering.
+    pub min_p: f32,
+    /// Keep the tokens with the information content (-log p) closest to the entropy,
+    /// up to this total probability; 1 means no filtering.
+    pub typical_p: f32,
+    /// Uniform in [0, 1); picks the token from the cumulative distribution
+    /// of the filtered probabilities (sorted by descending probability).
+    pub uniform: f32,
+    /// Treat the logits of these tokens as -inf, unless no other token is allowed.
+    pub suppress_tokens: Vec<u32>,
+    /// These are never sampled (their logits are -inf before anything else),
+    /// even when no other token is allowed; see SamplingParams::token_ban_list.
+    pub banned: Option<Arc<SimpleVob>>,
+    /// Added after the filters above; a bias that leaves no tokens is ignored,
+    /// see sample_host(). Set by call_hook().
+    pub hook_bias: HookBias,
+    /// ModelExec::sample_batch() only looks at hook_bias; the engine calls the hook
+    /// before that.
+    pub hook: Option<HookCall>,
+}
+
+impl SampleRow {
+    pub fn uses_top_p(&self) -> bool {
+        self.top_p > 0.0 && self.top_p < 1.0
+    }
+
+    pub fn uses_typical_p(&self) -> bool {
+        self.typical_p > 0.0 && self.typical_p < 1.0
+    }
+
+    /// Set hook_bias from the hook, if any.
+    pub fn call_hook(&mut self) -> Result<()> {
+        if let Some(h) = &self.hook {
+            let mut bias = HookBias::default();
+            h.hook.adjust(&mut bias, &h.ctx)?;
+            self.hook_bias = bias;
+        }
+        Ok(())
+    }
+
+    /// Reference implementation on the CPU; backends with a device-side
+    /// implementation need to return the same token up to numerical precision.
+    /// When hook_bias leaves no tokens, this is the token picked without it.
+    pub fn sample(&self, logits: &[f32]) -> u32 {
+        let mut logits = logits.to_vec();
+        if let Some(banned) = &self.banned {
+            for (tok, l) in logits.iter_mut().enumerate() {
+                if tok < banned.len() && banned.is_allowed(tok as u32) {
+                    *l = f32::NEG_INFINITY;
+                }
+            }
+        }
+        if !self.suppress_tokens.is_empty()
+            && logits
+                .iter()
+                .enumerate()
+                .any(|(i, l)| *l > f32::NEG_INFINITY && !self.suppress_tokens.contains(&(i as u32)))
+        {
+            for &tok in &self.suppress_tokens {
+                logits[tok as usize] = f32::NEG_INFINITY;
+            }
+        }
+
+        let select = |logits: &[f32]| match self.temperature {
+            None => argmax(logits),
+            Some(_) => pick(logits, self.uniform),
+        };
+        if let Some(t) = self.temperature {
+            logits.iter_mut().for_each(|l| *l /= t);
+            self.truncate(&mut logits);
+        }
+        let tok = select(&logits);
+        if self.hook_bias.is_empty() {
+            return tok;
+        }
+        self.hook_bias.apply(&mut logits);
+        if logits.iter().all(|l| *l == f32::NEG_INFINITY) {
+            return tok;
+        }
+        select(&logits)
+    }
+
+    /// Like sample(), failing when hook_bias leaves no tokens; then the token
+    /// sample() returns is one of the banned ones.
+    pub fn sample_host(&self, logits: &[f32]) -> Result<u32> {
+        let tok = self.sample(logits);
+        if self.hook_bias.bans(tok) {
+            bail!("the sampler hook left no tokens");
         }
+        Ok(tok)
+    }
+
+    /// Set the logits (already divided by the temperature) of the tokens
+    /// that can't be sampled to -inf.
+    fn truncate(&self, logits: &mut [f32]) {
+        let prs = probabilities(logits);
+        let order = descending(&prs);
+
+        // top-p (nucleus) sampling keeps the smallest set of tokens that exceed
+        // probability top_p; top-k keeps the k most likely tokens; min-p the ones
+        // at least min_p times as likely as the first one.
+        let min_pr = self.min_p * prs[order[0]];
+        let mut kept = 0;
+        let mut cumsum = 0.0;
+        for &idx in &order {
+            if (self.uses_top_p() && cumsum >= self.top_p)
+                || (self.top_k > 0 && kept >= self.top_k)
+                || prs[idx] < min_pr
+                || prs[idx] == 0.0
+            {
+                break;
+            }
+            cumsum += prs[idx];
+            kept += 1;
+        }
+        let mut keep = order[0..kept].to_vec();
+
+        if self.uses_typical_p() {
+            let entropy = -keep
+                .iter()
+                .map(|&i| prs[i] / cumsum * (prs[i] / cumsum).ln())
+                .sum::<f32>();
+            let score = |i: usize| (-(prs[i] / cumsum).ln() - entropy).abs();
+            keep.sort_by(|&i, &j| score(i).total_cmp(&score(j)));
+            let mut mass = 0.0;
+            let mut typical = 0;
+            for &idx in &keep {
+                mass += prs[idx] / cumsum;
+                typical += 1;
+                if mass >= self.typical_p {
+                    break;
+                }
+            }
+            keep.truncate(typical);
+        }
+
+        let mut dropped = vec![true; logits.len()];
+        for idx in keep {
+            dropped[idx] = false;
+        }
+        for (l, d) in logits.iter_mut().zip(dropped) {
+            if d {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+fn argmax(logits: &[f32]) -> u32 {
+    let mut top_idx = 0;
+    for (i, x) in logits.iter().enumerate() {
+        if *x > logits[top_idx] {
+            top_idx = i;
+        }
+    }
+    top_idx as u32
+}
+
+fn probabilities(logits: &[f32]) -> Vec<f32> {
+    let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let mut prs: Vec<f32> = logits.iter().map(|l| (l - max_logit).exp()).collect();
+    let sum = prs.iter().sum::<f32>();
+    prs.iter_mut().for_each(|p| *p /= sum);
+    prs
+}
+
+// indices by descending probability; ties keep the order of the tokens
+fn descending(prs: &[f32]) -> Vec<usize> {
+    let mut order = (0..prs.len()).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+    order
+}
+
+/// Inverse transform sampling: the first token (by descending probability) where
+/// the cumulative probability exceeds `uniform`.
+fn pick(logits: &[f32], uniform: f32) -> u32 {
+    let prs = probabilities(logits);
+    let order = descending(&prs);
+    let kept = order.iter().take_while(|&&i| prs[i] > 0.0).count();
+    let threshold = uniform * order[0..kept].iter().map(|&i| prs[i]).sum::<f32>();
+    let mut acc = 0.0;
+    for &idx in &order[0..kept - 1] {
+        acc += prs[idx];
+        if acc > threshold {
+            return idx as u32;
+        }
+    }
+    order[kept - 1] as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // probabilities 0.4, 0.25, 0.15, 0.1, 0.06, 0.04
+    fn synthetic_logits() -> Vec<f32> {
+        [0.4f32, 0.25, 0.15, 0.1, 0.06, 0.04]
+            .iter()
+            .map(|p| p.ln())
+            .collect()
+    }
+
+    fn row(temperature: f32) -> SampleRow {
+        SampleRow {
+            temperature: Some(temperature),
+            top_p: 1.0,
+            top_k: 0,
+            min_p: 0.0,
+            typical_p: 1.0,
+            uniform: 0.0,
+            suppress_tokens: vec![],
+            banned: None,
+            hook_bias: HookBias::default(),
+            hook: None,
+        }
+    }
+
+    // tokens that can still be sampled after truncation
+    fn kept(row: &SampleRow) -> Vec<usize> {
+        let mut logits = synthetic_logits();
+        logits
+            .iter_mut()
+            .for_each(|l| *l /= row.temperature.unwrap());
+        row.truncate(&mut logits);
+        (0..logits.len())
+            .filter(|&i| logits[i] > f32::NEG_INFINITY)
+            .collect()
+    }
+
+    struct Ban(u32);
+
+    impl SamplerHook for Ban {
+        fn adjust(&self, bias: &mut HookBias, _ctx: &SamplerCtx) -> Result<()> {
+            bias.ban(self.0);
+            Ok(())
+        }
+    }
+
+    struct Fail;
+
+    impl SamplerHook for Fail {
+        fn adjust(&self, _bias: &mut HookBias, _ctx: &SamplerCtx) -> Result<()> {
+            bail!("hook failed")
+        }
+    }
+
+    fn with_hook(mut row: SampleRow, hook: impl SamplerHook + 'static) -> Result<SampleRow> {
+        row.hook = Some(HookCall {
+            hook: Arc::new(hook),
+            ctx: SamplerCtx {
+                seq_index: 0,
+                output_tokens: vec![],
+                step_no: 0,
+            },
+        });
+        row.call_hook()?;
+        Ok(row)
+    }
+
+    #[test]
+    fn min_p_keeps_likely_tokens() {
+        for (min_p, expected) in [
+            (0.0, vec![0, 1, 2, 3, 4, 5]),
+            (0.2, vec![0, 1, 2, 3]),
+            (0.3, vec![0, 1, 2]),
+            (0.5, vec![0, 1]),
+            (0.9, vec![0]),
+        ] {
+            let r = SampleRow { min_p, ..row(1.0) };
+            assert_eq!(kept(&r), expected, "min_p {min_p}");
+        }
+
+        // the intersection with top-k
+        let r = SampleRow {
+            min_p: 0.2,
+            top_k: 2,
+            ..row(1.0)
+        };
+        assert_eq!(kept(&r), vec![0, 1]);
+    }
+
+    #[test]
+    fn filters_apply_in_order() {
+        // min-p looks at the distribution after the temperature
+        let r = SampleRow {
+            min_p: 0.55,
+            ..row(1.0)
+        };
+        assert_eq!(kept(&r), vec![0, 1]);
+        let r = SampleRow {
+            min_p: 0.55,
+            ..row(2.0)
+        };
+        assert_eq!(kept(&r), vec![0, 1, 2]);
+
+        // typical-p picks from what top-k kept: 0.5, 0.3125, 0.1875 after renormalizing,
+        // where token 1 is the closest to the entropy, then token 0
+        let r = SampleRow {
+            top_k: 3,
+            typical_p: 0.5,
+            ..row(1.0)
+        };
+        assert_eq!(kept(&r), vec![0, 1]);
+        let r = SampleRow {
+            top_k: 3,
+            typical_p: 0.3,
+            ..row(1.0)
+        };
+        assert_eq!(kept(&r), vec![1]);
+    }
+
+    #[test]
+    fn hook_bans_tokens() {
+        let logits = synthetic_logits();
+        let mut sampled_0 = false;
+        for i in 0..100 {
+            let r = SampleRow {
+                uniform: i as f32 / 100.0,
+                ..row(1.0)
+            };
+            sampled_0 |= r.sample(&logits) == 0;
+            let r = with_hook(r, Ban(0)).unwrap();
+            assert_ne!(r.sample_host(&logits).unwrap(), 0);
+        }
+        assert!(sampled_0);
+
+        let greedy = SampleRow {
+            temperature: None,
+            ..row(1.0)
+        };
+        assert_eq!(greedy.sample_host(&logits).unwrap(), 0);
+        let r = with_hook(greedy, Ban(0)).unwrap();
+        assert_eq!(r.sample_host(&logits).unwrap(), 1);
+    }
+
+    #[test]
+    fn banned_tokens_are_never_sampled() {
+        let logits = synthetic_logits();
+        let mut banned = SimpleVob::alloc(logits.len());
+        banned.allow_token(0);
+        banned.allow_token(2);
+        let banned = Some(Arc::new(banned));
+        for i in 0..100 {
+            let r = SampleRow {
+                uniform: i as f32 / 100.0,
+                banned: banned.clone(),
+                ..row(1.0)
+            };
+            assert!(![0, 2].contains(&r.sample(&logits)));
+        }
+        let greedy = SampleRow {
+            temperature: None,
+            banned: banned.clone(),
+            ..row(1.0)
+        };
+        assert_eq!(greedy.sample(&logits), 1);
+
+        // suppress_tokens applies only while a token that is neither suppressed nor banned is left
+        let r = SampleRow {
+            suppress_tokens: vec![1, 3, 4, 5],
+            banned,
+            ..greedy
+        };
+        assert_eq!(r.sample(&logits), 1);
+        let r = SampleRow { banned: None, ..r };
+        assert_eq!(r.sample(&logits), 0);
+    }
+
+    #[test]
+    fn hook_errors_fail_sampling() {
+        let logits = synthetic_logits();
+        let err = with_hook(row(1.0), Fail).unwrap_err();
+        assert_eq!(err.to_string(), "hook failed");
+        // the bias comes after top-k, which leaves only the banned token
+        let r = SampleRow {
+            top_k: 1,
+            ..row(1.0)
+        };
+        let r = with_hook(r, Ban(0)).unwrap();
+        assert_eq!(r.sample(&logits), 0);
+        let err = r.sample_host(&logits).unwrap_err();
+        assert!(err.to_string().contains("no tokens"));
     }
 }
@@ -0,0 +1,308 @@
+use anyhow::{bail, Result};
+use std::{fmt::Write, time::Duration};
+
+/// Upper bound of the first bucket, in seconds; each next one is twice the previous,
+/// up to about 105s.
+const FIRST_BOUND: f64 = 100e-6;
+const NUM_BOUNDS: usize = 21;
+
+/// Batch sizes are grouped by powers of 2: 1, 2, 3-4, 5-8, ..., 65-128, and 129+.
+const NUM_BATCH_BUCKETS: usize = 9;
+
+fn bound(idx: usize) -> f64 {
+    FIRST_BOUND * (1u64 << idx) as f64
+}
+
+fn batch_bucket(batch_size: usize) -> usize {
+    let idx = (usize::BITS - batch_size.saturating_sub(1).leading_zeros()) as usize;
+    std::cmp::min(idx, NUM_BATCH_BUCKETS - 1)
+}
+
+fn batch_label(idx: usize) -> String {
+    match idx {
+        0 => "1".to_string(),
+        1 => "2".to_string(),
+        _ if idx == NUM_BATCH_BUCKETS - 1 => format!("{}+", (1 << (idx - 1)) + 1),
+        _ => format!("{}-{}", (1 << (idx - 1)) + 1, 1 << idx),
+    }
+}
+
+/// Latencies (in seconds) in fixed exponential buckets; observing doesn't allocate,
+/// so it's always on.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Not cumulative; the last one is for values over all the bounds.
+    counts: [u64; NUM_BOUNDS + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, secs: f64) {
+        let idx = (0..NUM_BOUNDS)
+            .find(|i| secs <= bound(*i))
+            .unwrap_or(NUM_BOUNDS);
+        self.counts[idx] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    pub fn observe_duration(&mut self, d: Duration) {
+        self.observe(d.as_secs_f64())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Upper bound of the bucket with the `q`-th quantile (e.g., 0.99 for p99);
+    /// infinite when it's over all the bounds, and 0 without observations.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return if idx == NUM_BOUNDS {
+                    f64::INFINITY
+                } else {
+                    bound(idx)
+                };
+            }
+        }
+        0.0
+    }
+
+    fn add(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// A Histogram per batch-size bucket.
+#[derive(Debug, Clone, Default)]
+pub struct BatchHistogram {
+    by_batch: [Histogram; NUM_BATCH_BUCKETS],
+}
+
+impl BatchHistogram {
+    pub fn observe(&mut self, batch_size: usize, d: Duration) {
+        self.by_batch[batch_bucket(batch_size)].observe_duration(d)
+    }
+
+    /// All the batch sizes together.
+    pub fn total(&self) -> Histogram {
+        let mut r = Histogram::default();
+        for h in self.by_batch.iter() {
+            r.add(h);
+        }
+        r
+    }
+}
+
+/// Latencies kept by the engine, by the size of the batch (running sequences)
+/// of the step they were observed in; see RllmEngine::metrics_text().
+#[derive(Debug, Clone, Default)]
+pub struct LatencyMetrics {
+    /// From arrival of a request until its first token.
+    pub time_to_first_token: BatchHistogram,
+    /// From arrival of a request until it's first scheduled.
+    pub queue_wait: BatchHistogram,
+    /// RllmEngine::step(), for steps that ran the model.
+    pub step: BatchHistogram,
+    /// The model forward pass.
+    pub model_fwd: BatchHistogram,
+    /// Sampling, including applying the controller biases.
+    pub sample: BatchHistogram,
+    /// mid_process() of a controller, for each sequence.
+    pub controller_mid_process: BatchHistogram,
+}
+
+/// Builds the Prometheus text exposition format.
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {name} {help}").unwrap();
+        writeln!(self.out, "# TYPE {name} {kind}").unwrap();
+    }
+
+    /// `name` should end in "_total".
+    pub fn counter(&mut self, name: &str, help: &str, value: usize) {
+        self.header(name, "counter", help);
+        writeln!(self.out, "{name} {value}").unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: usize) {
+        self.header(name, "gauge", help);
+        writeln!(self.out, "{name} {value}").unwrap();
+    }
+
+    /// One series per batch-size bucket (label "batch"); empty ones are left out.
+    pub fn histogram(&mut self, name: &str, help: &str, hist: &BatchHistogram) {
+        self.header(name, "histogram", help);
+        for (idx, h) in hist.by_batch.iter().enumerate() {
+            if h.count == 0 {
+                continue;
+            }
+            let batch = batch_label(idx);
+            let mut cumulative = 0;
+            for (idx, n) in h.counts.iter().enumerate() {
+                cumulative += n;
+                let le = if idx == NUM_BOUNDS {
+                    "+Inf".to_string()
+                } else {
+                    bound(idx).to_string()
+                };
+                writeln!(
+                    self.out,
+                    "{name}_bucket{{batch=\"{batch}\",le=\"{le}\"}} {cumulative}"
+                )
+                .unwrap();
+            }
+            writeln!(self.out, "{name}_sum{{batch=\"{batch}\"}} {}", h.sum).unwrap();
+            writeln!(self.out, "{name}_count{{batch=\"{batch}\"}} {}", h.count).unwrap();
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn parse_value(s: &str) -> Result<f64> {
+    match s {
+        "+Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        _ => Ok(s.parse()?),
+    }
+}
+
+/// Check the output of MetricsWriter: every sample parses and has a declared type,
+/// and the buckets of each histogram series have increasing bounds, are cumulative,
+/// and end with +Inf at the series' _count. Returns the number of samples.
+pub(crate) fn check_exposition(text: &str) -> Result<usize> {
+    let mut types: crate::HashMap<String, String> = crate::HashMap::default();
+    // histogram series (name and labels without "le") -> (last le, last count)
+    let mut buckets: crate::HashMap<String, (f64, f64)> = crate::HashMap::default();
+    let mut num_samples = 0;
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap_or((rest, ""));
+            if !["counter", "gauge", "histogram"].contains(&kind) {
+                bail!("bad type: {line}");
+            }
+            types.insert(name.to_string(), kind.to_string());
+            continue;
+        }
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let (series, value) = match line.rsplit_once(' ') {
+            Some(x) => x,
+            None => bail!("no value: {line}"),
+        };
+        let value = parse_value(value)?;
+        let (name, labels) = match series.split_once('{') {
+            Some((name, labels)) => match labels.strip_suffix('}') {
+                Some(labels) => (name, labels),
+                None => bail!("bad labels: {line}"),
+            },
+            None => (series, ""),
+        };
+        num_samples += 1;
+        if types.contains_key(name) {
+            continue;
+        }
+        let (base, suffix) = match ["_bucket", "_sum", "_count"]
+            .iter()
+            .find_map(|s| name.strip_suffix(s).map(|base| (base, *s)))
+        {
+            Some(x) => x,
+            None => bail!("no type for {name}"),
+        };
+        if types.get(base).map(|t| t.as_str()) != Some("histogram") {
+            bail!("no histogram type for {name}");
+        }
+        let labels = labels.split(',').collect::<Vec<_>>();
+        let rest = labels
+            .iter()
+            .filter(|l| !l.starts_with("le="))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(",");
+        let key = format!("{base}{{{rest}}}");
+        match suffix {
+            "_bucket" => {
+                let le = match labels.iter().find_map(|l| l.strip_prefix("le=")) {
+                    Some(le) => parse_value(le.trim_matches('"'))?,
+                    None => bail!("bucket without le: {line}"),
+                };
+                let (prev_le, prev) = buckets.get(&key).cloned().unwrap_or((f64::MIN, 0.0));
+                if le <= prev_le || value < prev {
+                    bail!("buckets not increasing: {line}");
+                }
+                buckets.insert(key, (le, value));
+            }
+            "_count" => match buckets.remove(&key) {
+                Some((le, count)) if le == f64::INFINITY && count == value => {}
+                b => bail!("{line}: buckets end with {b:?}"),
+            },
+            _ => {}
+        }
+    }
+    if let Some(key) = buckets.keys().next() {
+        bail!("no _count for {key}");
+    }
+    Ok(num_samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let mut hist = BatchHistogram::default();
+        for us in [50, 150, 150, 3_000, 10_000_000] {
+            hist.observe(1, Duration::from_micros(us));
+        }
+        hist.observe(6, Duration::from_secs(1000));
+        let total = hist.total();
+        assert_eq!(total.count(), 6);
+        assert_eq!(total.quantile(0.5), 200e-6);
+        assert_eq!(total.quantile(0.99), f64::INFINITY);
+        assert_eq!(Histogram::default().quantile(0.5), 0.0);
+        assert_eq!(
+            [1, 2, 3, 4, 5, 8, 9, 128, 129, 1000].map(batch_bucket),
+            [0, 1, 2, 2, 3, 3, 4, 7, 8, 8]
+        );
+        assert_eq!(batch_label(3), "5-8");
+        assert_eq!(batch_label(8), "129+");
+
+        let mut w = MetricsWriter::default();
+        w.counter("rllm_steps_total", "Steps.", 3);
+        w.gauge("rllm_gpu_blocks_free", "Free blocks.", 10);
+        w.histogram("rllm_step_seconds", "Step time.", &hist);
+        let text = w.finish();
+        // 2 plain samples; 2 series with a bucket per bound and +Inf, _sum and _count
+        assert_eq!(check_exposition(&text).unwrap(), 2 + 2 * (NUM_BOUNDS + 3));
+        assert!(text.contains("rllm_step_seconds_bucket{batch=\"1\",le=\"0.0002\"} 3\n"));
+        assert!(text.contains("rllm_step_seconds_bucket{batch=\"5-8\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("rllm_step_seconds_count{batch=\"1\"} 5\n"));
+
+        let broken = text.replace("_count{batch=\"1\"} 5", "_count{batch=\"1\"} 4");
+        assert!(check_exposition(&broken).is_err());
+        assert!(check_exposition("rllm_unknown 1\n").is_err());
+    }
+}
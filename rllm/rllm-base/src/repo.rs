@@ -0,0 +1,379 @@
+use crate::{HashMap, LoaderArgs};
+use anyhow::{bail, Error as E, Result};
+use hf_hub::{
+    api::sync::{ApiBuilder, ApiRepo},
+    Cache, CacheRepo, RepoType,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Problems with model files that retrying the load as-is won't fix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WeightLoadError {
+    /// The file doesn't match the size or sha256 listed on the hub, even after re-downloading.
+    Corrupted {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    /// Running with --offline and these files are not in the local cache.
+    Offline { missing: Vec<String> },
+}
+
+impl Display for WeightLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WeightLoadError::Corrupted {
+                file,
+                expected,
+                actual,
+            } => write!(f, "{file} is corrupted: expected {expected}, got {actual}"),
+            WeightLoadError::Offline { missing } => write!(
+                f,
+                "offline mode, but missing from the cache: {}",
+                missing.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WeightLoadError {}
+
+pub enum Repo {
+    Api(HubRepo),
+    Local(PathBuf),
+}
+
+/// A model on the HuggingFace hub, downloaded to (or already in) the local cache.
+pub struct HubRepo {
+    api: ApiRepo,
+    cache: CacheRepo,
+    model_id: String,
+    revision: String,
+    offline: bool,
+    // None if the hub didn't tell us
+    files: OnceLock<Option<HashMap<String, HubFile>>>,
+}
+
+struct HubFile {
+    size: u64,
+    // only for LFS files
+    sha256: Option<String>,
+}
+
+impl Repo {
+    pub fn from(args: &LoaderArgs) -> Result<Repo> {
+        match &args.local_weights {
+            Some(path) => Ok(Repo::Local(PathBuf::from(path))),
+            None => Ok(Repo::Api(HubRepo::new(
+                Cache::default(),
+                args.model_id.clone(),
+                args.revision.clone().unwrap_or("main".to_string()),
+                args.offline,
+            )?)),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_local(&self) -> bool {
+        match self {
+            Repo::Api(_) => false,
+            Repo::Local(_) => true,
+        }
+    }
+
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        match self {
+            Repo::Api(hub) => hub.get(filename),
+            Repo::Local(path) => {
+                let p = path.join(filename);
+                if p.exists() {
+                    Ok(p)
+                } else {
+                    bail!("file {p:?} doesn't exists")
+                }
+            }
+        }
+    }
+
+    /// Like get() for each file, but when offline, report all missing files at once.
+    pub fn get_all(&self, filenames: &[String]) -> Result<Vec<PathBuf>> {
+        if let Repo::Api(hub) = self {
+            if hub.offline {
+                let missing: Vec<_> = filenames
+                    .iter()
+                    .filter(|f| hub.cache.get(f.as_str()).is_none())
+                    .cloned()
+                    .collect();
+                if missing.len() > 0 {
+                    return Err(WeightLoadError::Offline { missing }.into());
+                }
+            }
+        }
+        filenames.iter().map(|f| self.get(f)).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn read(&self, filename: &str) -> Result<Vec<u8>> {
+        std::fs::read(self.get(filename)?).map_err(E::msg)
+    }
+}
+
+impl HubRepo {
+    fn new(cache: Cache, model_id: String, revision: String, offline: bool) -> Result<Self> {
+        let hub_repo =
+            hf_hub::Repo::with_revision(model_id.clone(), RepoType::Model, revision.clone());
+        let api = ApiBuilder::new()
+            .with_cache_dir(cache.path().clone())
+            .build()?
+            .repo(hub_repo.clone());
+        Ok(HubRepo {
+            api,
+            cache: cache.repo(hub_repo),
+            model_id,
+            revision,
+            offline,
+            files: OnceLock::new(),
+        })
+    }
+
+    fn get(&self, filename: &str) -> Result<PathBuf> {
+        // downloads only get into the cache once complete (and verified below),
+        // so starting with everything cached doesn't need the hub at all
+        if let Some(path) = self.cache.get(filename) {
+            return Ok(path);
+        }
+        if self.offline {
+            return Err(WeightLoadError::Offline {
+                missing: vec![filename.to_string()],
+            }
+            .into());
+        }
+
+        let path = self.api.get(filename).map_err(E::msg)?;
+        match self.verify(filename, &path) {
+            Ok(()) => Ok(path),
+            Err(e) => {
+                log::warn!("{e}; downloading again");
+                remove_cached(&path);
+                let path = self.api.download(filename).map_err(E::msg)?;
+                self.verify(filename, &path)?;
+                Ok(path)
+            }
+        }
+    }
+
+    /// Check a fresh download against the size and sha256 the hub lists for it.
+    fn verify(&self, filename: &str, path: &Path) -> Result<(), WeightLoadError> {
+        let info = match self.files().and_then(|files| files.get(filename)) {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        let corrupted = |expected: String, actual: String| WeightLoadError::Corrupted {
+            file: filename.to_string(),
+            expected,
+            actual,
+        };
+
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if size != info.size {
+            return Err(corrupted(
+                format!("{} bytes", info.size),
+                format!("{} bytes", size),
+            ));
+        }
+
+        if let Some(expected) = &info.sha256 {
+            let actual =
+                sha256_file(path).map_err(|e| corrupted(expected.clone(), e.to_string()))?;
+            if &actual != expected {
+                return Err(corrupted(
+                    format!("sha256 {expected}"),
+                    format!("sha256 {actual}"),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn files(&self) -> Option<&HashMap<String, HubFile>> {
+        self.files
+            .get_or_init(|| match self.fetch_files() {
+                Ok(files) => Some(files),
+                Err(e) => {
+                    log::warn!("can't get file list from the hub, not verifying downloads: {e}");
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    fn fetch_files(&self) -> Result<HashMap<String, HubFile>> {
+        let endpoint = std::env::var("HF_ENDPOINT").unwrap_or("https://huggingface.co".to_string());
+        let url = format!(
+            "{endpoint}/api/models/{}/revision/{}?blobs=true",
+            self.model_id, self.revision
+        );
+        let mut req = ureq::get(&url);
+        if let Some(token) = Cache::default().token() {
+            req = req.set("Authorization", &format!("Bearer {token}"));
+        }
+        let resp: serde_json::Value = serde_json::from_str(&req.call()?.into_string()?)?;
+
+        let mut files = HashMap::default();
+        for sib in resp["siblings"].as_array().unwrap_or(&Vec::new()) {
+            let (name, size) = match (sib["rfilename"].as_str(), sib["size"].as_u64()) {
+                (Some(name), Some(size)) => (name, size),
+                _ => continue,
+            };
+            let sha256 = sib["lfs"]["sha256"].as_str().map(|s| s.to_string());
+            files.insert(name.to_string(), HubFile { size, sha256 });
+        }
+        Ok(files)
+    }
+}
+
+/// Remove the file from the hub cache, so the next download starts from scratch.
+fn remove_cached(path: &Path) {
+    // cache entries are symlinks into the blob store
+    if let Ok(blob) = std::fs::canonicalize(path) {
+        let _ = std::fs::remove_file(blob);
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl Display for Repo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Repo::Api(hub) => write!(f, "{}", hub.api.url("")),
+            Repo::Local(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sha256_file, HubFile, HubRepo, Repo, WeightLoadError};
+    use crate::HashMap;
+    use hf_hub::Cache;
+    use std::path::{Path, PathBuf};
+
+    const MODEL: &str = "org/model";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rllm-repo-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // the layout of the HuggingFace cache, with `main` at commit "c0ffee"
+    fn add_cached(cache: &Path, filename: &str, data: &[u8]) -> PathBuf {
+        let repo = cache.join(format!("models--{}", MODEL.replace('/', "--")));
+        std::fs::create_dir_all(repo.join("refs")).unwrap();
+        std::fs::write(repo.join("refs").join("main"), "c0ffee").unwrap();
+        let path = repo.join("snapshots").join("c0ffee").join(filename);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn hub_repo(cache: &Path, offline: bool) -> HubRepo {
+        let cache = Cache::new(cache.to_path_buf());
+        HubRepo::new(cache, MODEL.to_string(), "main".to_string(), offline).unwrap()
+    }
+
+    #[test]
+    fn local_paths_are_joined() {
+        let dir = temp_dir("local");
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        // without a trailing slash
+        let repo = Repo::Local(PathBuf::from(dir.to_str().unwrap().trim_end_matches('/')));
+        assert_eq!(repo.get("config.json").unwrap(), dir.join("config.json"));
+        assert!(repo.get("model.safetensors").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cached_files_dont_need_the_hub() {
+        let dir = temp_dir("cached");
+        let path = add_cached(&dir, "config.json", b"{}");
+        let hub = hub_repo(&dir, false);
+        assert_eq!(hub.get("config.json").unwrap(), path);
+        // the file list was never fetched
+        assert!(hub.files.get().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn offline_lists_all_missing_files() {
+        let dir = temp_dir("offline");
+        add_cached(&dir, "config.json", b"{}");
+        let repo = Repo::Api(hub_repo(&dir, true));
+        assert_eq!(repo.get_all(&["config.json".to_string()]).unwrap().len(), 1);
+
+        let files = ["a.safetensors", "config.json", "b.safetensors"].map(|f| f.to_string());
+        let err = repo.get_all(&files).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WeightLoadError>(),
+            Some(&WeightLoadError::Offline {
+                missing: vec!["a.safetensors".to_string(), "b.safetensors".to_string()]
+            })
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_files_are_corrupted() {
+        let dir = temp_dir("verify");
+        let data = b"0123456789";
+        let path = add_cached(&dir, "model.safetensors", data);
+        let sha256 = sha256_file(&path).unwrap();
+
+        let hub = hub_repo(&dir, false);
+        let listed = |size: usize, sha256: &str| {
+            let file = HubFile {
+                size: size as u64,
+                sha256: Some(sha256.to_string()),
+            };
+            HashMap::from_iter([("model.safetensors".to_string(), file)])
+        };
+        assert!(hub.files.set(Some(listed(data.len() + 5, &sha256))).is_ok());
+        assert_eq!(
+            hub.verify("model.safetensors", &path),
+            Err(WeightLoadError::Corrupted {
+                file: "model.safetensors".to_string(),
+                expected: "15 bytes".to_string(),
+                actual: "10 bytes".to_string(),
+            })
+        );
+        // files the hub doesn't list are not checked
+        assert_eq!(hub.verify("config.json", &path), Ok(()));
+
+        let hub = hub_repo(&dir, false);
+        assert!(hub.files.set(Some(listed(data.len(), "00"))).is_ok());
+        match hub.verify("model.safetensors", &path) {
+            Err(WeightLoadError::Corrupted { actual, .. }) => {
+                assert_eq!(actual, format!("sha256 {sha256}"))
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+
+        let hub = hub_repo(&dir, false);
+        assert!(hub.files.set(Some(listed(data.len(), &sha256))).is_ok());
+        assert_eq!(hub.verify("model.safetensors", &path), Ok(()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,34 +1,29 @@
 use crate::{
-    config::RllmConfig,
-    seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup},
+    config::{PreemptionMode, RllmConfig, Truncation},
+    listener::Listeners,
+    seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup, Token},
     util::limit_str,
-    HashMap, ModelExec, SequenceManager, TBlockSpaceManager,
+    AddRequestError, HashMap, ModelExec, SequenceManager, TBlockSpaceManager,
 };
-use aicirt::api::SequenceResult;
+use aicirt::{api::SequenceResult, bail_user};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
+    cmp::Reverse,
     ops::Deref,
     sync::{Arc, Mutex},
+    time::Instant,
     vec::Vec,
 };
 
-/// Preemption modes.
-#[derive(Debug, Clone, Copy)]
-pub enum PreemptionMode {
-    /// Swap out the blocks of the preempted sequences to CPU memory
-    /// and swap them back in when the sequences are resumed.
-    Swap,
-
-    /// Discard the blocks of the preempted sequences and
-    /// recompute them when the sequences are resumed, treating the sequences as
-    /// new prompts.
-    Recompute,
-}
-
 /// Scheduler outputs.
 pub struct SchedulerOutputs {
     pub prompt_run: bool,
     pub num_batched_tokens: usize,
+    /// Part of num_batched_tokens for sequences running on more than one token:
+    /// prompts (or chunks of them), and tokens spliced in by the controller.
+    pub num_prefill_tokens: usize,
     pub blocks_to_swap_in: HashMap<usize, usize>,
     pub blocks_to_swap_out: HashMap<usize, usize>,
     pub blocks_to_copy: HashMap<usize, Vec<usize>>,
@@ -42,6 +37,7 @@ impl SchedulerOutputs {
         SchedulerOutputs {
             prompt_run: false,
             num_batched_tokens: 0,
+            num_prefill_tokens: 0,
             blocks_to_swap_in: HashMap::default(),
             blocks_to_swap_out: HashMap::default(),
             blocks_to_copy: HashMap::default(),
@@ -51,9 +47,6 @@ impl SchedulerOutputs {
     }
     fn validate(&self) {
         assert!(self.blocks_to_swap_in.is_empty() || self.blocks_to_swap_out.is_empty());
-        // swapping not impl yet
-        assert!(self.blocks_to_swap_in.is_empty());
-        assert!(self.blocks_to_swap_out.is_empty());
 
         self.dropped_seq_groups.iter().for_each(|sg| {
             assert!(sg.is_finished());
@@ -70,6 +63,14 @@ impl SchedulerOutputs {
             && self.blocks_to_copy.is_empty()
     }
 
+    /// Sequences the model runs on in the step.
+    pub fn num_running_seqs(&self) -> usize {
+        self.next_seq_groups
+            .iter()
+            .map(|sg| sg.num_seqs(Some(SchedulingPhase::Running)))
+            .sum()
+    }
+
     pub fn copy_block(&mut self, src_block: usize, dst_block: usize) {
         self.blocks_to_copy
             .entry(src_block)
@@ -92,15 +93,85 @@ enum Queue {
 
 const NUM_QUEUES: usize = Queue::Swapped as usize + 1;
 
+/// Scheduling counters for a given priority class.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PriorityStats {
+    pub scheduled: usize,
+    pub preempted: usize,
+    /// Preemptions that swapped the KV cache out to CPU memory.
+    pub swapped_out: usize,
+    /// Preemptions that dropped the KV cache, to be recomputed later.
+    pub recomputed: usize,
+}
+
+/// KV cache blocks of a sequence; see TBlockSpaceManager::seq_cache_usage().
+/// A block used by several sequences is charged to the first one that took it
+/// (and then to the next one, when that one frees it).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeqCacheUsage {
+    pub gpu_blocks: usize,
+    pub cpu_blocks: usize,
+    /// Of the blocks charged to the sequence, the ones also used by other sequences.
+    pub shared_blocks: usize,
+}
+
+/// Block counts of the GPU or CPU part of the KV cache.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachePoolUsage {
+    pub num_blocks: usize,
+    /// Free blocks, including the ones only kept for the prefix cache.
+    pub free_blocks: usize,
+    /// Blocks used by at least one sequence; the rest (neither free nor used) are waiting
+    /// for a swap-out copy to finish.
+    pub used_blocks: usize,
+    /// Blocks used by more than one sequence (after a fork, or from the prefix cache).
+    pub shared_blocks: usize,
+}
+
+/// KV cache usage of a request (sequence group).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestCacheUsage {
+    pub request_id: String,
+    /// Tokens in the context of the unfinished sequences (tokens shared after a fork
+    /// are counted for every sequence).
+    pub logical_tokens: usize,
+    /// Blocks charged to the sequences of the request (see SeqCacheUsage).
+    pub gpu_blocks: usize,
+    pub cpu_blocks: usize,
+    pub shared_blocks: usize,
+}
+
+/// See Scheduler::cache_report().
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheReport {
+    pub requests: Vec<RequestCacheUsage>,
+    pub gpu: CachePoolUsage,
+    pub cpu: CachePoolUsage,
+    /// GPU blocks kept free when admitting requests.
+    pub watermark_blocks: usize,
+}
+
 /// Scheduler.
 pub struct Scheduler<ME: ModelExec> {
     pub(crate) config: Arc<RllmConfig<ME>>,
     prompt_limit: usize,
     pub(crate) block_manager: ME::BlockSpaceManager,
     freed_seq_ids: RefCell<Vec<usize>>,
+    /// Requests with a controller instantiated in aicirt that never reached mid_process().
+    freed_req_ids: RefCell<Vec<String>>,
     seq_mgr: Arc<ME::SequenceManager>,
 
     queues: Mutex<Vec<Vec<SequenceGroup>>>,
+    /// Finished groups kept for extend_seq_group() (SamplingParams::retain_after_finish),
+    /// oldest first.
+    retained: Vec<SequenceGroup>,
+    priority_stats: HashMap<i32, PriorityStats>,
+    num_deadline_exceeded: usize,
+    /// When false, groups that were never scheduled stay in the waiting queue
+    /// (preempted ones still come back); see set_admit_new().
+    admit_new: bool,
+    clock: Box<dyn Fn() -> Instant + Send>,
+    pub(crate) listeners: Listeners,
 }
 
 impl<ME: ModelExec> Scheduler<ME> {
@@ -154,46 +225,333 @@ impl<ME: ModelExec> Scheduler<ME> {
         block_manager: ME::BlockSpaceManager,
         config: Arc<RllmConfig<ME>>,
     ) -> Self {
-        let prompt_limit = std::cmp::min(
-            config.scheduler.max_model_len,
-            config.scheduler.max_num_batched_tokens,
-        );
+        let prompt_limit = if config.scheduler.is_chunked_prefill() {
+            // long prompts are split across several steps
+            config.scheduler.max_model_len
+        } else {
+            std::cmp::min(
+                config.scheduler.max_model_len,
+                config.scheduler.max_num_batched_tokens,
+            )
+        };
         Self {
             config,
             seq_mgr,
             prompt_limit,
             block_manager,
             freed_seq_ids: RefCell::new(Vec::new()),
+            freed_req_ids: RefCell::new(Vec::new()),
             queues: Mutex::new((0..NUM_QUEUES).map(|_| Vec::new()).collect()),
+            retained: Vec::new(),
+            priority_stats: HashMap::default(),
+            num_deadline_exceeded: 0,
+            admit_new: true,
+            clock: Box::new(Instant::now),
+            listeners: Listeners::default(),
+        }
+    }
+
+    /// Replace Instant::now() as the source of time for deadlines and priority boosts.
+    pub fn set_clock(&mut self, clock: impl Fn() -> Instant + Send + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Number of requests finished because of SamplingParams.deadline.
+    pub fn get_num_deadline_exceeded(&self) -> usize {
+        self.num_deadline_exceeded
+    }
+
+    pub fn get_priority_stats(&self) -> HashMap<i32, PriorityStats> {
+        self.priority_stats.clone()
+    }
+
+    /// KV cache blocks held by each request, and the totals of the pools.
+    /// Only includes the requests in the queues (i.e., not the ones being stepped),
+    /// and the retained ones (see SamplingParams::retain_after_finish).
+    /// The gpu_blocks of the requests and the free GPU blocks add up to the used and
+    /// free blocks of the pool, as each block is charged to one sequence.
+    pub fn cache_report(&self) -> CacheReport {
+        let mut requests = Vec::new();
+        let mut report = |sg: &SequenceGroup| {
+            let mut r = RequestCacheUsage {
+                request_id: sg.request_id.clone(),
+                ..Default::default()
+            };
+            for seq in sg.seqs.iter() {
+                if !seq.is_finished() {
+                    r.logical_tokens += seq.get_len();
+                }
+                let usage = self.block_manager.seq_cache_usage(seq);
+                r.gpu_blocks += usage.gpu_blocks;
+                r.cpu_blocks += usage.cpu_blocks;
+                r.shared_blocks += usage.shared_blocks;
+            }
+            requests.push(r);
+        };
+        self.for_each_sg(|sg| report(sg));
+        self.retained.iter().for_each(&mut report);
+        let (gpu, cpu) = self.block_manager.pool_usage();
+        CacheReport {
+            requests,
+            gpu,
+            cpu,
+            watermark_blocks: self.block_manager.get_watermark_blocks(),
+        }
+    }
+
+    fn prio_stats(&mut self, seq_group: &SequenceGroup) -> &mut PriorityStats {
+        self.priority_stats
+            .entry(seq_group.sampling_params.priority)
+            .or_default()
+    }
+
+    /// Stop (or restart) admitting groups that were never scheduled; see RllmEngine::drain().
+    pub(crate) fn set_admit_new(&mut self, admit: bool) {
+        self.admit_new = admit;
+    }
+
+    /// Take the groups that were never scheduled out of the waiting queue, in order of
+    /// arrival; their sequences are freed (without an output, or listener events).
+    pub(crate) fn take_unscheduled(&mut self) -> Vec<SequenceGroup> {
+        let mut taken: Vec<SequenceGroup> = self.q_with(Queue::Waiting, |q| {
+            let (taken, rest): (Vec<_>, Vec<_>) = q
+                .drain(..)
+                .partition(|sg| sg.first_scheduled_time.is_none());
+            *q = rest;
+            taken
+        });
+        taken.sort_by_key(|sg| sg.arrival_time);
+        for sg in taken.iter() {
+            sg.seqs.iter().for_each(|seq| self.free_seq(seq));
         }
+        taken
+    }
+
+    /// Finish all unfinished groups with FinishReason::Interrupted; they are dropped,
+    /// with their final outputs, in the next schedule().
+    /// Returns the ids of the ones that were swapped out.
+    pub(crate) fn interrupt_all(&mut self) -> Vec<String> {
+        let swapped = self.q_map(Queue::Swapped, |sg| sg.request_id.clone());
+        self.for_each_sg(|sg| {
+            if !sg.is_finished() {
+                self.set_phase(sg, SchedulingPhase::Finished(FinishReason::Interrupted));
+            }
+        });
+        swapped
     }
 
     pub(crate) fn get_freed_seq_ids(&self) -> Vec<usize> {
         self.freed_seq_ids.borrow_mut().drain(..).collect()
     }
 
-    pub fn add_seq_group(&mut self, seq_group: SequenceGroup) {
+    pub(crate) fn free_req_id(&self, request_id: &str) {
+        self.freed_req_ids.borrow_mut().push(request_id.to_string());
+    }
+
+    pub(crate) fn get_freed_req_ids(&self) -> Vec<String> {
+        self.freed_req_ids.borrow_mut().drain(..).collect()
+    }
+
+    pub fn add_seq_group(&mut self, mut seq_group: SequenceGroup) {
+        Self::set_retain_kv(&mut seq_group);
         let len = seq_group.seqs[0].prompt_len;
         log::debug!(
             "add_seq_group: {}; {len} tokens; {:?}",
             seq_group.request_id,
             limit_str(&seq_group.prompt, 200)
         );
+        self.listeners.request_added(&seq_group);
         self.q_push(Queue::Waiting, seq_group);
     }
 
+    /// Add the groups made by `fork` from the group of `request_id`.
+    /// They go to the same queue as the original, since they share its KV cache.
+    pub(crate) fn fork_seq_group(
+        &mut self,
+        request_id: &str,
+        fork: impl FnOnce(&SequenceGroup) -> Result<Vec<SequenceGroup>>,
+    ) -> Result<()> {
+        let mut queues = self.queues.lock().unwrap();
+        for q in [Queue::Waiting, Queue::OnGpu, Queue::Swapped] {
+            let mut forks = match queues[q as usize]
+                .iter()
+                .find(|sg| sg.request_id == request_id)
+            {
+                Some(sg) if sg.is_finished() => bail_user!("request {request_id} is finished"),
+                Some(_) if matches!(q, Queue::Swapped) => {
+                    bail_user!("request {request_id} is swapped out")
+                }
+                Some(sg) => fork(sg)?,
+                None => continue,
+            };
+            forks.iter_mut().for_each(Self::set_retain_kv);
+            forks.iter().for_each(|sg| self.listeners.request_added(sg));
+            queues[q as usize].extend(forks);
+            return Ok(());
+        }
+        bail_user!("request {request_id} not found")
+    }
+
     pub fn abort_seq_group(&mut self, request_id: &str) {
         self.for_each_sg(|seq_group| {
             if seq_group.request_id == request_id {
                 self.set_phase(seq_group, SchedulingPhase::Finished(FinishReason::Aborted));
             }
         });
+        if let Some(idx) = self.retained_idx(request_id) {
+            let mut seq_group = self.retained.remove(idx);
+            for seq in seq_group.seqs.iter_mut() {
+                seq.retain_kv = false;
+                self.free_seq(seq);
+            }
+        }
+    }
+
+    // sequences of groups with SamplingParams::retain_after_finish keep their KV cache
+    // when they finish; see finish_seq()
+    fn set_retain_kv(seq_group: &mut SequenceGroup) {
+        let retain = seq_group.sampling_params.retain_after_finish;
+        seq_group
+            .seqs
+            .iter_mut()
+            .for_each(|seq| seq.retain_kv = retain);
+    }
+
+    fn retained_idx(&self, request_id: &str) -> Option<usize> {
+        self.retained
+            .iter()
+            .position(|sg| sg.request_id == request_id)
+    }
+
+    /// Free the KV cache of the oldest retained group that still has it, so that
+    /// running or waiting groups can use the blocks; returns false if there is none.
+    /// Extending the group later recomputes the cache.
+    fn evict_retained(&mut self) -> bool {
+        let seq_mgr = self.seq_mgr.deref();
+        let sg = match self
+            .retained
+            .iter_mut()
+            .find(|sg| sg.seqs.iter().any(|seq| seq.num_kv_computed > 0))
+        {
+            Some(sg) => sg,
+            None => return false,
+        };
+        log::info!(
+            "evicting the KV cache of retained request {}; extending it will recompute {} tokens",
+            sg.request_id,
+            sg.only_seq().get_len()
+        );
+        for seq in sg.seqs.iter_mut() {
+            seq.clear_computed_kv(seq_mgr);
+        }
+        true
+    }
+
+    /// Evict retained KV caches (see evict_retained()) until `fits` holds;
+    /// returns false if it still doesn't hold with all of them evicted.
+    fn evict_until(&mut self, fits: impl Fn(&Self) -> bool) -> bool {
+        while !fits(self) {
+            if !self.evict_retained() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Continue a retained group (see SamplingParams::retain_after_finish) with `tokens`
+    /// appended to its sequence as a new prompt; see Sequence::extend_prompt().
+    /// When the group still has its KV cache, it goes back on the GPU, where only the new
+    /// tokens (and the last generated one) are prefilled, in chunks like a prompt.
+    /// When the cache was evicted, the group waits to recompute all of its tokens.
+    /// Fails if the group is still in the queues, i.e., until its final output.
+    pub(crate) fn extend_seq_group(&mut self, request_id: &str, tokens: &[Token]) -> Result<()> {
+        if self
+            .queues
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .any(|sg| sg.request_id == request_id)
+        {
+            bail_user!("request {request_id} is still running");
+        }
+        let idx = match self.retained_idx(request_id) {
+            Some(idx) => idx,
+            None => bail_user!("request {request_id} not found, or not retained"),
+        };
+
+        let sg = &self.retained[idx];
+        let len = sg.only_seq().get_len() + tokens.len();
+        let max = self.config.scheduler.max_model_len - 1;
+        if len > max && sg.sampling_params.context_truncation == Truncation::Error {
+            return Err(AddRequestError::PromptTooLong {
+                prompt_len: len,
+                max,
+            }
+            .into());
+        }
+
+        let mut sg = self.retained.remove(idx);
+        let now = (self.clock)();
+        sg.arrival_time = now;
+        sg.first_token_time = None;
+        let seq = &mut sg.seqs[0];
+        seq.extend_prompt(tokens);
+        let q = if seq.num_kv_computed > 0 {
+            log::debug!(
+                "extending {request_id}: {} new tokens after {} with KV cache",
+                tokens.len(),
+                seq.num_kv_computed
+            );
+            seq.sched_phase = SchedulingPhase::Running;
+            sg.first_scheduled_time = Some(now);
+            Queue::OnGpu
+        } else {
+            log::info!(
+                "extending {request_id}: KV cache was evicted, recomputing {} tokens",
+                seq.get_len()
+            );
+            seq.sched_phase = SchedulingPhase::Waiting;
+            sg.first_scheduled_time = None;
+            Queue::Waiting
+        };
+        self.listeners.request_added(&sg);
+        if matches!(q, Queue::OnGpu) {
+            self.listeners.scheduled(&sg);
+        }
+        self.q_push(q, sg);
+        Ok(())
     }
 
     pub fn has_unfinished_seqs(&self) -> bool {
         self.get_num_unfinished_seq_groups() > 0
     }
 
+    /// Number of unfinished sequences (waiting, on GPU, swapped out).
+    pub fn get_num_seqs(&self) -> (usize, usize, usize) {
+        let count = |q| {
+            self.q_with(q, |q| {
+                q.iter()
+                    .flat_map(|sg| sg.seqs.iter())
+                    .filter(|seq| !seq.is_finished())
+                    .count()
+            })
+        };
+        (
+            count(Queue::Waiting),
+            count(Queue::OnGpu),
+            count(Queue::Swapped),
+        )
+    }
+
+    /// Number of sequence groups in the waiting queue, and the sum of their prompt lengths;
+    /// see SchedulerConfig::max_waiting_groups.
+    pub fn get_waiting_queue_size(&self) -> (usize, usize) {
+        self.q_with(Queue::Waiting, |q| {
+            (q.len(), q.iter().map(|sg| sg.seqs[0].prompt_len).sum())
+        })
+    }
+
     pub fn get_num_unfinished_seq_groups(&self) -> usize {
         self.queues.lock().unwrap().iter().map(|q| q.len()).sum()
     }
@@ -219,8 +577,42 @@ impl<ME: ModelExec> Scheduler<ME> {
     }
 
     fn step_drop_finished(&mut self, outputs: &mut SchedulerOutputs) {
+        // this covers all queues, so swapped out groups also expire (freeing their CPU blocks)
+        let now = (self.clock)();
+        let mut num_expired = 0;
+        self.for_each_sg(|sg| {
+            if sg.is_finished() {
+                return;
+            }
+            if let Some(deadline) = sg.sampling_params.deadline {
+                if now.saturating_duration_since(sg.arrival_time) > deadline {
+                    log::warn!(
+                        "seq_group {} exceeded deadline {:?}",
+                        sg.request_id,
+                        deadline
+                    );
+                    num_expired += 1;
+                    self.set_phase(
+                        sg,
+                        SchedulingPhase::Finished(FinishReason::DeadlineExceeded),
+                    );
+                    return;
+                }
+            }
+            if let Some(max_total) = sg.sampling_params.max_total_tokens {
+                if sg.total_tokens() >= max_total {
+                    log::debug!("seq_group {} used {} tokens", sg.request_id, max_total);
+                    self.set_phase(
+                        sg,
+                        SchedulingPhase::Finished(FinishReason::MaxTokensReached),
+                    );
+                }
+            }
+        });
+        self.num_deadline_exceeded += num_expired;
+
         self.for_each_sg(|sg| {
-            if sg.sampling_params.controller.is_some() {
+            if sg.has_controller() {
                 let fuel = sg.usage.fuel_tokens();
                 let max_fuel = std::cmp::min(
                     sg.sampling_params.aici_fuel.unwrap_or(usize::MAX),
@@ -233,6 +625,44 @@ impl<ME: ModelExec> Scheduler<ME> {
             }
         });
 
+        // this can happen when generating (or splicing) past the end of the context;
+        // unless the request allows dropping tokens from the context, finish just
+        // the offending sequence, so the batch can still be built
+        let max_len = self.config.scheduler.max_model_len;
+        self.for_each_sg(|sg| {
+            let truncation = sg.sampling_params.context_truncation;
+            for seq in sg.seqs.iter_mut() {
+                // a sequence of max_len tokens has no room for the next one
+                if seq.is_finished() || seq.get_len() < max_len {
+                    continue;
+                }
+                match truncation {
+                    // only running sequences grow, so they are not swapped out yet
+                    Truncation::SlidingWindow { keep_first }
+                        if seq.sched_phase != SchedulingPhase::Swapped =>
+                    {
+                        // keep half of the rest of the context, to truncate less often
+                        let window = (max_len - keep_first) / 2;
+                        log::debug!(
+                            "seq {}: dropping {} tokens from the context",
+                            seq.seq_id,
+                            seq.get_len() - keep_first - window
+                        );
+                        seq.truncate_context(self.seq_mgr.deref(), keep_first, window);
+                    }
+                    _ => {
+                        log::warn!(
+                            "seq {} is too long ({} >= {})",
+                            seq.seq_id,
+                            seq.get_len(),
+                            max_len
+                        );
+                        self.finish_seq(seq, FinishReason::LengthError);
+                    }
+                }
+            }
+        });
+
         self.q_for_each(Queue::Waiting, |seq_group| {
             assert!(seq_group.seqs.len() == 1);
             let num_prompt_tokens = seq_group.get_seqs(None)[0].get_len();
@@ -250,6 +680,13 @@ impl<ME: ModelExec> Scheduler<ME> {
         self.queues.lock().unwrap().iter_mut().for_each(|q| {
             Self::drop_finished(outputs, q);
         });
+        for sg in outputs.dropped_seq_groups.iter() {
+            self.listeners.finished(sg);
+            // finished before its controller got the sequence id (see AiciMidOp::req_id)
+            if sg.sampling_params.controller.is_some() && !sg.seqs.iter().any(|s| s.has_aici) {
+                self.free_req_id(&sg.request_id);
+            }
+        }
     }
 
     fn max_num_running_seq(&self, q: Queue) -> usize {
@@ -258,13 +695,67 @@ impl<ME: ModelExec> Scheduler<ME> {
             .sum()
     }
 
+    /// GPU blocks the running sequences of the group are expected to need
+    /// for the next SchedulerConfig::admission_lookahead tokens.
+    fn future_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        let lookahead = self.config.scheduler.admission_lookahead;
+        let max_tokens = seq_group.sampling_params.max_tokens;
+        seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| {
+                let n = std::cmp::min(lookahead, seq.remaining_token_bound(max_tokens));
+                self.block_manager.num_blocks_to_grow(seq, n)
+            })
+            .sum()
+    }
+
+    /// Check if every running sequence on the GPU can take the next step
+    /// without preempting anything.
+    fn running_can_step(&self) -> bool {
+        let needed: usize = self
+            .q_map(Queue::OnGpu, |sg| {
+                sg.get_seqs(Some(SchedulingPhase::Running))
+                    .iter()
+                    .map(|seq| self.block_manager.num_blocks_to_grow(seq, 0))
+                    .sum::<usize>()
+            })
+            .iter()
+            .sum();
+        needed <= self.block_manager.get_num_free_gpu_blocks()
+    }
+
     fn step_prompts(&mut self, outputs: &mut SchedulerOutputs) {
         log::trace!("step_start_waiting ({} seqs)", self.q_len(Queue::Waiting));
         self.sort_by_priority(Queue::Waiting);
 
-        let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu);
+        let mut num_curr_seqs = self.num_curr_seqs(outputs);
+        let mut reserved: usize = self
+            .q_map(Queue::OnGpu, |sg| self.future_blocks(sg))
+            .iter()
+            .sum();
+        reserved += outputs
+            .next_seq_groups
+            .iter()
+            .map(|sg| self.future_blocks(sg))
+            .sum::<usize>();
+        let max_batched = self.config.scheduler.max_num_batched_tokens;
+        let max_prefill = self.config.scheduler.max_prefill_tokens;
+        let mut held = Vec::new();
         while let Some(mut seq_group) = self.q_pop(Queue::Waiting) {
-            let num_prompt_tokens = seq_group.only_seq().get_len();
+            if !self.admit_new && seq_group.first_scheduled_time.is_none() {
+                held.push(seq_group);
+                continue;
+            }
+            // the prompt gets what the running sequences left of the batch;
+            // the rest of it is computed in the next step(s), in chunks
+            let seq = seq_group.only_seq();
+            let pending = std::cmp::max(seq.get_len() - seq.num_kv_computed, 1);
+            let left = std::cmp::min(
+                max_batched.saturating_sub(outputs.num_batched_tokens),
+                std::cmp::max(self.prefill_budget(outputs, 0), 1),
+            );
+            let num_prompt_tokens = pending.min(max_prefill).min(left);
             let num_new_seqs = seq_group.get_max_num_running_seqs();
 
             log::trace!(
@@ -274,28 +765,54 @@ impl<ME: ModelExec> Scheduler<ME> {
                 num_new_seqs
             );
 
-            // Check allocation and batch token limits
-            if !self.block_manager.can_allocate(&seq_group)
-                || outputs.num_batched_tokens + num_prompt_tokens
-                    > self.config.scheduler.max_num_batched_tokens
+            // Check batch token limits (a prompt isn't started with a single token)
+            // and allocation
+            if num_prompt_tokens == 0
+                || (num_prompt_tokens == 1 && pending > 1)
                 || num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
+                || !self.evict_until(|s| s.block_manager.can_allocate(&seq_group, reserved))
             {
                 self.q_push(Queue::Waiting, seq_group); // Put back the sequence group
                 break;
             }
 
-            self._allocate(&mut seq_group);
+            self._allocate(&mut seq_group, num_prompt_tokens);
+            reserved += self.future_blocks(&seq_group);
+            seq_group
+                .first_scheduled_time
+                .get_or_insert_with(Instant::now);
+            self.prio_stats(&seq_group).scheduled += 1;
+            self.listeners.scheduled(&seq_group);
             outputs.next_seq_groups.push(seq_group);
             outputs.num_batched_tokens += num_prompt_tokens;
+            outputs.num_prefill_tokens += Self::num_step_prefill_tokens(&seq_group);
             num_curr_seqs += num_new_seqs;
         }
+        if !held.is_empty() {
+            // popped from the end, so they go back there, in the same order
+            held.reverse();
+            self.q_with(Queue::Waiting, |q| q.append(&mut held));
+        }
+    }
+
+    /// Priority of the group, including the boost for time spent in the queue.
+    fn effective_priority(&self, seq_group: &SequenceGroup, now: Instant) -> i32 {
+        let prio = seq_group.sampling_params.priority;
+        let boost_ms = self.config.scheduler.priority_boost_ms;
+        if boost_ms == 0 {
+            return prio;
+        }
+        let waited = now.duration_since(seq_group.arrival_time).as_millis() as u64;
+        let boost = std::cmp::min(waited / boost_ms, i32::MAX as u64) as i32;
+        prio.saturating_add(boost)
     }
 
     fn sort_by_priority(&self, q: Queue) {
+        let now = (self.clock)();
         self.q_with(q, |seq_groups| {
             // note that we take elements first from the end of the queue (Vec::pop())
-            seq_groups.sort_by_key(|g| g.arrival_time);
-            seq_groups.reverse();
+            // so the highest priority and earliest arrival time goes last
+            seq_groups.sort_by_key(|g| (self.effective_priority(g, now), Reverse(g.arrival_time)));
         });
     }
 
@@ -305,45 +822,145 @@ impl<ME: ModelExec> Scheduler<ME> {
         let mut did_preempt = false;
         self.sort_by_priority(Queue::OnGpu);
 
-        let mut suspended = Vec::new();
+        let mut skipped = Vec::new();
+        let max_batched = self.config.scheduler.max_num_batched_tokens;
+        let max_prefill = self.config.scheduler.max_prefill_tokens;
+        // every sequence still in the queue needs (at least) one token in the batch
+        let mut reserve: usize = self
+            .q_map(Queue::OnGpu, |sg| Self::num_step_seqs(sg))
+            .iter()
+            .sum();
 
-        while let Some(mut seq_group) = self.q_pop(Queue::OnGpu) {
+        'groups: while let Some(mut seq_group) = self.q_pop(Queue::OnGpu) {
             if seq_group.is_suspended() {
-                suspended.push(seq_group);
+                skipped.push(seq_group);
                 continue;
             }
+            let num_seqs = Self::num_step_seqs(&seq_group);
+            reserve = reserve.saturating_sub(num_seqs);
+
+            // Tokens spliced in by the controller are all computed in the next step,
+            // so a single sequence may need many tokens; these count against the batch limit,
+            // and the prefill limit, like the rest of a prompt prefilled in chunks.
+            // Sequences running on a single token are only limited by the former.
+            let left = std::cmp::min(
+                max_batched.saturating_sub(outputs.num_batched_tokens),
+                std::cmp::max(self.prefill_budget(outputs, reserve), num_seqs),
+            );
+            let mut max_tokens = max_prefill;
+            if Self::num_step_tokens(&seq_group, max_tokens) > left {
+                if outputs.num_batched_tokens > 0 && (num_seqs > 1 || left == 0) {
+                    // try again in the next step
+                    skipped.push(seq_group);
+                    continue;
+                }
+                // compute the rest in the next step(s), like a prompt chunk;
+                // the budget is split between the sequences, each running on at least one token
+                let per_seq = left / std::cmp::max(num_seqs, 1);
+                max_tokens = std::cmp::min(max_tokens, std::cmp::max(per_seq, 1));
+            }
+
             while !self.block_manager.can_append_slot(&seq_group) {
+                if self.evict_retained() {
+                    continue;
+                }
                 did_preempt = true;
                 if self.q_len(Queue::OnGpu) > 0 {
-                    // take the first group in queue (lowest priority)
+                    // take the first group in queue (lowest priority, and youngest among these)
                     let victim_seq_group = self.q_with(Queue::OnGpu, |q| q.remove(0));
                     self._preempt(victim_seq_group, outputs);
                 } else {
                     // preempt the current sequence group and stop
                     self._preempt(seq_group, outputs);
-                    return did_preempt;
+                    break 'groups;
                 }
             }
 
-            self._append_slots(&mut seq_group, outputs);
+            self._append_slots(&mut seq_group, outputs, max_tokens);
+            outputs.num_batched_tokens += Self::num_step_tokens(&seq_group, max_tokens);
+            outputs.num_prefill_tokens += Self::num_step_prefill_tokens(&seq_group);
             outputs.next_seq_groups.push(seq_group);
         }
 
-        if suspended.len() > 0 {
-            self.q_with(Queue::OnGpu, |q| q.append(&mut suspended));
+        if !skipped.is_empty() {
+            self.q_with(Queue::OnGpu, |q| q.append(&mut skipped));
         }
 
         return did_preempt;
     }
 
-    fn _allocate(&mut self, seq_group: &mut SequenceGroup) {
+    fn _allocate(&mut self, seq_group: &mut SequenceGroup, max_tokens: usize) {
         self.block_manager.allocate(seq_group);
         self.set_phase(seq_group, SchedulingPhase::Running);
+        for seq in &mut seq_group.seqs {
+            seq.set_prefill_chunk(max_tokens);
+        }
+    }
+
+    fn num_step_seqs(seq_group: &SequenceGroup) -> usize {
+        if seq_group.is_suspended() {
+            0
+        } else {
+            seq_group.num_seqs(Some(SchedulingPhase::Running))
+        }
+    }
+
+    /// Part of num_step_tokens() counted in SchedulerOutputs::num_prefill_tokens,
+    /// once the prefill chunks are set.
+    fn num_step_prefill_tokens(seq_group: &SequenceGroup) -> usize {
+        seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| seq.num_query_tokens())
+            .filter(|&n| n > 1)
+            .sum()
+    }
+
+    /// Sequences on the GPU, including the ones already in the batch.
+    fn num_curr_seqs(&self, outputs: &SchedulerOutputs) -> usize {
+        let in_batch: usize = outputs
+            .next_seq_groups
+            .iter()
+            .map(|sg| sg.get_max_num_running_seqs())
+            .sum();
+        self.max_num_running_seq(Queue::OnGpu) + in_batch
     }
 
-    fn _append_slots(&mut self, seq_group: &mut SequenceGroup, outputs: &mut SchedulerOutputs) {
+    /// Number of prompt tokens (see SchedulerOutputs::num_prefill_tokens) that can still be
+    /// added to the batch, leaving room for `reserve` tokens of sequences not in it yet.
+    fn prefill_budget(&self, outputs: &SchedulerOutputs, reserve: usize) -> usize {
+        let cfg = &self.config.scheduler;
+        let batched = cfg
+            .max_num_batched_tokens
+            .saturating_sub(outputs.num_batched_tokens + reserve);
+        let prefill = cfg
+            .max_prefill_tokens_per_step
+            .saturating_sub(outputs.num_prefill_tokens);
+        std::cmp::min(batched, prefill)
+    }
+
+    /// Number of tokens the model runs on for the group in the next step,
+    /// when computing at most `max_tokens` for every sequence.
+    fn num_step_tokens(seq_group: &SequenceGroup, max_tokens: usize) -> usize {
+        seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| {
+                let pending = seq.get_len() - seq.num_kv_computed;
+                pending.clamp(1, std::cmp::max(max_tokens, 1))
+            })
+            .sum()
+    }
+
+    fn _append_slots(
+        &mut self,
+        seq_group: &mut SequenceGroup,
+        outputs: &mut SchedulerOutputs,
+        max_tokens: usize,
+    ) {
         for seq in &mut seq_group.seqs {
             if seq.sched_phase == SchedulingPhase::Running {
+                seq.set_prefill_chunk(max_tokens);
                 self.block_manager.append_slots(seq, outputs);
             }
         }
@@ -354,25 +971,56 @@ impl<ME: ModelExec> Scheduler<ME> {
         outputs.blocks_to_swap_in.extend(src_to_dst);
     }
 
-    fn _preempt(&mut self, mut seq_group: SequenceGroup, outputs: &mut SchedulerOutputs) {
-        let mode = if seq_group.get_max_num_running_seqs() == 1 {
-            PreemptionMode::Recompute
-        } else {
-            PreemptionMode::Swap
+    fn preemption_mode(&self, seq_group: &SequenceGroup) -> PreemptionMode {
+        let cfg = &self.config.scheduler;
+        if seq_group.get_max_num_running_seqs() != 1 {
+            // the waiting queue only holds single-sequence groups
+            return PreemptionMode::Swap;
+        }
+        let mode = match cfg.preemption_mode {
+            PreemptionMode::Auto => {
+                let len = seq_group
+                    .seqs
+                    .iter()
+                    .map(|s| s.get_len())
+                    .max()
+                    .unwrap_or(0);
+                if len <= cfg.recompute_max_len {
+                    PreemptionMode::Recompute
+                } else {
+                    PreemptionMode::Swap
+                }
+            }
+            mode => mode,
         };
+        if mode == PreemptionMode::Swap && !self.block_manager.can_swap_out(seq_group) {
+            // no (or not enough) CPU swap space; recomputing is always possible
+            return PreemptionMode::Recompute;
+        }
+        mode
+    }
+
+    fn _preempt(&mut self, mut seq_group: SequenceGroup, outputs: &mut SchedulerOutputs) {
+        let mode = self.preemption_mode(&seq_group);
 
         log::debug!("preempting seq_group {} ({:?})", seq_group.request_id, mode);
+        self.prio_stats(&seq_group).preempted += 1;
+        self.listeners.preempted(&seq_group, mode);
 
         match mode {
             PreemptionMode::Swap => {
                 if !self.block_manager.can_swap_out(&seq_group) {
                     panic!("Aborted due to the lack of CPU swap space. Please increase the swap space to avoid this error.");
                 }
+                self.prio_stats(&seq_group).swapped_out += 1;
                 let map = self.block_manager.swap_out(&mut seq_group);
                 outputs.blocks_to_swap_out.extend(map);
                 self.q_push(Queue::Swapped, seq_group);
             }
             PreemptionMode::Recompute => {
+                // generated tokens stay in the sequence, and the sampling state in the group,
+                // so the continuation is the same as without preemption
+                self.prio_stats(&seq_group).recomputed += 1;
                 self.set_phase(&mut seq_group, SchedulingPhase::Waiting);
                 self.q_push(Queue::Waiting, seq_group);
             }
@@ -382,18 +1030,20 @@ impl<ME: ModelExec> Scheduler<ME> {
     fn step_swap_in(&mut self, outputs: &mut SchedulerOutputs) {
         self.sort_by_priority(Queue::Swapped);
 
-        let mut num_curr_seqs = self.max_num_running_seq(Queue::OnGpu);
+        let mut num_curr_seqs = self.num_curr_seqs(outputs);
         while let Some(mut seq_group) = self.q_pop(Queue::Swapped) {
             let num_new_seqs = seq_group.get_max_num_running_seqs();
-            if !self.block_manager.can_swap_in(&seq_group)
-                || num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
+            if num_curr_seqs + num_new_seqs > self.config.scheduler.max_num_seqs
+                || !self.evict_until(|s| s.block_manager.can_swap_in(&seq_group))
             {
                 self.q_push(Queue::Swapped, seq_group);
                 break;
             }
             self._swap_in(&mut seq_group, outputs);
-            self._append_slots(&mut seq_group, outputs);
+            let max_prefill = self.config.scheduler.max_prefill_tokens;
+            self._append_slots(&mut seq_group, outputs, max_prefill);
             num_curr_seqs += num_new_seqs;
+            self.listeners.scheduled(&seq_group);
             self.q_push(Queue::OnGpu, seq_group);
         }
     }
@@ -408,34 +1058,34 @@ impl<ME: ModelExec> Scheduler<ME> {
                 seq.sched_phase = SchedulingPhase::Running;
             }
         });
+        for sg in outputs.dropped_seq_groups.drain(..) {
+            if sg.seqs.iter().any(|seq| seq.retain_kv) {
+                log::debug!("retaining {}", sg.request_id);
+                self.retained.push(sg);
+            }
+        }
     }
 
     pub fn schedule(&mut self) -> SchedulerOutputs {
         let mut outputs = SchedulerOutputs::new();
         self.step_drop_finished(&mut outputs);
 
-        if self.q_len(Queue::Swapped) == 0 {
-            self.step_prompts(&mut outputs);
-        }
+        // the running sequences go first, so that a long prompt doesn't hold up their tokens;
+        // new prompts share the rest of the batch (see max_prefill_tokens_per_step)
+        let did_preempt = self.step_generation(&mut outputs);
 
-        if outputs.next_seq_groups.is_empty() {
-            let did_preempt = self.step_generation(&mut outputs);
-
-            // Swap in logic for swapped sequences
-            if !did_preempt {
-                self.step_swap_in(&mut outputs);
-            }
+        // Swap in logic for swapped sequences
+        if !did_preempt {
+            self.step_swap_in(&mut outputs);
+        }
 
-            // Update num_batched_tokens based on the sequences in the RUNNING state
-            outputs.num_batched_tokens = self
-                .q_map(Queue::OnGpu, |sg| {
-                    sg.get_seqs(Some(SchedulingPhase::Running))
-                        .iter()
-                        .map(|seq| seq.get_len())
-                        .sum()
-                })
-                .iter()
-                .sum();
+        // when the running sequences couldn't all take a step, some of them were preempted;
+        // admitting new prompts now would only make them compete for the same blocks
+        if !did_preempt
+            && self.q_len(Queue::Swapped) == 0
+            && self.evict_until(|s| s.running_can_step())
+        {
+            self.step_prompts(&mut outputs);
         }
 
         outputs.validate();
@@ -452,7 +1102,21 @@ impl<ME: ModelExec> Scheduler<ME> {
                 reason
             )))
         }
+        let retain = matches!(
+            reason,
+            FinishReason::FoundEos | FinishReason::StopString | FinishReason::MaxTokensReached
+        );
         seq.sched_phase = SchedulingPhase::Finished(reason);
+        seq.finish_time = Some(Instant::now());
+        if seq.retain_kv && retain {
+            // the group is retained in step_finished(), with the KV cache
+            return;
+        }
+        seq.retain_kv = false;
+        self.free_seq(seq);
+    }
+
+    fn free_seq(&self, seq: &Sequence) {
         self.freed_seq_ids.borrow_mut().push(seq.seq_id.to_num());
         self.seq_mgr.delete(seq.seq_id);
     }
@@ -469,13 +1133,13 @@ impl<ME: ModelExec> Scheduler<ME> {
                 seq_group
                     .seqs
                     .iter_mut()
-                    .for_each(|seq| self.finish_seq(seq, reason));
+                    .for_each(|seq| self.finish_seq(seq, reason.clone()));
                 return;
             }
         };
         for seq in seq_group.seqs.iter_mut() {
             assert!(!seq.is_finished());
-            seq.sched_phase = status;
+            seq.sched_phase = status.clone();
             if to_waiting {
                 seq.clear_computed_kv(self.seq_mgr.deref());
             }
@@ -487,3 +1151,621 @@ pub struct CacheSize {
     pub gpu: usize,
     pub cpu: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{AiciConfig, ModelMeta, ParallelConfig, SamplingParams, SchedulerConfig},
+        seq::{Token, TokenUsage},
+        testing::{mock_cache, num_blocks, MockBlocks, MockSeqMgr, Recorder},
+        AiciBias, EngineListener, LoaderArgs, LogitsProcessor, RequestMeta, RllmEngine,
+    };
+    use aicirt::TimerRef;
+
+    struct MockBias;
+
+    impl AiciBias<()> for MockBias {
+        fn apply(&self, _logits: &mut (), _seq_id: usize) {}
+    }
+
+    /// Only the types are used; the scheduler never calls the model.
+    struct MockExec;
+
+    impl ModelExec for MockExec {
+        type Tensor = ();
+        type BlockSpaceManager = MockBlocks<Self>;
+        type AiciBias = MockBias;
+        type ModelConfig = ();
+        type ModelLoaderArgs = ();
+        type SequenceManager = MockSeqMgr;
+
+        fn tensor_to_vec1(_tensor: &()) -> Vec<f32> {
+            vec![]
+        }
+        fn load_model_config(_args: &LoaderArgs, _model_args: &mut ()) -> Result<(ModelMeta, ())> {
+            unreachable!("not used by these tests")
+        }
+        fn verify_args(_args: &RllmConfig<Self>) -> Result<()> {
+            Ok(())
+        }
+        fn load_rllm_engine(_args: LoaderArgs, _model_args: ()) -> Result<RllmEngine<Self>> {
+            unreachable!("not used by these tests")
+        }
+        fn sequence_manager(&self) -> Arc<MockSeqMgr> {
+            unreachable!("not used by these tests")
+        }
+        fn run(
+            &mut self,
+            _vocab_size: usize,
+            _tim: &TimerRef,
+            _step_no: usize,
+            _sched_out: &mut SchedulerOutputs,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn get_logits(&self, _seq_id: usize) {}
+        fn finalize_run(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn empty_bias(&self, _vocab_size: usize) -> MockBias {
+            MockBias
+        }
+        fn new_bias(&self, _slice: &[f32], _num_seqs: usize, _vocab_size: usize) -> MockBias {
+            MockBias
+        }
+    }
+
+    fn scheduler(num_gpu_blocks: usize) -> Scheduler<MockExec> {
+        scheduler_with(num_gpu_blocks, |_| {})
+    }
+
+    fn scheduler_with(
+        num_gpu_blocks: usize,
+        update: impl FnOnce(&mut SchedulerConfig),
+    ) -> Scheduler<MockExec> {
+        let (seq_mgr, block_mgr) = mock_cache(Some(num_gpu_blocks));
+        let mut config = RllmConfig {
+            model: (),
+            meta: ModelMeta {
+                id: "mock".to_string(),
+                max_sequence_length: 64,
+                vocab_size: 100,
+                tok_vocab_size: 100,
+            },
+            parallel: ParallelConfig::single(),
+            scheduler: SchedulerConfig {
+                max_num_batched_tokens: 64,
+                max_num_kv_tokens: 640,
+                max_num_seqs: 100,
+                max_model_len: 64,
+                priority_boost_ms: 0,
+                max_prefill_tokens: 64,
+                max_prefill_tokens_per_step: 64,
+                preemption_mode: PreemptionMode::Recompute,
+                recompute_max_len: 64,
+                gpu_watermark: 0.0,
+                admission_lookahead: 4,
+                livelock_steps: 100,
+                max_waiting_groups: 0,
+                max_queued_tokens: 0,
+            },
+            aici: AiciConfig::default(),
+        };
+        update(&mut config.scheduler);
+        Scheduler::new(seq_mgr, block_mgr, Arc::new(config))
+    }
+
+    fn add_request(sched: &mut Scheduler<MockExec>, prompt_len: usize, max_tokens: usize) {
+        let mut sampling_params = SamplingParams::default();
+        sampling_params.max_tokens = max_tokens;
+        add_request_with(sched, &vec![1; prompt_len], sampling_params);
+    }
+
+    fn add_request_with(
+        sched: &mut Scheduler<MockExec>,
+        prompt: &[Token],
+        sampling_params: SamplingParams,
+    ) {
+        let seq = Sequence::new(sched.seq_mgr.new_sequence(), prompt);
+        let sg = SequenceGroup {
+            request_id: format!("req{}", seq.seq_id),
+            prompt: String::new(),
+            seqs: vec![seq],
+            logits_processor: LogitsProcessor::new(&sampling_params, 0),
+            sampling_params,
+            arrival_time: Instant::now(),
+            first_scheduled_time: None,
+            first_token_time: None,
+            max_index: 0,
+            usage: TokenUsage::default(),
+            native_ctrl: false,
+            auth: None,
+            ctrl_prompt: Vec::new(),
+            restored: Vec::new(),
+        };
+        sched.add_seq_group(sg);
+    }
+
+    /// Run the scheduler like RllmEngine::step() does, with one token sampled for every
+    /// running sequence; returns the number of generated tokens of each finished request.
+    fn run_to_completion(sched: &mut Scheduler<MockExec>, max_steps: usize) -> Vec<usize> {
+        run_checking_batches(sched, max_steps, |_| {})
+    }
+
+    /// Like run_to_completion(), calling `check` on every sequence in a batch.
+    fn run_checking_batches(
+        sched: &mut Scheduler<MockExec>,
+        max_steps: usize,
+        mut check: impl FnMut(&Sequence),
+    ) -> Vec<usize> {
+        let mut generated = vec![];
+        for _ in 0..max_steps {
+            if !sched.has_unfinished_seqs() {
+                return generated;
+            }
+            generated.extend(run_step(sched, &mut check));
+        }
+        panic!("requests not finished after {max_steps} steps");
+    }
+
+    /// One step of run_checking_batches(); returns the number of generated tokens
+    /// of each request dropped in the step.
+    fn run_step(sched: &mut Scheduler<MockExec>, mut check: impl FnMut(&Sequence)) -> Vec<usize> {
+        let mut outputs = sched.schedule();
+        let generated = outputs
+            .dropped_seq_groups
+            .iter()
+            .map(|sg| sg.only_seq().get_gen_len())
+            .collect();
+        for sg in outputs.next_seq_groups.iter_mut() {
+            let max_tokens = sg.sampling_params.max_tokens;
+            for seq in sg.seqs.iter_mut() {
+                if seq.sched_phase != SchedulingPhase::Running {
+                    continue;
+                }
+                check(seq);
+                let prefilling = seq.is_prefilling();
+                seq.sync_computed_kv();
+                if prefilling {
+                    continue;
+                }
+                seq.append_tokens(&[1]);
+                if seq.get_gen_len() >= max_tokens {
+                    sched.finish_seq(seq, FinishReason::MaxTokensReached);
+                }
+            }
+        }
+        sched.step_finished(outputs);
+        generated
+    }
+
+    #[test]
+    fn tiny_cache_finishes_all_requests() {
+        // 16 blocks hold 64 tokens; each request grows to 40 tokens (10 blocks)
+        let mut sched = scheduler(16);
+        for _ in 0..6 {
+            add_request(&mut sched, 8, 32);
+        }
+        let generated = run_to_completion(&mut sched, 1000);
+        assert_eq!(generated, vec![32; 6]);
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 16);
+    }
+
+    #[test]
+    fn admission_reserves_blocks_for_running_seqs() {
+        let mut sched = scheduler(4);
+        // 3 blocks for the prompt, and room for one more token
+        add_request(&mut sched, 12, 8);
+        let outputs = sched.schedule();
+        assert_eq!(outputs.next_seq_groups.len(), 1);
+        sched.step_finished(outputs);
+        sched.for_each_ongpu_sg(|sg| {
+            sg.seqs[0].sync_computed_kv();
+            sg.seqs[0].append_tokens(&[1]);
+        });
+
+        // the running sequence will need the last free block soon; the new prompt has to wait
+        add_request(&mut sched, 1, 8);
+        let outputs = sched.schedule();
+        assert_eq!(outputs.next_seq_groups.len(), 1);
+        assert_eq!(outputs.next_seq_groups[0].only_seq().get_len(), 13);
+        assert_eq!(sched.get_num_seqs().0, 1);
+    }
+
+    #[test]
+    fn long_sequences_finish_with_length_error() {
+        let mut sched = scheduler(20);
+        add_request(&mut sched, 16, 200);
+        // the sequence is finished once it has max_model_len (64) tokens
+        assert_eq!(run_to_completion(&mut sched, 1000), vec![48]);
+    }
+
+    #[test]
+    fn unstarted_controllers_are_freed() {
+        let mut sched = scheduler(20);
+        let mut sampling_params = SamplingParams::default();
+        sampling_params.controller = Some("ctrl".to_string());
+        add_request_with(&mut sched, &[1, 2, 3], sampling_params);
+        add_request(&mut sched, 3, 8);
+        let ids: Vec<String> = sched.q_map(Queue::Waiting, |sg| sg.request_id.clone());
+        for id in ids.iter() {
+            sched.abort_seq_group(id);
+        }
+        let outputs = sched.schedule();
+        assert_eq!(outputs.dropped_seq_groups.len(), 2);
+        // only the request with a controller, which never had a mid_process()
+        assert_eq!(sched.get_freed_req_ids(), vec![ids[0].clone()]);
+        assert_eq!(sched.get_freed_seq_ids().len(), 2);
+    }
+
+    #[test]
+    fn sliding_window_generates_past_max_model_len() {
+        let mut sched = scheduler(20);
+        let prompt: Vec<Token> = (10..26).collect();
+        let mut sampling_params = SamplingParams::default();
+        sampling_params.max_tokens = 200;
+        sampling_params.context_truncation = Truncation::SlidingWindow { keep_first: 8 };
+        add_request_with(&mut sched, &prompt, sampling_params);
+
+        let mut num_truncated = 0;
+        let generated = run_checking_batches(&mut sched, 1000, |seq| {
+            assert!(seq.get_len() <= 64);
+            let first: Vec<Token> = (0..8).map(|idx| seq.get_token(idx)).collect();
+            assert_eq!(first, prompt[..8]);
+            if seq.get_len() < seq.all_tokens().len() {
+                num_truncated += 1;
+            }
+        });
+        assert_eq!(generated, vec![200]);
+        assert!(num_truncated > 0);
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 20);
+    }
+
+    #[test]
+    fn generation_continues_during_long_prefill() {
+        let mut sched = scheduler_with(400, |cfg| {
+            cfg.max_model_len = 2048;
+            cfg.max_num_batched_tokens = 2048;
+            cfg.max_prefill_tokens = 2048;
+            cfg.max_prefill_tokens_per_step = 128;
+        });
+        add_request(&mut sched, 8, 100);
+        run_step(&mut sched, |_| {});
+
+        add_request(&mut sched, 1000, 4);
+        let mut steps = vec![];
+        loop {
+            let (mut gen_len, mut chunk) = (None, None);
+            run_step(&mut sched, |seq| {
+                if seq.prompt_len == 8 {
+                    gen_len = Some(seq.get_gen_len());
+                } else if seq.is_prefilling() {
+                    chunk = Some(seq.num_query_tokens());
+                }
+            });
+            match chunk {
+                Some(chunk) => steps.push((gen_len, chunk)),
+                None => break,
+            }
+        }
+        // the running request gets a token in every step of the prefill
+        let expected: Vec<_> = (1..8).map(|idx| (Some(idx), 128)).collect();
+        assert_eq!(steps, expected);
+    }
+
+    #[test]
+    fn prefill_chunks_count_against_batched_tokens() {
+        let mut sched = scheduler_with(100, |cfg| {
+            cfg.max_num_batched_tokens = 16;
+        });
+        add_request(&mut sched, 4, 20);
+        run_step(&mut sched, |_| {});
+
+        // a prompt in the middle of its prefill, and a new one
+        add_request(&mut sched, 40, 4);
+        run_step(&mut sched, |_| {});
+        add_request(&mut sched, 30, 4);
+        let mut max_batch = 0;
+        let mut generated = vec![];
+        for _ in 0..100 {
+            if !sched.has_unfinished_seqs() {
+                break;
+            }
+            let mut batch = 0;
+            generated.extend(run_step(&mut sched, |seq| batch += seq.num_query_tokens()));
+            max_batch = std::cmp::max(max_batch, batch);
+        }
+        generated.sort();
+        assert_eq!(generated, vec![4, 4, 20]);
+        assert_eq!(max_batch, 16);
+    }
+
+    #[test]
+    fn forced_tokens_of_the_first_group_are_capped() {
+        let mut sched = scheduler_with(100, |cfg| {
+            cfg.max_num_batched_tokens = 16;
+        });
+        add_request(&mut sched, 4, 40);
+        run_step(&mut sched, |_| {});
+
+        // two forks, each with 20 tokens spliced in by the controller
+        sched.for_each_ongpu_sg(|sg| {
+            sg.seqs[0].append_tokens(&[1; 20]);
+            let fork = sg.seqs[0].fork_as(sched.seq_mgr.deref(), sched.seq_mgr.new_sequence(), 1);
+            sg.seqs.push(fork);
+        });
+        let mut batches = vec![];
+        for _ in 0..3 {
+            let mut batch = vec![];
+            run_step(&mut sched, |seq| batch.push(seq.num_query_tokens()));
+            batches.push(batch);
+        }
+        assert_eq!(batches, [[8, 8], [8, 8], [4, 4]]);
+    }
+
+    #[test]
+    fn cache_report_charges_all_used_blocks() {
+        let mut sched = scheduler(20);
+        add_request(&mut sched, 10, 8);
+        add_request(&mut sched, 6, 8);
+        let outputs = sched.schedule();
+        sched.step_finished(outputs);
+
+        let report = sched.cache_report();
+        assert_eq!(report.requests.len(), 2);
+        let tokens: usize = report.requests.iter().map(|r| r.logical_tokens).sum();
+        let blocks: usize = report.requests.iter().map(|r| r.gpu_blocks).sum();
+        assert_eq!(tokens, 16);
+        assert_eq!(blocks, num_blocks(10) + num_blocks(6));
+        assert_eq!(blocks + report.gpu.free_blocks, report.gpu.num_blocks);
+    }
+
+    #[test]
+    fn drain_interrupts_unfinished_requests() {
+        let mut sched = scheduler(40);
+        for max_tokens in [2, 10, 20] {
+            add_request(&mut sched, 4, max_tokens);
+        }
+        let mut finished = run_step(&mut sched, |_| {});
+        finished.extend(run_step(&mut sched, |_| {}));
+
+        // like RllmEngine::drain(), with the deadline passing after three more steps
+        sched.set_admit_new(false);
+        add_request(&mut sched, 4, 8);
+        for _ in 0..3 {
+            finished.extend(run_step(&mut sched, |_| {}));
+        }
+        assert_eq!(finished, vec![2]);
+        assert_eq!(sched.get_num_seqs(), (1, 2, 0));
+
+        let waiting = sched.take_unscheduled();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].only_seq().get_gen_len(), 0);
+        assert!(sched.interrupt_all().is_empty());
+
+        let outputs = sched.schedule();
+        assert!(outputs.next_seq_groups.is_empty());
+        let interrupted: Vec<_> = outputs
+            .dropped_seq_groups
+            .iter()
+            .map(|sg| {
+                let seq = sg.only_seq();
+                (seq.finish_reason(), seq.get_gen_len())
+            })
+            .collect();
+        assert_eq!(interrupted, vec![(Some(FinishReason::Interrupted), 5); 2]);
+        sched.step_finished(outputs);
+        assert!(!sched.has_unfinished_seqs());
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 40);
+    }
+
+    fn retained_params(max_tokens: usize) -> SamplingParams {
+        let mut sampling_params = SamplingParams::default();
+        sampling_params.max_tokens = max_tokens;
+        sampling_params.retain_after_finish = true;
+        sampling_params
+    }
+
+    /// Number of tokens in the first batch the request is in.
+    fn run_first_prefill(sched: &mut Scheduler<MockExec>) -> usize {
+        let mut first = None;
+        run_checking_batches(sched, 100, |seq| {
+            first.get_or_insert(seq.num_query_tokens());
+        });
+        first.unwrap()
+    }
+
+    #[test]
+    fn extended_requests_prefill_only_new_tokens() {
+        let mut sched = scheduler(20);
+        add_request_with(&mut sched, &[1; 8], retained_params(4));
+
+        let mut prefills = vec![run_first_prefill(&mut sched)];
+        for _ in 0..2 {
+            // the KV cache of the last generated token is computed along with the new ones
+            sched.extend_seq_group("req1", &[2; 5]).unwrap();
+            prefills.push(run_first_prefill(&mut sched));
+        }
+        assert_eq!(prefills, vec![8, 6, 6]);
+
+        // max_tokens counts from the end of the last prompt
+        let seq = sched.retained[0].only_seq();
+        assert_eq!((seq.prompt_len, seq.get_gen_len()), (8 + 4 + 5 + 4 + 5, 4));
+        let held = sched.cache_report().requests[0].gpu_blocks;
+        assert!(held >= num_blocks(seq.prompt_len));
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 20 - held);
+
+        sched.abort_seq_group("req1");
+        assert!(sched.retained.is_empty());
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 20);
+    }
+
+    #[test]
+    fn evicted_requests_are_recomputed() {
+        let mut sched = scheduler(6);
+        add_request_with(&mut sched, &[1; 8], retained_params(4));
+        run_to_completion(&mut sched, 100);
+        let free = sched.block_manager.get_num_free_gpu_blocks();
+        assert_eq!(free, 6 - num_blocks(12));
+
+        // the next request only fits without the retained KV cache
+        add_request(&mut sched, 16, 4);
+        assert_eq!(run_to_completion(&mut sched, 100), vec![4]);
+        assert_eq!(sched.retained[0].only_seq().num_kv_computed, 0);
+
+        sched.extend_seq_group("req1", &[2; 3]).unwrap();
+        assert_eq!(run_first_prefill(&mut sched), 12 + 3);
+    }
+
+    #[test]
+    fn only_retained_requests_can_be_extended() {
+        let mut sched = scheduler(20);
+        add_request_with(&mut sched, &[1; 8], retained_params(4));
+        assert!(sched.extend_seq_group("req1", &[2]).is_err());
+        let outputs = sched.schedule();
+        sched.step_finished(outputs);
+        assert!(sched.extend_seq_group("req1", &[2]).is_err());
+        run_to_completion(&mut sched, 100);
+
+        // finished without retain_after_finish
+        add_request(&mut sched, 4, 2);
+        run_to_completion(&mut sched, 100);
+        assert!(sched.extend_seq_group("req2", &[2]).is_err());
+        assert!(sched.extend_seq_group("req3", &[2]).is_err());
+        assert_eq!(sched.retained.len(), 1);
+
+        // too long for the model
+        assert!(sched.extend_seq_group("req1", &[2; 64]).is_err());
+        sched.extend_seq_group("req1", &[2]).unwrap();
+        assert_eq!(run_to_completion(&mut sched, 100), vec![4]);
+    }
+
+    struct Panicking;
+
+    impl EngineListener for Panicking {
+        fn on_scheduled(&mut self, _req: &RequestMeta) {
+            panic!("listener bug");
+        }
+    }
+
+    #[test]
+    fn listeners_get_lifecycle_events_in_order() {
+        // 3 blocks hold both prompts, but not both sequences after a token is generated
+        let mut sched = scheduler(3);
+        let events = Arc::new(Mutex::new(vec![]));
+        sched.listeners.add(Box::new(Panicking));
+        sched.listeners.add(Box::new(Recorder(events.clone())));
+        // req1 runs first, and req2 is preempted, as it has the lower priority
+        for priority in [1, 0] {
+            let mut sampling_params = SamplingParams::default();
+            sampling_params.max_tokens = 4;
+            sampling_params.priority = priority;
+            add_request_with(&mut sched, &[1; 4], sampling_params);
+        }
+        assert_eq!(run_to_completion(&mut sched, 100), vec![4, 4]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "added req1",
+                "added req2",
+                "scheduled req1",
+                "scheduled req2",
+                "preempted req2 Recompute",
+                "finished req1 MaxTokensReached",
+                "scheduled req2",
+                "finished req2 MaxTokensReached",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_draft_tokens_free_their_blocks() {
+        let mut sched = scheduler(8);
+        add_request(&mut sched, 7, 16);
+        let outputs = sched.schedule();
+        sched.step_finished(outputs);
+        sched.for_each_ongpu_sg(|sg| {
+            sg.seqs[0].sync_computed_kv();
+            sg.seqs[0].append_tokens(&[1]);
+        });
+
+        // like RllmEngine::propose_drafts(), and run() computing KV for all of them
+        let mut outputs = sched.schedule();
+        let seq = &mut outputs.next_seq_groups[0].seqs[0];
+        let mut copies = SchedulerOutputs::new();
+        for tok in 2..7 {
+            seq.push_draft_token(tok);
+            sched.block_manager.append_slots(seq, &mut copies);
+        }
+        assert_eq!(sched.block_manager.num_blocks_to_grow(seq, 0), 0);
+        assert_eq!(seq.num_query_tokens(), 6);
+        seq.sync_computed_kv();
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 4);
+
+        seq.accept_draft_tokens(sched.seq_mgr.deref(), 1);
+        assert_eq!(seq.all_tokens()[7..], [1, 2]);
+        assert_eq!(seq.num_kv_computed, 9);
+        assert_eq!(seq.num_draft_tokens(), 0);
+        assert_eq!(sched.block_manager.get_num_free_gpu_blocks(), 5);
+    }
+
+    #[test]
+    fn deadlines_follow_the_clock() {
+        use std::time::Duration;
+
+        let mut sched = scheduler(16);
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = now.clone();
+        sched.set_clock(move || *clock.lock().unwrap());
+        let mut sampling_params = SamplingParams::default();
+        sampling_params.max_tokens = 20;
+        sampling_params.deadline = Some(Duration::from_secs(10));
+        add_request_with(&mut sched, &[1; 4], sampling_params);
+        add_request(&mut sched, 4, 20);
+
+        *now.lock().unwrap() += Duration::from_secs(9);
+        run_step(&mut sched, |_| {});
+        run_step(&mut sched, |_| {});
+        assert_eq!(sched.get_num_deadline_exceeded(), 0);
+
+        // only the request with the deadline is dropped, with what it generated so far
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(run_step(&mut sched, |_| {}), vec![1]);
+        assert_eq!(sched.get_num_deadline_exceeded(), 1);
+        assert_eq!(run_to_completion(&mut sched, 1000), vec![20]);
+    }
+
+    /// Which of a priority-0 request waiting for 25s and a new priority-2 one goes first.
+    fn first_of_boosted(priority_boost_ms: u64) -> String {
+        let mut sched = scheduler_with(8, |cfg| {
+            cfg.max_num_seqs = 1;
+            cfg.priority_boost_ms = priority_boost_ms;
+        });
+        for priority in [0, 2] {
+            let mut sampling_params = SamplingParams::default();
+            sampling_params.priority = priority;
+            add_request_with(&mut sched, &[1; 4], sampling_params);
+        }
+        let long_ago = Instant::now() - std::time::Duration::from_secs(25);
+        sched.q_with(Queue::Waiting, |q| {
+            let old = q.iter_mut().find(|sg| sg.sampling_params.priority == 0);
+            old.unwrap().arrival_time = long_ago;
+        });
+        let outputs = sched.schedule();
+        assert_eq!(outputs.next_seq_groups.len(), 1);
+        outputs.next_seq_groups[0].request_id.clone()
+    }
+
+    #[test]
+    fn priority_boost_is_configurable() {
+        // no boost: priority only
+        assert_eq!(first_of_boosted(0), "req2");
+        // +2 after 25s: a tie, and the earlier arrival goes first
+        assert_eq!(first_of_boosted(10_000), "req1");
+        // +1 after 25s
+        assert_eq!(first_of_boosted(20_000), "req2");
+        // +25
+        assert_eq!(first_of_boosted(1_000), "req1");
+    }
+}
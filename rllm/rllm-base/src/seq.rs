@@ -1,16 +1,22 @@
 use crate::{
-    config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SequenceManager,
+    config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SeqSnapshot,
+    SequenceManager,
 };
-use aici_abi::{toktree::TokTrie, Branch, TokenId};
-use aicirt::api::{AiciMidOp, SequenceResult};
+use aici_abi::{toktree::TokTrie, Branch, Capture, ContextTruncation, TokenId};
+use aicirt::api::{AiciMidOp, AuthInfo, SequenceResult};
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    ops::Range,
+    time::{Duration, Instant},
+};
 
 pub type Token = u32;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum FinishReason {
-    /// EOS token was generated.
+    /// EOS token (or one of SamplingParams.stop_token_ids) was generated.
     FoundEos,
     /// Stopped by AICI.
     AiciStop,
@@ -24,6 +30,18 @@ pub enum FinishReason {
     Failed,
     /// All sequences in the group are suspended.
     Deadlock,
+    /// One of SamplingParams.stop strings was generated.
+    StopString,
+    /// The sequence grew past the maximum model length.
+    LengthError,
+    /// The model produced NaN or infinite logits.
+    NumericalError,
+    /// SamplingParams.deadline passed.
+    DeadlineExceeded,
+    /// The controller reported an error, with the message (also in aici_logs).
+    ControllerError(String),
+    /// The engine was drained (RllmEngine::drain()) before the sequence finished.
+    Interrupted,
 }
 
 impl FinishReason {
@@ -36,12 +54,18 @@ impl FinishReason {
             FinishReason::AiciStop => "aici-stop",
             FinishReason::Deadlock => "deadlock",
             FinishReason::AiciOutOfFuel => "aici-out-of-fuel",
+            FinishReason::StopString => "stop",
+            FinishReason::LengthError => "length-error",
+            FinishReason::NumericalError => "numerical-error",
+            FinishReason::DeadlineExceeded => "deadline",
+            FinishReason::ControllerError(_) => "controller-error",
+            FinishReason::Interrupted => "interrupted",
         };
         r.to_string()
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum SchedulingPhase {
     Waiting,
     Running,
@@ -53,18 +77,64 @@ pub enum SchedulingPhase {
 pub struct Sequence {
     pub seq_id: SeqId,
     pub index: usize, // within the sequence group
+    // all tokens, including ones dropped from the model's context
     tokens: Vec<Token>,
+    // byte offsets of the ends of the first tokens, extended by get_byte_len()
+    byte_ends: Vec<usize>,
+    // the model's context is tokens[..ctx_keep_first] followed by
+    // tokens[ctx_keep_first + ctx_dropped..]; see truncate_context()
+    ctx_keep_first: usize,
+    ctx_dropped: usize,
+    /// Tokens dropped from the context since the last mid_process() call.
+    pub(crate) context_truncation: Option<ContextTruncation>,
     pub prompt_len: usize,
     pub(crate) output_ptr: usize,
     pub(crate) output_pending: Vec<u8>,
+    /// Number of bytes to cut from the end of the output text after a stop string match.
+    pub(crate) stop_trim: usize,
     pub num_kv_computed: usize,
+    /// When the prompt is prefilled in chunks, KV is computed only up to here in the current step.
+    pub(crate) prefill_end: Option<usize>,
+    /// Tokens at the end proposed by the draft model, verified in the current step.
+    pub(crate) num_draft_tokens: usize,
     pub(crate) has_aici: bool,
     pub(crate) aici_sampling: Option<Branch<usize>>,
+    /// Tokens (indices into all tokens) hidden from attention by the controller;
+    /// see Branch::attention_mask and masked_positions().
+    pub(crate) masked_tokens: Vec<Range<usize>>,
     pub aici_logs: Vec<SequenceResult>,
+    /// Reported by the controller (MidProcessResult::captures); only the latest
+    /// of each name and occurrence, without the retracted ones.
+    pub(crate) captures: Vec<Capture>,
+    /// Changes to the captures since the last gen_output(), as reported.
+    new_captures: Vec<Capture>,
     pub(crate) expected: Option<ExpectedGeneration>,
+    /// Options scored by RllmEngine::classify(); the sequence with index `i` is fed
+    /// the tokens of option `i` instead of sampled ones.
+    pub(crate) forced_options: Vec<Vec<Token>>,
 
     pub(crate) mid_op: Option<AiciMidOp>,
 
+    /// Sum of log-probabilities of the sampled tokens (only tracked with best_of > 1),
+    /// or of the forced ones.
+    pub cumulative_logprob: f32,
+
+    /// The sequence won't grow past this length, as hinted by the controller
+    /// (MidProcessResult::max_remaining_tokens).
+    pub(crate) max_len_hint: Option<usize>,
+
+    /// Number of most likely tokens reported to the controller after sampling
+    /// (aici_abi::InitPromptResult::top_logprobs, capped); 0 for none.
+    pub(crate) top_logprobs: usize,
+
+    /// Set by Scheduler::finish_seq().
+    pub finish_time: Option<Instant>,
+    /// Whether an output with is_final was already produced.
+    pub(crate) final_output_sent: bool,
+    /// Keep the KV cache when the sequence finishes with EOS, a stop string, or max_tokens
+    /// (SamplingParams::retain_after_finish); see Scheduler::finish_seq().
+    pub(crate) retain_kv: bool,
+
     // state for Scheduler and BlockSpaceManager
     pub sched_phase: SchedulingPhase,
 }
@@ -75,48 +145,128 @@ impl Debug for Sequence {
             .field("seq_id", &self.seq_id.to_num())
             .field("sched_phase", &self.sched_phase)
             .field("kv_computed", &self.num_kv_computed)
+            .field("prefill_end", &self.prefill_end)
             .field("aici_sampling", &self.aici_sampling)
             .field("tokens", &self.tokens)
+            .field("ctx_dropped", &self.ctx_dropped)
             .field("prompt_len", &self.prompt_len)
             .finish()
     }
 }
 
 impl Sequence {
-    pub(crate) fn new(seq_id: SeqId, tokens: &[Token]) -> Self {
+    pub fn new(seq_id: SeqId, tokens: &[Token]) -> Self {
         let prompt_len = tokens.len();
         Self {
             seq_id,
             index: 0,
             sched_phase: SchedulingPhase::Waiting,
             tokens: tokens.to_vec(),
+            byte_ends: Vec::new(),
+            ctx_keep_first: 0,
+            ctx_dropped: 0,
+            context_truncation: None,
             num_kv_computed: 0,
+            prefill_end: None,
+            num_draft_tokens: 0,
             prompt_len,
             output_ptr: prompt_len,
             output_pending: Vec::new(),
+            stop_trim: 0,
             has_aici: false,
             aici_logs: Vec::new(),
+            captures: Vec::new(),
+            new_captures: Vec::new(),
             aici_sampling: None,
+            masked_tokens: Vec::new(),
             mid_op: None,
             expected: None,
+            forced_options: Vec::new(),
+            cumulative_logprob: 0.0,
+            max_len_hint: None,
+            top_logprobs: 0,
+            finish_time: None,
+            final_output_sent: false,
+            retain_kv: false,
         }
     }
 
+    /// Number of tokens in the model's context; after truncate_context(),
+    /// this is less than the number of tokens in the sequence.
     pub fn get_len(&self) -> usize {
-        self.tokens.len()
+        self.tokens.len() - self.ctx_dropped
+    }
+
+    /// All tokens of the sequence (prompt included), also the ones dropped from the context.
+    pub fn all_tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Number of tokens that will have KV computed after the current step.
+    /// This is less than get_len() while a long prompt is being prefilled in chunks.
+    pub fn get_kv_len(&self) -> usize {
+        self.prefill_end.unwrap_or(self.get_len())
+    }
+
+    /// True if the current step only computes KV for part of the prompt,
+    /// and thus no token is to be sampled.
+    pub fn is_prefilling(&self) -> bool {
+        self.get_kv_len() < self.get_len()
+    }
+
+    /// Number of tokens the model runs on for this sequence in the current step:
+    /// the prompt (chunk), the tokens spliced in by the controller, or just the last token
+    /// (re-computed when KV is already there for all tokens).
+    pub fn num_query_tokens(&self) -> usize {
+        std::cmp::max(self.get_kv_len() - self.num_kv_computed, 1)
+    }
+
+    /// Limit the KV computation in the current step to at most `max_tokens` new tokens.
+    pub(crate) fn set_prefill_chunk(&mut self, max_tokens: usize) {
+        let end = self.num_kv_computed + max_tokens;
+        self.prefill_end = if end < self.get_len() {
+            Some(end)
+        } else {
+            None
+        };
+    }
+
+    /// Number of tokens at the end proposed by the draft model, for the model to verify
+    /// in the current step; it computes logits for each of them, and after the last one.
+    pub fn num_draft_tokens(&self) -> usize {
+        self.num_draft_tokens
+    }
+
+    pub(crate) fn push_draft_token(&mut self, token: Token) {
+        self.tokens.push(token);
+        self.num_draft_tokens += 1;
+    }
+
+    /// Keep the first `num_accepted` draft tokens, and drop the rest together with their KV.
+    pub(crate) fn accept_draft_tokens(
+        &mut self,
+        seq_mgr: &impl SequenceManager,
+        num_accepted: usize,
+    ) {
+        assert!(num_accepted <= self.num_draft_tokens);
+        let num_rejected = self.num_draft_tokens - num_accepted;
+        self.num_draft_tokens = 0;
+        if num_rejected > 0 {
+            self.truncate_tokens(self.tokens.len() - num_rejected);
+            self.trim_physical_blocks(seq_mgr);
+        }
     }
 
     /// Indicate that the generation will soon run for this sequence and thus
-    /// all the tokens will have KV computed.
+    /// all the tokens (or the current prefill chunk) will have KV computed.
     pub fn sync_computed_kv(&mut self) {
-        self.num_kv_computed = self.get_len();
+        self.num_kv_computed = self.get_kv_len();
     }
 
     fn trim_computed_kv(&mut self, v: usize, seq_mgr: &impl SequenceManager) {
         if self.num_kv_computed != v {
             assert!(self.num_kv_computed > v);
-            seq_mgr.trim(self.seq_id, v);
-            self.num_kv_computed = v;
+            self.num_kv_computed = seq_mgr.trim(self.seq_id, v);
         }
     }
 
@@ -136,9 +286,29 @@ impl Sequence {
             req_id: None,
             backtrack: 0,
             tokens: vec![],
+            forced_byte_prefix: vec![],
+            byte_offset: 0,
+            context_truncation: None,
+            top_logprobs: vec![],
         }
     }
 
+    /// Length in bytes of the decoded sequence, prompt included.
+    pub fn get_byte_len(&mut self, tok_trie: &TokTrie) -> usize {
+        // only the tokens added since the last call are counted
+        let mut len = self.byte_ends.last().copied().unwrap_or(0);
+        for t in &self.tokens[self.byte_ends.len()..] {
+            len += tok_trie.token_len(*t);
+            self.byte_ends.push(len);
+        }
+        len
+    }
+
+    fn truncate_tokens(&mut self, len: usize) {
+        self.tokens.truncate(len);
+        self.byte_ends.truncate(len);
+    }
+
     pub fn splice_tokens(
         &mut self,
         seq_mgr: &impl SequenceManager,
@@ -146,10 +316,15 @@ impl Sequence {
         tokens: &[Token],
     ) {
         if backtrack > 0 {
-            self.tokens.truncate(self.get_len() - backtrack);
-            self.output_ptr = std::cmp::min(self.output_ptr, self.get_len());
+            self.truncate_tokens(self.tokens.len() - backtrack);
+            // backtracking into the dropped tokens leaves just the kept ones in the context
+            self.ctx_dropped = std::cmp::min(
+                self.ctx_dropped,
+                self.tokens.len().saturating_sub(self.ctx_keep_first),
+            );
+            self.output_ptr = std::cmp::min(self.output_ptr, self.tokens.len());
             // backtracking can remove some tokens from the initial prompt
-            self.prompt_len = std::cmp::min(self.prompt_len, self.get_len());
+            self.prompt_len = std::cmp::min(self.prompt_len, self.tokens.len());
             self.output_pending.clear();
             self.output_pending.extend_from_slice(" ↩ ".as_bytes());
             self.trim_physical_blocks(seq_mgr);
@@ -161,8 +336,90 @@ impl Sequence {
         self.tokens.len() - self.prompt_len
     }
 
+    /// Token at position `idx` in the model's context.
     pub fn get_token(&self, idx: usize) -> TokenId {
-        self.tokens[idx]
+        if idx < self.ctx_keep_first {
+            self.tokens[idx]
+        } else {
+            self.tokens[idx + self.ctx_dropped]
+        }
+    }
+
+    /// Drop tokens from the model's context, right after the first `keep_first` ones,
+    /// so that only the last `window` tokens remain after them. The KV of the remaining
+    /// tokens is recomputed, at their new positions.
+    pub(crate) fn truncate_context(
+        &mut self,
+        seq_mgr: &impl SequenceManager,
+        keep_first: usize,
+        window: usize,
+    ) {
+        let len = self.get_len();
+        assert!(len > keep_first + window);
+        let num_tokens = len - keep_first - window;
+        let start = keep_first + self.ctx_dropped;
+        self.trim_computed_kv(std::cmp::min(self.num_kv_computed, keep_first), seq_mgr);
+        self.prefill_end = None;
+        self.ctx_keep_first = keep_first;
+        self.ctx_dropped += num_tokens;
+        self.context_truncation = Some(match self.context_truncation.take() {
+            // not yet passed to the controller
+            Some(prev) => ContextTruncation {
+                start: prev.start,
+                num_tokens: prev.num_tokens + num_tokens,
+            },
+            None => ContextTruncation { start, num_tokens },
+        });
+    }
+
+    /// The tokens to feed instead of sampled ones, if any (see forced_options).
+    pub(crate) fn forced_tokens(&self) -> Option<&[Token]> {
+        self.forced_options.get(self.index).map(|t| t.as_slice())
+    }
+
+    /// Set the tokens hidden from attention, from a mask of Branch::attention_mask.
+    pub(crate) fn set_attention_mask(&mut self, mask: &[f32]) -> Result<()> {
+        if !mask.is_empty() && mask.len() != self.tokens.len() {
+            bail!(
+                "attention mask has {} elements, but there are {} tokens",
+                mask.len(),
+                self.tokens.len()
+            );
+        }
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (idx, &w) in mask.iter().enumerate() {
+            if w == 1.0 {
+                continue;
+            }
+            if w != 0.0 {
+                bail!("attention mask at {idx}: {w}; only 0.0 and 1.0 are supported");
+            }
+            match ranges.last_mut() {
+                Some(r) if r.end == idx => r.end += 1,
+                _ => ranges.push(idx..idx + 1),
+            }
+        }
+        self.masked_tokens = ranges;
+        Ok(())
+    }
+
+    /// Positions in the model's context (see get_len()) hidden from attention;
+    /// sorted, and not overlapping.
+    pub fn masked_positions(&self) -> Vec<Range<usize>> {
+        let keep_first = self.ctx_keep_first;
+        let resume = keep_first + self.ctx_dropped;
+        let mut res = Vec::new();
+        for r in self.masked_tokens.iter() {
+            let end = std::cmp::min(r.end, self.tokens.len());
+            if r.start < keep_first {
+                res.push(r.start..std::cmp::min(end, keep_first));
+            }
+            let start = std::cmp::max(r.start, resume);
+            if start < end {
+                res.push(start - self.ctx_dropped..end - self.ctx_dropped);
+            }
+        }
+        res
     }
 
     pub(crate) fn fork_as(
@@ -175,17 +432,149 @@ impl Sequence {
         Self {
             seq_id,
             index,
-            sched_phase: self.sched_phase,
+            sched_phase: self.sched_phase.clone(),
             num_kv_computed: self.num_kv_computed,
+            prefill_end: self.prefill_end,
+            num_draft_tokens: 0,
             tokens: self.tokens.clone(),
+            byte_ends: self.byte_ends.clone(),
+            ctx_keep_first: self.ctx_keep_first,
+            ctx_dropped: self.ctx_dropped,
+            context_truncation: self.context_truncation.clone(),
             output_ptr: self.prompt_len,
             prompt_len: self.prompt_len,
             output_pending: Vec::new(),
+            stop_trim: 0,
             has_aici: self.has_aici,
             aici_logs: Vec::new(),
+            captures: self.captures.clone(),
+            // the output of the fork starts over
+            new_captures: self.captures.clone(),
             aici_sampling: None,
+            masked_tokens: self.masked_tokens.clone(),
             expected: None,
+            forced_options: self.forced_options.clone(),
             mid_op: None,
+            cumulative_logprob: self.cumulative_logprob,
+            max_len_hint: self.max_len_hint,
+            top_logprobs: self.top_logprobs,
+            finish_time: None,
+            final_output_sent: false,
+            retain_kv: false,
+        }
+    }
+
+    pub(crate) fn to_snapshot(&self, num_draws: u64) -> SeqSnapshot {
+        SeqSnapshot {
+            index: self.index,
+            tokens: self.tokens.clone(),
+            ctx_keep_first: self.ctx_keep_first,
+            ctx_dropped: self.ctx_dropped,
+            prompt_len: self.prompt_len,
+            output_ptr: self.output_ptr,
+            output_pending: self.output_pending.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            max_len_hint: self.max_len_hint,
+            num_draws,
+        }
+    }
+
+    /// A sequence restored from `snap` (as with from_snapshot()), sharing the KV cache
+    /// of this one for the tokens they have in common; the rest is computed in the next
+    /// step, like tokens spliced in by a controller.
+    pub(crate) fn restore_fork(
+        &self,
+        seq_mgr: &impl SequenceManager,
+        seq_id: SeqId,
+        snap: &SeqSnapshot,
+    ) -> Self {
+        let mut seq = Self::from_snapshot(seq_id, snap);
+        let mut common = self
+            .tokens
+            .iter()
+            .zip(&snap.tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if self.ctx_dropped > 0 || seq.ctx_dropped > 0 {
+            // past the kept tokens, the same positions in the context hold different tokens
+            common = common.min(self.ctx_keep_first).min(seq.ctx_keep_first);
+        }
+        seq_mgr.copy(self.seq_id, seq_id, self.num_kv_computed);
+        seq.num_kv_computed = self.num_kv_computed;
+        // at least the last token is computed again, for the logits
+        let keep = common.min(self.num_kv_computed).min(seq.get_len() - 1);
+        seq.trim_computed_kv(keep, seq_mgr);
+        // not run in the current step
+        seq.prefill_end = Some(keep);
+        seq.sched_phase = self.sched_phase.clone();
+        seq
+    }
+
+    /// The sequence starts waiting, with no KV cache; all of its tokens are prefilled again.
+    pub(crate) fn from_snapshot(seq_id: SeqId, snap: &SeqSnapshot) -> Self {
+        let mut seq = Self::new(seq_id, &snap.tokens);
+        seq.index = snap.index;
+        seq.ctx_keep_first = snap.ctx_keep_first;
+        seq.ctx_dropped = snap.ctx_dropped;
+        if snap.ctx_dropped > 0 {
+            // for the restarted controller
+            seq.context_truncation = Some(ContextTruncation {
+                start: snap.ctx_keep_first,
+                num_tokens: snap.ctx_dropped,
+            });
+        }
+        seq.prompt_len = snap.prompt_len;
+        seq.output_ptr = snap.output_ptr;
+        seq.output_pending = snap.output_pending.clone();
+        seq.cumulative_logprob = snap.cumulative_logprob;
+        seq.max_len_hint = snap.max_len_hint;
+        seq
+    }
+
+    /// Record the controller's hint that at most `max_remaining` more tokens will be generated.
+    /// The hint can only tighten the bound; a looser one is ignored.
+    pub(crate) fn set_max_remaining_hint(&mut self, max_remaining: u32) {
+        let max_len = self.tokens.len() + max_remaining as usize;
+        match self.max_len_hint {
+            Some(prev) if prev < max_len => {
+                log::warn!(
+                    "seq {}: ignoring max_remaining_tokens={} looser than before ({})",
+                    self.seq_id.to_num(),
+                    max_remaining,
+                    prev.saturating_sub(self.tokens.len())
+                );
+            }
+            _ => self.max_len_hint = Some(max_len),
+        }
+    }
+
+    /// Record captures from the controller; ones with the name and occurrence of
+    /// an earlier one replace it, or remove it when retracted.
+    pub(crate) fn add_captures(&mut self, captures: &[Capture]) {
+        for c in captures {
+            let idx = self
+                .captures
+                .iter()
+                .position(|e| e.name == c.name && e.occurrence == c.occurrence);
+            match idx {
+                Some(idx) if c.retracted => {
+                    self.captures.remove(idx);
+                }
+                Some(idx) => self.captures[idx] = c.clone(),
+                None if c.retracted => {}
+                None => self.captures.push(c.clone()),
+            }
+            self.new_captures.push(c.clone());
+        }
+    }
+
+    /// Upper bound on the number of tokens the sequence may still generate,
+    /// given SamplingParams::max_tokens and the controller's hint, if any.
+    pub fn remaining_token_bound(&self, max_tokens: usize) -> usize {
+        let bound = max_tokens.saturating_sub(self.get_gen_len());
+        match self.max_len_hint {
+            Some(max_len) => std::cmp::min(bound, max_len.saturating_sub(self.tokens.len())),
+            None => bound,
         }
     }
 
@@ -193,14 +582,112 @@ impl Sequence {
         self.tokens.extend_from_slice(tokens)
     }
 
+    /// Append `tokens` as the prompt of another turn of a finished sequence
+    /// (see Scheduler::extend_seq_group()). Only the tokens generated after them
+    /// are in the outputs, and count towards max_tokens.
+    pub(crate) fn extend_prompt(&mut self, tokens: &[Token]) {
+        self.append_tokens(tokens);
+        self.prompt_len = self.tokens.len();
+        self.output_ptr = self.tokens.len();
+        self.output_pending.clear();
+        self.stop_trim = 0;
+        self.max_len_hint = None;
+        self.finish_time = None;
+        self.final_output_sent = false;
+    }
+
     pub fn finish_reason(&self) -> Option<FinishReason> {
-        match self.sched_phase {
-            SchedulingPhase::Finished(reason) => Some(reason),
+        match &self.sched_phase {
+            SchedulingPhase::Finished(reason) => Some(reason.clone()),
             _ => None,
         }
     }
 
-    pub fn gen_output(&mut self, tok_trie: &TokTrie) -> SeqOutput {
+    /// Check if any of the `stop` strings ends within the text of the last `num_new` tokens.
+    /// Only a tail of the output, long enough to contain the longest stop string,
+    /// is decoded, so stop strings spanning several tokens are found.
+    /// On a match, the text after the stop string (and the stop string itself,
+    /// unless `include_stop` is set) is scheduled to be cut from the output.
+    pub(crate) fn check_stop_strings(
+        &mut self,
+        tok_trie: &TokTrie,
+        stop: &[String],
+        num_new: usize,
+        include_stop: bool,
+    ) -> bool {
+        let len = self.tokens.len();
+        match self.find_stop_string(tok_trie, stop, len, num_new, include_stop) {
+            Some(trim) => {
+                self.stop_trim = trim;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like check_stop_strings(), for the first `len` tokens of the sequence;
+    /// returns the number of bytes to cut from the end of their text.
+    pub(crate) fn find_stop_string(
+        &self,
+        tok_trie: &TokTrie,
+        stop: &[String],
+        len: usize,
+        num_new: usize,
+        include_stop: bool,
+    ) -> Option<usize> {
+        let max_stop_len = stop.iter().map(|s| s.len()).max()?;
+        let num_new = std::cmp::min(num_new, len.saturating_sub(self.prompt_len));
+        if num_new == 0 {
+            return None;
+        }
+        // every token is at least one byte, so this covers the longest stop string
+        let start = std::cmp::max(self.prompt_len, (len - num_new).saturating_sub(max_stop_len));
+        let tail = tok_trie.decode(&self.tokens[start..len]);
+        let new_start = tail.len() - tok_trie.decode(&self.tokens[len - num_new..len]).len();
+
+        let mut best: Option<(usize, usize)> = None;
+        for s in stop {
+            let s = s.as_bytes();
+            // only matches ending within the new text count; earlier ones were already checked
+            let from = (new_start + 1).saturating_sub(s.len());
+            if let Some(pos) = tail[from..].windows(s.len()).position(|w| w == s) {
+                let pos = from + pos;
+                if best.map_or(true, |(p, _)| pos < p) {
+                    best = Some((pos, pos + s.len()));
+                }
+            }
+        }
+
+        best.map(|(pos, end)| {
+            let keep = if include_stop { end } else { pos };
+            tail.len() - keep
+        })
+    }
+
+    /// Hold back the longest suffix of `buf` that is a prefix of a stop string,
+    /// so that it can still be cut if the stop string is completed later.
+    fn hold_stop_prefix(&mut self, buf: &mut Vec<u8>, stop: &[String]) {
+        let mut hold = 0;
+        for s in stop {
+            let s = s.as_bytes();
+            for l in (hold + 1..std::cmp::min(s.len(), buf.len() + 1)).rev() {
+                if buf.ends_with(&s[..l]) {
+                    hold = l;
+                    break;
+                }
+            }
+        }
+        if hold > 0 {
+            let mut pending: Vec<u8> = buf.drain(buf.len() - hold..).collect();
+            pending.append(&mut self.output_pending);
+            self.output_pending = pending;
+        }
+    }
+
+    /// Produce the new output since the last call.
+    /// When a stop string was matched, it is cut from the text (at byte level, as it may
+    /// end in the middle of a token), but the tokens are reported unchanged.
+    pub fn gen_output(&mut self, tok_trie: &TokTrie, stop: &[String]) -> SeqOutput {
         let new_output_tokens = self.tokens[self.output_ptr..].to_vec();
         let mut buf = std::mem::take(&mut self.output_pending);
         buf.append(&mut tok_trie.decode(&new_output_tokens));
@@ -229,8 +716,17 @@ impl Sequence {
                 }
             }
         }
+        if self.finish_reason() == Some(FinishReason::StopString) {
+            buf.append(&mut self.output_pending);
+            let trim = std::mem::take(&mut self.stop_trim);
+            buf.truncate(buf.len().saturating_sub(trim));
+        } else if !self.is_finished() && stop.len() > 0 {
+            self.hold_stop_prefix(&mut buf, stop);
+        }
         self.output_ptr = self.tokens.len();
         let new_text = String::from_utf8_lossy(&buf).to_string();
+        let is_final = self.is_finished() && !self.final_output_sent;
+        self.final_output_sent |= is_final;
         SeqOutput {
             seq_id: self.seq_id.to_num(),
             index: self.index,
@@ -239,11 +735,17 @@ impl Sequence {
             output_tokens: self.tokens[self.prompt_len..].to_vec(),
             finish_reason: self.finish_reason(),
             aici_logs: std::mem::take(&mut self.aici_logs),
+            captures: std::mem::take(&mut self.new_captures),
+            cumulative_logprob: self.cumulative_logprob,
+            prompt_token_count: self.prompt_len,
+            gen_token_count: self.get_gen_len(),
+            is_final,
+            ..Default::default()
         }
     }
 
     pub fn is_finished(&self) -> bool {
-        self.finish_reason().is_some()
+        matches!(self.sched_phase, SchedulingPhase::Finished(_))
     }
 }
 
@@ -253,10 +755,25 @@ pub struct SequenceGroup {
     pub prompt: String,
     pub seqs: Vec<Sequence>,
     pub sampling_params: SamplingParams,
-    pub arrival_time: std::time::Instant,
+    pub arrival_time: Instant,
+    /// When the group was first picked from the waiting queue.
+    pub first_scheduled_time: Option<Instant>,
+    /// When the first token was generated for any of the sequences.
+    pub first_token_time: Option<Instant>,
     pub logits_processor: LogitsProcessor,
     pub max_index: usize,
     pub usage: TokenUsage,
+    /// The controller runs natively, in the engine (see RllmEngine::add_native_request());
+    /// SamplingParams::controller is then None.
+    pub native_ctrl: bool,
+    /// See AddRequest::auth.
+    pub auth: Option<AuthInfo>,
+    /// The prompt the controller was started with, before its InitPromptResult::ff_tokens;
+    /// empty without a controller.
+    pub ctrl_prompt: Vec<Token>,
+    /// Sequences restored from a snapshot, other than the first one; they are added
+    /// once the first one is prefilled (see RllmEngine::restore_requests()).
+    pub restored: Vec<SeqSnapshot>,
 }
 
 impl Debug for SequenceGroup {
@@ -290,6 +807,11 @@ impl SequenceGroup {
         }
     }
 
+    /// Whether the sequences have a controller (in aicirt, or native).
+    pub fn has_controller(&self) -> bool {
+        self.sampling_params.controller.is_some() || self.native_ctrl
+    }
+
     pub fn only_seq(&self) -> &Sequence {
         if self.seqs.len() == 1 {
             &self.seqs[0]
@@ -315,6 +837,12 @@ impl SequenceGroup {
         self.get_seqs(status).len()
     }
 
+    /// Prompt tokens plus the tokens generated by all sequences.
+    pub fn total_tokens(&self) -> usize {
+        let prompt_len = self.seqs.first().map_or(0, |seq| seq.prompt_len);
+        prompt_len + self.seqs.iter().map(|seq| seq.get_gen_len()).sum::<usize>()
+    }
+
     /// Checks if all sequences are finished.
     pub fn is_finished(&self) -> bool {
         self.seqs.iter().all(|seq| seq.is_finished())
@@ -327,7 +855,7 @@ impl SequenceGroup {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SeqOutput {
     pub seq_id: usize,
     pub index: usize, // within the sequence group
@@ -337,6 +865,67 @@ pub struct SeqOutput {
     pub output_tokens: Vec<Token>,
     pub finish_reason: Option<FinishReason>,
     pub aici_logs: Vec<SequenceResult>,
+    /// Changes to the captures of the controller (aici_abi::Capture) since the last
+    /// output, in order: new or updated ones, and retractions.
+    #[serde(default)]
+    pub captures: Vec<Capture>,
+    /// Sum of log-probabilities of output_tokens; used to pick the best of several
+    /// sequences (SamplingParams.best_of). Always 0 when best_of is 1,
+    /// except for the options scored by RllmEngine::classify().
+    #[serde(default)]
+    pub cumulative_logprob: f32,
+    #[serde(default)]
+    pub prompt_token_count: usize,
+    #[serde(default)]
+    pub gen_token_count: usize,
+    /// Time from arrival of the request until it was first scheduled.
+    #[serde(default)]
+    pub queued_duration: Duration,
+    /// Time from first scheduling until the first token.
+    #[serde(default)]
+    pub prefill_duration: Duration,
+    /// Time from the first token until the sequence finished (or until now).
+    #[serde(default)]
+    pub decode_duration: Duration,
+    /// Set in the last output for this sequence; other sequences in the group
+    /// may still continue.
+    #[serde(default)]
+    pub is_final: bool,
+}
+
+impl SeqOutput {
+    /// Split the time from arrival until `end` into queueing, prefill, and decoding.
+    pub(crate) fn set_durations(
+        &mut self,
+        arrival: Instant,
+        first_scheduled: Option<Instant>,
+        first_token: Option<Instant>,
+        end: Instant,
+    ) {
+        (
+            self.queued_duration,
+            self.prefill_duration,
+            self.decode_duration,
+        ) = split_durations(arrival, first_scheduled, first_token, end);
+    }
+}
+
+/// The time from arrival until `end`, as (queued, prefill, decode) durations;
+/// see SeqOutput::set_durations().
+pub(crate) fn split_durations(
+    arrival: Instant,
+    first_scheduled: Option<Instant>,
+    first_token: Option<Instant>,
+    end: Instant,
+) -> (Duration, Duration, Duration) {
+    let end = end.max(arrival);
+    let scheduled = first_scheduled.unwrap_or(end).clamp(arrival, end);
+    let first_token = first_token.unwrap_or(end).clamp(scheduled, end);
+    (
+        scheduled - arrival,
+        first_token - scheduled,
+        end - first_token,
+    )
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -359,6 +948,136 @@ impl TokenUsage {
 pub struct RequestOutput {
     pub request_id: String,
     pub usage: TokenUsage,
+    /// Seed of the request (SamplingParams::seed, or the one drawn when it's None);
+    /// passing it back reproduces the output.
+    #[serde(default)]
+    pub seed: u64,
     pub seq_outputs: Vec<SeqOutput>,
     pub is_final: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aici_abi::bytes::TokRxInfo;
+
+    const WORDS: &[&str] = &["Hi", "!\nUs", "er: bye", "Us", "er", ":", "\n"];
+
+    fn trie() -> TokTrie {
+        let mut words: Vec<Vec<u8>> = WORDS.iter().map(|w| w.as_bytes().to_vec()).collect();
+        words.push(vec![]); // EOS
+        let info = TokRxInfo {
+            vocab_size: words.len() as u32,
+            tok_eos: words.len() as TokenId - 1,
+        };
+        TokTrie::from(&info, &words)
+    }
+
+    fn tok(word: &str) -> Token {
+        WORDS.iter().position(|w| *w == word).unwrap() as Token
+    }
+
+    /// Generate the tokens one by one, like RllmEngine does; returns the output text.
+    fn generate(words: &[&str], include_stop: bool) -> (String, bool) {
+        let trie = trie();
+        let stop = vec!["\nUser:".to_string()];
+        let mut seq = Sequence::new(SeqId(1), &[tok("\n")]);
+        let mut text = String::new();
+        let mut stopped = false;
+        for w in words {
+            seq.append_tokens(&[tok(w)]);
+            if seq.check_stop_strings(&trie, &stop, 1, include_stop) {
+                seq.sched_phase = SchedulingPhase::Finished(FinishReason::StopString);
+                stopped = true;
+            }
+            text += &seq.gen_output(&trie, &stop).new_text;
+            if stopped {
+                break;
+            }
+        }
+        (text, stopped)
+    }
+
+    #[test]
+    fn stop_string_straddling_tokens() {
+        // "\nUs" is held back until "er: bye" completes the stop string in its middle
+        let words = ["Hi", "!\nUs", "er: bye", "Hi"];
+        assert_eq!(generate(&words, false), ("Hi!".to_string(), true));
+        assert_eq!(generate(&words, true), ("Hi!\nUser:".to_string(), true));
+        // a stop string prefix which isn't completed is released
+        let words = ["Hi", "!\nUs", "Hi"];
+        assert_eq!(generate(&words, false), ("Hi!\nUsHi".to_string(), false));
+        // over several tokens, none containing all of it
+        let words = ["Hi", "\n", "Us", "er", ":", "Hi"];
+        assert_eq!(generate(&words, false), ("Hi".to_string(), true));
+    }
+
+    #[test]
+    fn byte_len_follows_backtracking() {
+        struct NoSeqMgr;
+        impl SequenceManager for NoSeqMgr {
+            fn new_sequence(&self) -> SeqId {
+                unreachable!("not used by these tests")
+            }
+            fn copy(&self, _src: SeqId, _dst: SeqId, _length: usize) {}
+            fn trim(&self, _seq: SeqId, length: usize) -> usize {
+                length
+            }
+            fn delete(&self, _seq: SeqId) {}
+        }
+
+        let trie = trie();
+        let mut seq = Sequence::new(SeqId(1), &[tok("Hi")]);
+        assert_eq!(seq.get_byte_len(&trie), 2);
+        seq.append_tokens(&[tok("er: bye"), tok(":")]);
+        assert_eq!(seq.get_byte_len(&trie), 10);
+        // replaced tokens are not counted
+        seq.splice_tokens(&NoSeqMgr, 2, &[tok("!\nUs")]);
+        assert_eq!(seq.get_byte_len(&trie), 6);
+        seq.push_draft_token(tok("er"));
+        assert_eq!(seq.get_byte_len(&trie), 8);
+        seq.accept_draft_tokens(&NoSeqMgr, 0);
+        assert_eq!(seq.get_byte_len(&trie), trie.decode(seq.all_tokens()).len());
+    }
+
+    #[test]
+    fn captures_are_output_as_changes() {
+        let trie = trie();
+        let capture = |name: &str, value: &str| Capture {
+            name: name.to_string(),
+            occurrence: 0,
+            start: 0,
+            end: value.len() as u32,
+            value: value.to_string(),
+            retracted: false,
+        };
+        let mut seq = Sequence::new(SeqId(1), &[tok("Hi")]);
+        seq.add_captures(&[capture("a", "x"), capture("b", "y")]);
+        seq.add_captures(&[capture("a", "xy")]);
+        let out = seq.gen_output(&trie, &[]);
+        assert_eq!(
+            out.captures,
+            [capture("a", "x"), capture("b", "y"), capture("a", "xy")]
+        );
+        assert!(seq.gen_output(&trie, &[]).captures.is_empty());
+        // a retraction of a backtracked one is passed on, and forgets it
+        seq.add_captures(&[Capture::retraction("b", 0)]);
+        let out = seq.gen_output(&trie, &[]);
+        assert_eq!(out.captures, [Capture::retraction("b", 0)]);
+        assert_eq!(seq.captures, [capture("a", "xy")]);
+    }
+
+    #[test]
+    fn max_remaining_hint_only_tightens() {
+        let mut seq = Sequence::new(SeqId(1), &[tok("Hi")]);
+        seq.set_max_remaining_hint(2);
+        assert_eq!(seq.remaining_token_bound(10), 2);
+        // forced tokens can go past the hint
+        seq.append_tokens(&[tok("Us"), tok("er"), tok(":")]);
+        assert_eq!(seq.remaining_token_bound(10), 0);
+        seq.set_max_remaining_hint(5);
+        assert_eq!(seq.remaining_token_bound(10), 0);
+        seq.set_max_remaining_hint(0);
+        assert_eq!(seq.remaining_token_bound(10), 0);
+    }
+}
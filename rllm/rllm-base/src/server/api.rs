@@ -1,4 +1,5 @@
-use aici_abi::StorageCmd;
+use crate::config::Truncation;
+use aici_abi::{Capture, StorageCmd};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,6 +10,26 @@ pub struct RunRequest {
     pub top_p: Option<f32>,        // defl 1.0
     pub top_k: Option<isize>,      // defl -1
     pub max_tokens: Option<usize>, // defl context size
+    pub priority: Option<i32>,     // defl 0
+    pub stop: Option<Vec<String>>, // defl []
+    /// defl false
+    pub include_stop_str_in_output: Option<bool>,
+    /// defl 0.0
+    pub min_p: Option<f32>,
+    /// defl 1.0
+    pub typical_p: Option<f32>,
+    /// defl 0
+    pub min_tokens: Option<usize>,
+    /// defl none
+    pub deadline_ms: Option<u64>,
+    /// defl none
+    pub max_total_tokens: Option<usize>,
+    /// defl none
+    pub lora: Option<String>,
+    /// defl random
+    pub seed: Option<u64>,
+    /// defl Error
+    pub context_truncation: Option<Truncation>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +52,7 @@ pub struct RunResponse {
     pub object: &'static str, // "run"
     pub forks: Vec<RunForkResponse>,
     pub usage: RunUsageResponse,
+    pub seed: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,4 +65,8 @@ pub struct RunForkResponse {
     pub logs: String,
     pub storage: Vec<StorageCmd>,
     pub micros: u64,
+    /// Changes to the values captured by the controller (e.g., named parts of a grammar)
+    /// since the previous chunk, including retractions of backtracked ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub captures: Vec<Capture>,
 }
@@ -1,9 +1,14 @@
 use crate::seq::{FinishReason, RequestOutput, SeqOutput};
 use crate::server::{auth_info, APIError, AiciServerData, InferenceResult};
-use crate::{config::SamplingParams, seq::Token, AddRequest};
+use crate::{
+    config::{SamplingParams, Truncation},
+    seq::Token,
+    AddRequest,
+};
 use actix_web::{post, web, web::Bytes, HttpResponse};
 use aicirt::{api::InstantiateReq, get_unix_time};
 use serde_json::{json, Value};
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use uuid::Uuid;
 
@@ -11,29 +16,31 @@ use super::api::{InitialRunResponse, RunForkResponse, RunRequest, RunResponse, R
 
 const NONE_CONTROLLER: &str = "none";
 
-fn check_length(
-    request: &web::Json<RunRequest>,
+/// Tokenize the prompt and check that it fits in the context together with `max_tokens`
+/// (which defaults to the rest of the context). With `truncate_context`, tokens are
+/// dropped from the context as needed, so only the prompt has to fit.
+pub(super) fn check_length(
     data: &AiciServerData,
+    prompt: &str,
+    add_special_tokens: bool,
+    max_tokens: Option<usize>,
+    truncate_context: bool,
 ) -> Result<(usize, Vec<Token>), APIError> {
-    let prompt = if request.controller == NONE_CONTROLLER {
-        request.controller_arg.as_str().unwrap_or("")
-    } else {
-        ""
-    };
     let token_ids = data
         .tokenizer
-        .encode(prompt, true)
+        .encode(prompt, add_special_tokens)
         .map_err(APIError::from)?
         .get_ids()
         .to_vec();
 
-    let max_tokens = if let Some(max_toks) = request.max_tokens {
+    let max_tokens = if let Some(max_toks) = max_tokens {
         max_toks
     } else {
         data.model_meta.max_sequence_length - token_ids.len()
     };
 
-    if token_ids.len() + max_tokens > data.model_meta.max_sequence_length {
+    let num_needed = if truncate_context { 1 } else { max_tokens };
+    if token_ids.len() + num_needed > data.model_meta.max_sequence_length {
         Err(APIError::new(format!(
             "This model's maximum context length is {} tokens. \
             However, you requested {} tokens ({} in the messages, \
@@ -73,7 +80,19 @@ async fn run_controller(
     data: web::Data<AiciServerData>,
     request: web::Json<RunRequest>,
 ) -> Result<HttpResponse, APIError> {
-    let token_ids = check_length(&request, &data);
+    let prompt = if request.controller == NONE_CONTROLLER {
+        request.controller_arg.as_str().unwrap_or("")
+    } else {
+        ""
+    };
+    let truncation = request.context_truncation.unwrap_or_default();
+    let token_ids = check_length(
+        &data,
+        prompt,
+        true,
+        request.max_tokens,
+        truncation != Truncation::Error,
+    );
     bail_if_error!(token_ids);
 
     let (max_tokens, token_ids) = token_ids.unwrap();
@@ -84,7 +103,27 @@ async fn run_controller(
     sampling_params.max_tokens = max_tokens;
     sampling_params.ignore_eos = true;
 
-    set_fields_if_some!(request, sampling_params, temperature, top_p, top_k);
+    set_fields_if_some!(
+        request,
+        sampling_params,
+        temperature,
+        top_p,
+        top_k,
+        min_p,
+        typical_p,
+        min_tokens,
+        priority,
+        include_stop_str_in_output
+    );
+    if let Some(stop) = &request.stop {
+        sampling_params.stop = stop.clone();
+    }
+    sampling_params.deadline = request.deadline_ms.map(Duration::from_millis);
+    sampling_params.max_total_tokens = request.max_total_tokens;
+    sampling_params.lora = request.lora.clone();
+    // drawn here rather than in the engine, so that failed requests report it too
+    sampling_params.seed = Some(request.seed.unwrap_or_else(rand::random));
+    sampling_params.context_truncation = truncation;
 
     if request.controller != NONE_CONTROLLER {
         sampling_params.controller = Some(request.controller.clone());
@@ -120,6 +159,7 @@ async fn run_controller(
             let outp = RequestOutput {
                 request_id: request_id.clone(),
                 usage: Default::default(),
+                seed: sampling_params.seed.unwrap(),
                 seq_outputs: vec![SeqOutput {
                     seq_id: 0,
                     index: 0,
@@ -127,7 +167,9 @@ async fn run_controller(
                     new_text: String::new(),
                     output_tokens: vec![],
                     finish_reason: Some(FinishReason::Failed),
-                    aici_logs: vec![r],
+                    aici_logs: vec![r.clone_with(None)],
+                    is_final: true,
+                    ..Default::default()
                 }],
                 is_final: true,
             };
@@ -142,6 +184,7 @@ async fn run_controller(
                 sampling_params,
                 expected: None,
                 init_result,
+                auth: Some(auth_info(&req)),
             });
 
             bail_if_error!(rx);
@@ -190,6 +233,7 @@ impl futures::Stream for Client {
                 let u = &so.usage;
                 let r = RunResponse {
                     object: "run",
+                    seed: so.seed,
                     usage: RunUsageResponse {
                         sampled_tokens: u.gen_tokens,
                         ff_tokens: u.prompt_tokens,
@@ -201,7 +245,7 @@ impl futures::Stream for Client {
                         .map(|choice| RunForkResponse {
                             text: choice.new_text.clone(),
                             index: choice.index,
-                            finish_reason: choice.finish_reason.map(|r| r.short_name()),
+                            finish_reason: choice.finish_reason.as_ref().map(|r| r.short_name()),
                             micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
                             logs: choice
                                 .aici_logs
@@ -220,6 +264,7 @@ impl futures::Stream for Client {
                                 .iter()
                                 .flat_map(|e| e.storage.clone())
                                 .collect::<Vec<_>>(),
+                            captures: choice.captures.clone(),
                         })
                         .collect(),
                 };
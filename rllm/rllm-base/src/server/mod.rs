@@ -1,9 +1,10 @@
 use crate::{
-    config::{ModelMeta, SamplingParams},
+    config::{ModelMeta, PreemptionMode, SamplingParams, WeightFormat},
     iface::{kill_self, AiciRtIface, AsyncCmdChannel},
-    seq::RequestOutput,
+    seq::{FinishReason, RequestOutput},
     util::apply_settings,
-    AddRequest, HashMap, LoaderArgs, ModelExec, RllmEngine,
+    AddRequest, AddRequestError, ChatTemplate, DraftModelArgs, EngineSnapshot, HashMap, HashSet,
+    LoaderArgs, ModelExec, RllmEngine, SyntheticBatch, WarmupProfile,
 };
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use aici_abi::toktree::TokTrie;
@@ -18,18 +19,24 @@ use clap::Args;
 use std::{
     fmt::Display,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
 };
-use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
 
 mod api;
 mod completion;
 mod openai;
+mod openai_completion;
 
 #[derive(Debug)]
 pub struct APIError {
     code: actix_web::http::StatusCode,
     msg: String,
+    /// For the Retry-After header of 429 responses.
+    retry_after: Option<Duration>,
 }
 
 impl From<anyhow::Error> for APIError {
@@ -49,6 +56,17 @@ impl actix_web::error::ResponseError for APIError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         self.code
     }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let mut res = actix_web::HttpResponse::build(self.code);
+        if let Some(d) = self.retry_after {
+            // whole seconds
+            let secs = d.as_secs_f64().ceil() as u64;
+            res.insert_header((actix_web::http::header::RETRY_AFTER, secs.to_string()));
+        }
+        res.content_type(actix_web::http::header::ContentType::plaintext())
+            .body(self.to_string())
+    }
 }
 
 impl Display for APIError {
@@ -62,6 +80,7 @@ impl APIError {
         Self {
             code: actix_web::http::StatusCode::BAD_REQUEST,
             msg: data,
+            retry_after: None,
         }
     }
 
@@ -70,17 +89,28 @@ impl APIError {
     }
 
     pub fn from_anyhow(value: anyhow::Error) -> Self {
-        if UserError::is_self(&value) {
+        if let Some(AddRequestError::QueueFull { retry_after_hint }) =
+            value.downcast_ref::<AddRequestError>()
+        {
+            log::info!("QueueFull: {value}");
+            Self {
+                code: actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+                msg: format!("{value}"),
+                retry_after: Some(*retry_after_hint),
+            }
+        } else if UserError::is_self(&value) || value.downcast_ref::<AddRequestError>().is_some() {
             log::info!("UserError: {value}");
             Self {
                 code: actix_web::http::StatusCode::BAD_REQUEST,
                 msg: format!("{value}"),
+                retry_after: None,
             }
         } else {
             log::warn!("APIError: {value:?}");
             Self {
                 code: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
                 msg: format!("{value:?}"),
+                retry_after: None,
             }
         }
     }
@@ -90,6 +120,7 @@ impl APIError {
         Self {
             code: actix_web::http::StatusCode::BAD_REQUEST,
             msg: format!("{value}"),
+            retry_after: None,
         }
     }
 }
@@ -119,6 +150,7 @@ pub struct AiciServerData {
     pub model_meta: ModelMeta,
     pub tokenizer: Arc<tokenizers::Tokenizer>,
     pub tok_trie: Arc<TokTrie>,
+    pub chat_template: Arc<ChatTemplate>,
     pub side_cmd_ch: AsyncCmdChannel,
     pub stats: Arc<Mutex<ServerStats>>,
 }
@@ -133,7 +165,7 @@ pub struct RllmCliArgs {
     #[arg(long)]
     pub log: Option<String>,
 
-    /// HuggingFace model name, URL or path starting with "./"
+    /// HuggingFace model name, URL or path starting with "./" (to a folder or a .gguf file)
     #[arg(short, long, help_heading = "Model")]
     pub model: String,
 
@@ -146,10 +178,101 @@ pub struct RllmCliArgs {
     #[arg(long, help_heading = "Model")]
     pub local_weights: Option<String>,
 
-    /// Tokenizer to use (see below or in --help for list)
+    /// Don't download anything; fail if model files are not in the HuggingFace cache
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub offline: bool,
+
+    /// Load a LoRA adapter (folder with adapter_config.json and adapter_model.safetensors);
+    /// requests select it by name (batches using adapters don't replay CUDA graphs)
+    #[arg(long, name = "NAME=PATH", help_heading = "Model")]
+    pub lora: Vec<String>,
+
+    /// Smaller model (like --model, with the same tokenizer) proposing tokens for the main one
+    /// to verify (speculative decoding); used for greedy sequences, checked against the masks
+    /// of their controllers
+    #[arg(long, help_heading = "Model")]
+    pub draft_model: Option<String>,
+
+    /// Tokens proposed by --draft-model in each step
+    #[arg(long, default_value_t = 4, help_heading = "Model")]
+    pub draft_tokens: usize,
+
+    /// Chat template for models that don't specify one in tokenizer_config.json (llama2, mistral)
+    #[arg(long, default_value = "llama2", help_heading = "Model")]
+    pub chat_template: String,
+
+    /// Tokenizer to use (see below or in --help for list); defaults to the one of a .gguf model
     #[arg(short, long, help_heading = "Model")]
     pub tokenizer: Option<String>,
 
+    /// Format of the model weights; auto picks GGUF import for .gguf files (the weights
+    /// are dequantized on load)
+    #[arg(long, value_enum, default_value_t = WeightFormat::Auto, help_heading = "Model")]
+    pub weight_format: WeightFormat,
+
+    /// Split prompts longer than this many tokens into chunks prefilled over several steps
+    #[arg(long, help_heading = "Model")]
+    pub max_prefill_tokens: Option<usize>,
+
+    /// Prompt tokens (over all requests) computed in one step alongside the generating requests;
+    /// the rest of a long prompt is prefilled in the next steps [default: max model length]
+    #[arg(long, help_heading = "Model")]
+    pub max_prefill_tokens_per_step: Option<usize>,
+
+    /// What to do with the KV cache of sequences preempted for lack of GPU memory
+    #[arg(long, value_enum, default_value_t = PreemptionMode::Recompute, help_heading = "Model")]
+    pub preemption_mode: PreemptionMode,
+
+    /// With --preemption-mode auto, recompute sequences up to this many tokens, swap longer ones
+    #[arg(long, default_value_t = 512, help_heading = "Model")]
+    pub recompute_max_len: usize,
+
+    /// Fraction of GPU KV cache blocks that must remain free after admitting a prompt
+    #[arg(long, default_value_t = 0.01, help_heading = "Model")]
+    pub gpu_watermark: f32,
+
+    /// Only admit prompts when every running sequence can still generate this many tokens
+    #[arg(long, default_value_t = 16, help_heading = "Model")]
+    pub admission_lookahead: usize,
+
+    /// Warn about a livelock after this many steps in a row without a sampled token
+    #[arg(long, default_value_t = 100, help_heading = "Model")]
+    pub livelock_steps: usize,
+
+    /// Raise the priority of waiting requests by one for every this many milliseconds
+    /// in the queue (so low-priority ones still get scheduled); 0 disables
+    #[arg(long, default_value_t = 10_000, help_heading = "Model")]
+    pub priority_boost_ms: u64,
+
+    /// Split model layers across this many consecutive GPUs (pipeline parallelism)
+    #[arg(long, default_value_t = 1, help_heading = "Model")]
+    pub pipeline_parallel_size: usize,
+
+    /// Reject requests (HTTP 429) while this many are waiting to be scheduled; 0 disables
+    #[arg(long, default_value_t = 0, help_heading = "Server")]
+    pub max_waiting_groups: usize,
+
+    /// Reject requests (HTTP 429) while the waiting ones have this many prompt tokens; 0 disables
+    #[arg(long, default_value_t = 0, help_heading = "Server")]
+    pub max_queued_tokens: usize,
+
+    /// Log engine stats (tokens, queue lengths, cache usage, timings) every this many steps; 0 disables
+    #[arg(long, default_value_t = 0, help_heading = "Server")]
+    pub log_stats_steps: usize,
+
+    /// Cache the tokens of up to this many prompts, for workloads where the same prompts recur; 0 disables
+    #[arg(long, default_value_t = 0, help_heading = "Server")]
+    pub tokenize_cache_entries: usize,
+
+    /// Size limit of the tokenization cache in MiB
+    #[arg(long, default_value_t = 64, help_heading = "Server")]
+    pub tokenize_cache_mb: usize,
+
+    /// On SIGTERM, write the unfinished requests to this file and exit; on startup, restore
+    /// the requests from it, if it exists (their clients are gone, so they only show in the log)
+    #[arg(long, help_heading = "Server")]
+    pub snapshot_file: Option<String>,
+
     /// Host to serve on
     #[arg(long, default_value_t = String::from("127.0.0.1"), help_heading = "Server")]
     pub host: String,
@@ -190,11 +313,42 @@ pub struct RllmCliArgs {
     #[arg(long, short = 'A', help_heading = "AICI settings")]
     pub aicirt_arg: Vec<String>,
 
+    /// How many sampled tokens a controller can reject in post_sample() in one step.
+    #[arg(long, default_value = "8", help_heading = "AICI settings")]
+    pub post_sample_retries: usize,
+
     /// Specify test-cases (expected/*/*.safetensors)
     #[arg(long, help_heading = "Development")]
     pub test: Vec<String>,
 
-    /// Specify warm-up request (expected/*/*.safetensors or "off")
+    /// With --test, also check that requests snapshotted mid-generation and restored
+    /// produce the same tokens as uninterrupted ones
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub test_snapshot: bool,
+
+    /// With --test, also check that draining the engine with a tight deadline finishes
+    /// the short request, and interrupts the long ones with their tokens so far
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub test_drain: bool,
+
+    /// With --test, also time 10k requests with prompts drawn from 100 templates with and
+    /// without tokenizing them (use with --tokenize-cache-entries), and check that cached
+    /// and uncached prompts generate the same tokens
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub test_tokenize_cache: bool,
+
+    /// With --test, also run a few requests and check the metrics in the Prometheus
+    /// format: the histograms are consistent, and count the requests and steps
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub test_metrics: bool,
+
+    /// With --test, also fill the waiting queue (use with --max-waiting-groups) and check
+    /// that requests over the limit are rejected until stepping makes room
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub test_queue_limits: bool,
+
+    /// Specify warm-up request (expected/*/*.safetensors or "off"); "synthetic" also runs
+    /// random batches of all the batch sizes through the model first
     #[arg(long, short, help_heading = "Development")]
     pub warmup: Option<String>,
 
@@ -202,6 +356,19 @@ pub struct RllmCliArgs {
     #[arg(long, default_value_t = false, help_heading = "Development")]
     pub warmup_only: bool,
 
+    /// Time prefill, decode, sampling and KV cache operations for a batch of random tokens,
+    /// given as BATCH_SIZE,PROMPT_LEN,GEN_LEN, and exit
+    #[arg(long, help_heading = "Development")]
+    pub bench: Option<String>,
+
+    /// Number of timed runs for --bench (after an untimed one)
+    #[arg(long, default_value_t = 5, help_heading = "Development")]
+    pub bench_runs: usize,
+
+    /// Panic when the model produces NaN/inf logits (instead of failing the sequence)
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub panic_on_nan: bool,
+
     // these are copied from command-specific parsers
     #[arg(skip)]
     pub file: Option<String>,
@@ -309,6 +476,8 @@ More info at: {url}"#
 
 pub enum InferenceReq {
     AddRequest(AddRequest),
+    /// Write the unfinished requests to --snapshot-file, and exit.
+    Shutdown,
 }
 
 type InferenceResult = Result<RequestOutput>;
@@ -342,6 +511,8 @@ fn inference_loop<ME: ModelExec>(
     mut recv: Receiver<InferenceReq>,
     stats: Arc<Mutex<ServerStats>>,
     warmup_only: bool,
+    snapshot_file: Option<String>,
+    mut restored: HashSet<String>,
 ) {
     loop {
         loop {
@@ -366,6 +537,17 @@ fn inference_loop<ME: ModelExec>(
                         }
                     }
                 }
+                Ok(InferenceReq::Shutdown) => {
+                    let path = snapshot_file.as_deref().unwrap();
+                    let code = match write_snapshot(&mut engine, path) {
+                        Ok(()) => 0,
+                        Err(e) => {
+                            log::error!("failed to write the snapshot to {path}: {e}");
+                            1
+                        }
+                    };
+                    std::process::exit(code);
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => panic!(),
             }
@@ -390,7 +572,9 @@ fn inference_loop<ME: ModelExec>(
                 match tx {
                     Some(tx) => {
                         if let Err(e) = tx.try_send(Ok(outp)) {
+                            // most likely the client disconnected
                             log::warn!("failed to send output to client {id}: {e}");
+                            running.remove(&id);
                             engine.abort_request(&id);
                         }
                     }
@@ -404,6 +588,12 @@ fn inference_loop<ME: ModelExec>(
                                     kill_self();
                                 }
                             }
+                        } else if restored.contains(&id) {
+                            // restored from --snapshot-file; the client is gone
+                            if outp.is_final {
+                                log::info!("restored request {id} finished");
+                                restored.remove(&id);
+                            }
                         } else {
                             log::warn!("output for unknown request {id}");
                             engine.abort_request(&id);
@@ -415,6 +605,46 @@ fn inference_loop<ME: ModelExec>(
     }
 }
 
+/// Write the unfinished requests to `path` (see --snapshot-file).
+fn write_snapshot<ME: ModelExec>(engine: &mut RllmEngine<ME>, path: &str) -> Result<()> {
+    engine.pause();
+    let snap = engine.snapshot();
+    // a partially written snapshot is never restored
+    let tmp = format!("{path}.tmp");
+    let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    snap.write_to(&mut w)?;
+    w.into_inner()?;
+    std::fs::rename(&tmp, path)?;
+    log::info!("{} requests written to {path}", snap.requests.len());
+    Ok(())
+}
+
+/// Queue the requests from `path` (see --snapshot-file), if it exists, and remove it.
+/// Returns the ids of the restored requests.
+fn restore_snapshot<ME: ModelExec>(
+    engine: &mut RllmEngine<ME>,
+    path: &str,
+) -> Result<HashSet<String>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let snap = EngineSnapshot::read_from(std::io::BufReader::new(file))?;
+    let mut ids: HashSet<String> = snap.requests.iter().map(|r| r.request_id.clone()).collect();
+    let skipped = engine.restore_requests(snap)?;
+    for id in skipped.iter() {
+        ids.remove(id);
+    }
+    std::fs::remove_file(path)?;
+    log::info!(
+        "{} requests restored from {path}; not restored: {:?}",
+        ids.len(),
+        skipped
+    );
+    Ok(ids)
+}
+
 fn run_tests<ME: ModelExec>(
     args: &RllmCliArgs,
     loader_args: LoaderArgs,
@@ -446,6 +676,26 @@ fn run_tests<ME: ModelExec>(
         engine.step().expect("test step failed");
     }
 
+    if args.test_snapshot {
+        run_snapshot_test(&args.test, &mut engine);
+    }
+
+    if args.test_drain {
+        run_drain_test(&args.test, &mut engine);
+    }
+
+    if args.test_tokenize_cache {
+        run_tokenize_cache_test(&args.test, &mut engine);
+    }
+
+    if args.test_metrics {
+        run_metrics_test(&args.test, &mut engine);
+    }
+
+    if args.test_queue_limits {
+        run_queue_limits_test(&args.test, &mut engine);
+    }
+
     if engine.num_errors > 0 {
         log::error!("there were {} errors", engine.num_errors);
         println!("there were {} errors", engine.num_errors);
@@ -453,6 +703,478 @@ fn run_tests<ME: ModelExec>(
     }
 }
 
+fn run_bench<ME: ModelExec>(
+    args: &RllmCliArgs,
+    batch: SyntheticBatch,
+    loader_args: LoaderArgs,
+    model_args: ME::ModelLoaderArgs,
+) {
+    let mut engine = ME::load_rllm_engine(loader_args, model_args).expect("failed to load model");
+    if args.warmup.as_deref() == Some("synthetic") {
+        engine
+            .warmup(WarmupProfile::default())
+            .expect("warmup failed");
+    }
+    // the first run of a shape includes kernel selection and CUDA graph capture
+    engine.bench_step(batch).expect("bench step failed");
+
+    let mut totals = Vec::new();
+    for idx in 0..args.bench_runs {
+        let timing = engine.bench_step(batch).expect("bench step failed");
+        println!("bench {batch} #{idx}: {timing}");
+        totals.push(timing.total().as_secs_f64() * 1000.0);
+    }
+    if totals.len() > 1 {
+        let min = totals.iter().fold(f64::INFINITY, |a, &b| a.min(b));
+        let max = totals.iter().fold(0.0, |a: f64, &b| a.max(b));
+        println!(
+            "bench {batch}: total {min:.2}ms - {max:.2}ms ({:.1}% spread)",
+            (max - min) / min * 100.0
+        );
+    }
+}
+
+const SNAPSHOT_AFTER_TOKENS: usize = 10;
+
+// Generate from the test prompts, with seeded sampling, once without interruption,
+// and once snapshotting the engine after SNAPSHOT_AFTER_TOKENS tokens and restoring
+// the requests into the drained engine; the outputs have to be the same.
+fn run_snapshot_test<ME: ModelExec>(tests: &[String], engine: &mut RllmEngine<ME>) {
+    let prompts = tests
+        .iter()
+        .map(|t| {
+            crate::ExpectedGeneration::load(&std::path::PathBuf::from(t))
+                .expect("can't load test")
+                .prompt
+        })
+        .collect::<Vec<_>>();
+    let queue_all = |engine: &mut RllmEngine<ME>| {
+        for (idx, prompt) in prompts.iter().enumerate() {
+            engine
+                .queue_request(AddRequest {
+                    request_id: format!("snapshot_{idx}"),
+                    prompt: prompt.clone(),
+                    sampling_params: SamplingParams {
+                        max_tokens: 3 * SNAPSHOT_AFTER_TOKENS,
+                        temperature: 0.8,
+                        seed: Some(idx as u64 + 1),
+                        ..SamplingParams::default()
+                    },
+                    expected: None,
+                    init_result: None,
+                    auth: None,
+                })
+                .unwrap();
+        }
+    };
+    let step = |engine: &mut RllmEngine<ME>, outputs: &mut HashMap<String, Vec<u32>>| {
+        for outp in engine.step().expect("snapshot test step failed") {
+            outputs.insert(outp.request_id, outp.seq_outputs[0].output_tokens.clone());
+        }
+    };
+
+    let mut expected = HashMap::default();
+    queue_all(engine);
+    while engine.num_pending_requests() > 0 {
+        step(engine, &mut expected);
+    }
+
+    let mut outputs = HashMap::default();
+    queue_all(engine);
+    // requests that finish early are not in the snapshot
+    while engine.num_pending_requests() > 0
+        && (outputs.len() < prompts.len()
+            || outputs
+                .values()
+                .any(|toks| toks.len() < SNAPSHOT_AFTER_TOKENS))
+    {
+        step(engine, &mut outputs);
+    }
+
+    let mut buf = Vec::new();
+    engine.snapshot().write_to(&mut buf).unwrap();
+    for idx in 0..prompts.len() {
+        engine.abort_request(&format!("snapshot_{idx}"));
+    }
+    while engine.num_pending_requests() > 0 {
+        engine.step().expect("snapshot test step failed");
+    }
+    log::info!("snapshot: {} bytes", buf.len());
+
+    let snap = EngineSnapshot::read_from(&buf[..]).unwrap();
+    let num_restored = snap.requests.len();
+    let skipped = engine.restore_requests(snap).unwrap();
+    assert!(skipped.is_empty(), "not restored: {skipped:?}");
+    while engine.num_pending_requests() > 0 {
+        step(engine, &mut outputs);
+    }
+
+    for (id, exp) in expected.iter() {
+        if outputs.get(id) != Some(exp) {
+            log::error!(
+                "snapshot test {id}: got {:?}, expected {:?}",
+                outputs.get(id),
+                exp
+            );
+            engine.num_errors += 1;
+        }
+    }
+    log::info!(
+        "snapshot test: {} requests, {} restored",
+        expected.len(),
+        num_restored
+    );
+}
+
+// Three requests of different lengths, and a fourth one queued right before draining
+// with a deadline of a few steps: the short one completes, the long ones are interrupted
+// with their tokens so far, and the fourth is handed back, to run after resume().
+fn run_drain_test<ME: ModelExec>(tests: &[String], engine: &mut RllmEngine<ME>) {
+    let prompt = crate::ExpectedGeneration::load(&std::path::PathBuf::from(&tests[0]))
+        .expect("can't load test")
+        .prompt;
+    let queue = |engine: &mut RllmEngine<ME>, request_id: &str, max_tokens: usize| {
+        engine
+            .queue_request(AddRequest {
+                request_id: request_id.to_string(),
+                prompt: prompt.clone(),
+                sampling_params: SamplingParams {
+                    max_tokens,
+                    ignore_eos: true,
+                    ..SamplingParams::default()
+                },
+                expected: None,
+                init_result: None,
+                auth: None,
+            })
+            .unwrap();
+    };
+    let max_tokens = [4, 200, 400];
+    for (idx, max_tokens) in max_tokens.iter().enumerate() {
+        queue(engine, &format!("drain_{idx}"), *max_tokens);
+    }
+    let mut tokens: HashMap<String, Vec<u32>> = HashMap::default();
+    let mut step_time = Duration::ZERO;
+    for _ in 0..2 {
+        let t0 = Instant::now();
+        for outp in engine.step().expect("drain test step failed") {
+            tokens.insert(outp.request_id, outp.seq_outputs[0].output_tokens.clone());
+        }
+        step_time = t0.elapsed();
+    }
+    queue(engine, "drain_late", 4);
+
+    let report = engine
+        .drain(10 * step_time)
+        .expect("drain test drain failed");
+    let mut errors = Vec::new();
+    let mut check = |ok: bool, msg: String| {
+        if !ok {
+            errors.push(msg);
+        }
+    };
+    for outp in report.outputs.iter() {
+        tokens.insert(
+            outp.request_id.clone(),
+            outp.seq_outputs[0].output_tokens.clone(),
+        );
+    }
+    let mut num_interrupted = 0;
+    for req in report.requests.iter() {
+        let idx: usize = req.request_id["drain_".len()..].parse().unwrap();
+        let num_tokens = tokens.get(&req.request_id).map_or(0, |t| t.len());
+        check(
+            num_tokens == req.gen_tokens,
+            format!("{} has {num_tokens} tokens; {req:?}", req.request_id),
+        );
+        if idx == 0 {
+            check(
+                req.finish_reason == FinishReason::MaxTokensReached,
+                format!("short request not completed: {req:?}"),
+            );
+        } else {
+            num_interrupted += 1;
+            check(
+                req.finish_reason == FinishReason::Interrupted
+                    && req.gen_tokens > 0
+                    && req.gen_tokens < max_tokens[idx],
+                format!("long request not interrupted: {req:?}"),
+            );
+        }
+    }
+    check(
+        report.requests.len() == 3 && num_interrupted == 2,
+        format!("{} requests drained", report.requests.len()),
+    );
+    let waiting = report
+        .waiting
+        .requests
+        .iter()
+        .map(|req| req.request_id.as_str())
+        .collect::<Vec<_>>();
+    check(
+        waiting == ["drain_late"],
+        format!("waiting requests: {waiting:?}"),
+    );
+
+    for msg in errors {
+        log::error!("drain test: {msg}");
+        engine.num_errors += 1;
+    }
+
+    engine.resume();
+    let skipped = engine.restore_requests(report.waiting).unwrap();
+    assert!(skipped.is_empty(), "not restored: {skipped:?}");
+    engine.run_to_completion();
+    log::info!(
+        "drain test: {} interrupted after {:?}",
+        num_interrupted,
+        10 * step_time
+    );
+}
+
+// 10k requests with prompts drawn from 100 templates, added with the prompt tokenized
+// by the caller (as without the cache), and then as text (all but the first of each
+// template come from the cache); cached and uncached prompts have to generate the same.
+fn run_tokenize_cache_test<ME: ModelExec>(tests: &[String], engine: &mut RllmEngine<ME>) {
+    const NUM_REQUESTS: usize = 10_000;
+    const NUM_TEMPLATES: usize = 100;
+    let prompt = crate::ExpectedGeneration::load(&std::path::PathBuf::from(&tests[0]))
+        .expect("can't load test")
+        .prompt;
+    let base = engine
+        .tokenizer
+        .decode(&prompt, false)
+        .expect("can't decode test prompt");
+    let templates = (0..NUM_TEMPLATES)
+        .map(|i| format!("{base}\nExample {i}:"))
+        .collect::<Vec<_>>();
+    let template = |i: usize| &templates[i * 7 % NUM_TEMPLATES];
+    let params = SamplingParams {
+        max_tokens: 8,
+        ..SamplingParams::default()
+    };
+    let abort_all = |engine: &mut RllmEngine<ME>| {
+        for i in 0..NUM_REQUESTS {
+            engine.abort_request(&format!("tokcache_{i}"));
+        }
+        engine.run_to_completion();
+    };
+
+    let t0 = Instant::now();
+    for i in 0..NUM_REQUESTS {
+        let tokens = engine.tokenize(template(i), true).unwrap();
+        engine
+            .add_request_tokens(format!("tokcache_{i}"), tokens, params.clone())
+            .unwrap();
+    }
+    let uncached_time = t0.elapsed();
+    abort_all(engine);
+
+    let stats0 = engine.stats();
+    let t0 = Instant::now();
+    for i in 0..NUM_REQUESTS {
+        engine
+            .add_request(format!("tokcache_{i}"), template(i), params.clone())
+            .unwrap();
+    }
+    let cached_time = t0.elapsed();
+    abort_all(engine);
+    let stats = engine.stats();
+    let hits = stats.tokenize_cache_hits - stats0.tokenize_cache_hits;
+    let misses = stats.tokenize_cache_misses - stats0.tokenize_cache_misses;
+    if (hits, misses) != (NUM_REQUESTS - NUM_TEMPLATES, NUM_TEMPLATES) {
+        log::error!(
+            "tokenize cache test: {hits} hits, {misses} misses (is --tokenize-cache-entries set?)"
+        );
+        engine.num_errors += 1;
+    }
+
+    let mut outputs: HashMap<String, Vec<u32>> = HashMap::default();
+    for idx in 0..3 {
+        let prompt = template(idx);
+        engine
+            .add_request(format!("tokcache_cached_{idx}"), prompt, params.clone())
+            .unwrap();
+        let tokens = engine.tokenize(prompt, true).unwrap();
+        engine
+            .add_request_tokens(format!("tokcache_uncached_{idx}"), tokens, params.clone())
+            .unwrap();
+    }
+    while engine.num_pending_requests() > 0 {
+        for outp in engine.step().expect("tokenize cache test step failed") {
+            outputs.insert(outp.request_id, outp.seq_outputs[0].output_tokens.clone());
+        }
+    }
+    for idx in 0..3 {
+        let cached = outputs.get(&format!("tokcache_cached_{idx}"));
+        let uncached = outputs.get(&format!("tokcache_uncached_{idx}"));
+        if cached.is_none() || cached != uncached {
+            log::error!("tokenize cache test {idx}: got {cached:?}, expected {uncached:?}");
+            engine.num_errors += 1;
+        }
+    }
+
+    log::info!(
+        "tokenize cache test: {NUM_REQUESTS} requests added in {:?} tokenizing each, {:?} with the cache",
+        uncached_time,
+        cached_time
+    );
+}
+
+// Run the test prompts, and check that the rendered metrics parse, the histogram buckets
+// are cumulative and match their _count, and the latencies of each request and step
+// are counted once.
+fn run_metrics_test<ME: ModelExec>(tests: &[String], engine: &mut RllmEngine<ME>) {
+    let counts = |engine: &RllmEngine<ME>| {
+        let lat = engine.latency();
+        [
+            lat.time_to_first_token.total().count(),
+            lat.queue_wait.total().count(),
+            lat.step.total().count(),
+        ]
+    };
+    let before = counts(engine);
+    for (idx, t) in tests.iter().enumerate() {
+        let prompt = crate::ExpectedGeneration::load(&std::path::PathBuf::from(t))
+            .expect("can't load test")
+            .prompt;
+        engine
+            .queue_request(AddRequest {
+                request_id: format!("metrics_{idx}"),
+                prompt,
+                sampling_params: SamplingParams {
+                    max_tokens: 8,
+                    ..SamplingParams::default()
+                },
+                expected: None,
+                init_result: None,
+                auth: None,
+            })
+            .unwrap();
+    }
+    let mut num_steps = 0;
+    while engine.num_pending_requests() > 0 {
+        engine.step().expect("metrics test step failed");
+        num_steps += 1;
+    }
+    let after = counts(engine);
+    let expected = [tests.len() as u64, tests.len() as u64, num_steps];
+    let mut errors = Vec::new();
+    for (idx, name) in ["time to first token", "queue wait", "step"]
+        .iter()
+        .enumerate()
+    {
+        if after[idx] - before[idx] != expected[idx] {
+            errors.push(format!(
+                "{name}: {} observations, expected {}",
+                after[idx] - before[idx],
+                expected[idx]
+            ));
+        }
+    }
+
+    let text = engine.metrics_text();
+    match crate::metrics::check_exposition(&text) {
+        Ok(n) => log::info!("metrics test: {n} samples after {num_steps} steps"),
+        Err(e) => errors.push(format!("{e}\n{text}")),
+    }
+    for name in ["rllm_steps_total", "rllm_step_seconds_count"] {
+        if !text.lines().any(|l| l.starts_with(name)) {
+            errors.push(format!("{name} missing"));
+        }
+    }
+
+    for msg in errors {
+        log::error!("metrics test: {msg}");
+        engine.num_errors += 1;
+    }
+}
+
+// With the engine paused, add requests past --max-waiting-groups: the ones over the limit
+// are rejected with QueueFull, and counted; after resuming, add_request_blocking() steps
+// until the queue has room, and is accepted.
+fn run_queue_limits_test<ME: ModelExec>(tests: &[String], engine: &mut RllmEngine<ME>) {
+    const NUM_OVER: usize = 3;
+    let limit = engine.config.scheduler.max_waiting_groups;
+    if limit == 0 {
+        log::error!("queue limits test: --max-waiting-groups is not set");
+        engine.num_errors += 1;
+        return;
+    }
+    let prompt = crate::ExpectedGeneration::load(&std::path::PathBuf::from(&tests[0]))
+        .expect("can't load test")
+        .prompt;
+    let prompt = engine
+        .tokenizer
+        .decode(&prompt, false)
+        .expect("can't decode test prompt");
+    let params = SamplingParams {
+        max_tokens: 4,
+        ..SamplingParams::default()
+    };
+    let mut errors = Vec::new();
+    let stats0 = engine.stats();
+
+    engine.pause();
+    let mut accepted = 0;
+    for idx in 0..limit + NUM_OVER {
+        match engine.add_request(format!("queue_{idx}"), &prompt, params.clone()) {
+            Ok(()) => accepted += 1,
+            Err(e) => match e.downcast_ref::<AddRequestError>() {
+                Some(AddRequestError::QueueFull { retry_after_hint })
+                    if !retry_after_hint.is_zero() => {}
+                _ => errors.push(format!("request {idx}: {e}")),
+            },
+        }
+    }
+    let stats = engine.stats();
+    let counted = (
+        stats.requests_admitted - stats0.requests_admitted,
+        stats.requests_rejected - stats0.requests_rejected,
+    );
+    if accepted != limit || stats.num_waiting_groups != limit || counted != (limit, NUM_OVER) {
+        errors.push(format!(
+            "{accepted} accepted, {} waiting, {counted:?} counted; expected {limit} and {NUM_OVER} rejected",
+            stats.num_waiting_groups
+        ));
+    }
+
+    engine.resume();
+    let mut num_waits = 0;
+    let r = engine.add_request_blocking(
+        "queue_blocking".to_string(),
+        &prompt,
+        params.clone(),
+        &mut |engine, _| {
+            num_waits += 1;
+            engine.step().map(|_| ())
+        },
+    );
+    if let Err(e) = r {
+        errors.push(format!("add_request_blocking(): {e}"));
+    }
+    engine.run_to_completion();
+    let stats = engine.stats();
+    if num_waits == 0 || stats.requests_rejected - stats0.requests_rejected != NUM_OVER {
+        errors.push(format!(
+            "add_request_blocking() waited {num_waits} times, {} rejected",
+            stats.requests_rejected - stats0.requests_rejected
+        ));
+    }
+    if stats.num_waiting_groups + stats.queued_prompt_tokens != 0 {
+        errors.push(format!(
+            "{} waiting with {} tokens after the run",
+            stats.num_waiting_groups, stats.queued_prompt_tokens
+        ));
+    }
+
+    for msg in errors {
+        log::error!("queue limits test: {msg}");
+        engine.num_errors += 1;
+    }
+}
+
 fn spawn_inference_loop<ME: ModelExec>(
     args: &RllmCliArgs,
     loader_args: LoaderArgs,
@@ -466,16 +1188,24 @@ fn spawn_inference_loop<ME: ModelExec>(
 
     let warmup = args.warmup.clone();
     let warmup_only = args.warmup_only.clone();
+    let snapshot_file = args.snapshot_file.clone();
 
     std::thread::spawn(move || {
         set_max_priority();
         let mut engine =
             ME::load_rllm_engine(loader_args, model_args).expect("failed to load model");
         engine.set_aicirt(iface);
+        let restored = match &snapshot_file {
+            Some(path) => restore_snapshot(&mut engine, path).unwrap_or_else(|e| {
+                log::error!("failed to restore the snapshot from {path}: {e}");
+                HashSet::default()
+            }),
+            None => HashSet::default(),
+        };
         let wid = "warmup".to_string();
         match warmup {
             Some(w) if w == "off" => {}
-            Some(w) => {
+            Some(w) if w != "synthetic" => {
                 let exp = crate::ExpectedGeneration::load(&std::path::PathBuf::from(&w))
                     .expect("can't load warmup");
                 log::info!(
@@ -486,6 +1216,11 @@ fn spawn_inference_loop<ME: ModelExec>(
                 engine.add_expected_generation(exp, Some(wid)).unwrap();
             }
             _ => {
+                if warmup.as_deref() == Some("synthetic") {
+                    engine
+                        .warmup(WarmupProfile::default())
+                        .expect("warmup failed");
+                }
                 engine
                     .add_request(
                         wid,
@@ -498,7 +1233,15 @@ fn spawn_inference_loop<ME: ModelExec>(
                     .unwrap();
             }
         }
-        inference_loop(handle, engine, recv, stats, warmup_only)
+        inference_loop(
+            handle,
+            engine,
+            recv,
+            stats,
+            warmup_only,
+            snapshot_file,
+            restored,
+        )
     });
 
     handle_res
@@ -530,22 +1273,40 @@ fn guess_aicirt() -> Result<String> {
     }
 }
 
-// #[actix_web::main]
-pub async fn server_main<ME: ModelExec>(
-    mut args: RllmCliArgs,
-    mut model_args: ME::ModelLoaderArgs,
-) -> () {
+/// The tokenizer of the model, when not given with --tokenizer.
+fn default_tokenizer(args: &LoaderArgs) -> Option<String> {
+    gguf_tokenizer(args).or_else(|| guess_tokenizer(&args.model_id))
+}
+
+/// The tokenizer of a local .gguf model: tokenizer.json next to it, or the one in
+/// its metadata (written out to a temporary file).
+fn gguf_tokenizer(args: &LoaderArgs) -> Option<String> {
+    if args.weight_format() != WeightFormat::Gguf {
+        return None;
+    }
+    let dir = std::path::Path::new(args.local_weights.as_ref()?);
+    let tokenizer_json = dir.join("tokenizer.json");
+    if tokenizer_json.exists() {
+        return Some(tokenizer_json.to_string_lossy().to_string());
+    }
+    match crate::gguf::write_tokenizer(&dir.join(args.file.as_ref()?)) {
+        Ok(path) => Some(path.to_string_lossy().to_string()),
+        Err(e) => {
+            log::warn!("can't read the tokenizer from the GGUF file: {e:#}");
+            None
+        }
+    }
+}
+
+/// Set up logging and engine settings, and compute the LoaderArgs from the command line
+/// (see RllmCliArgs::model for the forms of --model). Exits on invalid arguments.
+pub fn init_loader_args(args: &mut RllmCliArgs, log_mode: aicirt::LogMode) -> LoaderArgs {
     // we set env, so that aicirt process also gets it
     match &args.log {
         Some(v) => std::env::set_var("RUST_LOG", v),
         None => {}
     }
-    aicirt::init_log(if args.daemon {
-        aicirt::LogMode::Daemon
-    } else {
-        aicirt::LogMode::Normal
-    })
-    .expect("Failed to initialize log");
+    aicirt::init_log(log_mode).expect("Failed to initialize log");
 
     match apply_settings(&args.setting) {
         Ok(_) => {}
@@ -580,6 +1341,17 @@ pub async fn server_main<ME: ModelExec>(
     }
 
     if args.model.starts_with(".") {
+        if args.file.is_none() && args.model.ends_with(".gguf") {
+            // --model ./path/to/model.gguf
+            let path = std::path::Path::new(&args.model);
+            let (Some(file), Some(dir)) = (path.file_name(), path.parent()) else {
+                eprintln!("invalid model path {}", args.model);
+                std::process::exit(10);
+            };
+            let dir = dir.to_string_lossy().to_string();
+            args.file = Some(file.to_string_lossy().to_string());
+            args.model = if dir.is_empty() { ".".to_string() } else { dir };
+        }
         args.local_weights = Some(args.model.clone());
     }
 
@@ -587,14 +1359,56 @@ pub async fn server_main<ME: ModelExec>(
     loader_args.model_id = args.model.clone();
     loader_args.revision = args.revision.clone();
     loader_args.local_weights = args.local_weights.clone();
+    loader_args.offline = args.offline;
+    loader_args.chat_template = args.chat_template.clone();
     loader_args.file = args.file.clone();
+    loader_args.format = args.weight_format;
+    loader_args.max_prefill_tokens = args.max_prefill_tokens;
+    loader_args.max_prefill_tokens_per_step = args.max_prefill_tokens_per_step;
+    loader_args.preemption_mode = args.preemption_mode;
+    loader_args.recompute_max_len = args.recompute_max_len;
+    loader_args.gpu_watermark = args.gpu_watermark;
+    loader_args.admission_lookahead = args.admission_lookahead;
+    loader_args.livelock_steps = args.livelock_steps;
+    loader_args.priority_boost_ms = args.priority_boost_ms;
+    loader_args.max_waiting_groups = args.max_waiting_groups;
+    loader_args.max_queued_tokens = args.max_queued_tokens;
+    loader_args.log_stats_steps = args.log_stats_steps;
+    loader_args.pipeline_parallel_size = args.pipeline_parallel_size;
+    loader_args.panic_on_nan = args.panic_on_nan;
+    loader_args.tokenize_cache_entries = args.tokenize_cache_entries;
+    loader_args.tokenize_cache_bytes = args.tokenize_cache_mb << 20;
+    loader_args.aici.max_post_sample_retries = args.post_sample_retries;
+    for lora in &args.lora {
+        match lora.split_once('=') {
+            Some((name, path)) => loader_args
+                .lora_adapters
+                .push((name.to_string(), path.into())),
+            None => {
+                eprintln!("--lora expects NAME=PATH, got {lora}");
+                std::process::exit(10);
+            }
+        }
+    }
+
+    if let Some(draft) = &args.draft_model {
+        let mut model_id = draft.clone();
+        let revision = strip_suffix("@", &mut model_id);
+        let local_weights = model_id.starts_with(".").then(|| model_id.clone());
+        loader_args.draft_model = Some(DraftModelArgs {
+            model_id,
+            revision,
+            local_weights,
+            num_tokens: args.draft_tokens,
+        });
+    }
 
     match &args.tokenizer {
         Some(v) => {
             log::info!("explicit tokenizer: {}", v);
             loader_args.tokenizer = v.clone();
         }
-        None => match guess_tokenizer(&loader_args.model_id) {
+        None => match default_tokenizer(&loader_args) {
             Some(v) => {
                 log::info!("guessed tokenizer: {}", v);
                 loader_args.tokenizer = v;
@@ -607,13 +1421,42 @@ pub async fn server_main<ME: ModelExec>(
         },
     }
 
+    loader_args
+}
+
+// #[actix_web::main]
+pub async fn server_main<ME: ModelExec>(
+    mut args: RllmCliArgs,
+    mut model_args: ME::ModelLoaderArgs,
+) -> () {
+    let log_mode = if args.daemon {
+        aicirt::LogMode::Daemon
+    } else {
+        aicirt::LogMode::Normal
+    };
+    let mut loader_args = init_loader_args(&mut args, log_mode);
+
     if args.test.len() > 0 {
         run_tests::<ME>(&args, loader_args, model_args);
         return;
     }
 
-    let (tokenizer, tok_trie) =
+    if let Some(bench) = &args.bench {
+        let batch = match bench.parse::<SyntheticBatch>() {
+            Ok(b) => b,
+            Err(e) => {
+                eprintln!("--bench: {e}");
+                std::process::exit(10);
+            }
+        };
+        run_bench::<ME>(&args, batch, loader_args, model_args);
+        return;
+    }
+
+    let (tokenizer, tok_trie, _) =
         RllmEngine::<ME>::load_tokenizer(&mut loader_args).expect("failed to load tokenizer");
+    let chat_template = RllmEngine::<ME>::load_chat_template(&loader_args, &tokenizer, &tok_trie)
+        .expect("failed to load chat template");
 
     // make sure we try to load the model before spawning inference thread
     // otherwise, if the model doesn't exist, the inference thread will panic and things get messy
@@ -645,6 +1488,7 @@ pub async fn server_main<ME: ModelExec>(
     let rt_args = crate::iface::Args {
         aicirt,
         tokenizer: loader_args.tokenizer.clone(),
+        model_vocab_size: model_meta.vocab_size,
         json_size: args.json_size,
         bin_size: args.bin_size,
         shm_prefix,
@@ -659,24 +1503,36 @@ pub async fn server_main<ME: ModelExec>(
     let iface = AiciRtIface::start_aicirt(&rt_args, &tok_trie).expect("failed to start aicirt");
     let side_cmd_ch = iface.side_cmd.clone();
     let handle = spawn_inference_loop::<ME>(&args, loader_args, model_args, iface, stats.clone());
+    if args.snapshot_file.is_some() {
+        let sender = handle.lock().unwrap().req_sender.clone();
+        tokio::spawn(async move {
+            let mut sigterm = signal(SignalKind::terminate()).expect("failed to handle SIGTERM");
+            sigterm.recv().await;
+            log::info!("SIGTERM; writing the snapshot");
+            let _ = sender.send(InferenceReq::Shutdown).await;
+        });
+    }
 
     let app_data = AiciServerData {
         worker: handle.clone(),
         model_meta,
         tokenizer: Arc::new(tokenizer),
         tok_trie: Arc::new(tok_trie),
+        chat_template: Arc::new(chat_template),
         side_cmd_ch,
         stats,
     };
     let app_data = web::Data::new(app_data);
 
     println!("Listening at http://{}:{}", args.host, args.port);
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .service(models)
             .service(tunnel_info)
             .service(completion::run_controller)
+            .service(openai_completion::completions)
+            .service(openai_completion::chat_completions)
             .service(get_controllers_tags)
             .service(tag_controller)
             .configure(|cfg| {
@@ -685,10 +1541,17 @@ pub async fn server_main<ME: ModelExec>(
             })
             .app_data(app_data.clone())
     })
-    .workers(3)
-    .bind((args.host, args.port))
-    .expect("failed to start server (bind)")
-    .run()
-    .await
-    .expect("failed to start server (run)");
+    .workers(3);
+    // with a snapshot, the inference loop exits (once it's written)
+    let server = if args.snapshot_file.is_some() {
+        server.disable_signals()
+    } else {
+        server
+    };
+    server
+        .bind((args.host, args.port))
+        .expect("failed to start server (bind)")
+        .run()
+        .await
+        .expect("failed to start server (run)");
 }
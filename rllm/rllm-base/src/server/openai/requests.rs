@@ -9,6 +9,7 @@ pub enum Messages {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum StopTokens {
     Multi(Vec<String>),
     Single(String),
@@ -27,7 +28,7 @@ pub struct ChatCompletionRequest {
     #[serde(default)]
     pub max_tokens: Option<usize>, //None
     #[serde(default)]
-    pub stop: Option<String>,
+    pub stop: Option<StopTokens>,
     #[serde(default)]
     pub stream: Option<bool>, //false
     #[serde(default)]
@@ -51,6 +52,8 @@ pub struct ChatCompletionRequest {
     pub skip_special_tokens: Option<bool>, //false
     #[serde(default)]
     pub stop_token_ids: Option<Vec<usize>>, //[]
+    #[serde(default)]
+    pub seed: Option<u64>, //None
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,7 +70,7 @@ pub struct CompletionRequest {
     #[serde(default)]
     pub max_tokens: Option<usize>, //None
     #[serde(default)]
-    pub stop: Option<Vec<String>>,
+    pub stop: Option<StopTokens>,
     #[serde(default)]
     pub stream: Option<bool>, //false
     #[serde(default)]
@@ -90,4 +93,6 @@ pub struct CompletionRequest {
     pub skip_special_tokens: Option<bool>, //false
     #[serde(default)]
     pub stop_token_ids: Option<Vec<usize>>, //[]
+    #[serde(default)]
+    pub seed: Option<u64>, //None
 }
@@ -0,0 +1,302 @@
+// OpenAI-compatible /v1/completions and /v1/chat/completions (without controllers).
+// Requests go to the inference loop like /v1/run; when the client disconnects,
+// the receiver is dropped, and the inference loop aborts the request.
+
+use crate::seq::{FinishReason, RequestOutput, Token, TokenUsage};
+use crate::server::{APIError, AiciServerData, InferenceResult};
+use crate::{config::SamplingParams, AddRequest, ChatMessage};
+use actix_web::{post, web, web::Bytes, HttpResponse};
+use aicirt::get_unix_time;
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::Receiver;
+use uuid::Uuid;
+
+use super::completion::check_length;
+use super::openai::requests::{ChatCompletionRequest, CompletionRequest, Messages, StopTokens};
+use super::openai::responses::{
+    ChatChoice, ChatChoiceData, ChatCompletionResponse, ChatCompletionUsageResponse,
+    CompletionChoice, CompletionResponse, StreamingChatChoice, StreamingChatCompletionResponse,
+    StreamingChoiceData, StreamingCompletionChoice, StreamingCompletionResponse,
+};
+
+// both request types have the same sampling fields
+macro_rules! sampling_params {
+    ($request:expr, $max_tokens:expr) => {{
+        let mut p = SamplingParams::default();
+        p.max_tokens = $max_tokens;
+        if let Some(v) = $request.temperature {
+            p.temperature = v;
+        }
+        if let Some(v) = $request.top_p {
+            p.top_p = v;
+        }
+        if let Some(v) = $request.top_k {
+            p.top_k = v;
+        }
+        if let Some(v) = $request.n {
+            p.n = v;
+        }
+        p.best_of = $request.best_of.unwrap_or(p.n);
+        if let Some(v) = $request.presence_penalty {
+            p.presence_penalty = v;
+        }
+        if let Some(v) = $request.frequency_penalty {
+            p.frequency_penalty = v;
+        }
+        if let Some(v) = $request.ignore_eos {
+            p.ignore_eos = v;
+        }
+        if let Some(v) = &$request.stop_token_ids {
+            p.stop_token_ids = v.iter().map(|t| *t as Token).collect();
+        }
+        p.seed = $request.seed;
+        p.stop = match &$request.stop {
+            Some(StopTokens::Multi(v)) => v.clone(),
+            Some(StopTokens::Single(s)) => vec![s.clone()],
+            None => vec![],
+        };
+        p
+    }};
+}
+
+/// What the responses to a request have in common.
+struct ResponseMeta {
+    id: String,
+    created: u64,
+    model: String,
+    chat: bool,
+}
+
+#[post("/v1/completions")]
+async fn completions(
+    data: web::Data<AiciServerData>,
+    request: web::Json<CompletionRequest>,
+) -> Result<HttpResponse, APIError> {
+    let (max_tokens, token_ids) =
+        check_length(&data, &request.prompt, true, request.max_tokens, false)?;
+    let sampling_params = sampling_params!(request, max_tokens);
+    let meta = ResponseMeta {
+        id: format!("cmpl-{}", Uuid::new_v4()),
+        created: get_unix_time(),
+        model: data.model_meta.id.clone(),
+        chat: false,
+    };
+    let rx = submit(&data, &meta.id, token_ids, sampling_params)?;
+    respond(meta, rx, request.stream.unwrap_or(false)).await
+}
+
+#[post("/v1/chat/completions")]
+async fn chat_completions(
+    data: web::Data<AiciServerData>,
+    request: web::Json<ChatCompletionRequest>,
+) -> Result<HttpResponse, APIError> {
+    let messages = match &request.messages {
+        Messages::Literal(s) => vec![ChatMessage::new("user", s)],
+        Messages::Map(lst) => lst
+            .iter()
+            .map(|m| match (m.get("role"), m.get("content")) {
+                (Some(role), Some(content)) => Ok(ChatMessage::new(role, content)),
+                _ => Err(APIError::new_str("messages need 'role' and 'content'")),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let prompt = data
+        .chat_template
+        .render(&messages, true)
+        .map_err(APIError::just_msg)?;
+    // the template already includes special tokens like BOS
+    let (max_tokens, token_ids) = check_length(&data, &prompt, false, request.max_tokens, false)?;
+    let sampling_params = sampling_params!(request, max_tokens);
+    let meta = ResponseMeta {
+        id: format!("chatcmpl-{}", Uuid::new_v4()),
+        created: get_unix_time(),
+        model: data.model_meta.id.clone(),
+        chat: true,
+    };
+    let rx = submit(&data, &meta.id, token_ids, sampling_params)?;
+    respond(meta, rx, request.stream.unwrap_or(false)).await
+}
+
+fn submit(
+    data: &AiciServerData,
+    request_id: &str,
+    prompt: Vec<Token>,
+    sampling_params: SamplingParams,
+) -> Result<Receiver<InferenceResult>, APIError> {
+    sampling_params.verify_args()?;
+    let rx = data.worker.lock().unwrap().add_request(AddRequest {
+        request_id: request_id.to_string(),
+        prompt,
+        sampling_params,
+        expected: None,
+        init_result: None,
+        auth: None,
+    })?;
+    Ok(rx)
+}
+
+async fn respond(
+    meta: ResponseMeta,
+    mut rx: Receiver<InferenceResult>,
+    stream: bool,
+) -> Result<HttpResponse, APIError> {
+    if stream {
+        return Ok(HttpResponse::Ok()
+            .append_header(("content-type", "text/event-stream"))
+            .streaming(Client { meta, rx }));
+    }
+
+    // index -> (text, finish_reason)
+    let mut choices = BTreeMap::<usize, (String, Option<String>)>::new();
+    let mut usage = TokenUsage::default();
+    while let Some(outp) = rx.recv().await {
+        let outp = outp?;
+        for so in &outp.seq_outputs {
+            let choice = choices.entry(so.index).or_default();
+            choice.0.push_str(&so.new_text);
+            if let Some(r) = &so.finish_reason {
+                choice.1 = Some(finish_reason_name(r));
+            }
+        }
+        usage = outp.usage;
+        if outp.is_final {
+            break;
+        }
+    }
+
+    let usage = usage_response(&usage);
+    let resp = if meta.chat {
+        HttpResponse::Ok().json(ChatCompletionResponse {
+            id: meta.id,
+            choices: choices
+                .into_iter()
+                .map(|(index, (text, finish_reason))| ChatChoice {
+                    message: ChatChoiceData {
+                        content: Some(text),
+                        role: "assistant".to_string(),
+                    },
+                    finish_reason,
+                    index,
+                })
+                .collect(),
+            created: meta.created,
+            model: meta.model,
+            object: "chat.completion",
+            usage,
+        })
+    } else {
+        HttpResponse::Ok().json(CompletionResponse {
+            id: meta.id,
+            choices: choices
+                .into_iter()
+                .map(|(index, (text, finish_reason))| CompletionChoice {
+                    text,
+                    finish_reason,
+                    index,
+                })
+                .collect(),
+            created: meta.created,
+            model: meta.model,
+            object: "text_completion",
+            usage,
+        })
+    };
+    Ok(resp)
+}
+
+/// OpenAI only uses "stop" and "length"; for errors, aborts etc. we use our own names.
+fn finish_reason_name(r: &FinishReason) -> String {
+    match r {
+        FinishReason::FoundEos | FinishReason::StopString | FinishReason::AiciStop => {
+            "stop".to_string()
+        }
+        FinishReason::MaxTokensReached
+        | FinishReason::LengthError
+        | FinishReason::AiciOutOfFuel => "length".to_string(),
+        _ => r.short_name(),
+    }
+}
+
+fn usage_response(u: &TokenUsage) -> ChatCompletionUsageResponse {
+    ChatCompletionUsageResponse {
+        completion_tokens: u.gen_tokens,
+        prompt_tokens: u.prompt_tokens,
+        total_tokens: u.total_tokens(),
+        fuel_tokens: u.fuel_tokens(),
+    }
+}
+
+impl ResponseMeta {
+    fn chunk(&self, outp: &RequestOutput) -> String {
+        let json = if self.chat {
+            serde_json::to_string(&StreamingChatCompletionResponse {
+                id: self.id.clone(),
+                choices: outp
+                    .seq_outputs
+                    .iter()
+                    .map(|so| StreamingChatChoice {
+                        delta: StreamingChoiceData {
+                            content: Some(so.new_text.clone()),
+                            role: "assistant".to_string(),
+                        },
+                        finish_reason: so.finish_reason.as_ref().map(finish_reason_name),
+                        index: so.index,
+                    })
+                    .collect(),
+                created: self.created,
+                model: self.model.clone(),
+                object: "chat.completion.chunk",
+            })
+        } else {
+            serde_json::to_string(&StreamingCompletionResponse {
+                object: "text_completion",
+                id: self.id.clone(),
+                model: self.model.clone(),
+                created: self.created,
+                choices: outp
+                    .seq_outputs
+                    .iter()
+                    .map(|so| StreamingCompletionChoice {
+                        index: so.index,
+                        finish_reason: so.finish_reason.as_ref().map(finish_reason_name),
+                        text: so.new_text.clone(),
+                        error: so.aici_logs.iter().map(|e| e.error.as_str()).collect(),
+                        logs: so.aici_logs.iter().map(|e| e.logs.as_str()).collect(),
+                        storage: so
+                            .aici_logs
+                            .iter()
+                            .flat_map(|e| e.storage.clone())
+                            .collect(),
+                    })
+                    .collect(),
+                usage: usage_response(&outp.usage),
+            })
+        };
+        let mut res = format!("data: {}\n\n", json.unwrap());
+        if outp.is_final {
+            res.push_str("data: [DONE]\n\n");
+        }
+        res
+    }
+}
+
+struct Client {
+    meta: ResponseMeta,
+    rx: Receiver<InferenceResult>,
+}
+
+impl futures::Stream for Client {
+    type Item = Result<Bytes, APIError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        this.rx.poll_recv(cx).map(|x| match x {
+            Some(Ok(outp)) => Some(Ok(Bytes::from(this.meta.chunk(&outp)))),
+            Some(Err(e)) => Some(Err(APIError::from(e))),
+            None => None,
+        })
+    }
+}
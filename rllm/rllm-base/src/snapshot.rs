@@ -0,0 +1,85 @@
+use crate::{
+    config::SamplingParams,
+    seq::{Token, TokenUsage},
+};
+use aicirt::{api::AuthInfo, bail_user};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Bumped on incompatible changes to the snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Unfinished requests of an engine, without the KV cache;
+/// see RllmEngine::snapshot() and RllmEngine::restore().
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub version: u32,
+    /// RllmEngine::model_id; the requests only continue the same way on the same model.
+    pub model_id: String,
+    /// In order of arrival.
+    pub requests: Vec<RequestSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestSnapshot {
+    pub request_id: String,
+    pub prompt: String,
+    /// Includes the controller and its argument, if any.
+    pub sampling_params: SamplingParams,
+    /// See RequestOutput::seed.
+    pub seed: u64,
+    pub max_index: usize,
+    pub usage: TokenUsage,
+    /// See AddRequest::auth.
+    pub auth: Option<AuthInfo>,
+    /// See SequenceGroup::ctrl_prompt.
+    pub ctrl_prompt: Vec<Token>,
+    /// The request had a native controller; see RllmEngine::restore_native_request().
+    #[serde(default)]
+    pub native_ctrl: bool,
+    /// Only the unfinished sequences.
+    pub seqs: Vec<SeqSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeqSnapshot {
+    pub index: usize,
+    /// The prompt, followed by the generated tokens.
+    pub tokens: Vec<Token>,
+    /// Tokens dropped from the model's context (see Truncation::SlidingWindow):
+    /// `ctx_dropped` tokens, after the first `ctx_keep_first` ones.
+    #[serde(default)]
+    pub ctx_keep_first: usize,
+    #[serde(default)]
+    pub ctx_dropped: usize,
+    pub prompt_len: usize,
+    /// Tokens before this were already returned in outputs.
+    pub output_ptr: usize,
+    /// Bytes held back from the outputs so far (incomplete UTF-8, or a possible
+    /// prefix of a stop string).
+    pub output_pending: Vec<u8>,
+    pub cumulative_logprob: f32,
+    pub max_len_hint: Option<usize>,
+    /// See LogitsProcessor::num_draws().
+    pub num_draws: u64,
+}
+
+impl EngineSnapshot {
+    pub fn write_to(&self, w: impl Write) -> Result<()> {
+        serde_json::to_writer(w, self)?;
+        Ok(())
+    }
+
+    pub fn read_from(r: impl Read) -> Result<Self> {
+        let snap: Self = serde_json::from_reader(r)?;
+        if snap.version != SNAPSHOT_VERSION {
+            bail_user!(
+                "snapshot version {} not supported (expecting {})",
+                snap.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        Ok(snap)
+    }
+}
@@ -0,0 +1,225 @@
+//! KV cache mocks and an engine listener shared by the scheduler and engine tests.
+
+use crate::{
+    config::PreemptionMode,
+    seq::{FinishReason, SchedulingPhase, Sequence, SequenceGroup},
+    EngineListener, HashMap, ModelExec, RequestMeta, SchedulerOutputs, SeqCacheUsage, SeqId,
+    SequenceManager, StepStats, TBlockSpaceManager, UsageStats,
+};
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub const BLOCK_SIZE: usize = 4;
+
+/// Number of blocks holding `len` tokens.
+pub fn num_blocks(len: usize) -> usize {
+    len.div_ceil(BLOCK_SIZE)
+}
+
+/// Blocks held by each sequence, shared by MockSeqMgr and MockBlocks, like the
+/// allocators in rllm-cuda. Only tracked with a GPU budget; the CPU swap space is
+/// unlimited. Forks don't share blocks.
+#[derive(Default)]
+pub struct MockCache {
+    pub gpu_budget: Option<usize>,
+    gpu: HashMap<usize, usize>,
+    cpu: HashMap<usize, usize>,
+}
+
+impl MockCache {
+    fn num_free(&self) -> usize {
+        match self.gpu_budget {
+            Some(n) => n - self.gpu.values().sum::<usize>(),
+            None => 0,
+        }
+    }
+
+    fn held(&self, seq: &Sequence) -> usize {
+        self.gpu.get(&seq.seq_id.to_num()).copied().unwrap_or(0)
+    }
+
+    /// Blocks the sequence needs on top of the ones it holds, for `len` tokens.
+    fn to_grow(&self, seq: &Sequence, len: usize) -> usize {
+        num_blocks(len).saturating_sub(self.held(seq))
+    }
+
+    fn resize(&mut self, seq: SeqId, len: usize) {
+        if self.gpu_budget.is_none() {
+            return;
+        }
+        self.gpu.remove(&seq.to_num());
+        let n = num_blocks(len);
+        assert!(n <= self.num_free(), "out of blocks");
+        if n > 0 {
+            self.gpu.insert(seq.to_num(), n);
+        }
+    }
+}
+
+/// Sequence ids start at 1.
+#[derive(Default)]
+pub struct MockSeqMgr {
+    next: AtomicUsize,
+    pub cache: Arc<Mutex<MockCache>>,
+}
+
+impl SequenceManager for MockSeqMgr {
+    fn new_sequence(&self) -> SeqId {
+        SeqId(self.next.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+    fn copy(&self, _src: SeqId, dst: SeqId, length: usize) {
+        self.cache.lock().unwrap().resize(dst, length);
+    }
+    fn trim(&self, seq: SeqId, length: usize) -> usize {
+        self.cache.lock().unwrap().resize(seq, length);
+        length
+    }
+    fn delete(&self, seq: SeqId) {
+        self.trim(seq, 0);
+    }
+}
+
+pub struct MockBlocks<ME> {
+    cache: Arc<Mutex<MockCache>>,
+    _exec: PhantomData<fn() -> ME>,
+}
+
+/// The sequence and block managers of a cache with `gpu_budget` blocks, or an
+/// unlimited one.
+pub fn mock_cache<ME>(gpu_budget: Option<usize>) -> (Arc<MockSeqMgr>, MockBlocks<ME>) {
+    let seq_mgr = Arc::new(MockSeqMgr::default());
+    seq_mgr.cache.lock().unwrap().gpu_budget = gpu_budget;
+    let blocks = MockBlocks {
+        cache: seq_mgr.cache.clone(),
+        _exec: PhantomData,
+    };
+    (seq_mgr, blocks)
+}
+
+impl<ME: ModelExec> TBlockSpaceManager<ME> for MockBlocks<ME> {
+    fn can_allocate(&self, seq_group: &SequenceGroup, reserved_blocks: usize) -> bool {
+        let cache = self.cache.lock().unwrap();
+        if cache.gpu_budget.is_none() {
+            return true;
+        }
+        let needed: usize = seq_group
+            .seqs
+            .iter()
+            .map(|seq| cache.to_grow(seq, seq.get_len()))
+            .sum();
+        needed + reserved_blocks <= cache.num_free()
+    }
+    fn allocate(&mut self, seq_group: &mut SequenceGroup) {
+        let mut cache = self.cache.lock().unwrap();
+        for seq in &seq_group.seqs {
+            cache.resize(seq.seq_id, seq.get_len());
+        }
+    }
+    fn can_append_slot(&self, seq_group: &SequenceGroup) -> bool {
+        let cache = self.cache.lock().unwrap();
+        if cache.gpu_budget.is_none() {
+            return true;
+        }
+        let needed: usize = seq_group
+            .get_seqs(Some(SchedulingPhase::Running))
+            .iter()
+            .map(|seq| cache.to_grow(seq, seq.get_len()))
+            .sum();
+        needed <= cache.num_free()
+    }
+    fn append_slots(&mut self, seq: &mut Sequence, _outputs: &mut SchedulerOutputs) {
+        self.cache.lock().unwrap().resize(seq.seq_id, seq.get_len());
+    }
+    fn get_num_free_gpu_blocks(&self) -> usize {
+        self.cache.lock().unwrap().num_free()
+    }
+    fn get_num_free_cpu_blocks(&self) -> usize {
+        0
+    }
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.cache.lock().unwrap().gpu_budget.unwrap_or(0)
+    }
+    fn num_blocks_to_grow(&self, seq: &Sequence, num_tokens: usize) -> usize {
+        let cache = self.cache.lock().unwrap();
+        if cache.gpu_budget.is_none() {
+            return 0;
+        }
+        cache.to_grow(seq, seq.get_len() + num_tokens)
+    }
+    fn seq_cache_usage(&self, seq: &Sequence) -> SeqCacheUsage {
+        SeqCacheUsage {
+            gpu_blocks: self.cache.lock().unwrap().held(seq),
+            ..Default::default()
+        }
+    }
+    fn can_swap_in(&self, seq_group: &SequenceGroup) -> bool {
+        let cache = self.cache.lock().unwrap();
+        let needed: usize = seq_group
+            .seqs
+            .iter()
+            .filter_map(|seq| cache.cpu.get(&seq.seq_id.to_num()))
+            .sum();
+        needed <= cache.num_free()
+    }
+    fn swap_in(&mut self, seq_group: &mut SequenceGroup) -> HashMap<usize, usize> {
+        let mut cache = self.cache.lock().unwrap();
+        for seq in &mut seq_group.seqs {
+            if seq.sched_phase == SchedulingPhase::Swapped {
+                let n = cache.cpu.remove(&seq.seq_id.to_num()).unwrap_or(0);
+                assert!(n <= cache.num_free(), "out of blocks");
+                cache.gpu.insert(seq.seq_id.to_num(), n);
+                seq.sched_phase = SchedulingPhase::Running;
+            }
+        }
+        HashMap::default()
+    }
+    fn swap_out(&mut self, seq_group: &mut SequenceGroup) -> HashMap<usize, usize> {
+        let mut cache = self.cache.lock().unwrap();
+        for seq in &mut seq_group.seqs {
+            if seq.sched_phase == SchedulingPhase::Running {
+                let n = cache.gpu.remove(&seq.seq_id.to_num()).unwrap_or(0);
+                cache.cpu.insert(seq.seq_id.to_num(), n);
+                seq.sched_phase = SchedulingPhase::Swapped;
+            }
+        }
+        HashMap::default()
+    }
+    fn can_swap_out(&self, _seq_group: &SequenceGroup) -> bool {
+        self.cache.lock().unwrap().gpu_budget.is_some()
+    }
+}
+
+/// Records the events it gets, as strings.
+pub struct Recorder(pub Arc<Mutex<Vec<String>>>);
+
+impl Recorder {
+    fn push(&self, event: String) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+impl EngineListener for Recorder {
+    fn on_request_added(&mut self, req: &RequestMeta) {
+        self.push(format!("added {}", req.request_id));
+    }
+    fn on_scheduled(&mut self, req: &RequestMeta) {
+        self.push(format!("scheduled {}", req.request_id));
+    }
+    fn on_first_token(&mut self, req: &RequestMeta) {
+        self.push(format!("first token {}", req.request_id));
+    }
+    fn on_preempted(&mut self, req: &RequestMeta, mode: PreemptionMode) {
+        self.push(format!("preempted {} {:?}", req.request_id, mode));
+    }
+    fn on_finished(&mut self, req: &RequestMeta, reason: &FinishReason, _usage: &UsageStats) {
+        self.push(format!("finished {} {:?}", req.request_id, reason));
+    }
+    fn on_step_complete(&mut self, stats: &StepStats) {
+        self.push(format!("step {} ({} seqs)", stats.step_no, stats.num_seqs));
+    }
+}
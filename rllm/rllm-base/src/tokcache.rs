@@ -0,0 +1,158 @@
+use crate::{seq::Token, HashMap};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+};
+
+/// (hash of the prompt, add_special_tokens)
+type CacheKey = (u64, bool);
+
+struct CacheEntry {
+    /// Checked on lookup, in case of hash collisions.
+    prompt: String,
+    tokens: Vec<Token>,
+    last_use: u64,
+}
+
+impl CacheEntry {
+    fn num_bytes(&self) -> usize {
+        self.prompt.len() + self.tokens.len() * std::mem::size_of::<Token>()
+    }
+}
+
+/// Tokens of recently tokenized prompts, so that prompts that recur (e.g., templates)
+/// don't go through the tokenizer again; see LoaderArgs::tokenize_cache_entries.
+/// The least recently used entries are evicted when there are more than `max_entries`,
+/// or they take more than `max_bytes` (prompts and tokens).
+pub struct TokenizeCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Last use -> key, oldest first.
+    by_use: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    max_entries: usize,
+    max_bytes: usize,
+    num_bytes: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl TokenizeCache {
+    /// With `max_entries` or `max_bytes` of 0, nothing is cached.
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        TokenizeCache {
+            entries: HashMap::default(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            max_entries,
+            max_bytes,
+            num_bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    fn key(prompt: &str, add_special_tokens: bool) -> CacheKey {
+        let mut h = DefaultHasher::new();
+        prompt.hash(&mut h);
+        (h.finish(), add_special_tokens)
+    }
+
+    fn touch(&mut self, key: CacheKey) -> u64 {
+        self.clock += 1;
+        self.by_use.insert(self.clock, key);
+        self.clock
+    }
+
+    pub fn get(&mut self, prompt: &str, add_special_tokens: bool) -> Option<Vec<Token>> {
+        let key = Self::key(prompt, add_special_tokens);
+        match self.entries.get(&key) {
+            Some(e) if e.prompt == prompt => {}
+            _ => {
+                self.misses += 1;
+                return None;
+            }
+        }
+        self.hits += 1;
+        let stamp = self.touch(key);
+        let entry = self.entries.get_mut(&key).unwrap();
+        self.by_use.remove(&entry.last_use);
+        entry.last_use = stamp;
+        Some(entry.tokens.clone())
+    }
+
+    /// Entries larger than max_bytes are not stored.
+    pub fn insert(&mut self, prompt: &str, add_special_tokens: bool, tokens: &[Token]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let key = Self::key(prompt, add_special_tokens);
+        self.remove(key);
+        let mut entry = CacheEntry {
+            prompt: prompt.to_string(),
+            tokens: tokens.to_vec(),
+            last_use: 0,
+        };
+        let size = entry.num_bytes();
+        if size > self.max_bytes {
+            return;
+        }
+        while self.entries.len() >= self.max_entries || self.num_bytes + size > self.max_bytes {
+            let (_, oldest) = self.by_use.pop_first().unwrap();
+            let e = self.entries.remove(&oldest).unwrap();
+            self.num_bytes -= e.num_bytes();
+        }
+        self.num_bytes += size;
+        entry.last_use = self.touch(key);
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: CacheKey) {
+        if let Some(e) = self.entries.remove(&key) {
+            self.by_use.remove(&e.last_use);
+            self.num_bytes -= e.num_bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TokenizeCache::new(2, 1000);
+        cache.insert("a", true, &[1]);
+        cache.insert("b", true, &[2]);
+        // the flag is part of the key
+        assert_eq!(cache.get("a", false), None);
+        assert_eq!(cache.get("a", true), Some(vec![1]));
+        cache.insert("c", true, &[3]);
+        assert_eq!(cache.get("b", true), None);
+        assert_eq!(cache.get("a", true), Some(vec![1]));
+        assert_eq!(cache.get("c", true), Some(vec![3]));
+        assert_eq!((cache.hits, cache.misses), (3, 2));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.num_bytes(), 2 * (1 + 4));
+
+        // by size: "aaaa" with 2 tokens is 12 bytes
+        let mut cache = TokenizeCache::new(10, 20);
+        cache.insert("aaaa", true, &[1, 1]);
+        cache.insert("bbbb", true, &[2, 2]);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get("bbbb", true), Some(vec![2, 2]));
+        cache.insert(&"x".repeat(30), true, &[3]);
+        assert_eq!(cache.entries.len(), 1);
+
+        let mut cache = TokenizeCache::new(0, 1000);
+        cache.insert("a", true, &[1]);
+        assert_eq!(cache.get("a", true), None);
+    }
+}
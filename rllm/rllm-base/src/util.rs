@@ -4,11 +4,12 @@ use anyhow::{bail, Result};
 use clap::{Args, Command, Parser};
 use std::time::Instant;
 
-const SETTINGS: [(&'static str, &'static str, f64); 4] = [
+const SETTINGS: [(&'static str, &'static str, f64); 5] = [
     ("attn_rtol", "relative tolerance for flash attn check", 0.1),
     ("attn_atol", "absolute tolerance for flash attn check", 0.1),
     ("test_maxtol", "max allowed error for --test and --warmup", 0.5),
     ("test_avgtol", "avg allowed error for --test and --warmup", 0.2),
+    ("cache_check_steps", "cross-check KV cache block counters every N steps", 0.0),
 ];
 
 lazy_static::lazy_static! {
@@ -29,7 +29,8 @@ for A in $FILES ; do
     echo
     echo "*** $A ***"
     echo
-    ARGS="$COMMON_ARGS `cat $A`"
+    # after the arguments of the model, so that EXTRA_ARGS can override its settings
+    ARGS="$COMMON_ARGS `cat $A` $EXTRA_ARGS"
     for S in $(dirname $A)/*.safetensors ; do
         ARGS="$ARGS --test $S"
     done
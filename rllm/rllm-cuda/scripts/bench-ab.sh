@@ -0,0 +1,32 @@
+#!/bin/sh
+
+# Runs `rllm-cli bench` with the given arguments, then again with $B_ARGS added,
+# and prints the times (in ms) of both runs, with the speedup of the second one.
+# For instance, CUDA graphs at batch size 1:
+#
+#   B_ARGS=--cuda-graphs ./scripts/bench-ab.sh --model microsoft/phi-1_5 --batch-sizes 1
+#
+# The numbers are only comparable on an otherwise idle GPU.
+
+set -e
+cd `dirname $0`/..
+
+bench () {
+  RUST_LOG=warn cargo run -q --release --bin rllm-cli -- bench --json "$@"
+}
+
+A=`bench "$@"`
+B=`bench "$@" $B_ARGS`
+
+python3 - "$A" "$B" "$B_ARGS" <<'PY'
+import json, sys
+a, b, b_args = json.loads(sys.argv[1]), json.loads(sys.argv[2]), sys.argv[3]
+print(f"{'':24} {'base':>10} {b_args:>16} {'speedup':>8}")
+for ra, rb in zip(a, b):
+    print(", ".join(f"{k} {v}" for k, v in ra.items() if isinstance(v, int)))
+    for k, va in ra.items():
+        if k.endswith("_ms"):
+            vb = rb[k]
+            speedup = va / vb if vb > 0 else float("nan")
+            print(f"  {k:22} {va:10.2f} {vb:16.2f} {speedup:7.2f}x")
+PY
@@ -0,0 +1,33 @@
+#!/bin/sh
+
+# Acceptance run of GGUF import (dequantized): TinyLlama q4_0 from a local path
+# generates a few greedy tokens through rllm-cli generate.
+
+set -e
+WS=`cd $(dirname $0)/../.. && pwd`
+URL=https://huggingface.co/TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF/resolve/main/tinyllama-1.1b-chat-v1.0.Q4_0.gguf
+# local paths given to --model have to start with "."
+GGUF=../../target/models/tinyllama-1.1b-chat-v1.0.Q4_0.gguf
+MAX_TOKENS=8
+
+cd $WS/rllm/rllm-cuda
+
+if [ ! -f "$GGUF" ] ; then
+  mkdir -p `dirname $GGUF`
+  curl -L --fail -o "$GGUF.tmp" $URL
+  mv "$GGUF.tmp" "$GGUF"
+fi
+
+OUT=`RUST_LOG=info,tokenizers=error cargo run --release --bin rllm-cli -- generate \
+  --model "$GGUF" --prompt "The capital of France is" \
+  --max-tokens $MAX_TOKENS --greedy --json $EXTRA_ARGS`
+echo "$OUT"
+
+echo "$OUT" | python3 -c '
+import json, sys
+r = json.load(sys.stdin)
+assert 0 < r["usage"]["gen_tokens"] <= int(sys.argv[1]), r
+assert r["text"].strip(), r
+' $MAX_TOKENS
+
+echo "GGUF OK!"
@@ -0,0 +1,130 @@
+//! The tch (libtorch) backend of rLLM, shared by the rllm-cuda server and rllm-cli.
+
+pub mod llm;
+
+use aicirt::bail_user;
+use anyhow::Result;
+use clap::Args;
+use llm::{tmodel::TchLoaderArgs, DType};
+use tch::Device;
+
+/// Model options of the tch backend.
+#[derive(Args, Debug)]
+pub struct TchArgs {
+    /// Type of the model weights and activations (bf16, f16, f32); defaults to the type
+    /// of the checkpoint, or f32 on CPU; checkpoints of another type are converted
+    #[arg(long, default_value = "", help_heading = "Model")]
+    pub dtype: String,
+
+    /// Type of the KV cache (bf16, f16, f32); defaults to the type of the weights
+    #[arg(long, default_value = "", help_heading = "Model")]
+    pub kv_cache_dtype: String,
+
+    /// Device to run the model on (auto, cpu, cuda, cuda:N)
+    #[arg(long, default_value = "auto", help_heading = "Model")]
+    pub device: String,
+
+    /// Fraction of GPU memory to use for model weights, activations and KV cache
+    #[arg(long, default_value_t = 0.9, help_heading = "Model")]
+    pub gpu_memory_utilization: f64,
+
+    /// Size of the CPU swap space for KV cache in GiB
+    #[arg(long, default_value_t = 2, help_heading = "Model")]
+    pub swap_space: usize,
+
+    /// Use this many GPU KV cache blocks instead of filling --gpu-memory-utilization
+    /// (mostly for testing behavior under memory pressure)
+    #[arg(long, help_heading = "Development")]
+    pub gpu_blocks: Option<usize>,
+
+    /// Replay CUDA graphs for batches of 1, 2, 4, 8 or 16 generating sequences
+    /// (uses some GPU memory on top of --gpu-memory-utilization);
+    /// batches with sequences using a LoRA adapter are run without them
+    #[arg(long, default_value_t = false, help_heading = "Model")]
+    pub cuda_graphs: bool,
+
+    /// Wait for KV cache swaps to finish before each forward pass, instead of
+    /// overlapping them with it (for comparison)
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub sync_swaps: bool,
+
+    /// Enable nvprof profiling for given engine step (if available)
+    #[arg(long, default_value_t = 0, help_heading = "Development")]
+    pub profile_step: usize,
+}
+
+impl TchArgs {
+    pub fn device(&self) -> Result<Device> {
+        Ok(match self.device.as_str() {
+            "auto" => {
+                if tch::Cuda::is_available() {
+                    Device::Cuda(0)
+                } else {
+                    // At least on AMD 5500m MPS is 3x slower than CPU
+                    Device::Cpu
+                }
+            }
+            "cpu" => Device::Cpu,
+            "cuda" => Device::Cuda(0),
+            d => match d.strip_prefix("cuda:").and_then(|n| n.parse().ok()) {
+                Some(n) => Device::Cuda(n),
+                None => bail_user!("invalid --device {d}; try one of auto, cpu, cuda, cuda:N"),
+            },
+        })
+    }
+
+    pub fn loader_args(&self) -> Result<TchLoaderArgs> {
+        let device = self.device()?;
+
+        let weight_dtype = match device {
+            Device::Cpu => Some(DType::Float),
+            _ => None,
+        };
+
+        Ok(TchLoaderArgs {
+            device,
+            weight_dtype: parse_dtype(&self.dtype, "dtype")?.or(weight_dtype),
+            kv_cache_dtype: parse_dtype(&self.kv_cache_dtype, "kv-cache-dtype")?,
+            profile_step_no: self.profile_step,
+            gpu_memory_utilization: self.gpu_memory_utilization,
+            swap_space: self.swap_space,
+            num_gpu_blocks: self.gpu_blocks,
+            enable_cuda_graphs: self.cuda_graphs,
+            overlap_swaps: !self.sync_swaps,
+        })
+    }
+}
+
+fn parse_dtype(name: &str, arg: &str) -> Result<Option<DType>> {
+    Ok(match name {
+        "bf16" => Some(DType::BFloat16),
+        "f16" => Some(DType::Half),
+        "f32" => Some(DType::Float),
+        "" => None,
+        "fp8" | "f8" | "e4m3" | "e5m2" | "int8" | "i8" => {
+            bail_user!(
+                "--{arg} {name}: quantized types are not supported; try one of bf16, f16, f32"
+            )
+        }
+        _ => bail_user!("invalid --{arg} {name}; try one of bf16, f16, f32"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_dtype;
+    use crate::llm::DType;
+    use aicirt::UserError;
+
+    #[test]
+    fn invalid_dtypes_are_user_errors() {
+        assert_eq!(parse_dtype("", "dtype").unwrap(), None);
+        assert_eq!(parse_dtype("bf16", "dtype").unwrap(), Some(DType::BFloat16));
+        let err = parse_dtype("fp8", "kv-cache-dtype").unwrap_err();
+        assert!(UserError::is_self(&err));
+        assert!(err.to_string().contains("--kv-cache-dtype fp8"), "{err}");
+        assert!(err.to_string().contains("not supported"), "{err}");
+        let err = parse_dtype("f64", "dtype").unwrap_err();
+        assert!(err.to_string().starts_with("invalid --dtype f64"), "{err}");
+    }
+}
@@ -13,6 +13,8 @@ pub trait TchRllmConfig {
     fn get_num_heads_parallel(&self) -> usize;
     fn get_num_layers_parallel(&self) -> usize;
     fn get_max_model_len(&self) -> usize;
+    fn get_pipeline_devices(&self) -> Vec<Device>;
+    fn get_layer_device(&self, layer_no: usize) -> Device;
     fn verify_args(&self) -> Result<()>;
 }
 
@@ -34,6 +36,41 @@ impl TchRllmConfig for RllmConfig<TModel> {
                 parallel.tensor_parallel_size
             );
         }
+        if parallel.tensor_parallel_size != 1 {
+            bail_user!("Tensor parallelism is not supported; use pipeline parallelism instead.");
+        }
+        if parallel.pipeline_parallel_size > 1 {
+            if model.model_type != ModelType::Llama {
+                bail_user!("Pipeline parallelism is only supported for Llama models.");
+            }
+            let first = match model.device {
+                Device::Cuda(n) => n,
+                _ => bail_user!("Pipeline parallelism requires a CUDA device."),
+            };
+            let num_gpus = tch::Cuda::device_count() as usize;
+            if first + parallel.pipeline_parallel_size > num_gpus {
+                bail_user!(
+                    "Pipeline parallel size ({}) starting at cuda:{} needs more GPUs than available ({}).",
+                    parallel.pipeline_parallel_size,
+                    first,
+                    num_gpus
+                );
+            }
+        }
+        if model.enable_cuda_graphs {
+            if !cfg!(feature = "cuda") || !matches!(model.device, Device::Cuda(_)) {
+                bail_user!("CUDA graphs require a CUDA device.");
+            }
+            if parallel.pipeline_parallel_size > 1 {
+                bail_user!("CUDA graphs are not supported with pipeline parallelism.");
+            }
+        }
+        if !(0.0..1.0).contains(&self.scheduler.gpu_watermark) {
+            bail_user!(
+                "GPU watermark must be in [0, 1); got {}.",
+                self.scheduler.gpu_watermark
+            );
+        }
         if self.aici.max_fuel < 100 {
             bail_user!("max_fuel not configured");
         }
@@ -55,12 +92,30 @@ impl TchRllmConfig for RllmConfig<TModel> {
     fn get_max_model_len(&self) -> usize {
         self.meta.max_sequence_length
     }
+    /// Devices holding consecutive groups of layers, starting with the configured device.
+    fn get_pipeline_devices(&self) -> Vec<Device> {
+        match self.model.device {
+            Device::Cuda(first) => (0..self.parallel.pipeline_parallel_size)
+                .map(|i| Device::Cuda(first + i))
+                .collect(),
+            d => vec![d],
+        }
+    }
+    fn get_layer_device(&self, layer_no: usize) -> Device {
+        match self.model.device {
+            Device::Cuda(first) => Device::Cuda(first + layer_no / self.get_num_layers_parallel()),
+            d => d,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ModelType {
     Llama,
+    /// MixFormer checkpoints (phi-1.5 and the original phi-2 release).
     Phi,
+    /// The transformers format of phi-1.5/phi-2 (PhiForCausalLM).
+    Phi2,
 }
 
 pub struct CommonModelConfig {
@@ -85,11 +140,18 @@ pub struct ModelConfig {
 
     pub layer_norm_eps: f64, // default 1e-5
     pub rope_theta: f32,     // default 10000
+    /// Mistral-style sliding-window attention: each token only attends to
+    /// this many most recent tokens (including itself).
+    pub sliding_window: Option<usize>,
 
     pub device: Device,
     pub dtype: DType,
 
     pub profile_step_no: usize,
+    /// Replay CUDA graphs for batches of generating sequences; see paged::CudaGraphs.
+    pub enable_cuda_graphs: bool,
+    /// Names of LoRA adapters, in the order of LoaderArgs::lora_adapters.
+    pub lora_adapters: Vec<String>,
     pub cache: CacheConfig,
 }
 
@@ -107,7 +169,7 @@ impl ModelConfig {
     }
 }
 pub trait RllmModelConfig {
-    fn into_config(self, common: CommonModelConfig) -> ModelConfig;
+    fn into_config(self, common: CommonModelConfig) -> Result<ModelConfig>;
 }
 
 #[derive(Debug, Clone)]
@@ -124,6 +186,16 @@ pub struct CacheConfig {
 
     // #[serde(skip)]
     pub swap_space_bytes: usize,
+
+    /// Use this many GPU blocks, instead of as many as fit in gpu_memory_utilization.
+    pub num_gpu_blocks: Option<usize>,
+
+    /// Run KV cache swaps on a separate stream, concurrently with the model;
+    /// otherwise wait for them to finish before the forward pass.
+    pub overlap_swaps: bool,
+
+    /// Type of the KV cache; keys and values are converted when stored and read.
+    pub dtype: DType,
 }
 
 impl Default for CacheConfig {
@@ -134,9 +206,9 @@ impl Default for CacheConfig {
 
 impl CacheConfig {
     pub fn new(block_size: usize, gpu_memory_utilization: f64, swap_space: usize) -> Result<Self> {
-        if gpu_memory_utilization > 1.0 {
+        if !(gpu_memory_utilization > 0.0 && gpu_memory_utilization <= 1.0) {
             bail_user!(
-                "GPU memory utilization must be less than 1.0. Got {}.",
+                "GPU memory utilization must be in (0, 1]. Got {}.",
                 gpu_memory_utilization
             );
         }
@@ -162,6 +234,10 @@ impl CacheConfig {
             swap_space,
             swap_space_bytes,
             paged_attn_kernel_v,
+            num_gpu_blocks: None,
+            overlap_swaps: true,
+            // the type of the model, once known
+            dtype: DType::BFloat16,
         })
     }
 }
@@ -170,3 +246,17 @@ fn get_cpu_memory() -> usize {
     // TODO
     64 * GB
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CacheConfig;
+
+    #[test]
+    fn gpu_memory_utilization_range() {
+        assert!(CacheConfig::new(16, 0.9, 2).is_ok());
+        assert!(CacheConfig::new(16, 1.0, 2).is_ok());
+        for frac in [0.0, -0.5, 1.01, f64::NAN] {
+            assert!(CacheConfig::new(16, frac, 2).is_err(), "{frac}");
+        }
+    }
+}
@@ -2,10 +2,16 @@
 pub use super::refkernels::*;
 use tch::{Device, Tensor};
 #[cfg(feature = "cuda")]
-pub use tch_cuda::flash_attn_varlen as varlen_attn;
+pub use tch_cuda::flash_attn_varlen_window as varlen_attn;
 #[cfg(feature = "cuda")]
 pub use tch_cuda::*;
 
+/// Whether the CUDA kernels can run on `t`; tensors on other devices (and all tensors,
+/// without the cuda feature) go through the reference kernels instead.
+pub fn on_cuda(t: &Tensor) -> bool {
+    cfg!(feature = "cuda") && t.device().is_cuda()
+}
+
 /// Convert a vector of lengths into a tensor of offsets, as expected by flash attn.
 pub fn to_offsets(seqlens: impl Iterator<Item = usize>, device: Device) -> (usize, Tensor) {
     let mut offsets = Vec::new();
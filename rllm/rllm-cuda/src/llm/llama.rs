@@ -3,6 +3,7 @@
 use super::{
     config::{CommonModelConfig, ModelConfig, ModelType, RllmModelConfig},
     linear_no_bias,
+    lora::{LoraAdapter, LoraLinear},
     paged::BatchInfo,
     varlen_attn, RmsNorm, RotaryEmbedding,
 };
@@ -11,7 +12,7 @@ use serde::Deserialize;
 use std::rc::Rc;
 use tch::{
     nn::{self, Module, Path},
-    Tensor,
+    Device, Tensor,
 };
 
 use super::tmodel::TModelInner;
@@ -29,6 +30,9 @@ pub struct LlamaConfig {
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
     pub torch_dtype: String,
+    // Mistral
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
 }
 
 fn default_rope() -> f32 {
@@ -36,13 +40,13 @@ fn default_rope() -> f32 {
 }
 
 impl RllmModelConfig for LlamaConfig {
-    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+    fn into_config(self, common: CommonModelConfig) -> Result<ModelConfig> {
         let head_dim = self.hidden_size / self.num_attention_heads;
         let mut meta = common.meta.clone();
         meta.vocab_size = self.vocab_size;
         meta.tok_vocab_size = self.vocab_size;
         meta.max_sequence_length = self.max_position_embeddings;
-        ModelConfig {
+        Ok(ModelConfig {
             model_type: ModelType::Llama,
             meta,
             hidden_size: self.hidden_size,
@@ -52,21 +56,24 @@ impl RllmModelConfig for LlamaConfig {
             num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
             layer_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            sliding_window: self.sliding_window,
             head_dim,
             rotary_dim: head_dim,
             dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
             device: common.device,
             profile_step_no: 0,
+            enable_cuda_graphs: false,
+            lora_adapters: Vec::new(),
             cache: Default::default(),
-        }
+        })
     }
 }
 
 struct CausalSelfAttention {
-    q_proj: nn::Linear,
-    k_proj: nn::Linear,
-    v_proj: nn::Linear,
-    o_proj: nn::Linear,
+    q_proj: LoraLinear,
+    k_proj: LoraLinear,
+    v_proj: LoraLinear,
+    o_proj: LoraLinear,
     config: Rc<ModelConfig>,
     rotary: RotaryEmbedding,
 }
@@ -78,9 +85,9 @@ impl CausalSelfAttention {
 
         batch_info.log_tensor("x", &x);
 
-        let q = self.q_proj.forward(x);
-        let k = self.k_proj.forward(x);
-        let v = self.v_proj.forward(x);
+        let q = self.q_proj.forward(x, batch_info);
+        let k = self.k_proj.forward(x, batch_info);
+        let v = self.v_proj.forward(x, batch_info);
 
         let (q, k) = self.rotary.forward(&batch_info.positions, &q, &k);
 
@@ -93,7 +100,7 @@ impl CausalSelfAttention {
         let y = varlen_attn(&self.config, q, k, v, batch_info, block_idx);
 
         let y = y.reshape(&[b_sz, seq_len, hidden_size]);
-        let y = self.o_proj.forward(&y);
+        let y = self.o_proj.forward(&y, batch_info);
 
         batch_info.log_tensor("yp", &y);
 
@@ -109,10 +116,10 @@ impl CausalSelfAttention {
         let v_proj = linear_no_bias(size_in, size_kv, &vb / "v_proj");
         let o_proj = linear_no_bias(size_q, size_in, &vb / "o_proj");
         Ok(Self {
-            q_proj,
-            k_proj,
-            v_proj,
-            o_proj,
+            q_proj: LoraLinear::new(q_proj),
+            k_proj: LoraLinear::new(k_proj),
+            v_proj: LoraLinear::new(v_proj),
+            o_proj: LoraLinear::new(o_proj),
             config: cfg.clone(),
             rotary: rotary.clone(),
         })
@@ -120,22 +127,22 @@ impl CausalSelfAttention {
 }
 
 struct Mlp {
-    c_fc1: nn::Linear,
-    c_fc2: nn::Linear,
-    c_proj: nn::Linear,
+    c_fc1: LoraLinear,
+    c_fc2: LoraLinear,
+    c_proj: LoraLinear,
 }
 
 impl Mlp {
     fn forward(&self, x: &Tensor, batch_info: &BatchInfo) -> Tensor {
-        let m1 = self.c_fc1.forward(x);
-        let m2 = self.c_fc2.forward(x);
-        batch_info.log_tensor("w1", &self.c_fc1.ws);
+        let m1 = self.c_fc1.forward(x, batch_info);
+        let m2 = self.c_fc2.forward(x, batch_info);
+        batch_info.log_tensor("w1", &self.c_fc1.base.ws);
         batch_info.log_tensor("m1", &m1);
         batch_info.log_tensor("m2", &m2);
         let si = m1.silu();
         batch_info.log_tensor("si", &m2);
         let x = si * &m2;
-        self.c_proj.forward(&x)
+        self.c_proj.forward(&x, batch_info)
     }
 
     fn load(vb: Path, cfg: &ModelConfig) -> Result<Self> {
@@ -145,9 +152,9 @@ impl Mlp {
         let c_fc2 = linear_no_bias(h_size, i_size, &vb / "up_proj");
         let c_proj = linear_no_bias(i_size, h_size, &vb / "down_proj");
         Ok(Self {
-            c_fc1,
-            c_fc2,
-            c_proj,
+            c_fc1: LoraLinear::new(c_fc1),
+            c_fc2: LoraLinear::new(c_fc2),
+            c_proj: LoraLinear::new(c_proj),
         })
     }
 }
@@ -175,6 +182,19 @@ impl Block {
         x
     }
 
+    /// The linear layers adapters can change, under their names in the checkpoint.
+    fn lora_targets(&mut self) -> [(&'static str, &mut LoraLinear); 7] {
+        [
+            ("self_attn.q_proj", &mut self.attn.q_proj),
+            ("self_attn.k_proj", &mut self.attn.k_proj),
+            ("self_attn.v_proj", &mut self.attn.v_proj),
+            ("self_attn.o_proj", &mut self.attn.o_proj),
+            ("mlp.gate_proj", &mut self.mlp.c_fc1),
+            ("mlp.up_proj", &mut self.mlp.c_fc2),
+            ("mlp.down_proj", &mut self.mlp.c_proj),
+        ]
+    }
+
     fn load(mut vb: Path, rotary: &RotaryEmbedding, cfg: &Rc<ModelConfig>) -> Result<Self> {
         let attn = CausalSelfAttention::load(&vb / "self_attn", rotary, cfg)?;
         let mlp = Mlp::load(&vb / "mlp", cfg)?;
@@ -196,39 +216,73 @@ pub struct Llama {
     blocks: Vec<Block>,
     ln_f: RmsNorm,
     lm_head: nn::Linear,
+    // one per pipeline stage; blocks are split evenly between them
+    devices: Vec<Device>,
 }
 
 impl TModelInner for Llama {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let layers_per_stage = self.blocks.len() / self.devices.len();
         let mut x = self.wte.forward(&batch_info.tokens).unsqueeze(0);
         for (block_idx, block) in self.blocks.iter().enumerate() {
+            if block_idx > 0 && block_idx % layers_per_stage == 0 {
+                let device = self.devices[block_idx / layers_per_stage];
+                x = x.to_device(device);
+                batch_info.to_device(device);
+            }
             x = block.forward(&x, batch_info, block_idx);
         }
         let x0 = self.ln_f.forward(&x);
         // println!("x: {}", x0);
         let x = batch_info.extract_positions(&x0.squeeze_dim(0));
         let logits = self.lm_head.forward(&x);
-        logits
+        // sampling and biases expect logits on the first device
+        logits.to_device(self.devices[0])
+    }
+
+    fn load_lora(&mut self, idx: usize, adapter: &mut LoraAdapter) -> Result<()> {
+        for (layer, block) in self.blocks.iter_mut().enumerate() {
+            for (module, linear) in block.lora_targets() {
+                if let Some((a, b)) = adapter.take(layer, module) {
+                    linear.add_adapter(idx, a, b, adapter.scale)?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl Llama {
-    pub fn load(vs: Path, cfg: &Rc<ModelConfig>) -> Result<Self> {
-        let rotary = RotaryEmbedding::new(cfg);
+    /// Load the model split into pipeline stages, one per element of `stages`.
+    /// Embeddings go to the first stage, final norm and LM head to the last one.
+    pub fn load(stages: &[Path], cfg: &Rc<ModelConfig>) -> Result<Self> {
+        let first = &stages[0];
+        let last = &stages[stages.len() - 1];
+        let layers_per_stage = cfg.num_hidden_layers / stages.len();
 
-        let lm_head = linear_no_bias(cfg.hidden_size, cfg.meta.vocab_size, &vs / "lm_head");
+        let lm_head = linear_no_bias(cfg.hidden_size, cfg.meta.vocab_size, last / "lm_head");
 
         let wte = nn::embedding(
-            &vs / "model" / "embed_tokens",
+            first / "model" / "embed_tokens",
             cfg.meta.vocab_size as i64,
             cfg.hidden_size as i64,
             Default::default(),
         );
 
-        let ln_f = RmsNorm::from_cfg(&vs / "model" / "norm", cfg);
+        let ln_f = RmsNorm::from_cfg(last / "model" / "norm", cfg);
+
+        let devices: Vec<_> = stages.iter().map(|vs| vs.device()).collect();
+        let rotaries: Vec<_> = devices
+            .iter()
+            .map(|d| RotaryEmbedding::new(cfg).to_device(*d))
+            .collect();
 
         let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(&vs / "model" / "layers" / i, &rotary, cfg).unwrap())
+            .map(|i| {
+                let stage = i / layers_per_stage;
+                let vs = &stages[stage] / "model" / "layers" / i;
+                Block::load(vs, &rotaries[stage], cfg).unwrap()
+            })
             .collect();
 
         Ok(Self {
@@ -236,6 +290,7 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
+            devices,
         })
     }
 }
@@ -1,15 +1,18 @@
 use super::{
-    config::ModelType,
+    config::{CacheConfig, ModelType, TchRllmConfig},
     llama,
+    lora::LoraAdapter,
     paged::{BatchInfoBuilder, BlockSpaceManager, CacheEngine},
     phi,
-    tmodel::TModel,
+    tmodel::{DraftModel, TModel},
     util::{gpu_memory_size, gpu_peak_allocated_bytes, log_mem_stats, reset_mem_stats},
 };
+use aicirt::bail_user;
 use anyhow::{bail, Result};
 use rllm::{
-    config::{ModelMeta, RllmConfig},
-    CacheSize, HashSet, LoaderArgs, Repo, RllmEngine,
+    config::{ModelMeta, RllmConfig, WeightFormat},
+    gguf::GgufFile,
+    CacheSize, DraftModelArgs, HashMap, HashSet, LoaderArgs, Repo, RllmEngine,
 };
 use safetensors::Dtype;
 use std::{path::PathBuf, rc::Rc, sync::Arc};
@@ -37,7 +40,7 @@ fn kind_from_dt(dtype: Dtype) -> Kind {
     }
 }
 
-fn read_tensor(s: &safetensors::SafeTensors, name: &str) -> Result<Tensor> {
+pub(super) fn read_tensor(s: &safetensors::SafeTensors, name: &str) -> Result<Tensor> {
     let view = s.tensor(name)?;
     let size: Vec<i64> = view.shape().iter().map(|&x| x as i64).collect();
     let kind: DType = kind_from_dt(view.dtype());
@@ -50,17 +53,32 @@ fn load_model(
     rllm_config: &RllmConfig<TModel>,
     filenames: Vec<PathBuf>,
 ) -> Result<Box<dyn TModelInner>> {
-    let mut vs = VarStore::new(rllm_config.model.device.clone());
+    // one var store per pipeline stage, so that weights are created on the right device
+    let mut stores: Vec<_> = rllm_config
+        .get_pipeline_devices()
+        .into_iter()
+        .map(VarStore::new)
+        .collect();
 
     let rc_cfg = Rc::new(rllm_config.model.clone());
     let mut model: Box<dyn TModelInner> = match rllm_config.model.model_type {
-        ModelType::Llama => Box::new(llama::Llama::load(vs.root(), &rc_cfg).unwrap()),
-        ModelType::Phi => Box::new(phi::MixFormerSequentialForCausalLM::new(&rc_cfg, vs.root())),
+        ModelType::Llama => {
+            let stages: Vec<_> = stores.iter().map(|vs| vs.root()).collect();
+            Box::new(llama::Llama::load(&stages, &rc_cfg)?)
+        }
+        ModelType::Phi => Box::new(phi::MixFormerSequentialForCausalLM::new(
+            &rc_cfg,
+            stores[0].root(),
+        )),
+        ModelType::Phi2 => Box::new(phi::PhiForCausalLM::new(&rc_cfg, stores[0].root())),
     };
 
-    vs.set_kind(rllm_config.model.dtype);
-
-    let mut vars = vs.variables();
+    let mut vars = HashMap::default();
+    let mut num_converted = 0;
+    for vs in stores.iter_mut() {
+        vs.set_kind(rllm_config.model.dtype);
+        vars.extend(vs.variables());
+    }
 
     let bar = indicatif::ProgressBar::new(vars.len() as u64);
     bar.set_style(
@@ -70,33 +88,62 @@ fn load_model(
         .unwrap(),
     );
 
+    let mut copy_var = |vname: &str, mut src_tensor: Tensor| -> Result<()> {
+        let mut var = match vars.remove(vname) {
+            Some(var) => var,
+            None => {
+                if !vname.ends_with(".inv_freq") {
+                    log::warn!("variable {} not found in the model", vname);
+                }
+                return Ok(());
+            }
+        };
+        if var.size() != src_tensor.size() {
+            bail!(
+                "variable {vname}: shape {:?} in the checkpoint, {:?} in the model",
+                src_tensor.size(),
+                var.size()
+            );
+        }
+        if src_tensor.kind() != var.kind() {
+            // e.g., a BF16 checkpoint loaded as F16
+            log::debug!("converting {} from {:?}", vname, src_tensor.kind());
+            num_converted += 1;
+            src_tensor = src_tensor.to_kind(var.kind());
+        }
+        var.f_copy_(&src_tensor)?;
+
+        bar.inc(1);
+        if bar.is_hidden() {
+            eprint!(".");
+        }
+        Ok(())
+    };
+
     for f in &filenames {
+        if f.extension().is_some_and(|e| e == "gguf") {
+            // dequantized to f32, and converted to the model's type when copied
+            log::info!(
+                "importing {}: dequantizing the weights to {:?}",
+                f.display(),
+                rllm_config.model.dtype
+            );
+            let gguf = GgufFile::open(f)?;
+            for w in gguf.hf_weights()? {
+                let w = w?;
+                let shape: Vec<i64> = w.shape.iter().map(|&d| d as i64).collect();
+                copy_var(&w.name, Tensor::from_slice(&w.data).reshape(&shape))?;
+            }
+            continue;
+        }
+
         let fp = std::fs::File::open(f)?;
         let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
         let safetensors = safetensors::SafeTensors::deserialize(&content)?;
 
         for vname in safetensors.names() {
-            let target_name = vname.to_string();
-            if !vars.contains_key(&target_name) {
-                if vname.ends_with(".inv_freq") {
-                    // OK
-                } else {
-                    log::warn!("variable {} not found in the model", target_name);
-                }
-                continue;
-            }
-
             // Using from_blob here instead of from_data_size avoids some unnecessary copy.
-            let src_tensor = read_tensor(&safetensors, vname)?;
-            let mut var = vars.remove(&target_name).unwrap();
-            assert!(var.size() == src_tensor.size());
-            // println!("copying to {var:?} from {src_tensor:?}");
-            var.f_copy_(&src_tensor)?;
-
-            bar.inc(1);
-            if bar.is_hidden() {
-                eprint!(".");
-            }
+            copy_var(vname, read_tensor(&safetensors, vname)?)?;
         }
     }
 
@@ -104,6 +151,13 @@ fn load_model(
         bail!("{} variables not found in the model: {vars:?}", vars.len());
     }
 
+    if num_converted > 0 {
+        log::info!(
+            "converted {num_converted} tensor(s) to {:?}",
+            rllm_config.model.dtype
+        );
+    }
+
     if bar.is_hidden() {
         eprintln!(" done");
     }
@@ -116,7 +170,14 @@ fn load_model(
     Ok(model)
 }
 
-fn model_filenames(repo: &Repo) -> Result<Vec<PathBuf>> {
+fn model_filenames(repo: &Repo, args: &LoaderArgs) -> Result<Vec<PathBuf>> {
+    if args.weight_format() == WeightFormat::Gguf {
+        match &args.file {
+            Some(file) => return repo.get_all(&[file.clone()]),
+            None => bail_user!("GGUF weights need a model file (--model ./path/model.gguf)"),
+        }
+    }
+
     let idx = repo.read("model.safetensors.index.json");
 
     let filenames = if let Ok(idx) = idx {
@@ -139,12 +200,7 @@ fn model_filenames(repo: &Repo) -> Result<Vec<PathBuf>> {
         }
     };
 
-    let filenames = filenames
-        .iter()
-        .map(|f| repo.get(f))
-        .collect::<Result<Vec<_>>>()?;
-
-    Ok(filenames)
+    repo.get_all(&filenames)
 }
 
 pub(super) fn load_rllm_engine(
@@ -153,71 +209,214 @@ pub(super) fn load_rllm_engine(
 ) -> Result<RllmEngine<TModel>> {
     let _no_grad = tch::no_grad_guard();
 
-    let device = model_args.device;
     let repo = Repo::from(&args)?;
 
     let rllm_config = RllmEngine::<TModel>::build_config(&args, &mut model_args)?;
 
-    let filenames = model_filenames(&repo)?;
-    log::info!("building the model");
+    let filenames = model_filenames(&repo, &args)?;
+    log::info!(
+        "building the model; weights: {:?}, KV cache: {:?}, logits: {:?}",
+        rllm_config.model.dtype,
+        rllm_config.model.cache.dtype,
+        DType::Float
+    );
+
+    let devices = rllm_config.get_pipeline_devices();
+    for &device in &devices {
+        let _ = Tensor::zeros(&[1], (rllm_config.model.dtype, device));
+        reset_mem_stats(device);
+        log_mem_stats("initial", device);
+    }
+
+    let mut model = load_model(&rllm_config, filenames)?;
 
-    let _ = Tensor::zeros(&[1], (rllm_config.model.dtype, device));
-    reset_mem_stats(device);
-    log_mem_stats("initial", device);
+    for (idx, (name, path)) in args.lora_adapters.iter().enumerate() {
+        log::info!("loading LoRA adapter '{}' from {}", name, path.display());
+        let mut adapter = LoraAdapter::load(name, path)?;
+        model.load_lora(idx, &mut adapter)?;
+        adapter.check_all_taken()?;
+    }
+    if rllm_config.model.enable_cuda_graphs && !args.lora_adapters.is_empty() {
+        log::warn!("CUDA graphs are not used for batches with sequences using a LoRA adapter");
+    }
 
-    let model = load_model(&rllm_config, filenames)?;
+    let draft = args
+        .draft_model
+        .as_ref()
+        .map(|draft| load_draft_model(&args, draft, &mut model_args, &rllm_config))
+        .transpose()?;
 
-    log_mem_stats("model fully loaded", device);
+    for &device in &devices {
+        log_mem_stats("model fully loaded", device);
+    }
 
     let rllm_config = Arc::new(rllm_config);
-    let cache_size = profile_model(rllm_config.clone(), &model);
+    let draft_config = draft.as_ref().map(|(config, _)| config);
+    let cache_size = profile_model(rllm_config.clone(), &model, draft_config)?;
     let cache_engine = CacheEngine::new(rllm_config.clone(), &cache_size);
+    let draft = draft.map(|(config, model)| {
+        // same blocks as the main cache; draft KV is recomputed after swaps
+        let draft_cache_size = CacheSize {
+            gpu: cache_size.gpu,
+            cpu: 0,
+        };
+        let config = Arc::new(config);
+        DraftModel {
+            cache_engine: CacheEngine::new(config.clone(), &draft_cache_size),
+            config,
+            model,
+        }
+    });
 
     let block_mgr = BlockSpaceManager::new(
         rllm_config.model.cache.block_size,
         &cache_size,
-        0.01,
+        rllm_config.scheduler.gpu_watermark,
         &rllm_config,
     );
     let seq_mgr = Arc::new(block_mgr.build_seq_mgr());
-    let tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model);
+    let tmodel = TModel::new(rllm_config.clone(), cache_engine, seq_mgr, model, draft);
 
     RllmEngine::build(args, tmodel, block_mgr, rllm_config)
 }
 
-fn profile_model(config: Arc<RllmConfig<TModel>>, model: &Box<dyn TModelInner>) -> CacheSize {
-    let device = config.model.device.clone();
-    let gpu_mem = gpu_memory_size(device);
+/// Load the draft model for speculative decoding; it runs on the same device, with the
+/// same types, and shares the block tables (so also the block size) with the main model.
+fn load_draft_model(
+    args: &LoaderArgs,
+    draft: &DraftModelArgs,
+    model_args: &mut TchLoaderArgs,
+    config: &RllmConfig<TModel>,
+) -> Result<(RllmConfig<TModel>, Box<dyn TModelInner>)> {
+    if args.pipeline_parallel_size > 1 {
+        bail_user!("Speculative decoding is not supported with pipeline parallelism.");
+    }
+    if draft.num_tokens == 0 {
+        bail_user!("The number of draft tokens must be positive.");
+    }
+    let draft_args = LoaderArgs {
+        tokenizer: args.tokenizer.clone(),
+        model_id: draft.model_id.clone(),
+        revision: draft.revision.clone(),
+        local_weights: draft.local_weights.clone(),
+        offline: args.offline,
+        aici: args.aici.clone(),
+        ..Default::default()
+    };
+    let repo = Repo::from(&draft_args)?;
+    let mut draft_config = RllmEngine::<TModel>::build_config(&draft_args, model_args)?;
+
+    let (model, dm) = (&config.model, &draft_config.model);
+    if model.sliding_window.is_some() || dm.sliding_window.is_some() {
+        bail_user!("Speculative decoding is not supported with sliding-window attention.");
+    }
+    if config.meta.vocab_size != draft_config.meta.vocab_size {
+        bail_user!(
+            "Draft model {} has a vocabulary of {} tokens, but the model has {}.",
+            repo,
+            draft_config.meta.vocab_size,
+            config.meta.vocab_size
+        );
+    }
+    if dm.cache.block_size != model.cache.block_size {
+        bail_user!(
+            "Draft model {} uses KV cache blocks of {} tokens, but the model uses {}.",
+            repo,
+            dm.cache.block_size,
+            model.cache.block_size
+        );
+    }
+    let max_model_len = config.scheduler.max_model_len;
+    if draft_config.meta.max_sequence_length < max_model_len {
+        bail_user!(
+            "Draft model {} supports up to {} tokens, but the model supports {}.",
+            repo,
+            draft_config.meta.max_sequence_length,
+            max_model_len
+        );
+    }
+    draft_config.scheduler.max_model_len = max_model_len;
+
+    log::info!(
+        "loading the draft model; {} token(s) per step",
+        draft.num_tokens
+    );
+    let draft_model = load_model(&draft_config, model_filenames(&repo, &draft_args)?)?;
+    Ok((draft_config, draft_model))
+}
 
-    let gpu_cache_size = if gpu_mem > 0 {
+fn profile_model(
+    config: Arc<RllmConfig<TModel>>,
+    model: &Box<dyn TModelInner>,
+    draft_config: Option<&RllmConfig<TModel>>,
+) -> Result<CacheSize> {
+    let devices = config.get_pipeline_devices();
+    let mut device_mem = vec![];
+    if gpu_memory_size(devices[0]) > 0 {
         let mut info = BatchInfoBuilder::new(config.clone()).profile_run();
-        reset_mem_stats(device);
-        log_mem_stats("before model profile", device);
+        for &device in &devices {
+            reset_mem_stats(device);
+            log_mem_stats("before model profile", device);
+        }
         let _logits = model.forward(&mut info);
-        log_mem_stats("after model profile", device);
-
-        let frac = config.model.cache.gpu_memory_utilization;
-        let peak = gpu_peak_allocated_bytes(device) as isize;
-        let left = (gpu_mem as f64 * frac) as isize - peak;
-        if left < 0 {
-            panic!("not enough GPU memory for the cache: {gpu_mem} * {frac} < {peak}");
+        for &device in &devices {
+            log_mem_stats("after model profile", device);
+            let peak = gpu_peak_allocated_bytes(device);
+            device_mem.push((device, gpu_memory_size(device), peak));
         }
-        left as usize
-    } else {
+    }
+
+    // each block holds the KV of both models; the draft model runs on the batches
+    // of the main one, so its activations fit in the profiled peak
+    let mut elt_size = CacheEngine::get_cache_block_size(&config);
+    if let Some(draft_config) = draft_config {
+        elt_size += CacheEngine::get_cache_block_size(draft_config);
+    }
+
+    cache_size_for(
+        &config.model.cache,
+        config.scheduler.max_model_len,
+        &device_mem,
+        elt_size,
+    )
+}
+
+/// The number of KV cache blocks of `elt_size` bytes, given the memory size and the
+/// peak allocated bytes (after the profile run) of each device; none without a GPU.
+fn cache_size_for(
+    cache: &CacheConfig,
+    max_model_len: usize,
+    device_mem: &[(Device, usize, usize)],
+    elt_size: usize,
+) -> Result<CacheSize> {
+    let gpu_cache_size = if device_mem.is_empty() {
         512 << 20 // 512MiB
+    } else {
+        // every device holds the same number of layers, so the cache size
+        // (in blocks) is limited by the device with the least memory left
+        let frac = cache.gpu_memory_utilization;
+        let mut min_left = isize::MAX;
+        for &(device, gpu_mem, peak) in device_mem {
+            let peak = peak as isize;
+            let left = (gpu_mem as f64 * frac) as isize - peak;
+            if left < 0 {
+                bail!(
+                    "not enough GPU memory for the cache on {device:?}: {gpu_mem} * {frac} < {peak}"
+                );
+            }
+            min_left = std::cmp::min(min_left, left);
+        }
+        min_left as usize
     };
 
-    let max_cpu = 2 << 30; // 2GiB
-    let cpu_cache_size = std::cmp::min(max_cpu, gpu_cache_size);
-
-    let elt_size = CacheEngine::get_cache_block_size(&config);
+    let cpu_cache_size = std::cmp::min(cache.swap_space_bytes, gpu_cache_size);
 
     let r = CacheSize {
         cpu: cpu_cache_size / elt_size,
-        gpu: gpu_cache_size / elt_size,
+        gpu: cache.num_gpu_blocks.unwrap_or(gpu_cache_size / elt_size),
     };
 
-    let token_kv_size = elt_size / config.model.cache.block_size;
+    let token_kv_size = elt_size / cache.block_size;
 
     const G: f64 = 1024.0 * 1024.0 * 1024.0;
     log::info!(
@@ -226,12 +425,21 @@ fn profile_model(config: Arc<RllmConfig<TModel>>, model: &Box<dyn TModelInner>)
         cpu_cache_size as f64 / G,
         r.gpu,
         r.cpu,
-        r.gpu * config.model.cache.block_size,
-        r.cpu * config.model.cache.block_size,
+        r.gpu * cache.block_size,
+        r.cpu * cache.block_size,
         token_kv_size / 1024,
     );
 
-    r
+    if r.gpu * cache.block_size < max_model_len {
+        bail!(
+            "KV cache too small: {} tokens fit, but a single sequence can have {} tokens; \
+             try increasing --gpu-memory-utilization",
+            r.gpu * cache.block_size,
+            max_model_len
+        );
+    }
+
+    Ok(r)
 }
 
 pub(super) fn load_model_config(
@@ -241,17 +449,55 @@ pub(super) fn load_model_config(
     let repo = Repo::from(args)?;
     log::info!("loading the model from {}", repo);
 
-    let bytes = repo.read("config.json")?;
+    let bytes = match (args.weight_format(), &args.file) {
+        (WeightFormat::Gguf, Some(file)) => {
+            serde_json::to_vec(&GgufFile::open(&repo.get(file)?)?.hf_config()?)?
+        }
+        _ => repo.read("config.json")?,
+    };
     let mut err = String::new();
 
-    let cfg = load_one_config::<llama::LlamaConfig>(&mut err, args, model_args, "llama", &bytes)
-        .or_else(|| load_one_config::<phi::PhiConfig>(&mut err, args, model_args, "phi", &bytes));
+    let json: serde_json::Value = serde_json::from_slice(&bytes)?;
+    let cfg = match json["model_type"].as_str().unwrap_or("") {
+        // Phi-2 has Llama-like field names, so don't guess
+        "phi" => load_one_config::<phi::Phi2Config>(&mut err, args, model_args, "phi2", &bytes)?,
+        _ => match load_one_config::<llama::LlamaConfig>(
+            &mut err, args, model_args, "llama", &bytes,
+        )? {
+            Some(v) => Some(v),
+            None => load_one_config::<phi::PhiConfig>(&mut err, args, model_args, "phi", &bytes)?,
+        },
+    };
 
     match cfg {
         Some(mut v) => {
             let tok = aicirt::bintokens::find_tokenizer(&args.tokenizer)?;
             v.meta.tok_vocab_size = tok.tokrx_info().vocab_size as usize;
             v.profile_step_no = model_args.profile_step_no;
+            v.enable_cuda_graphs = model_args.enable_cuda_graphs;
+            v.lora_adapters = args.lora_adapters.iter().map(|(n, _)| n.clone()).collect();
+            v.cache = CacheConfig::new(
+                v.cache.block_size,
+                model_args.gpu_memory_utilization,
+                model_args.swap_space,
+            )?;
+            v.cache.num_gpu_blocks = model_args.num_gpu_blocks;
+            v.cache.overlap_swaps = model_args.overlap_swaps;
+            v.cache.dtype = model_args.kv_cache_dtype.unwrap_or(v.dtype);
+            match v.cache.dtype {
+                DType::BFloat16 | DType::Half | DType::Float => {}
+                // the attention kernels have no scales to dequantize these with
+                dt => bail_user!("KV cache of type {dt:?} is not supported; use bf16, f16 or f32"),
+            }
+            if !v.device.is_cuda() {
+                // the paged attention kernel has no reference version; varlen attention
+                // falls back to the naive one
+                v.cache.paged_attn_kernel_v = 0;
+            } else if v.cache.dtype != v.dtype && v.cache.paged_attn_kernel_v > 0 {
+                // the kernel reads the cache in the type of the queries
+                log::info!("KV cache of another type than the model; not using paged attention");
+                v.cache.paged_attn_kernel_v = 0;
+            }
             Ok(v)
         }
         None => bail!("failed to load model config:\n{}", err),
@@ -264,7 +510,7 @@ fn load_one_config<T>(
     model_args: &TchLoaderArgs,
     name: &str,
     bytes: &[u8],
-) -> Option<ModelConfig>
+) -> Result<Option<ModelConfig>>
 where
     T: RllmModelConfig + serde::de::DeserializeOwned,
 {
@@ -275,14 +521,64 @@ where
             tok_vocab_size: 0,
             max_sequence_length: 0,
         },
-        dtype: model_args.dtype,
+        dtype: model_args.weight_dtype,
         device: model_args.device,
     };
     let json = serde_json::from_slice::<T>(bytes);
     if let Ok(json) = json {
-        Some(json.into_config(common))
+        Ok(Some(json.into_config(common)?))
     } else {
         *err += &format!("{name}: {}\n", json.err().unwrap());
-        None
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_size_for, CacheConfig};
+    use tch::Device;
+
+    const GIB: usize = 1 << 30;
+    // 1MiB per block
+    const ELT_SIZE: usize = 1 << 20;
+
+    #[test]
+    fn gpu_blocks_follow_free_memory() {
+        // half of the memory, with 1GiB of swap space
+        let cache = CacheConfig::new(16, 0.5, 1).unwrap();
+        let size = |max_model_len, device_mem: &[(Device, usize, usize)]| {
+            cache_size_for(&cache, max_model_len, device_mem, ELT_SIZE)
+        };
+
+        // 4GiB of 8GiB, less the 1GiB peak of the profile run
+        let r = size(1024, &[(Device::Cuda(0), 8 * GIB, GIB)]).unwrap();
+        assert_eq!((r.gpu, r.cpu), (3 * 1024, 1024));
+
+        // the device with the least memory left sets the number of blocks
+        let two = [
+            (Device::Cuda(0), 8 * GIB, GIB),
+            (Device::Cuda(1), 8 * GIB, 2 * GIB),
+        ];
+        assert_eq!(size(1024, &two).unwrap().gpu, 2 * 1024);
+
+        // without a GPU, 512MiB
+        assert_eq!(size(1024, &[]).unwrap().gpu, 512);
+
+        // the peak is over the fraction of the memory
+        assert!(size(1024, &[(Device::Cuda(0), 8 * GIB, 5 * GIB)]).is_err());
+
+        // 3072 blocks hold 49152 tokens, just enough for one sequence
+        let one = [(Device::Cuda(0), 8 * GIB, GIB)];
+        assert!(size(3 * 1024 * 16, &one).is_ok());
+        let err = size(3 * 1024 * 16 + 1, &one).unwrap_err();
+        assert!(err.to_string().starts_with("KV cache too small"), "{err}");
+
+        // a given number of blocks is used as is
+        let cache = CacheConfig {
+            num_gpu_blocks: Some(100),
+            ..cache.clone()
+        };
+        let r = cache_size_for(&cache, 1024, &one, ELT_SIZE).unwrap();
+        assert_eq!(r.gpu, 100);
     }
 }
@@ -0,0 +1,217 @@
+// LoRA adapters in the PEFT format, applied on top of the base weights (without merging),
+// so that every sequence in a batch can use a different adapter, or none.
+
+use super::{loader::read_tensor, paged::BatchInfo};
+use anyhow::{anyhow, bail, Result};
+use rllm::HashMap;
+use std::path::Path;
+use tch::{
+    nn::{self, Module},
+    Tensor,
+};
+
+pub struct LoraAdapter {
+    pub name: String,
+    /// lora_alpha / r (or / sqrt(r) with use_rslora), from adapter_config.json
+    pub scale: f64,
+    /// (layer, module like "self_attn.q_proj") -> (A [r, in], B [out, r]); on CPU
+    weights: HashMap<(usize, String), (Tensor, Tensor)>,
+}
+
+impl LoraAdapter {
+    /// Load adapter_config.json and adapter_model.safetensors from the given folder.
+    pub fn load(name: &str, path: &Path) -> Result<Self> {
+        let cfg: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(path.join("adapter_config.json"))?)?;
+        let r = cfg["r"]
+            .as_f64()
+            .ok_or_else(|| anyhow!("{name}: 'r' missing in adapter_config.json"))?;
+        let alpha = cfg["lora_alpha"].as_f64().unwrap_or(r);
+        let scale = if cfg["use_rslora"].as_bool() == Some(true) {
+            alpha / r.sqrt()
+        } else {
+            alpha / r
+        };
+
+        let fp = std::fs::File::open(path.join("adapter_model.safetensors"))?;
+        let content = unsafe { memmap2::MmapOptions::new().map(&fp)? };
+        let safetensors = safetensors::SafeTensors::deserialize(&content)?;
+
+        let mut a_weights = HashMap::default();
+        let mut b_weights = HashMap::default();
+        for vname in safetensors.names() {
+            // base_model.model.model.layers.3.self_attn.q_proj.lora_A.weight
+            let parsed = vname
+                .split_once("layers.")
+                .and_then(|(_, rest)| rest.strip_suffix(".weight"))
+                .map(|rest| rest.trim_end_matches(".default"))
+                .and_then(|rest| rest.split_once('.'))
+                .and_then(|(layer, rest)| {
+                    let (module, kind) = rest.rsplit_once('.')?;
+                    Some((layer.parse::<usize>().ok()?, module.to_string(), kind))
+                });
+            let (layer, module, kind) = match parsed {
+                Some(p) => p,
+                None => {
+                    log::warn!("{name}: unexpected tensor {vname}");
+                    continue;
+                }
+            };
+            // the memory map goes away at the end of this function
+            let tensor = read_tensor(&safetensors, vname)?.copy();
+            match kind {
+                "lora_A" => a_weights.insert((layer, module), tensor),
+                "lora_B" => b_weights.insert((layer, module), tensor),
+                _ => {
+                    log::warn!("{name}: unexpected tensor {vname}");
+                    continue;
+                }
+            };
+        }
+
+        let mut weights = HashMap::default();
+        for (key, a) in a_weights {
+            match b_weights.remove(&key) {
+                Some(b) => weights.insert(key, (a, b)),
+                None => bail!("{name}: lora_B missing for layer {} {}", key.0, key.1),
+            };
+        }
+        if let Some(key) = b_weights.keys().next() {
+            bail!("{name}: lora_A missing for layer {} {}", key.0, key.1);
+        }
+        if weights.is_empty() {
+            bail!("{name}: no LoRA weights found");
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            scale,
+            weights,
+        })
+    }
+
+    /// Remove the weights for the given layer and module, if the adapter has them.
+    pub fn take(&mut self, layer: usize, module: &str) -> Option<(Tensor, Tensor)> {
+        self.weights.remove(&(layer, module.to_string()))
+    }
+
+    /// Fail if some weights were not taken, i.e., they don't match the model.
+    pub fn check_all_taken(&self) -> Result<()> {
+        match self.weights.keys().next() {
+            Some((layer, module)) => bail!(
+                "{}: no place in the model for weights of layer {layer} {module}",
+                self.name
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+struct LoraWeights {
+    a_t: Tensor, // [in, r]
+    b_t: Tensor, // [r, out]
+    scale: f64,
+}
+
+/// A linear layer with LoRA adapters; each token goes through the adapter
+/// of its sequence (see BatchInfo::lora_rows), if any.
+pub struct LoraLinear {
+    pub base: nn::Linear,
+    // indexed by the position of the adapter in LoaderArgs::lora_adapters;
+    // None when the adapter doesn't change this layer
+    adapters: Vec<Option<LoraWeights>>,
+}
+
+impl LoraLinear {
+    pub fn new(base: nn::Linear) -> Self {
+        Self {
+            base,
+            adapters: Vec::new(),
+        }
+    }
+
+    pub fn add_adapter(&mut self, idx: usize, a: Tensor, b: Tensor, scale: f64) -> Result<()> {
+        let ws = &self.base.ws; // [out, in]
+        let (out_dim, in_dim) = ws.size2()?;
+        let (r, a_in) = a.size2()?;
+        let (b_out, b_r) = b.size2()?;
+        if a_in != in_dim || b_out != out_dim || b_r != r {
+            bail!(
+                "LoRA shapes A={:?} B={:?} don't match weight {:?}",
+                a.size(),
+                b.size(),
+                ws.size()
+            );
+        }
+        if self.adapters.len() <= idx {
+            self.adapters.resize_with(idx + 1, || None);
+        }
+        let to_ws = |t: Tensor| t.to_device(ws.device()).to_kind(ws.kind());
+        self.adapters[idx] = Some(LoraWeights {
+            a_t: to_ws(a.tr()).contiguous(),
+            b_t: to_ws(b.tr()).contiguous(),
+            scale,
+        });
+        Ok(())
+    }
+
+    pub fn forward(&self, x: &Tensor, batch_info: &BatchInfo) -> Tensor {
+        self.forward_rows(x, &batch_info.lora_rows)
+    }
+
+    fn forward_rows(&self, x: &Tensor, lora_rows: &[(usize, Tensor)]) -> Tensor {
+        let y = self.base.forward(x);
+        if self.adapters.is_empty() || lora_rows.is_empty() {
+            return y;
+        }
+        let y_size = y.size();
+        let x = x.reshape(&[-1, *x.size().last().unwrap()]);
+        let mut y = y.reshape(&[-1, *y_size.last().unwrap()]);
+        for (idx, rows) in lora_rows {
+            if let Some(Some(w)) = self.adapters.get(*idx) {
+                let delta = x.index_select(0, rows).matmul(&w.a_t).matmul(&w.b_t) * w.scale;
+                y = y.index_add(0, rows, &delta);
+            }
+        }
+        y.reshape(&y_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tch::{Device, Kind};
+
+    const OPTS: (Kind, Device) = (Kind::Float, Device::Cpu);
+
+    #[test]
+    fn zero_b_gives_the_base_outputs() {
+        tch::manual_seed(1);
+        let (in_dim, out_dim, r) = (16, 12, 8);
+        let base = nn::Linear {
+            ws: Tensor::randn(&[out_dim, in_dim], OPTS),
+            bs: None,
+        };
+        let mut lin = LoraLinear::new(base);
+        let a = Tensor::randn(&[r, in_dim], OPTS);
+        let zero_b = Tensor::zeros(&[out_dim, r], OPTS);
+        lin.add_adapter(0, a.copy(), zero_b, 2.0).unwrap();
+        let b = Tensor::randn(&[out_dim, r], OPTS);
+        lin.add_adapter(1, a, b, 2.0).unwrap();
+
+        let x = Tensor::randn(&[5, in_dim], OPTS);
+        let expected = lin.base.forward(&x);
+        let rows = vec![(0, Tensor::from_slice(&[0i64, 2, 3]))];
+        assert!(lin.forward_rows(&x, &rows).equal(&expected));
+
+        // a non-zero B changes the rows of its sequences, and only these
+        let rows = vec![(1, Tensor::from_slice(&[1i64, 4]))];
+        let diff = (lin.forward_rows(&x, &rows) - &expected).abs();
+        let changed: Vec<bool> = diff
+            .sum_dim_intlist(-1, false, Kind::Float)
+            .gt(1e-4)
+            .try_into()
+            .unwrap();
+        assert_eq!(changed, [false, true, false, false, true]);
+    }
+}
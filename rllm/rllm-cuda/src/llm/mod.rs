@@ -2,6 +2,7 @@ pub mod config;
 pub mod kernels;
 pub mod llama;
 pub mod loader;
+pub mod lora;
 pub mod phi;
 pub mod refkernels;
 pub mod tmodel;
@@ -59,6 +60,13 @@ impl RotaryEmbedding {
         }
     }
 
+    pub fn to_device(&self, device: tch::Device) -> Self {
+        Self {
+            config: self.config.clone(),
+            cos_sin: self.cos_sin.to_device(device),
+        }
+    }
+
     pub fn forward(
         &self,
         positions: &Tensor, // [num_tokens]
@@ -82,7 +90,7 @@ impl RotaryEmbedding {
         // println!("k: {k:?}");
         // println!("c: {:?}", &self.cache.cos_sin);
 
-        if CHECK {
+        if CHECK && kernels::on_cuda(&q) {
             let mut qq = q.copy();
             let mut kk = k.copy();
             kernels::rotary_embedding(
@@ -103,7 +111,7 @@ impl RotaryEmbedding {
             );
             check_all_close(&q, &qq, 1e-5);
             check_all_close(&k, &kk, 1e-5);
-        } else {
+        } else if kernels::on_cuda(&q) {
             kernels::rotary_embedding(
                 &positions,
                 &mut q,
@@ -112,6 +120,15 @@ impl RotaryEmbedding {
                 &self.cos_sin,
                 true,
             );
+        } else {
+            refkernels::rotary_embedding(
+                &positions,
+                &mut q,
+                &mut k,
+                self.config.head_dim,
+                &self.cos_sin,
+                true,
+            );
         }
 
         let q = q.reshape(&[
@@ -139,9 +156,20 @@ fn save_attn(
     let (mut key_cache, mut value_cache) = batch_info.kv_cache.get(block_idx);
 
     assert!(v.size() == k.size());
+    // the cache may be of another type than the model (see CacheConfig::dtype)
+    let k = &k.to_kind(key_cache.kind());
+    let v = &v.to_kind(value_cache.kind());
 
     // first, stuff the query-sized key/value into the cache
-    if CHECK {
+    if !kernels::on_cuda(&key_cache) {
+        refkernels::reshape_and_cache(
+            k,
+            v,
+            &mut key_cache,
+            &mut value_cache,
+            &batch_info.slot_mapping,
+        );
+    } else if CHECK {
         let mut kk = key_cache.copy();
         let mut vv = value_cache.copy();
         kernels::reshape_and_cache(
@@ -184,19 +212,29 @@ fn compute_varlen_attn(
             config.num_key_value_heads as i64,
             config.head_dim as i64,
         ],
-        (q.kind(), q.device()),
+        (key_cache.kind(), q.device()),
     );
 
     let mut v = k.empty_like();
-    kernels::gather_cached_kv(
-        &mut k,
-        &mut v,
-        &key_cache,
-        &value_cache,
-        &batch_info.gather_mapping,
-    );
+    if kernels::on_cuda(&key_cache) {
+        kernels::gather_cached_kv(
+            &mut k,
+            &mut v,
+            &key_cache,
+            &value_cache,
+            &batch_info.gather_mapping,
+        );
+    } else {
+        refkernels::gather_cached_kv(
+            &mut k,
+            &mut v,
+            &key_cache,
+            &value_cache,
+            &batch_info.gather_mapping,
+        );
+    }
 
-    if CHECK {
+    if CHECK && kernels::on_cuda(&key_cache) {
         let mut kk = k.empty_like();
         let mut vv = v.empty_like();
 
@@ -211,8 +249,8 @@ fn compute_varlen_attn(
         check_all_close(&v, &vv, 1e-5);
     }
 
-    let k = repeat_kv(config, k);
-    let v = repeat_kv(config, v);
+    let k = repeat_kv(config, k.to_kind(q.kind()));
+    let v = repeat_kv(config, v.to_kind(q.kind()));
 
     let y = {
         batch_info.log_tensor("q", &q);
@@ -224,7 +262,9 @@ fn compute_varlen_attn(
 
         let causal = true;
 
-        let y = if config.dtype == DType::BFloat16 || config.dtype == DType::Half {
+        // flash-attn only runs on CUDA, in 16 bits; otherwise attend naively
+        let flash = config.dtype == DType::BFloat16 || config.dtype == DType::Half;
+        let y = if flash && kernels::on_cuda(&q) {
             let y = kernels::varlen_attn(
                 &q,
                 &k,
@@ -235,6 +275,7 @@ fn compute_varlen_attn(
                 batch_info.max_seqlen_k,
                 softmax_scale,
                 causal,
+                config.sliding_window,
             );
 
             if CHECK {
@@ -248,6 +289,7 @@ fn compute_varlen_attn(
                     batch_info.max_seqlen_k,
                     softmax_scale,
                     causal,
+                    config.sliding_window,
                 );
                 check_all_close_attn(&y, &y2);
             }
@@ -264,9 +306,26 @@ fn compute_varlen_attn(
                 batch_info.max_seqlen_k,
                 softmax_scale,
                 causal,
+                config.sliding_window,
             )
         };
 
+        if !batch_info.attn_masks.is_empty() {
+            // flash-attn has no masks other than causal and sliding window, so the
+            // sequences with masks are computed again with the reference kernel
+            refkernels::apply_attn_masks(
+                &y,
+                &q,
+                &k,
+                &v,
+                &batch_info.seqlens_q,
+                &batch_info.seqlens_k,
+                softmax_scale,
+                config.sliding_window,
+                &batch_info.attn_masks,
+            );
+        }
+
         y
     };
 
@@ -1,15 +1,19 @@
-use super::super::{kernels::to_offsets, tmodel::TModel};
+use super::super::{config::TchRllmConfig, kernels::to_offsets, tmodel::TModel};
 use super::cache_engine::CacheEngine;
 use super::BlockAllocator;
 use rllm::{
-    config::RllmConfig, seq::SchedulingPhase, util::pad_to_multiple, HashMap, SchedulerOutputs,
+    config::RllmConfig,
+    seq::{SchedulingPhase, Sequence},
+    util::pad_to_multiple,
+    HashMap, HashSet, SchedulerOutputs,
 };
 use aicirt::api::Token;
 use std::{
     fmt::Debug,
+    ops::Range,
     sync::{Arc, Mutex},
 };
-use tch::{IndexOp, Tensor};
+use tch::{Device, IndexOp, Kind, Tensor};
 
 pub trait CacheIface {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor);
@@ -22,10 +26,10 @@ pub struct BatchInfo {
     pub seqlens_k: Tensor,      // u32, [batch_size + 1]; can go outside tokens/positions
     pub gather_mapping: Tensor, // u32, [sum(context_len + prompt_len)]
     pub slot_mapping: Tensor,   // u32, [num_tokens]
-    pub logit_idxs: Tensor,     // u32, [batch_size]
+    pub logit_idxs: Tensor,     // u32, [num_logits]; the last num_logits tokens of each seq
     pub max_seqlen_q: usize,
     pub max_seqlen_k: usize,
-    pub seq_id_to_idx: HashMap<usize, usize>, // seq_id -> index into seqlens_*
+    pub seq_id_to_idx: HashMap<usize, usize>, // seq_id -> its last row in logit_idxs
 
     pub infer_log: Mutex<Vec<(String, Tensor)>>,
     pub step_no: usize,
@@ -40,6 +44,14 @@ pub struct BatchInfo {
 
     pub seqlen_multi: i64,
     pub q_multi: i64,
+
+    /// For each LoRA adapter used in the batch: its index and the indices
+    /// (i64, into tokens) of tokens of sequences using it.
+    pub lora_rows: Vec<(usize, Tensor)>,
+
+    /// For varlen entries (index into seqlens_q) with keys hidden by the controller:
+    /// the hidden ranges of keys of the entry (see Sequence::masked_positions()).
+    pub attn_masks: Vec<(usize, Vec<Range<usize>>)>,
 }
 
 impl BatchInfo {
@@ -69,6 +81,26 @@ impl BatchInfo {
     pub fn extract_positions(&self, x: &Tensor) -> Tensor {
         x.i((&self.logit_idxs, ..))
     }
+
+    /// Move the tensors used by attention layers to another device
+    /// (when crossing pipeline stages).
+    pub fn to_device(&mut self, device: Device) {
+        for t in [
+            &mut self.positions,
+            &mut self.seqlens_q,
+            &mut self.seqlens_k,
+            &mut self.gather_mapping,
+            &mut self.slot_mapping,
+            &mut self.logit_idxs,
+            &mut self.paged_block_tables,
+            &mut self.paged_context_lens,
+        ] {
+            *t = t.to_device(device);
+        }
+        for (_, rows) in self.lora_rows.iter_mut() {
+            *rows = rows.to_device(device);
+        }
+    }
 }
 
 impl Debug for BatchInfo {
@@ -101,7 +133,82 @@ pub struct BatchInfoBuilder {
 struct BatchEntry {
     seq_id: usize,
     query_pos_token: Vec<(usize, Token)>,
+    // only the slots within the sliding window, if any
     kv_slots: Vec<usize>,
+    // some KV before kv_slots falls out of the sliding window
+    windowed: bool,
+    // index of the LoRA adapter, if any
+    lora: Option<usize>,
+    // logits of the last tokens of the query; more than 1 when verifying draft tokens
+    num_logits: usize,
+    // keys (indices into kv_slots) hidden from attention
+    masked: Vec<Range<usize>>,
+}
+
+impl BatchEntry {
+    // paged attention can't start in the middle of a block, so windowed entries go through varlen;
+    // it also has no masks
+    fn is_single_token(&self) -> bool {
+        self.query_pos_token.len() == 1 && !self.windowed && self.masked.is_empty()
+    }
+}
+
+/// Host-side contents of BatchInfo.
+struct HostBatch {
+    positions: Vec<i64>,
+    tokens: Vec<i32>,
+    logit_idxs: Vec<i32>,
+    seqlens_q: Vec<usize>,
+    seqlens_k: Vec<usize>,
+    gather_mapping: Vec<i32>,
+    slot_mapping: Vec<i32>,
+    seq_id_to_idx: HashMap<usize, usize>,
+    paged_block_tables: Vec<Vec<i32>>,
+    paged_context_lens: Vec<i32>,
+    num_multitoken: usize,
+    first_single_token: usize,
+    lora_rows: Vec<(usize, Vec<i64>)>,
+    attn_masks: Vec<(usize, Vec<Range<usize>>)>,
+}
+
+/// Device buffers for batches of `batch_size` single-token sequences using
+/// paged attention (see BatchInfoBuilder::paged_batch_size()).
+/// BatchInfoBuilder::finish_into() copies the batch into these buffers, instead
+/// of allocating new tensors, so that their addresses stay the same across steps,
+/// as needed for replaying CUDA graphs.
+pub struct StaticBatchInputs {
+    pub batch_size: usize,
+    tokens: Tensor,
+    positions: Tensor,
+    slot_mapping: Tensor,
+    logit_idxs: Tensor,
+    paged_block_tables: Tensor, // [batch_size, blocks for max_model_len]
+    paged_context_lens: Tensor,
+    // not used by paged attention
+    seqlens: Tensor,
+    gather_mapping: Tensor,
+}
+
+impl StaticBatchInputs {
+    pub fn new(config: &RllmConfig<TModel>, batch_size: usize) -> Self {
+        let device = config.model.device;
+        let n = batch_size as i64;
+        let max_blocks = config
+            .scheduler
+            .max_model_len
+            .div_ceil(config.model.cache.block_size) as i64;
+        Self {
+            batch_size,
+            tokens: Tensor::zeros(&[n], (Kind::Int, device)),
+            positions: Tensor::zeros(&[n], (Kind::Int64, device)),
+            slot_mapping: Tensor::zeros(&[n], (Kind::Int, device)),
+            logit_idxs: Tensor::zeros(&[n], (Kind::Int, device)),
+            paged_block_tables: Tensor::zeros(&[n, max_blocks], (Kind::Int, device)),
+            paged_context_lens: Tensor::zeros(&[n], (Kind::Int, device)),
+            seqlens: Tensor::zeros(&[1], (Kind::Int, device)),
+            gather_mapping: Tensor::zeros(&[0], (Kind::Int, device)),
+        }
+    }
 }
 
 impl BatchInfoBuilder {
@@ -119,29 +226,54 @@ impl BatchInfoBuilder {
     ) -> &mut Self {
         assert!(sched_out.next_seq_groups.len() > 0);
         for sg in sched_out.next_seq_groups.iter_mut() {
+            // the engine checks the name when queuing the request
+            let lora = sg.sampling_params.lora.as_ref().map(|name| {
+                self.config
+                    .model
+                    .lora_adapters
+                    .iter()
+                    .position(|n| n == name)
+                    .unwrap()
+            });
             for seq in sg.seqs.iter_mut() {
                 if seq.sched_phase != SchedulingPhase::Running {
                     continue;
                 }
 
-                let seq_len = seq.get_len();
-                let k_len = seq_len;
+                // while prefilling in chunks, only compute KV up to the end of the chunk
+                let k_len = seq.get_kv_len();
                 log::trace!("seq: {seq:?}");
-                let mut q_len = seq.get_len() - seq.num_kv_computed;
-                if q_len == 0 {
-                    // just re-compute the last token
-                    q_len = 1;
+                let q_len = seq.num_query_tokens();
+                if !seq.is_prefilling() {
+                    sg.usage.gen_tokens += 1;
                 }
-                sg.usage.gen_tokens += 1;
-                sg.usage.prompt_tokens += q_len;
+                // the engine charges the draft tokens it accepts
+                sg.usage.prompt_tokens += q_len - seq.num_draft_tokens();
 
                 let off = k_len - q_len;
+                // the first query needs window-1 keys before it
+                let kv_start = match self.config.model.sliding_window {
+                    Some(window) => (off + 1).saturating_sub(window),
+                    None => 0,
+                };
+                let masked = seq
+                    .masked_positions()
+                    .into_iter()
+                    .filter_map(|r| {
+                        let r = std::cmp::max(r.start, kv_start)..std::cmp::min(r.end, k_len);
+                        (r.start < r.end).then(|| r.start - kv_start..r.end - kv_start)
+                    })
+                    .collect();
                 self.entries.push(BatchEntry {
                     seq_id: seq.seq_id.to_num(),
                     query_pos_token: (off..off + q_len)
                         .map(|idx| (idx, seq.get_token(idx)))
                         .collect(),
-                    kv_slots: alloc.get_block_idxes(seq.seq_id, k_len),
+                    kv_slots: alloc.get_block_idxes(seq.seq_id, kv_start, k_len),
+                    windowed: kv_start > 0,
+                    lora,
+                    num_logits: seq.num_draft_tokens() + 1,
+                    masked,
                 });
 
                 seq.sync_computed_kv();
@@ -151,6 +283,23 @@ impl BatchInfoBuilder {
         self
     }
 
+    /// Add the sequence for a step of the draft model, which has KV for the first
+    /// `start` tokens; `start` is before the last token, which has to be computed.
+    pub fn draft_seq(&mut self, seq: &Sequence, start: usize, alloc: &BlockAllocator) -> &mut Self {
+        let len = seq.get_len();
+        assert!(start < len);
+        self.entries.push(BatchEntry {
+            seq_id: seq.seq_id.to_num(),
+            query_pos_token: (start..len).map(|idx| (idx, seq.get_token(idx))).collect(),
+            kv_slots: alloc.get_block_idxes(seq.seq_id, 0, len),
+            windowed: false,
+            lora: None,
+            num_logits: 1,
+            masked: Vec::new(),
+        });
+        self
+    }
+
     pub fn profile_run(&mut self) -> BatchInfo {
         let sch_cfg = &self.config.clone().scheduler;
         let seq_len = sch_cfg.max_model_len;
@@ -166,6 +315,10 @@ impl BatchInfoBuilder {
                 seq_id,
                 query_pos_token: (0..1).map(|_| (idx, fake_token)).collect(),
                 kv_slots: (0..avg_len).map(|_| fake_slot).collect(),
+                windowed: false,
+                lora: None,
+                num_logits: 1,
+                masked: Vec::new(),
             });
         }
 
@@ -177,6 +330,10 @@ impl BatchInfoBuilder {
                 seq_id,
                 query_pos_token: (0..seq_len).map(|idx| (idx, fake_token)).collect(),
                 kv_slots: (0..seq_len).map(|_| fake_slot).collect(),
+                windowed: false,
+                lora: None,
+                num_logits: 1,
+                masked: Vec::new(),
             });
         }
 
@@ -188,12 +345,48 @@ impl BatchInfoBuilder {
     }
 
     fn fake_finish(&mut self) -> BatchInfo {
-        let (k, v) = CacheEngine::alloc_gpu_cache_layer(&self.config, 1);
-        let kv_cache = Box::new(FakeKVCache { k, v });
+        let kv = self
+            .config
+            .get_pipeline_devices()
+            .into_iter()
+            .map(|d| CacheEngine::alloc_gpu_cache_layer(&self.config, 1, d))
+            .collect();
+        let kv_cache = Box::new(FakeKVCache {
+            kv,
+            layers_per_stage: self.config.get_num_layers_parallel(),
+        });
         self.finish(0, kv_cache)
     }
 
-    pub fn finish(&mut self, step_no: usize, kv_cache: Box<dyn CacheIface>) -> BatchInfo {
+    /// If all sequences in the batch use paged attention (i.e., they compute a single token,
+    /// which is typical when generating), returns their number.
+    pub fn paged_batch_size(&self) -> Option<usize> {
+        if self.config.model.cache.paged_attn_kernel_v > 0
+            && self.entries.iter().all(|e| e.is_single_token())
+        {
+            Some(self.entries.len())
+        } else {
+            None
+        }
+    }
+
+    /// Some sequences in the batch use a LoRA adapter.
+    pub fn has_lora(&self) -> bool {
+        self.entries.iter().any(|e| e.lora.is_some())
+    }
+
+    /// Some sequences in the batch attend to KV in one of the `blocks`.
+    pub fn reads_any_block(&self, blocks: &HashSet<usize>) -> bool {
+        let block_size = self.config.model.cache.block_size;
+        !blocks.is_empty()
+            && self.entries.iter().any(|e| {
+                e.kv_slots
+                    .iter()
+                    .any(|s| blocks.contains(&(s / block_size)))
+            })
+    }
+
+    fn host_batch(&mut self) -> HostBatch {
         let mut positions: Vec<i64> = Vec::new();
         let mut tokens: Vec<i32> = Vec::new();
         let mut logit_idxs: Vec<i32> = Vec::new();
@@ -205,12 +398,14 @@ impl BatchInfoBuilder {
 
         let mut paged_block_tables: Vec<Vec<i32>> = Vec::new();
         let mut paged_context_lens: Vec<i32> = Vec::new();
+        let mut lora_rows: Vec<(usize, Vec<i64>)> = Vec::new();
+        let mut attn_masks: Vec<(usize, Vec<Range<usize>>)> = Vec::new();
 
         let num_multitoken = if self.config.model.cache.paged_attn_kernel_v > 0 {
             // sort single-token entries to the back
             let (single, multi) = std::mem::take(&mut self.entries)
                 .into_iter()
-                .partition::<Vec<_>, _>(|e| e.query_pos_token.len() == 1);
+                .partition::<Vec<_>, _>(|e| e.is_single_token());
             let multi_len = multi.len();
             self.entries = multi;
             self.entries.extend(single);
@@ -224,21 +419,35 @@ impl BatchInfoBuilder {
         let max_seq = self.config.scheduler.max_model_len;
         let mut idx = 0;
         for e in &self.entries {
-            seq_id_to_idx.insert(e.seq_id, idx);
             let query = &e.query_pos_token;
             let off = e.kv_slots.len() - query.len();
+            if let Some(lora) = e.lora {
+                let start = tokens.len() as i64;
+                let end = start + query.len() as i64;
+                match lora_rows.iter_mut().find(|(l, _)| *l == lora) {
+                    Some((_, rows)) => rows.extend(start..end),
+                    None => lora_rows.push((lora, (start..end).collect())),
+                }
+            }
             for (qidx, (tpos, token)) in query.iter().enumerate() {
-                assert!(*tpos < max_seq);
+                // the scheduler finishes sequences that grow too long (FinishReason::LengthError)
+                assert!(*tpos < max_seq, "position {} past max_model_len", tpos);
                 positions.push(*tpos as i64);
                 tokens.push(*token as i32);
                 slot_mapping.push(e.kv_slots[off + qidx] as i32);
             }
-            logit_idxs.push((tokens.len() - 1) as i32);
+            for k in (1..=e.num_logits).rev() {
+                logit_idxs.push((tokens.len() - k) as i32);
+            }
+            seq_id_to_idx.insert(e.seq_id, logit_idxs.len() - 1);
             if idx < num_multitoken {
                 for slot in e.kv_slots.iter() {
                     gather_mapping.push(*slot as i32);
                 }
                 first_single_token = tokens.len();
+                if !e.masked.is_empty() {
+                    attn_masks.push((seqlens_q.len(), e.masked.clone()));
+                }
                 seqlens_q.push(query.len());
                 seqlens_k.push(e.kv_slots.len());
             } else {
@@ -261,35 +470,57 @@ impl BatchInfoBuilder {
 
         assert!(seqlens_q.len() + paged_context_lens.len() > 0);
 
+        HostBatch {
+            positions,
+            tokens,
+            logit_idxs,
+            seqlens_q,
+            seqlens_k,
+            gather_mapping,
+            slot_mapping,
+            seq_id_to_idx,
+            paged_block_tables,
+            paged_context_lens,
+            num_multitoken,
+            first_single_token,
+            lora_rows,
+            attn_masks,
+        }
+    }
+
+    pub fn finish(&mut self, step_no: usize, kv_cache: Box<dyn CacheIface>) -> BatchInfo {
+        let b = self.host_batch();
+
         let device = self.config.model.device;
-        let (max_seqlen_q, seqlens_q) = to_offsets(seqlens_q.into_iter(), device);
-        let (max_seqlen_k, seqlens_k) = to_offsets(seqlens_k.into_iter(), device);
+        let (max_seqlen_q, seqlens_q) = to_offsets(b.seqlens_q.into_iter(), device);
+        let (max_seqlen_k, seqlens_k) = to_offsets(b.seqlens_k.into_iter(), device);
 
         // TODO positions, tokens should be padded to 8? see worker.py, search for multiple_of=8
-        let positions = Tensor::from_slice(positions.as_slice()).to(device);
-        let tokens = Tensor::from_slice(tokens.as_slice()).to(device);
-        let slot_mapping = Tensor::from_slice(slot_mapping.as_slice()).to(device);
-        let gather_mapping = Tensor::from_slice(gather_mapping.as_slice()).to(device);
-        let logit_idxs = Tensor::from_slice(logit_idxs.as_slice()).to(device);
-
-        let num_paged = paged_context_lens.len() as i64;
-        let paged_max_context_len = *paged_context_lens.iter().max().unwrap_or(&0) as usize;
-        let paged_block_tables_max_len = paged_block_tables
+        let positions = Tensor::from_slice(b.positions.as_slice()).to(device);
+        let tokens = Tensor::from_slice(b.tokens.as_slice()).to(device);
+        let slot_mapping = Tensor::from_slice(b.slot_mapping.as_slice()).to(device);
+        let gather_mapping = Tensor::from_slice(b.gather_mapping.as_slice()).to(device);
+        let logit_idxs = Tensor::from_slice(b.logit_idxs.as_slice()).to(device);
+
+        let num_paged = b.paged_context_lens.len() as i64;
+        let paged_max_context_len = *b.paged_context_lens.iter().max().unwrap_or(&0) as usize;
+        let paged_block_tables_max_len = b
+            .paged_block_tables
             .iter()
             .map(|v| v.len())
             .max()
             .unwrap_or(0);
-        let flat_block_tables = paged_block_tables
-            .into_iter()
-            .flat_map(|mut v| {
-                pad_to_multiple(&mut v, paged_block_tables_max_len);
-                v.into_iter()
-            })
-            .collect::<Vec<_>>();
+        let flat_block_tables =
+            flatten_block_tables(b.paged_block_tables, paged_block_tables_max_len);
         let paged_block_tables = Tensor::from_slice(&flat_block_tables)
             .to(device)
             .reshape(&[num_paged, paged_block_tables_max_len as i64]);
-        let paged_context_lens = Tensor::from_slice(paged_context_lens.as_slice()).to(device);
+        let paged_context_lens = Tensor::from_slice(b.paged_context_lens.as_slice()).to(device);
+        let lora_rows = b
+            .lora_rows
+            .into_iter()
+            .map(|(lora, rows)| (lora, Tensor::from_slice(&rows).to(device)))
+            .collect();
 
         BatchInfo {
             tokens,
@@ -299,29 +530,255 @@ impl BatchInfoBuilder {
             logit_idxs,
             slot_mapping,
             gather_mapping,
-            seqlen_multi: num_multitoken as i64,
-            q_multi: first_single_token as i64,
+            seqlen_multi: b.num_multitoken as i64,
+            q_multi: b.first_single_token as i64,
             max_seqlen_q,
             max_seqlen_k,
             kv_cache,
-            seq_id_to_idx,
+            seq_id_to_idx: b.seq_id_to_idx,
             infer_log: Mutex::new(Vec::new()),
             step_no,
             paged_block_size: self.config.model.cache.block_size,
             paged_max_context_len,
             paged_block_tables,
             paged_context_lens,
+            lora_rows,
+            attn_masks: b.attn_masks,
+        }
+    }
+
+    /// Like finish(), but the returned BatchInfo uses the buffers in `inputs`,
+    /// after copying the batch there.
+    /// paged_batch_size() has to be `inputs.batch_size`.
+    pub fn finish_into(
+        &mut self,
+        step_no: usize,
+        kv_cache: Box<dyn CacheIface>,
+        inputs: &mut StaticBatchInputs,
+    ) -> BatchInfo {
+        assert!(self.paged_batch_size() == Some(inputs.batch_size));
+        let b = self.host_batch();
+        assert!(b.num_multitoken == 0);
+        assert!(b.lora_rows.is_empty());
+
+        let max_blocks = inputs.paged_block_tables.size()[1] as usize;
+        let flat_block_tables = flatten_block_tables(b.paged_block_tables, max_blocks);
+        inputs.tokens.copy_(&Tensor::from_slice(&b.tokens));
+        inputs.positions.copy_(&Tensor::from_slice(&b.positions));
+        inputs
+            .slot_mapping
+            .copy_(&Tensor::from_slice(&b.slot_mapping));
+        inputs.logit_idxs.copy_(&Tensor::from_slice(&b.logit_idxs));
+        inputs
+            .paged_block_tables
+            .copy_(&Tensor::from_slice(&flat_block_tables).reshape(&[-1, max_blocks as i64]));
+        inputs
+            .paged_context_lens
+            .copy_(&Tensor::from_slice(&b.paged_context_lens));
+
+        BatchInfo {
+            tokens: inputs.tokens.shallow_clone(),
+            positions: inputs.positions.shallow_clone(),
+            seqlens_q: inputs.seqlens.shallow_clone(),
+            seqlens_k: inputs.seqlens.shallow_clone(),
+            logit_idxs: inputs.logit_idxs.shallow_clone(),
+            slot_mapping: inputs.slot_mapping.shallow_clone(),
+            gather_mapping: inputs.gather_mapping.shallow_clone(),
+            seqlen_multi: 0,
+            q_multi: 0,
+            max_seqlen_q: 0,
+            max_seqlen_k: 0,
+            kv_cache,
+            seq_id_to_idx: b.seq_id_to_idx,
+            infer_log: Mutex::new(Vec::new()),
+            step_no,
+            paged_block_size: self.config.model.cache.block_size,
+            // the kernel only uses this for sizing shared memory, so the results
+            // are the same as with the actual maximum
+            paged_max_context_len: self.config.scheduler.max_model_len,
+            paged_block_tables: inputs.paged_block_tables.shallow_clone(),
+            paged_context_lens: inputs.paged_context_lens.shallow_clone(),
+            lora_rows: Vec::new(),
+            attn_masks: Vec::new(),
         }
     }
 }
 
+fn flatten_block_tables(tables: Vec<Vec<i32>>, max_len: usize) -> Vec<i32> {
+    tables
+        .into_iter()
+        .flat_map(|mut v| {
+            pad_to_multiple(&mut v, max_len);
+            v.into_iter()
+        })
+        .collect()
+}
+
 struct FakeKVCache {
-    k: Tensor,
-    v: Tensor,
+    // one single-block layer per pipeline stage
+    kv: Vec<(Tensor, Tensor)>,
+    layers_per_stage: usize,
 }
 
 impl CacheIface for FakeKVCache {
-    fn get(&self, _layer_no: usize) -> (Tensor, Tensor) {
-        (self.k.shallow_clone(), self.v.shallow_clone())
+    fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
+        let (k, v) = &self.kv[layer_no / self.layers_per_stage];
+        (k.shallow_clone(), v.shallow_clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{
+        config::{CommonModelConfig, RllmModelConfig},
+        llama::{Llama, LlamaConfig},
+        tmodel::TModelInner,
+        DType,
+    };
+    use rllm::config::{AiciConfig, ModelMeta, ParallelConfig, PreemptionMode, SchedulerConfig};
+    use std::rc::Rc;
+    use tch::nn::VarStore;
+
+    const VOCAB_SIZE: usize = 50;
+
+    /// A 2-layer Llama with grouped-query attention, on CPU.
+    fn tiny_config() -> RllmConfig<TModel> {
+        let llama: LlamaConfig = serde_json::from_value(serde_json::json!({
+            "hidden_size": 32,
+            "intermediate_size": 64,
+            "vocab_size": VOCAB_SIZE,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 64,
+            "torch_dtype": "float",
+        }))
+        .unwrap();
+        let common = CommonModelConfig {
+            meta: ModelMeta {
+                id: "tiny".to_string(),
+                max_sequence_length: 0,
+                vocab_size: 0,
+                tok_vocab_size: 0,
+            },
+            device: Device::Cpu,
+            dtype: Some(DType::Float),
+        };
+        let mut model = llama.into_config(common).unwrap();
+        // as set by the loader on CPU
+        model.cache.paged_attn_kernel_v = 0;
+        model.cache.dtype = model.dtype;
+        let len = model.meta.max_sequence_length;
+        RllmConfig {
+            meta: model.meta.clone(),
+            model,
+            parallel: ParallelConfig {
+                pipeline_parallel_size: 1,
+                tensor_parallel_size: 1,
+            },
+            scheduler: SchedulerConfig {
+                max_num_batched_tokens: len,
+                max_num_kv_tokens: len,
+                max_num_seqs: 1,
+                max_model_len: len,
+                priority_boost_ms: 0,
+                max_prefill_tokens: len,
+                max_prefill_tokens_per_step: len,
+                preemption_mode: PreemptionMode::Recompute,
+                recompute_max_len: len,
+                gpu_watermark: 0.0,
+                admission_lookahead: 0,
+                livelock_steps: 100,
+                max_waiting_groups: 1,
+                max_queued_tokens: len,
+            },
+            aici: AiciConfig::default(),
+        }
+    }
+
+    /// Random weights, with the norms at 1 (they start at 0).
+    fn tiny_llama(config: &RllmConfig<TModel>) -> Llama {
+        tch::manual_seed(42);
+        let vs = VarStore::new(Device::Cpu);
+        let model = Llama::load(&[vs.root()], &Rc::new(config.model.clone())).unwrap();
+        for (name, mut var) in vs.variables() {
+            if name.ends_with("norm.weight") {
+                let _ = var.fill_(1.0);
+            }
+        }
+        model
+    }
+
+    /// One KV cache per layer, with slot i holding position i of the only sequence.
+    struct TestCache(Vec<(Tensor, Tensor)>);
+
+    impl CacheIface for TestCache {
+        fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
+            let (k, v) = &self.0[layer_no];
+            (k.shallow_clone(), v.shallow_clone())
+        }
+    }
+
+    fn new_cache(config: &RllmConfig<TModel>) -> Vec<(Tensor, Tensor)> {
+        let num_blocks = config.scheduler.max_model_len / config.model.cache.block_size;
+        (0..config.model.num_hidden_layers)
+            .map(|_| CacheEngine::alloc_gpu_cache_layer(config, num_blocks as i64, Device::Cpu))
+            .collect()
+    }
+
+    /// Logits after `tokens`, computing the ones from `start` on, with the KV of
+    /// the previous ones in `cache`.
+    fn forward(
+        config: &Arc<RllmConfig<TModel>>,
+        model: &Llama,
+        cache: &[(Tensor, Tensor)],
+        tokens: &[Token],
+        start: usize,
+    ) -> Tensor {
+        let mut builder = BatchInfoBuilder::new(config.clone());
+        builder.entries.push(BatchEntry {
+            seq_id: 1,
+            query_pos_token: (start..tokens.len()).map(|i| (i, tokens[i])).collect(),
+            kv_slots: (0..tokens.len()).collect(),
+            windowed: false,
+            lora: None,
+            num_logits: 1,
+            masked: Vec::new(),
+        });
+        let kv_cache = cache
+            .iter()
+            .map(|(k, v)| (k.shallow_clone(), v.shallow_clone()))
+            .collect();
+        let mut info = builder.finish(0, Box::new(TestCache(kv_cache)));
+        model.forward(&mut info)
+    }
+
+    fn greedy(logits: &Tensor) -> Token {
+        logits.argmax(-1, false).int64_value(&[0]) as Token
+    }
+
+    #[test]
+    fn tiny_llama_generates_on_cpu() {
+        let _no_grad = tch::no_grad_guard();
+        let config = Arc::new(tiny_config());
+        let model = tiny_llama(&config);
+        let cache = new_cache(&config);
+
+        // greedy decoding, one token per step after the prompt, reading the KV cache
+        let mut tokens: Vec<Token> = vec![1, 7, 3, 12, 5];
+        let mut logits = forward(&config, &model, &cache, &tokens, 0);
+        for _ in 0..5 {
+            assert_eq!(logits.size(), [1, VOCAB_SIZE as i64]);
+            tokens.push(greedy(&logits));
+            logits = forward(&config, &model, &cache, &tokens, tokens.len() - 1);
+        }
+        assert_eq!(tokens.len(), 10);
+        assert!(tokens.iter().all(|&t| (t as usize) < VOCAB_SIZE));
+
+        // the same logits without the cache
+        let full = forward(&config, &model, &new_cache(&config), &tokens, 0);
+        assert!(logits.allclose(&full, 1e-4, 1e-4, false));
     }
 }
@@ -2,11 +2,13 @@ use super::super::tmodel::TModel;
 use super::cache_engine::CacheEngine;
 use rllm::{
     config::RllmConfig,
-    seq::{SchedulingPhase, Sequence, SequenceGroup},
-    BlockLocation, CacheSize, HashMap, SchedulerOutputs, SeqId, SequenceManager,
-    TBlockSpaceManager,
+    seq::{SchedulingPhase, Sequence, SequenceGroup, Token},
+    BlockLocation, CachePoolUsage, CacheSize, HashMap, HashSet, SchedulerOutputs, SeqCacheUsage,
+    SeqId, SequenceManager, TBlockSpaceManager,
 };
 use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::Hasher,
     sync::{Arc, Mutex},
     vec::Vec,
 };
@@ -14,30 +16,92 @@ use std::{
 /// Represents the state of a block in the KV cache.
 #[derive(Debug)]
 pub struct PhysicalTokenBlock {
-    ref_count: usize,
+    // sequences using the block (the reference count is their number);
+    // the first one is charged for the block in cache reports
+    owners: Vec<SeqId>,
+    // hash of the prompt prefix up to and including this (full) block
+    prefix_hash: Option<u64>,
+    // tokens of the block and the block (with its generation) holding the
+    // preceding prefix; hashes can collide, so these are checked on lookup
+    tokens: Vec<Token>,
+    parent: Option<(usize, u64)>,
+    // bumped whenever the contents of the block change
+    generation: u64,
+    last_used: u64,
 }
 
 impl PhysicalTokenBlock {
     pub fn new(_device: BlockLocation, _block_number: usize, _block_size: usize) -> Self {
-        Self { ref_count: 0 }
+        Self {
+            owners: Vec::new(),
+            prefix_hash: None,
+            tokens: Vec::new(),
+            parent: None,
+            generation: 0,
+            last_used: 0,
+        }
+    }
+
+    fn ref_count(&self) -> usize {
+        self.owners.len()
     }
 }
 
+/// Blocks charged to a sequence (see PhysicalTokenBlock::owners).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct SeqUsage {
+    charged: usize,
+    // of the charged blocks, the ones with other owners too
+    charged_shared: usize,
+}
+
 /// Manages free physical token blocks for a device.
 ///
 /// The allocator maintains a list of free blocks and allocates a block when
 /// requested. When a block is freed, its reference count is decremented. If
 /// the reference count becomes zero, the block is added back to the free list.
-
+///
+/// Full blocks of prompt tokens are additionally indexed by the hash of the
+/// prefix they end. A hit is only taken if the block holds the same tokens,
+/// and follows the block matched for the preceding prefix. Once free, such blocks are kept around (and can be
+/// picked up by a new sequence with the same prefix) until the free list
+/// runs out, at which point they are evicted in LRU order.
+///
+/// Blocks being swapped out are still read by the copy to the CPU, which
+/// runs concurrently with the model; they are freed only in end_swap_out().
+///
+/// The numbers of used and shared blocks, and the usage of each sequence, are kept
+/// up to date on every reference change, for cache reports; check_counters()
+/// recomputes them from scratch.
 struct Allocator {
     free_list: Vec<usize>,
     all_blocks: Vec<PhysicalTokenBlock>,
     block_size: usize,
+    prefix_cache: HashMap<u64, usize>,
+    // free blocks still present in prefix_cache, keyed by last_used
+    evictable: BTreeMap<u64, usize>,
+    blocks_pending_swap_out: HashSet<usize>,
+    lru_tick: u64,
+    prefix_hits: usize,
+    prefix_misses: usize,
+    num_used: usize,
+    num_shared: usize,
+    seq_usage: HashMap<SeqId, SeqUsage>,
 }
 
 struct BlockAllocatorInner {
     alloc: Allocator,
-    seq_blocks: HashMap<SeqId, Vec<BlockRef>>,
+    seq_blocks: HashMap<SeqId, SeqBlocks>,
+    sliding_window: Option<usize>,
+}
+
+/// Block table of a sequence. With sliding-window attention, leading blocks
+/// that are entirely out of the window are freed.
+struct SeqBlocks {
+    num_dropped: usize,
+    blocks: Vec<BlockRef>,
+    // tokens with KV computed in the cache of the draft model, which uses the same slots
+    draft_kv: usize,
 }
 
 #[derive(Clone)]
@@ -54,38 +118,177 @@ impl Allocator {
         (length + self.block_size - 1) / self.block_size
     }
 
-    fn free(&mut self, block: BlockRef) {
-        let blk = &mut self.all_blocks[block.block_idx];
-        assert!(blk.ref_count > 0);
-        blk.ref_count -= 1;
-        if blk.ref_count == 0 {
-            self.free_list.push(block.block_idx);
+    fn num_free(&self) -> usize {
+        self.free_list.len() + self.evictable.len()
+    }
+
+    fn is_cached(&self, block_idx: usize) -> bool {
+        match self.all_blocks[block_idx].prefix_hash {
+            Some(h) => self.prefix_cache.get(&h) == Some(&block_idx),
+            None => false,
         }
     }
 
-    fn fork(&mut self, block: &BlockRef) -> BlockRef {
-        let blk = &mut self.all_blocks[block.block_idx];
-        assert!(blk.ref_count > 0);
-        blk.ref_count += 1;
+    // charge the block to its first owner (or take the charge back)
+    fn charge(&mut self, block_idx: usize, add: bool) {
+        let owners = &self.all_blocks[block_idx].owners;
+        let seq = match owners.first() {
+            Some(&s) => s,
+            None => return,
+        };
+        let shared = owners.len() > 1;
+        let usage = self.seq_usage.entry(seq).or_default();
+        if add {
+            usage.charged += 1;
+            usage.charged_shared += shared as usize;
+        } else {
+            usage.charged -= 1;
+            usage.charged_shared -= shared as usize;
+            if usage.charged == 0 {
+                self.seq_usage.remove(&seq);
+            }
+        }
+    }
+
+    fn add_owner(&mut self, block_idx: usize, seq: SeqId) {
+        self.charge(block_idx, false);
+        self.all_blocks[block_idx].owners.push(seq);
+        self.charge(block_idx, true);
+        match self.all_blocks[block_idx].ref_count() {
+            1 => self.num_used += 1,
+            2 => self.num_shared += 1,
+            _ => {}
+        }
+    }
+
+    fn remove_owner(&mut self, block_idx: usize, seq: SeqId) {
+        self.charge(block_idx, false);
+        let owners = &mut self.all_blocks[block_idx].owners;
+        let pos = owners
+            .iter()
+            .position(|s| *s == seq)
+            .expect("sequence doesn't own the block");
+        owners.remove(pos);
+        match owners.len() {
+            0 => self.num_used -= 1,
+            1 => self.num_shared -= 1,
+            _ => {}
+        }
+        self.charge(block_idx, true);
+    }
+
+    fn free(&mut self, block: BlockRef, seq: SeqId) {
+        assert!(self.all_blocks[block.block_idx].ref_count() > 0);
+        self.remove_owner(block.block_idx, seq);
+        if self.all_blocks[block.block_idx].ref_count() == 0 {
+            if self.blocks_pending_swap_out.contains(&block.block_idx) {
+                // kept out of both lists until end_swap_out()
+                self.forget_hash(block.block_idx);
+            } else if self.is_cached(block.block_idx) {
+                self.lru_tick += 1;
+                let blk = &mut self.all_blocks[block.block_idx];
+                blk.last_used = self.lru_tick;
+                self.evictable.insert(blk.last_used, block.block_idx);
+            } else {
+                self.free_list.push(block.block_idx);
+            }
+        }
+    }
+
+    /// Drop the block from the prefix cache; needed before its contents change.
+    fn forget_hash(&mut self, block_idx: usize) {
+        let blk = &mut self.all_blocks[block_idx];
+        blk.generation += 1;
+        blk.tokens.clear();
+        blk.parent = None;
+        if let Some(h) = blk.prefix_hash.take() {
+            if self.prefix_cache.get(&h) == Some(&block_idx) {
+                self.prefix_cache.remove(&h);
+            }
+        }
+    }
+
+    /// Identifies the current contents of the block, see PhysicalTokenBlock::parent.
+    fn block_version(&self, block_idx: usize) -> (usize, u64) {
+        (block_idx, self.all_blocks[block_idx].generation)
+    }
+
+    fn register_hash(
+        &mut self,
+        block_idx: usize,
+        hash: u64,
+        tokens: Vec<Token>,
+        parent: Option<usize>,
+    ) {
+        let parent = parent.map(|p| self.block_version(p));
+        let blk = &mut self.all_blocks[block_idx];
+        assert!(blk.prefix_hash.is_none());
+        blk.prefix_hash = Some(hash);
+        blk.tokens = tokens;
+        blk.parent = parent;
+        // if another block already holds the same prefix, keep that one
+        self.prefix_cache.entry(hash).or_insert(block_idx);
+    }
+
+    fn lookup_prefix(
+        &mut self,
+        hash: u64,
+        tokens: &[Token],
+        parent: Option<usize>,
+        seq: SeqId,
+    ) -> Option<BlockRef> {
+        let block_idx = *self.prefix_cache.get(&hash)?;
+        let parent = parent.map(|p| self.block_version(p));
+        let blk = &self.all_blocks[block_idx];
+        if blk.tokens != tokens || blk.parent != parent {
+            // hash collision
+            return None;
+        }
+        if blk.ref_count() == 0 {
+            self.evictable.remove(&blk.last_used);
+        }
+        self.add_owner(block_idx, seq);
+        Some(BlockRef { block_idx })
+    }
+
+    fn fork(&mut self, block: &BlockRef, seq: SeqId) -> BlockRef {
+        assert!(self.all_blocks[block.block_idx].ref_count() > 0);
+        self.add_owner(block.block_idx, seq);
         BlockRef {
             block_idx: block.block_idx,
         }
     }
 
-    fn allocate(&mut self) -> BlockRef {
-        let block_idx = self
-            .free_list
-            .pop()
-            .expect("Out of memory! No free blocks are available.");
-        assert!(self.all_blocks[block_idx].ref_count == 0);
-        self.all_blocks[block_idx].ref_count += 1;
+    fn allocate(&mut self, seq: SeqId) -> BlockRef {
+        let block_idx = match self.free_list.pop() {
+            Some(idx) => idx,
+            None => {
+                let (_, idx) = self
+                    .evictable
+                    .pop_first()
+                    .expect("Out of memory! No free blocks are available.");
+                idx
+            }
+        };
+        self.forget_hash(block_idx);
+        assert!(self.all_blocks[block_idx].ref_count() == 0);
+        self.add_owner(block_idx, seq);
         BlockRef { block_idx }
     }
 
     fn is_singular(&self, block: &BlockRef) -> bool {
         let blk = &self.all_blocks[block.block_idx];
-        assert!(blk.ref_count > 0);
-        blk.ref_count == 1
+        assert!(blk.ref_count() > 0);
+        blk.ref_count() == 1
+    }
+
+    fn pool_usage(&self) -> CachePoolUsage {
+        CachePoolUsage {
+            num_blocks: self.all_blocks.len(),
+            free_blocks: self.num_free(),
+            used_blocks: self.num_used,
+            shared_blocks: self.num_shared,
+        }
     }
 }
 
@@ -95,40 +298,182 @@ impl BlockAllocatorInner {
         let seq_blocks = &mut self.seq_blocks;
         match seq_blocks.get(&src) {
             Some(v) => {
+                let draft_kv = std::cmp::min(v.draft_kv, length);
                 let length = alloc.num_blocks(length);
-                let mut new_v = Vec::with_capacity(std::cmp::min(length, v.len()));
-                for e in v.iter().take(length) {
-                    new_v.push(alloc.fork(e));
+                let num_dropped = std::cmp::min(v.num_dropped, length);
+                let mut new_v = Vec::with_capacity(length - num_dropped);
+                for e in v.blocks.iter().take(length - num_dropped) {
+                    new_v.push(alloc.fork(e, dst));
                 }
-                seq_blocks.insert(dst, new_v);
+                seq_blocks.insert(
+                    dst,
+                    SeqBlocks {
+                        num_dropped,
+                        blocks: new_v,
+                        draft_kv,
+                    },
+                );
             }
             None => {}
         }
     }
 
-    fn trim(&mut self, seq: SeqId, length: usize) {
+    /// Returns the number of tokens with KV still available, which is less than
+    /// `length` only if we need KV that already fell out of the sliding window.
+    fn trim(&mut self, seq: SeqId, length: usize) -> usize {
         let alloc = &mut self.alloc;
-        let length = alloc.num_blocks(length);
-        self.seq_blocks.get_mut(&seq).map(|v| {
-            for e in v.drain(length..) {
-                alloc.free(e)
-            }
-        });
-        if length == 0 {
+        let v = match self.seq_blocks.get_mut(&seq) {
+            Some(v) => v,
+            None => return length,
+        };
+
+        let needed_from = match self.sliding_window {
+            Some(window) => length.saturating_sub(window),
+            None => 0,
+        };
+        let lost = length > 0 && v.num_dropped * alloc.block_size > needed_from;
+        let length = if lost {
+            log::warn!("seq {seq}: backtracked out of the sliding window; recomputing KV");
+            // keep the (empty) entry, so append_slots() allocates everything again
+            v.num_dropped = 0;
+            0
+        } else {
+            length
+        };
+        v.draft_kv = std::cmp::min(v.draft_kv, length);
+
+        let keep = alloc.num_blocks(length).saturating_sub(v.num_dropped);
+        for e in v.blocks.drain(std::cmp::min(keep, v.blocks.len())..) {
+            alloc.free(e, seq)
+        }
+        if length == 0 && !lost {
             self.seq_blocks.remove(&seq);
         }
+        length
+    }
+
+    /// Free blocks that the next query (at position `next_pos`) can no longer attend to.
+    fn drop_out_of_window(&mut self, seq: SeqId, seq_blocks: &mut SeqBlocks, next_pos: usize) {
+        let window = match self.sliding_window {
+            Some(w) => w,
+            None => return,
+        };
+        let num_out = next_pos.saturating_sub(window) / self.alloc.block_size;
+        if num_out > seq_blocks.num_dropped {
+            for e in seq_blocks.blocks.drain(0..num_out - seq_blocks.num_dropped) {
+                self.alloc.free(e, seq);
+            }
+            seq_blocks.num_dropped = num_out;
+        }
+    }
+
+    fn block_tokens(&self, seq: &Sequence, block_no: usize) -> Vec<Token> {
+        let block_size = self.alloc.block_size;
+        (block_no * block_size..(block_no + 1) * block_size)
+            .map(|idx| seq.get_token(idx))
+            .collect()
+    }
+
+    fn block_hash(&self, parent: u64, tokens: &[Token]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(parent);
+        for &t in tokens {
+            hasher.write_u32(t);
+        }
+        hasher.finish()
+    }
+
+    /// Index full prompt blocks, which already have their KV computed.
+    fn register_prefix(&mut self, seq: &Sequence, block_table: &SeqBlocks) {
+        if block_table.num_dropped > 0 {
+            // prefix hashes were computed before the blocks were dropped
+            return;
+        }
+        let block_table = &block_table.blocks;
+        let num_full = std::cmp::min(seq.num_kv_computed, seq.prompt_len) / self.alloc.block_size;
+        let mut parent = 0;
+        let mut parent_block = None;
+        for (block_no, blk) in block_table.iter().take(num_full).enumerate() {
+            let prefix_hash = self.alloc.all_blocks[blk.block_idx].prefix_hash;
+            parent = match prefix_hash {
+                Some(h) => h,
+                None => {
+                    let tokens = self.block_tokens(seq, block_no);
+                    let h = self.block_hash(parent, &tokens);
+                    self.alloc
+                        .register_hash(blk.block_idx, h, tokens, parent_block);
+                    h
+                }
+            };
+            parent_block = Some(blk.block_idx);
+        }
+    }
+
+    /// Recompute the block counters from all blocks and block tables, and check them
+    /// against the incrementally maintained ones.
+    fn check_counters(&self) {
+        let alloc = &self.alloc;
+        let mut seq_usage: HashMap<SeqId, SeqUsage> = HashMap::default();
+        let mut num_used = 0;
+        let mut num_shared = 0;
+        let mut num_pending = 0;
+        for (idx, blk) in alloc.all_blocks.iter().enumerate() {
+            let charged = match blk.owners.first() {
+                Some(&s) => s,
+                None => {
+                    if alloc.blocks_pending_swap_out.contains(&idx) {
+                        num_pending += 1;
+                    }
+                    continue;
+                }
+            };
+            num_used += 1;
+            let usage = seq_usage.entry(charged).or_default();
+            usage.charged += 1;
+            if blk.owners.len() > 1 {
+                num_shared += 1;
+                usage.charged_shared += 1;
+            }
+            for seq in blk.owners.iter() {
+                let in_table = self
+                    .seq_blocks
+                    .get(seq)
+                    .is_some_and(|v| v.blocks.iter().any(|b| b.block_idx == idx));
+                assert!(in_table, "block {idx} owned by {seq}, but not in its table");
+            }
+        }
+        let num_refs: usize = self.seq_blocks.values().map(|v| v.blocks.len()).sum();
+        let num_owners: usize = alloc.all_blocks.iter().map(|b| b.ref_count()).sum();
+        assert_eq!(num_refs, num_owners, "block tables vs. owners");
+        assert_eq!(num_used, alloc.num_used, "used blocks");
+        assert_eq!(num_shared, alloc.num_shared, "shared blocks");
+        assert!(seq_usage == alloc.seq_usage, "usage of sequences");
+        assert_eq!(
+            alloc.num_free() + num_used + num_pending,
+            alloc.all_blocks.len(),
+            "free + used + pending blocks"
+        );
     }
 
     fn get_block_idx(&self, seq: SeqId, position: usize) -> usize {
-        let blocks = self.seq_blocks.get(&seq).unwrap();
+        let v = self.seq_blocks.get(&seq).unwrap();
         let block_size = self.alloc.block_size;
         let block_offset = position % block_size;
-        blocks[position / block_size].block_idx * block_size + block_offset
+        assert!(
+            position / block_size >= v.num_dropped,
+            "KV out of the window"
+        );
+        v.blocks[position / block_size - v.num_dropped].block_idx * block_size + block_offset
     }
 }
 
 impl BlockAllocator {
-    fn new(device: BlockLocation, block_size: usize, num_blocks: usize) -> Self {
+    fn new(
+        device: BlockLocation,
+        block_size: usize,
+        num_blocks: usize,
+        sliding_window: Option<usize>,
+    ) -> Self {
         let all_blocks = (0..num_blocks)
             .map(|i| PhysicalTokenBlock::new(device, i, block_size))
             .collect();
@@ -137,21 +482,67 @@ impl BlockAllocator {
                 all_blocks,
                 free_list: (0..num_blocks).rev().collect(),
                 block_size,
+                prefix_cache: HashMap::default(),
+                evictable: BTreeMap::new(),
+                blocks_pending_swap_out: HashSet::default(),
+                lru_tick: 0,
+                prefix_hits: 0,
+                prefix_misses: 0,
+                num_used: 0,
+                num_shared: 0,
+                seq_usage: HashMap::default(),
             },
             seq_blocks: HashMap::default(),
+            sliding_window,
         };
         Self {
             inner: Arc::new(Mutex::new(inner)),
         }
     }
 
+    fn get_num_blocks(&self) -> usize {
+        self.inner.lock().unwrap().alloc.all_blocks.len()
+    }
+
     fn get_num_free_blocks(&self) -> usize {
-        self.inner.lock().unwrap().alloc.free_list.len()
+        self.inner.lock().unwrap().alloc.num_free()
     }
 
-    pub fn get_block_idxes(&self, seq: SeqId, len: usize) -> Vec<usize> {
+    fn get_prefix_cache_stats(&self) -> (usize, usize) {
         let l = self.inner.lock().unwrap();
-        (0..len).map(|k| l.get_block_idx(seq, k)).collect()
+        (l.alloc.prefix_hits, l.alloc.prefix_misses)
+    }
+
+    fn pool_usage(&self) -> CachePoolUsage {
+        self.inner.lock().unwrap().alloc.pool_usage()
+    }
+
+    fn seq_usage(&self, seq: SeqId) -> SeqUsage {
+        let l = self.inner.lock().unwrap();
+        l.alloc.seq_usage.get(&seq).copied().unwrap_or_default()
+    }
+
+    fn check_counters(&self) {
+        self.inner.lock().unwrap().check_counters()
+    }
+
+    /// Slots of KV for positions `start..end` of the sequence.
+    pub fn get_block_idxes(&self, seq: SeqId, start: usize, end: usize) -> Vec<usize> {
+        let l = self.inner.lock().unwrap();
+        (start..end).map(|k| l.get_block_idx(seq, k)).collect()
+    }
+
+    /// Tokens of the sequence with KV in the cache of the draft model; 0 after a swap.
+    pub(crate) fn draft_kv_len(&self, seq: SeqId) -> usize {
+        let l = self.inner.lock().unwrap();
+        l.seq_blocks.get(&seq).map_or(0, |v| v.draft_kv)
+    }
+
+    pub(crate) fn set_draft_kv_len(&self, seq: SeqId, len: usize) {
+        let mut l = self.inner.lock().unwrap();
+        if let Some(v) = l.seq_blocks.get_mut(&seq) {
+            v.draft_kv = len;
+        }
     }
 
     fn num_needed_blocks(&self, seq: &Sequence) -> usize {
@@ -159,87 +550,185 @@ impl BlockAllocator {
         l.alloc.num_blocks(seq.get_len())
     }
 
+    /// Blocks to allocate on top of the current ones, so that the sequence
+    /// can hold `num_tokens` tokens more than it has now.
+    fn num_blocks_to_grow(&self, seq: &Sequence, num_tokens: usize) -> usize {
+        let l = self.inner.lock().unwrap();
+        let allocated = l
+            .seq_blocks
+            .get(&seq.seq_id)
+            .map(|v| v.num_dropped + v.blocks.len())
+            .unwrap_or(0);
+        l.alloc
+            .num_blocks(seq.get_len() + num_tokens)
+            .saturating_sub(allocated)
+    }
+
     fn num_allocated_blocks(&self, seq: &Sequence) -> usize {
         let l = self.inner.lock().unwrap();
-        l.seq_blocks.get(&seq.seq_id).map(|v| v.len()).unwrap_or(0)
+        l.seq_blocks
+            .get(&seq.seq_id)
+            .map(|v| v.blocks.len())
+            .unwrap_or(0)
     }
 
-    fn alloc_seq(&self, seq: &Sequence) {
+    /// Allocate blocks for the prompt, reusing cached prefix blocks where possible.
+    /// Returns the number of tokens that already have KV computed.
+    fn alloc_seq(&self, seq: &Sequence) -> usize {
         assert!(self.num_allocated_blocks(seq) == 0);
         let mut l = self.inner.lock().unwrap();
+        let block_size = l.alloc.block_size;
         let num_bl = l.alloc.num_blocks(seq.get_len());
         let mut v = Vec::with_capacity(num_bl);
-        for _ in 0..num_bl {
-            v.push(l.alloc.allocate())
+
+        // the last block is never shared, even if full - we need to compute
+        // at least one token to get the logits
+        let max_cached = seq.get_len().saturating_sub(1) / block_size;
+        let mut parent = 0;
+        for block_no in 0..max_cached {
+            let tokens = l.block_tokens(seq, block_no);
+            let h = l.block_hash(parent, &tokens);
+            let parent_block = v.last().map(|b: &BlockRef| b.block_idx);
+            match l.alloc.lookup_prefix(h, &tokens, parent_block, seq.seq_id) {
+                Some(b) => v.push(b),
+                None => break,
+            }
+            parent = h;
+        }
+        let num_cached = v.len();
+        l.alloc.prefix_hits += num_cached;
+        l.alloc.prefix_misses += max_cached - num_cached;
+
+        for _ in num_cached..num_bl {
+            v.push(l.alloc.allocate(seq.seq_id))
         }
-        l.seq_blocks.insert(seq.seq_id, v);
+        l.seq_blocks.insert(
+            seq.seq_id,
+            SeqBlocks {
+                num_dropped: 0,
+                blocks: v,
+                draft_kv: 0,
+            },
+        );
+        num_cached * block_size
     }
 
-    fn swap_out(&self, seq: &Sequence) -> Vec<usize> {
+    /// Returns the number of dropped leading blocks, and the remaining blocks.
+    fn swap_out(&self, seq: &Sequence) -> (usize, Vec<usize>) {
         let r = {
             let l = self.inner.lock().unwrap();
-            l.seq_blocks
-                .get(&seq.seq_id)
-                .unwrap_or(&Vec::new())
-                .iter()
-                .map(|b| b.block_idx)
-                .collect()
+            match l.seq_blocks.get(&seq.seq_id) {
+                Some(v) => (
+                    v.num_dropped,
+                    v.blocks.iter().map(|b| b.block_idx).collect(),
+                ),
+                None => (0, Vec::new()),
+            }
         };
         self.trim(seq.seq_id, 0);
         r
     }
 
-    fn swap_in(&self, seq: &Sequence, block_idxs: Vec<usize>, mapping: &mut HashMap<usize, usize>) {
+    /// Like swap_out(), but the blocks are not reused until end_swap_out(),
+    /// as the copy out of them may still be running.
+    fn begin_swap_out(&self, seq: &Sequence) -> (usize, Vec<usize>) {
+        {
+            let mut l = self.inner.lock().unwrap();
+            if let Some(v) = l.seq_blocks.get(&seq.seq_id) {
+                let idxs: Vec<usize> = v.blocks.iter().map(|b| b.block_idx).collect();
+                l.alloc.blocks_pending_swap_out.extend(idxs);
+            }
+        }
+        self.swap_out(seq)
+    }
+
+    /// The copy out of these blocks (see begin_swap_out()) has finished.
+    pub fn end_swap_out(&self, block_idxs: &[usize]) {
+        let mut l = self.inner.lock().unwrap();
+        for &idx in block_idxs {
+            if l.alloc.blocks_pending_swap_out.remove(&idx)
+                && l.alloc.all_blocks[idx].ref_count() == 0
+            {
+                l.alloc.free_list.push(idx);
+            }
+        }
+    }
+
+    fn swap_in(
+        &self,
+        seq: &Sequence,
+        (num_dropped, block_idxs): (usize, Vec<usize>),
+        mapping: &mut HashMap<usize, usize>,
+    ) {
         assert!(self.num_allocated_blocks(seq) == 0);
         let mut l = self.inner.lock().unwrap();
         let mut v = Vec::with_capacity(block_idxs.len());
         for bidx in block_idxs {
             match mapping.get(&bidx) {
                 Some(&new_bidx) => {
-                    v.push(l.alloc.fork(&BlockRef {
-                        block_idx: new_bidx,
-                    }));
+                    v.push(l.alloc.fork(
+                        &BlockRef {
+                            block_idx: new_bidx,
+                        },
+                        seq.seq_id,
+                    ));
                 }
                 None => {
-                    let b2 = l.alloc.allocate();
+                    let b2 = l.alloc.allocate(seq.seq_id);
                     mapping.insert(bidx, b2.block_idx);
                     v.push(b2);
                 }
             }
         }
-        l.seq_blocks.insert(seq.seq_id, v);
+        l.seq_blocks.insert(
+            seq.seq_id,
+            SeqBlocks {
+                num_dropped,
+                blocks: v,
+                draft_kv: 0,
+            },
+        );
     }
 
     fn append_slots(&self, seq: &Sequence, outputs: &mut SchedulerOutputs) {
         let mut l = self.inner.lock().unwrap();
         let block_size = l.alloc.block_size;
-        let mut block_table = l.seq_blocks.remove(&seq.seq_id).unwrap();
+        let mut seq_blocks = l.seq_blocks.remove(&seq.seq_id).unwrap();
 
-        assert!(block_table.len() > 0);
-        assert!(block_table.len() * block_size >= seq.num_kv_computed);
+        assert!(
+            (seq_blocks.num_dropped + seq_blocks.blocks.len()) * block_size >= seq.num_kv_computed
+        );
+
+        l.register_prefix(seq, &seq_blocks);
+        l.drop_out_of_window(seq.seq_id, &mut seq_blocks, seq.num_kv_computed);
 
+        let num_dropped = seq_blocks.num_dropped;
+        let block_table = &mut seq_blocks.blocks;
         let mut ptr = seq.num_kv_computed;
         while ptr < seq.get_len() {
-            let block_idx = ptr / block_size;
+            let block_idx = ptr / block_size - num_dropped;
             if block_idx < block_table.len() {
                 let curr_block = &mut block_table[block_idx];
-                if !l.alloc.is_singular(curr_block) {
-                    let new_block = l.alloc.allocate();
+                if l.alloc.is_singular(curr_block) {
+                    // about to be overwritten in place
+                    l.alloc.forget_hash(curr_block.block_idx);
+                } else {
+                    let new_block = l.alloc.allocate(seq.seq_id);
                     let old_block_number = curr_block.block_idx;
                     let new_block_number = new_block.block_idx;
                     let old_block = std::mem::replace(curr_block, new_block);
-                    l.alloc.free(old_block);
+                    l.alloc.free(old_block, seq.seq_id);
                     outputs.copy_block(old_block_number, new_block_number);
                 }
             } else {
                 assert!(block_table.len() == block_idx);
-                block_table.push(l.alloc.allocate());
+                block_table.push(l.alloc.allocate(seq.seq_id));
             }
-            ptr = (block_idx + 1) * block_size;
+            ptr = (num_dropped + block_idx + 1) * block_size;
         }
 
-        assert!(block_table.len() == l.alloc.num_blocks(seq.get_len()));
-        l.seq_blocks.insert(seq.seq_id, block_table);
+        assert!(num_dropped + block_table.len() == l.alloc.num_blocks(seq.get_len()));
+        l.seq_blocks.insert(seq.seq_id, seq_blocks);
     }
 
     fn copy(&self, src: SeqId, dst: SeqId, length: usize) {
@@ -247,8 +736,8 @@ impl BlockAllocator {
         self.inner.lock().unwrap().copy(src, dst, length)
     }
 
-    fn trim(&self, seq: SeqId, length: usize) {
-        self.inner.lock().unwrap().trim(seq, length);
+    fn trim(&self, seq: SeqId, length: usize) -> usize {
+        self.inner.lock().unwrap().trim(seq, length)
     }
 
     fn delete(&self, seq: SeqId) {
@@ -264,15 +753,16 @@ pub struct BlockSpaceManager {
 }
 
 impl TBlockSpaceManager<TModel> for BlockSpaceManager {
-    fn can_allocate(&self, seq_group: &SequenceGroup) -> bool {
+    fn can_allocate(&self, seq_group: &SequenceGroup, reserved_blocks: usize) -> bool {
         let num_required_blocks = self.gpu_allocator.num_needed_blocks(seq_group.only_seq());
-        self.can_alloc_gpu(num_required_blocks + self.watermark_blocks)
+        self.can_alloc_gpu(num_required_blocks + self.watermark_blocks + reserved_blocks)
     }
 
     fn allocate(&mut self, seq_group: &mut SequenceGroup) {
-        let seq = seq_group.only_seq();
+        assert!(seq_group.seqs.len() == 1);
+        let seq = &mut seq_group.seqs[0];
         assert!(seq.num_kv_computed == 0);
-        self.gpu_allocator.alloc_seq(seq);
+        seq.num_kv_computed = self.gpu_allocator.alloc_seq(seq);
     }
 
     fn can_append_slot(&self, seq_group: &SequenceGroup) -> bool {
@@ -296,8 +786,8 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
         let mut mapping = HashMap::default();
         for seq in &mut seq_group.seqs {
             if seq.sched_phase == SchedulingPhase::Swapped {
-                self.cpu_allocator
-                    .swap_in(seq, self.gpu_allocator.swap_out(seq), &mut mapping);
+                self.gpu_allocator
+                    .swap_in(seq, self.cpu_allocator.swap_out(seq), &mut mapping);
                 seq.sched_phase = SchedulingPhase::Running;
             }
         }
@@ -308,8 +798,11 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
         let mut mapping = HashMap::default();
         for seq in &mut seq_group.seqs {
             if seq.sched_phase == SchedulingPhase::Running {
-                self.gpu_allocator
-                    .swap_in(seq, self.cpu_allocator.swap_out(seq), &mut mapping);
+                self.cpu_allocator.swap_in(
+                    seq,
+                    self.gpu_allocator.begin_swap_out(seq),
+                    &mut mapping,
+                );
                 seq.sched_phase = SchedulingPhase::Swapped;
             }
         }
@@ -328,6 +821,48 @@ impl TBlockSpaceManager<TModel> for BlockSpaceManager {
     fn get_num_free_cpu_blocks(&self) -> usize {
         self.cpu_allocator.get_num_free_blocks()
     }
+
+    fn num_blocks_to_grow(&self, seq: &Sequence, num_tokens: usize) -> usize {
+        self.gpu_allocator.num_blocks_to_grow(seq, num_tokens)
+    }
+
+    fn get_prefix_cache_stats(&self) -> (usize, usize) {
+        self.gpu_allocator.get_prefix_cache_stats()
+    }
+
+    fn get_num_gpu_blocks(&self) -> usize {
+        self.gpu_allocator.get_num_blocks()
+    }
+
+    fn get_num_cpu_blocks(&self) -> usize {
+        self.cpu_allocator.get_num_blocks()
+    }
+
+    fn seq_cache_usage(&self, seq: &Sequence) -> SeqCacheUsage {
+        let gpu = self.gpu_allocator.seq_usage(seq.seq_id);
+        let cpu = self.cpu_allocator.seq_usage(seq.seq_id);
+        SeqCacheUsage {
+            gpu_blocks: gpu.charged,
+            cpu_blocks: cpu.charged,
+            shared_blocks: gpu.charged_shared + cpu.charged_shared,
+        }
+    }
+
+    fn pool_usage(&self) -> (CachePoolUsage, CachePoolUsage) {
+        (
+            self.gpu_allocator.pool_usage(),
+            self.cpu_allocator.pool_usage(),
+        )
+    }
+
+    fn get_watermark_blocks(&self) -> usize {
+        self.watermark_blocks
+    }
+
+    fn check_cache_counters(&self) {
+        self.gpu_allocator.check_counters();
+        self.cpu_allocator.check_counters();
+    }
 }
 
 impl BlockSpaceManager {
@@ -375,7 +910,12 @@ impl BlockSpaceManager {
             num_blocks,
             (num_blocks * CacheEngine::get_cache_block_size(config)) >> 20
         );
-        BlockAllocator::new(location, block_size, num_blocks)
+        BlockAllocator::new(
+            location,
+            block_size,
+            num_blocks,
+            config.model.sliding_window,
+        )
     }
 
     fn can_alloc_gpu(&self, num_required_blocks: usize) -> bool {
@@ -431,9 +971,10 @@ impl SequenceManager for TchSeqMgr {
         self.gpu_allocator.copy(src, dst, length);
     }
 
-    fn trim(&self, seq: SeqId, length: usize) {
-        self.cpu_allocator.trim(seq, length);
-        self.gpu_allocator.trim(seq, length);
+    fn trim(&self, seq: SeqId, length: usize) -> usize {
+        let cpu = self.cpu_allocator.trim(seq, length);
+        let gpu = self.gpu_allocator.trim(seq, length);
+        std::cmp::min(cpu, gpu)
     }
 
     fn delete(&self, seq: SeqId) {
@@ -441,3 +982,152 @@ impl SequenceManager for TchSeqMgr {
         self.gpu_allocator.delete(seq);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(charged: usize, charged_shared: usize) -> SeqUsage {
+        SeqUsage {
+            charged,
+            charged_shared,
+        }
+    }
+
+    #[test]
+    fn shared_blocks_follow_forks() {
+        let gpu = BlockAllocator::new(BlockLocation::GPU, 4, 16, None);
+        let (a_id, b_id) = (SeqId(1), SeqId(2));
+        let prompt = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let mut a = Sequence::new(a_id, &prompt);
+        assert_eq!(gpu.alloc_seq(&a), 0);
+        a.num_kv_computed = 10;
+
+        // forking shares all the blocks, which stay charged to the parent
+        gpu.copy(a_id, b_id, 10);
+        let mut b = Sequence::new(b_id, &prompt);
+        b.num_kv_computed = 10;
+        gpu.check_counters();
+        assert_eq!(gpu.seq_usage(a_id), usage(3, 3));
+        assert_eq!(gpu.seq_usage(b_id), usage(0, 0));
+        let pool = gpu.pool_usage();
+        assert_eq!(pool.used_blocks, 3);
+        assert_eq!(pool.shared_blocks, 3);
+        assert_eq!(pool.free_blocks, 13);
+
+        // the forks diverge in the last (partial) block, which gets copied once
+        let mut outputs = SchedulerOutputs::new();
+        a.append_tokens(&[11]);
+        gpu.append_slots(&a, &mut outputs);
+        b.append_tokens(&[12]);
+        gpu.append_slots(&b, &mut outputs);
+        gpu.check_counters();
+        assert_eq!(outputs.blocks_to_copy.values().flatten().count(), 1);
+        assert_eq!(gpu.seq_usage(a_id), usage(3, 2));
+        assert_eq!(gpu.seq_usage(b_id), usage(1, 0));
+        let pool = gpu.pool_usage();
+        assert_eq!(pool.used_blocks, 4);
+        assert_eq!(pool.shared_blocks, 2);
+        assert_eq!(pool.used_blocks + pool.free_blocks, pool.num_blocks);
+
+        // the remaining owner is charged for the blocks of a deleted sequence
+        gpu.delete(a_id);
+        gpu.check_counters();
+        assert_eq!(gpu.seq_usage(a_id), usage(0, 0));
+        assert_eq!(gpu.seq_usage(b_id), usage(3, 0));
+        let pool = gpu.pool_usage();
+        assert_eq!(pool.used_blocks, 3);
+        assert_eq!(pool.shared_blocks, 0);
+        assert_eq!(pool.free_blocks, 13);
+    }
+
+    #[test]
+    fn shared_prefix_is_reused() {
+        let gpu = BlockAllocator::new(BlockLocation::GPU, 4, 16, None);
+        let mut outputs = SchedulerOutputs::new();
+        let mut a = Sequence::new(SeqId(1), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(gpu.alloc_seq(&a), 0);
+        a.num_kv_computed = 10;
+        gpu.append_slots(&a, &mut outputs);
+
+        // the two full prompt blocks are picked up by a sequence with the same prefix
+        let b = Sequence::new(SeqId(2), &[1, 2, 3, 4, 5, 6, 7, 8, 20, 21, 22]);
+        assert_eq!(gpu.alloc_seq(&b), 8);
+        gpu.check_counters();
+        assert_eq!(gpu.pool_usage().shared_blocks, 2);
+
+        // only the first block matches
+        let c = Sequence::new(SeqId(3), &[1, 2, 3, 4, 30, 31, 32, 33, 34]);
+        assert_eq!(gpu.alloc_seq(&c), 4);
+        assert_eq!(gpu.get_prefix_cache_stats(), (3, 3));
+
+        // the blocks stay cached after their owners are gone
+        gpu.delete(SeqId(1));
+        gpu.delete(SeqId(2));
+        gpu.delete(SeqId(3));
+        gpu.check_counters();
+        let d = Sequence::new(SeqId(4), &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(gpu.alloc_seq(&d), 8);
+    }
+
+    #[test]
+    fn prefix_hash_collisions_are_misses() {
+        let gpu = BlockAllocator::new(BlockLocation::GPU, 4, 16, None);
+        let mut outputs = SchedulerOutputs::new();
+        let mut a = Sequence::new(SeqId(1), &[1, 2, 3, 4, 5]);
+        assert_eq!(gpu.alloc_seq(&a), 0);
+        a.num_kv_computed = 5;
+        gpu.append_slots(&a, &mut outputs);
+
+        // make the first block of another prompt hash to the cached block
+        let b = Sequence::new(SeqId(2), &[9, 9, 9, 9, 5]);
+        {
+            let mut l = gpu.inner.lock().unwrap();
+            let tokens = l.block_tokens(&b, 0);
+            let h = l.block_hash(0, &tokens);
+            let block_idx = l.seq_blocks[&SeqId(1)].blocks[0].block_idx;
+            l.alloc.prefix_cache.insert(h, block_idx);
+        }
+        assert_eq!(gpu.alloc_seq(&b), 0);
+        gpu.check_counters();
+        assert_eq!(gpu.pool_usage().shared_blocks, 0);
+    }
+
+    #[test]
+    fn blocks_out_of_the_window_are_freed() {
+        let gpu = BlockAllocator::new(BlockLocation::GPU, 4, 16, Some(6));
+        let mut outputs = SchedulerOutputs::new();
+        let (a_id, b_id) = (SeqId(1), SeqId(2));
+        let mut a = Sequence::new(a_id, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(gpu.alloc_seq(&a), 0);
+        assert_eq!(gpu.pool_usage().used_blocks, 3);
+
+        // the query at position 10 only sees positions 5..=10, so the first block goes
+        a.num_kv_computed = 10;
+        a.append_tokens(&[11]);
+        gpu.append_slots(&a, &mut outputs);
+        gpu.check_counters();
+        assert_eq!(gpu.pool_usage().used_blocks, 2);
+        assert_eq!(gpu.get_block_idxes(a_id, 4, 11).len(), 7);
+
+        // forks don't get the dropped block back
+        gpu.copy(a_id, b_id, 11);
+        gpu.check_counters();
+        assert_eq!(gpu.pool_usage().used_blocks, 2);
+        assert_eq!(gpu.pool_usage().shared_blocks, 2);
+
+        // backtracking within the window keeps the KV
+        assert_eq!(gpu.trim(a_id, 10), 10);
+        // position 2 was already dropped, so everything has to be recomputed
+        assert_eq!(gpu.trim(a_id, 8), 0);
+        gpu.check_counters();
+        assert_eq!(gpu.seq_usage(a_id), usage(0, 0));
+        assert_eq!(gpu.seq_usage(b_id), usage(2, 0));
+
+        a.num_kv_computed = 0;
+        gpu.append_slots(&a, &mut outputs);
+        gpu.check_counters();
+        assert_eq!(gpu.pool_usage().used_blocks, 5);
+        assert_eq!(gpu.get_block_idxes(a_id, 0, 11).len(), 11);
+    }
+}
@@ -1,8 +1,8 @@
 // based on https://github.com/vllm-project/vllm/blob/b9fe4616f98b77b4b9458bce203aa6544cb31ef2/vllm/worker/cache_engine.py
 
-use super::super::{config::TchRllmConfig, kernels, tmodel::TModel};
+use super::super::{config::TchRllmConfig, kernels, refkernels, tmodel::TModel};
 use super::CacheIface;
-use rllm::{config::RllmConfig, CacheSize, HashMap};
+use rllm::{config::RllmConfig, CacheSize, HashMap, HashSet};
 use std::sync::Arc;
 use tch::{Device, Tensor};
 
@@ -13,25 +13,40 @@ use tch_cuda::{CudaEvent, CudaStream};
 
 type KVCache = (Tensor, Tensor);
 
+/// Holds the KV cache of all layers; with pipeline parallelism, each layer's
+/// GPU cache lives on the device that runs that layer.
+///
+/// Swaps run on separate streams, overlapped with the forward pass: the model only
+/// waits for swap-ins it reads, and GPU blocks being swapped out are not reused
+/// until finish_swap_out() (see BlockAllocator::begin_swap_out()).
 pub struct CacheEngine {
     gpu_cache: Arc<Vec<KVCache>>,
     cpu_cache: Vec<KVCache>,
-    cache_stream: CudaStream,
+    // one per pipeline stage
+    cache_streams: Vec<CudaStream>,
+    // recorded after swapping the layer
     events: Arc<Vec<CudaEvent>>,
-    used_events: bool,
+    overlap_swaps: bool,
+    // GPU blocks written by swap_in() this round
+    swapped_in: HashSet<usize>,
+    // GPU blocks still read by swap_out(), until finish_swap_out()
+    pending_swap_out: Vec<usize>,
+    layers_per_stage: usize,
 }
 
 struct MyCacheAwaiter {
     gpu_cache: Arc<Vec<KVCache>>,
     events: Option<Arc<Vec<CudaEvent>>>,
-    stream: CudaStream,
+    // current stream of each pipeline stage
+    streams: Vec<CudaStream>,
+    layers_per_stage: usize,
 }
 
 impl CacheIface for MyCacheAwaiter {
     fn get(&self, layer_no: usize) -> (Tensor, Tensor) {
         let (key, value) = &self.gpu_cache[layer_no];
         if let Some(events) = &self.events {
-            events[layer_no].wait(&self.stream);
+            events[layer_no].wait(&self.streams[layer_no / self.layers_per_stage]);
         }
         (key.shallow_clone(), value.shallow_clone())
     }
@@ -39,53 +54,112 @@ impl CacheIface for MyCacheAwaiter {
 
 impl CacheEngine {
     pub fn new(config: Arc<RllmConfig<TModel>>, num_blocks: &CacheSize) -> Self {
-        let num_layers = config.get_num_layers_parallel();
+        let num_layers = config.model.num_hidden_layers;
         let (gpu_cache, cpu_cache) = Self::allocate_caches(&config, num_blocks);
+        // on CPU, swaps are plain copies, with no streams or events to wait for
+        let on_cuda = config.model.device.is_cuda();
+        let cache_streams = if on_cuda {
+            config
+                .get_pipeline_devices()
+                .into_iter()
+                .map(CudaStream::new)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let num_events = if on_cuda { num_layers } else { 0 };
         Self {
             gpu_cache: Arc::new(gpu_cache),
             cpu_cache,
-            cache_stream: CudaStream::new(config.model.device),
-            events: Arc::new((0..num_layers).map(|_| CudaEvent::new()).collect()),
-            used_events: false,
+            cache_streams,
+            events: Arc::new((0..num_events).map(|_| CudaEvent::new()).collect()),
+            overlap_swaps: config.model.cache.overlap_swaps,
+            swapped_in: HashSet::default(),
+            pending_swap_out: Vec::new(),
+            layers_per_stage: config.get_num_layers_parallel(),
         }
     }
 
-    pub fn get_cache_iface(&mut self) -> Box<dyn CacheIface> {
-        let d = self.gpu_cache[0].0.device();
-        let events = if self.used_events {
+    /// `reads_swapped_in` is whether the batch reads any of swapped_in_blocks();
+    /// only then does the model wait for the swap-ins.
+    pub fn get_cache_iface(&mut self, reads_swapped_in: bool) -> Box<dyn CacheIface> {
+        let events = if reads_swapped_in && self.overlap_swaps && !self.events.is_empty() {
             Some(self.events.clone())
         } else {
             None
         };
+        let streams = self
+            .gpu_cache
+            .iter()
+            .step_by(self.layers_per_stage)
+            .filter(|(key, _)| key.device().is_cuda())
+            .map(|(key, _)| CudaStream::current(key.device()))
+            .collect();
         Box::new(MyCacheAwaiter {
             events,
-            stream: CudaStream::current(d),
+            streams,
             gpu_cache: self.gpu_cache.clone(),
+            layers_per_stage: self.layers_per_stage,
         })
     }
 
     pub fn new_round(&mut self) {
-        self.used_events = false;
+        self.swapped_in.clear();
+    }
+
+    pub fn swapped_in_blocks(&self) -> &HashSet<usize> {
+        &self.swapped_in
     }
 
     pub fn swap_in(&mut self, src_to_dst: &HashMap<usize, usize>) {
         self.swap(&self.cpu_cache, &self.gpu_cache, src_to_dst);
-        self.used_events = true;
+        if !self.overlap_swaps {
+            self.wait_for_swaps();
+        }
+        self.swapped_in.extend(src_to_dst.values());
     }
 
+    /// The source GPU blocks must not be written until finish_swap_out().
     pub fn swap_out(&mut self, src_to_dst: &HashMap<usize, usize>) {
         self.swap(&self.gpu_cache, &self.cpu_cache, src_to_dst);
-        self.used_events = true;
+        if !self.overlap_swaps {
+            self.wait_for_swaps();
+        }
+        self.pending_swap_out.extend(src_to_dst.keys());
+    }
+
+    /// Wait (on the CPU) for swap-outs to finish; returns the GPU blocks they read,
+    /// which can be reused now.
+    pub fn finish_swap_out(&mut self) -> Vec<usize> {
+        if !self.pending_swap_out.is_empty() {
+            self.wait_for_swaps();
+        }
+        std::mem::take(&mut self.pending_swap_out)
+    }
+
+    // streams are in order, so the event of the last layer of a stage covers the stage
+    fn last_stage_events(&self) -> impl Iterator<Item = &CudaEvent> {
+        self.events
+            .iter()
+            .skip(self.layers_per_stage - 1)
+            .step_by(self.layers_per_stage)
+    }
+
+    fn wait_for_swaps(&self) {
+        for ev in self.last_stage_events() {
+            ev.synchronize();
+        }
     }
 
     fn alloc_key_block(config: &RllmConfig<TModel>, num_bl: i64, device: Device) -> Tensor {
         let head_size = config.get_head_size() as i64;
         let num_heads = config.get_num_heads_parallel() as i64;
         let block_size = config.model.cache.block_size as i64;
-        let x = 16 / (config.model.dtype.elt_size_in_bytes() as i64);
+        let dtype = config.model.cache.dtype;
+        let x = 16 / (dtype.elt_size_in_bytes() as i64);
         Tensor::empty(
             &[num_bl, num_heads, head_size / x, block_size, x],
-            (config.model.dtype, device),
+            (dtype, device),
         )
     }
 
@@ -95,12 +169,15 @@ impl CacheEngine {
         let block_size = config.model.cache.block_size as i64;
         Tensor::empty(
             &[num_bl, num_heads, head_size, block_size],
-            (config.model.dtype, device),
+            (config.model.cache.dtype, device),
         )
     }
 
-    pub fn alloc_gpu_cache_layer(config: &RllmConfig<TModel>, num_bl: i64) -> (Tensor, Tensor) {
-        let device = config.model.device;
+    pub fn alloc_gpu_cache_layer(
+        config: &RllmConfig<TModel>,
+        num_bl: i64,
+        device: Device,
+    ) -> (Tensor, Tensor) {
         (
             Self::alloc_key_block(config, num_bl, device),
             Self::alloc_value_block(config, num_bl, device),
@@ -111,10 +188,13 @@ impl CacheEngine {
         config: &RllmConfig<TModel>,
         num_blocks: &CacheSize,
     ) -> (Vec<KVCache>, Vec<KVCache>) {
-        let num_layers = config.get_num_layers_parallel() as i64;
+        let num_layers = config.model.num_hidden_layers;
 
         let gpu_cache = (0..num_layers)
-            .map(|_| Self::alloc_gpu_cache_layer(config, num_blocks.gpu as i64))
+            .map(|i| {
+                let device = config.get_layer_device(i);
+                Self::alloc_gpu_cache_layer(config, num_blocks.gpu as i64, device)
+            })
             .collect();
 
         let cpu_cache = (0..num_layers)
@@ -131,37 +211,61 @@ impl CacheEngine {
         (gpu_cache, cpu_cache)
     }
 
+    /// Both caches live in host memory; just copy the blocks.
+    fn swap_on_host(src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) {
+        for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
+            let (dst_k_cache, dst_v_cache) = &dst[i];
+            refkernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst);
+            refkernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst);
+        }
+    }
+
     #[cfg(not(feature = "cuda"))]
-    fn swap(&self, _src: &[KVCache], _dst: &[KVCache], _src_to_dst: &HashMap<usize, usize>) {
-        let _ = self.cache_stream;
-        panic!("swap not implemented for CPU");
+    fn swap(&self, src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) {
+        let _ = &self.cache_streams;
+        Self::swap_on_host(src, dst, src_to_dst);
     }
 
     #[cfg(feature = "cuda")]
     fn swap(&self, src: &[KVCache], dst: &[KVCache], src_to_dst: &HashMap<usize, usize>) {
-        let stream = &self.cache_stream;
+        if self.cache_streams.is_empty() {
+            return Self::swap_on_host(src, dst, src_to_dst);
+        }
         for (i, (src_k_cache, src_v_cache)) in src.iter().enumerate() {
+            let stream = &self.cache_streams[i / self.layers_per_stage];
             let (dst_k_cache, dst_v_cache) = &dst[i];
-            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, &self.cache_stream);
-            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, &self.cache_stream);
+            kernels::swap_blocks(src_k_cache, dst_k_cache, src_to_dst, stream);
+            kernels::swap_blocks(src_v_cache, dst_v_cache, src_to_dst, stream);
             self.events[i].record(stream);
         }
     }
 
     pub fn copy(&mut self, src_to_dsts: &HashMap<usize, Vec<usize>>) {
-        let mut key_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(key, _)| key.shallow_clone())
-            .collect();
-        let mut value_caches: Vec<_> = self
-            .gpu_cache
-            .iter()
-            .map(|(_, value)| value.shallow_clone())
-            .collect();
-        kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+        // copy-on-write may read blocks just swapped in
+        let swap_events: Vec<_> = if self.overlap_swaps && !self.swapped_in.is_empty() {
+            self.last_stage_events().collect()
+        } else {
+            Vec::new()
+        };
+        // copy_blocks() needs all caches on one device, so go stage by stage
+        for (stage_no, stage) in self.gpu_cache.chunks(self.layers_per_stage).enumerate() {
+            if let Some(ev) = swap_events.get(stage_no) {
+                ev.wait(&CudaStream::current(stage[0].0.device()));
+            }
+            let mut key_caches: Vec<_> = stage.iter().map(|(key, _)| key.shallow_clone()).collect();
+            let mut value_caches: Vec<_> = stage
+                .iter()
+                .map(|(_, value)| value.shallow_clone())
+                .collect();
+            if kernels::on_cuda(&key_caches[0]) {
+                kernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+            } else {
+                refkernels::copy_blocks(&mut key_caches, &mut value_caches, &src_to_dsts);
+            }
+        }
     }
 
+    /// Size of a cache block on a single device (i.e., for one pipeline stage).
     pub fn get_cache_block_size(config: &RllmConfig<TModel>) -> usize {
         let block_size = config.model.cache.block_size;
         let head_size = config.get_head_size();
@@ -171,6 +275,6 @@ impl CacheEngine {
         let key_cache_block = block_size * num_heads * head_size;
         let value_cache_block = key_cache_block;
         let total = num_layers * (key_cache_block + value_cache_block);
-        config.model.dtype.elt_size_in_bytes() * total
+        config.model.cache.dtype.elt_size_in_bytes() * total
     }
 }
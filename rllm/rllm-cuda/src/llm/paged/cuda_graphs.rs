@@ -0,0 +1,109 @@
+use super::super::{
+    tmodel::{TModel, TModelInner},
+    util::synchronize,
+};
+use super::{BatchInfo, BatchInfoBuilder, CacheIface, StaticBatchInputs};
+use rllm::{config::RllmConfig, HashMap};
+use std::sync::Arc;
+use tch::{Device, Tensor};
+
+#[cfg(not(feature = "cuda"))]
+use super::cuda_stub::{CudaGraph, CudaStream};
+#[cfg(feature = "cuda")]
+use tch_cuda::{CudaGraph, CudaStream};
+
+/// Batch sizes for which CUDA graphs are used; other batches run eagerly.
+pub const CUDA_GRAPH_BATCH_SIZES: [usize; 5] = [1, 2, 4, 8, 16];
+
+struct GraphEntry {
+    inputs: StaticBatchInputs,
+    // the graph and its output (logits), once captured
+    graph: Option<(CudaGraph, Tensor)>,
+}
+
+/// Runs the forward pass of batches where all sequences use paged attention
+/// (see BatchInfoBuilder::paged_batch_size()) by replaying CUDA graphs,
+/// one per batch size, each captured when the batch size is first seen.
+/// The kernels are the same as in eager mode, and so are the results.
+pub struct CudaGraphs {
+    config: Arc<RllmConfig<TModel>>,
+    graphs: HashMap<usize, GraphEntry>,
+}
+
+impl CudaGraphs {
+    pub fn new(config: Arc<RllmConfig<TModel>>) -> Self {
+        Self {
+            config,
+            graphs: HashMap::default(),
+        }
+    }
+
+    pub fn supports(&self, builder: &BatchInfoBuilder) -> bool {
+        // the graphs are captured without LoRA, which changes the computation
+        !builder.has_lora()
+            && builder
+                .paged_batch_size()
+                .is_some_and(|n| CUDA_GRAPH_BATCH_SIZES.contains(&n))
+    }
+
+    /// Replaces BatchInfoBuilder::finish(); requires supports(builder).
+    pub fn prepare(
+        &mut self,
+        builder: &mut BatchInfoBuilder,
+        step_no: usize,
+        kv_cache: Box<dyn CacheIface>,
+    ) -> BatchInfo {
+        let batch_size = builder.paged_batch_size().unwrap();
+        let config = &self.config;
+        let entry = self.graphs.entry(batch_size).or_insert_with(|| GraphEntry {
+            inputs: StaticBatchInputs::new(config, batch_size),
+            graph: None,
+        });
+        builder.finish_into(step_no, kv_cache, &mut entry.inputs)
+    }
+
+    /// Replaces model.forward(info) for `info` from prepare().
+    pub fn forward(&mut self, model: &dyn TModelInner, info: &mut BatchInfo) -> Tensor {
+        let batch_size = info.tokens.size()[0] as usize;
+        if self.graphs[&batch_size].graph.is_none() {
+            // all graphs use the same memory pool; they never run concurrently
+            let pool_of = self.graphs.values().find_map(|e| e.graph.as_ref());
+            let captured = capture(
+                self.config.model.device,
+                model,
+                info,
+                pool_of.map(|(g, _)| g),
+            );
+            log::info!("captured CUDA graph for batch size {batch_size}");
+            self.graphs.get_mut(&batch_size).unwrap().graph = Some(captured);
+        }
+        let (graph, logits) = self.graphs[&batch_size].graph.as_ref().unwrap();
+        graph.replay();
+        // the next replay overwrites the output
+        logits.copy()
+    }
+}
+
+fn capture(
+    device: Device,
+    model: &dyn TModelInner,
+    info: &mut BatchInfo,
+    pool_of: Option<&CudaGraph>,
+) -> (CudaGraph, Tensor) {
+    // capturing requires a non-default stream
+    let stream = CudaStream::new(device);
+    let graph = CudaGraph::new();
+    synchronize(device);
+    let logits = {
+        let _guard = stream.guard();
+        // initialize libraries and the like outside of the capture;
+        // this only writes the same KV cache entries as the replay will
+        let _ = model.forward(info);
+        graph.capture_begin(pool_of);
+        let logits = model.forward(info);
+        graph.capture_end();
+        logits
+    };
+    synchronize(device);
+    (graph, logits)
+}
@@ -7,7 +7,11 @@ impl CudaEvent {
         CudaEvent {}
     }
 
+    pub fn record(&self, _stream: &CudaStream) {}
+
     pub fn wait(&self, _stream: &CudaStream) {}
+
+    pub fn synchronize(&self) {}
 }
 
 pub struct CudaStream {}
@@ -20,4 +24,20 @@ impl CudaStream {
     pub fn current(_device: Device) -> Self {
         CudaStream {}
     }
+
+    pub fn guard(&self) {}
+}
+
+pub struct CudaGraph {}
+
+impl CudaGraph {
+    pub fn new() -> Self {
+        CudaGraph {}
+    }
+
+    pub fn capture_begin(&self, _pool_of: Option<&CudaGraph>) {}
+
+    pub fn capture_end(&self) {}
+
+    pub fn replay(&self) {}
 }
@@ -4,7 +4,9 @@ mod cuda_stub;
 mod batch_info;
 mod blocks;
 mod cache_engine;
+mod cuda_graphs;
 
 pub use batch_info::*;
 pub use blocks::*;
 pub use cache_engine::*;
+pub use cuda_graphs::*;
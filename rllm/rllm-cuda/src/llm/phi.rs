@@ -4,6 +4,8 @@ use super::{
     paged::BatchInfo,
     varlen_attn, RotaryEmbedding,
 };
+use aicirt::bail_user;
+use anyhow::Result;
 use serde::Deserialize;
 use std::rc::Rc;
 use tch::{
@@ -32,12 +34,12 @@ pub struct PhiConfig {
 }
 
 impl RllmModelConfig for PhiConfig {
-    fn into_config(self, common: CommonModelConfig) -> ModelConfig {
+    fn into_config(self, common: CommonModelConfig) -> Result<ModelConfig> {
         let mut meta = common.meta.clone();
         meta.vocab_size = self.vocab_size;
         meta.tok_vocab_size = self.vocab_size;
         meta.max_sequence_length = self.n_positions;
-        ModelConfig {
+        Ok(ModelConfig {
             model_type: ModelType::Phi,
             meta,
             hidden_size: self.n_embd,
@@ -47,13 +49,74 @@ impl RllmModelConfig for PhiConfig {
             num_key_value_heads: self.n_head,
             layer_norm_eps: self.layer_norm_epsilon,
             rope_theta: 10000.0,
+            sliding_window: None,
             head_dim: self.n_embd / self.n_head,
             rotary_dim: self.rotary_dim,
             dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
             device: common.device,
             profile_step_no: 0,
+            enable_cuda_graphs: false,
+            lora_adapters: Vec::new(),
             cache: Default::default(),
+        })
+    }
+}
+
+/// Phi in the transformers format.
+/// https://huggingface.co/microsoft/phi-2
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Phi2Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) num_key_value_heads: Option<usize>,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) layer_norm_eps: f64,
+    #[serde(default = "default_rope")]
+    pub(crate) rope_theta: f32,
+    /// Only this fraction of each head gets rotary embeddings.
+    pub(crate) partial_rotary_factor: f64,
+    #[serde(default)]
+    pub(crate) qk_layernorm: bool,
+    pub(crate) torch_dtype: String,
+}
+
+fn default_rope() -> f32 {
+    10_000.0
+}
+
+impl RllmModelConfig for Phi2Config {
+    fn into_config(self, common: CommonModelConfig) -> Result<ModelConfig> {
+        if self.qk_layernorm {
+            bail_user!("Phi models with qk_layernorm are not supported");
         }
+        let head_dim = self.hidden_size / self.num_attention_heads;
+        let mut meta = common.meta.clone();
+        meta.vocab_size = self.vocab_size;
+        meta.tok_vocab_size = self.vocab_size;
+        meta.max_sequence_length = self.max_position_embeddings;
+        Ok(ModelConfig {
+            model_type: ModelType::Phi2,
+            meta,
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
+            layer_norm_eps: self.layer_norm_eps,
+            rope_theta: self.rope_theta,
+            sliding_window: None,
+            head_dim,
+            rotary_dim: (self.partial_rotary_factor * head_dim as f64) as usize,
+            dtype: ModelConfig::dtype_from_str(common.dtype, &self.torch_dtype),
+            device: common.device,
+            profile_step_no: 0,
+            enable_cuda_graphs: false,
+            lora_adapters: Vec::new(),
+            cache: Default::default(),
+        })
     }
 }
 
@@ -215,19 +278,141 @@ impl TModelInner for MixFormerSequentialForCausalLM {
             xs = block.forward(&xs, batch_info);
         }
         let r = self.head.forward(&xs);
+        extract_logits(&self.config, &r, batch_info)
+    }
+}
+
+/// Cut the logits to the tokenizer's vocabulary, at the positions we sample from.
+fn extract_logits(config: &ModelConfig, r: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+    // it should approximately match...
+    let tok_size = config.meta.tok_vocab_size as i64;
+    if r.size()[1] < tok_size || r.size()[1] > tok_size + 1000 {
+        panic!(
+            "unexpected logits size: {:?} ({}/{})",
+            r.size(),
+            tok_size,
+            config.meta.vocab_size
+        );
+    }
+
+    let r = r.i((.., 0..tok_size));
+    batch_info.extract_positions(&r)
+}
+
+/// Same as MHA, but with separate q/k/v projections (which allows for grouped-query attention).
+#[derive(Debug)]
+struct PhiAttention {
+    q_proj: nn::Linear,
+    k_proj: nn::Linear,
+    v_proj: nn::Linear,
+    dense: nn::Linear,
+    rotary_emb: RotaryEmbedding,
+    config: Rc<ModelConfig>,
+    block_idx: usize,
+}
+
+impl PhiAttention {
+    fn new(cfg: &Rc<ModelConfig>, block_idx: usize, vb: Path) -> Self {
+        let size_q = cfg.head_dim * cfg.num_attention_heads;
+        let size_kv = cfg.head_dim * cfg.num_key_value_heads;
+        Self {
+            q_proj: linear(cfg.hidden_size, size_q, &vb / "q_proj"),
+            k_proj: linear(cfg.hidden_size, size_kv, &vb / "k_proj"),
+            v_proj: linear(cfg.hidden_size, size_kv, &vb / "v_proj"),
+            dense: linear(size_q, cfg.hidden_size, &vb / "dense"),
+            rotary_emb: RotaryEmbedding::new(cfg),
+            config: cfg.clone(),
+            block_idx,
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        let (seq_len, _hidden_size) = xs.size2().unwrap();
+        let q = self.q_proj.forward(xs);
+        let k = self.k_proj.forward(xs);
+        let v = self.v_proj.forward(xs).reshape(&[
+            seq_len,
+            self.config.num_key_value_heads as i64,
+            self.config.head_dim as i64,
+        ]);
+        // only the first rotary_dim of each head are rotated
+        let (q, k) = self.rotary_emb.forward(&batch_info.positions, &q, &k);
+        let y = varlen_attn(&self.config, q, k, v, batch_info, self.block_idx);
+        self.dense.forward(&y)
+    }
+}
 
-        // it should approximately match...
-        let tok_size = self.config.meta.tok_vocab_size as i64;
-        if r.size()[1] < tok_size || r.size()[1] > tok_size + 1000 {
-            panic!(
-                "unexpected logits size: {:?} ({}/{})",
-                r.size(),
-                tok_size,
-                self.config.meta.vocab_size
-            );
+/// Attention and MLP both read the same normalized input (as in ParallelBlock).
+#[derive(Debug)]
+struct PhiDecoderLayer {
+    input_layernorm: nn::LayerNorm,
+    self_attn: PhiAttention,
+    mlp: MLP,
+}
+
+impl PhiDecoderLayer {
+    fn new(cfg: &Rc<ModelConfig>, mut vb: Path, block_idx: usize) -> Self {
+        let input_layernorm = layer_norm(&vb / "input_layernorm", cfg);
+        let self_attn = PhiAttention::new(cfg, block_idx, &vb / "self_attn");
+        let mlp = MLP::new(cfg, &vb / "mlp");
+        // this optimizes memory usage
+        vb.set_kind(cfg.dtype);
+        Self {
+            input_layernorm,
+            self_attn,
+            mlp,
         }
+    }
 
-        let r = r.i((.., 0..tok_size));
-        batch_info.extract_positions(&r)
+    fn forward(&self, xs: &Tensor, batch_info: &mut BatchInfo) -> Tensor {
+        let residual = xs;
+        let xs = xs.apply(&self.input_layernorm);
+        let attn_outputs = self.self_attn.forward(&xs, batch_info);
+        let feed_forward_hidden_states = self.mlp.forward(&xs);
+        attn_outputs + feed_forward_hidden_states + residual
+    }
+}
+
+#[derive(Debug)]
+pub struct PhiForCausalLM {
+    embed_tokens: nn::Embedding,
+    layers: Vec<PhiDecoderLayer>,
+    final_layernorm: nn::LayerNorm,
+    lm_head: nn::Linear,
+    config: Rc<ModelConfig>,
+}
+
+impl PhiForCausalLM {
+    pub fn new(cfg: &Rc<ModelConfig>, vb0: Path) -> Self {
+        let vb = &vb0 / "model";
+        let embed_tokens = nn::embedding(
+            &vb / "embed_tokens",
+            cfg.meta.vocab_size as i64,
+            cfg.hidden_size as i64,
+            Default::default(),
+        );
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| PhiDecoderLayer::new(cfg, &vb / "layers" / i, i))
+            .collect();
+        let final_layernorm = layer_norm(&vb / "final_layernorm", cfg);
+        let lm_head = linear(cfg.hidden_size, cfg.meta.vocab_size, &vb0 / "lm_head");
+        Self {
+            embed_tokens,
+            layers,
+            final_layernorm,
+            lm_head,
+            config: cfg.clone(),
+        }
+    }
+}
+
+impl TModelInner for PhiForCausalLM {
+    fn forward(&self, batch_info: &mut BatchInfo) -> Tensor {
+        let mut xs = self.embed_tokens.forward(&batch_info.tokens);
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, batch_info);
+        }
+        let r = xs.apply(&self.final_layernorm).apply(&self.lm_head);
+        extract_logits(&self.config, &r, batch_info)
     }
 }
@@ -1,5 +1,6 @@
 use super::util::{check_all_close_attn, to_vec1};
 use rllm::HashMap;
+use std::ops::Range;
 use tch::{IndexOp, Kind, Tensor};
 
 pub fn reshape_and_cache(
@@ -66,85 +67,183 @@ pub fn varlen_attn(
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
+    window: Option<usize>,
+) -> Tensor {
+    varlen_attn_masked(
+        q,
+        k,
+        v,
+        seqlens_q,
+        seqlens_k,
+        max_seqlen_q,
+        max_seqlen_k,
+        softmax_scale,
+        causal,
+        window,
+        &[],
+    )
+}
+
+/// Like varlen_attn(), but sequence `i` doesn't attend to the keys in `masked[j].1`
+/// for `masked[j].0 == i`; a query still attends to its own key.
+pub fn varlen_attn_masked(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    causal: bool,
+    window: Option<usize>,
+    masked: &[(usize, Vec<Range<usize>>)],
 ) -> Tensor {
     let seqlens_q = to_vec1::<i32>(seqlens_q);
     let seqlens_k = to_vec1::<i32>(seqlens_k);
     assert!(seqlens_q.len() == seqlens_k.len());
     let batch_size = seqlens_k.len() - 1;
-
-    let softmax_scale = softmax_scale as f64;
-
-    let (_batch_size_q, num_heads, head_dim) = q.size3().unwrap();
-
     assert!(causal == true);
-
-    // flash-attn expects (seq_len, nheads, head_dim)
-
     let mut attns = Vec::with_capacity(batch_size);
-
     for i in 0..batch_size {
-        let ptr_q = seqlens_q[i] as i64;
-        let ptr_k = seqlens_k[i] as i64;
-        let len_q = seqlens_q[i + 1] as i64 - ptr_q;
-        let len_k = seqlens_k[i + 1] as i64 - ptr_k;
-
+        let ranges = masked
+            .iter()
+            .find(|(idx, _)| *idx == i)
+            .map_or(&[][..], |(_, r)| r.as_slice());
+        let (attn0, len_q, len_k) = attn_entry(
+            q,
+            k,
+            v,
+            &seqlens_q,
+            &seqlens_k,
+            i,
+            softmax_scale,
+            window,
+            ranges,
+        );
         assert!(len_q <= max_seqlen_q as i64);
         assert!(len_k <= max_seqlen_k as i64);
+        attns.push(attn0);
+    }
+    let attn = Tensor::cat(&attns, 0);
+    attn
+}
 
-        let q = q.i((ptr_q..ptr_q + len_q, .., ..)).transpose(0, 1);
-        let k = k.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
-        let v = v.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
-
-        assert!(q.size() == [num_heads, len_q, head_dim]);
-        assert!(k.size() == [num_heads, len_k, head_dim]);
-        assert!(v.size() == [num_heads, len_k, head_dim]);
+/// Compute the sequences with masks (see varlen_attn_masked()) again, replacing their
+/// rows of `y`, the result of varlen attention without the masks; the others are kept.
+pub fn apply_attn_masks(
+    y: &Tensor,
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    softmax_scale: f32,
+    window: Option<usize>,
+    masked: &[(usize, Vec<Range<usize>>)],
+) {
+    let seqlens_q = to_vec1::<i32>(seqlens_q);
+    let seqlens_k = to_vec1::<i32>(seqlens_k);
+    for (i, ranges) in masked {
+        let (attn0, len_q, _) = attn_entry(
+            q,
+            k,
+            v,
+            &seqlens_q,
+            &seqlens_k,
+            *i,
+            softmax_scale,
+            window,
+            ranges,
+        );
+        let ptr_q = seqlens_q[*i] as i64;
+        y.i((ptr_q..ptr_q + len_q, .., ..)).copy_(&attn0);
+    }
+}
 
-        let attn_bias = Tensor::zeros(&[len_q, len_k], (q.kind(), q.device()));
-        let mask = Tensor::ones(&[len_q, len_q], (Kind::Bool, q.device()))
-            .tril(0)
-            .logical_not();
+/// Attention of the `i`-th sequence of the batch, hiding the `masked` keys; returns
+/// it with the numbers of queries and keys.
+fn attn_entry(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &[i32],
+    seqlens_k: &[i32],
+    i: usize,
+    softmax_scale: f32,
+    window: Option<usize>,
+    masked: &[Range<usize>],
+) -> (Tensor, i64, i64) {
+    let softmax_scale = softmax_scale as f64;
+    let (_batch_size_q, num_heads, head_dim) = q.size3().unwrap();
+    // flash-attn expects (seq_len, nheads, head_dim)
+    let ptr_q = seqlens_q[i] as i64;
+    let ptr_k = seqlens_k[i] as i64;
+    let len_q = seqlens_q[i + 1] as i64 - ptr_q;
+    let len_k = seqlens_k[i + 1] as i64 - ptr_k;
+    let q = q.i((ptr_q..ptr_q + len_q, .., ..)).transpose(0, 1);
+    let k = k.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
+    let v = v.i((ptr_k..ptr_k + len_k, .., ..)).transpose(0, 1);
+    assert!(q.size() == [num_heads, len_q, head_dim]);
+    assert!(k.size() == [num_heads, len_k, head_dim]);
+    assert!(v.size() == [num_heads, len_k, head_dim]);
+    let attn_bias = Tensor::zeros(&[len_q, len_k], (q.kind(), q.device()));
+    let mask = Tensor::ones(&[len_q, len_q], (Kind::Bool, q.device()))
+        .tril(0)
+        .logical_not();
+    let _ = attn_bias
+        .i((.., len_k - len_q..))
+        .masked_fill_(&mask, f64::NEG_INFINITY);
+    if let Some(w) = window {
+        // query i sits at key position len_k - len_q + i, and sees the last w keys
+        let old_keys =
+            Tensor::ones(&[len_q, len_k], (Kind::Bool, q.device())).tril(len_k - len_q - w as i64);
+        let _ = attn_bias.masked_fill_(&old_keys, f64::NEG_INFINITY);
+    }
+    if !masked.is_empty() {
+        for r in masked {
+            let _ = attn_bias
+                .i((.., r.start as i64..r.end as i64))
+                .fill_(f64::NEG_INFINITY);
+        }
         let _ = attn_bias
             .i((.., len_k - len_q..))
-            .masked_fill_(&mask, f64::NEG_INFINITY);
-
-        let attn0 = Tensor::scaled_dot_product_attention(
-            &q,
-            &k,
-            &v,
-            Some(&attn_bias),
+            .diagonal(0, 0, 1)
+            .fill_(0.0);
+    }
+    let attn0 = Tensor::scaled_dot_product_attention(
+        &q,
+        &k,
+        &v,
+        Some(&attn_bias),
+        0.0,
+        false,
+        softmax_scale,
+    )
+    .reshape(&[num_heads, len_q, head_dim])
+    .transpose(0, 1);
+
+    // println!("attn0: {attn0:?}");
+
+    if false {
+        let attn_cpu = Tensor::scaled_dot_product_attention(
+            &q.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
+            &k.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
+            &v.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
+            Some(attn_bias.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true)),
             0.0,
             false,
             softmax_scale,
         )
+        .to_dtype_layout((q.kind(), q.device()), false, true)
         .reshape(&[num_heads, len_q, head_dim])
         .transpose(0, 1);
 
-        // println!("attn0: {attn0:?}");
-
-        if false {
-            let attn_cpu = Tensor::scaled_dot_product_attention(
-                &q.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
-                &k.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
-                &v.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true),
-                Some(attn_bias.to_dtype_layout((tch::Kind::Float, tch::Device::Cpu), false, true)),
-                0.0,
-                false,
-                softmax_scale,
-            )
-            .to_dtype_layout((q.kind(), q.device()), false, true)
-            .reshape(&[num_heads, len_q, head_dim])
-            .transpose(0, 1);
-
-            check_all_close_attn(&attn_cpu, &attn0);
-        }
-
-        assert!(!attn0.max().double_value(&[]).is_nan());
-
-        attns.push(attn0);
+        check_all_close_attn(&attn_cpu, &attn0);
     }
 
-    let attn = Tensor::cat(&attns, 0);
-    attn
+    assert!(!attn0.max().double_value(&[]).is_nan());
+    (attn0, len_q, len_k)
 }
 
 pub fn rotary_embedding(
@@ -209,21 +308,85 @@ pub fn rotary_embedding(
     key0.copy_(&key_rot.reshape(key0.size()));
 }
 
-#[allow(dead_code)]
 pub fn copy_blocks(
-    _key_caches: &mut Vec<Tensor>,
-    _value_caches: &mut Vec<Tensor>,
-    _block_mapping: &HashMap<usize, Vec<usize>>,
+    key_caches: &mut Vec<Tensor>,   // per layer: [num_blocks, ...]
+    value_caches: &mut Vec<Tensor>, // per layer: [num_blocks, ...]
+    block_mapping: &HashMap<usize, Vec<usize>>,
 ) {
-    todo!()
+    for cache in key_caches.iter().chain(value_caches.iter()) {
+        for (&src, dsts) in block_mapping {
+            for &dst in dsts {
+                cache.get(dst as i64).copy_(&cache.get(src as i64));
+            }
+        }
+    }
 }
 
-#[allow(dead_code)]
 pub fn swap_blocks(
-    _src: &Tensor,
-    _dst: &Tensor,
-    _block_mapping: &HashMap<usize, usize>,
-    // _stream: &CudaStream,
+    src: &Tensor, // [num_blocks, ...]
+    dst: &Tensor, // [num_blocks, ...]
+    block_mapping: &HashMap<usize, usize>,
 ) {
-    todo!()
+    for (&s, &d) in block_mapping {
+        dst.get(d as i64).copy_(&src.get(s as i64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tch::Device;
+
+    const OPTS: (Kind, Device) = (Kind::Float, Device::Cpu);
+
+    fn seqlens(lens: &[i32]) -> Tensor {
+        let mut acc = vec![0];
+        for l in lens {
+            acc.push(acc.last().unwrap() + l);
+        }
+        Tensor::from_slice(&acc)
+    }
+
+    #[test]
+    fn masked_keys_are_ignored() {
+        tch::manual_seed(1);
+        let (num_heads, head_dim) = (2, 8);
+        // two sequences, with 3 queries over 7 keys, and 2 over 5
+        let q = Tensor::randn(&[5, num_heads, head_dim], OPTS);
+        let k = Tensor::randn(&[12, num_heads, head_dim], OPTS);
+        let v = Tensor::randn(&[12, num_heads, head_dim], OPTS);
+        let (seqlens_q, seqlens_k) = (seqlens(&[3, 2]), seqlens(&[7, 5]));
+        let attn =
+            |k: &Tensor, v: &Tensor, seqlens_k: &Tensor, masked: &[(usize, Vec<Range<usize>>)]| {
+                varlen_attn_masked(
+                    &q, k, v, &seqlens_q, seqlens_k, 3, 7, 0.5, true, None, masked,
+                )
+            };
+        let plain = attn(&k, &v, &seqlens_k, &[]);
+
+        // hiding keys 1..3 of the first sequence is the same as dropping them
+        let masked = [(0, vec![1..3])];
+        let y = attn(&k, &v, &seqlens_k, &masked);
+        let keep = Tensor::from_slice(&[0i64, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        let dropped = attn(
+            &k.index_select(0, &keep),
+            &v.index_select(0, &keep),
+            &seqlens(&[5, 5]),
+            &[],
+        );
+        assert!(y.allclose(&dropped, 1e-5, 1e-6, false));
+        assert!(!y.i(0..3).allclose(&plain.i(0..3), 1e-5, 1e-6, false));
+        assert!(y.i(3..).equal(&plain.i(3..)));
+
+        // only the masked sequence is computed again
+        let y2 = plain.copy();
+        apply_attn_masks(&y2, &q, &k, &v, &seqlens_q, &seqlens_k, 0.5, None, &masked);
+        assert!(y2.allclose(&y, 1e-5, 1e-6, false));
+
+        // a query still attends to its own key
+        let masked = [(1, vec![0..5])];
+        let y = attn(&k, &v, &seqlens_k, &masked);
+        let own = v.i(10..12);
+        assert!(y.i(3..).allclose(&own, 1e-5, 1e-6, false));
+    }
 }
@@ -1,20 +1,39 @@
 use super::{
     config::{self, TchRllmConfig},
     loader::{load_model_config, load_rllm_engine},
-    paged::{BatchInfo, BatchInfoBuilder, BlockSpaceManager, CacheEngine, CacheIface, TchSeqMgr},
+    lora::LoraAdapter,
+    paged::{
+        BatchInfo, BatchInfoBuilder, BlockAllocator, BlockSpaceManager, CacheEngine, CacheIface,
+        CudaGraphs, TchSeqMgr, CUDA_GRAPH_BATCH_SIZES,
+    },
     util::{synchronize, to_vec1},
     DType,
 };
+use aici_abi::svob::SimpleVob;
 use aicirt::{with_timer, TimerRef};
-use anyhow::Result;
-use rand::distributions::Distribution as _;
-use rllm::{config::RllmConfig, AiciBias, LogitsProcessor, ModelExec, SchedulerOutputs};
-use std::{sync::Arc, time::Instant};
+use anyhow::{bail, Result};
+use rand::{Rng as _, SeedableRng as _};
+use rllm::{
+    config::RllmConfig, seq::Sequence, AiciBias, HashMap, HookBias, ModelExec, SampleRow,
+    SchedulerOutputs,
+};
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
 use tch::{Device, IndexOp, Tensor};
 
 pub trait TModelInner {
     fn forward(&self, batch_info: &mut BatchInfo) -> Tensor;
     fn finalize(&mut self) {}
+    /// Attach the weights of the LoRA adapter number `idx` (as in LoaderArgs::lora_adapters),
+    /// taking them out of `adapter`.
+    fn load_lora(&mut self, _idx: usize, adapter: &mut LoraAdapter) -> Result<()> {
+        bail!(
+            "LoRA adapter '{}': not supported for this model",
+            adapter.name
+        )
+    }
 }
 
 pub struct TModel {
@@ -25,13 +44,61 @@ pub struct TModel {
     logits: Option<Tensor>,
     t0: Instant,
     seq_mgr: Arc<TchSeqMgr>,
+    cuda_graphs: Option<CudaGraphs>,
+    draft: Option<DraftModel>,
+    banned_masks: BannedMasks,
     pub nv_profile: bool,
 }
 
+/// The draft model for speculative decoding. Its KV cache has as many blocks as the one
+/// of the main model, and the sequences use the same block tables for both
+/// (BlockAllocator::draft_kv_len() tracks how much of it is computed).
+pub struct DraftModel {
+    pub config: Arc<RllmConfig<TModel>>,
+    pub model: Box<dyn TModelInner>,
+    pub cache_engine: CacheEngine,
+}
+
+impl DraftModel {
+    fn next_tokens(&mut self, seqs: &[&Sequence], alloc: &BlockAllocator) -> Vec<u32> {
+        let mut builder = BatchInfoBuilder::new(self.config.clone());
+        for seq in seqs {
+            builder.draft_seq(seq, draft_start(seq, alloc), alloc);
+            alloc.set_draft_kv_len(seq.seq_id, seq.get_len());
+        }
+        // copies of blocks (and swaps) are only applied in run(), so the engine doesn't
+        // draft in steps with copy-on-write
+        let kv_cache = self.cache_engine.get_cache_iface(false);
+        let mut info = builder.finish(0, kv_cache);
+        let logits = self.model.forward(&mut info);
+        let tokens = to_vec1::<i64>(&logits.argmax(-1, false));
+        seqs.iter()
+            .map(|seq| tokens[info.seq_id_to_idx[&seq.seq_id.to_num()]] as u32)
+            .collect()
+    }
+}
+
+/// First token the draft model computes, i.e., without KV in its cache; always
+/// at least the last one, to get its logits.
+fn draft_start(seq: &Sequence, alloc: &BlockAllocator) -> usize {
+    std::cmp::min(alloc.draft_kv_len(seq.seq_id), seq.get_len() - 1)
+}
+
 pub struct TchLoaderArgs {
     pub profile_step_no: usize,
     pub device: Device,
-    pub dtype: Option<DType>,
+    /// Type of the weights and activations; None for the type of the checkpoint.
+    pub weight_dtype: Option<DType>,
+    /// Type of the KV cache; None for the type of the weights.
+    pub kv_cache_dtype: Option<DType>,
+    pub gpu_memory_utilization: f64,
+    pub swap_space: usize,
+    /// Override the number of GPU KV cache blocks (see CacheConfig::num_gpu_blocks).
+    pub num_gpu_blocks: Option<usize>,
+    /// Capture and replay CUDA graphs for small batches of generating sequences.
+    pub enable_cuda_graphs: bool,
+    /// See CacheConfig::overlap_swaps.
+    pub overlap_swaps: bool,
 }
 
 impl ModelExec for TModel {
@@ -78,9 +145,28 @@ impl ModelExec for TModel {
             self.nv_profile = true;
         }
 
-        let mut info = BatchInfoBuilder::new(self.config.clone())
-            .sched_out(sched_out, self.seq_mgr.get_gpu_allocator())
-            .finish(step_no, self.cache_iface(sched_out));
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "build_batch_info",
+            step_no,
+            batch_size = sched_out.num_running_seqs()
+        )
+        .entered();
+        let mut builder = BatchInfoBuilder::new(self.config.clone());
+        builder.sched_out(sched_out, self.seq_mgr.get_gpu_allocator());
+        // the graphs don't wait for KV cache swap-ins; swap-outs don't block the model
+        let no_swap = sched_out.blocks_to_swap_in.is_empty();
+        let kv_cache = self.cache_iface(sched_out, &builder);
+        let mut graphs = self
+            .cuda_graphs
+            .as_mut()
+            .filter(|g| no_swap && g.supports(&builder));
+        let mut info = match &mut graphs {
+            Some(graphs) => graphs.prepare(&mut builder, step_no, kv_cache),
+            None => builder.finish(step_no, kv_cache),
+        };
+        #[cfg(feature = "tracing")]
+        drop(span);
         log::trace!("batch_info #{}: {:?}", info.step_no, info);
 
         #[cfg(feature = "cuda")]
@@ -91,12 +177,16 @@ impl ModelExec for TModel {
         self.t0 = Instant::now();
 
         let logits = with_timer!(tim, {
-            let l = self.model.forward(&mut info);
+            let l = match graphs {
+                Some(graphs) => graphs.forward(self.model.as_ref(), &mut info),
+                None => self.model.forward(&mut info),
+            };
             if false {
                 // without this, the timing is off but we may get better perf
                 synchronize(self.config.model.device.clone());
             }
-            l
+            // sample from F32 logits, whatever the type of the model
+            l.to_kind(DType::Float)
         });
 
         {
@@ -105,7 +195,7 @@ impl ModelExec for TModel {
             if logit_vocab_size != t_vocab {
                 panic!("vocab size mismatch: model {logit_vocab_size} != tokenizer {t_vocab}");
             }
-            assert!(num_seq == info.seq_id_to_idx.len() as i64);
+            assert!(num_seq == info.logit_idxs.size()[0]);
         }
 
         self.batch_info = Some(info);
@@ -115,14 +205,16 @@ impl ModelExec for TModel {
     }
 
     fn get_logits(&self, seq_id: usize) -> Tensor {
-        let _no_grad = tch::no_grad_guard();
-        let idx = self.batch_info.as_ref().unwrap().seq_id_to_idx[&seq_id];
-        self.logits.as_ref().unwrap().i((idx as i64, ..))
+        self.get_logits_at(seq_id, 0)
     }
 
     fn finalize_run(&mut self) -> Result<()> {
         let _no_grad = tch::no_grad_guard();
 
+        // the forward pass is done, so the swap-outs are most likely done too;
+        // free their blocks before the next round is scheduled
+        self.finish_swap_out();
+
         let dur = self.t0.elapsed().as_micros() as f64 / 1000.0;
         let info = self.batch_info.as_ref().unwrap();
 
@@ -145,6 +237,40 @@ impl ModelExec for TModel {
         Ok(())
     }
 
+    fn get_logits_at(&self, seq_id: usize, back: usize) -> Tensor {
+        let _no_grad = tch::no_grad_guard();
+        let idx = self.batch_info.as_ref().unwrap().seq_id_to_idx[&seq_id] - back;
+        self.logits.as_ref().unwrap().i((idx as i64, ..))
+    }
+
+    fn draft_next_tokens(&mut self, seqs: &[&Sequence]) -> Result<Vec<u32>> {
+        let _no_grad = tch::no_grad_guard();
+        let draft = match self.draft.as_mut() {
+            Some(d) => d,
+            None => bail!("no draft model"),
+        };
+        let alloc = self.seq_mgr.get_gpu_allocator();
+        // catching up with the KV of new sequences can take many tokens; keep the batches
+        // within the number of tokens the memory was profiled for
+        let max_tokens = self.config.scheduler.max_num_batched_tokens;
+        let mut result = Vec::with_capacity(seqs.len());
+        let mut rest = seqs;
+        while !rest.is_empty() {
+            let mut num_tokens = 0;
+            let n = rest
+                .iter()
+                .take_while(|seq| {
+                    num_tokens += seq.get_len() - draft_start(seq, alloc);
+                    num_tokens <= max_tokens
+                })
+                .count();
+            let (batch, tail) = rest.split_at(std::cmp::max(n, 1));
+            result.extend(draft.next_tokens(batch, alloc));
+            rest = tail;
+        }
+        Ok(result)
+    }
+
     fn empty_bias(&self, vocab_size: usize) -> Self::AiciBias {
         TchAiciBias {
             vocab_size,
@@ -152,12 +278,7 @@ impl ModelExec for TModel {
         }
     }
 
-    fn new_bias(
-        &self,
-        slice: &'static [f32],
-        num_seqs: usize,
-        vocab_size: usize,
-    ) -> Self::AiciBias {
+    fn new_bias(&self, slice: &[f32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias {
         let _no_grad = tch::no_grad_guard();
 
         let tensor = Tensor::from_slice(slice)
@@ -169,33 +290,86 @@ impl ModelExec for TModel {
         }
     }
 
-    fn sample(&self, state: &mut LogitsProcessor, logits: &Tensor) -> Result<u32> {
+    fn sample_batch(&self, logits: &[Tensor], rows: &[SampleRow]) -> Result<Vec<u32>> {
         let _no_grad = tch::no_grad_guard();
-
-        let next_token = match state.temperature {
-            None => self.sample_argmax(&logits),
-            Some(temperature) => {
-                let logits = logits.to_kind(DType::Float);
-                let logits = logits / (temperature as f64);
-                let prs = logits.softmax(-1, DType::Float);
-
-                let top_p = state.top_p;
-                if top_p <= 0.0 || top_p >= 1.0 {
-                    // simply sample from the predicted probability distribution
-                    prs.multinomial(1, false).int64_value(&[]) as u32
-                } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    let mut prs: Vec<f32> = to_vec1(&prs);
-                    self.sample_topp(state, &mut prs, top_p as f32)?
-                }
+        if logits.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut tokens = sample_on_device(&Tensor::stack(logits, 0), rows, &self.banned_masks);
+        // typical-p sorts by entropy score, which only the host implementation does
+        for (i, row) in rows.iter().enumerate() {
+            if row.temperature.is_some() && row.uses_typical_p() {
+                tokens[i] = row.sample(&to_vec1::<f32>(&logits[i]));
             }
-        };
-        Ok(next_token)
+        }
+        Ok(tokens)
+    }
+
+    fn logits_finite(&self, logits: &[Tensor]) -> Vec<bool> {
+        let _no_grad = tch::no_grad_guard();
+        if logits.is_empty() {
+            return Vec::new();
+        }
+        let finite = Tensor::stack(logits, 0).isfinite().all_dim(-1, false);
+        to_vec1::<bool>(&finite)
+    }
+
+    fn token_logprobs(&self, logits: &[Tensor], tokens: &[u32]) -> Vec<f32> {
+        let _no_grad = tch::no_grad_guard();
+        if logits.is_empty() {
+            return Vec::new();
+        }
+        let logits = Tensor::stack(logits, 0).to_kind(DType::Float);
+        let tokens: Vec<i64> = tokens.iter().map(|t| *t as i64).collect();
+        let tokens = Tensor::from_slice(&tokens)
+            .to(logits.device())
+            .unsqueeze(1);
+        let logprobs = logits
+            .log_softmax(-1, DType::Float)
+            .gather(1, &tokens, false)
+            .squeeze_dim(1);
+        to_vec1::<f32>(&logprobs)
+    }
+
+    fn top_logprobs(&self, logits: &[Tensor], ks: &[usize]) -> Vec<Vec<(u32, f32)>> {
+        let _no_grad = tch::no_grad_guard();
+        let max_k = ks.iter().copied().max().unwrap_or(0);
+        if max_k == 0 {
+            return ks.iter().map(|_| Vec::new()).collect();
+        }
+        // only the top entries of each row are copied to the host
+        let (values, indices) = Tensor::stack(logits, 0)
+            .to_kind(DType::Float)
+            .log_softmax(-1, DType::Float)
+            .topk(max_k as i64, -1, true, true);
+        let values = to_vec1::<f32>(&values.flatten(0, -1));
+        let indices = to_vec1::<i64>(&indices.flatten(0, -1));
+        ks.iter()
+            .enumerate()
+            .map(|(i, k)| {
+                let row = i * max_k..i * max_k + k;
+                indices[row.clone()]
+                    .iter()
+                    .zip(&values[row])
+                    .map(|(t, lp)| (*t as u32, *lp))
+                    .collect()
+            })
+            .collect()
     }
 
     fn tensor_to_vec1(tensor: &Self::Tensor) -> Vec<f32> {
         to_vec1(tensor)
     }
+
+    fn synchronize(&self) {
+        synchronize(self.config.model.device.clone());
+    }
+
+    fn batch_size_buckets(&self) -> Vec<usize> {
+        // batches of these sizes capture a CUDA graph on first use (if enabled);
+        // other sizes still pick different cuBLAS kernels
+        CUDA_GRAPH_BATCH_SIZES.to_vec()
+    }
 }
 
 impl TModel {
@@ -204,20 +378,33 @@ impl TModel {
         cache_engine: CacheEngine,
         seq_mgr: Arc<TchSeqMgr>,
         model: Box<dyn TModelInner>,
+        draft: Option<DraftModel>,
     ) -> Self {
         Self {
-            config,
             cache_engine,
+            draft,
             nv_profile: false,
             model,
             batch_info: None,
             logits: None,
+            cuda_graphs: config
+                .model
+                .enable_cuda_graphs
+                .then(|| CudaGraphs::new(config.clone())),
+            config,
             seq_mgr,
             t0: Instant::now(),
+            banned_masks: BannedMasks::default(),
         }
     }
 
-    fn cache_iface(&mut self, sched_out: &mut SchedulerOutputs) -> Box<dyn CacheIface> {
+    fn cache_iface(
+        &mut self,
+        sched_out: &mut SchedulerOutputs,
+        builder: &BatchInfoBuilder,
+    ) -> Box<dyn CacheIface> {
+        // in case the previous round failed before finalize_run()
+        self.finish_swap_out();
         self.cache_engine.new_round();
         if sched_out.blocks_to_swap_in.len() > 0 {
             self.cache_engine.swap_in(&sched_out.blocks_to_swap_in);
@@ -227,45 +414,19 @@ impl TModel {
         }
         if sched_out.blocks_to_copy.len() > 0 {
             self.cache_engine.copy(&sched_out.blocks_to_copy);
+            if let Some(draft) = self.draft.as_mut() {
+                draft.cache_engine.copy(&sched_out.blocks_to_copy);
+            }
         }
-        self.cache_engine.get_cache_iface()
-    }
-
-    fn sample_argmax(&self, logits: &Tensor) -> u32 {
-        logits.argmax(0, false).int64_value(&[]) as u32
+        let reads_swapped_in = builder.reads_any_block(self.cache_engine.swapped_in_blocks());
+        self.cache_engine.get_cache_iface(reads_swapped_in)
     }
 
-    fn sample_multinomial(&self, state: &mut LogitsProcessor, prs: &Vec<f32>) -> Result<u32> {
-        let distr = rand::distributions::WeightedIndex::new(prs)?;
-        let next_token = distr.sample(&mut state.rng) as u32;
-        Ok(next_token)
-    }
-
-    fn sample_topp(
-        &self,
-        state: &mut LogitsProcessor,
-        prs: &mut Vec<f32>,
-        top_p: f32,
-    ) -> Result<u32> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices.sort_by(|&i, &j| prs[j].partial_cmp(&prs[i]).unwrap());
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                prs[*index] = 0.0;
-            } else {
-                cumsum += prs[*index];
-            }
+    fn finish_swap_out(&mut self) {
+        let blocks = self.cache_engine.finish_swap_out();
+        if !blocks.is_empty() {
+            self.seq_mgr.get_gpu_allocator().end_swap_out(&blocks);
         }
-        // Sample with clamped probabilities.
-        self.sample_multinomial(state, prs)
     }
 }
 
@@ -281,3 +442,271 @@ impl AiciBias<Tensor> for TchAiciBias {
         *logits = &*logits + bias;
     }
 }
+
+/// Device copies of the token_ban_list masks of SampleRow::banned. The rows of a request
+/// share the Arc, so its address is the key; entries go once the request drops it.
+#[derive(Default)]
+pub struct BannedMasks {
+    masks: Mutex<HashMap<usize, (Weak<SimpleVob>, Tensor)>>,
+}
+
+impl BannedMasks {
+    /// [num_rows, vocab_size], true for the banned tokens; None when no row bans any.
+    fn rows_mask(&self, rows: &[SampleRow], vocab_size: i64, device: Device) -> Option<Tensor> {
+        if rows.iter().all(|r| r.banned.is_none()) {
+            return None;
+        }
+        let mut masks = self.masks.lock().unwrap();
+        // a dropped set's address can be reused by the next one
+        masks.retain(|_, (set, _)| set.strong_count() > 0);
+        let none = Tensor::zeros(&[vocab_size], (DType::Bool, device));
+        let rows: Vec<Tensor> = rows
+            .iter()
+            .map(|r| match &r.banned {
+                Some(b) => masks
+                    .entry(Arc::as_ptr(b) as usize)
+                    .or_insert_with(|| {
+                        let mask: Vec<bool> = (0..vocab_size as u32)
+                            .map(|t| (t as usize) < b.len() && b.is_allowed(t))
+                            .collect();
+                        (Arc::downgrade(b), Tensor::from_slice(&mask).to(device))
+                    })
+                    .1
+                    .shallow_clone(),
+                None => none.shallow_clone(),
+            })
+            .collect();
+        Some(Tensor::stack(&rows, 0))
+    }
+}
+
+/// Sample one token per row of `logits` ([num_rows, vocab_size]) using per-row parameters.
+/// Everything happens on the device of `logits`; only the token ids are copied back.
+/// Matches SampleRow::sample() up to numerical precision, except that typical-p is ignored.
+pub fn sample_on_device(logits: &Tensor, rows: &[SampleRow], banned: &BannedMasks) -> Vec<u32> {
+    let (num_rows, vocab_size) = logits.size2().unwrap();
+    assert!(num_rows == rows.len() as i64);
+    let device = logits.device();
+    let mut logits = logits.to_kind(DType::Float);
+
+    if let Some(banned) = banned.rows_mask(rows, vocab_size, device) {
+        logits = logits.masked_fill(&banned, f64::NEG_INFINITY);
+    }
+
+    let max_suppressed = rows
+        .iter()
+        .map(|r| r.suppress_tokens.len())
+        .max()
+        .unwrap_or(0);
+    if max_suppressed > 0 {
+        // [num_rows, max_suppressed], padded with -1
+        let toks: Vec<i64> = rows
+            .iter()
+            .flat_map(|r| {
+                let pad = max_suppressed - r.suppress_tokens.len();
+                r.suppress_tokens
+                    .iter()
+                    .map(|t| *t as i64)
+                    .chain(std::iter::repeat(-1).take(pad))
+            })
+            .collect();
+        let toks = Tensor::from_slice(&toks)
+            .to(device)
+            .view([num_rows, 1, max_suppressed as i64]);
+        let mask = Tensor::arange(vocab_size, (DType::Int64, device))
+            .view([1, vocab_size, 1])
+            .eq_tensor(&toks)
+            .any_dim(-1, false);
+        let masked = logits.masked_fill(&mask, f64::NEG_INFINITY);
+        // keep rows where only the suppressed tokens are allowed
+        let none_left = masked.amax(&[-1], true).eq(f64::NEG_INFINITY);
+        logits = logits.where_self(&none_left, &masked);
+    }
+
+    let hook_bias = hook_bias_tensor(rows, vocab_size, device);
+
+    let mut greedy = logits.argmax(-1, false);
+    if let Some((bias, hooked)) = &hook_bias {
+        let biased = &logits + bias;
+        let keep = none_left(&biased).logical_or(&hooked.logical_not());
+        greedy = greedy.where_self(&keep, &biased.argmax(-1, false));
+    }
+
+    if rows.iter().all(|r| r.temperature.is_none()) {
+        return to_vec1::<i64>(&greedy).iter().map(|t| *t as u32).collect();
+    }
+
+    // copy all per-row parameters to the device at once
+    let mut params: Vec<f32> = Vec::with_capacity(5 * rows.len());
+    params.extend(rows.iter().map(|r| r.temperature.unwrap_or(1.0)));
+    params.extend(
+        rows.iter()
+            .map(|r| if r.uses_top_p() { r.top_p } else { 2.0 }),
+    );
+    params.extend(rows.iter().map(|r| {
+        if r.top_k > 0 {
+            r.top_k as f32
+        } else {
+            vocab_size as f32
+        }
+    }));
+    params.extend(rows.iter().map(|r| r.min_p));
+    params.extend(rows.iter().map(|r| r.uniform));
+    let params = Tensor::from_slice(&params)
+        .to(device)
+        .reshape(&[5, num_rows, 1]);
+    let (temperature, top_p, top_k, min_p, uniform) = (
+        params.get(0),
+        params.get(1),
+        params.get(2),
+        params.get(3),
+        params.get(4),
+    );
+
+    let logits = logits / temperature;
+    let probs = logits.softmax(-1, DType::Float);
+    let (sorted, order) = probs.sort(-1, true);
+
+    // drop tokens once the preceding ones exceed top_p, past the top_k most likely ones,
+    // or less likely than min_p times the first one
+    let cum = sorted.cumsum(-1, DType::Float);
+    let rank = Tensor::arange(vocab_size, (DType::Float, device)).reshape(&[1, vocab_size]);
+    let min_pr = min_p * sorted.select(1, 0).unsqueeze(1);
+    let dropped = (&cum - &sorted)
+        .ge_tensor(&top_p)
+        .logical_or(&rank.ge_tensor(&top_k))
+        .logical_or(&sorted.lt_tensor(&min_pr));
+    let num_kept = dropped
+        .logical_not()
+        .sum_dim_intlist(-1, false, DType::Int64);
+
+    let cum = sorted.masked_fill(&dropped, 0.0).cumsum(-1, DType::Float);
+    let mut sampled = pick_sorted(&cum, &order, &num_kept, &uniform);
+
+    if let Some((bias, hooked)) = &hook_bias {
+        // the same again, with the dropped tokens at -inf and the hook bias added
+        let dropped = dropped.scatter(1, &order, &dropped);
+        let biased = logits.masked_fill(&dropped, f64::NEG_INFINITY) + bias;
+        let (sorted, order) = biased.softmax(-1, DType::Float).sort(-1, true);
+        let num_kept = sorted.gt(0.0).sum_dim_intlist(-1, false, DType::Int64);
+        let cum = sorted.cumsum(-1, DType::Float);
+        let keep = none_left(&biased).logical_or(&hooked.logical_not());
+        sampled = sampled.where_self(&keep, &pick_sorted(&cum, &order, &num_kept, &uniform));
+    }
+
+    let both = to_vec1::<i64>(&Tensor::cat(&[greedy, sampled], 0));
+    let (greedy, sampled) = both.split_at(rows.len());
+    rows.iter()
+        .enumerate()
+        .map(|(i, r)| {
+            if r.temperature.is_none() {
+                greedy[i] as u32
+            } else {
+                sampled[i] as u32
+            }
+        })
+        .collect()
+}
+
+// [num_rows, vocab_size] with the SampleRow::hook_bias of each row, and [num_rows],
+// true for the rows that have one; None when no row has one
+fn hook_bias_tensor(
+    rows: &[SampleRow],
+    vocab_size: i64,
+    device: Device,
+) -> Option<(Tensor, Tensor)> {
+    if rows.iter().all(|r| r.hook_bias.is_empty()) {
+        return None;
+    }
+    let mut row_idx = Vec::new();
+    let mut toks = Vec::new();
+    let mut values = Vec::new();
+    for (i, r) in rows.iter().enumerate() {
+        for &(tok, value) in r.hook_bias.entries() {
+            if (tok as i64) < vocab_size {
+                row_idx.push(i as i64);
+                toks.push(tok as i64);
+                values.push(value);
+            }
+        }
+    }
+    let mut bias = Tensor::zeros(&[rows.len() as i64, vocab_size], (DType::Float, device));
+    let _ = bias.index_put_(
+        &[
+            Some(Tensor::from_slice(&row_idx).to(device)),
+            Some(Tensor::from_slice(&toks).to(device)),
+        ],
+        &Tensor::from_slice(&values).to(device),
+        true,
+    );
+    let hooked: Vec<bool> = rows.iter().map(|r| !r.hook_bias.is_empty()).collect();
+    Some((bias, Tensor::from_slice(&hooked).to(device)))
+}
+
+// [num_rows], true where all the logits are -inf
+fn none_left(logits: &Tensor) -> Tensor {
+    logits.amax(&[-1], false).eq(f64::NEG_INFINITY)
+}
+
+// inverse transform sampling: the first token where the cumulative probability (`cum`,
+// in the sorted `order`) exceeds uniform * (total probability of the `num_kept` tokens)
+fn pick_sorted(cum: &Tensor, order: &Tensor, num_kept: &Tensor, uniform: &Tensor) -> Tensor {
+    let vocab_size = cum.size()[1];
+    let threshold = uniform * cum.select(1, vocab_size - 1).unsqueeze(1);
+    let pos = cum
+        .le_tensor(&threshold)
+        .sum_dim_intlist(-1, false, DType::Int64)
+        .minimum(&(num_kept - 1))
+        .clamp_min(0);
+    order.gather(1, &pos.unsqueeze(1), false).squeeze_dim(1)
+}
+
+/// Compare sampling each row on the host (after copying it there) with sample_on_device().
+pub fn bench_sampling(device: Device, vocab_size: i64) {
+    const ITERS: u32 = 20;
+    let _no_grad = tch::no_grad_guard();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let banned = BannedMasks::default();
+    for batch_size in [1, 16, 64] {
+        let logits = Tensor::randn(&[batch_size, vocab_size], (DType::Float, device)) * 5.0;
+        let per_row: Vec<_> = (0..batch_size).map(|i| logits.i((i, ..))).collect();
+        let rows: Vec<_> = (0..batch_size)
+            .map(|_| SampleRow {
+                temperature: Some(0.8),
+                top_p: 0.9,
+                top_k: 0,
+                min_p: 0.0,
+                typical_p: 1.0,
+                uniform: rng.gen(),
+                suppress_tokens: vec![],
+                banned: None,
+                hook_bias: HookBias::default(),
+                hook: None,
+            })
+            .collect();
+
+        let mut host = Vec::new();
+        let t0 = Instant::now();
+        for _ in 0..ITERS {
+            host = per_row
+                .iter()
+                .zip(&rows)
+                .map(|(l, r)| r.sample(&to_vec1::<f32>(l)))
+                .collect();
+        }
+        let host_time = t0.elapsed() / ITERS;
+
+        let mut dev = Vec::new();
+        let t0 = Instant::now();
+        for _ in 0..ITERS {
+            dev = sample_on_device(&logits, &rows, &banned);
+        }
+        let dev_time = t0.elapsed() / ITERS;
+
+        let same = host.iter().zip(&dev).filter(|(a, b)| a == b).count();
+        println!(
+            "sample batch={batch_size:>2}: per-row {host_time:>10.2?}, batched {dev_time:>10.2?}; \
+             {same}/{batch_size} tokens agree"
+        );
+    }
+}
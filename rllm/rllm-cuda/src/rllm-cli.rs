@@ -0,0 +1,75 @@
+use clap::{Parser, Subcommand};
+use rllm::{
+    cli::{self, BenchArgs, GenerateArgs, TokenizeArgs},
+    server::{init_loader_args, RllmCliArgs},
+    util::parse_with_settings,
+};
+use rllm_cuda::{llm::tmodel::TModel, TchArgs};
+
+/// Generate, tokenize, or benchmark from the command line with tch (torch) backend.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate text for a prompt
+    Generate {
+        #[clap(flatten)]
+        args: RllmCliArgs,
+        #[clap(flatten)]
+        tch: TchArgs,
+        #[clap(flatten)]
+        generate: GenerateArgs,
+    },
+    /// Print the tokens of a text, with their bytes
+    Tokenize {
+        #[clap(flatten)]
+        args: RllmCliArgs,
+        #[clap(flatten)]
+        tokenize: TokenizeArgs,
+    },
+    /// Measure prefill and decode throughput with random prompts
+    Bench {
+        #[clap(flatten)]
+        args: RllmCliArgs,
+        #[clap(flatten)]
+        tch: TchArgs,
+        #[clap(flatten)]
+        bench: BenchArgs,
+    },
+}
+
+fn main() {
+    let cli_args = parse_with_settings::<CliArgs>();
+    let log_mode = aicirt::LogMode::Stderr;
+    let r = match cli_args.command {
+        Command::Generate {
+            mut args,
+            tch,
+            generate,
+        } => {
+            let loader_args = init_loader_args(&mut args, log_mode);
+            tch.loader_args()
+                .and_then(|model_args| cli::generate::<TModel>(&generate, loader_args, model_args))
+        }
+        Command::Tokenize { mut args, tokenize } => {
+            let loader_args = init_loader_args(&mut args, log_mode);
+            cli::tokenize::<TModel>(&tokenize, loader_args)
+        }
+        Command::Bench {
+            mut args,
+            tch,
+            bench,
+        } => {
+            let loader_args = init_loader_args(&mut args, log_mode);
+            tch.loader_args().and_then(|model_args| {
+                cli::bench::<TModel>(&bench, args.bench_runs, loader_args, model_args)
+            })
+        }
+    };
+    cli::exit_on_error(r);
+}
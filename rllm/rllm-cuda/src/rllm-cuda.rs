@@ -1,12 +1,6 @@
-mod llm;
-
 use clap::Parser;
-use llm::{
-    tmodel::{TModel, TchLoaderArgs},
-    DType,
-};
 use rllm::util::parse_with_settings;
-use tch::Device;
+use rllm_cuda::{llm, llm::tmodel::TModel, TchArgs};
 
 /// Serve LLMs with AICI over HTTP with tch (torch) backend.
 #[derive(Parser, Debug)]
@@ -15,43 +9,30 @@ pub struct DriverArgs {
     #[clap(flatten)]
     pub args: rllm::server::RllmCliArgs,
 
-    /// Specify which type to use in the model (bf16, f16, f32)
-    #[arg(long, default_value = "", help_heading = "Model")]
-    pub dtype: String,
+    #[clap(flatten)]
+    pub tch: TchArgs,
 
-    /// Enable nvprof profiling for given engine step (if available)
-    #[arg(long, default_value_t = 0, help_heading = "Development")]
-    pub profile_step: usize,
+    /// Benchmark per-row vs batched sampling on the device and exit
+    #[arg(long, default_value_t = false, help_heading = "Development")]
+    pub bench_sample: bool,
 }
 
 #[actix_web::main]
 async fn main() -> () {
     let args = parse_with_settings::<DriverArgs>();
-    let _ = args;
 
-    let (device, dtype) = if tch::Cuda::is_available() {
-        (Device::Cuda(0), None)
-    } else {
-        // At least on AMD 5500m MPS is 3x slower than CPU
-        // #[cfg(target_os = "macos")]
-        // let r = (Device::Mps, DType::Half);
-        // #[cfg(not(target_os = "macos"))]
-        let r = (Device::Cpu, Some(DType::Float));
-        r
+    let model_args = match args.tch.loader_args() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(rllm::cli::EXIT_USER_ERROR);
+        }
     };
 
-    let dtype = match args.dtype.as_str() {
-        "bf16" => Some(DType::BFloat16),
-        "f16" => Some(DType::Half),
-        "f32" => Some(DType::Float),
-        "" => dtype,
-        _ => panic!("invalid dtype; try one of bf16, f16, f32"),
-    };
+    if args.bench_sample {
+        llm::tmodel::bench_sampling(model_args.device, 32000);
+        return;
+    }
 
-    let model_args = TchLoaderArgs {
-        device,
-        dtype,
-        profile_step_no: args.profile_step,
-    };
     rllm::server::server_main::<TModel>(args.args, model_args).await;
 }
@@ -6,6 +6,57 @@ set -e
 expected/phi-1_5 \
 expected/orca
 
+# generation steps replay CUDA graphs here; the logits have to match as well
+EXTRA_ARGS=--cuda-graphs ./expected/go.sh \
+expected/phi-1_5
+
+# requests snapshotted after a few tokens and restored have to continue the same way
+EXTRA_ARGS=--test-snapshot ./expected/go.sh \
+expected/phi-1_5
+
+# draining with a tight deadline completes the short request and interrupts the long ones
+EXTRA_ARGS=--test-drain ./expected/go.sh \
+expected/phi-1_5
+
+# prompts from the tokenization cache have to generate the same as tokenized ones
+EXTRA_ARGS="--test-tokenize-cache --tokenize-cache-entries 1000" ./expected/go.sh \
+expected/phi-1_5
+
+# latency histograms in the Prometheus format have to be consistent
+EXTRA_ARGS=--test-metrics ./expected/go.sh \
+expected/phi-1_5
+
+# with a tiny waiting queue, requests over the limit are rejected until steps make room
+EXTRA_ARGS="--test-queue-limits --max-waiting-groups 3" ./expected/go.sh \
+expected/phi-1_5
+
+# with a tiny KV cache, sequences are swapped out and back in; the outputs have to
+# match both with the swaps overlapped with the model (the default) and without
+EXTRA_ARGS="--preemption-mode swap --gpu-blocks 32" ./expected/go.sh \
+expected/phi-1_5
+EXTRA_ARGS="--preemption-mode swap --gpu-blocks 32 --sync-swaps" ./expected/go.sh \
+expected/phi-1_5
+
+# the checkpoint is F16; the outputs have to match with it converted to BF16,
+# and with the KV cache kept in F32; BF16 has 8 bits of mantissa (F16 has 11),
+# so its logits are only held to the default tolerances
+EXTRA_ARGS="--dtype bf16 -s test_maxtol=0.5 -s test_avgtol=0.2" ./expected/go.sh \
+expected/phi-1_5
+EXTRA_ARGS="--kv-cache-dtype f32" ./expected/go.sh \
+expected/phi-1_5
+
+# GGUF import (dequantized): TinyLlama q4_0 from a local path has to generate,
+# also with the KV cache in another type than the weights
+./scripts/gguf-tinyllama.sh
+EXTRA_ARGS="--kv-cache-dtype f32" ./scripts/gguf-tinyllama.sh
+
+# with two GPUs, the layers of a Llama model are split between them; the logits
+# have to match the reference ones just as on a single GPU
+if [ "$(nvidia-smi -L | wc -l)" -ge 2 ] ; then
+EXTRA_ARGS="--pipeline-parallel-size 2" ./expected/go.sh \
+expected/llama
+fi
+
 if [ "$1" = "all" ] ; then
 ./expected/go.sh \
 expected/codellama34 \
@@ -9,7 +9,7 @@ use super::tmodel::TModel;
 pub struct CppBlockSpaceManager {}
 
 impl TBlockSpaceManager<TModel> for CppBlockSpaceManager {
-    fn can_allocate(&self, _seq_group: &SequenceGroup) -> bool {
+    fn can_allocate(&self, _seq_group: &SequenceGroup, _reserved_blocks: usize) -> bool {
         true
     }
 
@@ -14,6 +14,12 @@ pub(super) fn load_rllm_engine(
     args: LoaderArgs,
     mut model_args: CppLoaderArgs,
 ) -> Result<RllmEngine<TModel>> {
+    if !args.lora_adapters.is_empty() {
+        bail!("LoRA adapters are not supported with llama.cpp");
+    }
+    if args.draft_model.is_some() {
+        bail!("Speculative decoding (--draft-model) is not supported with llama.cpp");
+    }
     let model = do_load(&args, &mut model_args)?;
     let rllm_config = RllmEngine::<TModel>::build_config(&args, &mut model_args)?;
 
@@ -38,10 +38,11 @@ impl SequenceManager for CppSequenceManager {
         dst.cp_from(src, 0, length as i32);
     }
 
-    fn trim(&self, seq: SeqId, length: usize) {
+    fn trim(&self, seq: SeqId, length: usize) -> usize {
         let seqs = self.seqs.lock().unwrap();
         let seq = seqs.get(&seq).unwrap();
         seq.rm(length as i32, -1);
+        length
     }
 
     fn delete(&self, seq: SeqId) {
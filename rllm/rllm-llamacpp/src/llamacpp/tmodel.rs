@@ -1,11 +1,10 @@
-use aicirt::{with_timer, TimerRef};
+use aicirt::{bail_user, with_timer, TimerRef};
 use anyhow::Result;
 use llama_cpp_low as cpp;
-use rand::distributions::Distribution as _;
 use rllm::{
     config::{ModelMeta, RllmConfig},
     seq::SchedulingPhase,
-    AiciBias, HashMap, LoaderArgs, LogitsProcessor, ModelExec, SchedulerOutputs,
+    AiciBias, HashMap, LoaderArgs, ModelExec, SchedulerOutputs,
 };
 use std::{sync::Arc, time::Instant};
 
@@ -64,20 +63,19 @@ impl ModelExec for TModel {
                     continue;
                 }
 
-                let seq_len = seq.get_len();
-                let k_len = seq_len;
+                // while prefilling in chunks, only compute KV up to the end of the chunk
+                let k_len = seq.get_kv_len();
                 log::trace!("fwd seq: {seq:?}");
-                let mut q_len = seq.get_len() - seq.num_kv_computed;
-                if q_len == 0 {
-                    // just re-compute the last token
-                    q_len = 1;
+                let q_len = seq.num_query_tokens();
+                if !seq.is_prefilling() {
+                    sg.usage.gen_tokens += 1;
                 }
-                sg.usage.gen_tokens += 1;
                 sg.usage.prompt_tokens += q_len;
 
                 let off = k_len - q_len;
+                let prefilling = seq.is_prefilling();
                 for idx in off..off + q_len {
-                    let logits = idx + 1 == off + q_len;
+                    let logits = !prefilling && idx + 1 == off + q_len;
                     if logits {
                         self.seq_id_to_idx
                             .insert(seq.seq_id.to_num(), self.batch.len());
@@ -129,48 +127,14 @@ impl ModelExec for TModel {
         }
     }
 
-    fn new_bias(
-        &self,
-        slice: &'static [f32],
-        num_seqs: usize,
-        vocab_size: usize,
-    ) -> Self::AiciBias {
-        let tensor = {
-            assert!(slice.len() == num_seqs * vocab_size);
-            Tensor::from_slice(slice)
-        };
+    fn new_bias(&self, slice: &[f32], num_seqs: usize, vocab_size: usize) -> Self::AiciBias {
+        assert!(slice.len() == num_seqs * vocab_size);
         CppAiciBias {
             vocab_size,
-            bias: Some(tensor),
+            bias: Some(slice.to_vec()),
         }
     }
 
-    fn sample(&self, state: &mut LogitsProcessor, logits: &Tensor) -> Result<u32> {
-        let next_token = match state.temperature {
-            None => self.sample_argmax(&logits),
-            Some(temperature) => {
-                let mut prs: Vec<f32> = logits.to_vec1();
-                let max_logit = prs.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-                let temp = (1.0 / temperature) as f32;
-                for idx in 0..prs.len() {
-                    prs[idx] = ((prs[idx] - max_logit) * temp).exp();
-                }
-                let sum = prs.iter().sum::<f32>();
-                for idx in 0..prs.len() {
-                    prs[idx] /= sum;
-                }
-                let top_p = state.top_p;
-                if top_p <= 0.0 || top_p >= 1.0 {
-                    self.sample_multinomial(state, &prs)?
-                } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(state, &mut prs, top_p as f32)?
-                }
-            }
-        };
-        Ok(next_token)
-    }
-
     fn load_model_config(
         args: &LoaderArgs,
         model_args: &mut Self::ModelLoaderArgs,
@@ -179,7 +143,10 @@ impl ModelExec for TModel {
         Ok((meta, ()))
     }
 
-    fn verify_args(_args: &RllmConfig<Self>) -> Result<()> {
+    fn verify_args(args: &RllmConfig<Self>) -> Result<()> {
+        if args.parallel.pipeline_parallel_size != 1 {
+            bail_user!("pipeline parallelism is not supported with llama.cpp");
+        }
         Ok(())
     }
 
@@ -212,57 +179,11 @@ impl TModel {
             t0: Instant::now(),
         }
     }
-
-    fn sample_argmax(&self, logits: &Tensor) -> u32 {
-        let data = logits.as_slice();
-        let mut top = data[0];
-        let mut top_idx = 0;
-        for (i, x) in data.iter().enumerate() {
-            if *x > top {
-                top = *x;
-                top_idx = i;
-            }
-        }
-        top_idx as u32
-    }
-
-    fn sample_multinomial(&self, state: &mut LogitsProcessor, prs: &Vec<f32>) -> Result<u32> {
-        let distr = rand::distributions::WeightedIndex::new(prs)?;
-        let next_token = distr.sample(&mut state.rng) as u32;
-        Ok(next_token)
-    }
-
-    fn sample_topp(
-        &self,
-        state: &mut LogitsProcessor,
-        prs: &mut Vec<f32>,
-        top_p: f32,
-    ) -> Result<u32> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices.sort_by(|&i, &j| prs[j].partial_cmp(&prs[i]).unwrap());
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                prs[*index] = 0.0;
-            } else {
-                cumsum += prs[*index];
-            }
-        }
-        // Sample with clamped probabilities.
-        self.sample_multinomial(state, prs)
-    }
 }
 
 pub struct CppAiciBias {
     pub vocab_size: usize,
-    pub bias: Option<Tensor>,
+    pub bias: Option<Vec<f32>>,
 }
 
 impl AiciBias<Tensor> for CppAiciBias {
@@ -270,7 +191,6 @@ impl AiciBias<Tensor> for CppAiciBias {
         let bias = self.bias.as_ref().unwrap();
         let sp = seq_id * self.vocab_size;
         let logits = logits.as_mut_slice();
-        let bias = bias.as_slice();
         for i in 0..logits.len() {
             logits[i] += bias[sp + i];
         }
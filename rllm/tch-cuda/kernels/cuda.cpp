@@ -2,6 +2,7 @@
 #include <c10/cuda/CUDAStream.h>
 #include <ATen/cuda/CUDAContext.h>
 #include <ATen/cuda/CUDAEvent.h>
+#include <ATen/cuda/CUDAGraph.h>
 
 using namespace c10::cuda;
 using namespace at::cuda;
@@ -146,4 +147,36 @@ char *cuda_event_free_C(CUDAEvent *cuEv) {
   PROTECT({ delete cuEv; });
 }
 
+//
+// Graphs
+//
+
+char *cuda_graph_create_C(CUDAGraph **cuGr) {
+  PROTECT({ *cuGr = new CUDAGraph(); });
+}
+
+// Captures work submitted to the current stream, which can't be the default
+// stream. If pool_of is given, the memory pool of that graph is shared.
+char *cuda_graph_capture_begin_C(CUDAGraph *cuGr, CUDAGraph *pool_of) {
+  PROTECT({
+    if (pool_of)
+      cuGr->capture_begin(pool_of->pool());
+    else
+      cuGr->capture_begin();
+  });
+}
+
+char *cuda_graph_capture_end_C(CUDAGraph *cuGr) {
+  PROTECT({ cuGr->capture_end(); });
+}
+
+// Launches the graph on the current stream.
+char *cuda_graph_replay_C(CUDAGraph *cuGr) {
+  PROTECT({ cuGr->replay(); });
+}
+
+char *cuda_graph_free_C(CUDAGraph *cuGr) {
+  PROTECT({ delete cuGr; });
+}
+
 } // extern "C"
\ No newline at end of file
@@ -0,0 +1,67 @@
+use crate::check_res;
+use std::os::raw::c_char;
+
+#[repr(C)]
+struct CUDAGraph {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn cuda_graph_create_C(cu_gr: *mut *mut CUDAGraph) -> *mut c_char;
+    fn cuda_graph_capture_begin_C(cu_gr: *mut CUDAGraph, pool_of: *mut CUDAGraph) -> *mut c_char;
+    fn cuda_graph_capture_end_C(cu_gr: *mut CUDAGraph) -> *mut c_char;
+    fn cuda_graph_replay_C(cu_gr: *mut CUDAGraph) -> *mut c_char;
+    fn cuda_graph_free_C(cu_gr: *mut CUDAGraph) -> *mut c_char;
+}
+
+/// Sequence of kernels recorded once and then launched again as a whole,
+/// saving the CPU-side launch overhead of every kernel.
+///
+/// Replaying reads from and writes to the same addresses as during the capture,
+/// so the inputs have to be copied into the tensors used during the capture,
+/// and the outputs read from the tensors produced there.
+pub struct CudaGraph {
+    ptr: *mut CUDAGraph,
+}
+
+impl CudaGraph {
+    pub fn new() -> Self {
+        let mut ptr: *mut CUDAGraph = std::ptr::null_mut();
+        unsafe { check_res("cuda_graph_create_C", cuda_graph_create_C(&mut ptr)) };
+        Self { ptr }
+    }
+
+    /// Start capturing work submitted to the current stream; the current stream
+    /// can't be the default stream (use `CudaStream::new()` and `.guard()`).
+    /// Memory allocated during the capture comes from a private pool of the graph,
+    /// or from the pool of `pool_of` if given (the graphs then can't be replayed concurrently).
+    pub fn capture_begin(&self, pool_of: Option<&CudaGraph>) {
+        let pool_of = pool_of.map_or(std::ptr::null_mut(), |g| g.ptr);
+        unsafe {
+            check_res(
+                "cuda_graph_capture_begin_C",
+                cuda_graph_capture_begin_C(self.ptr, pool_of),
+            )
+        };
+    }
+
+    pub fn capture_end(&self) {
+        unsafe {
+            check_res(
+                "cuda_graph_capture_end_C",
+                cuda_graph_capture_end_C(self.ptr),
+            )
+        };
+    }
+
+    /// Launch the captured work on the current stream.
+    pub fn replay(&self) {
+        unsafe { check_res("cuda_graph_replay_C", cuda_graph_replay_C(self.ptr)) };
+    }
+}
+
+impl Drop for CudaGraph {
+    fn drop(&mut self) {
+        unsafe { check_res("cuda_graph_free_C", cuda_graph_free_C(self.ptr)) };
+    }
+}
@@ -4,9 +4,11 @@ use torch_sys::C_tensor;
 use rustc_hash::FxHashMap as HashMap;
 
 mod event;
+mod graph;
 mod stream;
 
 pub use event::*;
+pub use graph::*;
 pub use stream::*;
 
 unsafe fn ptr_to_string(ptr: *mut libc::c_char) -> Option<String> {
@@ -77,6 +79,42 @@ pub fn flash_attn_varlen(
     softmax_scale: f32,
     causal: bool,
 ) -> Tensor {
+    flash_attn_varlen_window(
+        q,
+        k,
+        v,
+        seqlens_q,
+        seqlens_k,
+        max_seqlen_q,
+        max_seqlen_k,
+        softmax_scale,
+        causal,
+        None,
+    )
+}
+
+/// Like `flash_attn_varlen()`, but with optional sliding-window (local) attention:
+/// each query only attends to the last `window` keys up to and including its own position
+/// (with queries aligned to the end of the keys).
+pub fn flash_attn_varlen_window(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    causal: bool,
+    window: Option<usize>,
+) -> Tensor {
+    let window_size_left = match window {
+        Some(w) => {
+            assert!(w > 0);
+            w as i32 - 1
+        }
+        None => -1,
+    };
     let mut outputs = vec![std::ptr::null_mut(); 1];
     let err = unsafe {
         ptr_to_string(mha_varlen_fwd_C(
@@ -92,7 +130,7 @@ pub fn flash_attn_varlen(
             softmax_scale,
             false,
             causal,
-            -1,
+            window_size_left,
             -1,
             outputs.as_mut_ptr(),
         ))
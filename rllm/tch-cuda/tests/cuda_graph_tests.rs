@@ -0,0 +1,48 @@
+use anyhow::Result;
+use tch::{Device, Kind, Tensor};
+use tch_cuda::{CudaGraph, CudaStream};
+
+#[test]
+fn cuda_graph_replay() -> Result<()> {
+    let _no_grad = tch::no_grad_guard();
+    let device = Device::Cuda(0);
+    let w = Tensor::randn(&[64, 64], (Kind::BFloat16, device));
+    let f = |x: &Tensor| (x.matmul(&w) + 1.0).relu().softmax(-1, Kind::Float);
+
+    // the graph reads from here
+    let mut input = Tensor::zeros(&[8, 64], (Kind::BFloat16, device));
+    let graph = CudaGraph::new();
+    let stream = CudaStream::new(device);
+    let output = {
+        let _guard = stream.guard();
+        // warm up outside of the capture
+        let _ = f(&input);
+        graph.capture_begin(None);
+        let output = f(&input);
+        graph.capture_end();
+        output
+    };
+    stream.synchronize();
+
+    for _ in 0..3 {
+        let x = Tensor::randn(&[8, 64], (Kind::BFloat16, device));
+        input.copy_(&x);
+        graph.replay();
+        assert!(output.equal(&f(&x)));
+    }
+
+    // a second graph can share the memory pool of the first one
+    let graph2 = CudaGraph::new();
+    let output2 = {
+        let _guard = stream.guard();
+        graph2.capture_begin(Some(&graph));
+        let output2 = f(&input) * 2.0;
+        graph2.capture_end();
+        output2
+    };
+    stream.synchronize();
+    graph2.replay();
+    assert!(output2.equal(&(f(&input) * 2.0)));
+
+    Ok(())
+}